@@ -0,0 +1,456 @@
+// Cartridges on the expansion port, loaded from VICE .crt images: a header
+// naming the hardware and the levels it puts on EXROM and GAME, followed
+// by CHIP packets holding the ROMs and where they go. Bank-switched
+// cartridges select their banks through registers in the I/O 1 ($DE00)
+// and I/O 2 ($DF00) areas. Freezer cartridges also have a button that
+// stops the running program with an NMI and brings in their own ROM.
+
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 16] = b"C64 CARTRIDGE   ";
+const HEADER_SIZE: usize = 0x40;
+const CHIP_HEADER_SIZE: usize = 0x10;
+const BANK_SIZE: usize = 0x2000;
+
+// The banking hardware, from the header's hardware type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mapper {
+    // Type 0: 8K, 16K or Ultimax ROMs with no banking
+    Normal,
+    // Type 1: Action Replay freezer, with 4 banks of 8K ROM and 8K of RAM,
+    // all switched through $DE00. $DF00-$DFFF shows the last page of the
+    // ROM or RAM at ROML.
+    ActionReplay,
+    // Type 5: up to 64 banks picked by writing $DE00
+    Ocean,
+    // Type 19: up to 128 banks of 8K at $8000 picked by writing $DE00; bit
+    // 7 switches the cartridge off
+    MagicDesk,
+    // Type 32: 64 banks of ROML and ROMH picked by $DE00, EXROM and GAME
+    // set through $DE02, and 256 bytes of RAM at $DF00
+    EasyFlash,
+}
+
+impl Mapper {
+    fn from_hardware(hardware: u16) -> Option<Self> {
+        match hardware {
+            0 => Some(Mapper::Normal),
+            1 => Some(Mapper::ActionReplay),
+            5 => Some(Mapper::Ocean),
+            19 => Some(Mapper::MagicDesk),
+            32 => Some(Mapper::EasyFlash),
+            _ => None,
+        }
+    }
+}
+
+// Serializable, ROMs and all, so a snapshot brings its cartridge with it
+#[derive(Serialize, Deserialize)]
+pub struct Cartridge {
+    pub name: String,
+    pub mapper: Mapper,
+    // 8K ROM banks, indexed by bank number; None where the image has none
+    roml: Vec<Option<Vec<u8>>>,
+    romh: Vec<Option<Vec<u8>>>,
+    // Line levels the cartridge drives, false for pulled low
+    exrom: bool,
+    game: bool,
+    bank: usize,
+    // EasyFlash's RAM at $DF00-$DFFF, or the Action Replay's 8K
+    ram: Vec<u8>,
+    // Action Replay: RAM instead of ROM at ROML, the cartridge switched
+    // off until the next reset, the freeze button's NMI held until the
+    // freezer lets go of it, and whether the CPU has yet to take that NMI
+    ram_enabled: bool,
+    disabled: bool,
+    frozen: bool,
+    freeze_pending: bool,
+}
+
+impl Cartridge {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).context(format!("Failed to read {:?}", path))?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        // Header and packet fields are big-endian
+        let word = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let long = |offset: usize| {
+            u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize
+        };
+
+        if bytes.len() < HEADER_SIZE || &bytes[0..16] != MAGIC {
+            anyhow::bail!("not a cartridge image: no C64 CARTRIDGE header");
+        }
+        let hardware = word(0x16);
+        let mapper = match Mapper::from_hardware(hardware) {
+            Some(mapper) => mapper,
+            None => anyhow::bail!("cartridge hardware type {} isn't supported", hardware),
+        };
+        // The name is padded with zeros
+        let name = bytes[0x20..0x40].iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
+
+        let mut cartridge = Self {
+            name,
+            mapper,
+            roml: Vec::new(),
+            romh: Vec::new(),
+            // The header holds 0 for a line the cartridge pulls low
+            exrom: bytes[0x18] != 0,
+            game: bytes[0x19] != 0,
+            bank: 0,
+            ram: vec![0; match mapper {
+                Mapper::EasyFlash => 0x100,
+                Mapper::ActionReplay => 0x2000,
+                _ => 0,
+            }],
+            ram_enabled: false,
+            disabled: false,
+            frozen: false,
+            freeze_pending: false,
+        };
+
+        // Some images give a shorter header length than the header really is
+        let mut offset = long(0x10).max(HEADER_SIZE);
+        while offset + CHIP_HEADER_SIZE <= bytes.len() {
+            if &bytes[offset..offset + 4] != b"CHIP" {
+                anyhow::bail!("cartridge image has a bad CHIP packet at ${:X}", offset);
+            }
+            let packet_size = long(offset + 4);
+            let size = word(offset + 0x0E) as usize;
+            let data = offset + CHIP_HEADER_SIZE;
+            if data + size > bytes.len() || packet_size < CHIP_HEADER_SIZE {
+                anyhow::bail!("cartridge image is truncated at ${:X}", offset);
+            }
+            cartridge.add_chip(word(offset + 0x0A) as usize, word(offset + 0x0C), &bytes[data..data + size])?;
+            offset += packet_size;
+        }
+        if cartridge.roml.iter().chain(&cartridge.romh).all(Option::is_none) {
+            anyhow::bail!("cartridge image has no ROMs");
+        }
+        Ok(cartridge)
+    }
+
+    // File a CHIP packet's ROM under ROML or ROMH. 4K ROMs show twice in
+    // their 8K window, as their address lines leave A12 unconnected.
+    fn add_chip(&mut self, bank: usize, load_address: u16, data: &[u8]) -> Result<()> {
+        fn put(banks: &mut Vec<Option<Vec<u8>>>, bank: usize, data: &[u8]) {
+            if banks.len() <= bank {
+                banks.resize(bank + 1, None);
+            }
+            banks[bank] = Some(data.iter().cycle().take(BANK_SIZE).copied().collect());
+        }
+
+        match (load_address, data.len()) {
+            (_, 0) => {}
+            (0x8000, 0x4000) => {
+                put(&mut self.roml, bank, &data[..BANK_SIZE]);
+                put(&mut self.romh, bank, &data[BANK_SIZE..]);
+            }
+            (0x8000, 1..=BANK_SIZE) => put(&mut self.roml, bank, data),
+            // The larger Ocean cartridges number the banks they put at
+            // $A000 on from 16, but select them with the same register
+            // values as the ones at $8000
+            (0xA000, 1..=BANK_SIZE) if self.mapper == Mapper::Ocean => put(&mut self.romh, bank & 0x0F, data),
+            (0xA000 | 0xE000 | 0xF000, 1..=BANK_SIZE) => put(&mut self.romh, bank, data),
+            (address, size) => {
+                anyhow::bail!("cartridge ROM of ${:X} bytes at ${:04X} doesn't fit", size, address)
+            }
+        }
+        Ok(())
+    }
+
+    // EXROM and GAME as the cartridge drives them now
+    pub fn lines(&self) -> (bool, bool) {
+        (self.exrom, self.game)
+    }
+
+    // The ROM bank selected
+    pub fn bank(&self) -> usize {
+        self.bank
+    }
+
+    // ROML and ROMH in the selected bank; None where the cartridge has no
+    // ROM there
+    pub fn read_roml(&self, addr: u16) -> Option<u8> {
+        if self.ram_enabled {
+            return Some(self.ram[addr as usize & (BANK_SIZE - 1)]);
+        }
+        Self::bank_rom(&self.roml, self.bank).map(|rom| rom[addr as usize & (BANK_SIZE - 1)])
+    }
+
+    pub fn read_romh(&self, addr: u16) -> Option<u8> {
+        self.romh().map(|rom| rom[addr as usize & (BANK_SIZE - 1)])
+    }
+
+    // The ROM at ROMH in the selected bank. Ocean and Action Replay
+    // cartridges without ROMs of their own there show the ROML ROM.
+    pub fn romh(&self) -> Option<&[u8]> {
+        match Self::bank_rom(&self.romh, self.bank) {
+            None if matches!(self.mapper, Mapper::Ocean | Mapper::ActionReplay) => Self::bank_rom(&self.roml, self.bank),
+            rom => rom,
+        }
+    }
+
+    // A write to ROML, which only the cartridge's RAM takes
+    pub fn write_roml(&mut self, addr: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[addr as usize & (BANK_SIZE - 1)] = value;
+        }
+    }
+
+    fn bank_rom(banks: &[Option<Vec<u8>>], bank: usize) -> Option<&[u8]> {
+        banks.get(bank)?.as_deref()
+    }
+
+    // A read from $DE00-$DFFF; None when nothing on the cartridge answers
+    pub fn read_io(&self, addr: u16) -> Option<u8> {
+        match (self.mapper, addr) {
+            (Mapper::EasyFlash, 0xDF00..=0xDFFF) => Some(self.ram[(addr & 0xFF) as usize]),
+            (Mapper::ActionReplay, 0xDF00..=0xDFFF) if !self.disabled => self.read_roml(0x1F00 | addr),
+            _ => None,
+        }
+    }
+
+    // A write to $DE00-$DFFF, which may switch banks and the EXROM and
+    // GAME lines
+    pub fn write_io(&mut self, addr: u16, value: u8) {
+        match (self.mapper, addr) {
+            (Mapper::Normal, _) => {}
+            (Mapper::Ocean, 0xDE00..=0xDEFF) => self.bank = (value & 0x3F) as usize,
+            (Mapper::MagicDesk, 0xDE00..=0xDEFF) => {
+                self.bank = (value & 0x7F) as usize;
+                self.exrom = value & 0x80 != 0;
+            }
+            (Mapper::EasyFlash, 0xDE00) => self.bank = (value & 0x3F) as usize,
+            (Mapper::EasyFlash, 0xDE02) => {
+                // Bit 2 clear leaves GAME to the boot jumper, which pulls
+                // it low so the machine starts in Ultimax mode
+                self.exrom = value & 0x02 == 0;
+                self.game = value & 0x04 != 0 && value & 0x01 == 0;
+            }
+            (Mapper::EasyFlash, 0xDF00..=0xDFFF) => self.ram[(addr & 0xFF) as usize] = value,
+            (Mapper::ActionReplay, 0xDE00..=0xDEFF) if !self.disabled => {
+                // Bit 0 pulls GAME low and bit 1 lets EXROM go high, so 0
+                // is the 8K mode the cartridge starts in. Bits 3-4 pick the
+                // ROM bank, bit 5 puts the RAM at ROML, bit 6 ends a freeze
+                // and bit 2 switches the cartridge off.
+                self.game = value & 0x01 == 0;
+                self.exrom = value & 0x02 != 0;
+                self.bank = ((value >> 3) & 0x03) as usize;
+                self.ram_enabled = value & 0x20 != 0;
+                if value & 0x40 != 0 {
+                    self.frozen = false;
+                }
+                if value & 0x04 != 0 {
+                    self.disabled = true;
+                    (self.exrom, self.game, self.ram_enabled) = (true, true, false);
+                }
+            }
+            (Mapper::ActionReplay, 0xDF00..=0xDFFF) => self.write_roml(0x1F00 | addr, value),
+            _ => {}
+        }
+        // I/O 2 is cartridge RAM on the types that have it; I/O 1 is registers
+        if addr < 0xDF00 {
+            tracing::debug!(target: "cartridge", "${:04X} <- ${:02X}: bank {} EXROM {} GAME {}", addr, value, self.bank, self.exrom, self.game);
+        }
+    }
+
+    // Press the freeze button, which pulls NMI low. Other cartridges have
+    // no button.
+    pub fn freeze(&mut self) {
+        if self.mapper == Mapper::ActionReplay {
+            tracing::debug!(target: "cartridge", "freeze button pressed");
+            self.frozen = true;
+            self.freeze_pending = true;
+        }
+    }
+
+    // The CPU is taking the freeze button's NMI: a freezer switches to
+    // Ultimax mode on its first bank, with its RAM in, so the vector and
+    // the handler come from its ROM at ROMH
+    pub fn acknowledge_nmi(&mut self) {
+        if self.freeze_pending {
+            (self.exrom, self.game) = (true, false);
+            self.bank = 0;
+            self.ram_enabled = true;
+            self.disabled = false;
+            self.freeze_pending = false;
+        }
+    }
+
+    // Whether the cartridge is holding NMI low
+    pub fn nmi(&self) -> bool {
+        self.frozen
+    }
+}
+
+// An image of a cartridge with the given hardware type, lines and ROMs
+// (bank, load address, data)
+#[cfg(test)]
+pub fn crt_image(hardware: u16, exrom: u8, game: u8, chips: &[(u16, u16, &[u8])]) -> Vec<u8> {
+    let mut bytes = vec![0; HEADER_SIZE];
+    bytes[0..16].copy_from_slice(MAGIC);
+    bytes[0x13] = HEADER_SIZE as u8;
+    bytes[0x14] = 1;
+    bytes[0x16..0x18].copy_from_slice(&hardware.to_be_bytes());
+    bytes[0x18] = exrom;
+    bytes[0x19] = game;
+    bytes[0x20..0x24].copy_from_slice(b"TEST");
+    for &(bank, address, data) in chips {
+        bytes.extend_from_slice(b"CHIP");
+        bytes.extend_from_slice(&((CHIP_HEADER_SIZE + data.len()) as u32).to_be_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&bank.to_be_bytes());
+        bytes.extend_from_slice(&address.to_be_bytes());
+        bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(data);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{C64Memory, Memory};
+
+    #[test]
+    fn test_parse_crt() {
+        let cartridge = Cartridge::parse(&crt_image(0, 0, 1, &[(0, 0x8000, &[0xAA; 0x2000])])).unwrap();
+        assert_eq!(cartridge.name, "TEST");
+        assert_eq!(cartridge.mapper, Mapper::Normal);
+        assert_eq!(cartridge.lines(), (false, true));
+        assert_eq!(cartridge.read_roml(0x8000), Some(0xAA));
+        assert_eq!(cartridge.read_romh(0xA000), None);
+
+        assert!(Cartridge::parse(b"C64 CARTRIDGE").is_err());
+        let mut truncated = crt_image(0, 0, 1, &[(0, 0x8000, &[0xAA; 0x2000])]);
+        truncated.truncate(0x1000);
+        assert!(Cartridge::parse(&truncated).is_err());
+        assert!(Cartridge::parse(&crt_image(2, 0, 1, &[(0, 0x8000, &[0; 0x2000])])).is_err());
+    }
+
+    #[test]
+    fn test_attach_16k_and_ultimax() {
+        let mut rom = vec![0x11; 0x4000];
+        rom[0x2000..].fill(0x22);
+        let mut memory = C64Memory::new();
+        memory.attach_cartridge(Some(Cartridge::parse(&crt_image(0, 0, 0, &[(0, 0x8000, &rom)])).unwrap()));
+        assert_eq!(memory.read(0x8000), 0x11);
+        assert_eq!(memory.read(0xA000), 0x22);
+
+        // A 4K Ultimax ROM at $F000, whose reset vector the CPU starts from
+        let mut rom = vec![0xEA; 0x1000];
+        rom[0xFFC] = 0x00;
+        rom[0xFFD] = 0xF0;
+        let mut memory = C64Memory::new();
+        memory.attach_cartridge(Some(Cartridge::parse(&crt_image(0, 1, 0, &[(0, 0xF000, &rom)])).unwrap()));
+        assert_eq!(memory.read(0xFFFC), 0x00);
+        assert_eq!(memory.read(0xFFFD), 0xF0);
+        assert_eq!(memory.read(0xE000), 0xEA);
+    }
+
+    #[test]
+    fn test_ocean_and_magic_desk_banks() {
+        // 16K Ocean: banks at $8000, and from 16 on at $A000
+        let chips: Vec<(u16, u16, Vec<u8>)> = (0..4)
+            .map(|bank| (bank, 0x8000, vec![bank as u8; 0x2000]))
+            .chain((0..2).map(|bank| (bank + 16, 0xA000, vec![0x80 | bank as u8; 0x2000])))
+            .collect();
+        let chips: Vec<(u16, u16, &[u8])> = chips.iter().map(|(b, a, d)| (*b, *a, d.as_slice())).collect();
+        let mut memory = C64Memory::new();
+        memory.attach_cartridge(Some(Cartridge::parse(&crt_image(5, 0, 0, &chips)).unwrap()));
+        assert_eq!((memory.read(0x8000), memory.read(0xA000)), (0, 0x80));
+        memory.write(0xDE00, 3);
+        assert_eq!(memory.read(0x8000), 3);
+        // No ROMH in this bank: ROML shows there too
+        assert_eq!(memory.read(0xA000), 3);
+        memory.write(0xDE00, 1);
+        assert_eq!((memory.read(0x8000), memory.read(0xA000)), (1, 0x81));
+
+        // Magic Desk: 8K banks, and bit 7 switches the cartridge out
+        let chips: Vec<Vec<u8>> = (0..2).map(|bank| vec![0x40 | bank; 0x2000]).collect();
+        let mut memory = C64Memory::new();
+        memory.write(0x8000, 0x99);
+        let image = crt_image(19, 0, 1, &[(0, 0x8000, &chips[0]), (1, 0x8000, &chips[1])]);
+        memory.attach_cartridge(Some(Cartridge::parse(&image).unwrap()));
+        assert_eq!(memory.read(0x8000), 0x40);
+        memory.write(0xDE00, 1);
+        assert_eq!(memory.read(0x8000), 0x41);
+        memory.write(0xDE00, 0x80);
+        assert_eq!(memory.read(0x8000), 0x99);
+    }
+
+    #[test]
+    fn test_easyflash() {
+        let mut rom = vec![0x10; 0x2000];
+        rom[0x1FFC..].copy_from_slice(&[0x00, 0xE0, 0x00, 0x00]);
+        let image = crt_image(32, 1, 0, &[(0, 0xA000, &rom), (1, 0x8000, &[0x21; 0x2000]), (1, 0xA000, &[0x22; 0x2000])]);
+        let mut memory = C64Memory::new();
+        memory.attach_cartridge(Some(Cartridge::parse(&image).unwrap()));
+
+        // Boots in Ultimax mode from bank 0's ROMH
+        assert_eq!(memory.read(0xFFFD), 0xE0);
+        assert_eq!(memory.read(0x4000), 0xFF);
+
+        // 16K mode on bank 1
+        memory.write(0xDE00, 1);
+        memory.write(0xDE02, 0x07);
+        assert_eq!((memory.read(0x8000), memory.read(0xA000)), (0x21, 0x22));
+        // Cartridge off: RAM shows again
+        memory.write(0xDE02, 0x04);
+        assert_eq!(memory.read(0x8000), 0x00);
+
+        // RAM at $DF00
+        memory.write(0xDF80, 0x5A);
+        assert_eq!(memory.read(0xDF80), 0x5A);
+    }
+
+    #[test]
+    fn test_action_replay_freeze() {
+        use crate::machine::{Machine, VideoStandard};
+
+        // Bank 0's NMI handler at $E100 keeps a byte in the cartridge RAM,
+        // then leaves Ultimax mode for 8K mode with the RAM in, releasing
+        // the NMI, and returns through an RTI in the RAM underneath
+        let mut banks = vec![vec![0; 0x2000]; 4];
+        let handler = [0xA9, 0xE5, 0x8D, 0x00, 0x80, 0xA9, 0x60, 0x8D, 0x00, 0xDE];
+        banks[0][0x0100..0x0100 + handler.len()].copy_from_slice(&handler);
+        banks[0][0x1FFA..0x1FFC].copy_from_slice(&[0x00, 0xE1]);
+        banks[3][0x1F00] = 0x33;
+        let chips: Vec<(u16, u16, &[u8])> = banks.iter().enumerate().map(|(bank, rom)| (bank as u16, 0x8000, rom.as_slice())).collect();
+
+        let mut machine = Machine::new(VideoStandard::Pal);
+        machine.memory.write(0x1000, 0x4C); // JMP $1000
+        machine.memory.write(0x1001, 0x00);
+        machine.memory.write(0x1002, 0x10);
+        machine.memory.write(0xE10A, 0x40); // RTI
+        machine.memory.write(0x8000, 0x80);
+        machine.cpu.pc = 0x1000;
+        machine.memory.attach_cartridge(Some(Cartridge::parse(&crt_image(1, 0, 1, &chips)).unwrap()));
+        assert_eq!(machine.memory.read(0x8000), 0x00);
+
+        // The last page of the selected bank shows at $DF00
+        machine.memory.write(0xDE00, 0x18);
+        assert_eq!(machine.memory.read(0xDF00), 0x33);
+        machine.memory.write(0xDE00, 0x00);
+
+        machine.memory.freeze();
+        machine.step().unwrap();
+        assert!(machine.memory.cartridge_nmi());
+        machine.step().unwrap();
+        assert_eq!(machine.cpu.pc, 0xE100);
+        while machine.cpu.pc != 0x1000 {
+            machine.step().unwrap();
+        }
+        assert!(!machine.memory.cartridge_nmi());
+        assert_eq!(machine.memory.read(0x8000), 0xE5);
+        // Ultimax writes missed the C64's RAM
+        machine.memory.write(0xDE00, 0x02);
+        assert_eq!(machine.memory.read(0x8000), 0x80);
+    }
+}
@@ -1,7 +1,15 @@
-/// CIA (Complex Interface Adapter) chip emulation
-/// The C64 has two CIA chips: CIA1 ($DC00) and CIA2 ($DD00)
-/// These handle keyboard, joystick, timers, and other I/O
+//! CIA (Complex Interface Adapter) chip emulation
+//! The C64 has two CIA chips: CIA1 ($DC00) and CIA2 ($DD00)
+//! These handle keyboard, joystick, timers, and other I/O
 
+use serde::{Deserialize, Serialize};
+
+use crate::interrupt::{InterruptLine, InterruptSource};
+use crate::machine::VideoStandard;
+
+// Serializable for snapshots. Keys, joysticks and gamepads held on the host
+// are input rather than chip state and are left out.
+#[derive(Serialize, Deserialize)]
 pub struct Cia {
     pub pra: u8,  // Port Register A
     pub prb: u8,  // Port Register B
@@ -11,6 +19,7 @@ pub struct Cia {
     pub ta_hi: u8, // Timer A high
     pub tb_lo: u8, // Timer B low
     pub tb_hi: u8, // Timer B high
+    // Time of day clock, in BCD; bit 7 of the hours is PM
     pub tod_10ths: u8,
     pub tod_sec: u8,
     pub tod_min: u8,
@@ -20,14 +29,48 @@ pub struct Cia {
     pub icr_mask: u8,  // Interrupt mask (which interrupts are enabled)
     pub cra: u8,  // Control Register A
     pub crb: u8,  // Control Register B
+    // Levels driven onto the port A pins from outside: the IEC bus on CIA2
+    pub port_a_input: u8,
+    // FLAG input level; a falling edge sets ICR bit 4. CIA1's is the
+    // cassette read line and serial SRQ, CIA2's the user port's pin B.
+    flag: bool,
     
     // Internal state
     timer_a: u16,
     timer_b: u16,
+
+    // TOD alarm (written with CRB bit 7 set), in the same order and format
+    // as the clock registers: 10ths, seconds, minutes, hours
+    tod_alarm: [u8; 4],
+    // Reading the hours freezes what the registers read until the 10ths
+    // are read, so the time can't roll over half way through
+    tod_latch: Option<[u8; 4]>,
+    // Writing the hours stops the clock until the 10ths are written
+    tod_halted: bool,
+    // The mains frequency pulses, worked out from the CPU clock, and the
+    // count of them towards the next 10th of a second
+    clock_hz: u32,
+    mains_hz: u32,
+    tod_phase: u32,
+    tod_pulses: u8,
+
+    // Serial port output (CRA bit 6 set): Timer A underflows left before
+    // the byte in the shift register is out, two per bit, and whether
+    // another byte was written to the SDR behind it
+    sdr_underflows: u8,
+    sdr_pending: bool,
     
     // Keyboard matrix (8x8 = 64 keys)
     // keyboard_matrix[row][col] is a counter: 0 = not pressed, >0 = pressed (frames remaining)
+    #[serde(skip)]
     keyboard_matrix: [[u8; 8]; 8],
+    // Joystick ports 1 and 2, the same kind of counter per switch (up,
+    // down, left, right, fire). Only CIA1 has joysticks attached.
+    #[serde(skip)]
+    joysticks: [[u8; 5]; 2],
+    // Switches held on gamepads in ports 1 and 2, which report releases
+    #[serde(skip)]
+    gamepads: [u8; 2],
 }
 
 impl Cia {
@@ -37,7 +80,7 @@ impl Cia {
             prb: 0xFF,
             ddra: 0,
             ddrb: 0,
-            ta_lo: 0x25,  // Default timer value for 60Hz: $4025 = 16421 cycles
+            ta_lo: 0x25,  // Default timer value for 60Hz: $4025 = 16421 cycles (PAL)
             ta_hi: 0x40,
             tb_lo: 0xFF,
             tb_hi: 0xFF,
@@ -50,179 +93,372 @@ impl Cia {
             icr_mask: 0,  // No interrupts enabled initially
             cra: 0x01,  // Timer A starts running on reset
             crb: 0,
+            port_a_input: 0xFF,
+            flag: true,
             timer_a: 0x4025,  // 16421 cycles for 60Hz at 1MHz
             timer_b: 0xFFFF,
+            tod_alarm: [0; 4],
+            tod_latch: None,
+            tod_halted: false,
+            clock_hz: VideoStandard::Pal.clock_hz(),
+            mains_hz: VideoStandard::Pal.mains_hz(),
+            tod_phase: 0,
+            tod_pulses: 0,
+            sdr_underflows: 0,
+            sdr_pending: false,
             keyboard_matrix: [[0; 8]; 8],  // No keys pressed initially
+            joysticks: [[0; 5]; 2],
+            gamepads: [0; 2],
         }
     }
     
-    pub fn read(&self, addr: u16) -> u8 {
+    // Take on the chip state in a snapshot, keeping what the host holds down
+    pub fn restore(&mut self, snapshot: Cia) {
+        let old = std::mem::replace(self, snapshot);
+        self.keyboard_matrix = old.keyboard_matrix;
+        self.joysticks = old.joysticks;
+        self.gamepads = old.gamepads;
+    }
+    
+    // Load the 60Hz jiffy timer value for this machine's clock, and take
+    // the TOD clock's pulses from the matching mains frequency
+    pub fn set_standard(&mut self, standard: VideoStandard) {
+        let timer = standard.jiffy_timer();
+        self.ta_lo = (timer & 0xFF) as u8;
+        self.ta_hi = (timer >> 8) as u8;
+        self.timer_a = timer;
+        self.clock_hz = standard.clock_hz();
+        self.mains_hz = standard.mains_hz();
+    }
+    
+    // Register value without side effects (debugger, UI)
+    pub fn peek(&self, addr: u16) -> u8 {
         match addr & 0x0F {
-            0x00 => self.pra,
-            0x01 => {
-                // Port B reads keyboard matrix based on Port A row selection
-                // Each bit in PRA selects a row (active low)
-                // Return column states (active low) in PRB
-                let result = self.read_keyboard_columns();
-                
-                result
-            },
+            // The ports read their pins, not the output registers
+            0x00 => self.port_a_pins(),
+            0x01 => self.port_b_pins(),
             0x02 => self.ddra,
             0x03 => self.ddrb,
             0x04 => (self.timer_a & 0xFF) as u8,  // Timer A low byte (current value, not latch)
             0x05 => ((self.timer_a >> 8) & 0xFF) as u8,  // Timer A high byte (current value)
             0x06 => (self.timer_b & 0xFF) as u8,  // Timer B low byte (current value, not latch)
             0x07 => ((self.timer_b >> 8) & 0xFF) as u8,  // Timer B high byte (current value)
-            0x08 => self.tod_10ths,
-            0x09 => self.tod_sec,
-            0x0A => self.tod_min,
-            0x0B => self.tod_hr,
+            0x08..=0x0B => self.tod_latch.unwrap_or_else(|| self.tod())[(addr & 0x03) as usize],
             0x0C => self.sdr,
-            0x0D => {
-                // Reading ICR returns current interrupts and clears them
-                let result = self.icr;
-                // Note: We can't clear here because we need mutable access
-                // The caller should call clear_icr() after reading
-                result
-            }
+            0x0D => self.icr,
             0x0E => self.cra,
             0x0F => self.crb,
             _ => 0,
         }
     }
     
-    // pub fn clear_icr(&mut self) {
-    //     // Reading ICR clears the interrupt flags (except bit 7 which indicates "interrupt occurred")
-    //     self.icr &= 0x80; // Keep only bit 7
-    // }
-    
-    // pub fn has_interrupt(&self) -> bool {
-    //     // Check if any enabled interrupt has occurred
-    //     // An interrupt fires if: (icr & icr_mask & 0x7F) != 0
-    //     (self.icr & self.icr_mask & 0x7F) != 0
-    // }
+    // CPU read. Reading the ICR returns the latched interrupt flags and
+    // clears them, which acknowledges the interrupt and releases the line.
+    pub fn read(&mut self, addr: u16) -> u8 {
+        let value = self.peek(addr);
+        match addr & 0x0F {
+            0x08 => self.tod_latch = None,
+            0x0B => self.tod_latch = Some(self.tod_latch.unwrap_or_else(|| self.tod())),
+            0x0D => self.icr = 0,
+            _ => {}
+        }
+        value
+    }
     
     pub fn write(&mut self, addr: u16, value: u8) {
+        tracing::trace!(target: "cia", "${:04X} <- ${:02X}", addr & 0xFF0F, value);
         match addr & 0x0F {
             0x00 => self.pra = value,
             0x01 => self.prb = value,
             0x02 => self.ddra = value,
             0x03 => self.ddrb = value,
-            0x04 => {
-                self.ta_lo = value;
-                self.timer_a = (self.timer_a & 0xFF00) | (value as u16);
-            }
+            // Timer writes go to the latch, and reach a stopped timer's
+            // counter with the high byte
+            0x04 => self.ta_lo = value,
             0x05 => {
                 self.ta_hi = value;
-                self.timer_a = ((value as u16) << 8) | (self.timer_a & 0x00FF);
-                // CIA Timer A configured (removed debug print to avoid TUI interference)
-            }
-            0x06 => {
-                self.tb_lo = value;
-                self.timer_b = (self.timer_b & 0xFF00) | (value as u16);
+                if self.cra & 0x01 == 0 {
+                    self.timer_a = self.latch_a();
+                }
             }
+            0x06 => self.tb_lo = value,
             0x07 => {
                 self.tb_hi = value;
-                self.timer_b = ((value as u16) << 8) | (self.timer_b & 0x00FF);
+                if self.crb & 0x01 == 0 {
+                    self.timer_b = self.latch_b();
+                }
+            }
+            0x08..=0x0B => self.write_tod(addr, value),
+            0x0C => {
+                self.sdr = value;
+                if self.cra & 0x40 != 0 {
+                    if self.sdr_underflows == 0 {
+                        self.sdr_underflows = 16;
+                    } else {
+                        self.sdr_pending = true;
+                    }
+                }
             }
-            0x08 => self.tod_10ths = value,
-            0x09 => self.tod_sec = value,
-            0x0A => self.tod_min = value,
-            0x0B => self.tod_hr = value,
-            0x0C => self.sdr = value,
             0x0D => {
                 // ICR mask register write
                 // Bit 7 = 1: SET interrupt mask bits
                 // Bit 7 = 0: CLEAR interrupt mask bits
                 if value & 0x80 != 0 {
-                    // Set mask bits
+                    // Set mask bits. A source already latched raises IR
+                    // as soon as it is enabled.
                     self.icr_mask |= value & 0x7F;
+                    if self.icr & self.icr_mask != 0 {
+                        self.icr |= 0x80;
+                    }
                 } else {
                     // Clear mask bits
                     self.icr_mask &= !(value & 0x7F);
                 }
             }
             0x0E => {
-                self.cra = value;
+                // Turning the serial port round abandons any byte in transit
+                if (self.cra ^ value) & 0x40 != 0 {
+                    self.sdr_underflows = 0;
+                    self.sdr_pending = false;
+                }
+                // Bit 4 strobes a load of the counter from the latch and
+                // isn't stored
+                self.cra = value & !0x10;
+                if value & 0x10 != 0 {
+                    self.timer_a = self.latch_a();
+                }
             }
             0x0F => {
-                self.crb = value;
-                // CIA Timer B control register (removed debug print)
+                self.crb = value & !0x10;
+                if value & 0x10 != 0 {
+                    self.timer_b = self.latch_b();
+                }
             }
             _ => {}
         }
     }
     
-    pub fn tick(&mut self, cycles: u8) -> bool {
-        let mut irq = false;
-
-        // Timer A handling
-        if self.cra & 0x01 != 0 {
-            // Timer A is running
-            if self.timer_a > cycles as u16 {
-                self.timer_a -= cycles as u16;
-            } else {
-                // Timer underflowed - reload from latch and set interrupt
-                self.timer_a = ((self.ta_hi as u16) << 8) | (self.ta_lo as u16);
-                self.icr |= 0x81; // Set bit 0 (timer A) and bit 7 (interrupt occurred)
-                
-                // Check if interrupt enabled
-                if (self.icr_mask & 0x01) != 0 {
-                    irq = true;
+    // Set the TOD clock, running, to a 24 hour time
+    pub fn set_time_of_day(&mut self, hours: u8, minutes: u8, seconds: u8, tenths: u8) {
+        let pm = if hours >= 12 { 0x80 } else { 0 };
+        let hour = match hours % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        self.tod_hr = bcd(hour) | pm;
+        self.tod_min = bcd(minutes);
+        self.tod_sec = bcd(seconds);
+        self.tod_10ths = tenths;
+        self.tod_pulses = 0;
+        self.tod_latch = None;
+        self.tod_halted = false;
+    }
+
+    // The clock registers, 10ths first
+    fn tod(&self) -> [u8; 4] {
+        [self.tod_10ths, self.tod_sec, self.tod_min, self.tod_hr]
+    }
+
+    // With CRB bit 7 set, writes go to the alarm rather than the clock.
+    // Unused bits always read as 0.
+    fn write_tod(&mut self, addr: u16, value: u8) {
+        let reg = (addr & 0x03) as usize;
+        let value = value & [0x0F, 0x7F, 0x7F, 0x9F][reg];
+        if self.crb & 0x80 != 0 {
+            self.tod_alarm[reg] = value;
+        } else {
+            match reg {
+                0 => {
+                    self.tod_10ths = value;
+                    self.tod_halted = false;
+                }
+                1 => self.tod_sec = value,
+                2 => self.tod_min = value,
+                _ => {
+                    self.tod_hr = value;
+                    self.tod_halted = true;
                 }
+            }
+        }
+        self.check_tod_alarm();
+    }
 
-                // Check if timer should stop (one-shot mode, bit 3 of CRA)
+    // Count a 10th of a second, carrying through the BCD digits. The hours
+    // run 1-12, flipping between AM and PM on the way from 11 to 12.
+    fn advance_tod(&mut self) {
+        self.tod_10ths = (self.tod_10ths + 1) % 10;
+        if self.tod_10ths != 0 {
+            return;
+        }
+        self.tod_sec = bcd_increment(self.tod_sec, 0x60);
+        if self.tod_sec != 0 {
+            return;
+        }
+        self.tod_min = bcd_increment(self.tod_min, 0x60);
+        if self.tod_min != 0 {
+            return;
+        }
+        let pm = self.tod_hr & 0x80;
+        self.tod_hr = match self.tod_hr & 0x1F {
+            0x11 => 0x12 | (pm ^ 0x80),
+            0x12 => 0x01 | pm,
+            hour => bcd_increment(hour, 0x13) | pm,
+        };
+    }
+
+    fn check_tod_alarm(&mut self) {
+        if self.tod() == self.tod_alarm {
+            self.raise_interrupt(0x04);
+        }
+    }
+
+    fn latch_a(&self) -> u16 {
+        ((self.ta_hi as u16) << 8) | self.ta_lo as u16
+    }
+
+    fn latch_b(&self) -> u16 {
+        ((self.tb_hi as u16) << 8) | self.tb_lo as u16
+    }
+
+    // One cycle of the timers. A timer counts down to 0 and is reloaded
+    // from its latch on its next count, which is when it underflows: a
+    // latch of N underflows every N + 1 counts. One-shot mode (bit 3)
+    // stops the timer there, reloaded.
+    fn clock_timers(&mut self) {
+        // Timer A counts cycles, or with CRA bit 5 set CNT edges, which
+        // nothing on the C64's CNT pins provides
+        let mut a_underflow = false;
+        if self.cra & 0x21 == 0x01 {
+            if self.timer_a == 0 {
+                self.timer_a = self.latch_a();
+                a_underflow = true;
+                self.raise_interrupt(0x01); // Set bit 0 (timer A)
+                self.shift_serial();
                 if self.cra & 0x08 != 0 {
-                    self.cra &= !0x01; // Stop timer
+                    self.cra &= !0x01;
                 }
+            } else {
+                self.timer_a -= 1;
             }
         }
-        
-        // Timer B handling
-        if self.crb & 0x01 != 0 {
-            // Timer B is running
-            if self.timer_b > cycles as u16 {
-                self.timer_b -= cycles as u16;
-            } else {
-                // Timer underflowed - reload from latch and set interrupt
-                self.timer_b = ((self.tb_hi as u16) << 8) | (self.tb_lo as u16);
-                self.icr |= 0x82; // Set bit 1 (timer B) and bit 7 (interrupt occurred)
-                
-                // Check if interrupt enabled
-                if (self.icr_mask & 0x02) != 0 {
-                    irq = true;
-                }
 
-                // Check if timer should stop (one-shot mode, bit 3 of CRB)
+        // Timer B counts cycles, CNT edges, or Timer A underflows (with or
+        // without CNT high, which it idles at)
+        let count_b = match self.crb & 0x60 {
+            0x00 => true,
+            0x20 => false,
+            _ => a_underflow,
+        };
+        if self.crb & 0x01 != 0 && count_b {
+            if self.timer_b == 0 {
+                self.timer_b = self.latch_b();
+                self.raise_interrupt(0x02); // Set bit 1 (timer B)
                 if self.crb & 0x08 != 0 {
-                    self.crb &= !0x01; // Stop timer
+                    self.crb &= !0x01;
                 }
+            } else {
+                self.timer_b -= 1;
             }
         }
+    }
 
-        irq
+    // In output mode each Timer A underflow toggles CNT, and a bit goes out
+    // on every other one. Once all 8 are out ICR bit 3 is set and a byte
+    // written meanwhile follows straight on. In input mode the bits would
+    // come in on CNT from the user port, where nothing is connected.
+    fn shift_serial(&mut self) {
+        if self.cra & 0x40 == 0 || self.sdr_underflows == 0 {
+            return;
+        }
+        self.sdr_underflows -= 1;
+        if self.sdr_underflows == 0 {
+            self.raise_interrupt(0x08);
+            if self.sdr_pending {
+                self.sdr_pending = false;
+                self.sdr_underflows = 16;
+            }
+        }
+    }
+
+    // Latch an interrupt source's flag in the ICR, and IR too if the
+    // source is enabled
+    fn raise_interrupt(&mut self, flag: u8) {
+        self.icr |= flag;
+        if self.icr_mask & flag != 0 {
+            self.icr |= 0x80;
+        }
+    }
+
+    // Advance the timers and drive this CIA's interrupt output (CIA1 is wired
+    // to IRQ, CIA2 to NMI) onto `line`
+    pub fn tick(&mut self, cycles: u8, line: &mut InterruptLine, source: InterruptSource) {
+        // The TOD clock counts mains pulses, 5 (CRA bit 7 set, 50 Hz) or 6
+        // (60 Hz) to the 10th of a second; set wrong, it runs fast or slow
+        self.tod_phase += cycles as u32 * self.mains_hz;
+        while self.tod_phase >= self.clock_hz {
+            self.tod_phase -= self.clock_hz;
+            self.tod_pulses += 1;
+            let divider = if self.cra & 0x80 != 0 { 5 } else { 6 };
+            if self.tod_pulses >= divider {
+                self.tod_pulses = 0;
+                if !self.tod_halted {
+                    self.advance_tod();
+                    self.check_tod_alarm();
+                }
+            }
+        }
+
+        for _ in 0..cycles {
+            self.clock_timers();
+        }
+
+        // The interrupt output follows IR and stays asserted until the CPU
+        // acknowledges it by reading the ICR
+        line.set(source, self.icr & 0x80 != 0);
     }
     
+    // Levels on the port A pins: PRA where DDRA selects output, and the
+    // pull-ups (1) on the input pins
+    pub fn port_a_output(&self) -> u8 {
+        self.pra | !self.ddra
+    }
+    
+    pub fn port_b_output(&self) -> u8 {
+        self.prb | !self.ddrb
+    }
+
+    // What can pull the port lines low besides the CIA itself: port 2's
+    // joystick on port A, port 1's on port B, the serial bus (CIA2 port
+    // A), and the keyboard. A held key connects its row (port A) to its
+    // column (port B), so a line held low on either side pulls the other
+    // down too; the KERNAL scans rows, some games scan columns.
+    fn port_a_pins(&self) -> u8 {
+        let columns = self.port_b_output() & self.joystick_lines(1);
+        self.port_a_output() & self.port_a_input & self.joystick_lines(2) & self.keyboard_lines(columns, true)
+    }
+
+    fn port_b_pins(&self) -> u8 {
+        let rows = self.port_a_output() & self.port_a_input & self.joystick_lines(2);
+        self.port_b_output() & self.joystick_lines(1) & self.keyboard_lines(rows, false)
+    }
+
     // Keyboard matrix methods
-    pub fn read_keyboard_columns(&self) -> u8 {
-        // PRA selects rows (active low - 0 means selected)
-        // Return PRB with columns (active low - 0 means key pressed)
-        let mut result = 0xFF; // All keys up by default
-        
-        // Check each row
+
+    // The lines on one side of the matrix that held keys connect to the
+    // other side's low `lines`: rows from columns, or columns from rows
+    fn keyboard_lines(&self, lines: u8, rows: bool) -> u8 {
+        let mut result = 0xFF;
         for row in 0..8 {
-            // If this row is selected (bit is 0 in PRA)
-            if (self.pra & (1 << row)) == 0 {
-                // Check each column in this row
-                for col in 0..8 {
-                    if self.keyboard_matrix[row][col] > 0 {
-                        // Key is pressed - clear the bit (active low)
-                        result &= !(1 << col);
-                    }
+            for col in 0..8 {
+                if self.keyboard_matrix[row][col] == 0 {
+                    continue;
+                }
+                let (from, to) = if rows { (col, row) } else { (row, col) };
+                if lines & (1 << from) == 0 {
+                    result &= !(1 << to);
                 }
             }
         }
-        
         result
     }
     
@@ -238,6 +474,37 @@ impl Cia {
         }
     }
     
+    // Close the `switches` (keyboard::joystick bits) of joystick `port`
+    // (1 or 2), held for as long as a key press
+    pub fn set_joystick(&mut self, port: u8, switches: u8) {
+        for (bit, held) in self.joysticks[port as usize - 1].iter_mut().enumerate() {
+            if switches & (1 << bit) != 0 {
+                *held = 5;
+            }
+        }
+    }
+
+    // Drive the FLAG input high or low
+    pub fn set_flag(&mut self, high: bool) {
+        if self.flag && !high {
+            self.raise_interrupt(0x10);
+        }
+        self.flag = high;
+    }
+
+    // The switches held on a gamepad in joystick `port`, until the next call
+    #[cfg(feature = "gamepad")]
+    pub fn set_gamepad(&mut self, port: u8, switches: u8) {
+        self.gamepads[port as usize - 1] = switches;
+    }
+
+    // Joystick `port` as the port lines see it: 0 for a closed switch
+    fn joystick_lines(&self, port: u8) -> u8 {
+        let held = self.joysticks[port as usize - 1];
+        let keys = (0..5).filter(|&bit| held[bit] > 0).fold(0, |switches, bit| switches | (1 << bit));
+        !(keys | self.gamepads[port as usize - 1])
+    }
+
     pub fn decay_keyboard(&mut self) {
         for row in 0..8 {
             for col in 0..8 {
@@ -246,6 +513,9 @@ impl Cia {
                 }
             }
         }
+        for held in self.joysticks.iter_mut().flatten() {
+            *held = held.saturating_sub(1);
+        }
     }
     
     // Legacy support for clear_keyboard, now just calls decay
@@ -253,3 +523,340 @@ impl Cia {
         self.decay_keyboard();
     }
 }
+
+fn bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+// Add one to a BCD number, wrapping to 0 at `limit` (also BCD)
+fn bcd_increment(value: u8, limit: u8) -> u8 {
+    let value = if value & 0x0F >= 9 { (value & 0xF0) + 0x10 } else { value + 1 };
+    if value >= limit { 0 } else { value }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run the TOD clock for `tenths` 10ths of a second of mains pulses
+    fn run_tod(cia: &mut Cia, line: &mut InterruptLine, tenths: u32) {
+        let cycles = (VideoStandard::Pal.clock_hz() * tenths).div_ceil(10);
+        for _ in 0..cycles {
+            cia.tick(1, line, InterruptSource::Cia1);
+        }
+    }
+
+    #[test]
+    fn test_tod_clock() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        cia.write(0xDC0E, 0x80); // 50 Hz, as on a PAL machine
+
+        // Set 11:59:59.8 AM: the clock stops at the hours and starts again
+        // at the 10ths
+        cia.write(0xDC0B, 0x11);
+        cia.write(0xDC0A, 0x59);
+        cia.write(0xDC09, 0x59);
+        run_tod(&mut cia, &mut irq, 5);
+        assert_eq!(cia.peek(0xDC09), 0x59);
+        cia.write(0xDC08, 0x08);
+        run_tod(&mut cia, &mut irq, 3);
+        assert_eq!([cia.peek(0xDC0B), cia.peek(0xDC0A), cia.peek(0xDC09), cia.peek(0xDC08)], [0x92, 0x00, 0x00, 0x01]);
+
+        // Reading the hours latches the time until the 10ths are read
+        assert_eq!(cia.read(0xDC0B), 0x92);
+        run_tod(&mut cia, &mut irq, 10);
+        assert_eq!(cia.read(0xDC09), 0x00);
+        assert_eq!(cia.read(0xDC08), 0x01);
+        assert_eq!(cia.read(0xDC09), 0x01);
+
+        // 12 PM rolls over to 1 PM
+        cia.write(0xDC0B, 0x92);
+        cia.write(0xDC0A, 0x59);
+        cia.write(0xDC09, 0x59);
+        cia.write(0xDC08, 0x09);
+        run_tod(&mut cia, &mut irq, 1);
+        assert_eq!(cia.peek(0xDC0B), 0x81);
+
+        // With the 60 Hz divider on 50 Hz mains the clock runs slow
+        cia.write(0xDC0E, 0x00);
+        cia.write(0xDC08, 0x00);
+        run_tod(&mut cia, &mut irq, 6);
+        assert_eq!(cia.peek(0xDC08), 0x05);
+    }
+
+    #[test]
+    fn test_set_time_of_day() {
+        let mut cia = Cia::new();
+        let time = |cia: &Cia| [cia.peek(0xDC0B), cia.peek(0xDC0A), cia.peek(0xDC09), cia.peek(0xDC08)];
+        cia.set_time_of_day(13, 5, 49, 7);
+        assert_eq!(time(&cia), [0x81, 0x05, 0x49, 0x07]);
+        cia.set_time_of_day(0, 30, 0, 0);
+        assert_eq!(time(&cia), [0x12, 0x30, 0x00, 0x00]);
+        cia.set_time_of_day(12, 0, 0, 0);
+        assert_eq!(time(&cia), [0x92, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_tod_alarm() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        cia.write(0xDC0E, 0x80);
+        cia.write(0xDC0D, 0x84); // Enable the alarm interrupt
+
+        // Alarm at 1:00:01.0 AM, the clock from 1:00:00.0 AM
+        cia.write(0xDC0F, 0x80);
+        cia.write(0xDC0B, 0x01);
+        cia.write(0xDC0A, 0x00);
+        cia.write(0xDC09, 0x01);
+        cia.write(0xDC08, 0x00);
+        cia.write(0xDC0F, 0x00);
+        cia.write(0xDC0B, 0x01);
+        cia.write(0xDC08, 0x00);
+        // The alarm is compared on writes too, and matched the clock while
+        // it was being set
+        cia.read(0xDC0D);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+
+        run_tod(&mut cia, &mut irq, 9);
+        assert!(!irq.is_asserted());
+        run_tod(&mut cia, &mut irq, 1);
+        assert!(irq.is_asserted_by(InterruptSource::Cia1));
+        assert_eq!(cia.read(0xDC0D), 0x84);
+    }
+
+    #[test]
+    fn test_timer_reload() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        cia.write(0xDC0E, 0x00);
+        // With the timer stopped the high byte loads the counter
+        cia.write(0xDC04, 0x03);
+        cia.write(0xDC05, 0x00);
+        assert_eq!(cia.peek(0xDC04), 0x03);
+
+        // 3, 2, 1, 0, then the reload from the latch is the underflow
+        cia.write(0xDC0E, 0x01);
+        let mut seen = Vec::new();
+        for _ in 0..5 {
+            cia.tick(1, &mut irq, InterruptSource::Cia1);
+            seen.push((cia.peek(0xDC04), cia.peek(0xDC0D) & 0x01));
+        }
+        assert_eq!(seen, [(2, 0), (1, 0), (0, 0), (3, 1), (2, 1)]);
+
+        // Running, the high byte only sets the latch, until a force load
+        cia.write(0xDC04, 0x10);
+        cia.write(0xDC05, 0x00);
+        assert_eq!(cia.peek(0xDC04), 2);
+        cia.write(0xDC0E, 0x11);
+        assert_eq!((cia.peek(0xDC04), cia.peek(0xDC0E)), (0x10, 0x01));
+
+        // One-shot: stops at the underflow, reloaded
+        cia.write(0xDC0E, 0x19);
+        cia.tick(17, &mut irq, InterruptSource::Cia1);
+        assert_eq!((cia.peek(0xDC04), cia.peek(0xDC0E) & 0x01), (0x10, 0));
+        cia.tick(5, &mut irq, InterruptSource::Cia1);
+        assert_eq!(cia.peek(0xDC04), 0x10);
+    }
+
+    #[test]
+    fn test_timer_b_counts_timer_a() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        // Timer A underflows every 10 cycles, Timer B every 3 of those
+        cia.write(0xDC04, 0x09);
+        cia.write(0xDC05, 0x00);
+        cia.write(0xDC06, 0x02);
+        cia.write(0xDC07, 0x00);
+        cia.write(0xDC0F, 0x51);
+        cia.write(0xDC0E, 0x11);
+        cia.read(0xDC0D);
+        cia.tick(29, &mut irq, InterruptSource::Cia1);
+        assert_eq!(cia.read(0xDC0D) & 0x02, 0);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert_eq!(cia.read(0xDC0D) & 0x02, 0x02);
+
+        // In CNT mode Timer A waits for edges that never come
+        cia.write(0xDC0E, 0x21);
+        cia.tick(100, &mut irq, InterruptSource::Cia1);
+        assert_eq!(cia.read(0xDC0D), 0);
+    }
+
+    #[test]
+    fn test_flag_interrupt() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        // A short low pulse, the way handshakes and the datasette signal
+        cia.set_flag(false);
+        cia.set_flag(true);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(!irq.is_asserted());
+        assert_eq!(cia.read(0xDC0D), 0x10);
+
+        // Only the falling edge counts
+        cia.write(0xDC0D, 0x90);
+        cia.set_flag(false);
+        assert_eq!(cia.read(0xDC0D), 0x90);
+        cia.set_flag(false);
+        cia.set_flag(true);
+        assert_eq!(cia.read(0xDC0D), 0x00);
+        cia.set_flag(false);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(irq.is_asserted_by(InterruptSource::Cia1));
+    }
+
+    #[test]
+    fn test_enabling_latched_interrupt() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        // The FLAG edge is latched while its interrupt is disabled
+        cia.set_flag(false);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(!irq.is_asserted());
+
+        // Enabling another source leaves IR clear, enabling FLAG sets it
+        cia.write(0xDC0D, 0x81);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(!irq.is_asserted());
+        cia.write(0xDC0D, 0x90);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(irq.is_asserted_by(InterruptSource::Cia1));
+        assert_eq!(cia.read(0xDC0D), 0x90);
+
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(!irq.is_asserted());
+    }
+
+    #[test]
+    fn test_ports_follow_ddr() {
+        let mut cia = Cia::new();
+        // Input pins read high from the pull-ups, whatever was written
+        cia.write(0xDC00, 0x00);
+        cia.write(0xDC01, 0x00);
+        assert_eq!((cia.read(0xDC00), cia.read(0xDC01)), (0xFF, 0xFF));
+        cia.write(0xDC02, 0x0F);
+        cia.write(0xDC03, 0xF0);
+        assert_eq!((cia.read(0xDC00), cia.read(0xDC01)), (0xF0, 0x0F));
+
+        // The KERNAL's way: rows out on port A, columns in on port B
+        cia.write(0xDC02, 0xFF);
+        cia.write(0xDC03, 0x00);
+        cia.set_key(3, 5, true);
+        cia.write(0xDC00, !0x08);
+        assert_eq!(cia.read(0xDC01), !0x20);
+        cia.write(0xDC00, !0x04);
+        assert_eq!(cia.read(0xDC01), 0xFF);
+
+        // Turned round: columns out on port B, rows in on port A
+        cia.write(0xDC02, 0x00);
+        cia.write(0xDC03, 0xFF);
+        cia.write(0xDC01, !0x20);
+        assert_eq!(cia.read(0xDC00), !0x08);
+    }
+
+    #[test]
+    fn test_joysticks() {
+        use crate::keyboard::joystick::{FIRE, LEFT, UP};
+        let mut cia = Cia::new();
+        cia.write(0xDC02, 0xFF); // Port A drives the keyboard rows
+        cia.write(0xDC00, 0x7F);
+        assert_eq!((cia.read(0xDC00), cia.read(0xDC01)), (0x7F, 0xFF));
+
+        cia.set_joystick(2, UP | FIRE);
+        cia.set_joystick(1, LEFT);
+        assert_eq!((cia.read(0xDC00), cia.read(0xDC01)), (0x6E, 0xFB));
+
+        // Held for a few frames, as a key press is
+        for _ in 0..5 {
+            cia.decay_keyboard();
+        }
+        assert_eq!((cia.read(0xDC00), cia.read(0xDC01)), (0x7F, 0xFF));
+
+        // Joystick 2 selects keyboard rows like port A does: up with
+        // nothing scanned reads the row 0 keys
+        cia.write(0xDC00, 0xFF);
+        cia.set_key(0, 1, true);
+        assert_eq!(cia.read(0xDC01), 0xFF);
+        cia.set_joystick(2, UP);
+        assert_eq!(cia.read(0xDC01), 0xFD);
+    }
+
+    #[test]
+    fn test_serial_output() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        cia.write(0xDC04, 0x0F); // Underflow every 16 cycles
+        cia.write(0xDC05, 0x00);
+        cia.write(0xDC0D, 0x88); // Enable the serial port interrupt
+        cia.write(0xDC0E, 0x51); // Serial port out, force load and start
+
+        // 8 bits at two underflows each, then the byte written second
+        cia.write(0xDC0C, 0x55);
+        cia.write(0xDC0C, 0xAA);
+        for _ in 0..15 {
+            cia.tick(16, &mut irq, InterruptSource::Cia1);
+        }
+        assert_eq!(cia.read(0xDC0D) & 0x08, 0);
+        cia.tick(16, &mut irq, InterruptSource::Cia1);
+        assert!(irq.is_asserted_by(InterruptSource::Cia1));
+        assert_eq!(cia.read(0xDC0D), 0x89);
+        for _ in 0..16 {
+            cia.tick(16, &mut irq, InterruptSource::Cia1);
+        }
+        assert_eq!(cia.read(0xDC0D), 0x89);
+
+        // Nothing more to send, and nothing at all in input mode
+        for _ in 0..16 {
+            cia.tick(16, &mut irq, InterruptSource::Cia1);
+        }
+        assert_eq!(cia.read(0xDC0D), 0x01);
+        cia.write(0xDC0E, 0x01);
+        cia.write(0xDC0C, 0x55);
+        for _ in 0..16 {
+            cia.tick(16, &mut irq, InterruptSource::Cia1);
+        }
+        assert_eq!(cia.read(0xDC0D), 0x01);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        cia.write(0xDC0D, 0x81); // Timer A interrupt
+        cia.write(0xDC04, 0x00);
+        cia.write(0xDC05, 0x10);
+        cia.write(0xDC0E, 0x91); // 50 Hz TOD, force load and start
+        cia.write(0xDC0F, 0x80);
+        cia.write(0xDC0B, 0x02); // Alarm at 2 AM
+        cia.write(0xDC0A, 0x00);
+        cia.write(0xDC09, 0x00);
+        cia.write(0xDC08, 0x00);
+        cia.write(0xDC0F, 0x00);
+        cia.set_time_of_day(1, 59, 59, 9);
+        cia.read(0xDC0B); // Latch the clock mid-read
+        cia.tick(100, &mut irq, InterruptSource::Cia1);
+        cia.write(0xDC02, 0xFF); // Scan every row
+        cia.write(0xDC00, 0x00);
+        cia.set_key(0, 0, true);
+
+        let json = serde_json::to_string(&cia).unwrap();
+        let mut restored: Cia = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.latch_a(), 0x1000);
+        assert_eq!(restored.peek(0xDC04), cia.peek(0xDC04));
+        assert_eq!(restored.peek(0xDC0B), 0x01);
+        assert_eq!(restored.peek(0xDC0D), cia.peek(0xDC0D));
+        // Keys held on the host aren't part of the chip
+        assert_ne!(cia.port_b_pins(), 0xFF);
+        assert_eq!(restored.port_b_pins(), 0xFF);
+
+        // Both reach the alarm and the next underflow together
+        let mut restored_irq = InterruptLine::new();
+        run_tod(&mut cia, &mut irq, 2);
+        run_tod(&mut restored, &mut restored_irq, 2);
+        assert_eq!(restored.read(0xDC08), cia.read(0xDC08));
+        assert_eq!(restored.peek(0xDC04), cia.peek(0xDC04));
+        let icr = cia.read(0xDC0D);
+        assert_eq!(icr & 0x04, 0x04);
+        assert_eq!(restored.read(0xDC0D), icr);
+    }
+}
@@ -0,0 +1,75 @@
+// Settings read from a TOML file, ~/.go64/config.toml unless --config names
+// another, for what users set once rather than on every command line:
+//
+//     storage_dir = "/media/c64/1541"
+//     rom_dir = "~/c64/roms"
+//
+// Options given on the command line win over the file. A leading ~ in a
+// path is the home directory, and relative paths are taken from the
+// file's folder.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+const CONFIG_FILE: &str = ".go64/config.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The folder drive 8 uses, instead of ~/.go64/1541
+    pub storage_dir: Option<PathBuf>,
+    /// Where basic.rom, kernal.rom and char.rom are, instead of ./roms
+    pub rom_dir: Option<PathBuf>,
+}
+
+impl Config {
+    // The file given, which must exist, or the default one if there is one
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match dirs::home_dir().map(|home| home.join(CONFIG_FILE)) {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let text = fs::read_to_string(&path).context(format!("Failed to read {:?}", path))?;
+        Self::parse(&text, path.parent().unwrap_or(Path::new("")), dirs::home_dir().as_deref())
+            .context(format!("Failed to read config {:?}", path))
+    }
+
+    fn parse(text: &str, base: &Path, home: Option<&Path>) -> Result<Self> {
+        let mut config: Self = toml::from_str(text)?;
+        for dir in [&mut config.storage_dir, &mut config.rom_dir].into_iter().flatten() {
+            *dir = resolve(dir, base, home)?;
+        }
+        Ok(config)
+    }
+}
+
+fn resolve(path: &Path, base: &Path, home: Option<&Path>) -> Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rest) => Ok(home.ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?.join(rest)),
+        Err(_) => Ok(base.join(path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let base = Path::new("/etc/go64");
+        let home = Some(Path::new("/home/c64"));
+        let config = Config::parse("storage_dir = \"~/disks\"\nrom_dir = \"roms\"\n", base, home).unwrap();
+        assert_eq!(config.storage_dir, Some(PathBuf::from("/home/c64/disks")));
+        assert_eq!(config.rom_dir, Some(PathBuf::from("/etc/go64/roms")));
+
+        let config = Config::parse("rom_dir = \"/opt/c64\"", base, home).unwrap();
+        assert_eq!((config.storage_dir, config.rom_dir), (None, Some(PathBuf::from("/opt/c64"))));
+        assert!(Config::parse("rom_directory = \"roms\"", base, home).is_err());
+    }
+}
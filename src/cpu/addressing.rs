@@ -3,10 +3,10 @@
 use crate::cpu::Cpu;
 use crate::memory::Memory;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AddressingMode {
-    // Implied,
-    // Accumulator,
+    Implied,
+    Accumulator,
     Immediate,
     ZeroPage,
     ZeroPageX,
@@ -20,14 +20,38 @@ pub enum AddressingMode {
     Relative,
 }
 
+impl AddressingMode {
+    // Instruction length in bytes (opcode + operand)
+    pub const fn bytes(self) -> u8 {
+        match self {
+            AddressingMode::Implied | AddressingMode::Accumulator => 1,
+            AddressingMode::Immediate
+            | AddressingMode::ZeroPage
+            | AddressingMode::ZeroPageX
+            | AddressingMode::ZeroPageY
+            | AddressingMode::IndirectX
+            | AddressingMode::IndirectY
+            | AddressingMode::Relative => 2,
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => 3,
+        }
+    }
+}
+
 pub struct AddressResult {
     pub address: u16,
     pub page_crossed: bool,
 }
 
 impl Cpu {
-    pub fn get_operand_address(&mut self, memory: &dyn Memory, mode: AddressingMode) -> AddressResult {
+    pub fn get_operand_address<M: Memory + ?Sized>(&mut self, memory: &mut M, mode: AddressingMode) -> AddressResult {
         match mode {
+            // No operand in memory
+            AddressingMode::Implied | AddressingMode::Accumulator => {
+                AddressResult { address: 0, page_crossed: false }
+            }
             AddressingMode::Immediate => {
                 let addr = self.pc;
                 self.pc = self.pc.wrapping_add(1);
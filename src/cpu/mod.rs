@@ -2,16 +2,20 @@
 
 pub mod opcodes;
 pub mod addressing;
+#[allow(clippy::module_inception, clippy::bool_assert_comparison)]
 mod tests;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use crate::memory::Memory;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct StatusFlags {
     pub carry: bool,        // C
     pub zero: bool,         // Z
     pub interrupt: bool,    // I (interrupt disable)
-    pub decimal: bool,      // D (decimal mode, not used in C64)
+    pub decimal: bool,      // D (decimal mode, BCD for ADC and SBC)
     pub break_flag: bool,   // B
     pub unused: bool,       // Always 1
     pub overflow: bool,     // V
@@ -62,7 +66,75 @@ impl StatusFlags {
     }
 }
 
-#[derive(Debug)]
+// Outcome of a single `Cpu::step`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    // An instruction or interrupt sequence ran (or the CPU sat out a stalled
+    // cycle), taking this many cycles
+    Executed(u8),
+    // PC reached a breakpoint. Nothing was executed; the next step runs the
+    // instruction at this address.
+    BreakpointHit(u16),
+    // The instruction at `pc` ran, taking `cycles`, and made an access a
+    // watchpoint stops at (from Machine::step only)
+    WatchpointHit { pc: u16, event: crate::debugger::watch::BusEvent, cycles: u8 },
+}
+
+impl StepResult {
+    #[cfg(test)]
+    pub fn cycles(self) -> u8 {
+        match self {
+            StepResult::Executed(cycles) | StepResult::WatchpointHit { cycles, .. } => cycles,
+            StepResult::BreakpointHit(_) => 0,
+        }
+    }
+}
+
+// Set of breakpoint addresses, one bit per address so the check in step()
+// is a single lookup. Nothing is allocated until the first breakpoint.
+#[derive(Default)]
+struct Breakpoints {
+    bits: Vec<u64>,
+}
+
+impl Breakpoints {
+    fn insert(&mut self, addr: u16) {
+        if self.bits.is_empty() {
+            self.bits = vec![0; 0x10000 / 64];
+        }
+        self.bits[addr as usize / 64] |= 1 << (addr % 64);
+    }
+
+    fn remove(&mut self, addr: u16) {
+        if let Some(word) = self.bits.get_mut(addr as usize / 64) {
+            *word &= !(1 << (addr % 64));
+        }
+    }
+
+    #[inline]
+    fn contains(&self, addr: u16) -> bool {
+        self.bits
+            .get(addr as usize / 64)
+            .is_some_and(|word| word & (1 << (addr % 64)) != 0)
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..=0xFFFFu16).filter(|&addr| self.contains(addr))
+    }
+}
+
+impl std::fmt::Debug for Breakpoints {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.addresses()).finish()
+    }
+}
+
+// Serializable so CPU state can be saved in snapshots and debug dumps. Every
+// field is architectural or pin state except the HLE trap setting and the
+// breakpoints, which are configuration, and the files the traps have open
+// and the host clock they read, which belong to the host. Those are left
+// out.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Cpu {
     pub a: u8,      // Accumulator
     pub x: u8,      // X register
@@ -71,6 +143,45 @@ pub struct Cpu {
     pub sp: u8,     // Stack pointer (points to $0100 + sp)
     pub status: StatusFlags,
     pub cycles: u64, // Total cycles executed
+
+    // Interrupt inputs
+    irq_line: bool,     // IRQ is level-triggered: serviced while held low and I is clear
+    nmi_line: bool,     // Last sampled NMI level (for edge detection)
+    nmi_pending: bool,  // NMI edge latched, serviced at the next instruction boundary
+    // The 6502 polls for interrupts before the last cycle of each instruction.
+    // CLI/SEI/PLP change the I flag after that poll, so the decision made at the
+    // next boundary is based on the value I had *before* they executed.
+    irq_inhibit: bool,
+
+    // RDY input, driven by the VIC-II's BA output. While it is low the CPU is
+    // halted so the VIC can use the bus for character and sprite DMA.
+    rdy: bool,
+
+    // Set by the JAM/KIL opcodes. A jammed 6502 stops fetching instructions
+    // and ignores IRQ and NMI; only a reset brings it back.
+    jammed: bool,
+
+    // Intercept the KERNAL's file routines and serve them from the devices'
+    // files directly, keeping the files opened that way
+    #[serde(skip, default = "hle_traps_default")]
+    pub hle_traps: bool,
+    #[serde(skip)]
+    pub files: crate::hle::Files,
+    // The jiffy clock for the RDTIM and SETTIM traps, when it follows the
+    // host's clock
+    #[serde(skip)]
+    pub clock: Option<crate::hle::HostClock>,
+
+    // Debugger breakpoints, and the one just reported so that the next step
+    // executes it instead of stopping again
+    #[serde(skip)]
+    breakpoints: Breakpoints,
+    #[serde(skip)]
+    resume_from: Option<u16>,
+}
+
+fn hle_traps_default() -> bool {
+    true
 }
 
 impl Cpu {
@@ -83,10 +194,21 @@ impl Cpu {
             sp: 0xFD, // Stack starts at $01FD
             status: StatusFlags::new(),
             cycles: 0,
+            irq_line: false,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_inhibit: true,
+            rdy: true,
+            jammed: false,
+            hle_traps: hle_traps_default(),
+            files: crate::hle::Files::default(),
+            clock: None,
+            breakpoints: Breakpoints::default(),
+            resume_from: None,
         }
     }
 
-    pub fn reset(&mut self, memory: &dyn crate::memory::Memory) {
+    pub fn reset(&mut self, memory: &mut dyn Memory) {
         self.a = 0;
         self.x = 0;
         self.y = 0;
@@ -99,290 +221,148 @@ impl Cpu {
         self.pc = (hi << 8) | lo;
         
         self.cycles = 0;
+        self.nmi_pending = false;
+        self.irq_inhibit = true;
+        self.jammed = false;
+        self.files = crate::hle::Files::default();
+    }
+
+    // Take on the state in a snapshot, keeping the HLE trap setting, host
+    // clock and breakpoints. Files the snapshot had open through the traps are left
+    // to the KERNAL, which finds them in its table in RAM.
+    pub fn restore(&mut self, snapshot: Cpu) {
+        let old = std::mem::replace(self, snapshot);
+        self.hle_traps = old.hle_traps;
+        self.clock = old.clock;
+        self.breakpoints = old.breakpoints;
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
     }
 
-    pub fn step(&mut self, memory: &mut dyn crate::memory::Memory) -> Result<u8> {
-        // HLE Traps for Storage
-        match self.pc {
-            0xFFD5 => {
-                // LOAD Trap
-                if self.handle_load(memory)? {
-                    // RTS behavior: Pull PC from stack
-                    self.pc = self.pop_word(memory).wrapping_add(1);
-                    return Ok(6); // Arbitrary cycle count
-                }
-            }
-            0xFFD8 => {
-                // SAVE Trap
-                if self.handle_save(memory)? {
-                    // RTS behavior: Pull PC from stack
-                    self.pc = self.pop_word(memory).wrapping_add(1);
-                    return Ok(6); // Arbitrary cycle count
-                }
-            }
-            _ => {}
+    // Stop before executing the instruction at `addr`
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(addr);
+    }
+
+    // The breakpoints set, lowest first
+    pub fn breakpoints(&self) -> Vec<u16> {
+        self.breakpoints.addresses().collect()
+    }
+
+    pub fn step<M: Memory + ?Sized>(&mut self, memory: &mut M) -> Result<StepResult> {
+        // Bus stolen by the VIC-II or CPU jammed: sit out one cycle
+        if !self.rdy || self.jammed {
+            self.cycles += 1;
+            return Ok(StepResult::Executed(1));
+        }
+
+        // Interrupts are taken at instruction boundaries
+        if let Some(vector) = self.pending_interrupt() {
+            self.nmi_pending &= vector != 0xFFFA;
+            self.interrupt(memory, vector);
+            return Ok(StepResult::Executed(7));
+        }
+
+        // Stop before the instruction at a breakpoint, unless it is the one
+        // that was just reported
+        let resume_from = self.resume_from.take();
+        if self.breakpoints.contains(self.pc) && resume_from != Some(self.pc) {
+            self.resume_from = Some(self.pc);
+            return Ok(StepResult::BreakpointHit(self.pc));
+        }
+
+        // HLE traps for the KERNAL's file routines. Only when the real
+        // KERNAL is mapped at the entry points, so programs that bank it out
+        // get their own code there.
+        let trapped = self.hle_traps && memory.kernal_rom_visible() && crate::hle::trap(self, memory);
+        if trapped {
+            // RTS behavior: Pull PC from stack
+            self.pc = self.pop_word(memory).wrapping_add(1);
+            self.irq_inhibit = self.status.interrupt;
+            return Ok(StepResult::Executed(6)); // Arbitrary cycle count
         }
 
         let opcode = memory.read(self.pc);
         self.pc = self.pc.wrapping_add(1);
         
+        let interrupt_before = self.status.interrupt;
         let cycles = opcodes::execute(self, memory, opcode)?;
         self.cycles += cycles as u64;
+
+        // CLI, SEI and PLP update I after the interrupt poll, delaying the
+        // effect by one instruction. Every other instruction (including RTI)
+        // is polled with the I flag it leaves behind.
+        self.irq_inhibit = match opcode {
+            0x58 | 0x78 | 0x28 => interrupt_before,
+            _ => self.status.interrupt,
+        };
         
-        Ok(cycles)
+        Ok(StepResult::Executed(cycles))
     }
 
-    // Handle KERNAL LOAD ($FFD5)
-    fn handle_load(&mut self, memory: &mut dyn crate::memory::Memory) -> Result<bool> {
-        // Arguments:
-        // A = 0: Load, 1: Verify
-        // X/Y = Load Address (if secondary address = 0)
-        
-        // Zero Page:
-        // $BA: Device Number
-        // $BB/$BC: Pointer to Filename
-        // $B7: Filename Length
-        // $90: Status Word (ST)
-        
-        let device = memory.read(0xBA);
-        
-        // Tape (Device 1) - Not Supported
-        if device == 1 {
-            self.status.carry = true; // Error
-            self.a = 5; // DEVICE NOT PRESENT
-            return Ok(true);
-        }
-        
-        // Disk (Device 8)
-        if device == 8 {
-            let fn_len = memory.read(0xB7) as u16;
-            let fn_ptr_lo = memory.read(0xBB) as u16;
-            let fn_ptr_hi = memory.read(0xBC) as u16;
-            let fn_ptr = (fn_ptr_hi << 8) | fn_ptr_lo;
-            
-            let mut filename = Vec::new();
-            for i in 0..fn_len {
-                filename.push(memory.read(fn_ptr + i));
-            }
-            
-            // Check for Directory Listing "$"
-            if filename.len() == 1 && filename[0] == b'$' {
-                match crate::storage::list_directory() {
-                    Ok((start_addr, data)) => {
-                        // Standard LOAD logic
-                        let sec_addr = memory.read(0xB9);
-                        let load_addr = if sec_addr == 0 {
-                            (self.x as u16) | ((self.y as u16) << 8)
-                        } else {
-                            start_addr
-                        };
-                        
-                        // Write data
-                        for (i, byte) in data.iter().enumerate() {
-                            memory.write(load_addr + i as u16, *byte);
-                        }
-                        
-                        // Update Pointers
-                        let end_addr = load_addr + data.len() as u16;
-                        memory.write(0xAE, (end_addr & 0xFF) as u8);
-                        memory.write(0xAF, ((end_addr >> 8) & 0xFF) as u8);
-                        
-                        // Update BASIC pointers to ensure old program is "gone"
-                        memory.write(0x2D, (end_addr & 0xFF) as u8);
-                        memory.write(0x2E, ((end_addr >> 8) & 0xFF) as u8);
-                        memory.write(0x2F, (end_addr & 0xFF) as u8);
-                        memory.write(0x30, ((end_addr >> 8) & 0xFF) as u8);
-                        memory.write(0x31, (end_addr & 0xFF) as u8);
-                        memory.write(0x32, ((end_addr >> 8) & 0xFF) as u8);
-                        
-                        // Success
-                        self.status.carry = false;
-                        self.x = (end_addr & 0xFF) as u8;
-                        self.y = ((end_addr >> 8) & 0xFF) as u8;
-                        
-                        return Ok(true);
-                    }
-                    Err(_) => {
-                        self.status.carry = true;
-                        self.a = 4; // FILE NOT FOUND
-                        return Ok(true);
-                    }
-                }
-            }
-            
-            // Try to load file
-            match crate::storage::load_prg(&filename) {
-                Ok((start_addr, data)) => {
-                    // Check Secondary Address ($B9)
-                    // 0 = Load to address in X/Y
-                    // 1 = Load to address in file header
-                    let sec_addr = memory.read(0xB9);
-                    
-                    let load_addr = if sec_addr == 0 {
-                        // Use X/Y
-                        (self.x as u16) | ((self.y as u16) << 8)
-                    } else {
-                        // Use file header
-                        start_addr
-                    };
-                    
-                    // Write data to memory
-                    for (i, byte) in data.iter().enumerate() {
-                        memory.write(load_addr + i as u16, *byte);
-                    }
-                    
-                    // Update End Address Pointers
-                    let end_addr = load_addr + data.len() as u16;
-                    
-                    // $AE/$AF = End Address
-                    memory.write(0xAE, (end_addr & 0xFF) as u8);
-                    memory.write(0xAF, ((end_addr >> 8) & 0xFF) as u8);
-                    
-                    // $2D/$2E = End of Basic Variables (for BASIC LOAD)
-                    memory.write(0x2D, (end_addr & 0xFF) as u8);
-                    memory.write(0x2E, ((end_addr >> 8) & 0xFF) as u8);
-
-                    // $2F-$32: Start/End of Arrays (Set to End of Program)
-                    memory.write(0x2F, (end_addr & 0xFF) as u8);
-                    memory.write(0x30, ((end_addr >> 8) & 0xFF) as u8);
-                    memory.write(0x31, (end_addr & 0xFF) as u8);
-                    memory.write(0x32, ((end_addr >> 8) & 0xFF) as u8);
-                    
-                    // Success
-                    self.status.carry = false;
-                    self.x = (end_addr & 0xFF) as u8;
-                    self.y = ((end_addr >> 8) & 0xFF) as u8;
-                    
-                    return Ok(true);
-                },
-                Err(_) => {
-                    self.status.carry = true; // Error
-                    self.a = 4; // FILE NOT FOUND
-                    return Ok(true);
-                }
-            }
+    // The vector of the interrupt the next step takes, if it takes one. NMI
+    // has priority over IRQ.
+    pub fn pending_interrupt(&self) -> Option<u16> {
+        if !self.rdy || self.jammed {
+            None
+        } else if self.nmi_pending {
+            Some(0xFFFA)
+        } else if self.irq_line && !self.irq_inhibit {
+            Some(0xFFFE)
+        } else {
+            None
         }
-        
-        // Let other devices fall through to standard KERNAL (which will fail for now)
-        Ok(false)
     }
 
-    // Handle KERNAL SAVE ($FFD8)
-    fn handle_save(&mut self, memory: &mut dyn crate::memory::Memory) -> Result<bool> {
-        // Arguments:
-        // A = Zero Page pointer to Start Address
-        // X/Y = End Address
-        
-        // Zero Page:
-        // $BA: Device Number
-        // $BB/$BC: Pointer to Filename
-        // $B7: Filename Length
-        
-        let device = memory.read(0xBA);
-        
-        // Tape (Device 1) - Not Supported
-        if device == 1 {
-            self.status.carry = true; // Error
-            self.a = 5; // DEVICE NOT PRESENT
-            return Ok(true);
-        }
-        
-        // Disk (Device 8)
-        if device == 8 {
-            // Get Filename
-            let fn_len = memory.read(0xB7) as u16;
-            let fn_ptr_lo = memory.read(0xBB) as u16;
-            let fn_ptr_hi = memory.read(0xBC) as u16;
-            let fn_ptr = (fn_ptr_hi << 8) | fn_ptr_lo;
-            
-            let mut filename = Vec::new();
-            for i in 0..fn_len {
-                filename.push(memory.read(fn_ptr + i));
-            }
-            
-            // Get Start Address (Indirect from ZP address in A)
-            let start_ptr = self.a as u16;
-            let start_addr = (memory.read(start_ptr) as u16) | ((memory.read(start_ptr + 1) as u16) << 8);
-            
-            // Get End Address (from X/Y)
-            // Note: KERNAL SAVE End Address is inclusive? No, typically it's exclusive or points to last byte.
-            // Documentation says: X/Y = End Address.
-            // Usually interpreted as: Start inclusive, End exclusive? 
-            // Actually C64 KERNAL SAVE routine usually expects End Address to be +1 byte.
-            let end_addr = (self.x as u16) | ((self.y as u16) << 8);
-            
-            if end_addr <= start_addr {
-                 self.status.carry = true;
-                 return Ok(true);
-            }
-            
-            // Read Data
-            let mut data = Vec::new();
-            for addr in start_addr..end_addr {
-                data.push(memory.read(addr));
-            }
-            
-            // Save
-            match crate::storage::save_prg(&filename, start_addr, &data) {
-                Ok(_) => {
-                    self.status.carry = false;
-                    return Ok(true);
-                },
-                Err(_) => {
-                    self.status.carry = true;
-                    self.a = 26; // WRITE PROTECT ON (Generic error)
-                    return Ok(true);
-                }
-            }
-        }
-        
-        Ok(false)
+    // Drive the IRQ input. The line is level-sensitive: it is sampled at every
+    // instruction boundary and serviced for as long as it is held and I is clear.
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
     }
-    
-    // IRQ interrupt request
-    pub fn irq(&mut self, memory: &mut dyn crate::memory::Memory) {
-        // Only trigger if interrupts are enabled
-        if !self.status.interrupt {
-            // Push PC and status to stack
-            let pc_hi = (self.pc >> 8) as u8;
-            let pc_lo = (self.pc & 0xFF) as u8;
-            self.push(memory, pc_hi);
-            self.push(memory, pc_lo);
-            
-            // B flag is clear (bit 4), bit 5 is always 1
-            self.push(memory, self.status.as_byte() & !0x10); 
-            
-            // Set interrupt disable flag to prevent recursive IRQs
-            self.status.interrupt = true;
-            
-            // Jump to IRQ vector
-            let lo = memory.read(0xFFFE) as u16;
-            let hi = memory.read(0xFFFF) as u16;
-            self.pc = (hi << 8) | lo;
-            
-            self.cycles += 7; // IRQ takes 7 cycles
+
+    // Drive the NMI input. NMI is edge-triggered: only a transition to the
+    // asserted state latches an interrupt, holding the line does not repeat it.
+    pub fn set_nmi_line(&mut self, asserted: bool) {
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
         }
+        self.nmi_line = asserted;
     }
-    
-    // NMI non-maskable interrupt
-    pub fn nmi(&mut self, memory: &mut dyn crate::memory::Memory) {
-        // NMI cannot be disabled
+
+    // Drive the RDY input. Pulling it low stalls the CPU at the next
+    // instruction boundary, one cycle at a time, until it is released.
+    pub fn set_rdy(&mut self, ready: bool) {
+        self.rdy = ready;
+    }
+
+    // Interrupt sequence shared by IRQ and NMI (BRK has its own in opcodes.rs)
+    fn interrupt<M: Memory + ?Sized>(&mut self, memory: &mut M, vector: u16) {
         // Push PC and status to stack
-        let pc_hi = (self.pc >> 8) as u8;
-        let pc_lo = (self.pc & 0xFF) as u8;
-        self.push(memory, pc_hi);
-        self.push(memory, pc_lo);
-        self.push(memory, self.status.as_byte() | 0x20); // B flag clear for NMI
+        let pc = self.pc;
+        self.push_word(memory, pc);
         
-        // Set interrupt disable flag
+        // B flag is clear (bit 4), bit 5 is always 1
+        let status = (self.status.as_byte() & !0x10) | 0x20;
+        self.push(memory, status);
+        
+        // Set interrupt disable flag to prevent recursive IRQs
         self.status.interrupt = true;
         
-        // Jump to NMI vector
-        let lo = memory.read(0xFFFA) as u16;
-        let hi = memory.read(0xFFFB) as u16;
+        // Jump to vector
+        let lo = memory.read(vector) as u16;
+        let hi = memory.read(vector.wrapping_add(1)) as u16;
         self.pc = (hi << 8) | lo;
         
-        self.cycles += 7; // NMI takes 7 cycles
+        self.cycles += 7; // Interrupt sequence takes 7 cycles
+
+        // The first handler instruction always runs before another IRQ is recognized
+        self.irq_inhibit = true;
     }
     
     // Check if PC is about to call a KERNAL routine we want to intercept
@@ -395,24 +375,24 @@ impl Cpu {
     // }
 
     // Stack operations
-    pub fn push(&mut self, memory: &mut dyn crate::memory::Memory, value: u8) {
+    pub fn push<M: Memory + ?Sized>(&mut self, memory: &mut M, value: u8) {
         let addr = 0x0100 | (self.sp as u16);
         memory.write(addr, value);
         self.sp = self.sp.wrapping_sub(1);
     }
 
-    pub fn pop(&mut self, memory: &dyn crate::memory::Memory) -> u8 {
+    pub fn pop<M: Memory + ?Sized>(&mut self, memory: &mut M) -> u8 {
         self.sp = self.sp.wrapping_add(1);
         let addr = 0x0100 | (self.sp as u16);
         memory.read(addr)
     }
 
-    pub fn push_word(&mut self, memory: &mut dyn crate::memory::Memory, value: u16) {
+    pub fn push_word<M: Memory + ?Sized>(&mut self, memory: &mut M, value: u16) {
         self.push(memory, (value >> 8) as u8); // High byte first
         self.push(memory, (value & 0xFF) as u8); // Low byte
     }
 
-    pub fn pop_word(&mut self, memory: &dyn crate::memory::Memory) -> u16 {
+    pub fn pop_word<M: Memory + ?Sized>(&mut self, memory: &mut M) -> u16 {
         let lo = self.pop(memory) as u16;
         let hi = self.pop(memory) as u16;
         (hi << 8) | lo
@@ -5,656 +5,913 @@ use crate::memory::Memory;
 use super::addressing::AddressingMode;
 use anyhow::{Result, bail};
 
-pub fn execute(cpu: &mut Cpu, memory: &mut dyn Memory, opcode: u8) -> Result<u8> {
-    match opcode {
-        // LDA - Load Accumulator
-        0xA9 => lda(cpu, memory, AddressingMode::Immediate),
-        0xA5 => lda(cpu, memory, AddressingMode::ZeroPage),
-        0xB5 => lda(cpu, memory, AddressingMode::ZeroPageX),
-        0xAD => lda(cpu, memory, AddressingMode::Absolute),
-        0xBD => lda(cpu, memory, AddressingMode::AbsoluteX),
-        0xB9 => lda(cpu, memory, AddressingMode::AbsoluteY),
-        0xA1 => lda(cpu, memory, AddressingMode::IndirectX),
-        0xB1 => lda(cpu, memory, AddressingMode::IndirectY),
-
-        // LDX - Load X Register
-        0xA2 => ldx(cpu, memory, AddressingMode::Immediate),
-        0xA6 => ldx(cpu, memory, AddressingMode::ZeroPage),
-        0xB6 => ldx(cpu, memory, AddressingMode::ZeroPageY),
-        0xAE => ldx(cpu, memory, AddressingMode::Absolute),
-        0xBE => ldx(cpu, memory, AddressingMode::AbsoluteY),
-
-        // LDY - Load Y Register
-        0xA0 => ldy(cpu, memory, AddressingMode::Immediate),
-        0xA4 => ldy(cpu, memory, AddressingMode::ZeroPage),
-        0xB4 => ldy(cpu, memory, AddressingMode::ZeroPageX),
-        0xAC => ldy(cpu, memory, AddressingMode::Absolute),
-        0xBC => ldy(cpu, memory, AddressingMode::AbsoluteX),
-
-        // STA - Store Accumulator
-        0x85 => sta(cpu, memory, AddressingMode::ZeroPage),
-        0x95 => sta(cpu, memory, AddressingMode::ZeroPageX),
-        0x8D => sta(cpu, memory, AddressingMode::Absolute),
-        0x9D => sta(cpu, memory, AddressingMode::AbsoluteX),
-        0x99 => sta(cpu, memory, AddressingMode::AbsoluteY),
-        0x81 => sta(cpu, memory, AddressingMode::IndirectX),
-        0x91 => sta(cpu, memory, AddressingMode::IndirectY),
-
-        // STX - Store X Register
-        0x86 => stx(cpu, memory, AddressingMode::ZeroPage),
-        0x96 => stx(cpu, memory, AddressingMode::ZeroPageY),
-        0x8E => stx(cpu, memory, AddressingMode::Absolute),
-
-        // STY - Store Y Register
-        0x84 => sty(cpu, memory, AddressingMode::ZeroPage),
-        0x94 => sty(cpu, memory, AddressingMode::ZeroPageX),
-        0x8C => sty(cpu, memory, AddressingMode::Absolute),
-
-        // TAX, TAY, TXA, TYA
-        0xAA => { cpu.x = cpu.a; cpu.status.update_zero_negative(cpu.x); Ok(2) }
-        0xA8 => { cpu.y = cpu.a; cpu.status.update_zero_negative(cpu.y); Ok(2) }
-        0x8A => { cpu.a = cpu.x; cpu.status.update_zero_negative(cpu.a); Ok(2) }
-        0x98 => { cpu.a = cpu.y; cpu.status.update_zero_negative(cpu.a); Ok(2) }
-
-        // TSX, TXS
-        0xBA => { cpu.x = cpu.sp; cpu.status.update_zero_negative(cpu.x); Ok(2) }
-        0x9A => { cpu.sp = cpu.x; Ok(2) }
-
-        // PHA, PLA, PHP, PLP
-        0x48 => { let a = cpu.a; cpu.push(memory, a); Ok(3) }
-        0x68 => { let val = cpu.pop(memory); cpu.a = val; cpu.status.update_zero_negative(cpu.a); Ok(4) }
-        0x08 => { let status = cpu.status.as_byte() | 0x30; cpu.push(memory, status); Ok(3) }
-        0x28 => { let val = cpu.pop(memory); cpu.status = StatusFlags::from_byte(val); cpu.status.unused = true; Ok(4) }
-
-        // JMP
-        0x4C => {
-            let result = cpu.get_operand_address(memory, AddressingMode::Absolute);
-            cpu.pc = result.address;
-            Ok(3)
-        }
-        0x6C => {
-            let result = cpu.get_operand_address(memory, AddressingMode::Indirect);
-            cpu.pc = result.address;
-            Ok(5)
-        }
-
-        // JSR
-        0x20 => {
-            let target = {
-                let lo = memory.read(cpu.pc) as u16;
-                let hi = memory.read(cpu.pc.wrapping_add(1)) as u16;
-                (hi << 8) | lo
+// Executes one instruction. Returns the number of cycles taken on top of the
+// base count in the table (page crossings, taken branches).
+//
+// Handlers are generic over the bus so that the CPU's hot path can be
+// monomorphized for C64Memory and the reads and writes inlined.
+pub type Handler<M> = fn(&mut Cpu, &mut M, AddressingMode) -> Result<u8>;
+
+// Static description of one opcode, shared by the dispatcher and anything
+// that needs to decode instructions (disassembler, debugger)
+pub struct Instruction<M: Memory + ?Sized = dyn Memory> {
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    pub bytes: u8,                   // Length including the opcode byte
+    pub cycles: u8,                  // Base cycle count
+    pub handler: Option<Handler<M>>, // None = not implemented
+}
+
+// Function pointers are Copy whatever M is, so don't let derive require M: Copy
+impl<M: Memory + ?Sized> Clone for Instruction<M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: Memory + ?Sized> Copy for Instruction<M> {}
+
+// Metadata view of the table, for code that only decodes instructions
+pub static INSTRUCTIONS: [Instruction; 256] = build_table();
+
+// The same table with handlers instantiated for a concrete bus type
+struct Dispatch<M: Memory + ?Sized>(std::marker::PhantomData<M>);
+
+impl<M: Memory + ?Sized> Dispatch<M> {
+    const TABLE: [Instruction<M>; 256] = build_table();
+}
+
+pub fn execute<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, opcode: u8) -> Result<u8> {
+    let table = &Dispatch::<M>::TABLE;
+    let instruction = &table[opcode as usize];
+    match instruction.handler {
+        Some(handler) => Ok(instruction.cycles + handler(cpu, memory, instruction.mode)?),
+        None => bail!("Unimplemented opcode: 0x{:02X} at PC: 0x{:04X}", opcode, cpu.pc.wrapping_sub(1)),
+    }
+}
+
+const fn build_table<M: Memory + ?Sized>() -> [Instruction<M>; 256] {
+    use AddressingMode::*;
+
+    let mut table = [Instruction {
+        mnemonic: "???",
+        mode: Implied,
+        bytes: 1,
+        cycles: 2,
+        handler: None,
+    }; 256];
+
+    macro_rules! op {
+        ($opcode:expr, $mnemonic:expr, $mode:expr, $cycles:expr, $handler:expr) => {
+            table[$opcode] = Instruction {
+                mnemonic: $mnemonic,
+                mode: $mode,
+                bytes: $mode.bytes(),
+                cycles: $cycles,
+                handler: Some($handler as Handler<M>),
+            };
+        };
+    }
+
+    // Opcodes the CPU doesn't run yet, decoded for the disassembler
+    macro_rules! undocumented {
+        ($opcode:expr, $mnemonic:expr, $mode:expr, $cycles:expr) => {
+            table[$opcode] = Instruction {
+                mnemonic: $mnemonic,
+                mode: $mode,
+                bytes: $mode.bytes(),
+                cycles: $cycles,
+                handler: None,
             };
-            let return_addr = cpu.pc.wrapping_add(1);
-            cpu.push_word(memory, return_addr);
-            cpu.pc = target;
-            Ok(6)
-        }
-
-        // RTS
-        0x60 => {
-            let addr = cpu.pop_word(memory);
-            cpu.pc = addr.wrapping_add(1);
-            Ok(6)
-        }
-
-        // RTI
-        0x40 => {
-            let status = cpu.pop(memory);
-            cpu.status = StatusFlags::from_byte(status);
-            cpu.status.unused = true;
-            cpu.pc = cpu.pop_word(memory);
-            Ok(6)
-        }
-
-        // BRK
-        0x00 => {
-            cpu.pc = cpu.pc.wrapping_add(1);
-            cpu.push_word(memory, cpu.pc);
-            cpu.status.break_flag = true;
-            let status = cpu.status.as_byte() | 0x10;
-            cpu.push(memory, status);
-            cpu.status.interrupt = true;
-            let lo = memory.read(0xFFFE) as u16;
-            let hi = memory.read(0xFFFF) as u16;
-            cpu.pc = (hi << 8) | lo;
-            Ok(7)
-        }
-
-        // NOP
-        0xEA => Ok(2),
-
-        // Branches
-        0x10 => branch(cpu, memory, !cpu.status.negative),
-        0x30 => branch(cpu, memory, cpu.status.negative),
-        0x50 => branch(cpu, memory, !cpu.status.overflow),
-        0x70 => branch(cpu, memory, cpu.status.overflow),
-        0x90 => branch(cpu, memory, !cpu.status.carry),
-        0xB0 => branch(cpu, memory, cpu.status.carry),
-        0xD0 => branch(cpu, memory, !cpu.status.zero),
-        0xF0 => branch(cpu, memory, cpu.status.zero),
-
-        // Flag instructions
-        0x18 => { cpu.status.carry = false; Ok(2) }
-        0x38 => { cpu.status.carry = true; Ok(2) }
-        0x58 => { cpu.status.interrupt = false; Ok(2) }
-        0x78 => { cpu.status.interrupt = true; Ok(2) }
-        0xB8 => { cpu.status.overflow = false; Ok(2) }
-        0xD8 => { cpu.status.decimal = false; Ok(2) }
-        0xF8 => { cpu.status.decimal = true; Ok(2) }
-
-        // INX, INY, DEX, DEY
-        0xE8 => { cpu.x = cpu.x.wrapping_add(1); cpu.status.update_zero_negative(cpu.x); Ok(2) }
-        0xC8 => { cpu.y = cpu.y.wrapping_add(1); cpu.status.update_zero_negative(cpu.y); Ok(2) }
-        0xCA => { cpu.x = cpu.x.wrapping_sub(1); cpu.status.update_zero_negative(cpu.x); Ok(2) }
-        0x88 => { cpu.y = cpu.y.wrapping_sub(1); cpu.status.update_zero_negative(cpu.y); Ok(2) }
-
-        // ADC - Add with Carry
-        0x69 => adc(cpu, memory, AddressingMode::Immediate),
-        0x65 => adc(cpu, memory, AddressingMode::ZeroPage),
-        0x75 => adc(cpu, memory, AddressingMode::ZeroPageX),
-        0x6D => adc(cpu, memory, AddressingMode::Absolute),
-        0x7D => adc(cpu, memory, AddressingMode::AbsoluteX),
-        0x79 => adc(cpu, memory, AddressingMode::AbsoluteY),
-        0x61 => adc(cpu, memory, AddressingMode::IndirectX),
-        0x71 => adc(cpu, memory, AddressingMode::IndirectY),
-
-        // SBC - Subtract with Carry
-        0xE9 => sbc(cpu, memory, AddressingMode::Immediate),
-        0xE5 => sbc(cpu, memory, AddressingMode::ZeroPage),
-        0xF5 => sbc(cpu, memory, AddressingMode::ZeroPageX),
-        0xED => sbc(cpu, memory, AddressingMode::Absolute),
-        0xFD => sbc(cpu, memory, AddressingMode::AbsoluteX),
-        0xF9 => sbc(cpu, memory, AddressingMode::AbsoluteY),
-        0xE1 => sbc(cpu, memory, AddressingMode::IndirectX),
-        0xF1 => sbc(cpu, memory, AddressingMode::IndirectY),
-
-        // AND - Logical AND
-        0x29 => and(cpu, memory, AddressingMode::Immediate),
-        0x25 => and(cpu, memory, AddressingMode::ZeroPage),
-        0x35 => and(cpu, memory, AddressingMode::ZeroPageX),
-        0x2D => and(cpu, memory, AddressingMode::Absolute),
-        0x3D => and(cpu, memory, AddressingMode::AbsoluteX),
-        0x39 => and(cpu, memory, AddressingMode::AbsoluteY),
-        0x21 => and(cpu, memory, AddressingMode::IndirectX),
-        0x31 => and(cpu, memory, AddressingMode::IndirectY),
-
-        // ORA - Logical OR
-        0x09 => ora(cpu, memory, AddressingMode::Immediate),
-        0x05 => ora(cpu, memory, AddressingMode::ZeroPage),
-        0x15 => ora(cpu, memory, AddressingMode::ZeroPageX),
-        0x0D => ora(cpu, memory, AddressingMode::Absolute),
-        0x1D => ora(cpu, memory, AddressingMode::AbsoluteX),
-        0x19 => ora(cpu, memory, AddressingMode::AbsoluteY),
-        0x01 => ora(cpu, memory, AddressingMode::IndirectX),
-        0x11 => ora(cpu, memory, AddressingMode::IndirectY),
-
-        // EOR - Logical XOR
-        0x49 => eor(cpu, memory, AddressingMode::Immediate),
-        0x45 => eor(cpu, memory, AddressingMode::ZeroPage),
-        0x55 => eor(cpu, memory, AddressingMode::ZeroPageX),
-        0x4D => eor(cpu, memory, AddressingMode::Absolute),
-        0x5D => eor(cpu, memory, AddressingMode::AbsoluteX),
-        0x59 => eor(cpu, memory, AddressingMode::AbsoluteY),
-        0x41 => eor(cpu, memory, AddressingMode::IndirectX),
-        0x51 => eor(cpu, memory, AddressingMode::IndirectY),
-
-        // CMP - Compare Accumulator
-        0xC9 => cmp(cpu, memory, AddressingMode::Immediate),
-        0xC5 => cmp(cpu, memory, AddressingMode::ZeroPage),
-        0xD5 => cmp(cpu, memory, AddressingMode::ZeroPageX),
-        0xCD => cmp(cpu, memory, AddressingMode::Absolute),
-        0xDD => cmp(cpu, memory, AddressingMode::AbsoluteX),
-        0xD9 => cmp(cpu, memory, AddressingMode::AbsoluteY),
-        0xC1 => cmp(cpu, memory, AddressingMode::IndirectX),
-        0xD1 => cmp(cpu, memory, AddressingMode::IndirectY),
-
-        // CPX - Compare X Register
-        0xE0 => cpx(cpu, memory, AddressingMode::Immediate),
-        0xE4 => cpx(cpu, memory, AddressingMode::ZeroPage),
-        0xEC => cpx(cpu, memory, AddressingMode::Absolute),
-
-        // CPY - Compare Y Register
-        0xC0 => cpy(cpu, memory, AddressingMode::Immediate),
-        0xC4 => cpy(cpu, memory, AddressingMode::ZeroPage),
-        0xCC => cpy(cpu, memory, AddressingMode::Absolute),
-
-        // INC - Increment Memory
-        0xE6 => inc(cpu, memory, AddressingMode::ZeroPage),
-        0xF6 => inc(cpu, memory, AddressingMode::ZeroPageX),
-        0xEE => inc(cpu, memory, AddressingMode::Absolute),
-        0xFE => inc(cpu, memory, AddressingMode::AbsoluteX),
-
-        // DEC - Decrement Memory
-        0xC6 => dec(cpu, memory, AddressingMode::ZeroPage),
-        0xD6 => dec(cpu, memory, AddressingMode::ZeroPageX),
-        0xCE => dec(cpu, memory, AddressingMode::Absolute),
-        0xDE => dec(cpu, memory, AddressingMode::AbsoluteX),
-
-        // ASL - Arithmetic Shift Left
-        0x0A => asl_acc(cpu),
-        0x06 => asl(cpu, memory, AddressingMode::ZeroPage),
-        0x16 => asl(cpu, memory, AddressingMode::ZeroPageX),
-        0x0E => asl(cpu, memory, AddressingMode::Absolute),
-        0x1E => asl(cpu, memory, AddressingMode::AbsoluteX),
-
-        // LSR - Logical Shift Right
-        0x4A => lsr_acc(cpu),
-        0x46 => lsr(cpu, memory, AddressingMode::ZeroPage),
-        0x56 => lsr(cpu, memory, AddressingMode::ZeroPageX),
-        0x4E => lsr(cpu, memory, AddressingMode::Absolute),
-        0x5E => lsr(cpu, memory, AddressingMode::AbsoluteX),
-
-        // ROL - Rotate Left
-        0x2A => rol_acc(cpu),
-        0x26 => rol(cpu, memory, AddressingMode::ZeroPage),
-        0x36 => rol(cpu, memory, AddressingMode::ZeroPageX),
-        0x2E => rol(cpu, memory, AddressingMode::Absolute),
-        0x3E => rol(cpu, memory, AddressingMode::AbsoluteX),
-
-        // ROR - Rotate Right
-        0x6A => ror_acc(cpu),
-        0x66 => ror(cpu, memory, AddressingMode::ZeroPage),
-        0x76 => ror(cpu, memory, AddressingMode::ZeroPageX),
-        0x6E => ror(cpu, memory, AddressingMode::Absolute),
-        0x7E => ror(cpu, memory, AddressingMode::AbsoluteX),
-
-        // BIT - Test Bits
-        0x24 => bit(cpu, memory, AddressingMode::ZeroPage),
-        0x2C => bit(cpu, memory, AddressingMode::Absolute),
-
-        _ => bail!("Unimplemented opcode: 0x{:02X} at PC: 0x{:04X}", opcode, cpu.pc.wrapping_sub(1)),
+        };
     }
+
+    // LDA - Load Accumulator
+    op!(0xA9, "LDA", Immediate, 2, lda);
+    op!(0xA5, "LDA", ZeroPage, 3, lda);
+    op!(0xB5, "LDA", ZeroPageX, 4, lda);
+    op!(0xAD, "LDA", Absolute, 4, lda);
+    op!(0xBD, "LDA", AbsoluteX, 4, lda);
+    op!(0xB9, "LDA", AbsoluteY, 4, lda);
+    op!(0xA1, "LDA", IndirectX, 6, lda);
+    op!(0xB1, "LDA", IndirectY, 5, lda);
+
+    // LDX - Load X Register
+    op!(0xA2, "LDX", Immediate, 2, ldx);
+    op!(0xA6, "LDX", ZeroPage, 3, ldx);
+    op!(0xB6, "LDX", ZeroPageY, 4, ldx);
+    op!(0xAE, "LDX", Absolute, 4, ldx);
+    op!(0xBE, "LDX", AbsoluteY, 4, ldx);
+
+    // LDY - Load Y Register
+    op!(0xA0, "LDY", Immediate, 2, ldy);
+    op!(0xA4, "LDY", ZeroPage, 3, ldy);
+    op!(0xB4, "LDY", ZeroPageX, 4, ldy);
+    op!(0xAC, "LDY", Absolute, 4, ldy);
+    op!(0xBC, "LDY", AbsoluteX, 4, ldy);
+
+    // STA - Store Accumulator
+    op!(0x85, "STA", ZeroPage, 3, sta);
+    op!(0x95, "STA", ZeroPageX, 4, sta);
+    op!(0x8D, "STA", Absolute, 4, sta);
+    op!(0x9D, "STA", AbsoluteX, 5, sta);
+    op!(0x99, "STA", AbsoluteY, 5, sta);
+    op!(0x81, "STA", IndirectX, 6, sta);
+    op!(0x91, "STA", IndirectY, 6, sta);
+
+    // STX - Store X Register
+    op!(0x86, "STX", ZeroPage, 3, stx);
+    op!(0x96, "STX", ZeroPageY, 4, stx);
+    op!(0x8E, "STX", Absolute, 4, stx);
+
+    // STY - Store Y Register
+    op!(0x84, "STY", ZeroPage, 3, sty);
+    op!(0x94, "STY", ZeroPageX, 4, sty);
+    op!(0x8C, "STY", Absolute, 4, sty);
+
+    // TAX, TAY, TXA, TYA
+    op!(0xAA, "TAX", Implied, 2, tax);
+    op!(0xA8, "TAY", Implied, 2, tay);
+    op!(0x8A, "TXA", Implied, 2, txa);
+    op!(0x98, "TYA", Implied, 2, tya);
+
+    // TSX, TXS
+    op!(0xBA, "TSX", Implied, 2, tsx);
+    op!(0x9A, "TXS", Implied, 2, txs);
+
+    // PHA, PLA, PHP, PLP
+    op!(0x48, "PHA", Implied, 3, pha);
+    op!(0x68, "PLA", Implied, 4, pla);
+    op!(0x08, "PHP", Implied, 3, php);
+    op!(0x28, "PLP", Implied, 4, plp);
+
+    // JMP
+    op!(0x4C, "JMP", Absolute, 3, jmp);
+    op!(0x6C, "JMP", Indirect, 5, jmp);
+
+    // JSR, RTS, RTI, BRK
+    op!(0x20, "JSR", Absolute, 6, jsr);
+    op!(0x60, "RTS", Implied, 6, rts);
+    op!(0x40, "RTI", Implied, 6, rti);
+    op!(0x00, "BRK", Implied, 7, brk);
+
+    // NOP
+    op!(0xEA, "NOP", Implied, 2, nop);
+
+    // JAM (KIL) - Lock up the CPU until reset
+    op!(0x02, "JAM", Implied, 2, jam);
+    op!(0x12, "JAM", Implied, 2, jam);
+    op!(0x22, "JAM", Implied, 2, jam);
+    op!(0x32, "JAM", Implied, 2, jam);
+    op!(0x42, "JAM", Implied, 2, jam);
+    op!(0x52, "JAM", Implied, 2, jam);
+    op!(0x62, "JAM", Implied, 2, jam);
+    op!(0x72, "JAM", Implied, 2, jam);
+    op!(0x92, "JAM", Implied, 2, jam);
+    op!(0xB2, "JAM", Implied, 2, jam);
+    op!(0xD2, "JAM", Implied, 2, jam);
+    op!(0xF2, "JAM", Implied, 2, jam);
+
+    // Branches
+    op!(0x10, "BPL", Relative, 2, bpl);
+    op!(0x30, "BMI", Relative, 2, bmi);
+    op!(0x50, "BVC", Relative, 2, bvc);
+    op!(0x70, "BVS", Relative, 2, bvs);
+    op!(0x90, "BCC", Relative, 2, bcc);
+    op!(0xB0, "BCS", Relative, 2, bcs);
+    op!(0xD0, "BNE", Relative, 2, bne);
+    op!(0xF0, "BEQ", Relative, 2, beq);
+
+    // Flag instructions
+    op!(0x18, "CLC", Implied, 2, clc);
+    op!(0x38, "SEC", Implied, 2, sec);
+    op!(0x58, "CLI", Implied, 2, cli);
+    op!(0x78, "SEI", Implied, 2, sei);
+    op!(0xB8, "CLV", Implied, 2, clv);
+    op!(0xD8, "CLD", Implied, 2, cld);
+    op!(0xF8, "SED", Implied, 2, sed);
+
+    // INX, INY, DEX, DEY
+    op!(0xE8, "INX", Implied, 2, inx);
+    op!(0xC8, "INY", Implied, 2, iny);
+    op!(0xCA, "DEX", Implied, 2, dex);
+    op!(0x88, "DEY", Implied, 2, dey);
+
+    // ADC - Add with Carry
+    op!(0x69, "ADC", Immediate, 2, adc);
+    op!(0x65, "ADC", ZeroPage, 3, adc);
+    op!(0x75, "ADC", ZeroPageX, 4, adc);
+    op!(0x6D, "ADC", Absolute, 4, adc);
+    op!(0x7D, "ADC", AbsoluteX, 4, adc);
+    op!(0x79, "ADC", AbsoluteY, 4, adc);
+    op!(0x61, "ADC", IndirectX, 6, adc);
+    op!(0x71, "ADC", IndirectY, 5, adc);
+
+    // SBC - Subtract with Carry
+    op!(0xE9, "SBC", Immediate, 2, sbc);
+    op!(0xE5, "SBC", ZeroPage, 3, sbc);
+    op!(0xF5, "SBC", ZeroPageX, 4, sbc);
+    op!(0xED, "SBC", Absolute, 4, sbc);
+    op!(0xFD, "SBC", AbsoluteX, 4, sbc);
+    op!(0xF9, "SBC", AbsoluteY, 4, sbc);
+    op!(0xE1, "SBC", IndirectX, 6, sbc);
+    op!(0xF1, "SBC", IndirectY, 5, sbc);
+
+    // AND - Logical AND
+    op!(0x29, "AND", Immediate, 2, and);
+    op!(0x25, "AND", ZeroPage, 3, and);
+    op!(0x35, "AND", ZeroPageX, 4, and);
+    op!(0x2D, "AND", Absolute, 4, and);
+    op!(0x3D, "AND", AbsoluteX, 4, and);
+    op!(0x39, "AND", AbsoluteY, 4, and);
+    op!(0x21, "AND", IndirectX, 6, and);
+    op!(0x31, "AND", IndirectY, 5, and);
+
+    // ORA - Logical OR
+    op!(0x09, "ORA", Immediate, 2, ora);
+    op!(0x05, "ORA", ZeroPage, 3, ora);
+    op!(0x15, "ORA", ZeroPageX, 4, ora);
+    op!(0x0D, "ORA", Absolute, 4, ora);
+    op!(0x1D, "ORA", AbsoluteX, 4, ora);
+    op!(0x19, "ORA", AbsoluteY, 4, ora);
+    op!(0x01, "ORA", IndirectX, 6, ora);
+    op!(0x11, "ORA", IndirectY, 5, ora);
+
+    // EOR - Logical XOR
+    op!(0x49, "EOR", Immediate, 2, eor);
+    op!(0x45, "EOR", ZeroPage, 3, eor);
+    op!(0x55, "EOR", ZeroPageX, 4, eor);
+    op!(0x4D, "EOR", Absolute, 4, eor);
+    op!(0x5D, "EOR", AbsoluteX, 4, eor);
+    op!(0x59, "EOR", AbsoluteY, 4, eor);
+    op!(0x41, "EOR", IndirectX, 6, eor);
+    op!(0x51, "EOR", IndirectY, 5, eor);
+
+    // CMP - Compare Accumulator
+    op!(0xC9, "CMP", Immediate, 2, cmp);
+    op!(0xC5, "CMP", ZeroPage, 3, cmp);
+    op!(0xD5, "CMP", ZeroPageX, 4, cmp);
+    op!(0xCD, "CMP", Absolute, 4, cmp);
+    op!(0xDD, "CMP", AbsoluteX, 4, cmp);
+    op!(0xD9, "CMP", AbsoluteY, 4, cmp);
+    op!(0xC1, "CMP", IndirectX, 6, cmp);
+    op!(0xD1, "CMP", IndirectY, 5, cmp);
+
+    // CPX - Compare X Register
+    op!(0xE0, "CPX", Immediate, 2, cpx);
+    op!(0xE4, "CPX", ZeroPage, 3, cpx);
+    op!(0xEC, "CPX", Absolute, 4, cpx);
+
+    // CPY - Compare Y Register
+    op!(0xC0, "CPY", Immediate, 2, cpy);
+    op!(0xC4, "CPY", ZeroPage, 3, cpy);
+    op!(0xCC, "CPY", Absolute, 4, cpy);
+
+    // INC - Increment Memory
+    op!(0xE6, "INC", ZeroPage, 5, inc);
+    op!(0xF6, "INC", ZeroPageX, 6, inc);
+    op!(0xEE, "INC", Absolute, 6, inc);
+    op!(0xFE, "INC", AbsoluteX, 7, inc);
+
+    // DEC - Decrement Memory
+    op!(0xC6, "DEC", ZeroPage, 5, dec);
+    op!(0xD6, "DEC", ZeroPageX, 6, dec);
+    op!(0xCE, "DEC", Absolute, 6, dec);
+    op!(0xDE, "DEC", AbsoluteX, 7, dec);
+
+    // ASL - Arithmetic Shift Left
+    op!(0x0A, "ASL", Accumulator, 2, asl);
+    op!(0x06, "ASL", ZeroPage, 5, asl);
+    op!(0x16, "ASL", ZeroPageX, 6, asl);
+    op!(0x0E, "ASL", Absolute, 6, asl);
+    op!(0x1E, "ASL", AbsoluteX, 7, asl);
+
+    // LSR - Logical Shift Right
+    op!(0x4A, "LSR", Accumulator, 2, lsr);
+    op!(0x46, "LSR", ZeroPage, 5, lsr);
+    op!(0x56, "LSR", ZeroPageX, 6, lsr);
+    op!(0x4E, "LSR", Absolute, 6, lsr);
+    op!(0x5E, "LSR", AbsoluteX, 7, lsr);
+
+    // ROL - Rotate Left
+    op!(0x2A, "ROL", Accumulator, 2, rol);
+    op!(0x26, "ROL", ZeroPage, 5, rol);
+    op!(0x36, "ROL", ZeroPageX, 6, rol);
+    op!(0x2E, "ROL", Absolute, 6, rol);
+    op!(0x3E, "ROL", AbsoluteX, 7, rol);
+
+    // ROR - Rotate Right
+    op!(0x6A, "ROR", Accumulator, 2, ror);
+    op!(0x66, "ROR", ZeroPage, 5, ror);
+    op!(0x76, "ROR", ZeroPageX, 6, ror);
+    op!(0x6E, "ROR", Absolute, 6, ror);
+    op!(0x7E, "ROR", AbsoluteX, 7, ror);
+
+    // BIT - Test Bits
+    op!(0x24, "BIT", ZeroPage, 3, bit);
+    op!(0x2C, "BIT", Absolute, 4, bit);
+
+    // SLO - ASL then ORA
+    undocumented!(0x03, "SLO", IndirectX, 8);
+    undocumented!(0x07, "SLO", ZeroPage, 5);
+    undocumented!(0x0F, "SLO", Absolute, 6);
+    undocumented!(0x13, "SLO", IndirectY, 8);
+    undocumented!(0x17, "SLO", ZeroPageX, 6);
+    undocumented!(0x1B, "SLO", AbsoluteY, 7);
+    undocumented!(0x1F, "SLO", AbsoluteX, 7);
+
+    // RLA - ROL then AND
+    undocumented!(0x23, "RLA", IndirectX, 8);
+    undocumented!(0x27, "RLA", ZeroPage, 5);
+    undocumented!(0x2F, "RLA", Absolute, 6);
+    undocumented!(0x33, "RLA", IndirectY, 8);
+    undocumented!(0x37, "RLA", ZeroPageX, 6);
+    undocumented!(0x3B, "RLA", AbsoluteY, 7);
+    undocumented!(0x3F, "RLA", AbsoluteX, 7);
+
+    // SRE - LSR then EOR
+    undocumented!(0x43, "SRE", IndirectX, 8);
+    undocumented!(0x47, "SRE", ZeroPage, 5);
+    undocumented!(0x4F, "SRE", Absolute, 6);
+    undocumented!(0x53, "SRE", IndirectY, 8);
+    undocumented!(0x57, "SRE", ZeroPageX, 6);
+    undocumented!(0x5B, "SRE", AbsoluteY, 7);
+    undocumented!(0x5F, "SRE", AbsoluteX, 7);
+
+    // RRA - ROR then ADC
+    undocumented!(0x63, "RRA", IndirectX, 8);
+    undocumented!(0x67, "RRA", ZeroPage, 5);
+    undocumented!(0x6F, "RRA", Absolute, 6);
+    undocumented!(0x73, "RRA", IndirectY, 8);
+    undocumented!(0x77, "RRA", ZeroPageX, 6);
+    undocumented!(0x7B, "RRA", AbsoluteY, 7);
+    undocumented!(0x7F, "RRA", AbsoluteX, 7);
+
+    // DCP - DEC then CMP
+    undocumented!(0xC3, "DCP", IndirectX, 8);
+    undocumented!(0xC7, "DCP", ZeroPage, 5);
+    undocumented!(0xCF, "DCP", Absolute, 6);
+    undocumented!(0xD3, "DCP", IndirectY, 8);
+    undocumented!(0xD7, "DCP", ZeroPageX, 6);
+    undocumented!(0xDB, "DCP", AbsoluteY, 7);
+    undocumented!(0xDF, "DCP", AbsoluteX, 7);
+
+    // ISC - INC then SBC
+    undocumented!(0xE3, "ISC", IndirectX, 8);
+    undocumented!(0xE7, "ISC", ZeroPage, 5);
+    undocumented!(0xEF, "ISC", Absolute, 6);
+    undocumented!(0xF3, "ISC", IndirectY, 8);
+    undocumented!(0xF7, "ISC", ZeroPageX, 6);
+    undocumented!(0xFB, "ISC", AbsoluteY, 7);
+    undocumented!(0xFF, "ISC", AbsoluteX, 7);
+
+    // SAX - Store A AND X
+    undocumented!(0x83, "SAX", IndirectX, 6);
+    undocumented!(0x87, "SAX", ZeroPage, 3);
+    undocumented!(0x8F, "SAX", Absolute, 4);
+    undocumented!(0x97, "SAX", ZeroPageY, 4);
+
+    // LAX - LDA and LDX at once
+    undocumented!(0xA3, "LAX", IndirectX, 6);
+    undocumented!(0xA7, "LAX", ZeroPage, 3);
+    undocumented!(0xAF, "LAX", Absolute, 4);
+    undocumented!(0xB3, "LAX", IndirectY, 5);
+    undocumented!(0xB7, "LAX", ZeroPageY, 4);
+    undocumented!(0xBF, "LAX", AbsoluteY, 4);
+
+    // Immediate operations
+    undocumented!(0x0B, "ANC", Immediate, 2);
+    undocumented!(0x2B, "ANC", Immediate, 2);
+    undocumented!(0x4B, "ALR", Immediate, 2);
+    undocumented!(0x6B, "ARR", Immediate, 2);
+    undocumented!(0x8B, "ANE", Immediate, 2);
+    undocumented!(0xAB, "LXA", Immediate, 2);
+    undocumented!(0xCB, "SBX", Immediate, 2);
+    undocumented!(0xEB, "SBC", Immediate, 2);
+
+    // Stores ANDed with the high byte of the address plus one
+    undocumented!(0x93, "SHA", IndirectY, 6);
+    undocumented!(0x9F, "SHA", AbsoluteY, 5);
+    undocumented!(0x9C, "SHY", AbsoluteX, 5);
+    undocumented!(0x9E, "SHX", AbsoluteY, 5);
+    undocumented!(0x9B, "TAS", AbsoluteY, 5);
+    undocumented!(0xBB, "LAS", AbsoluteY, 4);
+
+    // NOPs that read an operand, or take none
+    undocumented!(0x1A, "NOP", Implied, 2);
+    undocumented!(0x3A, "NOP", Implied, 2);
+    undocumented!(0x5A, "NOP", Implied, 2);
+    undocumented!(0x7A, "NOP", Implied, 2);
+    undocumented!(0xDA, "NOP", Implied, 2);
+    undocumented!(0xFA, "NOP", Implied, 2);
+    undocumented!(0x80, "NOP", Immediate, 2);
+    undocumented!(0x82, "NOP", Immediate, 2);
+    undocumented!(0x89, "NOP", Immediate, 2);
+    undocumented!(0xC2, "NOP", Immediate, 2);
+    undocumented!(0xE2, "NOP", Immediate, 2);
+    undocumented!(0x04, "NOP", ZeroPage, 3);
+    undocumented!(0x44, "NOP", ZeroPage, 3);
+    undocumented!(0x64, "NOP", ZeroPage, 3);
+    undocumented!(0x14, "NOP", ZeroPageX, 4);
+    undocumented!(0x34, "NOP", ZeroPageX, 4);
+    undocumented!(0x54, "NOP", ZeroPageX, 4);
+    undocumented!(0x74, "NOP", ZeroPageX, 4);
+    undocumented!(0xD4, "NOP", ZeroPageX, 4);
+    undocumented!(0xF4, "NOP", ZeroPageX, 4);
+    undocumented!(0x0C, "NOP", Absolute, 4);
+    undocumented!(0x1C, "NOP", AbsoluteX, 4);
+    undocumented!(0x3C, "NOP", AbsoluteX, 4);
+    undocumented!(0x5C, "NOP", AbsoluteX, 4);
+    undocumented!(0x7C, "NOP", AbsoluteX, 4);
+    undocumented!(0xDC, "NOP", AbsoluteX, 4);
+    undocumented!(0xFC, "NOP", AbsoluteX, 4);
+
+    table
 }
 
-fn lda(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+// Handlers return only the extra cycles on top of the table's base count:
+// reads add one on a page crossing, stores and read-modify-write ops never do.
+
+fn lda<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     cpu.a = memory.read(result.address);
     cpu.status.update_zero_negative(cpu.a);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 5 + result.page_crossed as u8,
-        _ => 0,
-    })
+    Ok(result.page_crossed as u8)
 }
 
-fn ldx(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn ldx<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     cpu.x = memory.read(result.address);
     cpu.status.update_zero_negative(cpu.x);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageY => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        _ => 0,
-    })
+    Ok(result.page_crossed as u8)
 }
 
-fn ldy(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn ldy<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     cpu.y = memory.read(result.address);
     cpu.status.update_zero_negative(cpu.y);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX => 4 + result.page_crossed as u8,
-        _ => 0,
-    })
+    Ok(result.page_crossed as u8)
 }
 
-fn sta(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn sta<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     memory.write(result.address, cpu.a);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 5,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 6,
-        _ => 0,
-    })
+    Ok(0)
 }
 
-fn stx(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn stx<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     memory.write(result.address, cpu.x);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageY => 4,
-        AddressingMode::Absolute => 4,
-        _ => 0,
-    })
+    Ok(0)
 }
 
-fn sty(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn sty<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     memory.write(result.address, cpu.y);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        _ => 0,
-    })
+    Ok(0)
+}
+
+// TAX, TAY, TXA, TYA
+fn tax<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.x = cpu.a;
+    cpu.status.update_zero_negative(cpu.x);
+    Ok(0)
+}
+
+fn tay<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.y = cpu.a;
+    cpu.status.update_zero_negative(cpu.y);
+    Ok(0)
+}
+
+fn txa<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.a = cpu.x;
+    cpu.status.update_zero_negative(cpu.a);
+    Ok(0)
+}
+
+fn tya<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.a = cpu.y;
+    cpu.status.update_zero_negative(cpu.a);
+    Ok(0)
+}
+
+// TSX, TXS
+fn tsx<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.x = cpu.sp;
+    cpu.status.update_zero_negative(cpu.x);
+    Ok(0)
+}
+
+fn txs<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.sp = cpu.x;
+    Ok(0)
+}
+
+// PHA, PLA, PHP, PLP
+fn pha<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    let a = cpu.a;
+    cpu.push(memory, a);
+    Ok(0)
+}
+
+fn pla<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    let val = cpu.pop(memory);
+    cpu.a = val;
+    cpu.status.update_zero_negative(cpu.a);
+    Ok(0)
+}
+
+fn php<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    let status = cpu.status.as_byte() | 0x30;
+    cpu.push(memory, status);
+    Ok(0)
+}
+
+fn plp<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    let val = cpu.pop(memory);
+    cpu.status = StatusFlags::from_byte(val);
+    cpu.status.unused = true;
+    Ok(0)
+}
+
+// JMP
+fn jmp<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
+    let result = cpu.get_operand_address(memory, mode);
+    cpu.pc = result.address;
+    Ok(0)
+}
+
+// JSR
+fn jsr<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    // The return address is pushed before the high byte of the target is
+    // read, as the real chip does
+    let lo = memory.read(cpu.pc) as u16;
+    let return_addr = cpu.pc.wrapping_add(1);
+    cpu.push_word(memory, return_addr);
+    let hi = memory.read(return_addr) as u16;
+    cpu.pc = (hi << 8) | lo;
+    Ok(0)
+}
+
+// RTS
+fn rts<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    let addr = cpu.pop_word(memory);
+    cpu.pc = addr.wrapping_add(1);
+    Ok(0)
+}
+
+// RTI
+fn rti<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    let status = cpu.pop(memory);
+    cpu.status = StatusFlags::from_byte(status);
+    cpu.status.unused = true;
+    cpu.pc = cpu.pop_word(memory);
+    Ok(0)
+}
+
+// BRK
+fn brk<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.pc = cpu.pc.wrapping_add(1);
+    cpu.push_word(memory, cpu.pc);
+    cpu.status.break_flag = true;
+    let status = cpu.status.as_byte() | 0x10;
+    cpu.push(memory, status);
+    cpu.status.interrupt = true;
+    let lo = memory.read(0xFFFE) as u16;
+    let hi = memory.read(0xFFFF) as u16;
+    cpu.pc = (hi << 8) | lo;
+    Ok(0)
+}
+
+// NOP
+fn nop<M: Memory + ?Sized>(_cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    Ok(0)
+}
+
+// JAM (KIL)
+fn jam<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.pc = cpu.pc.wrapping_sub(1); // Leave PC on the JAM opcode
+    cpu.jammed = true;
+    tracing::warn!(target: "cpu", "JAM at ${:04X}", cpu.pc);
+    Ok(0)
 }
 
-fn branch(cpu: &mut Cpu, memory: &dyn Memory, condition: bool) -> Result<u8> {
+// Taken branches cost one extra cycle, plus one more when crossing a page
+fn branch<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, condition: bool) -> Result<u8> {
     let result = cpu.get_operand_address(memory, AddressingMode::Relative);
     if condition {
         cpu.pc = result.address;
-        Ok(3 + result.page_crossed as u8)
+        Ok(1 + result.page_crossed as u8)
     } else {
-        Ok(2)
+        Ok(0)
     }
 }
 
+fn bpl<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, !cpu.status.negative)
+}
+
+fn bmi<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, cpu.status.negative)
+}
+
+fn bvc<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, !cpu.status.overflow)
+}
+
+fn bvs<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, cpu.status.overflow)
+}
+
+fn bcc<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, !cpu.status.carry)
+}
+
+fn bcs<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, cpu.status.carry)
+}
+
+fn bne<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, !cpu.status.zero)
+}
+
+fn beq<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    branch(cpu, memory, cpu.status.zero)
+}
+
+// Flag instructions
+fn clc<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.status.carry = false;
+    Ok(0)
+}
+
+fn sec<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.status.carry = true;
+    Ok(0)
+}
+
+fn cli<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.status.interrupt = false;
+    Ok(0)
+}
+
+fn sei<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.status.interrupt = true;
+    Ok(0)
+}
+
+fn clv<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.status.overflow = false;
+    Ok(0)
+}
+
+fn cld<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.status.decimal = false;
+    Ok(0)
+}
+
+fn sed<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.status.decimal = true;
+    Ok(0)
+}
+
+// INX, INY, DEX, DEY
+fn inx<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.x = cpu.x.wrapping_add(1);
+    cpu.status.update_zero_negative(cpu.x);
+    Ok(0)
+}
+
+fn iny<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.y = cpu.y.wrapping_add(1);
+    cpu.status.update_zero_negative(cpu.y);
+    Ok(0)
+}
+
+fn dex<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.x = cpu.x.wrapping_sub(1);
+    cpu.status.update_zero_negative(cpu.x);
+    Ok(0)
+}
+
+fn dey<M: Memory + ?Sized>(cpu: &mut Cpu, _memory: &mut M, _mode: AddressingMode) -> Result<u8> {
+    cpu.y = cpu.y.wrapping_sub(1);
+    cpu.status.update_zero_negative(cpu.y);
+    Ok(0)
+}
+
 // ADC - Add with Carry
-fn adc(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn adc<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address);
     let carry = cpu.status.carry as u16;
     let sum = cpu.a as u16 + value as u16 + carry;
-    
+
+    if cpu.status.decimal {
+        adc_decimal(cpu, value, sum);
+        return Ok(result.page_crossed as u8);
+    }
+
     // Check for overflow: both operands same sign, result different sign
     let overflow = ((cpu.a ^ value) & 0x80) == 0 && ((cpu.a ^ sum as u8) & 0x80) != 0;
-    
+
     cpu.a = sum as u8;
     cpu.status.carry = sum > 0xFF;
     cpu.status.overflow = overflow;
     cpu.status.update_zero_negative(cpu.a);
-    
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 5 + result.page_crossed as u8,
-        _ => 0,
-    })
+
+    Ok(result.page_crossed as u8)
+}
+
+// ADC in decimal mode, as the NMOS 6502 does it: Z comes from the binary
+// sum, N and V from the sum with only the low digit adjusted
+fn adc_decimal(cpu: &mut Cpu, value: u8, binary: u16) {
+    let mut low = (cpu.a & 0x0F) as u16 + (value & 0x0F) as u16 + cpu.status.carry as u16;
+    if low > 0x09 {
+        low = ((low + 0x06) & 0x0F) + 0x10;
+    }
+    let mut sum = (cpu.a & 0xF0) as u16 + (value & 0xF0) as u16 + low;
+
+    cpu.status.zero = binary as u8 == 0;
+    cpu.status.negative = sum & 0x80 != 0;
+    cpu.status.overflow = ((cpu.a ^ value) & 0x80) == 0 && ((cpu.a as u16 ^ sum) & 0x80) != 0;
+    if sum > 0x9F {
+        sum += 0x60;
+    }
+    cpu.status.carry = sum > 0xFF;
+    cpu.a = sum as u8;
 }
 
 // SBC - Subtract with Carry
-fn sbc(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn sbc<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address);
     let carry = if cpu.status.carry { 1 } else { 0 };
     let diff = (cpu.a as i16) - (value as i16) - (1 - carry);
-    
+
     // Check for overflow
     let overflow = ((cpu.a ^ value) & 0x80) != 0 && ((cpu.a ^ diff as u8) & 0x80) != 0;
-    
-    cpu.a = diff as u8;
+
     cpu.status.carry = diff >= 0;
     cpu.status.overflow = overflow;
-    cpu.status.update_zero_negative(cpu.a);
-    
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 5 + result.page_crossed as u8,
-        _ => 0,
-    })
+    cpu.status.update_zero_negative(diff as u8);
+
+    // In decimal mode the flags are still those of the binary difference
+    cpu.a = if cpu.status.decimal {
+        sbc_decimal(cpu.a, value, carry)
+    } else {
+        diff as u8
+    };
+
+    Ok(result.page_crossed as u8)
+}
+
+fn sbc_decimal(a: u8, value: u8, carry: i16) -> u8 {
+    let mut low = (a & 0x0F) as i16 - (value & 0x0F) as i16 + carry - 1;
+    if low < 0 {
+        low = ((low - 0x06) & 0x0F) - 0x10;
+    }
+    let mut diff = (a & 0xF0) as i16 - (value & 0xF0) as i16 + low;
+    if diff < 0 {
+        diff -= 0x60;
+    }
+    diff as u8
 }
 
 // AND - Logical AND
-fn and(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn and<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     cpu.a &= memory.read(result.address);
     cpu.status.update_zero_negative(cpu.a);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 5 + result.page_crossed as u8,
-        _ => 0,
-    })
+    Ok(result.page_crossed as u8)
 }
 
 // ORA - Logical OR
-fn ora(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn ora<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     cpu.a |= memory.read(result.address);
     cpu.status.update_zero_negative(cpu.a);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 5 + result.page_crossed as u8,
-        _ => 0,
-    })
+    Ok(result.page_crossed as u8)
 }
 
 // EOR - Logical XOR
-fn eor(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn eor<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     cpu.a ^= memory.read(result.address);
     cpu.status.update_zero_negative(cpu.a);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 5 + result.page_crossed as u8,
-        _ => 0,
-    })
+    Ok(result.page_crossed as u8)
 }
 
 // CMP - Compare Accumulator
-fn cmp(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn cmp<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address);
     let diff = cpu.a.wrapping_sub(value);
     cpu.status.carry = cpu.a >= value;
     cpu.status.update_zero_negative(diff);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::ZeroPageX => 4,
-        AddressingMode::Absolute => 4,
-        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => 4 + result.page_crossed as u8,
-        AddressingMode::IndirectX => 6,
-        AddressingMode::IndirectY => 5 + result.page_crossed as u8,
-        _ => 0,
-    })
+    Ok(result.page_crossed as u8)
 }
 
 // CPX - Compare X Register
-fn cpx(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn cpx<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address);
     let diff = cpu.x.wrapping_sub(value);
     cpu.status.carry = cpu.x >= value;
     cpu.status.update_zero_negative(diff);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::Absolute => 4,
-        _ => 0,
-    })
+    Ok(0)
 }
 
 // CPY - Compare Y Register
-fn cpy(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn cpy<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address);
     let diff = cpu.y.wrapping_sub(value);
     cpu.status.carry = cpu.y >= value;
     cpu.status.update_zero_negative(diff);
-    Ok(match mode {
-        AddressingMode::Immediate => 2,
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::Absolute => 4,
-        _ => 0,
-    })
+    Ok(0)
 }
 
 // INC - Increment Memory
-fn inc(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn inc<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address).wrapping_add(1);
     memory.write(result.address, value);
     cpu.status.update_zero_negative(value);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 5,
-        AddressingMode::ZeroPageX => 6,
-        AddressingMode::Absolute => 6,
-        AddressingMode::AbsoluteX => 7,
-        _ => 0,
-    })
+    Ok(0)
 }
 
 // DEC - Decrement Memory
-fn dec(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn dec<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address).wrapping_sub(1);
     memory.write(result.address, value);
     cpu.status.update_zero_negative(value);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 5,
-        AddressingMode::ZeroPageX => 6,
-        AddressingMode::Absolute => 6,
-        AddressingMode::AbsoluteX => 7,
-        _ => 0,
-    })
+    Ok(0)
 }
 
-// ASL - Arithmetic Shift Left (Accumulator)
-fn asl_acc(cpu: &mut Cpu) -> Result<u8> {
-    cpu.status.carry = (cpu.a & 0x80) != 0;
-    cpu.a <<= 1;
-    cpu.status.update_zero_negative(cpu.a);
-    Ok(2)
+// Shared read-modify-write path for the shifts and rotates: applies `op` to
+// the accumulator or the memory operand and updates N/Z from the result
+fn shift<M: Memory + ?Sized>(
+    cpu: &mut Cpu,
+    memory: &mut M,
+    mode: AddressingMode,
+    op: fn(&mut Cpu, u8) -> u8,
+) -> Result<u8> {
+    if mode == AddressingMode::Accumulator {
+        cpu.a = op(cpu, cpu.a);
+        cpu.status.update_zero_negative(cpu.a);
+    } else {
+        let result = cpu.get_operand_address(memory, mode);
+        let value = memory.read(result.address);
+        let new_value = op(cpu, value);
+        memory.write(result.address, new_value);
+        cpu.status.update_zero_negative(new_value);
+    }
+    Ok(0)
 }
 
-// ASL - Arithmetic Shift Left (Memory)
-fn asl(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
-    let result = cpu.get_operand_address(memory, mode);
-    let value = memory.read(result.address);
-    cpu.status.carry = (value & 0x80) != 0;
-    let new_value = value << 1;
-    memory.write(result.address, new_value);
-    cpu.status.update_zero_negative(new_value);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 5,
-        AddressingMode::ZeroPageX => 6,
-        AddressingMode::Absolute => 6,
-        AddressingMode::AbsoluteX => 7,
-        _ => 0,
+// ASL - Arithmetic Shift Left
+fn asl<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
+    shift(cpu, memory, mode, |cpu, value| {
+        cpu.status.carry = (value & 0x80) != 0;
+        value << 1
     })
 }
 
-// LSR - Logical Shift Right (Accumulator)
-fn lsr_acc(cpu: &mut Cpu) -> Result<u8> {
-    cpu.status.carry = (cpu.a & 0x01) != 0;
-    cpu.a >>= 1;
-    cpu.status.update_zero_negative(cpu.a);
-    Ok(2)
-}
-
-// LSR - Logical Shift Right (Memory)
-fn lsr(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
-    let result = cpu.get_operand_address(memory, mode);
-    let value = memory.read(result.address);
-    cpu.status.carry = (value & 0x01) != 0;
-    let new_value = value >> 1;
-    memory.write(result.address, new_value);
-    cpu.status.update_zero_negative(new_value);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 5,
-        AddressingMode::ZeroPageX => 6,
-        AddressingMode::Absolute => 6,
-        AddressingMode::AbsoluteX => 7,
-        _ => 0,
+// LSR - Logical Shift Right
+fn lsr<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
+    shift(cpu, memory, mode, |cpu, value| {
+        cpu.status.carry = (value & 0x01) != 0;
+        value >> 1
     })
 }
 
-// ROL - Rotate Left (Accumulator)
-fn rol_acc(cpu: &mut Cpu) -> Result<u8> {
-    let old_carry = cpu.status.carry as u8;
-    cpu.status.carry = (cpu.a & 0x80) != 0;
-    cpu.a = (cpu.a << 1) | old_carry;
-    cpu.status.update_zero_negative(cpu.a);
-    Ok(2)
-}
-
-// ROL - Rotate Left (Memory)
-fn rol(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
-    let result = cpu.get_operand_address(memory, mode);
-    let value = memory.read(result.address);
-    let old_carry = cpu.status.carry as u8;
-    cpu.status.carry = (value & 0x80) != 0;
-    let new_value = (value << 1) | old_carry;
-    memory.write(result.address, new_value);
-    cpu.status.update_zero_negative(new_value);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 5,
-        AddressingMode::ZeroPageX => 6,
-        AddressingMode::Absolute => 6,
-        AddressingMode::AbsoluteX => 7,
-        _ => 0,
+// ROL - Rotate Left
+fn rol<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
+    shift(cpu, memory, mode, |cpu, value| {
+        let old_carry = cpu.status.carry as u8;
+        cpu.status.carry = (value & 0x80) != 0;
+        (value << 1) | old_carry
     })
 }
 
-// ROR - Rotate Right (Accumulator)
-fn ror_acc(cpu: &mut Cpu) -> Result<u8> {
-    let old_carry = cpu.status.carry as u8;
-    cpu.status.carry = (cpu.a & 0x01) != 0;
-    cpu.a = (cpu.a >> 1) | (old_carry << 7);
-    cpu.status.update_zero_negative(cpu.a);
-    Ok(2)
-}
-
-// ROR - Rotate Right (Memory)
-fn ror(cpu: &mut Cpu, memory: &mut dyn Memory, mode: AddressingMode) -> Result<u8> {
-    let result = cpu.get_operand_address(memory, mode);
-    let value = memory.read(result.address);
-    let old_carry = cpu.status.carry as u8;
-    cpu.status.carry = (value & 0x01) != 0;
-    let new_value = (value >> 1) | (old_carry << 7);
-    memory.write(result.address, new_value);
-    cpu.status.update_zero_negative(new_value);
-    Ok(match mode {
-        AddressingMode::ZeroPage => 5,
-        AddressingMode::ZeroPageX => 6,
-        AddressingMode::Absolute => 6,
-        AddressingMode::AbsoluteX => 7,
-        _ => 0,
+// ROR - Rotate Right
+fn ror<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
+    shift(cpu, memory, mode, |cpu, value| {
+        let old_carry = cpu.status.carry as u8;
+        cpu.status.carry = (value & 0x01) != 0;
+        (value >> 1) | (old_carry << 7)
     })
 }
 
 // BIT - Test Bits
-fn bit(cpu: &mut Cpu, memory: &dyn Memory, mode: AddressingMode) -> Result<u8> {
+fn bit<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M, mode: AddressingMode) -> Result<u8> {
     let result = cpu.get_operand_address(memory, mode);
     let value = memory.read(result.address);
     cpu.status.zero = (cpu.a & value) == 0;
     cpu.status.negative = (value & 0x80) != 0;
     cpu.status.overflow = (value & 0x40) != 0;
-    Ok(match mode {
-        AddressingMode::ZeroPage => 3,
-        AddressingMode::Absolute => 4,
-        _ => 0,
-    })
+    Ok(0)
 }
@@ -1,7 +1,8 @@
 #[cfg(test)]
 mod tests {
-    use crate::cpu::Cpu;
+    use crate::cpu::{Cpu, StepResult};
     use crate::memory::{Memory, BasicMemory};
+    use serde_json::Value;
 
     #[test]
     fn test_lda_immediate() {
@@ -109,7 +110,7 @@ mod tests {
         let mut memory = BasicMemory::new();
         
         cpu.push(&mut memory, 0x42);
-        let val = cpu.pop(&memory);
+        let val = cpu.pop(&mut memory);
         
         assert_eq!(val, 0x42);
     }
@@ -169,6 +170,63 @@ mod tests {
         assert_eq!(cpu.status.carry, true);
     }
 
+    #[test]
+    fn test_adc_sbc_decimal() {
+        // SED, ADC #$27, SBC #$49
+        let mut cpu = Cpu::new();
+        let mut memory = BasicMemory::new();
+        for (i, byte) in [0xF8, 0x69, 0x27, 0xE9, 0x49].into_iter().enumerate() {
+            memory.write(i as u16, byte);
+        }
+        
+        cpu.a = 0x75;
+        cpu.pc = 0x0000;
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        
+        // 75 + 27 = 102
+        assert_eq!(cpu.a, 0x02);
+        assert!(cpu.status.carry);
+        
+        // 02 - 49 = -47, borrowing
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.a, 0x53);
+        assert!(!cpu.status.carry);
+    }
+
+    #[test]
+    fn test_adc_sbc_decimal_flags() {
+        // SED, ADC #$01, LDA #$50, SBC #$25, CLD, ADC #$05
+        let mut cpu = Cpu::new();
+        let mut memory = BasicMemory::new();
+        for (i, byte) in [0xF8, 0x69, 0x01, 0xA9, 0x50, 0xE9, 0x25, 0xD8, 0x69, 0x05].into_iter().enumerate() {
+            memory.write(i as u16, byte);
+        }
+
+        cpu.a = 0x99;
+        cpu.pc = 0x0000;
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+
+        // 99 + 01 = 100. Z comes from the binary sum ($9A) and N from the
+        // sum before the high digit is adjusted ($A0).
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.status.carry);
+        assert!(!cpu.status.zero);
+        assert!(cpu.status.negative);
+
+        // 50 - 25 = 25, no borrow
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.a, 0x25);
+        assert!(cpu.status.carry);
+
+        // Back in binary mode: $25 + $05 + carry = $2B
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.a, 0x2B);
+    }
+
     #[test]
     fn test_and() {
         let mut cpu = Cpu::new();
@@ -352,4 +410,548 @@ mod tests {
         assert_eq!(cpu.status.negative, true); // Bit 7 set
         assert_eq!(cpu.status.overflow, true); // Bit 6 set
     }
+
+    #[test]
+    fn test_page_cross_and_branch_cycles() {
+        let mut cpu = Cpu::new();
+        let mut memory = BasicMemory::new();
+
+        // LDA $10FF,X with X=1 crosses into page $11: 4 + 1 cycles
+        memory.write(0x0000, 0xBD);
+        memory.write(0x0001, 0xFF);
+        memory.write(0x0002, 0x10);
+        // STA $10FF,X always takes 5, page cross or not
+        memory.write(0x0003, 0x9D);
+        memory.write(0x0004, 0xFF);
+        memory.write(0x0005, 0x10);
+        // BNE +$02 (taken, same page): 2 + 1 cycles
+        memory.write(0x0006, 0xD0);
+        memory.write(0x0007, 0x02);
+
+        cpu.pc = 0x0000;
+        cpu.x = 0x01;
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(5));
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(5));
+        cpu.status.zero = false;
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(3));
+        assert_eq!(cpu.pc, 0x000A);
+    }
+
+    #[test]
+    fn test_instruction_table_metadata() {
+        use crate::cpu::addressing::AddressingMode;
+        use crate::cpu::opcodes::INSTRUCTIONS;
+
+        let lda_abs_x = &INSTRUCTIONS[0xBD];
+        assert_eq!(lda_abs_x.mnemonic, "LDA");
+        assert_eq!(lda_abs_x.mode, AddressingMode::AbsoluteX);
+        assert_eq!(lda_abs_x.bytes, 3);
+        assert_eq!(lda_abs_x.cycles, 4);
+
+        assert_eq!(INSTRUCTIONS[0x0A].mode, AddressingMode::Accumulator);
+        assert_eq!(INSTRUCTIONS[0x0A].bytes, 1);
+        assert_eq!(INSTRUCTIONS[0x00].cycles, 7);
+        assert!(INSTRUCTIONS[0x02].handler.is_some()); // JAM
+    }
+
+    // Fill memory with NOPs and point the IRQ/NMI vectors at known handlers
+    fn interrupt_test_memory() -> BasicMemory {
+        let mut memory = BasicMemory::new();
+        for addr in 0x0000..0x0100 {
+            memory.write(addr, 0xEA); // NOP
+        }
+        memory.write(0xFFFE, 0x00); // IRQ -> $C000
+        memory.write(0xFFFF, 0xC0);
+        memory.write(0xFFFA, 0x00); // NMI -> $D000
+        memory.write(0xFFFB, 0xD0);
+        memory
+    }
+
+    #[test]
+    fn test_irq_taken_at_instruction_boundary() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+        
+        cpu.pc = 0x0010;
+        cpu.status.interrupt = false;
+        cpu.step(&mut memory).unwrap(); // NOP
+        
+        cpu.set_irq_line(true);
+        let cycles = cpu.step(&mut memory).unwrap().cycles();
+        
+        assert_eq!(cycles, 7);
+        assert_eq!(cpu.pc, 0xC000);
+        assert!(cpu.status.interrupt);
+        // Return address and status (B clear) on the stack
+        assert_eq!(memory.read(0x01FD), 0x00);
+        assert_eq!(memory.read(0x01FC), 0x11);
+        assert_eq!(memory.read(0x01FB) & 0x30, 0x20);
+    }
+
+    #[test]
+    fn test_masked_irq_stays_pending() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+        
+        cpu.pc = 0x0010;
+        cpu.set_irq_line(true);
+        
+        // I is set: the level-triggered line is ignored but not forgotten
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0x0012);
+        
+        memory.write(0x0012, 0x58); // CLI
+        cpu.step(&mut memory).unwrap();
+        // CLI's effect is delayed: one more instruction runs first
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0x0014);
+        
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0xC000);
+    }
+
+    #[test]
+    fn test_irq_recognized_after_sei() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+        
+        cpu.pc = 0x0010;
+        cpu.status.interrupt = false;
+        cpu.step(&mut memory).unwrap(); // NOP
+        
+        memory.write(0x0011, 0x78); // SEI
+        cpu.step(&mut memory).unwrap();
+        
+        // The poll during SEI still saw I clear, so the IRQ is taken right after it
+        cpu.set_irq_line(true);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0xC000);
+    }
+
+    #[test]
+    fn test_irq_released_before_boundary_is_not_taken() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+        
+        cpu.pc = 0x0010;
+        cpu.status.interrupt = false;
+        cpu.step(&mut memory).unwrap();
+        
+        cpu.set_irq_line(true);
+        cpu.set_irq_line(false);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0x0012);
+    }
+
+    #[test]
+    fn test_nmi_is_edge_triggered() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+        for addr in 0xD000..0xD010 {
+            memory.write(addr, 0xEA);
+        }
+        
+        cpu.pc = 0x0010;
+        cpu.set_nmi_line(true);
+        let cycles = cpu.step(&mut memory).unwrap().cycles();
+        assert_eq!(cycles, 7);
+        assert_eq!(cpu.pc, 0xD000);
+        
+        // Holding the line does not retrigger
+        cpu.set_nmi_line(true);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0xD001);
+        
+        // A new falling edge does, even with I set
+        cpu.set_nmi_line(false);
+        cpu.set_nmi_line(true);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0xD000);
+    }
+
+    #[test]
+    fn test_rdy_low_halts_cpu() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+        
+        cpu.pc = 0x0010;
+        cpu.set_rdy(false);
+        for _ in 0..40 {
+            assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(1));
+        }
+        assert_eq!(cpu.pc, 0x0010);
+        assert_eq!(cpu.cycles, 40);
+        
+        cpu.set_rdy(true);
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(2));
+        assert_eq!(cpu.pc, 0x0011);
+    }
+
+    #[test]
+    fn test_jam_halts_until_reset() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+        
+        cpu.pc = 0x0010;
+        memory.write(0x0010, 0x02); // JAM
+        cpu.step(&mut memory).unwrap();
+        
+        assert!(cpu.is_jammed());
+        assert_eq!(cpu.pc, 0x0010);
+        
+        // No further instructions or interrupts while jammed
+        cpu.set_nmi_line(true);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0x0010);
+        
+        memory.write(0xFFFC, 0x20);
+        memory.write(0xFFFD, 0x00);
+        cpu.reset(&mut memory);
+        assert!(!cpu.is_jammed());
+        assert_eq!(cpu.pc, 0x0020);
+    }
+
+    #[test]
+    fn test_breakpoint_stops_before_instruction() {
+        let mut cpu = Cpu::new();
+        let mut memory = BasicMemory::new();
+        memory.write(0x0000, 0xE8); // INX
+        memory.write(0x0001, 0xE8); // INX
+        memory.write(0x0002, 0x4C); // JMP $0001
+        memory.write(0x0003, 0x01);
+        memory.write(0x0004, 0x00);
+        cpu.add_breakpoint(0x0001);
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(2));
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::BreakpointHit(0x0001));
+        assert_eq!(cpu.x, 1);
+        assert_eq!(cpu.cycles, 2);
+
+        // Stepping again resumes past the breakpoint, and it fires on the next visit
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(2));
+        assert_eq!(cpu.x, 2);
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(3));
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::BreakpointHit(0x0001));
+
+        cpu.remove_breakpoint(0x0001);
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(2));
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(3));
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepResult::Executed(2));
+        assert_eq!(cpu.x, 4);
+    }
+
+    #[test]
+    fn test_cpu_state_round_trip() {
+        let mut cpu = Cpu::new();
+        let mut memory = interrupt_test_memory();
+
+        cpu.a = 0x12;
+        cpu.x = 0x34;
+        cpu.y = 0x56;
+        cpu.sp = 0xF0;
+        cpu.pc = 0x0010;
+        cpu.cycles = 123_456;
+        cpu.status.carry = true;
+        cpu.status.interrupt = false;
+        cpu.set_nmi_line(true); // Latched NMI must survive the round trip
+
+        let json = serde_json::to_string(&cpu).unwrap();
+        let mut restored: Cpu = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.a, 0x12);
+        assert_eq!(restored.x, 0x34);
+        assert_eq!(restored.y, 0x56);
+        assert_eq!(restored.sp, 0xF0);
+        assert_eq!(restored.pc, 0x0010);
+        assert_eq!(restored.cycles, 123_456);
+        assert_eq!(restored.status, cpu.status);
+
+        restored.step(&mut memory).unwrap();
+        assert_eq!(restored.pc, 0xD000);
+    }
+
+    #[test]
+    fn test_load_trap_requires_kernal_banked_in() {
+        use crate::memory::C64Memory;
+
+        // KERNAL image of NOPs; RAM under $FFD5 holds LDA #$42
+        let mut memory = C64Memory::new();
+        memory.load_kernal_rom(vec![0xEA; 0x2000]);
+        memory.write(0xFFD5, 0xA9);
+        memory.write(0xFFD6, 0x42);
+        memory.write(0x00BA, 1); // Device 1 (tape): the trap answers DEVICE NOT PRESENT
+
+        let call_load = |cpu: &mut Cpu, memory: &mut C64Memory| {
+            cpu.pc = 0xFFD5;
+            cpu.sp = 0xFD;
+            cpu.a = 0;
+            cpu.push_word(memory, 0x1233); // As if called by JSR from $1231
+            cpu.step(memory).unwrap();
+        };
+
+        // KERNAL mapped in: trapped and returned to the caller
+        let mut cpu = Cpu::new();
+        call_load(&mut cpu, &mut memory);
+        assert_eq!(cpu.a, 5);
+        assert_eq!(cpu.pc, 0x1234);
+
+        // Traps disabled: the ROM code runs
+        cpu.hle_traps = false;
+        call_load(&mut cpu, &mut memory);
+        assert_eq!(cpu.pc, 0xFFD6);
+
+        // KERNAL banked out: the RAM underneath runs
+        cpu.hle_traps = true;
+        memory.write(0x0001, 0x35);
+        call_load(&mut cpu, &mut memory);
+        assert_eq!(cpu.a, 0x42);
+    }
+
+    // Tom Harte's SingleStepTests (https://github.com/SingleStepTests/65x02)
+    //
+    // Each 6502/v1/XX.json file holds 10,000 randomized cases for opcode $XX with
+    // the register/RAM state before and after the instruction and every bus cycle.
+    // The cycle count and the bus accesses the CPU models are checked too.
+    // Point SINGLESTEP_TESTS_DIR at that directory and run:
+    //   cargo test singlestep
+
+    fn json_u16(value: &Value, key: &str) -> u16 {
+        value[key].as_u64().unwrap_or_else(|| panic!("missing field '{}'", key)) as u16
+    }
+
+    fn load_singlestep_state(cpu: &mut Cpu, memory: &mut BasicMemory, state: &Value) {
+        cpu.pc = json_u16(state, "pc");
+        cpu.sp = json_u16(state, "s") as u8;
+        cpu.a = json_u16(state, "a") as u8;
+        cpu.x = json_u16(state, "x") as u8;
+        cpu.y = json_u16(state, "y") as u8;
+        cpu.status = crate::cpu::StatusFlags::from_byte(json_u16(state, "p") as u8);
+        for entry in state["ram"].as_array().expect("ram") {
+            memory.write(entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8);
+        }
+    }
+
+    fn check_singlestep_state(cpu: &Cpu, memory: &BasicMemory, state: &Value) -> Result<(), String> {
+        let registers = [
+            ("pc", cpu.pc, json_u16(state, "pc")),
+            ("s", cpu.sp as u16, json_u16(state, "s")),
+            ("a", cpu.a as u16, json_u16(state, "a")),
+            ("x", cpu.x as u16, json_u16(state, "x")),
+            ("y", cpu.y as u16, json_u16(state, "y")),
+            // B and bit 5 only exist on the stack, not in the register
+            ("p", (cpu.status.as_byte() & 0xCF) as u16, json_u16(state, "p") & 0xCF),
+        ];
+        for (name, actual, expected) in registers {
+            if actual != expected {
+                return Err(format!("{}: got ${:02X}, expected ${:02X}", name, actual, expected));
+            }
+        }
+        for entry in state["ram"].as_array().expect("ram") {
+            let addr = entry[0].as_u64().unwrap() as u16;
+            let expected = entry[1].as_u64().unwrap() as u8;
+            let actual = memory.peek(addr);
+            if actual != expected {
+                return Err(format!("ram[${:04X}]: got ${:02X}, expected ${:02X}", addr, actual, expected));
+            }
+        }
+        Ok(())
+    }
+
+    // BasicMemory with every access on the bus logged, as the cycles of a case are
+    struct BusLog {
+        memory: BasicMemory,
+        accesses: Vec<(u16, u8, &'static str)>,
+    }
+
+    impl Memory for BusLog {
+        fn read(&mut self, addr: u16) -> u8 {
+            let value = self.memory.read(addr);
+            self.accesses.push((addr, value, "read"));
+            value
+        }
+
+        fn write(&mut self, addr: u16, value: u8) {
+            self.accesses.push((addr, value, "write"));
+            self.memory.write(addr, value);
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.memory.peek(addr)
+        }
+    }
+
+    // The CPU does its bus accesses in order but leaves out the dummy ones
+    // (the read of the byte after an implied or immediate opcode, the
+    // write-back of read-modify-write instructions, the read from the wrong
+    // page when indexing crosses one), so each access it does make has to
+    // match one of the remaining cycles: the address, the value and whether
+    // it was read or written
+    fn check_singlestep_bus(accesses: &[(u16, u8, &str)], cycles: &Value) -> Result<(), String> {
+        let mut cycles = cycles.as_array().expect("cycles").iter().map(|cycle| {
+            (cycle[0].as_u64().unwrap() as u16, cycle[1].as_u64().unwrap() as u8, cycle[2].as_str().unwrap())
+        });
+        for (i, &(addr, value, kind)) in accesses.iter().enumerate() {
+            if !cycles.any(|expected| expected == (addr, value, kind)) {
+                return Err(format!("bus access {}: {} ${:02X} at ${:04X} is not in the cycles", i + 1, kind, value, addr));
+            }
+        }
+        Ok(())
+    }
+
+    enum SingleStepOutcome {
+        Passed,
+        Failed(String),
+        Unsupported,
+    }
+
+    fn run_singlestep_case(case: &Value) -> SingleStepOutcome {
+        let mut cpu = Cpu::new();
+        let mut memory = BasicMemory::new();
+        load_singlestep_state(&mut cpu, &mut memory, &case["initial"]);
+        let mut bus = BusLog { memory, accesses: Vec::new() };
+        
+        let cycles = match cpu.step(&mut bus) {
+            Ok(result) => result.cycles(),
+            Err(_) => return SingleStepOutcome::Unsupported,
+        };
+        // JAM cases describe the bus while locked up, which we don't model
+        if cpu.is_jammed() {
+            return SingleStepOutcome::Unsupported;
+        }
+        
+        if let Err(e) = check_singlestep_state(&cpu, &bus.memory, &case["final"]) {
+            return SingleStepOutcome::Failed(e);
+        }
+        let expected_cycles = case["cycles"].as_array().map(|c| c.len()).unwrap_or(0);
+        if cycles as usize != expected_cycles {
+            return SingleStepOutcome::Failed(format!("cycles: got {}, expected {}", cycles, expected_cycles));
+        }
+        if let Err(e) = check_singlestep_bus(&bus.accesses, &case["cycles"]) {
+            return SingleStepOutcome::Failed(e);
+        }
+        SingleStepOutcome::Passed
+    }
+
+    #[test]
+    fn test_singlestep_case_format() {
+        // LDA #$42 in the SingleStepTests layout
+        let case: Value = serde_json::from_str(r#"{
+            "name": "a9 42 00",
+            "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38,
+                         "ram": [[512, 169], [513, 66]] },
+            "final":   { "pc": 514, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36,
+                         "ram": [[512, 169], [513, 66]] },
+            "cycles": [[512, 169, "read"], [513, 66, "read"]]
+        }"#).unwrap();
+        assert!(matches!(run_singlestep_case(&case), SingleStepOutcome::Passed));
+        
+        let mut wrong = case.clone();
+        wrong["final"]["a"] = Value::from(65);
+        assert!(matches!(run_singlestep_case(&wrong), SingleStepOutcome::Failed(_)));
+
+        // The right state and cycle count, but a write where the bus read
+        let mut wrong = case.clone();
+        wrong["cycles"][1][2] = Value::from("write");
+        assert!(matches!(run_singlestep_case(&wrong), SingleStepOutcome::Failed(_)));
+
+        // NOP reads the byte after it in its second cycle, which isn't modelled
+        let nop: Value = serde_json::from_str(r#"{
+            "name": "ea 00 00",
+            "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                         "ram": [[512, 234], [513, 0]] },
+            "final":   { "pc": 513, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                         "ram": [[512, 234], [513, 0]] },
+            "cycles": [[512, 234, "read"], [513, 0, "read"]]
+        }"#).unwrap();
+        assert!(matches!(run_singlestep_case(&nop), SingleStepOutcome::Passed));
+
+        // JSR $1234 pushes the return address before it reads the target's
+        // high byte
+        let jsr: Value = serde_json::from_str(r#"{
+            "name": "20 34 12",
+            "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                         "ram": [[512, 32], [513, 52], [514, 18], [509, 0]] },
+            "final":   { "pc": 4660, "s": 251, "a": 0, "x": 0, "y": 0, "p": 36,
+                         "ram": [[512, 32], [513, 52], [514, 18], [509, 2], [508, 2]] },
+            "cycles": [[512, 32, "read"], [513, 52, "read"], [509, 0, "read"],
+                       [509, 2, "write"], [508, 2, "write"], [514, 18, "read"]]
+        }"#).unwrap();
+        assert!(matches!(run_singlestep_case(&jsr), SingleStepOutcome::Passed));
+    }
+
+    #[test]
+    fn test_singlestep_all_opcodes() {
+        let Ok(dir) = std::env::var("SINGLESTEP_TESTS_DIR") else {
+            eprintln!("SINGLESTEP_TESTS_DIR not set, skipping");
+            return;
+        };
+        
+        let mut total_failed = 0;
+        for opcode in 0..=0xFFu8 {
+            let path = std::path::Path::new(&dir).join(format!("{:02x}.json", opcode));
+            let Ok(text) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let cases: Vec<Value> = serde_json::from_str(&text)
+                .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            
+            let mut failed = 0;
+            let mut first_failure = None;
+            for case in &cases {
+                match run_singlestep_case(case) {
+                    SingleStepOutcome::Passed => {}
+                    SingleStepOutcome::Unsupported => break,
+                    SingleStepOutcome::Failed(reason) => {
+                        failed += 1;
+                        first_failure.get_or_insert_with(|| format!("{} -> {}", case["name"], reason));
+                    }
+                }
+            }
+            if let Some(first) = first_failure {
+                eprintln!("${:02X}: {}/{} failed, first: {}", opcode, failed, cases.len(), first);
+            }
+            total_failed += failed;
+        }
+        assert_eq!(total_failed, 0, "SingleStepTests failures");
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn bench_cpu_throughput() {
+        use crate::memory::C64Memory;
+        
+        let mut cpu = Cpu::new();
+        let mut memory = C64Memory::new();
+        
+        // $0800: LDX #$00
+        // $0802: LDA $1000,X / STA $2000,X / INX / BNE $0802
+        // $080A: JMP $0800
+        let program = [
+            0xA2, 0x00,
+            0xBD, 0x00, 0x10,
+            0x9D, 0x00, 0x20,
+            0xE8,
+            0xD0, 0xF7,
+            0x4C, 0x00, 0x08,
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            memory.write(0x0800 + i as u16, *byte);
+        }
+        cpu.pc = 0x0800;
+        
+        let instructions = 50_000_000u64;
+        let start = std::time::Instant::now();
+        for _ in 0..instructions {
+            cpu.step(&mut memory).unwrap();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        
+        println!(
+            "{} instructions, {} cycles in {:.3}s: {:.1} MIPS, {:.1}x a 985 kHz C64",
+            instructions, cpu.cycles, elapsed,
+            instructions as f64 / elapsed / 1e6,
+            cpu.cycles as f64 / elapsed / 985_248.0
+        );
+    }
 }
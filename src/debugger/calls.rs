@@ -0,0 +1,145 @@
+// Shadow stack of the calls the CPU is in, for the monitor's bt. The 6502
+// keeps only return addresses on its stack, so each JSR and interrupt is
+// noted here as it happens with the stack pointer it left. A frame is gone
+// once the stack pointer is back above it, however that came about: RTS,
+// RTI, or a program dropping its return address and jumping away.
+
+use crate::debugger::symbols::Labels;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    Call,
+    Irq,
+    Nmi,
+    Brk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub entry: Entry,
+    // Where the JSR was, or where the interrupt came, and where it went
+    pub from: u16,
+    pub to: u16,
+    // The stack pointer with the return address pushed
+    sp: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct CallStack {
+    // Outermost first
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // After an instruction or interrupt from `pc` with the stack pointer at
+    // `sp`, which left the CPU at `to` with it at `sp_after`. `interrupt` is
+    // the vector of an interrupt sequence.
+    pub fn record(&mut self, pc: u16, sp: u8, to: u16, sp_after: u8, interrupt: Option<u16>) {
+        // Only a JSR pushes two bytes, and only interrupts and BRK three
+        let entry = match (sp.wrapping_sub(sp_after), interrupt) {
+            (3, Some(NMI_VECTOR)) => Some(Entry::Nmi),
+            (3, Some(_)) => Some(Entry::Irq),
+            (3, None) => Some(Entry::Brk),
+            (2, None) => Some(Entry::Call),
+            _ => None,
+        };
+        // Frames at or above the stack pointer have been returned from, or
+        // pushed over
+        let left = if entry.is_some() { sp_after.wrapping_add(1) } else { sp_after };
+        while self.frames.last().is_some_and(|frame| frame.sp < left) {
+            self.frames.pop();
+        }
+        if let Some(entry) = entry {
+            self.frames.push(Frame { entry, from: pc, to, sp: sp_after });
+        }
+    }
+
+    // Innermost first
+    #[cfg(test)]
+    fn frames(&self) -> impl Iterator<Item = &Frame> {
+        self.frames.iter().rev()
+    }
+
+    // A line a frame, gdb style: where the CPU is and the routine it is
+    // in, then where each call came from and the routine that made it
+    pub fn backtrace(&self, pc: u16, labels: &Labels) -> Vec<String> {
+        let routine = |frame: Option<&Frame>| match frame {
+            Some(frame) => labels.describe(frame.to).unwrap_or_else(|| format!("${:04X}", frame.to)),
+            None => "?".to_string(),
+        };
+        let mut frames = self.frames.iter().rev().peekable();
+        let mut lines = vec![format!("#0  ${:04X} in {}", pc, routine(frames.peek().copied()))];
+        while let Some(frame) = frames.next() {
+            let how = match frame.entry {
+                Entry::Call => "",
+                Entry::Irq => ", interrupted by IRQ",
+                Entry::Nmi => ", interrupted by NMI",
+                Entry::Brk => ", BRK",
+            };
+            lines.push(format!("#{:<2} ${:04X} in {}{}", lines.len(), frame.from, routine(frames.peek().copied()), how));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{Machine, VideoStandard};
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_backtrace() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        // $1000 calls $1100, which calls CHROUT's entry in RAM at $FFD2 and
+        // then drops its return address to jump back to $1000
+        let code: [(u16, &[u8]); 3] = [
+            (0x1000, &[0x20, 0x00, 0x11]),
+            (0x1100, &[0x20, 0xD2, 0xFF, 0x68, 0x68, 0x4C, 0x00, 0x10]),
+            (0xFFD2, &[0xEA, 0x60]),
+        ];
+        machine.memory.write(0x0000, 0x2F);
+        machine.memory.write(0x0001, 0x34);
+        for (addr, bytes) in code {
+            for (i, byte) in bytes.iter().enumerate() {
+                machine.memory.write(addr + i as u16, *byte);
+            }
+        }
+        let mut labels = Labels::new();
+        labels.add(0x1100, "print".to_string());
+        machine.cpu.pc = 0x1000;
+
+        // JSR, JSR, NOP
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(
+            machine.calls.backtrace(machine.cpu.pc, &labels),
+            ["#0  $FFD3 in CHROUT", "#1  $1100 in .print", "#2  $1000 in ?"]
+        );
+        // RTS, then the PLAs drop the return address
+        machine.step().unwrap();
+        assert_eq!(machine.calls.frames().count(), 1);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.calls.backtrace(machine.cpu.pc, &labels), ["#0  $1105 in ?"]);
+
+        // An interrupt during a call
+        let mut calls = CallStack::new();
+        calls.record(0x1000, 0xF0, 0x1100, 0xEE, None);
+        calls.record(0x1103, 0xEE, 0xFF48, 0xEB, Some(0xFFFE));
+        calls.record(0xFF48, 0xEB, 0xEA31, 0xEB, None);
+        assert_eq!(calls.backtrace(0xEA31, &labels), ["#0  $EA31 in IRQ", "#1  $1103 in .print, interrupted by IRQ", "#2  $1000 in ?"]);
+        // RTI, RTS and another call
+        calls.record(0xEA81, 0xEB, 0x1103, 0xEE, None);
+        calls.record(0x1110, 0xEE, 0x1003, 0xF0, None);
+        calls.record(0x1003, 0xF0, 0x1100, 0xEE, None);
+        assert_eq!(calls.frames().map(|frame| frame.from).collect::<Vec<_>>(), [0x1003]);
+    }
+}
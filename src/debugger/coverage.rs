@@ -0,0 +1,200 @@
+// Code coverage (--coverage, --coverage-listing): every address the CPU
+// runs an instruction from, and every address it reads or writes as data,
+// for mapping which parts of a program run and what they touch. The bus
+// tells the CPU fetching an instruction apart from reading data by the
+// address the step is expected to start at.
+
+use std::fmt::Write;
+use std::ops::{Range, RangeInclusive};
+
+use super::disassemble;
+use super::symbols::Labels;
+use super::watch::Access;
+use crate::cpu::opcodes::INSTRUCTIONS;
+use crate::memory::Memory;
+
+// What happened at an address, a bit each
+const EXECUTED: u8 = 0x01; // An instruction started here
+const OPERAND: u8 = 0x02; // Fetched as part of an instruction
+const READ: u8 = 0x04;
+const WRITTEN: u8 = 0x08;
+const CODE: u8 = EXECUTED | OPERAND;
+
+// Gaps in the code up to this long are listed with the code around them
+const LISTING_GAP: u32 = 16;
+
+pub struct Coverage {
+    flags: Vec<u8>,
+    // Where the next instruction is fetched from, then its operand bytes
+    // once the opcode has been
+    fetch: Option<u16>,
+    operands: Range<u32>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self { flags: vec![0; 0x10000], fetch: None, operands: 0..0 }
+    }
+
+    // Before a step: the instruction at `pc` is about to be fetched, or
+    // (None) an interrupt sequence is about to run
+    pub fn expect_instruction(&mut self, pc: Option<u16>) {
+        self.fetch = pc;
+        self.operands = 0..0;
+    }
+
+    // A read or write by the CPU on the bus
+    pub fn record(&mut self, access: Access, addr: u16, value: u8) {
+        let flag = match access {
+            Access::Write => WRITTEN,
+            Access::Read if self.fetch == Some(addr) => {
+                self.fetch = None;
+                let bytes = INSTRUCTIONS[value as usize].bytes as u32;
+                self.operands = addr as u32 + 1..addr as u32 + bytes;
+                EXECUTED
+            }
+            Access::Read if self.operands.contains(&(addr as u32)) => OPERAND,
+            Access::Read => READ,
+        };
+        self.flags[addr as usize] |= flag;
+    }
+
+    // Summary and the ranges executed, read and written
+    pub fn report(&self, labels: &Labels) -> String {
+        let count = |flag: u8| self.flags.iter().filter(|&&flags| flags & flag != 0).count();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Coverage: {} instructions at {} code bytes, {} bytes read, {} written",
+            count(EXECUTED),
+            count(CODE),
+            count(READ),
+            count(WRITTEN)
+        );
+        for (title, flag) in [("Executed", CODE), ("Read", READ), ("Written", WRITTEN)] {
+            let _ = writeln!(out, "{}:", title);
+            for range in self.runs(flag) {
+                let span = if range.start() == range.end() {
+                    format!("${:04X}", range.start())
+                } else {
+                    format!("${:04X}-${:04X}", range.start(), range.end())
+                };
+                let name = labels.describe(*range.start() as u16).unwrap_or_default();
+                let _ = writeln!(out, "{}", format!("  {:<12} {}", span, name).trim_end());
+            }
+        }
+        out
+    }
+
+    // A disassembly of the code that ran and the gaps in it, a line an
+    // instruction marked with whether it ran (x), was read (r) or written
+    // (w). Code that never ran is disassembled as far as it doesn't run
+    // into an instruction that did, and the rest left as bytes.
+    pub fn listing(&self, memory: &dyn Memory, labels: &Labels) -> String {
+        let mut out = String::new();
+        for area in self.areas() {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            let mut addr = *area.start();
+            while addr <= *area.end() {
+                if let Some(label) = labels.name(addr as u16) {
+                    let _ = writeln!(out, ".{}:", label);
+                }
+                let (mut text, mut bytes) = disassemble(memory, addr as u16);
+                let ran = self.flags[addr as usize] & EXECUTED != 0;
+                let overlaps = (addr + 1..addr + bytes as u32).any(|at| at > 0xFFFF || self.flags[at as usize] & EXECUTED != 0);
+                if !ran && (overlaps || INSTRUCTIONS[memory.peek(addr as u16) as usize].handler.is_none()) {
+                    text = format!(".byte ${:02X}", memory.peek(addr as u16));
+                    bytes = 1;
+                }
+                let hex: Vec<String> = (0..bytes).map(|i| format!("{:02X}", memory.peek((addr as u16).wrapping_add(i)))).collect();
+                let _ = writeln!(out, "{} {:04X}  {:<8}  {}", self.marks(addr as u16), addr, hex.join(" "), text);
+                addr += bytes as u32;
+            }
+        }
+        out
+    }
+
+    // e.g. "x-w" for an instruction the program wrote over
+    fn marks(&self, addr: u16) -> String {
+        let flags = self.flags[addr as usize];
+        [(EXECUTED, 'x'), (READ, 'r'), (WRITTEN, 'w')]
+            .iter()
+            .map(|&(flag, mark)| if flags & flag != 0 { mark } else { '-' })
+            .collect()
+    }
+
+    // Runs of addresses with `flag`
+    fn runs(&self, flag: u8) -> Vec<RangeInclusive<u32>> {
+        let mut runs: Vec<RangeInclusive<u32>> = Vec::new();
+        for addr in (0..0x10000u32).filter(|&addr| self.flags[addr as usize] & flag != 0) {
+            match runs.last_mut() {
+                Some(run) if *run.end() + 1 == addr => *run = *run.start()..=addr,
+                _ => runs.push(addr..=addr),
+            }
+        }
+        runs
+    }
+
+    // The code that ran, with short gaps in it joined up
+    fn areas(&self) -> Vec<RangeInclusive<u32>> {
+        let mut areas: Vec<RangeInclusive<u32>> = Vec::new();
+        for run in self.runs(CODE) {
+            match areas.last_mut() {
+                Some(area) if *run.start() - *area.end() <= LISTING_GAP => *area = *area.start()..=*run.end(),
+                _ => areas.push(run),
+            }
+        }
+        areas
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{Machine, VideoStandard};
+
+    #[test]
+    fn test_coverage() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        // LDA $2000, STA $D020, then a loop counting up the byte it jumps
+        // over
+        let code = [0xAD, 0x00, 0x20, 0x8D, 0x20, 0xD0, 0xEE, 0x0B, 0xC0, 0x90, 0x01, 0x2C, 0x4C, 0x06, 0xC0];
+        for (i, byte) in code.iter().enumerate() {
+            machine.memory.write(0xC000 + i as u16, *byte);
+        }
+        machine.memory.coverage = Some(Coverage::new());
+        machine.cpu.pc = 0xC000;
+        for _ in 0..8 {
+            machine.step().unwrap();
+        }
+        let mut labels = Labels::new();
+        labels.add(0xC006, "loop".to_string());
+
+        let coverage = machine.memory.coverage.take().unwrap();
+        assert_eq!(
+            coverage.report(&labels),
+            "Coverage: 5 instructions at 14 code bytes, 2 bytes read, 2 written\n\
+             Executed:\n  $C000-$C00A\n  $C00C-$C00E\n\
+             Read:\n  $2000\n  $C00B\n\
+             Written:\n  $C00B\n  $D020        border color\n"
+        );
+        assert_eq!(
+            coverage.listing(&machine.memory, &labels),
+            "x-- C000  AD 00 20  LDA $2000\n\
+             x-- C003  8D 20 D0  STA $D020\n\
+             .loop:\n\
+             x-- C006  EE 0B C0  INC $C00B\n\
+             x-- C009  90 01     BCC $C00C\n\
+             -rw C00B  2E        .byte $2E\n\
+             x-- C00C  4C 06 C0  JMP $C006\n"
+        );
+    }
+}
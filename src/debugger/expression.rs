@@ -0,0 +1,297 @@
+// Expressions the debug view shows the value of as the machine runs (the
+// monitor's disp, --display), for keeping an eye on variables without
+// stopping. Numbers are hex as in the monitor, with or without a $, but A
+// is the accumulator; @(addr) is the byte at an address and @w(addr) the
+// little-endian word there, read as the CPU sees memory without touching
+// the I/O registers.
+//
+//   A  X  Y  SP  P  PC      the registers
+//   $FB  D012  .label       numbers, and labels for their addresses
+//   @(addr)  @w(addr)       memory
+//   + - & | ^  ( )          arithmetic, the usual precedence
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+use super::symbols::{is_label, Labels};
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+}
+
+impl Op {
+    // Loosest first, as in C
+    fn precedence(self) -> u8 {
+        match self {
+            Op::Or => 0,
+            Op::Xor => 1,
+            Op::And => 2,
+            Op::Add | Op::Sub => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(Value),
+    Register(Register),
+    Peek { addr: Box<Node>, word: bool },
+    Binary(Op, Box<Node>, Box<Node>),
+}
+
+// A byte or a word, by what went into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Value {
+    pub value: u16,
+    pub word: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    text: String,
+    node: Node,
+}
+
+impl Expression {
+    // Labels are looked up now, so a mistyped one is caught at once
+    pub fn parse(text: &str, labels: &Labels) -> Result<Self> {
+        let text = text.trim();
+        let mut parser = Parser { chars: text.chars().collect(), at: 0, labels };
+        let node = parser.expression(0)?;
+        parser.skip_spaces();
+        if let Some(c) = parser.peek() {
+            bail!("unexpected {:?} in {:?}", c, text);
+        }
+        Ok(Self { text: text.to_string(), node })
+    }
+
+    pub fn evaluate(&self, cpu: &Cpu, memory: &dyn Memory) -> Value {
+        evaluate(&self.node, cpu, memory)
+    }
+}
+
+fn evaluate(node: &Node, cpu: &Cpu, memory: &dyn Memory) -> Value {
+    let byte = |value: u8| Value { value: value as u16, word: false };
+    match node {
+        Node::Number(value) => *value,
+        Node::Register(Register::A) => byte(cpu.a),
+        Node::Register(Register::X) => byte(cpu.x),
+        Node::Register(Register::Y) => byte(cpu.y),
+        Node::Register(Register::Sp) => byte(cpu.sp),
+        Node::Register(Register::P) => byte(cpu.status.as_byte()),
+        Node::Register(Register::Pc) => Value { value: cpu.pc, word: true },
+        Node::Peek { addr, word: false } => byte(memory.peek(evaluate(addr, cpu, memory).value)),
+        Node::Peek { addr, word: true } => {
+            let addr = evaluate(addr, cpu, memory).value;
+            let value = u16::from_le_bytes([memory.peek(addr), memory.peek(addr.wrapping_add(1))]);
+            Value { value, word: true }
+        }
+        Node::Binary(op, left, right) => {
+            let (left, right) = (evaluate(left, cpu, memory), evaluate(right, cpu, memory));
+            let value = match op {
+                Op::Add => left.value.wrapping_add(right.value),
+                Op::Sub => left.value.wrapping_sub(right.value),
+                Op::And => left.value & right.value,
+                Op::Or => left.value | right.value,
+                Op::Xor => left.value ^ right.value,
+            };
+            // Byte arithmetic wraps round as the CPU's does
+            let word = left.word || right.word;
+            Value { value: if word { value } else { value & 0xFF }, word }
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    at: usize,
+    labels: &'a Labels,
+}
+
+impl Parser<'_> {
+    // Operators binding at least as tightly as `precedence`, left to right
+    fn expression(&mut self, precedence: u8) -> Result<Node> {
+        let mut node = self.operand()?;
+        loop {
+            self.skip_spaces();
+            let op = match self.peek() {
+                Some('+') => Op::Add,
+                Some('-') => Op::Sub,
+                Some('&') => Op::And,
+                Some('|') => Op::Or,
+                Some('^') => Op::Xor,
+                _ => return Ok(node),
+            };
+            if op.precedence() < precedence {
+                return Ok(node);
+            }
+            self.at += 1;
+            let right = self.expression(op.precedence() + 1)?;
+            node = Node::Binary(op, Box::new(node), Box::new(right));
+        }
+    }
+
+    fn operand(&mut self) -> Result<Node> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('(') => {
+                self.at += 1;
+                self.bracketed()
+            }
+            Some('@') => {
+                self.at += 1;
+                let word = matches!(self.peek(), Some('w' | 'W'));
+                if word {
+                    self.at += 1;
+                }
+                if self.peek() != Some('(') {
+                    bail!("expected ( after @");
+                }
+                self.at += 1;
+                Ok(Node::Peek { addr: Box::new(self.bracketed()?), word })
+            }
+            Some('$') => {
+                self.at += 1;
+                let digits = self.word();
+                number(&digits)
+            }
+            Some('.') => {
+                self.at += 1;
+                let label = self.word();
+                if !is_label(&label) {
+                    bail!("expected a label after .");
+                }
+                match self.labels.address(&label) {
+                    Some(addr) => Ok(Node::Number(Value { value: addr, word: true })),
+                    None => bail!("unknown label .{}", label),
+                }
+            }
+            Some(_) => {
+                let word = self.word();
+                let register = match word.to_ascii_uppercase().as_str() {
+                    "A" => Register::A,
+                    "X" => Register::X,
+                    "Y" => Register::Y,
+                    "SP" => Register::Sp,
+                    "P" => Register::P,
+                    "PC" => Register::Pc,
+                    "" => bail!("unexpected {:?}", self.peek().unwrap_or_default()),
+                    _ => return number(&word),
+                };
+                Ok(Node::Register(register))
+            }
+            None => bail!("expression ends early"),
+        }
+    }
+
+    // The rest of a bracket, once the ( has gone
+    fn bracketed(&mut self) -> Result<Node> {
+        let node = self.expression(0)?;
+        self.skip_spaces();
+        if self.peek() != Some(')') {
+            bail!("missing )");
+        }
+        self.at += 1;
+        Ok(node)
+    }
+
+    fn word(&mut self) -> String {
+        let start = self.at;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+            self.at += 1;
+        }
+        self.chars[start..self.at].iter().collect()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.at += 1;
+        }
+    }
+}
+
+// Hex, a word if written with more than two digits
+fn number(digits: &str) -> Result<Node> {
+    match u16::from_str_radix(digits, 16) {
+        Ok(value) => Ok(Node::Number(Value { value, word: digits.len() > 2 })),
+        Err(_) => bail!("invalid number {:?}", digits),
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// In hex and decimal, e.g. "$FB 251"
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.word {
+            write!(f, "${:04X} {}", self.value, self.value)
+        } else {
+            write!(f, "${:02X} {}", self.value, self.value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BasicMemory;
+
+    #[test]
+    fn test_expressions() {
+        let mut memory = BasicMemory::new();
+        memory.write(0x00FB, 0x00);
+        memory.write(0x00FC, 0xC0);
+        memory.write(0xD012, 0x33);
+        let mut cpu = Cpu::new();
+        cpu.a = 0xFF;
+        cpu.x = 0x02;
+        cpu.pc = 0xE5CD;
+        let mut labels = Labels::new();
+        labels.add(0x00FB, "ptr".to_string());
+
+        let value = |text: &str| Expression::parse(text, &labels).unwrap().evaluate(&cpu, &memory).to_string();
+        assert_eq!(value("@w($FB)"), "$C000 49152");
+        assert_eq!(value("A"), "$FF 255");
+        assert_eq!(value("@($D012)"), "$33 51");
+        assert_eq!(value("@(d012)"), "$33 51");
+        assert_eq!(value("pc"), "$E5CD 58829");
+        // Bytes wrap as bytes, and take the usual precedence
+        assert_eq!(value("A + 1"), "$00 0");
+        assert_eq!(value("A + 0001"), "$0100 256");
+        assert_eq!(value("1 + A & 0F"), "$00 0");
+        assert_eq!(value("1 + (A & 0F)"), "$10 16");
+        assert_eq!(value("@w(.ptr) + X"), "$C002 49154");
+        assert_eq!(value("@(@w(.ptr - 1 + 1) | D012)"), "$33 51");
+        assert_eq!(Expression::parse(" @( $FB ) ", &labels).unwrap().to_string(), "@( $FB )");
+
+        for bad in ["", "@w $FB", "(A", "A +", "$G0", ".nowhere", "A B", "Q"] {
+            assert!(Expression::parse(bad, &labels).is_err(), "{:?}", bad);
+        }
+    }
+}
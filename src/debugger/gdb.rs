@@ -0,0 +1,580 @@
+// GDB remote serial protocol stub (--gdb PORT), so gdb, lldb and the IDEs
+// that drive them can debug the machine over TCP. The 6502 has no target of
+// its own in gdb, so the registers are described to it in target.xml:
+//
+//   0 a   1 x   2 y   3 p   4 sp   (8 bits)   5 pc (16 bits)
+//
+// Memory reads as the monitor's does, as the CPU sees it without the side
+// effects of reading I/O registers. Breakpoints (Z0/Z1) are the CPU's and
+// watchpoints (Z2-Z4) the bus's, so the monitor lists them too.
+//
+// The stub is polled from the main loop. A debugger connecting stops the
+// machine, as does Ctrl-C from it; continuing hands the machine back to the
+// main loop, which reports where it stops with stopped(). Only one debugger
+// is served at a time.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use anyhow::{Context, Result};
+
+use super::watch::{Access, BusEvent, Watch, WatchKind, Watches};
+use crate::cpu::{StatusFlags, StepResult};
+use crate::machine::Machine;
+use crate::memory::Memory;
+
+// The most a packet holds, and so the most memory read at once
+const PACKET_SIZE: usize = 0x1000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.go64.mos6502">
+    <reg name="a" bitsize="8" regnum="0" type="uint8"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// What the main loop should do with the machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Stop,
+    Go,
+}
+
+// Why the machine stopped, for the debugger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Step,
+    Breakpoint,
+    Watchpoint(BusEvent),
+    Interrupt,
+    // The CPU couldn't go on, e.g. at an opcode it doesn't run
+    Error,
+}
+
+impl StopReason {
+    fn reply(self) -> String {
+        match self {
+            StopReason::Step | StopReason::Breakpoint => "S05".to_string(),
+            StopReason::Watchpoint(event) => {
+                let kind = match event.access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                };
+                format!("T05{}:{:04x};", kind, event.addr)
+            }
+            StopReason::Interrupt => "S02".to_string(),
+            StopReason::Error => "S04".to_string(),
+        }
+    }
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<Client>,
+}
+
+struct Client {
+    stream: TcpStream,
+    // Bytes received and not yet made into packets
+    input: Vec<u8>,
+    // Packets are acknowledged until the debugger asks for them not to be
+    ack: bool,
+    // Continued, and waiting for the machine to stop
+    running: bool,
+}
+
+// What arrives from the debugger
+enum Incoming {
+    Packet(String),
+    // A packet whose checksum is wrong, to be sent again
+    Corrupt,
+    // Ctrl-C
+    Interrupt,
+}
+
+impl GdbStub {
+    pub fn listen(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("starting the GDB stub")?;
+        listener.set_nonblocking(true)?;
+        tracing::info!(target: "gdb", "Listening on {}", listener.local_addr()?);
+        Ok(Self { listener, client: None })
+    }
+
+    // Where it listens, for tests that let it pick a port
+    #[cfg(test)]
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Take a debugger's connection and serve what it has sent. Returns what
+    // should become of the machine, if anything: stopped when a debugger
+    // connects or interrupts, going when it continues or leaves.
+    pub fn poll(&mut self, machine: &mut Machine) -> Option<Request> {
+        let mut request = None;
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, peer)) => match Client::new(stream) {
+                    Ok(client) => {
+                        tracing::info!(target: "gdb", "Debugger connected from {}", peer);
+                        self.client = Some(client);
+                        request = Some(Request::Stop);
+                    }
+                    Err(e) => tracing::warn!(target: "gdb", "Connection from {}: {}", peer, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => tracing::warn!(target: "gdb", "Accepting a connection: {}", e),
+            }
+        }
+        let client = self.client.as_mut()?;
+
+        let served = client.receive().and_then(|open| {
+            while let Some(incoming) = client.next_incoming() {
+                match incoming {
+                    Incoming::Packet(packet) => {
+                        if client.ack {
+                            client.send_raw(b"+")?;
+                        }
+                        match client.serve(machine, &packet)? {
+                            Served::Reply(reply) => client.send(&reply)?,
+                            Served::Request(serve_request) => request = Some(serve_request),
+                            Served::Detach => return Ok(false),
+                        }
+                    }
+                    Incoming::Corrupt => client.send_raw(b"-")?,
+                    Incoming::Interrupt if client.running => {
+                        client.running = false;
+                        client.send(&StopReason::Interrupt.reply())?;
+                        request = Some(Request::Stop);
+                    }
+                    Incoming::Interrupt => {}
+                }
+            }
+            Ok(open)
+        });
+        match served {
+            Ok(true) => request,
+            Ok(false) => self.disconnect("Debugger detached"),
+            Err(e) => self.disconnect(&format!("Debugger connection lost: {}", e)),
+        }
+    }
+
+    // Tell a debugger waiting on the machine where it stopped. False when no
+    // debugger is waiting, for the machine to stop in the monitor instead.
+    pub fn stopped(&mut self, reason: StopReason) -> bool {
+        let Some(client) = self.client.as_mut().filter(|client| client.running) else {
+            return false;
+        };
+        client.running = false;
+        if let Err(e) = client.send(&reason.reply()) {
+            self.disconnect(&format!("Debugger connection lost: {}", e));
+            return false;
+        }
+        true
+    }
+
+    // The machine goes on without the debugger
+    fn disconnect(&mut self, why: &str) -> Option<Request> {
+        tracing::info!(target: "gdb", "{}", why);
+        self.client = None;
+        Some(Request::Go)
+    }
+}
+
+enum Served {
+    Reply(String),
+    // Continued, with the reply to come once the machine stops
+    Request(Request),
+    Detach,
+}
+
+impl Client {
+    fn new(stream: TcpStream) -> std::io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream, input: Vec::new(), ack: true, running: false })
+    }
+
+    // Read what has arrived; false once the debugger has hung up
+    fn receive(&mut self) -> std::io::Result<bool> {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // The next packet ("$data#checksum") or interrupt, skipping the
+    // debugger's acknowledgements
+    fn next_incoming(&mut self) -> Option<Incoming> {
+        loop {
+            match *self.input.first()? {
+                0x03 => {
+                    self.input.remove(0);
+                    return Some(Incoming::Interrupt);
+                }
+                b'$' => {
+                    let end = self.input.iter().position(|&byte| byte == b'#')?;
+                    if self.input.len() < end + 3 {
+                        return None;
+                    }
+                    let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    if self.ack && checksum != Some(checksum_of(data)) {
+                        return Some(Incoming::Corrupt);
+                    }
+                    return Some(Incoming::Packet(String::from_utf8_lossy(data).into_owned()));
+                }
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> std::io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.send_raw(packet.as_bytes())
+    }
+
+    // Written whole, waiting if the socket is full
+    fn send_raw(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let written = self.stream.write_all(bytes);
+        self.stream.set_nonblocking(true)?;
+        written
+    }
+
+    fn serve(&mut self, machine: &mut Machine, packet: &str) -> std::io::Result<Served> {
+        let reply = |text: &str| Ok(Served::Reply(text.to_string()));
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match command {
+            "?" => reply("S05"),
+            "g" => reply(&hex(&registers(machine))),
+            "G" => match parse_hex(args).and_then(|all| <[u8; 7]>::try_from(all).ok()) {
+                Some(all) => {
+                    set_registers(machine, &all);
+                    reply("OK")
+                }
+                None => reply("E01"),
+            },
+            "p" => match number(args).and_then(|n| register_bytes(n as usize)) {
+                Some(bytes) => reply(&hex(&registers(machine)[bytes])),
+                None => reply("E01"),
+            },
+            "P" => {
+                let set = args.split_once('=').and_then(|(n, value)| {
+                    let bytes = register_bytes(number(n)? as usize)?;
+                    let value = parse_hex(value).filter(|value| value.len() == bytes.len())?;
+                    let mut all = registers(machine);
+                    all[bytes].copy_from_slice(&value);
+                    set_registers(machine, &all);
+                    Some(())
+                });
+                reply(if set.is_some() { "OK" } else { "E01" })
+            }
+            "m" => match address_and_length(args) {
+                Some((addr, length)) => {
+                    let bytes: Vec<u8> = (0..length.min(PACKET_SIZE / 2)).map(|i| machine.memory.peek(addr.wrapping_add(i as u16))).collect();
+                    reply(&hex(&bytes))
+                }
+                None => reply("E01"),
+            },
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, length) = address_and_length(range)?;
+                    let bytes = parse_hex(data).filter(|bytes| bytes.len() == length)?;
+                    for (i, byte) in bytes.into_iter().enumerate() {
+                        machine.memory.write(addr.wrapping_add(i as u16), byte);
+                    }
+                    Some(())
+                });
+                reply(if written.is_some() { "OK" } else { "E01" })
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match number(args) {
+                        Some(addr) => machine.cpu.pc = addr as u16,
+                        None => return reply("E01"),
+                    }
+                }
+                if command == "s" {
+                    return reply(&step(machine).reply());
+                }
+                self.running = true;
+                Ok(Served::Request(Request::Go))
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let (Some(kind), Some(addr), Some(length)) = (fields.next(), fields.next().and_then(number), fields.next().and_then(number)) else {
+                    return reply("E01");
+                };
+                let addr = addr as u16;
+                let watch = |kind| Watch { range: addr..=addr.saturating_add((length as u16).max(1) - 1), kind };
+                match (kind, command == "Z") {
+                    ("0" | "1", true) => machine.cpu.add_breakpoint(addr),
+                    ("0" | "1", false) => machine.cpu.remove_breakpoint(addr),
+                    ("2" | "3" | "4", insert) => {
+                        let watch = watch(match kind {
+                            "2" => WatchKind::Write,
+                            "3" => WatchKind::Read,
+                            _ => WatchKind::ReadWrite,
+                        });
+                        if insert {
+                            machine.memory.watches.get_or_insert_with(Watches::new).add_watchpoint(watch);
+                        } else if let Some(watches) = &mut machine.memory.watches {
+                            watches.remove_watchpoint(&watch);
+                            if watches.is_empty() {
+                                machine.memory.watches = None;
+                            }
+                        }
+                    }
+                    _ => return reply(""),
+                }
+                reply("OK")
+            }
+            "H" | "T" => reply("OK"),
+            "D" => {
+                self.send("OK")?;
+                Ok(Served::Detach)
+            }
+            "k" => Ok(Served::Detach),
+            _ => self.query(packet),
+        }
+    }
+
+    // The q and Q packets, and anything not known, which gets an empty reply
+    fn query(&mut self, packet: &str) -> std::io::Result<Served> {
+        let reply = match packet.split(':').next().unwrap_or_default() {
+            "qSupported" => format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE),
+            "QStartNoAckMode" => {
+                // Acknowledged already; from here on packets aren't
+                self.ack = false;
+                "OK".to_string()
+            }
+            "qXfer" => match packet.strip_prefix("qXfer:features:read:target.xml:").and_then(|range| range.split_once(',')) {
+                Some((offset, length)) => match (number(offset), number(length)) {
+                    (Some(offset), Some(length)) => {
+                        let rest = TARGET_XML.get(offset as usize..).unwrap_or_default();
+                        let chunk = &rest[..rest.len().min(length as usize)];
+                        format!("{}{}", if chunk.len() < rest.len() { 'm' } else { 'l' }, chunk)
+                    }
+                    _ => "E01".to_string(),
+                },
+                None => String::new(),
+            },
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        };
+        Ok(Served::Reply(reply))
+    }
+}
+
+// One instruction, past a breakpoint the CPU is stopped at
+fn step(machine: &mut Machine) -> StopReason {
+    loop {
+        match machine.step() {
+            Ok(StepResult::Executed(_)) => return StopReason::Step,
+            // Going on from a breakpoint runs the instruction there
+            Ok(StepResult::BreakpointHit(_)) => {}
+            Ok(StepResult::WatchpointHit { event, .. }) => return StopReason::Watchpoint(event),
+            Err(e) => {
+                tracing::warn!(target: "gdb", "{}", e);
+                return StopReason::Error;
+            }
+        }
+    }
+}
+
+// The registers in gdb's order, the PC low byte first
+fn registers(machine: &Machine) -> [u8; 7] {
+    let cpu = &machine.cpu;
+    let [pc_lo, pc_hi] = cpu.pc.to_le_bytes();
+    [cpu.a, cpu.x, cpu.y, cpu.status.as_byte(), cpu.sp, pc_lo, pc_hi]
+}
+
+fn set_registers(machine: &mut Machine, [a, x, y, p, sp, pc_lo, pc_hi]: &[u8; 7]) {
+    let cpu = &mut machine.cpu;
+    (cpu.a, cpu.x, cpu.y, cpu.sp) = (*a, *x, *y, *sp);
+    cpu.status = StatusFlags::from_byte(*p);
+    cpu.pc = u16::from_le_bytes([*pc_lo, *pc_hi]);
+}
+
+// Where register `n` is in the registers
+fn register_bytes(n: usize) -> Option<std::ops::Range<usize>> {
+    match n {
+        0..=4 => Some(n..n + 1),
+        5 => Some(5..7),
+        _ => None,
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn number(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+// "addr,length", the address taken within the 64K gdb may see as more
+fn address_and_length(text: &str) -> Option<(u16, usize)> {
+    let (addr, length) = text.split_once(',')?;
+    Some((number(addr)? as u16, number(length)? as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::VideoStandard;
+    use std::time::Duration;
+
+    // A debugger's side of the connection
+    struct Debugger {
+        stream: TcpStream,
+        input: Vec<u8>,
+    }
+
+    impl Debugger {
+        // Send a packet and poll the stub until it replies
+        fn ask(&mut self, stub: &mut GdbStub, machine: &mut Machine, packet: &str) -> (String, Option<Request>) {
+            write!(self.stream, "${}#{:02x}", packet, checksum_of(packet.as_bytes())).unwrap();
+            self.reply(stub, machine)
+        }
+
+        fn reply(&mut self, stub: &mut GdbStub, machine: &mut Machine) -> (String, Option<Request>) {
+            let mut request = None;
+            for _ in 0..100 {
+                request = stub.poll(machine).or(request);
+                let mut buffer = [0; 1024];
+                if let Ok(n) = self.stream.read(&mut buffer) {
+                    self.input.extend_from_slice(&buffer[..n]);
+                }
+                // Past the acknowledgement, a whole packet
+                let start = self.input.iter().position(|&byte| byte == b'$');
+                let end = self.input.iter().position(|&byte| byte == b'#');
+                if let (Some(start), Some(end)) = (start, end)
+                    && self.input.len() >= end + 3
+                {
+                    let packet = String::from_utf8(self.input[start + 1..end].to_vec()).unwrap();
+                    self.input.drain(..end + 3);
+                    return (packet, request);
+                }
+            }
+            panic!("no reply");
+        }
+    }
+
+    fn connect() -> (GdbStub, Machine, Debugger) {
+        let mut stub = GdbStub::listen("127.0.0.1:0").unwrap();
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let stream = TcpStream::connect(stub.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        // Connecting stops the machine
+        let request = (0..100).find_map(|_| {
+            std::thread::sleep(Duration::from_millis(1));
+            stub.poll(&mut machine)
+        });
+        assert_eq!(request, Some(Request::Stop));
+        (stub, machine, Debugger { stream, input: Vec::new() })
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let (mut stub, mut machine, mut debugger) = connect();
+        let mut ask = |machine: &mut Machine, packet: &str| debugger.ask(&mut stub, machine, packet).0;
+
+        assert!(ask(&mut machine, "qSupported:multiprocess+;xmlRegisters=i386").contains("qXfer:features:read+"));
+        assert_eq!(ask(&mut machine, "?"), "S05");
+        let target = ask(&mut machine, "qXfer:features:read:target.xml:0,1000");
+        assert!(target.starts_with("l<?xml") && target.contains(r#"<reg name="pc" bitsize="16""#));
+        assert!(ask(&mut machine, "qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+
+        // A, X, Y, P, SP and the PC low byte first
+        (machine.cpu.a, machine.cpu.x, machine.cpu.y, machine.cpu.sp, machine.cpu.pc) = (0x01, 0x02, 0x03, 0xF0, 0xC000);
+        machine.cpu.status = StatusFlags::from_byte(0x24);
+        assert_eq!(ask(&mut machine, "g"), "01020324f000c0");
+        assert_eq!(ask(&mut machine, "p5"), "00c0");
+        assert_eq!(ask(&mut machine, "P5=10c1"), "OK");
+        assert_eq!(ask(&mut machine, "P0=ff"), "OK");
+        assert_eq!((machine.cpu.pc, machine.cpu.a), (0xC110, 0xFF));
+        assert_eq!(ask(&mut machine, "G0a0b0c20fd0008"), "OK");
+        assert_eq!((machine.cpu.a, machine.cpu.sp, machine.cpu.pc), (0x0A, 0xFD, 0x0800));
+        assert_eq!(ask(&mut machine, "P9=00"), "E01");
+
+        assert_eq!(ask(&mut machine, "Mc000,3:a9ffea"), "OK");
+        assert_eq!(ask(&mut machine, "mc000,3"), "a9ffea");
+        // Addresses are within the 64K
+        assert_eq!(ask(&mut machine, "m1c000,1"), "a9");
+        assert_eq!(ask(&mut machine, "Mc000,3:a9"), "E01");
+        assert_eq!(ask(&mut machine, "vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn test_running() {
+        let (mut stub, mut machine, mut debugger) = connect();
+        // C000: INX, INX, STA $D020, JMP $C000
+        for (i, &byte) in [0xE8, 0xE8, 0x8D, 0x20, 0xD0, 0x4C, 0x00, 0xC0].iter().enumerate() {
+            machine.memory.write(0xC000 + i as u16, byte);
+        }
+        assert_eq!(debugger.ask(&mut stub, &mut machine, "sc000").0, "S05");
+        assert_eq!(machine.cpu.pc, 0xC001);
+
+        // Continue to a breakpoint, as the main loop runs the machine
+        assert_eq!(debugger.ask(&mut stub, &mut machine, "Z0,c005,1").0, "OK");
+        debugger.stream.write_all(b"$c#63").unwrap();
+        assert_eq!((0..100).find_map(|_| stub.poll(&mut machine)), Some(Request::Go));
+        while let Ok(StepResult::Executed(_)) = machine.step() {}
+        assert_eq!(machine.cpu.pc, 0xC005);
+        assert!(stub.stopped(StopReason::Breakpoint));
+        assert_eq!(debugger.reply(&mut stub, &mut machine).0, "S05");
+        // Nothing is waiting now, so a stop is the monitor's
+        assert!(!stub.stopped(StopReason::Breakpoint));
+
+        // A watchpoint on the border
+        assert_eq!(debugger.ask(&mut stub, &mut machine, "z0,c005,1").0, "OK");
+        assert_eq!(debugger.ask(&mut stub, &mut machine, "Z2,d020,1").0, "OK");
+        for _ in 0..3 {
+            assert_eq!(debugger.ask(&mut stub, &mut machine, "s").0, "S05");
+        }
+        assert_eq!(debugger.ask(&mut stub, &mut machine, "s").0, "T05watch:d020;");
+        assert_eq!(debugger.ask(&mut stub, &mut machine, "z2,d020,1").0, "OK");
+        assert!(machine.memory.watches.is_none());
+
+        // Ctrl-C stops it while it runs
+        debugger.stream.write_all(b"$c#63").unwrap();
+        assert_eq!((0..100).find_map(|_| stub.poll(&mut machine)), Some(Request::Go));
+        debugger.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(debugger.reply(&mut stub, &mut machine), ("S02".to_string(), Some(Request::Stop)));
+
+        // Leaving sets the machine going
+        assert_eq!(debugger.ask(&mut stub, &mut machine, "D"), ("OK".to_string(), Some(Request::Go)));
+        assert!(!stub.stopped(StopReason::Breakpoint));
+    }
+}
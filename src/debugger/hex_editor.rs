@@ -0,0 +1,231 @@
+// Hex editor: memory a screenful at a time beside the running machine
+// (Alt+E), with a cursor to write bytes where it is and bytes that have
+// just changed picked out. Reads and writes are the monitor's: as the CPU
+// sees memory, without the side effects of reading I/O registers.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use super::symbols::Labels;
+use crate::memory::Memory;
+
+// Bytes on a row, as for the monitor's m
+pub const ROW_WIDTH: u16 = 8;
+// How long a byte that changed stays picked out
+const CHANGE_SHOWN: Duration = Duration::from_secs(1);
+
+pub struct HexEditor {
+    // The first address shown, and the byte the cursor is on
+    pub top: u16,
+    pub cursor: u16,
+    // Rows shown, as many as the panel has room for
+    pub rows: u16,
+    // The high digit typed at the cursor, waiting for the low one
+    pub pending: Option<u8>,
+    // An address being typed after g, to go to, and what was wrong with
+    // the last one
+    pub goto: Option<String>,
+    pub error: Option<String>,
+    // The bytes as last shown, and when the ones that changed did
+    seen: Vec<Option<u8>>,
+    changed: HashMap<u16, Instant>,
+}
+
+impl HexEditor {
+    pub fn new() -> Self {
+        Self {
+            top: 0,
+            cursor: 0,
+            rows: 16,
+            pending: None,
+            goto: None,
+            error: None,
+            seen: vec![None; 0x10000],
+            changed: HashMap::new(),
+        }
+    }
+
+    // Fit the rows to the panel, keeping the cursor on them
+    pub fn set_rows(&mut self, rows: u16) {
+        self.rows = rows.max(1);
+        self.follow_cursor();
+    }
+
+    pub fn move_cursor(&mut self, bytes: i32) {
+        self.error = None;
+        self.cursor = self.cursor.wrapping_add(bytes as u16);
+        self.pending = None;
+        self.follow_cursor();
+    }
+
+    // Up or down a screenful
+    pub fn page(&mut self, down: bool) {
+        let bytes = (self.rows * ROW_WIDTH) as i32;
+        self.top = self.top.wrapping_add(if down { bytes } else { -bytes } as u16);
+        self.move_cursor(if down { bytes } else { -bytes });
+    }
+
+    // A key typed: a hex digit for the byte at the cursor, g to start
+    // typing an address, or part of that address
+    pub fn type_char(&mut self, c: char, memory: &mut dyn Memory) {
+        self.error = None;
+        if let Some(goto) = &mut self.goto {
+            goto.push(c);
+            return;
+        }
+        if c.eq_ignore_ascii_case(&'g') {
+            self.goto = Some(String::new());
+            self.pending = None;
+            return;
+        }
+        let Some(digit) = c.to_digit(16).map(|digit| digit as u8) else {
+            return;
+        };
+        match self.pending.take() {
+            None => self.pending = Some(digit),
+            Some(high) => {
+                memory.write(self.cursor, high << 4 | digit);
+                self.move_cursor(1);
+            }
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        match &mut self.goto {
+            Some(goto) => {
+                goto.pop();
+            }
+            None => self.pending = None,
+        }
+    }
+
+    // Go to the address typed, in hex or as a .label
+    pub fn enter(&mut self, labels: &Labels) {
+        let Some(goto) = self.goto.take() else {
+            return;
+        };
+        match address(goto.trim(), labels) {
+            Ok(addr) => {
+                self.cursor = addr;
+                self.top = addr - addr % ROW_WIDTH;
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    // Escape leaves the address being typed, the half typed byte or the
+    // error; false when there was none and the editor should close
+    pub fn cancel(&mut self) -> bool {
+        self.goto.take().is_some() || self.pending.take().is_some() || self.error.take().is_some()
+    }
+
+    // Called before showing the rows: note the bytes that have changed since
+    pub fn refresh(&mut self, memory: &dyn Memory, now: Instant) {
+        self.changed.retain(|_, at| now.duration_since(*at) < CHANGE_SHOWN);
+        for i in 0..self.rows * ROW_WIDTH {
+            let addr = self.top.wrapping_add(i);
+            let value = memory.peek(addr);
+            let seen = self.seen[addr as usize].replace(value);
+            if seen.is_some_and(|seen| seen != value) {
+                self.changed.insert(addr, now);
+            }
+        }
+    }
+
+    pub fn recently_changed(&self, addr: u16) -> bool {
+        self.changed.contains_key(&addr)
+    }
+
+    // Scroll so the cursor's row is shown
+    fn follow_cursor(&mut self) {
+        let shown = self.rows * ROW_WIDTH;
+        if self.cursor.wrapping_sub(self.top) >= shown {
+            let row = self.cursor - self.cursor % ROW_WIDTH;
+            // Moving back brings the row in at the top, forward at the bottom
+            self.top = if self.top.wrapping_sub(row) <= shown { row } else { row.wrapping_sub(shown - ROW_WIDTH) };
+        }
+    }
+}
+
+fn address(text: &str, labels: &Labels) -> Result<u16> {
+    match text.strip_prefix('.') {
+        Some(label) => match labels.address(label) {
+            Some(addr) => Ok(addr),
+            None => bail!("unknown label .{}", label),
+        },
+        None => u16::from_str_radix(text.trim_start_matches('$'), 16).map_err(|_| anyhow::anyhow!("invalid address {:?}", text)),
+    }
+}
+
+impl Default for HexEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BasicMemory;
+
+    #[test]
+    fn test_editing() {
+        let mut memory = BasicMemory::new();
+        let mut editor = HexEditor::new();
+        editor.rows = 4;
+
+        editor.goto = Some(String::new());
+        for c in "c000".chars() {
+            editor.type_char(c, &mut memory);
+        }
+        editor.enter(&Labels::new());
+        assert_eq!((editor.top, editor.cursor), (0xC000, 0xC000));
+
+        // Two digits a byte, going on to the next
+        for c in "a9Ff".chars() {
+            editor.type_char(c, &mut memory);
+        }
+        assert_eq!((memory.read(0xC000), memory.read(0xC001), editor.cursor), (0xA9, 0xFF, 0xC002));
+        editor.type_char('1', &mut memory);
+        assert!(editor.cancel());
+        assert!(!editor.cancel());
+        assert_eq!(memory.read(0xC002), 0x00);
+
+        // Down past the last row scrolls a row, and back up past the first
+        editor.move_cursor(4 * 8);
+        assert_eq!((editor.top, editor.cursor), (0xC008, 0xC022));
+        editor.move_cursor(-4 * 8);
+        assert_eq!(editor.top, 0xC002 - 2);
+        editor.page(false);
+        assert_eq!((editor.top, editor.cursor), (0xBFE0, 0xBFE2));
+
+        let mut labels = Labels::new();
+        labels.add(0x0810, "main".to_string());
+        editor.goto = Some(".main".to_string());
+        editor.enter(&labels);
+        assert_eq!((editor.top, editor.cursor), (0x0810, 0x0810));
+        editor.goto = Some(".start".to_string());
+        editor.enter(&labels);
+        assert_eq!(editor.error.as_deref(), Some("unknown label .start"));
+        assert_eq!(editor.cursor, 0x0810);
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut memory = BasicMemory::new();
+        let mut editor = HexEditor::new();
+        editor.top = 0x0400;
+        let start = Instant::now();
+        editor.refresh(&memory, start);
+        assert!(!editor.recently_changed(0x0401));
+
+        memory.write(0x0401, 0x20);
+        editor.refresh(&memory, start);
+        assert!(editor.recently_changed(0x0401));
+        assert!(!editor.recently_changed(0x0400));
+        editor.refresh(&memory, start + CHANGE_SHOWN);
+        assert!(!editor.recently_changed(0x0401));
+    }
+}
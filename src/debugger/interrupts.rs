@@ -0,0 +1,170 @@
+// Interrupt history: each time a chip pulls IRQ or NMI, with what it was
+// for, and each time the CPU takes one and where it went, stamped with the
+// frame, raster line and cycle. For the monitor's int, to see why a program
+// gets no interrupts (or too many): a source that never asserts, or one
+// that asserts and is never taken because I is set.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::interrupt::InterruptSource;
+use crate::memory::C64Memory;
+
+// Events kept, the oldest going first: a few seconds of the KERNAL's IRQ
+const CAPACITY: usize = 1024;
+
+// $D019/$D01A bits 0-3: raster, sprite-background and sprite-sprite
+// collision, light pen
+pub const VIC_SOURCES: [&str; 4] = ["RST", "MBC", "MMC", "LP"];
+// A CIA's interrupt control register bits 0-4: timers A and B, the TOD
+// alarm, the serial port and the FLAG pin
+const CIA_SOURCES: [&str; 5] = ["TA", "TB", "TOD", "SDR", "FLG"];
+
+const SOURCES: [(InterruptSource, &str); 6] = [
+    (InterruptSource::Vic, "VIC"),
+    (InterruptSource::Cia1, "CIA1"),
+    (InterruptSource::Cia2, "CIA2"),
+    (InterruptSource::Restore, "RESTORE"),
+    (InterruptSource::Cartridge, "cartridge"),
+    (InterruptSource::Swiftlink, "Swiftlink"),
+];
+
+const NMI_VECTOR: u16 = 0xFFFA;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub frame: u64,
+    pub line: u16,
+    pub cycle: u16,
+    pub kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    // A source started pulling the line, for the reasons its chip gives
+    Asserted { nmi: bool, source: &'static str, reasons: Vec<&'static str> },
+    // The CPU went from `from` to the handler at `to` through the vector
+    Taken { nmi: bool, from: u16, to: u16 },
+}
+
+#[derive(Debug, Default)]
+pub struct InterruptHistory {
+    events: VecDeque<Event>,
+    // Frames counted from the raster line wrapping round
+    frame: u64,
+    line: u16,
+    // The sources pulling each line when last looked at
+    irq: u8,
+    nmi: u8,
+}
+
+impl InterruptHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The CPU ran an interrupt sequence through `vector`, before the chips
+    // caught up with it
+    pub fn taken(&mut self, memory: &C64Memory, vector: u16, from: u16, to: u16) {
+        self.push(memory, Kind::Taken { nmi: vector == NMI_VECTOR, from, to });
+    }
+
+    // After the chips have ticked: note the sources that have started
+    // pulling a line since
+    pub fn observe(&mut self, memory: &C64Memory) {
+        let line = memory.vic.raster_line();
+        if line < self.line {
+            self.frame += 1;
+        }
+        self.line = line;
+
+        let (irq, nmi) = (memory.irq.sources(), memory.nmi.sources());
+        if irq & !self.irq != 0 || nmi & !self.nmi != 0 {
+            for (nmi_line, sources, seen) in [(false, irq, self.irq), (true, nmi, self.nmi)] {
+                for (source, name) in SOURCES {
+                    if sources & !seen & source as u8 != 0 {
+                        let kind = Kind::Asserted { nmi: nmi_line, source: name, reasons: reasons(memory, source) };
+                        self.push(memory, kind);
+                    }
+                }
+            }
+        }
+        (self.irq, self.nmi) = (irq, nmi);
+    }
+
+    // The last `count` events, oldest first
+    pub fn last(&self, count: usize) -> impl Iterator<Item = &Event> {
+        self.events.iter().skip(self.events.len().saturating_sub(count))
+    }
+
+    fn push(&mut self, memory: &C64Memory, kind: Kind) {
+        if self.events.len() == CAPACITY {
+            self.events.pop_front();
+        }
+        let (line, cycle) = (memory.vic.raster_line(), memory.vic.raster_cycle());
+        self.events.push_back(Event { frame: self.frame, line, cycle, kind });
+    }
+}
+
+// What the chip's interrupt flags say it was for
+fn reasons(memory: &C64Memory, source: InterruptSource) -> Vec<&'static str> {
+    let (flags, names): (u8, &[&str]) = match source {
+        InterruptSource::Vic => (memory.vic.read_register(0xD019), &VIC_SOURCES),
+        InterruptSource::Cia1 => (memory.cia1.peek(0xDC0D), &CIA_SOURCES),
+        InterruptSource::Cia2 => (memory.cia2.peek(0xDD0D), &CIA_SOURCES),
+        _ => return Vec::new(),
+    };
+    names.iter().enumerate().filter(|(bit, _)| flags & (1 << bit) != 0).map(|(_, name)| *name).collect()
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>6} {:3}/{:<2} ", self.frame, self.line, self.cycle)?;
+        let name = |nmi: bool| if nmi { "NMI" } else { "IRQ" };
+        match &self.kind {
+            Kind::Asserted { nmi, source, reasons } if reasons.is_empty() => write!(f, "{} from {}", name(*nmi), source),
+            Kind::Asserted { nmi, source, reasons } => write!(f, "{} from {} {}", name(*nmi), source, reasons.join(" ")),
+            Kind::Taken { nmi, from, to } => write!(f, "{} taken at ${:04X} -> ${:04X}", name(*nmi), from, to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{Machine, VideoStandard};
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_history() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        // CIA1 timer A interrupts after 16 cycles
+        machine.memory.write(0xDC04, 0x10);
+        machine.memory.write(0xDC05, 0x00);
+        machine.memory.write(0xDC0D, 0x81);
+        machine.memory.write(0xDC0E, 0x19);
+        // Then all RAM, with NOPs to run and the IRQ vector pointing at more
+        machine.memory.write(0x0000, 0x2F);
+        machine.memory.write(0x0001, 0x34);
+        for addr in 0x1000..0x1100 {
+            machine.memory.write(addr, 0xEA);
+        }
+        machine.memory.write(0xFFFE, 0x80);
+        machine.memory.write(0xFFFF, 0x10);
+        machine.cpu.pc = 0x1000;
+        machine.cpu.status.interrupt = false;
+        while machine.cpu.pc < 0x1080 {
+            machine.step().unwrap();
+        }
+
+        let events: Vec<&Event> = machine.interrupts.last(10).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, Kind::Asserted { nmi: false, source: "CIA1", reasons: vec!["TA"] });
+        let Kind::Taken { nmi: false, from, to: 0x1080 } = events[1].kind else {
+            panic!("{:?}", events[1]);
+        };
+        assert!((0x1008..0x1010).contains(&from));
+        assert_eq!(events[0].frame, 0);
+        assert_eq!(events[1].to_string(), format!("     0 {:3}/{:<2} IRQ taken at ${:04X} -> $1080", events[1].line, events[1].cycle, from));
+    }
+}
@@ -1,64 +1,149 @@
-// Simple disassembler for 6502
+// Disassembler for the 6502, undocumented opcodes included
 
-// use crate::memory::Memory;
+pub mod calls;
+pub mod coverage;
+pub mod expression;
+pub mod gdb;
+pub mod hex_editor;
+pub mod interrupts;
+pub mod monitor;
+pub mod profiler;
+pub mod stack;
+pub mod symbols;
+pub mod watch;
 
-    // pub fn disassemble(memory: &dyn Memory, addr: u16) -> (String, u16) {
-    //     let opcode = memory.read(addr);
-    //     
-    //     match opcode {
-    //         // LDA
-    //         0xA9 => (format!("LDA #${:02X}", memory.read(addr + 1)), 2),
-    //         0xA5 => (format!("LDA ${:02X}", memory.read(addr + 1)), 2),
-    //         0xB5 => (format!("LDA ${:02X},X", memory.read(addr + 1)), 2),
-    //         0xAD => (format!("LDA ${:02X}{:02X}", memory.read(addr + 2), memory.read(addr + 1)), 3),
-    //         0xBD => (format!("LDA ${:02X}{:02X},X", memory.read(addr + 2), memory.read(addr + 1)), 3),
-    //         0xB9 => (format!("LDA ${:02X}{:02X},Y", memory.read(addr + 2), memory.read(addr + 1)), 3),
-    //         
-    //         // STA
-    //         0x85 => (format!("STA ${:02X}", memory.read(addr + 1)), 2),
-    //         0x8D => (format!("STA ${:02X}{:02X}", memory.read(addr + 2), memory.read(addr + 1)), 3),
-    //         
-    //         // JMP
-    //         0x4C => (format!("JMP ${:02X}{:02X}", memory.read(addr + 2), memory.read(addr + 1)), 3),
-    //         0x6C => (format!("JMP (${:02X}{:02X})", memory.read(addr + 2), memory.read(addr + 1)), 3),
-    //         
-    //         // JSR/RTS
-    //         0x20 => (format!("JSR ${:02X}{:02X}", memory.read(addr + 2), memory.read(addr + 1)), 3),
-    //         0x60 => ("RTS".to_string(), 1),
-    //         
-    //         // Transfers
-    //         0xAA => ("TAX".to_string(), 1),
-    //         0xA8 => ("TAY".to_string(), 1),
-    //         0x8A => ("TXA".to_string(), 1),
-    //         0x98 => ("TYA".to_string(), 1),
-    //         
-    //         // Stack
-    //         0x48 => ("PHA".to_string(), 1),
-    //         0x68 => ("PLA".to_string(), 1),
-    //         
-    //         // Branches
-    //         0x10 => (format!("BPL ${:02X}", memory.read(addr + 1)), 2),
-    //         0x30 => (format!("BMI ${:02X}", memory.read(addr + 1)), 2),
-    //         0x50 => (format!("BVC ${:02X}", memory.read(addr + 1)), 2),
-    //         0x70 => (format!("BVS ${:02X}", memory.read(addr + 1)), 2),
-    //         0x90 => (format!("BCC ${:02X}", memory.read(addr + 1)), 2),
-    //         0xB0 => (format!("BCS ${:02X}", memory.read(addr + 1)), 2),
-    //         0xD0 => (format!("BNE ${:02X}", memory.read(addr + 1)), 2),
-    //         0xF0 => (format!("BEQ ${:02X}", memory.read(addr + 1)), 2),
-    //         
-    //         // Other common ones
-    //         0xEA => ("NOP".to_string(), 1),
-    //         0x00 => ("BRK".to_string(), 1),
-    //         
-    //         _ => (format!("??? ${:02X}", opcode), 1),
-    //     }
-    // }
-    
-    // pub fn disassemble_range(memory: &dyn Memory, start: u16, count: usize) {
-    //     let mut addr = start;
-    //     for _ in 0..count {
-    //         let (instr, size) = disassemble(memory, addr);
-    //         println!("${:04X}: {}", addr, instr);
-    //         addr = addr.wrapping_add(size);
-    //     }
-    // }
+use crate::cpu::addressing::AddressingMode;
+use crate::cpu::Cpu;
+use crate::cpu::opcodes::INSTRUCTIONS;
+use crate::memory::Memory;
+use symbols::Labels;
+
+// Disassemble the instruction at `addr`. Returns the text and its length in bytes.
+pub fn disassemble(memory: &dyn Memory, addr: u16) -> (String, u16) {
+    let opcode = memory.peek(addr);
+    let instruction = &INSTRUCTIONS[opcode as usize];
+    let lo = memory.peek(addr.wrapping_add(1));
+    let hi = memory.peek(addr.wrapping_add(2));
+
+    let operand = match instruction.mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => " A".to_string(),
+        AddressingMode::Immediate => format!(" #${:02X}", lo),
+        AddressingMode::ZeroPage => format!(" ${:02X}", lo),
+        AddressingMode::ZeroPageX => format!(" ${:02X},X", lo),
+        AddressingMode::ZeroPageY => format!(" ${:02X},Y", lo),
+        AddressingMode::Absolute => format!(" ${:02X}{:02X}", hi, lo),
+        AddressingMode::AbsoluteX => format!(" ${:02X}{:02X},X", hi, lo),
+        AddressingMode::AbsoluteY => format!(" ${:02X}{:02X},Y", hi, lo),
+        AddressingMode::Indirect => format!(" (${:02X}{:02X})", hi, lo),
+        AddressingMode::IndirectX => format!(" (${:02X},X)", lo),
+        AddressingMode::IndirectY => format!(" (${:02X}),Y", lo),
+        AddressingMode::Relative => {
+            // Show the branch target rather than the raw offset
+            let target = addr.wrapping_add(2).wrapping_add(lo as i8 as u16);
+            format!(" ${:04X}", target)
+        }
+    };
+
+    (format!("{}{}", instruction.mnemonic, operand), instruction.bytes as u16)
+}
+
+// A note on what the instruction at `addr` works on: the name of the
+// address it gives, e.g. "CHROUT" for JSR $FFD2 or a label of the user's
+// (see symbols). With the CPU stopped at
+// it, indexed and indirect operands are followed with its registers to the
+// address they reach, e.g. "$D021 background color 0" for STA $D000,X.
+pub fn annotate(memory: &dyn Memory, labels: &Labels, addr: u16, cpu: Option<&Cpu>) -> Option<String> {
+    let instruction = &INSTRUCTIONS[memory.peek(addr) as usize];
+    let lo = memory.peek(addr.wrapping_add(1));
+    let operand = u16::from_le_bytes([lo, memory.peek(addr.wrapping_add(2))]);
+    // Pointers in the zero page wrap around within it
+    let pointer = |at: u8| u16::from_le_bytes([memory.peek(at as u16), memory.peek(at.wrapping_add(1) as u16)]);
+
+    let effective = match (instruction.mode, cpu) {
+        (AddressingMode::ZeroPage, _) => return labels.describe(lo as u16),
+        (AddressingMode::Absolute, _) => return labels.describe(operand),
+        (AddressingMode::Indirect, None) => return labels.describe(operand),
+        (_, None) => return None,
+        (AddressingMode::ZeroPageX, Some(cpu)) => lo.wrapping_add(cpu.x) as u16,
+        (AddressingMode::ZeroPageY, Some(cpu)) => lo.wrapping_add(cpu.y) as u16,
+        (AddressingMode::AbsoluteX, Some(cpu)) => operand.wrapping_add(cpu.x as u16),
+        (AddressingMode::AbsoluteY, Some(cpu)) => operand.wrapping_add(cpu.y as u16),
+        (AddressingMode::IndirectX, Some(cpu)) => pointer(lo.wrapping_add(cpu.x)),
+        (AddressingMode::IndirectY, Some(cpu)) => pointer(lo).wrapping_add(cpu.y as u16),
+        // JMP ($xxFF) takes the high byte from the start of the same page
+        (AddressingMode::Indirect, Some(_)) => {
+            u16::from_le_bytes([memory.peek(operand), memory.peek(operand & 0xFF00 | operand.wrapping_add(1) & 0x00FF)])
+        }
+        _ => return None,
+    };
+    Some(match labels.describe(effective) {
+        Some(name) => format!("${:04X} {}", effective, name),
+        None => format!("${:04X}", effective),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BasicMemory;
+
+    #[test]
+    fn test_disassemble() {
+        let mut memory = BasicMemory::new();
+        let code = [
+            0xBE, 0x00, 0xD0, // LDX $D000,Y
+            0xD1, 0xFB, // CMP ($FB),Y
+            0xA7, 0x02, // LAX $02
+            0x1A, // NOP
+            0x3C, 0x34, 0x12, // NOP $1234,X
+            0xF0, 0xF3, // BEQ $C000
+        ];
+        for (i, &byte) in code.iter().enumerate() {
+            memory.write(0xC000 + i as u16, byte);
+        }
+        let mut addr = 0xC000;
+        let mut lines = Vec::new();
+        while addr < 0xC000 + code.len() as u16 {
+            let (text, size) = disassemble(&memory, addr);
+            lines.push(text);
+            addr += size;
+        }
+        assert_eq!(lines, ["LDX $D000,Y", "CMP ($FB),Y", "LAX $02", "NOP", "NOP $1234,X", "BEQ $C000"]);
+    }
+
+    #[test]
+    fn test_annotate() {
+        let mut memory = BasicMemory::new();
+        for (i, &byte) in [0x20, 0xD2, 0xFF, 0x9D, 0x00, 0xD0, 0x91, 0xFB, 0x6C, 0x14, 0x03].iter().enumerate() {
+            memory.write(0xC000 + i as u16, byte);
+        }
+        // The IRQ vector, pointing at $EA31, and a pointer to the screen
+        memory.write(0x0314, 0x31);
+        memory.write(0x0315, 0xEA);
+        memory.write(0xFB, 0x00);
+        memory.write(0xFC, 0x04);
+        let mut cpu = Cpu::new();
+        (cpu.x, cpu.y) = (0x21, 0x28);
+        let mut labels = Labels::new();
+
+        assert_eq!(annotate(&memory, &labels, 0xC000, None).as_deref(), Some("CHROUT"));
+        assert_eq!(annotate(&memory, &labels, 0xC003, None), None);
+        assert_eq!(annotate(&memory, &labels, 0xC003, Some(&cpu)).as_deref(), Some("$D021 background color 0"));
+        assert_eq!(annotate(&memory, &labels, 0xC006, Some(&cpu)).as_deref(), Some("$0428"));
+        assert_eq!(annotate(&memory, &labels, 0xC008, None).as_deref(), Some("CINV"));
+        assert_eq!(annotate(&memory, &labels, 0xC008, Some(&cpu)).as_deref(), Some("$EA31 IRQ handler"));
+        // The user's labels come first
+        labels.add(0x0428, "row_1".to_string());
+        assert_eq!(annotate(&memory, &labels, 0xC006, Some(&cpu)).as_deref(), Some("$0428 .row_1"));
+    }
+
+    #[test]
+    fn test_every_opcode_decodes() {
+        for (opcode, instruction) in INSTRUCTIONS.iter().enumerate() {
+            assert_ne!(instruction.mnemonic, "???", "${:02X}", opcode);
+            assert_eq!(instruction.bytes, instruction.mode.bytes(), "${:02X}", opcode);
+        }
+        assert_eq!(INSTRUCTIONS.iter().filter(|instruction| instruction.handler.is_none()).count(), 256 - 151 - 12);
+    }
+}
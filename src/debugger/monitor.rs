@@ -0,0 +1,953 @@
+// Machine language monitor: VICE style commands typed in the TUI while the
+// machine is paused (Alt+M)
+//
+//   m [start [end]]     memory in hex and as text
+//   d [start [end]]     disassemble
+//   > addr byte ...     write bytes
+//   a addr [instr]      assemble, going on at the next address until an
+//                       empty line
+//   r [reg=value ...]   the registers, or set them (A, X, Y, SP, PC, P)
+//   z [count]           step instructions
+//   n                   step over: a JSR runs until its routine returns
+//   ret                 step out: run until the current routine returns
+//   until addr          run until the PC reaches addr
+//   g [addr]            go, from addr if given
+//   x                   leave the monitor and go on
+//   break [addr]        stop before the instruction at addr, or list where
+//   watch [kind] start [end]
+//                       stop after an access to the range (kind load, store
+//                       or change, any access by default), or list where
+//   watch bank          stop after a write that changes the banking, or to
+//                       a cartridge's registers
+//   del [addr]          no longer stop at addr, or anywhere
+//   ll file             load labels from a VICE label file
+//   al addr .label      label an address
+//   shl                 list the labels
+//   int [count]         the last interrupts asserted and taken
+//   bt                  the calls and interrupts the CPU is in
+//   disp [expr]         show an expression's value in the debug view as the
+//                       machine runs (see expression.rs), or list them
+//   undisp [n]          stop showing expression n, or any
+//
+// Numbers are hex, with or without a $, or a .label for its address.
+// Memory reads as the CPU sees it, without the side effects of reading I/O
+// registers; writes go where the CPU's would, so under ROM to RAM.
+
+use anyhow::{bail, Result};
+
+use super::{annotate, disassemble};
+use super::expression::Expression;
+use super::symbols::{is_label, Labels};
+use super::watch::{BusEvent, Watch, WatchKind, Watches};
+use crate::cpu::addressing::AddressingMode;
+use crate::cpu::opcodes::{Instruction, INSTRUCTIONS};
+use crate::cpu::{StatusFlags, StepResult};
+use crate::machine::Machine;
+use crate::memory::Memory;
+
+// Lines of output kept to scroll back through
+const SCROLLBACK: usize = 1000;
+// Bytes on a line of m, and the lines m and d show by default
+const DUMP_WIDTH: u16 = 8;
+const DEFAULT_LINES: u16 = 16;
+// Instructions shown after the PC where the CPU stopped
+const LINES_AFTER_PC: usize = 4;
+// Interrupt events int shows by default
+const DEFAULT_INTERRUPTS: u16 = 16;
+
+#[derive(Default)]
+pub struct Monitor {
+    // What has been printed, commands included
+    pub output: Vec<String>,
+    pub input: String,
+    history: Vec<String>,
+    // Position in the history while going back through it with the arrows
+    recalled: Option<usize>,
+    // Where m and d go on from
+    next_dump: Option<u16>,
+    next_disassembly: Option<u16>,
+    // The next address to assemble at, while assembling
+    assembling: Option<u16>,
+    // Where the run started by n, ret or until stops
+    until: Option<Until>,
+}
+
+// Where a run the monitor started stops, going by the stack pointer too so
+// that a routine calling itself doesn't stop it early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Until {
+    // The PC reaching an address, the stack no deeper than it was
+    Address { pc: u16, sp: u8 },
+    // The stack pointer rising above where it was, as the RTS (or RTI)
+    // leaving the routine pops its return address
+    Return { sp: u8 },
+}
+
+const JSR: u8 = 0x20;
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called on entering: show where the CPU stopped
+    pub fn open(&mut self, machine: &Machine) {
+        self.next_disassembly = None;
+        self.assembling = None;
+        self.until = None;
+        for line in where_stopped(machine) {
+            self.print(line);
+        }
+    }
+
+    // Entered by a breakpoint rather than by hand
+    pub fn open_at_breakpoint(&mut self, machine: &Machine) {
+        self.print(format!("BREAK: ${:04X}", machine.cpu.pc));
+        self.open(machine);
+    }
+
+    // Entered by a watchpoint, once the instruction at `pc` made `event`
+    pub fn open_at_watchpoint(&mut self, machine: &Machine, pc: u16, event: BusEvent) {
+        self.print(format!("WATCH: {} at ${:04X}", event.describe(), pc));
+        for line in banking_change(machine, &event) {
+            self.print(line);
+        }
+        self.open(machine);
+    }
+
+    pub fn prompt(&self, pc: u16) -> String {
+        match self.assembling {
+            Some(addr) => format!(".C:{:04X}  ", addr),
+            None => format!("(C:${:04X}) ", pc),
+        }
+    }
+
+    pub fn type_char(&mut self, c: char) {
+        self.input.push(c);
+    }
+
+    pub fn backspace(&mut self) {
+        self.input.pop();
+    }
+
+    // Bring back an earlier (or, going forward, later) command
+    pub fn recall(&mut self, back: bool) {
+        let position = match (self.recalled, back) {
+            (None, true) => self.history.len().checked_sub(1),
+            (None, false) => None,
+            (Some(n), true) => Some(n.saturating_sub(1)),
+            (Some(n), false) => (n + 1 < self.history.len()).then_some(n + 1),
+        };
+        self.recalled = position;
+        self.input = position.map(|n| self.history[n].clone()).unwrap_or_default();
+    }
+
+    // Run the line typed in. True when the machine should go on and the
+    // monitor close.
+    pub fn enter(&mut self, machine: &mut Machine) -> bool {
+        let line = std::mem::take(&mut self.input);
+        self.print(format!("{}{}", self.prompt(machine.cpu.pc), line));
+        self.recalled = None;
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        let result = match self.assembling {
+            Some(addr) if line.trim().is_empty() => {
+                self.next_disassembly = Some(addr);
+                self.assembling = None;
+                Ok(false)
+            }
+            Some(addr) => resolve(&machine.labels, &line).and_then(|line| self.assemble_at(machine, addr, &line)).map(|_| false),
+            None => self.run(machine, &line),
+        };
+        result.unwrap_or_else(|e| {
+            self.print(format!("Error: {:#}", e));
+            false
+        })
+    }
+
+    // Called after each step while the machine runs: true when a run
+    // started with n, ret or until has got where it was going, and the
+    // monitor should open again
+    #[inline]
+    pub fn run_ended(&self, machine: &Machine) -> bool {
+        let cpu = &machine.cpu;
+        match self.until {
+            None => false,
+            Some(Until::Address { pc, sp }) => cpu.pc == pc && cpu.sp >= sp,
+            Some(Until::Return { sp }) => cpu.sp > sp,
+        }
+    }
+
+    fn print(&mut self, line: String) {
+        self.output.push(line);
+        if self.output.len() > SCROLLBACK {
+            self.output.drain(..self.output.len() - SCROLLBACK);
+        }
+    }
+
+    fn run(&mut self, machine: &mut Machine, line: &str) -> Result<bool> {
+        let line = line.trim();
+        // Labels stand for their addresses, but in the commands that give them
+        let line = match line.split_whitespace().next().map(str::to_ascii_lowercase).as_deref() {
+            Some("al" | "ll" | "disp") => line.to_string(),
+            _ => resolve(&machine.labels, line)?,
+        };
+        let line = line.as_str();
+        let (command, rest) = match line.strip_prefix('>') {
+            Some(rest) => (">", rest),
+            None => line.split_once(char::is_whitespace).unwrap_or((line, "")),
+        };
+        let args: Vec<&str> = rest.split([' ', ',']).filter(|arg| !arg.is_empty()).collect();
+        match command.to_ascii_lowercase().as_str() {
+            "" => {}
+            "m" => {
+                let (start, end) = range(&args, self.next_dump, machine.cpu.pc)?;
+                let lines = end.map_or(DEFAULT_LINES, |end| end.wrapping_sub(start) / DUMP_WIDTH + 1);
+                for line in 0..lines {
+                    let addr = start.wrapping_add(line * DUMP_WIDTH);
+                    let bytes: Vec<u8> = (0..DUMP_WIDTH).map(|i| machine.memory.peek(addr.wrapping_add(i))).collect();
+                    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    let text: String = bytes.iter().map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+                    self.print(format!(">C:{:04X}  {}  {}", addr, hex.join(" "), text));
+                }
+                self.next_dump = Some(start.wrapping_add(lines.wrapping_mul(DUMP_WIDTH)));
+            }
+            "d" => {
+                let (start, end) = range(&args, self.next_disassembly, machine.cpu.pc)?;
+                // Up to the instruction covering the end, or a screenful
+                let mut covered = 0u32;
+                for line in 1.. {
+                    let addr = start.wrapping_add(covered as u16);
+                    if let Some(label) = label_line(machine, addr) {
+                        self.print(label);
+                    }
+                    let (text, size) = disassembly_line(machine, addr, false);
+                    self.print(text);
+                    covered += size as u32;
+                    if end.map_or(line == DEFAULT_LINES, |end| covered > end.wrapping_sub(start) as u32) {
+                        break;
+                    }
+                }
+                self.next_disassembly = Some(start.wrapping_add(covered as u16));
+            }
+            ">" => {
+                let Some((addr, bytes)) = args.split_first() else {
+                    bail!("> needs an address and bytes");
+                };
+                let addr = number(addr)?;
+                if bytes.is_empty() {
+                    bail!("> needs bytes to write");
+                }
+                for (i, byte) in bytes.iter().enumerate() {
+                    machine.memory.write(addr.wrapping_add(i as u16), byte_number(byte)?);
+                }
+            }
+            "a" => {
+                let Some(addr) = args.first() else {
+                    bail!("a needs an address");
+                };
+                let addr = number(addr)?;
+                let instruction = rest.trim_start().split_once(char::is_whitespace).map_or("", |(_, instruction)| instruction);
+                if instruction.trim().is_empty() {
+                    self.assembling = Some(addr);
+                } else {
+                    self.assemble_at(machine, addr, instruction)?;
+                }
+            }
+            "r" => {
+                for arg in &args {
+                    let Some((register, value)) = arg.split_once('=') else {
+                        bail!("expected REGISTER=VALUE, not {:?}", arg);
+                    };
+                    let cpu = &mut machine.cpu;
+                    match register.to_ascii_uppercase().as_str() {
+                        "A" => cpu.a = byte_number(value)?,
+                        "X" => cpu.x = byte_number(value)?,
+                        "Y" => cpu.y = byte_number(value)?,
+                        "SP" => cpu.sp = byte_number(value)?,
+                        "PC" => cpu.pc = number(value)?,
+                        "P" => cpu.status = StatusFlags::from_byte(byte_number(value)?),
+                        _ => bail!("unknown register {:?}", register),
+                    }
+                }
+                self.registers(machine);
+            }
+            "z" => {
+                let count = match args.first() {
+                    Some(count) => number(count)?,
+                    None => 1,
+                };
+                self.step(machine, count)?;
+            }
+            "n" => {
+                // Anything but a JSR is a single step
+                if machine.memory.peek(machine.cpu.pc) != JSR {
+                    self.step(machine, 1)?;
+                    return Ok(false);
+                }
+                self.until = Some(Until::Address { pc: machine.cpu.pc.wrapping_add(3), sp: machine.cpu.sp });
+                return Ok(true);
+            }
+            "ret" => {
+                self.until = Some(Until::Return { sp: machine.cpu.sp });
+                return Ok(true);
+            }
+            "until" | "un" => {
+                let Some(addr) = args.first() else {
+                    bail!("until needs an address");
+                };
+                self.until = Some(Until::Address { pc: number(addr)?, sp: 0 });
+                return Ok(true);
+            }
+            "g" => {
+                if let Some(addr) = args.first() {
+                    machine.cpu.pc = number(addr)?;
+                }
+                return Ok(true);
+            }
+            "x" => return Ok(true),
+            "al" => {
+                let [addr, label] = args[..] else {
+                    bail!("al needs an address and a .label");
+                };
+                let Some(label) = label.strip_prefix('.').filter(|label| is_label(label)) else {
+                    bail!("{:?} isn't a .label", label);
+                };
+                machine.labels.add(number(addr)?, label.to_string());
+            }
+            "ll" => {
+                let path = rest.trim().trim_matches('"');
+                if path.is_empty() {
+                    bail!("ll needs a file");
+                }
+                let count = machine.labels.load(std::path::Path::new(path))?;
+                self.print(format!("{} labels from {}", count, path));
+            }
+            "shl" => {
+                let labels: Vec<String> = machine.labels.iter().map(|(addr, label)| format!("${:04X} .{}", addr, label)).collect();
+                if labels.is_empty() {
+                    self.print("No labels".to_string());
+                }
+                for line in labels {
+                    self.print(line);
+                }
+            }
+            "int" => {
+                let count = match args.first() {
+                    Some(count) => number(count)?,
+                    None => DEFAULT_INTERRUPTS,
+                };
+                let lines: Vec<String> = machine.interrupts.last(count as usize).map(|event| event.to_string()).collect();
+                if lines.is_empty() {
+                    self.print("No interrupts".to_string());
+                } else {
+                    self.print(" Frame Line/Cycle".to_string());
+                }
+                for line in lines {
+                    self.print(line);
+                }
+            }
+            "bt" => {
+                for line in machine.calls.backtrace(machine.cpu.pc, &machine.labels) {
+                    self.print(line);
+                }
+            }
+            "disp" => {
+                if !rest.trim().is_empty() {
+                    machine.displays.push(Expression::parse(rest, &machine.labels)?);
+                }
+                let lines: Vec<String> = machine
+                    .displays
+                    .iter()
+                    .enumerate()
+                    .map(|(i, expression)| format!("{}: {} = {}", i + 1, expression, expression.evaluate(&machine.cpu, &machine.memory)))
+                    .collect();
+                if lines.is_empty() {
+                    self.print("No displays".to_string());
+                }
+                for line in lines {
+                    self.print(line);
+                }
+            }
+            "undisp" => match args.first() {
+                Some(n) => {
+                    let n: usize = n.parse().map_err(|_| anyhow::anyhow!("invalid display number {:?}", n))?;
+                    if n == 0 || n > machine.displays.len() {
+                        bail!("no display {}", n);
+                    }
+                    machine.displays.remove(n - 1);
+                }
+                None => machine.displays.clear(),
+            },
+            "break" | "bk" => match args.first() {
+                Some(addr) => {
+                    let addr = number(addr)?;
+                    machine.cpu.add_breakpoint(addr);
+                    self.print(format!("Break at ${:04X}", addr));
+                }
+                None => {
+                    let breakpoints = machine.cpu.breakpoints();
+                    if breakpoints.is_empty() {
+                        self.print("No breakpoints".to_string());
+                    }
+                    for addr in breakpoints {
+                        self.print(format!("Break at ${:04X}", addr));
+                    }
+                }
+            },
+            "watch" | "w" if args.first().is_some_and(|arg| arg.eq_ignore_ascii_case("bank")) => {
+                machine.memory.watches.get_or_insert_with(Watches::new).watch_banking(true);
+                self.print("Watch banking".to_string());
+            }
+            "watch" | "w" => {
+                let (kind, args) = match args.first().map(|kind| kind.to_ascii_lowercase()).as_deref() {
+                    Some("load") => (WatchKind::Read, &args[1..]),
+                    Some("store") => (WatchKind::Write, &args[1..]),
+                    Some("change") => (WatchKind::Change, &args[1..]),
+                    _ => (WatchKind::ReadWrite, &args[..]),
+                };
+                match args {
+                    [] => {
+                        let watchpoints = machine.memory.watches.as_ref().map_or(&[][..], |watches| watches.watchpoints());
+                        let mut lines: Vec<String> = watchpoints.iter().map(|watch| format!("Watch {}", watch.describe())).collect();
+                        if machine.memory.watches.as_ref().is_some_and(|watches| watches.banking_watched()) {
+                            lines.push("Watch banking".to_string());
+                        }
+                        if lines.is_empty() {
+                            self.print("No watchpoints".to_string());
+                        }
+                        for line in lines {
+                            self.print(line);
+                        }
+                    }
+                    [start, rest @ ..] => {
+                        let start = number(start)?;
+                        let end = match rest.first() {
+                            Some(end) => number(end)?,
+                            None => start,
+                        };
+                        if end < start {
+                            bail!("range ends before it starts");
+                        }
+                        let watch = Watch { range: start..=end, kind };
+                        self.print(format!("Watch {}", watch.describe()));
+                        machine.memory.watches.get_or_insert_with(Watches::new).add_watchpoint(watch);
+                    }
+                }
+            }
+            "delete" | "del" => {
+                let addr = args.first().map(|addr| number(addr)).transpose()?;
+                match addr {
+                    Some(addr) => machine.cpu.remove_breakpoint(addr),
+                    None => {
+                        for addr in machine.cpu.breakpoints() {
+                            machine.cpu.remove_breakpoint(addr);
+                        }
+                    }
+                }
+                if let Some(watches) = &mut machine.memory.watches {
+                    watches.remove_watchpoints(addr);
+                    // Nothing left to watch, so the bus can stop looking
+                    if watches.is_empty() {
+                        machine.memory.watches = None;
+                    }
+                }
+            }
+            _ => bail!("unknown command {:?}", command),
+        }
+        Ok(false)
+    }
+
+    // Run `count` instructions, or until a watchpoint stops them
+    fn step(&mut self, machine: &mut Machine, count: u16) -> Result<()> {
+        for _ in 0..count {
+            match machine.step()? {
+                // A breakpoint stops the first step; the next one runs it
+                StepResult::BreakpointHit(_) => {
+                    machine.step()?;
+                }
+                StepResult::WatchpointHit { pc, event, .. } => {
+                    self.print(format!("WATCH: {} at ${:04X}", event.describe(), pc));
+                    for line in banking_change(machine, &event) {
+                        self.print(line);
+                    }
+                    break;
+                }
+                StepResult::Executed(_) => {}
+            }
+        }
+        self.registers(machine);
+        self.print(disassembly_line(machine, machine.cpu.pc, true).0);
+        self.next_disassembly = None;
+        Ok(())
+    }
+
+    fn assemble_at(&mut self, machine: &mut Machine, addr: u16, instruction: &str) -> Result<()> {
+        let bytes = assemble(addr, instruction)?;
+        for (i, &byte) in bytes.iter().enumerate() {
+            machine.memory.write(addr.wrapping_add(i as u16), byte);
+        }
+        self.print(disassembly_line(machine, addr, false).0);
+        self.assembling = Some(addr.wrapping_add(bytes.len() as u16));
+        Ok(())
+    }
+
+    fn registers(&mut self, machine: &Machine) {
+        for line in registers(machine) {
+            self.print(line);
+        }
+    }
+}
+
+fn registers(machine: &Machine) -> [String; 2] {
+    let cpu = &machine.cpu;
+    [
+        "  ADDR A  X  Y  SP NV-BDIZC".to_string(),
+        format!(".;{:04X} {:02X} {:02X} {:02X} {:02X} {:08b}", cpu.pc, cpu.a, cpu.x, cpu.y, cpu.sp, cpu.status.as_byte()),
+    ]
+}
+
+// How the banking changed, when the banking watch stopped at `event`
+pub fn banking_change(machine: &Machine, event: &BusEvent) -> Vec<String> {
+    match machine.memory.watches.as_ref().and_then(|watches| watches.banking_change(event)) {
+        Some((before, after)) => vec![format!("Banking was {}", before), format!("        now {}", after)],
+        None => Vec::new(),
+    }
+}
+
+// The registers and the code around the PC, the instruction there marked
+pub fn where_stopped(machine: &Machine) -> Vec<String> {
+    let pc = machine.cpu.pc;
+    let mut lines = registers(machine).to_vec();
+    let mut addr = lead_in(&machine.memory, pc);
+    while addr != pc {
+        let (line, size) = disassembly_line(machine, addr, false);
+        lines.extend(label_line(machine, addr));
+        lines.push(line);
+        addr = addr.wrapping_add(size);
+    }
+    for n in 0..=LINES_AFTER_PC {
+        let (line, size) = disassembly_line(machine, addr, n == 0);
+        lines.extend(label_line(machine, addr));
+        lines.push(if n == 0 { line.replacen('.', ">", 1) } else { line });
+        addr = addr.wrapping_add(size);
+    }
+    lines
+}
+
+// The user's label for `addr`, on its own line before the instruction there
+fn label_line(machine: &Machine, addr: u16) -> Option<String> {
+    machine.labels.name(addr).map(|label| format!(".{}:", label))
+}
+
+// Where to start disassembling to show up to 3 instructions before `pc`:
+// the furthest back that decodes into instructions the CPU runs, ending
+// right at it, as code before the PC can't be told for sure
+fn lead_in(memory: &dyn Memory, pc: u16) -> u16 {
+    (1..=9u16)
+        .rev()
+        .map(|back| pc.wrapping_sub(back))
+        .find(|&start| {
+            let mut addr = start;
+            for _ in 0..3 {
+                let instruction = &INSTRUCTIONS[memory.peek(addr) as usize];
+                if instruction.handler.is_none() {
+                    return false;
+                }
+                addr = addr.wrapping_add(instruction.bytes as u16);
+                if addr == pc {
+                    return true;
+                }
+            }
+            false
+        })
+        .unwrap_or(pc)
+}
+
+// The line with each .label in it replaced by its address
+fn resolve(labels: &Labels, line: &str) -> Result<String> {
+    let mut resolved = String::new();
+    let mut rest = line;
+    while let Some(dot) = rest.find('.') {
+        resolved.push_str(&rest[..dot]);
+        let after = &rest[dot + 1..];
+        let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+        match &after[..end] {
+            "" => resolved.push('.'),
+            label => match labels.address(label) {
+                Some(addr) => resolved.push_str(&format!("${:04X}", addr)),
+                None => bail!("unknown label .{}", label),
+            },
+        }
+        rest = &after[end..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+// Where m or d start: from the first argument, or going on from where the
+// last one stopped (or the PC). The second is the last address, if given.
+fn range(args: &[&str], next: Option<u16>, pc: u16) -> Result<(u16, Option<u16>)> {
+    let start = match args.first() {
+        Some(start) => number(start)?,
+        None => next.unwrap_or(pc),
+    };
+    Ok((start, args.get(1).map(|end| number(end)).transpose()?))
+}
+
+fn number(text: &str) -> Result<u16> {
+    u16::from_str_radix(text.trim().trim_start_matches('$'), 16).map_err(|_| anyhow::anyhow!("invalid number {:?}", text))
+}
+
+fn byte_number(text: &str) -> Result<u8> {
+    match number(text)? {
+        value @ 0..=0xFF => Ok(value as u8),
+        _ => bail!("{:?} is more than a byte", text),
+    }
+}
+
+// A line of d: address, bytes and instruction, with a note on what it works
+// on (see annotate), and the instruction's length. With the CPU stopped at
+// the instruction, the note follows its operand with the registers.
+fn disassembly_line(machine: &Machine, addr: u16, at_pc: bool) -> (String, u16) {
+    let memory = &machine.memory;
+    let (instruction, size) = disassemble(memory, addr);
+    let bytes: Vec<String> = (0..size).map(|i| format!("{:02X}", memory.peek(addr.wrapping_add(i)))).collect();
+    let line = format!(".C:{:04X}  {:<9} {}", addr, bytes.join(" "), instruction);
+    match annotate(memory, &machine.labels, addr, at_pc.then_some(&machine.cpu)) {
+        Some(note) => (format!("{:<30} ; {}", line, note), size),
+        None => (line, size),
+    }
+}
+
+// Assemble one instruction to go at `addr`, e.g. "LDA ($FB),Y" or
+// "BNE $1000". Operands that fit a byte take the zero page modes.
+fn assemble(addr: u16, text: &str) -> Result<Vec<u8>> {
+    use AddressingMode::*;
+    let text = text.trim().to_ascii_uppercase();
+    let (mnemonic, operand) = text.split_once(char::is_whitespace).unwrap_or((&text, ""));
+    let operand: String = operand.chars().filter(|c| !c.is_whitespace()).collect();
+    // Only what the CPU runs, so the documented NOP and SBC are found
+    let runs = |instruction: &Instruction| instruction.handler.is_some();
+    if !INSTRUCTIONS.iter().any(|instruction| runs(instruction) && instruction.mnemonic == mnemonic) {
+        bail!("unknown instruction {:?}", mnemonic);
+    }
+
+    // The modes the operand could be, and its value
+    let (modes, value): (&[AddressingMode], u16) = if operand.is_empty() {
+        (&[Implied, Accumulator], 0)
+    } else if operand == "A" {
+        (&[Accumulator], 0)
+    } else if let Some(value) = operand.strip_prefix('#') {
+        (&[Immediate], number(value)?)
+    } else if let Some(value) = operand.strip_prefix('(').and_then(|inner| inner.strip_suffix(",X)")) {
+        (&[IndirectX], number(value)?)
+    } else if let Some(value) = operand.strip_prefix('(').and_then(|inner| inner.strip_suffix("),Y")) {
+        (&[IndirectY], number(value)?)
+    } else if let Some(value) = operand.strip_prefix('(').and_then(|inner| inner.strip_suffix(')')) {
+        (&[Indirect], number(value)?)
+    } else if let Some(value) = operand.strip_suffix(",X") {
+        (&[ZeroPageX, AbsoluteX], number(value)?)
+    } else if let Some(value) = operand.strip_suffix(",Y") {
+        (&[ZeroPageY, AbsoluteY], number(value)?)
+    } else {
+        (&[Relative, ZeroPage, Absolute], number(&operand)?)
+    };
+
+    let byte_operand = |mode| matches!(mode, Immediate | ZeroPage | ZeroPageX | ZeroPageY | IndirectX | IndirectY);
+    let (opcode, mode) = modes
+        .iter()
+        .filter(|&&mode| value <= 0xFF || !byte_operand(mode))
+        .find_map(|&mode| {
+            let opcode = INSTRUCTIONS.iter().position(|instruction| runs(instruction) && instruction.mnemonic == mnemonic && instruction.mode == mode)?;
+            Some((opcode as u8, mode))
+        })
+        .ok_or_else(|| anyhow::anyhow!("{} can't take {:?}", mnemonic, operand))?;
+
+    let mut bytes = vec![opcode];
+    match mode {
+        Implied | Accumulator => {}
+        Relative => {
+            let offset = value.wrapping_sub(addr.wrapping_add(2)) as i16;
+            if !(-128..=127).contains(&offset) {
+                bail!("branch to ${:04X} is out of range", value);
+            }
+            bytes.push(offset as u8);
+        }
+        _ if byte_operand(mode) => bytes.push(value as u8),
+        _ => bytes.extend_from_slice(&value.to_le_bytes()),
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::VideoStandard;
+
+    #[test]
+    fn test_assemble() {
+        assert_eq!(assemble(0x1000, "lda #$0a").unwrap(), vec![0xA9, 0x0A]);
+        assert_eq!(assemble(0x1000, "LDA $FB").unwrap(), vec![0xA5, 0xFB]);
+        assert_eq!(assemble(0x1000, "LDA $00FB,X").unwrap(), vec![0xB5, 0xFB]);
+        assert_eq!(assemble(0x1000, "STA $D020").unwrap(), vec![0x8D, 0x20, 0xD0]);
+        assert_eq!(assemble(0x1000, "LDA ($FB),Y").unwrap(), vec![0xB1, 0xFB]);
+        assert_eq!(assemble(0x1000, "JMP ($0314)").unwrap(), vec![0x6C, 0x14, 0x03]);
+        assert_eq!(assemble(0x1000, "ASL").unwrap(), vec![0x0A]);
+        assert_eq!(assemble(0x1000, "RTS").unwrap(), vec![0x60]);
+        // JMP has no zero page mode, and LDX $FB,Y no absolute one needed
+        assert_eq!(assemble(0x1000, "JMP $10").unwrap(), vec![0x4C, 0x10, 0x00]);
+        assert_eq!(assemble(0x1000, "LDX $FB,Y").unwrap(), vec![0xB6, 0xFB]);
+        assert_eq!(assemble(0x1000, "BNE $1000").unwrap(), vec![0xD0, 0xFE]);
+        assert_eq!(assemble(0x1000, "BCC 1081").unwrap(), vec![0x90, 0x7F]);
+
+        assert!(assemble(0x1000, "BCC $1082").is_err());
+        assert!(assemble(0x1000, "LDA #$100").is_err());
+        assert!(assemble(0x1000, "STA #$01").is_err());
+        assert!(assemble(0x1000, "FOO").is_err());
+        // The documented opcodes, not the undocumented ones the CPU can't run
+        assert_eq!(assemble(0x1000, "NOP").unwrap(), vec![0xEA]);
+        assert_eq!(assemble(0x1000, "SBC #$01").unwrap(), vec![0xE9, 0x01]);
+        assert!(assemble(0x1000, "LAX $02").is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let mut monitor = Monitor::new();
+        let enter = |monitor: &mut Monitor, machine: &mut Machine, line: &str| {
+            monitor.input = line.to_string();
+            monitor.enter(machine)
+        };
+
+        // Assemble a loop, one line at a time, and read it back
+        enter(&mut monitor, &mut machine, "a c000 ldx #$03");
+        for line in ["inc $d020", "dex", "bne $c002", ""] {
+            enter(&mut monitor, &mut machine, line);
+        }
+        monitor.output.clear();
+        enter(&mut monitor, &mut machine, "d c000 c006");
+        assert_eq!(monitor.output[1..], [
+            ".C:C000  A2 03     LDX #$03",
+            ".C:C002  EE 20 D0  INC $D020   ; border color",
+            ".C:C005  CA        DEX",
+            ".C:C006  D0 FA     BNE $C002",
+        ]);
+
+        // Write bytes, and see them
+        enter(&mut monitor, &mut machine, ">c100 48 49 00");
+        enter(&mut monitor, &mut machine, "m c100 c107");
+        assert_eq!(monitor.output.last().unwrap(), ">C:C100  48 49 00 00 00 00 00 00  HI......");
+
+        // Set the PC and step through the first two instructions
+        enter(&mut monitor, &mut machine, "r pc=c000, p=20");
+        assert_eq!(monitor.output.last().unwrap(), ".;C000 00 00 00 FD 00100000");
+        enter(&mut monitor, &mut machine, "z 2");
+        assert_eq!(machine.cpu.pc, 0xC005);
+        assert_eq!(machine.cpu.x, 3);
+        assert_eq!(monitor.output.last().unwrap(), ".C:C005  CA        DEX");
+        enter(&mut monitor, &mut machine, "int");
+        assert_eq!(monitor.output.last().unwrap(), "No interrupts");
+        enter(&mut monitor, &mut machine, "bt");
+        assert_eq!(monitor.output.last().unwrap(), "#0  $C005 in ?");
+
+        // Displays, listed with their values as they are added
+        enter(&mut monitor, &mut machine, "disp");
+        assert_eq!(monitor.output.last().unwrap(), "No displays");
+        enter(&mut monitor, &mut machine, "disp X");
+        enter(&mut monitor, &mut machine, "disp @w($C003) + 1");
+        assert_eq!(monitor.output[monitor.output.len() - 2..], ["1: X = $03 3", "2: @w($C003) + 1 = $D021 53281"]);
+        enter(&mut monitor, &mut machine, "undisp 1");
+        assert_eq!(machine.displays.len(), 1);
+        enter(&mut monitor, &mut machine, "undisp 2");
+        assert_eq!(monitor.output.last().unwrap(), "Error: no display 2");
+        enter(&mut monitor, &mut machine, "undisp");
+        assert!(machine.displays.is_empty());
+
+        assert!(!enter(&mut monitor, &mut machine, "q"));
+        assert_eq!(monitor.output.last().unwrap(), "Error: unknown command \"q\"");
+        assert!(enter(&mut monitor, &mut machine, "g c002"));
+        assert_eq!(machine.cpu.pc, 0xC002);
+
+        monitor.recall(true);
+        assert_eq!(monitor.input, "g c002");
+        monitor.recall(true);
+        assert_eq!(monitor.input, "q");
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let mut monitor = Monitor::new();
+        let enter = |monitor: &mut Monitor, machine: &mut Machine, line: &str| {
+            monitor.input = line.to_string();
+            monitor.enter(machine)
+        };
+        let path = std::env::temp_dir().join(format!("go64-monitor-labels-{}.lbl", std::process::id()));
+        std::fs::write(&path, "al C:c000 .main\nal C:d020 .border\n").unwrap();
+        enter(&mut monitor, &mut machine, &format!("ll \"{}\"", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(monitor.output.last().unwrap(), &format!("2 labels from {}", path.display()));
+
+        // Labels go where addresses do, and show in the disassembly
+        enter(&mut monitor, &mut machine, "al c005 .loop");
+        enter(&mut monitor, &mut machine, "a .main lda #$00");
+        for line in ["sta .border", "jmp .loop", ""] {
+            enter(&mut monitor, &mut machine, line);
+        }
+        monitor.output.clear();
+        enter(&mut monitor, &mut machine, "d .main c005");
+        assert_eq!(monitor.output[1..], [
+            ".main:",
+            ".C:C000  A9 00     LDA #$00",
+            ".C:C002  8D 20 D0  STA $D020   ; .border",
+            ".loop:",
+            ".C:C005  4C 05 C0  JMP $C005   ; .loop",
+        ]);
+        monitor.output.clear();
+        enter(&mut monitor, &mut machine, "shl");
+        assert_eq!(monitor.output[1..], ["$C000 .main", "$C005 .loop", "$D020 .border"]);
+
+        enter(&mut monitor, &mut machine, "d .start");
+        assert_eq!(monitor.output.last().unwrap(), "Error: unknown label .start");
+        enter(&mut monitor, &mut machine, "ll /nonexistent/labels");
+        assert!(monitor.output.last().unwrap().starts_with("Error: reading labels from /nonexistent/labels: "));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let mut monitor = Monitor::new();
+        let enter = |monitor: &mut Monitor, machine: &mut Machine, line: &str| {
+            monitor.input = line.to_string();
+            monitor.enter(machine)
+        };
+        // LDX #$03, INC $D020, DEX, BNE $C002
+        for (i, &byte) in [0xA2, 0x03, 0xEE, 0x20, 0xD0, 0xCA, 0xD0, 0xFA].iter().enumerate() {
+            machine.memory.write(0xC000 + i as u16, byte);
+        }
+        machine.cpu.pc = 0xC000;
+
+        enter(&mut monitor, &mut machine, "break c005");
+        enter(&mut monitor, &mut machine, "bk c006");
+        enter(&mut monitor, &mut machine, "del c006");
+        enter(&mut monitor, &mut machine, "break");
+        assert_eq!(monitor.output.last().unwrap(), "Break at $C005");
+        assert_eq!(machine.cpu.breakpoints(), vec![0xC005]);
+
+        // Stepping stops there, and the code before it is shown
+        while machine.step().unwrap() != StepResult::BreakpointHit(0xC005) {}
+        let lines = where_stopped(&machine);
+        assert_eq!(lines[1], ".;C005 00 03 00 FD 00100100");
+        assert_eq!(lines[2..6], [
+            ".C:C000  A2 03     LDX #$03",
+            ".C:C002  EE 20 D0  INC $D020   ; border color",
+            ">C:C005  CA        DEX",
+            ".C:C006  D0 FA     BNE $C002",
+        ]);
+
+        enter(&mut monitor, &mut machine, "del");
+        assert!(machine.cpu.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let mut monitor = Monitor::new();
+        let enter = |monitor: &mut Monitor, machine: &mut Machine, line: &str| {
+            monitor.input = line.to_string();
+            monitor.enter(machine)
+        };
+        // Run the machine as the main loop does, until the monitor's run ends
+        let run = |monitor: &mut Monitor, machine: &mut Machine| {
+            while !monitor.run_ended(machine) {
+                machine.step().unwrap();
+            }
+            monitor.open(machine);
+        };
+        // C000: JSR C010, then LDA #$01 at C003. C010 counts X down,
+        // calling itself until X goes negative.
+        enter(&mut monitor, &mut machine, "a c000 jsr c010");
+        enter(&mut monitor, &mut machine, "lda #$01");
+        enter(&mut monitor, &mut machine, "");
+        enter(&mut monitor, &mut machine, "a c010 dex");
+        for line in ["bmi c016", "jsr c010", "rts", ""] {
+            enter(&mut monitor, &mut machine, line);
+        }
+        machine.cpu.pc = 0xC000;
+        machine.cpu.x = 2;
+
+        // Stepping over the call runs all of it, the calls within included
+        assert!(enter(&mut monitor, &mut machine, "n"));
+        run(&mut monitor, &mut machine);
+        assert_eq!((machine.cpu.pc, machine.cpu.sp, machine.cpu.x), (0xC003, 0xFD, 0xFF));
+        // Not a JSR: a single step
+        assert!(!enter(&mut monitor, &mut machine, "n"));
+        assert_eq!(machine.cpu.pc, 0xC005);
+
+        // Into the routine two calls deep, then out of that call and no
+        // further, the calls it makes included
+        machine.cpu.pc = 0xC000;
+        machine.cpu.x = 2;
+        enter(&mut monitor, &mut machine, "z 4");
+        assert_eq!((machine.cpu.pc, machine.cpu.sp), (0xC010, 0xF9));
+        assert!(enter(&mut monitor, &mut machine, "ret"));
+        run(&mut monitor, &mut machine);
+        assert_eq!((machine.cpu.pc, machine.cpu.sp), (0xC016, 0xFB));
+
+        assert!(enter(&mut monitor, &mut machine, "until c003"));
+        run(&mut monitor, &mut machine);
+        assert_eq!((machine.cpu.pc, machine.cpu.sp), (0xC003, 0xFD));
+        assert!(!monitor.run_ended(&machine));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let mut monitor = Monitor::new();
+        let enter = |monitor: &mut Monitor, machine: &mut Machine, line: &str| {
+            monitor.input = line.to_string();
+            monitor.enter(machine)
+        };
+        // LDA #$00, STA $FB, STA $FB, INC $FB
+        for (i, &byte) in [0xA9, 0x00, 0x85, 0xFB, 0x85, 0xFB, 0xE6, 0xFB].iter().enumerate() {
+            machine.memory.write(0xC000 + i as u16, byte);
+        }
+        machine.memory.write(0xFB, 0x00);
+        machine.cpu.pc = 0xC000;
+
+        // Storing the 0 already there changes nothing
+        enter(&mut monitor, &mut machine, "watch change fb");
+        enter(&mut monitor, &mut machine, "z 10");
+        assert_eq!(monitor.output[monitor.output.len() - 4], "WATCH: store $00FB = $01 at $C006");
+        assert_eq!(machine.cpu.pc, 0xC008);
+
+        enter(&mut monitor, &mut machine, "w store d020 d021");
+        enter(&mut monitor, &mut machine, "watch");
+        assert_eq!(monitor.output[monitor.output.len() - 2..], ["Watch $00FB change", "Watch $D020-$D021 store"]);
+        enter(&mut monitor, &mut machine, "del d021");
+        enter(&mut monitor, &mut machine, "watch");
+        assert_eq!(monitor.output.last().unwrap(), "Watch $00FB change");
+        enter(&mut monitor, &mut machine, "del");
+        assert!(machine.memory.watches.is_none());
+
+        // LDA #$37, STA $01 changes nothing; LDA #$35, STA $01 banks out
+        // the ROMs
+        for (i, &byte) in [0xA9, 0x37, 0x85, 0x01, 0xA9, 0x35, 0x85, 0x01].iter().enumerate() {
+            machine.memory.write(0xC008 + i as u16, byte);
+        }
+        enter(&mut monitor, &mut machine, "watch bank");
+        enter(&mut monitor, &mut machine, "z 10");
+        let lines = &monitor.output[monitor.output.len() - 6..monitor.output.len() - 3];
+        assert_eq!(lines, [
+            "WATCH: store $0001 = $35 at $C00E",
+            "Banking was %111 8:RAM A:BASIC D:I/O E:KERNAL",
+            "        now %101 8:RAM A:RAM D:I/O E:RAM",
+        ]);
+        enter(&mut monitor, &mut machine, "watch");
+        assert_eq!(monitor.output.last().unwrap(), "Watch banking");
+        enter(&mut monitor, &mut machine, "del");
+        assert!(machine.memory.watches.is_none());
+    }
+}
@@ -0,0 +1,90 @@
+// Execution profiler: counts instructions and cycles per PC address
+
+use std::fmt::Write;
+
+use super::symbols::routine;
+use crate::memory::Memory;
+
+pub struct Profiler {
+    instructions: Vec<u64>, // Indexed by PC
+    cycles: Vec<u64>,       // Indexed by PC
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            instructions: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+        }
+    }
+
+    // Attribute one CPU step to the PC it started at. Cycles spent stalled
+    // on RDY or entering an interrupt are charged to the address that was
+    // about to execute.
+    pub fn record(&mut self, pc: u16, cycles: u8) {
+        self.instructions[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles as u64;
+    }
+
+    // The `top_n` addresses with the most cycles, hottest first
+    pub fn hot_spots(&self, top_n: usize) -> Vec<(u16, u64, u64)> {
+        let mut spots: Vec<(u16, u64, u64)> = (0..=0xFFFFu16)
+            .filter(|&pc| self.instructions[pc as usize] > 0)
+            .map(|pc| (pc, self.instructions[pc as usize], self.cycles[pc as usize]))
+            .collect();
+        spots.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        spots.truncate(top_n);
+        spots
+    }
+
+    pub fn report(&self, memory: &dyn Memory, top_n: usize) -> String {
+        let total_instructions: u64 = self.instructions.iter().sum();
+        let total_cycles: u64 = self.cycles.iter().sum();
+
+        let mut out = String::new();
+        let _ = writeln!(out, "Profile: {} instructions, {} cycles", total_instructions, total_cycles);
+        let _ = writeln!(out, "{:<7} {:<14} {:>12} {:>14} {:>6}  Region", "Address", "Instruction", "Count", "Cycles", "%Cyc");
+
+        for (pc, count, cycles) in self.hot_spots(top_n) {
+            let (instruction, _) = super::disassemble(memory, pc);
+            let percent = if total_cycles > 0 { cycles as f64 * 100.0 / total_cycles as f64 } else { 0.0 };
+            let _ = writeln!(
+                out,
+                "${:04X}   {:<14} {:>12} {:>14} {:>5.1}%  {}",
+                pc, instruction, count, cycles, percent, region_label(pc)
+            );
+        }
+        out
+    }
+}
+
+// Name the part of the default memory map an address belongs to
+fn region_label(pc: u16) -> String {
+    match pc {
+        0xA000..=0xBFFF if let Some(name) = routine(pc) => format!("BASIC {}", name),
+        _ if let Some(name) = routine(pc) => format!("KERNAL {}", name),
+        0xA000..=0xBFFF => "BASIC ROM".to_string(),
+        0xE000..=0xFFFF => "KERNAL ROM".to_string(),
+        _ => "RAM".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_spots_sorted_by_cycles() {
+        let mut profiler = Profiler::new();
+        profiler.record(0xE5CD, 4);
+        profiler.record(0xE5CD, 4);
+        profiler.record(0x0801, 2);
+        profiler.record(0xFFD2, 6);
+
+        let spots = profiler.hot_spots(2);
+        assert_eq!(spots, vec![(0xE5CD, 2, 8), (0xFFD2, 1, 6)]);
+        assert_eq!(region_label(0xFFD2), "KERNAL CHROUT");
+        assert_eq!(region_label(0xE5CD), "KERNAL ROM");
+        assert_eq!(region_label(0xA474), "BASIC READY");
+    }
+}
@@ -0,0 +1,94 @@
+// The stack read back as calls and interrupts. Nothing records what pushed
+// each byte, so return addresses are recognised by the instruction they
+// return past: a JSR pushes the address of its own last byte, and an
+// interrupt pushes the address it goes back to and then the status, which
+// always has bit 5 set (and the B flag for a BRK).
+
+use crate::memory::Memory;
+
+const STACK: u16 = 0x0100;
+const JSR: u8 = 0x20;
+const BRK: u8 = 0x00;
+// Bits 5 and 4 of the status as pushed
+const UNUSED: u8 = 0x20;
+const BREAK: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    // A byte that is neither, pushed with PHA or PHP or left over
+    Byte { addr: u16, value: u8 },
+    // The return address of the JSR at `call` to `target`
+    Return { addr: u16, call: u16, target: u16 },
+    // The status and return address pushed by an IRQ or NMI, or a BRK
+    Interrupt { addr: u16, status: u8, to: u16, brk: bool },
+}
+
+// What is on the stack above `sp`, from the top (the last pushed) down
+pub fn decode(memory: &dyn Memory, sp: u8) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut offset = sp as u16 + 1;
+    while offset <= 0xFF {
+        let addr = STACK + offset;
+        let byte = |i: u16| memory.peek(STACK + offset + i);
+        let word = |i: u16| u16::from_le_bytes([byte(i), byte(i + 1)]);
+        if offset < 0xFF && memory.peek(word(0).wrapping_sub(2)) == JSR {
+            let call = word(0).wrapping_sub(2);
+            let target = u16::from_le_bytes([memory.peek(call.wrapping_add(1)), memory.peek(call.wrapping_add(2))]);
+            entries.push(Entry::Return { addr, call, target });
+            offset += 2;
+        } else if offset < 0xFE && byte(0) & UNUSED != 0 && interrupted(memory, byte(0), word(1)) {
+            let status = byte(0);
+            entries.push(Entry::Interrupt { addr, status, to: word(1), brk: status & BREAK != 0 });
+            offset += 3;
+        } else {
+            entries.push(Entry::Byte { addr, value: byte(0) });
+            offset += 1;
+        }
+    }
+    entries
+}
+
+// A BRK goes back past its padding byte; an IRQ or NMI could have come
+// anywhere, so only an address in I/O or beyond the top of memory rules
+// it out
+fn interrupted(memory: &dyn Memory, status: u8, to: u16) -> bool {
+    if status & BREAK != 0 {
+        return memory.peek(to.wrapping_sub(2)) == BRK;
+    }
+    !(0xD000..=0xDFFF).contains(&to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BasicMemory;
+
+    #[test]
+    fn test_decode() {
+        let mut memory = BasicMemory::new();
+        let mut push = |sp: &mut u8, bytes: &[u8]| {
+            for &byte in bytes {
+                memory.write(STACK + *sp as u16, byte);
+                *sp -= 1;
+            }
+        };
+        let mut sp = 0xFF;
+        // JSR $C100 at $C000 pushes $C002, high byte first
+        push(&mut sp, &[0xC0, 0x02]);
+        // An IRQ at $C105 with the carry set, then the KERNAL saves A
+        push(&mut sp, &[0xC1, 0x05, 0x21, 0x41]);
+        memory.write(0xC000, JSR);
+        memory.write(0xC001, 0x00);
+        memory.write(0xC002, 0xC1);
+
+        assert_eq!(
+            decode(&memory, sp),
+            [
+                Entry::Byte { addr: 0x01FA, value: 0x41 },
+                Entry::Interrupt { addr: 0x01FB, status: 0x21, to: 0xC105, brk: false },
+                Entry::Return { addr: 0x01FE, call: 0xC000, target: 0xC100 },
+            ]
+        );
+        assert_eq!(decode(&memory, 0xFF), []);
+    }
+}
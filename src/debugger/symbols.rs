@@ -0,0 +1,280 @@
+// Names for the addresses code works with: the KERNAL's and BASIC's
+// routines, the vectors they jump through, and the I/O registers, and
+// labels for the user's own code from VICE label files
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+// Entry points in the KERNAL's jump table and the routines behind the
+// interrupts, and in BASIC
+const ROUTINES: &[(u16, &str)] = &[
+    (0xFF81, "CINT"),
+    (0xFF84, "IOINIT"),
+    (0xFF87, "RAMTAS"),
+    (0xFF8A, "RESTOR"),
+    (0xFF8D, "VECTOR"),
+    (0xFF90, "SETMSG"),
+    (0xFF93, "SECOND"),
+    (0xFF96, "TKSA"),
+    (0xFF99, "MEMTOP"),
+    (0xFF9C, "MEMBOT"),
+    (0xFF9F, "SCNKEY"),
+    (0xFFA2, "SETTMO"),
+    (0xFFA5, "ACPTR"),
+    (0xFFA8, "CIOUT"),
+    (0xFFAB, "UNTLK"),
+    (0xFFAE, "UNLSN"),
+    (0xFFB1, "LISTEN"),
+    (0xFFB4, "TALK"),
+    (0xFFB7, "READST"),
+    (0xFFBA, "SETLFS"),
+    (0xFFBD, "SETNAM"),
+    (0xFFC0, "OPEN"),
+    (0xFFC3, "CLOSE"),
+    (0xFFC6, "CHKIN"),
+    (0xFFC9, "CHKOUT"),
+    (0xFFCC, "CLRCHN"),
+    (0xFFCF, "CHRIN"),
+    (0xFFD2, "CHROUT"),
+    (0xFFD5, "LOAD"),
+    (0xFFD8, "SAVE"),
+    (0xFFDB, "SETTIM"),
+    (0xFFDE, "RDTIM"),
+    (0xFFE1, "STOP"),
+    (0xFFE4, "GETIN"),
+    (0xFFE7, "CLALL"),
+    (0xFFEA, "UDTIM"),
+    (0xFFED, "SCREEN"),
+    (0xFFF0, "PLOT"),
+    (0xFFF3, "IOBASE"),
+    (0xFCE2, "reset"),
+    (0xFE43, "NMI"),
+    (0xFF48, "IRQ"),
+    (0xEA31, "IRQ handler"),
+    (0xEA81, "IRQ return"),
+    (0xE544, "clear screen"),
+    (0xA474, "READY"),
+    (0xA533, "LNKPRG"),
+    (0xA7AE, "NEWSTT"),
+    (0xA871, "RUN"),
+    (0xAB1E, "STROUT"),
+    (0xBDCD, "LINPRT"),
+];
+
+// The vectors in page 3 that the KERNAL and BASIC jump through, and the
+// CPU's own at the top of memory
+const VECTORS: &[(u16, &str)] = &[
+    (0x0300, "IERROR"),
+    (0x0302, "IMAIN"),
+    (0x0304, "ICRNCH"),
+    (0x0306, "IQPLOP"),
+    (0x0308, "IGONE"),
+    (0x030A, "IEVAL"),
+    (0x0314, "CINV"),
+    (0x0316, "CBINV"),
+    (0x0318, "NMINV"),
+    (0x031A, "IOPEN"),
+    (0x031C, "ICLOSE"),
+    (0x031E, "ICHKIN"),
+    (0x0320, "ICKOUT"),
+    (0x0322, "ICLRCH"),
+    (0x0324, "IBASIN"),
+    (0x0326, "IBSOUT"),
+    (0x0328, "ISTOP"),
+    (0x032A, "IGETIN"),
+    (0x032C, "ICLALL"),
+    (0x032E, "USRCMD"),
+    (0x0330, "ILOAD"),
+    (0x0332, "ISAVE"),
+    (0xFFFA, "NMI vector"),
+    (0xFFFC, "reset vector"),
+    (0xFFFE, "IRQ vector"),
+];
+
+const VIC_REGISTERS: [&str; 0x2F] = [
+    "sprite 0 x", "sprite 0 y", "sprite 1 x", "sprite 1 y", "sprite 2 x", "sprite 2 y", "sprite 3 x", "sprite 3 y",
+    "sprite 4 x", "sprite 4 y", "sprite 5 x", "sprite 5 y", "sprite 6 x", "sprite 6 y", "sprite 7 x", "sprite 7 y",
+    "sprite x high bits", "control 1", "raster line", "light pen x", "light pen y", "sprite enable", "control 2",
+    "sprite y expand", "memory pointers", "interrupt status", "interrupt enable", "sprite priority",
+    "sprite multicolor", "sprite x expand", "sprite collisions", "sprite background collisions", "border color",
+    "background color 0", "background color 1", "background color 2", "background color 3", "sprite multicolor 0",
+    "sprite multicolor 1", "sprite 0 color", "sprite 1 color", "sprite 2 color", "sprite 3 color", "sprite 4 color",
+    "sprite 5 color", "sprite 6 color", "sprite 7 color",
+];
+
+const VOICE_REGISTERS: [&str; 7] =
+    ["frequency low", "frequency high", "pulse width low", "pulse width high", "control", "attack/decay", "sustain/release"];
+
+const SID_REGISTERS: [&str; 8] = [
+    "filter cutoff low", "filter cutoff high", "filter resonance", "volume/filter mode", "paddle x", "paddle y",
+    "oscillator 3", "envelope 3",
+];
+
+const CIA_REGISTERS: [&str; 16] = [
+    "port A", "port B", "data direction A", "data direction B", "timer A low", "timer A high", "timer B low",
+    "timer B high", "TOD tenths", "TOD seconds", "TOD minutes", "TOD hours", "serial data", "interrupt control",
+    "control A", "control B",
+];
+
+// The name for `addr`, if it has one: the routine starting there, the
+// vector there or the I/O register (at any of its mirrors)
+pub fn name(addr: u16) -> Option<String> {
+    if let Some((_, name)) = ROUTINES.iter().chain(VECTORS).find(|(known, _)| *known == addr) {
+        return Some(name.to_string());
+    }
+    let name = match addr {
+        0x0000 => "CPU port direction".to_string(),
+        0x0001 => "CPU port".to_string(),
+        0xD000..=0xD3FF => VIC_REGISTERS.get(addr as usize & 0x3F)?.to_string(),
+        0xD400..=0xD7FF => match addr as usize & 0x1F {
+            register @ 0..=0x14 => format!("voice {} {}", register / 7 + 1, VOICE_REGISTERS[register % 7]),
+            register => SID_REGISTERS.get(register - 0x15)?.to_string(),
+        },
+        0xD800..=0xDBFF => "color RAM".to_string(),
+        0xDC00..=0xDCFF => format!("CIA1 {}", CIA_REGISTERS[addr as usize & 0x0F]),
+        0xDD00..=0xDDFF => format!("CIA2 {}", CIA_REGISTERS[addr as usize & 0x0F]),
+        _ => return None,
+    };
+    Some(name)
+}
+
+// The routine starting at `addr`, for labelling where code runs
+pub fn routine(addr: u16) -> Option<&'static str> {
+    ROUTINES.iter().find(|(known, _)| *known == addr).map(|(_, name)| *name)
+}
+
+// Labels as cross-assemblers write them for VICE, a line each:
+//
+//   al C:080d .main
+//
+// The address is hex, the C: (the computer's memory) optional. Named
+// without the dot here.
+#[derive(Debug, Default, Clone)]
+pub struct Labels {
+    // The last label given for each address, and where each label is
+    names: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add the labels in a file, returning how many there were
+    pub fn load(&mut self, path: &Path) -> Result<usize> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading labels from {}", path.display()))?;
+        let labels = parse(&text).with_context(|| format!("reading labels from {}", path.display()))?;
+        let count = labels.len();
+        for (addr, name) in labels {
+            self.add(addr, name);
+        }
+        Ok(count)
+    }
+
+    pub fn add(&mut self, addr: u16, name: String) {
+        // A label moved from elsewhere leaves its old address to any other
+        // label there
+        if let Some(old) = self.addresses.insert(name.clone(), addr)
+            && self.names.get(&old) == Some(&name)
+        {
+            self.names.remove(&old);
+            if let Some(other) = self.addresses.iter().find(|&(_, &at)| at == old).map(|(other, _)| other.clone()) {
+                self.names.insert(old, other);
+            }
+        }
+        self.names.insert(addr, name);
+    }
+
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    // Every label, by address
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        let mut labels: Vec<(u16, &str)> = self.addresses.iter().map(|(name, &addr)| (addr, name.as_str())).collect();
+        labels.sort();
+        labels.into_iter()
+    }
+
+    // The name for `addr`: the user's label, or else the built in one
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        match self.name(addr) {
+            Some(label) => Some(format!(".{}", label)),
+            None => name(addr),
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Vec<(u16, String)>> {
+    let mut labels = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => {}
+            ["al", addr, label] => {
+                let hex = addr.strip_prefix("C:").or_else(|| addr.strip_prefix("c:")).unwrap_or(addr);
+                let addr = u16::from_str_radix(hex, 16).ok();
+                match (addr, label.strip_prefix('.')) {
+                    (Some(addr), Some(label)) if is_label(label) => labels.push((addr, label.to_string())),
+                    _ => bail!("line {}: bad label {:?}", n + 1, line),
+                }
+            }
+            _ => bail!("line {}: expected \"al ADDRESS .LABEL\", not {:?}", n + 1, line),
+        }
+    }
+    Ok(labels)
+}
+
+// Letters, digits and underscores, not starting with a digit
+pub fn is_label(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(name(0xFFD2).as_deref(), Some("CHROUT"));
+        assert_eq!(name(0x0314).as_deref(), Some("CINV"));
+        assert_eq!(name(0xD020).as_deref(), Some("border color"));
+        // The VIC-II's registers repeat every 64 bytes, with a gap at the end
+        assert_eq!(name(0xD061).as_deref(), Some("background color 0"));
+        assert_eq!(name(0xD02F), None);
+        assert_eq!(name(0xD40B).as_deref(), Some("voice 2 control"));
+        assert_eq!(name(0xD418).as_deref(), Some("volume/filter mode"));
+        assert_eq!(name(0xD41D), None);
+        assert_eq!(name(0xDC0D).as_deref(), Some("CIA1 interrupt control"));
+        assert_eq!(name(0xDD00).as_deref(), Some("CIA2 port A"));
+        assert_eq!(name(0xC000), None);
+    }
+
+    #[test]
+    fn test_labels() {
+        let labels = parse("al C:080d .main\n\nal c000 .loop_2\nal C:080d .start\n").unwrap();
+        assert_eq!(labels, [(0x080D, "main".to_string()), (0xC000, "loop_2".to_string()), (0x080D, "start".to_string())]);
+        assert!(parse("al C:080d main").is_err());
+        assert!(parse("al C:080g .main").is_err());
+        assert!(parse("break 080d").is_err());
+
+        let mut table = Labels::new();
+        for (addr, label) in labels {
+            table.add(addr, label);
+        }
+        // Both find the address, and the last given names it
+        assert_eq!((table.address("main"), table.address("start")), (Some(0x080D), Some(0x080D)));
+        assert_eq!(table.describe(0x080D).as_deref(), Some(".start"));
+        assert_eq!(table.describe(0xFFD2).as_deref(), Some("CHROUT"));
+        table.add(0x0810, "start".to_string());
+        assert_eq!(table.name(0x080D), Some("main"));
+        assert_eq!(table.iter().collect::<Vec<_>>(), [(0x080D, "main"), (0x0810, "start"), (0xC000, "loop_2")]);
+    }
+}
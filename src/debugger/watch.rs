@@ -0,0 +1,257 @@
+// Bus watches: record the CPU's reads and writes in chosen address ranges,
+// for I/O traces and scripts that follow what a program does, or stop the
+// machine at them as watchpoints. The bus only looks at them while there
+// are any.
+
+use std::ops::RangeInclusive;
+
+use crate::memory::Banking;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Which accesses a watch records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+    // Writes that change the value there
+    Change,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access, changed: bool) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::ReadWrite => true,
+            WatchKind::Change => access == Access::Write && changed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusEvent {
+    pub access: Access,
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl BusEvent {
+    // e.g. "store $D020 = $0E"
+    pub fn describe(&self) -> String {
+        let access = match self.access {
+            Access::Read => "load",
+            Access::Write => "store",
+        };
+        format!("{} ${:04X} = ${:02X}", access, self.addr, self.value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+impl Watch {
+    // Parse a watch as given on the command line: an address or a range of
+    // them in hex ("D020", "$D000-$D3FF"), optionally followed by ":r" or
+    // ":w" to watch only reads or only writes, or ":c" for changes
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (range, kind) = match text.rsplit_once(':') {
+            Some((range, "r")) => (range, WatchKind::Read),
+            Some((range, "w")) => (range, WatchKind::Write),
+            Some((range, "c")) => (range, WatchKind::Change),
+            Some((_, kind)) => return Err(format!("unknown access {:?}, expected r, w or c", kind)),
+            None => (text, WatchKind::ReadWrite),
+        };
+        let address = |addr: &str| {
+            u16::from_str_radix(addr.trim().trim_start_matches('$'), 16)
+                .map_err(|_| format!("invalid address {:?}", addr))
+        };
+        let range = match range.split_once('-') {
+            Some((start, end)) => address(start)?..=address(end)?,
+            None => address(range)?..=address(range)?,
+        };
+        if range.is_empty() {
+            return Err(format!("range {} ends before it starts", text));
+        }
+        Ok(Self { range, kind })
+    }
+
+    // As the monitor lists it, e.g. "$D020-$D021 store"
+    pub fn describe(&self) -> String {
+        let kind = match self.kind {
+            WatchKind::Read => " load",
+            WatchKind::Write => " store",
+            WatchKind::ReadWrite => "",
+            WatchKind::Change => " change",
+        };
+        match (*self.range.start(), *self.range.end()) {
+            (start, end) if start == end => format!("${:04X}{}", start, kind),
+            (start, end) => format!("${:04X}-${:04X}{}", start, end, kind),
+        }
+    }
+}
+
+// The watches set on the bus, and the accesses they caught since the
+// owner last took them: all of them for traces, the first for watchpoints.
+// Watching the banking stops the machine at writes that change it, or to a
+// cartridge's registers, remembering the last change.
+pub struct Watches {
+    watches: Vec<Watch>,
+    events: Vec<BusEvent>,
+    watchpoints: Vec<Watch>,
+    hit: Option<BusEvent>,
+    banking: bool,
+    banking_change: Option<(BusEvent, Banking, Banking)>,
+}
+
+impl Watches {
+    pub fn new() -> Self {
+        Self {
+            watches: Vec::new(),
+            events: Vec::new(),
+            watchpoints: Vec::new(),
+            hit: None,
+            banking: false,
+            banking_change: None,
+        }
+    }
+
+    pub fn add(&mut self, watch: Watch) {
+        self.watches.push(watch);
+    }
+
+    // Stop the machine at accesses the watch catches
+    pub fn add_watchpoint(&mut self, watch: Watch) {
+        self.watchpoints.push(watch);
+    }
+
+    pub fn watchpoints(&self) -> &[Watch] {
+        &self.watchpoints
+    }
+
+    // Drop the watchpoints covering `addr`, or all of them and the watch
+    // on the banking
+    pub fn remove_watchpoints(&mut self, addr: Option<u16>) {
+        self.watchpoints.retain(|watch| addr.is_some_and(|addr| !watch.range.contains(&addr)));
+        self.banking &= addr.is_some();
+    }
+
+    pub fn watch_banking(&mut self, on: bool) {
+        self.banking = on;
+    }
+
+    pub fn banking_watched(&self) -> bool {
+        self.banking
+    }
+
+    // Called by the bus after a write that stops a banking watch
+    pub fn banking_changed(&mut self, event: BusEvent, before: Banking, after: Banking) {
+        if self.hit.is_none() {
+            self.hit = Some(event);
+            self.banking_change = Some((event, before, after));
+        }
+    }
+
+    // The banking before and after the write `event`, if it was a change
+    // the banking watch stopped at
+    pub fn banking_change(&self, event: &BusEvent) -> Option<(Banking, Banking)> {
+        self.banking_change.filter(|(at, _, _)| at == event).map(|(_, before, after)| (before, after))
+    }
+
+    pub fn remove_watchpoint(&mut self, watch: &Watch) {
+        self.watchpoints.retain(|other| other != watch);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty() && self.watchpoints.is_empty() && !self.banking
+    }
+
+    // Called by the bus for every CPU access. `changed` is whether a write
+    // changes the value there.
+    #[inline]
+    pub fn record(&mut self, access: Access, addr: u16, value: u8, changed: bool) {
+        let caught = |watches: &[Watch]| watches.iter().any(|watch| watch.kind.matches(access, changed) && watch.range.contains(&addr));
+        if caught(&self.watches) {
+            self.events.push(BusEvent { access, addr, value });
+        }
+        if self.hit.is_none() && caught(&self.watchpoints) {
+            self.hit = Some(BusEvent { access, addr, value });
+        }
+    }
+
+    // The accesses caught so far, oldest first, leaving none behind
+    pub fn take_events(&mut self) -> Vec<BusEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // The first access a watchpoint caught since the last call
+    pub fn take_hit(&mut self) -> Option<BusEvent> {
+        self.hit.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_watch() {
+        assert_eq!(Watch::parse("D020"), Ok(Watch { range: 0xD020..=0xD020, kind: WatchKind::ReadWrite }));
+        assert_eq!(Watch::parse("$D000-$D3FF:w"), Ok(Watch { range: 0xD000..=0xD3FF, kind: WatchKind::Write }));
+        assert_eq!(Watch::parse("dc00-dc0f:r"), Ok(Watch { range: 0xDC00..=0xDC0F, kind: WatchKind::Read }));
+        assert!(Watch::parse("D3FF-D000").is_err());
+        assert_eq!(Watch::parse("0314:c"), Ok(Watch { range: 0x0314..=0x0314, kind: WatchKind::Change }));
+        assert!(Watch::parse("D020:x").is_err());
+        assert!(Watch::parse("10000").is_err());
+    }
+
+    #[test]
+    fn test_record() {
+        let mut watches = Watches::new();
+        watches.add(Watch { range: 0xD020..=0xD021, kind: WatchKind::Write });
+        watches.add(Watch { range: 0xDC01..=0xDC01, kind: WatchKind::ReadWrite });
+        watches.record(Access::Write, 0xD020, 0x0E, true);
+        watches.record(Access::Read, 0xD020, 0x0E, false); // Writes only
+        watches.record(Access::Write, 0xD022, 0x01, true); // Outside
+        watches.record(Access::Read, 0xDC01, 0xFF, false);
+        assert_eq!(
+            watches.take_events(),
+            vec![
+                BusEvent { access: Access::Write, addr: 0xD020, value: 0x0E },
+                BusEvent { access: Access::Read, addr: 0xDC01, value: 0xFF },
+            ]
+        );
+        assert!(watches.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut watches = Watches::new();
+        watches.add_watchpoint(Watch::parse("0314-0315:c").unwrap());
+        watches.add_watchpoint(Watch::parse("D020").unwrap());
+        watches.record(Access::Write, 0x0314, 0x31, false); // The same value
+        assert_eq!(watches.take_hit(), None);
+        watches.record(Access::Write, 0x0315, 0xEA, true);
+        watches.record(Access::Read, 0xD020, 0xFE, false);
+        // The first access stops the machine, and isn't traced
+        let hit = watches.take_hit().unwrap();
+        assert_eq!(hit, BusEvent { access: Access::Write, addr: 0x0315, value: 0xEA });
+        assert_eq!(hit.describe(), "store $0315 = $EA");
+        assert_eq!(watches.take_hit(), None);
+        assert!(watches.take_events().is_empty());
+
+        assert_eq!(watches.watchpoints()[0].describe(), "$0314-$0315 change");
+        watches.remove_watchpoints(Some(0x0315));
+        assert_eq!(watches.watchpoints()[0].describe(), "$D020");
+        watches.remove_watchpoints(None);
+        assert!(watches.is_empty());
+    }
+}
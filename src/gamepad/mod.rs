@@ -0,0 +1,163 @@
+// Gamepads (USB and the like) as joysticks and paddles, through gilrs. This
+// needs the "gamepad" feature (and on Linux libudev); without it only the
+// keyboard works the joystick ports.
+
+#[cfg(any(feature = "gamepad", test))]
+use crate::keyboard::joystick::{DOWN, FIRE, LEFT, RIGHT, UP};
+
+#[cfg(feature = "gamepad")]
+pub use input::Gamepads;
+
+// Stick deflection that counts as a direction
+#[cfg(any(feature = "gamepad", test))]
+const STICK_THRESHOLD: f32 = 0.5;
+
+// One pad's controls as the C64 sees them
+#[cfg(any(feature = "gamepad", test))]
+#[derive(Debug, Default, Clone, Copy)]
+struct PadState {
+    dpad: [bool; 4], // Up, down, left, right
+    stick: (f32, f32), // -1.0 to 1.0, up positive
+    fire: bool,
+}
+
+#[cfg(any(feature = "gamepad", test))]
+impl PadState {
+    // Joystick switches closed: the D-pad or the left stick, and fire
+    fn switches(&self) -> u8 {
+        let (x, y) = self.stick;
+        let mut switches = 0;
+        if self.dpad[0] || y > STICK_THRESHOLD {
+            switches |= UP;
+        }
+        if self.dpad[1] || y < -STICK_THRESHOLD {
+            switches |= DOWN;
+        }
+        if self.dpad[2] || x < -STICK_THRESHOLD {
+            switches |= LEFT;
+        }
+        if self.dpad[3] || x > STICK_THRESHOLD {
+            switches |= RIGHT;
+        }
+        if self.fire {
+            switches |= FIRE;
+        }
+        switches
+    }
+
+    // The left stick's axes as a pair of paddles: POTX and POTY readings,
+    // 0 at the left or top
+    fn paddles(&self) -> (u8, u8) {
+        let pot = |value: f32| ((value.clamp(-1.0, 1.0) + 1.0) / 2.0 * 255.0).round() as u8;
+        (pot(self.stick.0), pot(-self.stick.1))
+    }
+}
+
+#[cfg(feature = "gamepad")]
+mod input {
+    use anyhow::{anyhow, Result};
+    use gilrs::{Axis, Button, EventType, GamepadId, Gilrs};
+
+    use super::PadState;
+    use crate::memory::C64Memory;
+
+    pub struct Gamepads {
+        gilrs: Gilrs,
+        // The port for each pad in the order they are plugged in, and the
+        // pad now in that place
+        ports: Vec<u8>,
+        pads: Vec<Option<GamepadId>>,
+    }
+
+    impl Gamepads {
+        pub fn open(ports: Vec<u8>) -> Result<Self> {
+            let gilrs = Gilrs::new().map_err(|e| anyhow!("{}", e))?;
+            let pads = vec![None; ports.len()];
+            let mut gamepads = Self { gilrs, ports, pads };
+            let connected: Vec<GamepadId> = gamepads.gilrs.gamepads().map(|(id, _)| id).collect();
+            for id in connected {
+                gamepads.connect(id);
+            }
+            Ok(gamepads)
+        }
+
+        // Pick up pads plugged in or pulled out, and set the joystick
+        // ports and paddles from the pads' controls
+        pub fn poll(&mut self, memory: &mut C64Memory) {
+            while let Some(event) = self.gilrs.next_event() {
+                match event.event {
+                    EventType::Connected => self.connect(event.id),
+                    EventType::Disconnected => {
+                        if let Some(slot) = self.pads.iter().position(|&pad| pad == Some(event.id)) {
+                            self.pads[slot] = None;
+                            memory.cia1.set_gamepad(self.ports[slot], 0);
+                            memory.sid.set_paddles(self.ports[slot], None);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            for (slot, id) in self.pads.iter().enumerate() {
+                let Some(pad) = id.and_then(|id| self.gilrs.connected_gamepad(id)) else { continue };
+                let state = PadState {
+                    dpad: [Button::DPadUp, Button::DPadDown, Button::DPadLeft, Button::DPadRight]
+                        .map(|button| pad.is_pressed(button)),
+                    stick: (pad.value(Axis::LeftStickX), pad.value(Axis::LeftStickY)),
+                    fire: pad.is_pressed(Button::South) || pad.is_pressed(Button::East),
+                };
+                memory.cia1.set_gamepad(self.ports[slot], state.switches());
+                memory.sid.set_paddles(self.ports[slot], Some(state.paddles()));
+            }
+        }
+
+        // Ports with a pad in them, for the status bar
+        pub fn ports(&self) -> Vec<u8> {
+            self.pads.iter().zip(&self.ports).filter(|(pad, _)| pad.is_some()).map(|(_, &port)| port).collect()
+        }
+
+        // Pads beyond the ports given are left out
+        fn connect(&mut self, id: GamepadId) {
+            if self.pads.contains(&Some(id)) {
+                return;
+            }
+            if let Some(slot) = self.pads.iter().position(|pad| pad.is_none()) {
+                self.pads[slot] = Some(id);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "gamepad"))]
+pub struct Gamepads;
+
+#[cfg(not(feature = "gamepad"))]
+impl Gamepads {
+    pub fn open(_ports: Vec<u8>) -> anyhow::Result<Self> {
+        anyhow::bail!("built without the gamepad feature")
+    }
+
+    pub fn poll(&mut self, _memory: &mut crate::memory::C64Memory) {}
+
+    pub fn ports(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pad_state() {
+        let centred = PadState::default();
+        assert_eq!(centred.switches(), 0);
+        assert_eq!(centred.paddles(), (128, 128));
+
+        let pad = PadState { dpad: [false, false, true, false], stick: (0.2, 0.9), fire: true };
+        assert_eq!(pad.switches(), UP | LEFT | FIRE);
+        let pad = PadState { stick: (-1.0, -1.0), ..Default::default() };
+        assert_eq!(pad.switches(), DOWN | LEFT);
+        assert_eq!(pad.paddles(), (0, 255));
+    }
+}
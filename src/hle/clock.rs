@@ -0,0 +1,103 @@
+// The jiffy clock (TI and TI$ in BASIC) from the host's clock. RDTIM and
+// SETTIM read and set the KERNAL's count of sixtieths of a second at
+// $A0-$A2, which its interrupt handler counts up and so only keeps time
+// while the emulator runs at full speed. With the host's clock behind them
+// they keep time in warp mode too, and when the emulator falls behind. The
+// count in memory is brought up to date whenever it is read or set, for
+// programs that look at it directly. Like the host's clock, it keeps going
+// through a reset.
+
+use std::time::Instant;
+
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+
+const JIFFIES: u16 = 0xA0;
+const JIFFIES_PER_SECOND: u64 = 60;
+// The KERNAL starts again from 0 at 24 hours
+const JIFFIES_PER_DAY: u64 = 24 * 60 * 60 * JIFFIES_PER_SECOND;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HostClock {
+    // The count when it was last set, and when that was
+    jiffies: u32,
+    set_at: Instant,
+}
+
+impl HostClock {
+    // Counting from `jiffies` now
+    pub fn new(jiffies: u32) -> Self {
+        Self { jiffies, set_at: Instant::now() }
+    }
+
+    fn jiffies_at(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.set_at).as_millis() as u64 * JIFFIES_PER_SECOND / 1000;
+        ((self.jiffies as u64 + elapsed) % JIFFIES_PER_DAY) as u32
+    }
+}
+
+// RDTIM: the count in Y (highest), X and A (lowest)
+pub fn rdtim<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let Some(clock) = cpu.clock else {
+        return false;
+    };
+    let [a, x, y, _] = clock.jiffies_at(Instant::now()).to_le_bytes();
+    (cpu.a, cpu.x, cpu.y) = (a, x, y);
+    write_jiffies(memory, [a, x, y]);
+    true
+}
+
+// SETTIM: set the count from the same registers
+pub fn settim<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let Some(clock) = &mut cpu.clock else {
+        return false;
+    };
+    let jiffies = u32::from_le_bytes([cpu.a, cpu.x, cpu.y, 0]);
+    *clock = HostClock::new(jiffies);
+    write_jiffies(memory, [cpu.a, cpu.x, cpu.y]);
+    true
+}
+
+// The count in memory is kept highest byte first
+fn write_jiffies<M: Memory + ?Sized>(memory: &mut M, [low, middle, high]: [u8; 3]) {
+    memory.write(JIFFIES, high);
+    memory.write(JIFFIES + 1, middle);
+    memory.write(JIFFIES + 2, low);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_jiffies() {
+        let clock = HostClock { jiffies: 1000, set_at: Instant::now() };
+        assert_eq!(clock.jiffies_at(clock.set_at), 1000);
+        assert_eq!(clock.jiffies_at(clock.set_at + Duration::from_millis(1500)), 1090);
+        // Round the clock, and back to the start
+        let clock = HostClock { jiffies: JIFFIES_PER_DAY as u32 - 60, ..clock };
+        assert_eq!(clock.jiffies_at(clock.set_at + Duration::from_secs(2)), 60);
+    }
+
+    #[test]
+    fn test_traps() {
+        use crate::hle::testing::{call, machine};
+        use crate::hle::{RDTIM, SETTIM};
+
+        // Left to the KERNAL without a host clock
+        let (mut cpu, mut memory) = machine();
+        assert!(!call(&mut cpu, &mut memory, RDTIM));
+
+        // TI$="123456" sets 45,296 seconds, in jiffies $2DC0C0
+        cpu.clock = Some(HostClock::new(0));
+        (cpu.a, cpu.x, cpu.y) = (0xC0, 0xC0, 0x2D);
+        assert!(call(&mut cpu, &mut memory, SETTIM));
+        (cpu.a, cpu.x, cpu.y) = (0, 0, 0);
+        assert!(call(&mut cpu, &mut memory, RDTIM));
+        // A jiffy may have gone by
+        let jiffies = u32::from_le_bytes([cpu.a, cpu.x, cpu.y, 0]);
+        assert!((0x2DC0C0..=0x2DC0C1).contains(&jiffies));
+        assert_eq!([0xA0, 0xA1].map(|addr| memory.read(addr)), [0x2D, 0xC0]);
+    }
+}
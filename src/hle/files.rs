@@ -0,0 +1,354 @@
+// Logical files: OPEN, CLOSE and the rest of the KERNAL's file routines,
+// for the devices with files behind them.
+//
+// The traps keep the KERNAL's own table of open files up to date alongside
+// theirs (logical file numbers at $0259, devices at $0263 and secondary
+// addresses at $026D, with the count at $98), so numbers stay unique across
+// the KERNAL's devices and the traps', and a program looking at the table
+// sees every file. The current input and output devices at $99 and $9A are
+// set as the KERNAL sets them.
+
+use super::{fail, filename, set_bits, succeed, KernalError, DEVICE, LOGICAL_FILE, SECONDARY_ADDRESS, STATUS};
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+
+const OPEN_FILES: u16 = 0x98;
+const INPUT_DEVICE: u16 = 0x99;
+const OUTPUT_DEVICE: u16 = 0x9A;
+const FILE_NUMBERS: u16 = 0x0259;
+const FILE_DEVICES: u16 = 0x0263;
+const FILE_SECONDARY_ADDRESSES: u16 = 0x026D;
+const MAX_FILES: u8 = 10;
+
+// The keyboard and screen, which CLRCHN goes back to
+const KEYBOARD: u8 = 0;
+const SCREEN: u8 = 3;
+
+// Status bits: in ST for the serial bus, and in the KERNAL's RS-232 status
+// at $0297 for device 2
+const STATUS_READ_TIMEOUT: u8 = 0x02;
+const STATUS_EOI: u8 = 0x40;
+const RS232_STATUS: u16 = 0x0297;
+const RS232_RECEIVE_EMPTY: u8 = 0x08;
+
+// The files opened through the traps, and those CHKIN and CHKOUT picked
+#[derive(Debug, Default)]
+pub struct Files {
+    open: Vec<File>,
+    input: Option<u8>,
+    output: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct File {
+    number: u8,
+    device: u8,
+    // The device's channel: the secondary address, or 0 without one
+    channel: u8,
+    // Opened with a secondary address, so the device hears of CLOSE
+    secondary: bool,
+}
+
+impl Files {
+    fn find(&self, number: Option<u8>) -> Option<File> {
+        self.open.iter().find(|file| Some(file.number) == number).copied()
+    }
+}
+
+// OPEN the logical file, device and secondary address set with SETLFS,
+// with the name set with SETNAM
+pub fn open<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let device = memory.read(DEVICE);
+    if memory.channels(device).is_none() {
+        return false;
+    }
+    let number = memory.read(LOGICAL_FILE);
+    let count = memory.read(OPEN_FILES).min(MAX_FILES);
+    if (0..count).any(|i| memory.read(FILE_NUMBERS + i as u16) == number) {
+        return fail(cpu, KernalError::FileOpen);
+    }
+    if count == MAX_FILES {
+        return fail(cpu, KernalError::TooManyFiles);
+    }
+
+    // The KERNAL sets bits 5 and 6 of secondary addresses, which leaves
+    // $FF, for none, as it is
+    let secondary = memory.read(SECONDARY_ADDRESS) | 0x60;
+    memory.write(SECONDARY_ADDRESS, secondary);
+    let at = count as u16;
+    memory.write(FILE_NUMBERS + at, number);
+    memory.write(FILE_DEVICES + at, device);
+    memory.write(FILE_SECONDARY_ADDRESSES + at, secondary);
+    memory.write(OPEN_FILES, count + 1);
+    memory.write(STATUS, 0);
+
+    let has_secondary = secondary & 0x80 == 0;
+    let file = File { number, device, channel: if has_secondary { secondary & 0x0F } else { 0 }, secondary: has_secondary };
+    let name = filename(memory);
+    tracing::debug!(target: "hle", "OPEN {},{},{} {:?}", number, device, file.channel, String::from_utf8_lossy(&name));
+    // As on the bus, the device only hears of a file with a secondary
+    // address and a name. Its errors are left for it to report, as a
+    // drive does on channel 15.
+    if file.secondary
+        && !name.is_empty()
+        && let Some(channels) = memory.channels(device)
+        && let Err(e) = channels.open(file.channel, &name)
+    {
+        tracing::debug!(target: "hle", "OPEN {}: {}", number, e);
+    }
+    cpu.files.open.push(file);
+    succeed(cpu)
+}
+
+// CLOSE the logical file in A
+pub fn close<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let number = cpu.a;
+    let Some(index) = cpu.files.open.iter().position(|file| file.number == number) else {
+        return false;
+    };
+    if cpu.files.output == Some(number) {
+        end_output(cpu, memory);
+    }
+    if cpu.files.input == Some(number) {
+        cpu.files.input = None;
+    }
+    let file = cpu.files.open.remove(index);
+    tracing::debug!(target: "hle", "CLOSE {}", number);
+    if file.secondary
+        && let Some(channels) = memory.channels(file.device)
+        && let Err(e) = channels.close(file.channel)
+    {
+        tracing::debug!(target: "hle", "CLOSE {}: {}", number, e);
+    }
+
+    // The last file in the KERNAL's table takes the closed one's place
+    let count = memory.read(OPEN_FILES).min(MAX_FILES);
+    if let Some(at) = (0..count as u16).find(|&at| memory.read(FILE_NUMBERS + at) == number) {
+        let last = count as u16 - 1;
+        for table in [FILE_NUMBERS, FILE_DEVICES, FILE_SECONDARY_ADDRESSES] {
+            let entry = memory.read(table + last);
+            memory.write(table + at, entry);
+        }
+        memory.write(OPEN_FILES, count - 1);
+    }
+    succeed(cpu)
+}
+
+// CHKIN: take input from the logical file in X. The status starts over,
+// so one file's end doesn't end the next read.
+pub fn chkin<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let Some(file) = cpu.files.find(Some(cpu.x)) else {
+        return false;
+    };
+    memory.write(INPUT_DEVICE, file.device);
+    memory.write(STATUS, 0);
+    cpu.files.input = Some(file.number);
+    succeed(cpu)
+}
+
+// CHKOUT: send output to the logical file in X
+pub fn chkout<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let Some(file) = cpu.files.find(Some(cpu.x)) else {
+        return false;
+    };
+    end_output(cpu, memory);
+    memory.write(OUTPUT_DEVICE, file.device);
+    cpu.files.output = Some(file.number);
+    succeed(cpu)
+}
+
+// CLRCHN: back to the keyboard and screen, leaving A 0 as the KERNAL does
+pub fn clrchn<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let input = cpu.files.input.take().is_some();
+    let output = cpu.files.output.is_some();
+    end_output(cpu, memory);
+    // Serial devices the KERNAL was using itself need its UNTALK and
+    // UNLISTEN, so it finishes the job
+    if (!input && memory.read(INPUT_DEVICE) > SCREEN) || (!output && memory.read(OUTPUT_DEVICE) > SCREEN) {
+        return false;
+    }
+    memory.write(INPUT_DEVICE, KEYBOARD);
+    memory.write(OUTPUT_DEVICE, SCREEN);
+    cpu.a = 0;
+    cpu.status.update_zero_negative(0);
+    true
+}
+
+// CLALL: forget every file, without closing them on the devices, as the
+// KERNAL does
+pub fn clall<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let trapped = clrchn(cpu, memory);
+    cpu.files.open.clear();
+    if trapped {
+        memory.write(OPEN_FILES, 0);
+    }
+    trapped
+}
+
+// CHRIN and GETIN: the next byte from the input file, in A. After the last
+// byte (EOI) or an error the status isn't 0, and the KERNAL hands out
+// carriage returns without asking the device any more. RS-232 has nothing
+// to wait for: with no byte in, it reads 0.
+pub fn chrin<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let Some(file) = cpu.files.find(cpu.files.input) else {
+        return false;
+    };
+    if memory.read(INPUT_DEVICE) != file.device {
+        return false;
+    }
+    let rs232 = file.device == crate::rs232::DEVICE;
+    cpu.a = if !rs232 && memory.read(STATUS) != 0 {
+        b'\r'
+    } else {
+        let next = match memory.channels(file.device) {
+            Some(channels) => channels.read(file.channel),
+            None => return false,
+        };
+        match next {
+            Some((byte, last)) => {
+                if last && !rs232 {
+                    set_bits(memory, STATUS, STATUS_EOI);
+                }
+                byte
+            }
+            None if rs232 => {
+                set_bits(memory, RS232_STATUS, RS232_RECEIVE_EMPTY);
+                0
+            }
+            None => {
+                set_bits(memory, STATUS, STATUS_EOI | STATUS_READ_TIMEOUT);
+                b'\r'
+            }
+        }
+    };
+    cpu.status.update_zero_negative(cpu.a);
+    succeed(cpu)
+}
+
+// CHROUT: send A to the output file
+pub fn chrout<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    let Some(file) = cpu.files.find(cpu.files.output) else {
+        return false;
+    };
+    if memory.read(OUTPUT_DEVICE) != file.device {
+        return false;
+    }
+    match memory.channels(file.device) {
+        Some(channels) => channels.write(file.channel, cpu.a),
+        None => return false,
+    }
+    succeed(cpu)
+}
+
+// Finish sending to the output file, as UNLISTEN does on the bus
+fn end_output<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) {
+    let output = cpu.files.output.take();
+    if let Some(file) = cpu.files.find(output)
+        && let Some(channels) = memory.channels(file.device)
+    {
+        channels.unlisten(file.channel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hle::testing::{call, machine, set_file};
+    use crate::hle::{CHKIN, CHKOUT, CHRIN, CHROUT, CLOSE, CLRCHN, GETIN, OPEN};
+    use crate::memory::C64Memory;
+    use crate::rs232::Swiftlink;
+
+    // SETLFS, SETNAM and OPEN
+    fn open(cpu: &mut Cpu, memory: &mut C64Memory, number: u8, device: u8, secondary: u8, name: &[u8]) -> bool {
+        set_file(memory, number, device, secondary, name);
+        call(cpu, memory, OPEN)
+    }
+
+    fn read_until_status(cpu: &mut Cpu, memory: &mut C64Memory) -> Vec<u8> {
+        let mut data = Vec::new();
+        while memory.read(STATUS) == 0 && data.len() < 100 {
+            assert!(call(cpu, memory, CHRIN));
+            data.push(cpu.a);
+        }
+        data
+    }
+
+    #[test]
+    fn test_write_and_read_file() {
+        let (mut cpu, mut memory) = machine();
+
+        assert!(open(&mut cpu, &mut memory, 2, 8, 2, b"NOTES,S,W"));
+        assert!(!cpu.status.carry);
+        assert_eq!(memory.read(OPEN_FILES), 1);
+        assert_eq!([memory.read(FILE_NUMBERS), memory.read(FILE_DEVICES), memory.read(FILE_SECONDARY_ADDRESSES)], [2, 8, 0x62]);
+        assert!(open(&mut cpu, &mut memory, 2, 8, 3, b"OTHER"));
+        assert!(cpu.status.carry);
+        assert_eq!(cpu.a, KernalError::FileOpen as u8);
+
+        cpu.x = 2;
+        assert!(call(&mut cpu, &mut memory, CHKOUT));
+        assert_eq!(memory.read(OUTPUT_DEVICE), 8);
+        for &byte in b"HELLO\r" {
+            cpu.a = byte;
+            assert!(call(&mut cpu, &mut memory, CHROUT));
+        }
+        assert!(call(&mut cpu, &mut memory, CLRCHN));
+        assert_eq!([memory.read(INPUT_DEVICE), memory.read(OUTPUT_DEVICE)], [KEYBOARD, SCREEN]);
+        cpu.a = 2;
+        assert!(call(&mut cpu, &mut memory, CLOSE));
+        assert_eq!(memory.read(OPEN_FILES), 0);
+
+        // Read back to the end, then carriage returns
+        assert!(open(&mut cpu, &mut memory, 3, 8, 3, b"NOTES,S,R"));
+        cpu.x = 3;
+        assert!(call(&mut cpu, &mut memory, CHKIN));
+        assert_eq!(read_until_status(&mut cpu, &mut memory), b"HELLO\r");
+        assert_eq!(memory.read(STATUS), STATUS_EOI);
+        assert!(call(&mut cpu, &mut memory, CHRIN));
+        assert_eq!(cpu.a, b'\r');
+
+        // The command channel, opened without a name, reads the status
+        assert!(open(&mut cpu, &mut memory, 15, 8, 15, b""));
+        cpu.x = 15;
+        assert!(call(&mut cpu, &mut memory, CHKIN));
+        assert_eq!(read_until_status(&mut cpu, &mut memory), b"00, OK,00,00\r");
+        assert_eq!(memory.read(OPEN_FILES), 2);
+        cpu.a = 3;
+        assert!(call(&mut cpu, &mut memory, CLOSE));
+        assert_eq!(memory.read(FILE_NUMBERS), 15);
+
+        // The screen, and devices with nothing on them, are the KERNAL's
+        assert!(!open(&mut cpu, &mut memory, 1, 3, 0xFF, b""));
+        assert!(!open(&mut cpu, &mut memory, 1, 9, 0xFF, b""));
+    }
+
+    #[test]
+    fn test_rs232() {
+        let (mut cpu, mut memory) = machine();
+        memory.swiftlink = Some(Swiftlink::new(985_248));
+
+        // 1200 baud, as BASIC would open it
+        assert!(open(&mut cpu, &mut memory, 2, 2, 0xFF, &[0x08, 0x00]));
+        cpu.x = 2;
+        assert!(call(&mut cpu, &mut memory, CHKOUT));
+        for &byte in b"AT\r" {
+            cpu.a = byte;
+            assert!(call(&mut cpu, &mut memory, CHROUT));
+        }
+        assert!(call(&mut cpu, &mut memory, CLRCHN));
+
+        // The modem echoes and answers, then there is nothing more
+        assert!(call(&mut cpu, &mut memory, CHKIN));
+        let mut received = Vec::new();
+        loop {
+            assert!(call(&mut cpu, &mut memory, GETIN));
+            if cpu.a == 0 {
+                break;
+            }
+            received.push(cpu.a);
+        }
+        assert!(received.starts_with(b"AT\r"));
+        assert!(cpu.status.zero);
+        assert_eq!(memory.read(RS232_STATUS) & RS232_RECEIVE_EMPTY, RS232_RECEIVE_EMPTY);
+    }
+}
@@ -0,0 +1,146 @@
+// High-level emulation of the KERNAL's file routines. The CPU traps the
+// jump table entries below, while the KERNAL ROM is banked in, and serves
+// them straight from the devices' files instead of running the KERNAL's
+// serial bus code bit by bit:
+//
+//   $FFC0 OPEN    $FFC3 CLOSE   $FFC6 CHKIN   $FFC9 CHKOUT  $FFCC CLRCHN
+//   $FFCF CHRIN   $FFD2 CHROUT  $FFD5 LOAD    $FFD8 SAVE    $FFE4 GETIN
+//   $FFE7 CLALL
+//
+// With a host clock set up, RDTIM ($FFDE) and SETTIM ($FFDB) are served from
+// it too (see clock).
+//
+// The devices plug in through the Channels trait, found by device number
+// with Memory::channels: the drives and printer on the serial bus, and
+// RS-232 (device 2) through a Swiftlink. LOAD and SAVE also reach the tape
+// in the datasette. A call for any other device, the keyboard and screen
+// among them, is left to the KERNAL, as are tape files opened with OPEN.
+//
+// Calls through the jump table skip the vectors at $031A-$0333, so a
+// program hooking those sees only the calls the traps leave alone.
+
+mod clock;
+mod files;
+mod program;
+
+pub use clock::HostClock;
+pub use files::Files;
+
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+
+const OPEN: u16 = 0xFFC0;
+const CLOSE: u16 = 0xFFC3;
+const CHKIN: u16 = 0xFFC6;
+const CHKOUT: u16 = 0xFFC9;
+const CLRCHN: u16 = 0xFFCC;
+const CHRIN: u16 = 0xFFCF;
+const CHROUT: u16 = 0xFFD2;
+const LOAD: u16 = 0xFFD5;
+const SAVE: u16 = 0xFFD8;
+const SETTIM: u16 = 0xFFDB;
+const RDTIM: u16 = 0xFFDE;
+const GETIN: u16 = 0xFFE4;
+const CLALL: u16 = 0xFFE7;
+
+// The KERNAL's variables for the call being made
+const STATUS: u16 = 0x90;
+const FILENAME_LENGTH: u16 = 0xB7;
+const LOGICAL_FILE: u16 = 0xB8;
+const SECONDARY_ADDRESS: u16 = 0xB9;
+const DEVICE: u16 = 0xBA;
+const FILENAME: u16 = 0xBB;
+
+// Errors the KERNAL returns in A with carry set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KernalError {
+    TooManyFiles = 1,
+    FileOpen = 2,
+    FileNotFound = 4,
+    DeviceNotPresent = 5,
+    NotOutputFile = 7,
+    MissingFileName = 8,
+}
+
+// Serve the KERNAL routine at the PC, if it is one of the above and for a
+// device there are files for. True when it was served and the CPU should
+// return to the caller.
+pub fn trap<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    match cpu.pc {
+        OPEN => files::open(cpu, memory),
+        CLOSE => files::close(cpu, memory),
+        CHKIN => files::chkin(cpu, memory),
+        CHKOUT => files::chkout(cpu, memory),
+        CLRCHN => files::clrchn(cpu, memory),
+        CHRIN | GETIN => files::chrin(cpu, memory),
+        CHROUT => files::chrout(cpu, memory),
+        CLALL => files::clall(cpu, memory),
+        LOAD => program::load(cpu, memory),
+        SAVE => program::save(cpu, memory),
+        SETTIM => clock::settim(cpu, memory),
+        RDTIM => clock::rdtim(cpu, memory),
+        _ => false,
+    }
+}
+
+fn succeed(cpu: &mut Cpu) -> bool {
+    cpu.status.carry = false;
+    true
+}
+
+fn fail(cpu: &mut Cpu, error: KernalError) -> bool {
+    cpu.status.carry = true;
+    cpu.a = error as u8;
+    true
+}
+
+// The name set with SETNAM
+fn filename<M: Memory + ?Sized>(memory: &mut M) -> Vec<u8> {
+    let length = memory.read(FILENAME_LENGTH) as u16;
+    let start = u16::from_le_bytes([memory.read(FILENAME), memory.read(FILENAME + 1)]);
+    (0..length).map(|i| memory.read(start.wrapping_add(i))).collect()
+}
+
+fn set_bits<M: Memory + ?Sized>(memory: &mut M, addr: u16, bits: u8) {
+    let value = memory.read(addr);
+    memory.write(addr, value | bits);
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::iec::device::SerialDevice;
+    use crate::memory::C64Memory;
+    use crate::storage::d64::D64;
+    use crate::storage::drive::{Drive, Medium};
+
+    // A KERNAL of NOPs and a drive 8 with a blank disk
+    pub fn machine() -> (Cpu, C64Memory) {
+        let mut memory = C64Memory::new();
+        memory.load_kernal_rom(vec![0xEA; 0x2000]);
+        memory.iec.attach(Box::new(SerialDevice::new(8, Drive::new(Medium::Image(D64::format(b"TEST", b"01"))))));
+        (Cpu::new(), memory)
+    }
+
+    // JSR to a KERNAL routine; true when a trap served it and returned
+    pub fn call(cpu: &mut Cpu, memory: &mut C64Memory, routine: u16) -> bool {
+        cpu.pc = routine;
+        cpu.sp = 0xFD;
+        cpu.push_word(memory, 0x1233);
+        cpu.step(memory).unwrap();
+        cpu.pc == 0x1234
+    }
+
+    // SETLFS and SETNAM, with the name at $0340
+    pub fn set_file(memory: &mut C64Memory, number: u8, device: u8, secondary: u8, name: &[u8]) {
+        memory.write(LOGICAL_FILE, number);
+        memory.write(DEVICE, device);
+        memory.write(SECONDARY_ADDRESS, secondary);
+        for (i, &byte) in name.iter().enumerate() {
+            memory.write(0x0340 + i as u16, byte);
+        }
+        memory.write(FILENAME_LENGTH, name.len() as u8);
+        memory.write(FILENAME, 0x40);
+        memory.write(FILENAME + 1, 0x03);
+    }
+}
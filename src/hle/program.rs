@@ -0,0 +1,269 @@
+// LOAD and SAVE of whole programs, through a drive's LOAD and SAVE channels
+// (0 and 1) without going over the bus, or from and to the tape in the
+// datasette
+
+use super::{fail, filename, set_bits, succeed, KernalError, DEVICE, SECONDARY_ADDRESS, STATUS};
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+use crate::storage::DosError;
+
+// The status bit VERIFY sets when memory differs from the file, which
+// BASIC's VERIFY reports as ?VERIFY ERROR
+const STATUS_VERIFY_ERROR: u8 = 0x10;
+
+const TAPE: u8 = 1;
+
+// Handle KERNAL LOAD ($FFD5)
+pub fn load<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    // Arguments:
+    // A = 0: Load, 1: Verify
+    // X/Y = Load Address (if secondary address = 0)
+
+    // Zero Page:
+    // $BA: Device Number
+    // $BB/$BC: Pointer to Filename
+    // $B7: Filename Length
+    // $90: Status Word (ST)
+
+    let device = memory.read(DEVICE);
+    let verify = cpu.a != 0;
+    let operation = if verify { "VERIFY" } else { "LOAD" };
+
+    // The tape, or a disk drive (devices 8-15) when one is attached. The
+    // drives read the file (or directory for "$") through their LOAD
+    // channel; every program on them goes where LOAD is told. Only the
+    // tape can do without a name.
+    let filename = filename(memory);
+    let loaded = match device {
+        TAPE => match memory.tape() {
+            Some(tape) => tape.find(&filename).map(|file| (file.start, file.data, file.relocatable)).ok_or(DosError::FileNotFound),
+            None => return fail(cpu, KernalError::DeviceNotPresent),
+        },
+        8..=15 => match memory.channels(device) {
+            Some(_) if filename.is_empty() => return fail(cpu, KernalError::MissingFileName),
+            Some(drive) => crate::storage::drive::load_file(drive, &filename).map(|(start, data)| (start, data, true)),
+            None => return false,
+        },
+        _ => return false,
+    };
+    memory.write(STATUS, 0);
+    let (start_addr, data, relocatable) = match loaded {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::warn!(target: "storage", "{} {:?}: {}", operation, String::from_utf8_lossy(&filename), e);
+            return fail(cpu, KernalError::FileNotFound);
+        }
+    };
+
+    // Check Secondary Address ($B9)
+    // 0 = Load to address in X/Y
+    // 1 = Load to address in file header, as tape programs pinned to their
+    //     address always do
+    let load_addr = if relocatable && memory.read(SECONDARY_ADDRESS) == 0 {
+        u16::from_le_bytes([cpu.x, cpu.y])
+    } else {
+        start_addr
+    };
+    let end_addr = load_addr.wrapping_add(data.len() as u16);
+    let [lo, hi] = end_addr.to_le_bytes();
+
+    if verify {
+        // Compare every byte with memory, as the KERNAL goes on to the end
+        // of the file after a difference
+        let mut differences = 0;
+        for (i, byte) in data.iter().enumerate() {
+            if memory.read(load_addr.wrapping_add(i as u16)) != *byte {
+                differences += 1;
+            }
+        }
+        if differences > 0 {
+            set_bits(memory, STATUS, STATUS_VERIFY_ERROR);
+        }
+        tracing::info!(target: "storage", "VERIFY {:?} at ${:04X}-${:04X}: {} bytes differ", String::from_utf8_lossy(&filename), load_addr, end_addr, differences);
+    } else {
+        // Write data to memory
+        for (i, byte) in data.iter().enumerate() {
+            memory.write(load_addr.wrapping_add(i as u16), *byte);
+        }
+        tracing::info!(target: "storage", "LOAD {:?} to ${:04X}-${:04X}", String::from_utf8_lossy(&filename), load_addr, end_addr);
+
+        // $2D/$2E = End of Basic Variables (for BASIC LOAD), and $2F-$32,
+        // the start and end of arrays, set to the end of the program too
+        for addr in [0x2D, 0x2F, 0x31] {
+            memory.write(addr, lo);
+            memory.write(addr + 1, hi);
+        }
+    }
+
+    // $AE/$AF = End Address
+    memory.write(0xAE, lo);
+    memory.write(0xAF, hi);
+    cpu.x = lo;
+    cpu.y = hi;
+    succeed(cpu)
+}
+
+// Handle KERNAL SAVE ($FFD8)
+pub fn save<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> bool {
+    // Arguments:
+    // A = Zero Page pointer to Start Address
+    // X/Y = End Address, one past the last byte saved
+
+    // Zero Page:
+    // $BA: Device Number
+    // $B9: Secondary Address (odd to pin a program on tape to its address)
+    // $BB/$BC: Pointer to Filename
+    // $B7: Filename Length
+
+    let device = memory.read(DEVICE);
+
+    // The tape, or a disk drive (devices 8-15) when one is attached
+    match device {
+        TAPE if memory.tape().is_none() => return fail(cpu, KernalError::DeviceNotPresent),
+        TAPE => {}
+        8..=15 if memory.channels(device).is_some() => {}
+        _ => return false,
+    }
+    let filename = filename(memory);
+    if filename.is_empty() && device != TAPE {
+        return fail(cpu, KernalError::MissingFileName);
+    }
+
+    // Start Address, indirect from the zero page address in A
+    let start_ptr = cpu.a as u16;
+    let start_addr = u16::from_le_bytes([memory.read(start_ptr), memory.read(start_ptr + 1)]);
+    let end_addr = u16::from_le_bytes([cpu.x, cpu.y]);
+    // Nothing to write out
+    if end_addr <= start_addr {
+        return fail(cpu, KernalError::NotOutputFile);
+    }
+    let data: Vec<u8> = (start_addr..end_addr).map(|addr| memory.read(addr)).collect();
+
+    if device == TAPE {
+        let relocatable = memory.read(SECONDARY_ADDRESS) & 1 == 0;
+        let file = crate::tape::File { name: filename.clone(), start: start_addr, data, relocatable };
+        let Some(tape) = memory.tape() else {
+            return false;
+        };
+        return match tape.save(file) {
+            Ok(()) => {
+                tracing::info!(target: "tape", "SAVE {:?} from ${:04X}-${:04X}", String::from_utf8_lossy(&filename), start_addr, end_addr);
+                succeed(cpu)
+            }
+            Err(e) => {
+                tracing::warn!(target: "tape", "SAVE {:?}: {:#}", String::from_utf8_lossy(&filename), e);
+                fail(cpu, KernalError::DeviceNotPresent)
+            }
+        };
+    }
+
+    let saved = match memory.channels(device) {
+        Some(drive) => crate::storage::drive::save_file(drive, &filename, start_addr, &data),
+        None => return false,
+    };
+    match saved {
+        Ok(_) => {
+            tracing::info!(target: "storage", "SAVE {:?} from ${:04X}-${:04X}", String::from_utf8_lossy(&filename), start_addr, end_addr);
+            succeed(cpu)
+        }
+        Err(e) => {
+            tracing::warn!(target: "storage", "SAVE {:?}: {}", String::from_utf8_lossy(&filename), e);
+            // The KERNAL has no code for the drive's errors; the drive
+            // keeps the real one for channel 15
+            cpu.status.carry = true;
+            cpu.a = 26;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hle::testing::{call, machine, set_file};
+    use crate::hle::{LOAD, SAVE};
+
+    #[test]
+    fn test_verify() {
+        let (mut cpu, mut memory) = machine();
+        for (i, addr) in (0x0801..0x0811).enumerate() {
+            memory.write(addr, i as u8);
+        }
+        set_file(&mut memory, 0, 8, 0, b"PROG");
+        memory.write(0xC1, 0x01);
+        memory.write(0xC2, 0x08);
+        cpu.a = 0xC1;
+        (cpu.x, cpu.y) = (0x11, 0x08);
+        assert!(call(&mut cpu, &mut memory, SAVE));
+        assert!(!cpu.status.carry);
+
+        let verify = |cpu: &mut Cpu, memory: &mut crate::memory::C64Memory| {
+            set_file(memory, 0, 8, 1, b"PROG");
+            cpu.a = 1;
+            assert!(call(cpu, memory, LOAD));
+            memory.read(STATUS)
+        };
+        memory.write(0x2D, 0x34);
+        assert_eq!(verify(&mut cpu, &mut memory), 0);
+        assert_eq!((cpu.x, cpu.y), (0x11, 0x08));
+        memory.write(0x0805, 0xFF);
+        assert_eq!(verify(&mut cpu, &mut memory), STATUS_VERIFY_ERROR);
+        assert!(!cpu.status.carry);
+        // Memory and BASIC's pointers are left alone
+        assert_eq!(memory.read(0x0805), 0xFF);
+        assert_eq!(memory.read(0x2D), 0x34);
+
+        set_file(&mut memory, 0, 8, 1, b"MISSING");
+        cpu.a = 1;
+        assert!(call(&mut cpu, &mut memory, LOAD));
+        assert!(cpu.status.carry);
+        assert_eq!(cpu.a, KernalError::FileNotFound as u8);
+
+        // Drives need a name, and SAVE something to save
+        set_file(&mut memory, 0, 8, 1, b"");
+        assert!(call(&mut cpu, &mut memory, LOAD));
+        assert_eq!((cpu.status.carry, cpu.a), (true, KernalError::MissingFileName as u8));
+        set_file(&mut memory, 0, 8, 0, b"EMPTY");
+        cpu.a = 0xC1;
+        (cpu.x, cpu.y) = (0x01, 0x08);
+        assert!(call(&mut cpu, &mut memory, SAVE));
+        assert_eq!((cpu.status.carry, cpu.a), (true, KernalError::NotOutputFile as u8));
+    }
+
+    #[test]
+    fn test_tape() {
+        let (mut cpu, mut memory) = machine();
+        let path = std::env::temp_dir().join(format!("go64-hle-tape-{}.tap", std::process::id()));
+        let mut image = crate::tape::tap::SIGNATURE.to_vec();
+        image.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        std::fs::write(&path, image).unwrap();
+        memory.tape = Some(crate::tape::Tape::load(&path).unwrap());
+
+        // SAVE "CODE",1,1 pins the program to $C000
+        memory.write(0xC000, 0x60);
+        memory.write(0xC1, 0x00);
+        memory.write(0xC2, 0xC0);
+        set_file(&mut memory, 0, 1, 1, b"CODE");
+        cpu.a = 0xC1;
+        (cpu.x, cpu.y) = (0x01, 0xC0);
+        assert!(call(&mut cpu, &mut memory, SAVE));
+        assert!(!cpu.status.carry);
+
+        // Put back from the image, LOAD "CO",1 finds it and loads it there
+        memory.tape = Some(crate::tape::Tape::load(&path).unwrap());
+        memory.write(0xC000, 0);
+        set_file(&mut memory, 0, 1, 0, b"CO");
+        cpu.a = 0;
+        (cpu.x, cpu.y) = (0x01, 0x08);
+        assert!(call(&mut cpu, &mut memory, LOAD));
+        assert!(!cpu.status.carry);
+        assert_eq!(memory.read(0xC000), 0x60);
+        assert_eq!((cpu.x, cpu.y), (0x01, 0xC0));
+        std::fs::remove_file(&path).unwrap();
+
+        // Without a tape, the datasette isn't there
+        memory.tape = None;
+        assert!(call(&mut cpu, &mut memory, LOAD));
+        assert_eq!(cpu.a, KernalError::DeviceNotPresent as u8);
+    }
+}
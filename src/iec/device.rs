@@ -0,0 +1,524 @@
+// A device on the serial bus, speaking the Commodore byte protocol over the
+// bare ATN, CLK and DATA lines, so the KERNAL's own serial routines (LISTEN,
+// TALK, CIOUT, ACPTR and the rest) reach it as they would a real drive.
+//
+// Each byte goes from talker to listener in three steps: the talker releases
+// CLK when it has a byte, the listener releases DATA when it can take it,
+// then eight bits follow, least significant first, each put on DATA while
+// CLK is low and valid while it is released. The listener acknowledges the
+// byte by pulling DATA. A talker that waits more than 200us before the bits
+// marks the last byte (EOI), and the listener acknowledges that by pulsing
+// DATA.
+//
+// Bytes sent while the C64 holds ATN are commands: LISTEN or TALK with a
+// device number, then a secondary address that opens (OPEN), closes (CLOSE)
+// or picks (DATA) one of the device's 16 channels. The name of a file being
+// opened follows as data. After TALK the roles turn around and the device
+// sends.
+
+use super::{IecDevice, IecLines};
+use crate::storage::drive::Medium;
+use crate::storage::{DosError, Status};
+
+// Half a bit when talking: the time DATA is set up with CLK low, and again
+// the time it stays valid with CLK released. Long enough for the KERNAL's
+// polling loop to see both even when the VIC-II stalls it for a bad line.
+const BIT_TIME: u32 = 70;
+
+// The delay before the next byte, and after turning around to talk
+const BYTE_DELAY: u32 = 100;
+
+// How long a talker holding back the bits marks EOI, and how long the
+// listener pulls DATA to acknowledge it
+const EOI_TIMEOUT: u32 = 200;
+const EOI_ACK_TIME: u32 = 80;
+
+// The files behind a device's channels. The serial protocol only moves
+// bytes; what they mean is up to this.
+pub trait Channels {
+    fn open(&mut self, channel: u8, name: &[u8]) -> Result<(), DosError>;
+    fn close(&mut self, channel: u8) -> Result<(), DosError>;
+    // The next byte to send on a channel, and whether it is the last
+    fn read(&mut self, channel: u8) -> Option<(u8, bool)>;
+    fn write(&mut self, channel: u8, byte: u8);
+    // The C64 has finished sending to a channel, at UNLISTEN
+    fn unlisten(&mut self, _channel: u8) {}
+    // What the command channel would read now
+    fn status(&self) -> Option<Status> {
+        None
+    }
+    // The write protect tab of a disk: with it on, writes fail
+    fn write_protected(&self) -> bool {
+        false
+    }
+    fn set_write_protected(&mut self, _on: bool) {}
+    // The disk or folder in a drive, which snapshots record, and putting
+    // another in its place
+    fn medium(&self) -> Option<&Medium> {
+        None
+    }
+    fn insert(&mut self, _medium: Medium) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    None,
+    Listener,
+    Talker,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Not addressed: everything released until the next ATN
+    Idle,
+    // Listening, holding DATA until the talker releases CLK
+    ListenBusy,
+    // DATA released; the talker starts the bits or, holding back, marks EOI
+    ListenReady { waited: u32, eoi: bool },
+    ListenEoiAck { left: u32 },
+    ListenBits { count: u8, byte: u8, clk_low: bool },
+    // Talking: waiting for the C64 to release CLK after ATN
+    Turnaround,
+    TalkDelay { left: u32 },
+    // CLK released, waiting for the listener to release DATA
+    TalkReady,
+    // Waiting for the listener's EOI acknowledgement pulse
+    TalkEoi { seen: bool },
+    TalkBits { count: u8, left: u32, valid: bool },
+    TalkAck,
+    // Nothing to send: the C64 times out reading
+    TalkEmpty,
+}
+
+pub struct SerialDevice<C> {
+    number: u8,
+    channels: C,
+    state: State,
+    pulls: IecLines,
+    atn: bool,
+    role: Role,
+    // Set by LISTEN or TALK to this device, until its secondary address
+    addressed: bool,
+    channel: u8,
+    // The name of the file being opened, while the channel is opening
+    opening: Option<Vec<u8>>,
+    // Byte being sent, kept until acknowledged so ATN doesn't lose it
+    sending: Option<(u8, bool)>,
+}
+
+impl<C: Channels> SerialDevice<C> {
+    pub fn new(number: u8, channels: C) -> Self {
+        Self {
+            number,
+            channels,
+            state: State::Idle,
+            pulls: IecLines::default(),
+            atn: false,
+            role: Role::None,
+            addressed: false,
+            channel: 0,
+            opening: None,
+            sending: None,
+        }
+    }
+
+    fn command(&mut self, byte: u8) {
+        tracing::trace!(target: "iec", "device {} command ${:02X}", self.number, byte);
+        let device = byte & 0x1F;
+        match byte {
+            0x3F if self.role == Role::Listener => self.unlisten(),
+            0x20..=0x3E => {
+                if self.role == Role::Listener {
+                    self.unlisten();
+                }
+                if device == self.number {
+                    self.role = Role::Listener;
+                    self.addressed = true;
+                    self.channel = 0;
+                }
+            }
+            0x5F if self.role == Role::Talker => self.role = Role::None,
+            0x40..=0x5E => {
+                self.role = if device == self.number { Role::Talker } else { Role::None };
+                self.addressed = device == self.number;
+                self.channel = 0;
+            }
+            _ if !self.addressed => {}
+            0x60..=0x7F => {
+                self.channel = byte & 0x0F;
+                self.addressed = false;
+            }
+            0xE0..=0xEF => {
+                self.channel = byte & 0x0F;
+                self.addressed = false;
+                if let Err(error) = self.channels.close(self.channel) {
+                    tracing::warn!(target: "iec", "device {} CLOSE {}: {}", self.number, self.channel, error);
+                }
+            }
+            0xF0..=0xFF => {
+                self.channel = byte & 0x0F;
+                self.addressed = false;
+                self.opening = Some(Vec::new());
+            }
+            _ => {}
+        }
+    }
+
+    // The end of a LISTEN: a name sent after OPEN, or the data sent to a
+    // channel, is complete
+    fn unlisten(&mut self) {
+        self.role = Role::None;
+        if let Some(name) = self.opening.take() {
+            tracing::debug!(target: "iec", "device {} OPEN {} {:?}", self.number, self.channel, String::from_utf8_lossy(&name));
+            if let Err(error) = self.channels.open(self.channel, &name) {
+                tracing::warn!(target: "iec", "device {} OPEN {}: {}", self.number, self.channel, error);
+            }
+        } else {
+            self.channels.unlisten(self.channel);
+        }
+    }
+
+    fn received(&mut self, byte: u8) {
+        if self.atn {
+            self.command(byte);
+        } else if let Some(name) = &mut self.opening {
+            name.push(byte);
+        } else {
+            self.channels.write(self.channel, byte);
+        }
+    }
+
+    fn listen(&mut self, bus: IecLines, cycles: u32) {
+        match self.state {
+            State::ListenBusy if !bus.clk => {
+                self.pulls.data = false;
+                self.state = State::ListenReady { waited: 0, eoi: false };
+            }
+            State::ListenReady { waited, eoi } => {
+                if bus.clk {
+                    self.state = State::ListenBits { count: 0, byte: 0, clk_low: true };
+                } else if !eoi && waited + cycles >= EOI_TIMEOUT {
+                    self.pulls.data = true;
+                    self.state = State::ListenEoiAck { left: EOI_ACK_TIME };
+                } else {
+                    self.state = State::ListenReady { waited: waited + cycles, eoi };
+                }
+            }
+            State::ListenEoiAck { left } => {
+                if left <= cycles {
+                    // Released, the talker starts the bits in its own time
+                    self.pulls.data = false;
+                    self.state = State::ListenReady { waited: 0, eoi: true };
+                } else {
+                    self.state = State::ListenEoiAck { left: left - cycles };
+                }
+            }
+            State::ListenBits { count, byte, clk_low } => {
+                if count == 8 {
+                    // The talker pulls CLK after the last bit, and the byte
+                    // is acknowledged
+                    if bus.clk {
+                        self.pulls.data = true;
+                        self.state = State::ListenBusy;
+                        self.received(byte);
+                    }
+                } else if clk_low && !bus.clk {
+                    let bit = if bus.data { 0 } else { 0x80 };
+                    self.state = State::ListenBits { count: count + 1, byte: (byte >> 1) | bit, clk_low: false };
+                } else {
+                    self.state = State::ListenBits { count, byte, clk_low: bus.clk };
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn talk(&mut self, bus: IecLines, cycles: u32) {
+        match self.state {
+            State::Turnaround if !bus.clk => {
+                self.pulls = IecLines { clk: true, ..Default::default() };
+                self.state = State::TalkDelay { left: BYTE_DELAY };
+            }
+            State::TalkDelay { left } => {
+                if left > cycles {
+                    self.state = State::TalkDelay { left: left - cycles };
+                    return;
+                }
+                if self.sending.is_none() {
+                    self.sending = self.channels.read(self.channel);
+                }
+                self.pulls.clk = false;
+                self.state = if self.sending.is_some() { State::TalkReady } else { State::TalkEmpty };
+            }
+            State::TalkReady if !bus.data => {
+                let (_, last) = self.sending.unwrap();
+                self.state = if last { State::TalkEoi { seen: false } } else { self.start_bits() };
+            }
+            State::TalkEoi { seen } => {
+                if bus.data {
+                    self.state = State::TalkEoi { seen: true };
+                } else if seen {
+                    self.state = self.start_bits();
+                }
+            }
+            State::TalkBits { count, left, valid } => {
+                if left > cycles {
+                    self.state = State::TalkBits { count, left: left - cycles, valid };
+                } else if !valid {
+                    self.pulls.clk = false;
+                    self.state = State::TalkBits { count, left: BIT_TIME, valid: true };
+                } else if count < 7 {
+                    self.state = self.set_up_bit(count + 1);
+                } else {
+                    // All eight sent: hold CLK and wait for the acknowledgement
+                    self.pulls = IecLines { clk: true, ..Default::default() };
+                    self.state = State::TalkAck;
+                }
+            }
+            State::TalkAck if bus.data => {
+                self.sending = None;
+                self.state = State::TalkDelay { left: BYTE_DELAY };
+            }
+            _ => {}
+        }
+    }
+
+    fn start_bits(&mut self) -> State {
+        self.set_up_bit(0)
+    }
+
+    // Pull CLK and put a bit on DATA: released for 1, pulled for 0
+    fn set_up_bit(&mut self, count: u8) -> State {
+        let (byte, _) = self.sending.unwrap();
+        self.pulls = IecLines { clk: true, data: byte & (1 << count) == 0, ..Default::default() };
+        State::TalkBits { count, left: BIT_TIME, valid: false }
+    }
+}
+
+impl<C: Channels> IecDevice for SerialDevice<C> {
+    fn tick(&mut self, cycles: u8, bus: IecLines) {
+        // ATN pulled: whatever was going on, listen for a command. Every
+        // device answers by pulling DATA, which tells the C64 one is there.
+        if bus.atn != self.atn {
+            self.atn = bus.atn;
+            if bus.atn {
+                self.pulls = IecLines { data: true, ..Default::default() };
+                self.state = State::ListenBusy;
+                return;
+            }
+            // ATN released: carry on as the commands said
+            self.state = match self.role {
+                Role::Listener => State::ListenBusy,
+                Role::Talker => {
+                    self.pulls.data = false;
+                    State::Turnaround
+                }
+                Role::None => {
+                    self.pulls = IecLines::default();
+                    State::Idle
+                }
+            };
+            return;
+        }
+        let cycles = cycles as u32;
+        match self.state {
+            State::Idle | State::TalkEmpty => {}
+            State::ListenBusy | State::ListenReady { .. } | State::ListenEoiAck { .. } | State::ListenBits { .. } => {
+                self.listen(bus, cycles)
+            }
+            _ => self.talk(bus, cycles),
+        }
+    }
+
+    fn pulls(&self) -> IecLines {
+        self.pulls
+    }
+
+    fn channels(&mut self, device: u8) -> Option<&mut dyn Channels> {
+        if device == self.number { Some(&mut self.channels) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iec::IecBus;
+    use crate::storage::d64::D64;
+    use crate::storage::drive::{Drive, Medium};
+
+    // The C64's end of the bus, doing what the KERNAL's serial routines do
+    // with the same handshakes and timeouts
+    struct Kernal {
+        bus: IecBus,
+        pulls: IecLines,
+    }
+
+    impl Kernal {
+        fn new() -> Self {
+            let mut disk = D64::format(b"TEST", b"01");
+            disk.write_prg(b"HELLO", false, 0x0801, &(0..300).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+            let mut bus = IecBus::new();
+            bus.attach(Box::new(SerialDevice::new(8, Drive::new(Medium::Image(disk)))));
+            Self { bus, pulls: IecLines::default() }
+        }
+
+        fn set(&mut self, atn: bool, clk: bool, data: bool) {
+            self.pulls = IecLines { atn, clk, data, srq: false };
+            self.bus.set_c64_port((atn as u8) << 3 | (clk as u8) << 4 | (data as u8) << 5);
+        }
+
+        fn run(&mut self, cycles: u32) {
+            for _ in 0..cycles.div_ceil(4) {
+                self.bus.tick(4);
+            }
+        }
+
+        // Poll for a condition every few cycles, for at most `timeout`
+        fn wait(&mut self, timeout: u32, condition: impl Fn(IecLines) -> bool) -> bool {
+            for _ in 0..timeout.div_ceil(4) {
+                if condition(self.bus.lines()) {
+                    return true;
+                }
+                self.bus.tick(4);
+            }
+            condition(self.bus.lines())
+        }
+
+        // ISOUR
+        fn send(&mut self, byte: u8, eoi: bool) {
+            let atn = self.pulls.atn;
+            assert!(self.bus.lines().data, "device not present");
+            self.set(atn, false, false);
+            assert!(self.wait(10000, |lines| !lines.data), "listener not ready");
+            if eoi {
+                assert!(self.wait(1000, |lines| lines.data), "no EOI acknowledgement");
+                assert!(self.wait(1000, |lines| !lines.data));
+            }
+            for bit in 0..8 {
+                self.set(atn, true, byte & (1 << bit) == 0);
+                self.run(20);
+                self.set(atn, false, byte & (1 << bit) == 0);
+                self.run(20);
+            }
+            self.set(atn, true, false);
+            assert!(self.wait(1000, |lines| lines.data), "byte not acknowledged");
+        }
+
+        // ACPTR: the byte and whether it came with EOI, or None on a timeout
+        fn receive(&mut self) -> Option<(u8, bool)> {
+            assert!(self.wait(10000, |lines| !lines.clk), "talker not ready");
+            self.set(false, false, false);
+            let mut eoi = false;
+            if !self.wait(256, |lines| lines.clk) {
+                eoi = true;
+                self.set(false, false, true);
+                self.run(40);
+                self.set(false, false, false);
+                if !self.wait(256, |lines| lines.clk) {
+                    return None;
+                }
+            }
+            let mut byte = 0;
+            for _ in 0..8 {
+                assert!(self.wait(1000, |lines| !lines.clk));
+                byte = (byte >> 1) | if self.bus.lines().data { 0 } else { 0x80 };
+                assert!(self.wait(1000, |lines| lines.clk));
+            }
+            // Acknowledged, and the rest of ACPTR runs
+            self.set(false, false, true);
+            self.run(20);
+            Some((byte, eoi))
+        }
+
+        fn command(&mut self, bytes: &[u8]) {
+            self.set(true, true, false);
+            self.run(1000);
+            for &byte in bytes {
+                self.send(byte, false);
+            }
+        }
+
+        fn listen(&mut self, secondary: u8, data: &[u8]) {
+            self.command(&[0x28, secondary]);
+            self.set(false, true, false);
+            for (i, &byte) in data.iter().enumerate() {
+                self.send(byte, i == data.len() - 1);
+            }
+            self.command(&[0x3F]);
+            self.set(false, false, false);
+            self.run(100);
+        }
+
+        fn talk(&mut self, secondary: u8) -> Option<Vec<u8>> {
+            self.command(&[0x48, secondary]);
+            // Turn around: the C64 becomes the listener
+            self.set(false, false, true);
+            assert!(self.wait(1000, |lines| lines.clk), "no turnaround");
+            let mut data = Vec::new();
+            let complete = loop {
+                match self.receive() {
+                    Some((byte, eoi)) => {
+                        data.push(byte);
+                        if eoi {
+                            break true;
+                        }
+                    }
+                    None => break false,
+                }
+            };
+            self.command(&[0x5F]);
+            self.set(false, false, false);
+            self.run(100);
+            complete.then_some(data)
+        }
+    }
+
+    #[test]
+    fn test_load_over_the_bus() {
+        let mut kernal = Kernal::new();
+        kernal.listen(0xF0, b"HELLO");
+        let data = kernal.talk(0x60).unwrap();
+        assert_eq!(data.len(), 302);
+        assert_eq!(&data[..4], &[0x01, 0x08, 0, 1]);
+        assert_eq!(data[301], 299u16 as u8);
+        kernal.listen(0xE0, &[]);
+
+        // A file that isn't there times out, which LOAD reports as not found
+        kernal.listen(0xF0, b"MISSING");
+        assert_eq!(kernal.talk(0x60), None);
+        kernal.listen(0xE0, &[]);
+    }
+
+    #[test]
+    fn test_save_over_the_bus() {
+        let mut kernal = Kernal::new();
+        kernal.listen(0xF1, b"NEW");
+        kernal.listen(0x61, &[0x00, 0xC0, 0xA9, 0x00, 0x60]);
+        kernal.listen(0xE1, &[]);
+
+        let drive = kernal.bus.channels(8).unwrap();
+        assert_eq!(crate::storage::drive::load_file(drive, b"NEW"), Ok((0xC000, vec![0xA9, 0x00, 0x60])));
+        assert!(kernal.bus.channels(9).is_none());
+    }
+
+    #[test]
+    fn test_command_channel_over_the_bus() {
+        // PRINT#15,"S0:HELLO" runs at UNLISTEN, and INPUT#15 reads the status
+        let mut kernal = Kernal::new();
+        kernal.listen(0x6F, b"S0:HELLO\r");
+        assert_eq!(kernal.talk(0x6F).unwrap(), b"01, FILES SCRATCHED,01,00\r");
+        assert_eq!(kernal.talk(0x6F).unwrap(), b"00, OK,00,00\r");
+    }
+
+    #[test]
+    fn test_other_device_ignored() {
+        // Device 8 answers ATN but stays quiet when device 9 is addressed
+        let mut kernal = Kernal::new();
+        kernal.command(&[0x49, 0x60]);
+        kernal.set(false, false, true);
+        assert!(!kernal.wait(2000, |lines| lines.clk));
+        kernal.set(false, false, false);
+        kernal.run(100);
+        assert_eq!(kernal.bus.lines(), IecLines::default());
+    }
+}
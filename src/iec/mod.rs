@@ -0,0 +1,160 @@
+// Commodore serial bus (IEC): the ATN, CLK and DATA lines the C64 shares
+// with disk drives and printers. Every line is open collector, so it is
+// high (released) unless something on the bus pulls it low.
+//
+// The C64 drives the bus from CIA2 port A through inverters (PA3 ATN, PA4
+// CLK, PA5 DATA: writing 1 pulls the line low) and reads CLK and DATA back
+// on PA6 and PA7. SRQ, which only devices pull, goes to CIA1's FLAG input.
+
+pub mod device;
+
+use serde::{Deserialize, Serialize};
+
+use device::Channels;
+
+// Lines pulled low, either by one party or by the bus as a whole
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IecLines {
+    pub atn: bool,
+    pub clk: bool,
+    pub data: bool,
+    pub srq: bool,
+}
+
+impl IecLines {
+    fn or(self, other: IecLines) -> IecLines {
+        IecLines {
+            atn: self.atn || other.atn,
+            clk: self.clk || other.clk,
+            data: self.data || other.data,
+            srq: self.srq || other.srq,
+        }
+    }
+}
+
+// A peripheral on the bus. It is clocked with the CPU, sees the lines as
+// they stand, and says which it pulls low.
+pub trait IecDevice {
+    fn tick(&mut self, cycles: u8, bus: IecLines);
+    fn pulls(&self) -> IecLines;
+
+    // The files behind the channels of device number `device`, if this is
+    // it, for the KERNAL traps that skip the bus
+    fn channels(&mut self, _device: u8) -> Option<&mut dyn Channels> {
+        None
+    }
+}
+
+// Serializable for snapshots, without the devices: they are plugged in by
+// the host and stay on the bus when one is loaded
+#[derive(Serialize, Deserialize)]
+pub struct IecBus {
+    c64: IecLines,
+    #[serde(skip)]
+    devices: Vec<Box<dyn IecDevice>>,
+}
+
+impl IecBus {
+    pub fn new() -> Self {
+        Self { c64: IecLines::default(), devices: Vec::new() }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn IecDevice>) {
+        self.devices.push(device);
+    }
+
+    pub fn restore(&mut self, snapshot: IecBus) {
+        let old = std::mem::replace(self, snapshot);
+        self.devices = old.devices;
+    }
+
+    // The lines the C64 pulls low, from the levels on CIA2's port A pins
+    pub fn set_c64_port(&mut self, port_a: u8) {
+        let c64 = IecLines { atn: port_a & 0x08 != 0, clk: port_a & 0x10 != 0, data: port_a & 0x20 != 0, srq: false };
+        if c64 != self.c64 {
+            tracing::trace!(target: "iec", "C64 pulls {:?}", c64);
+        }
+        self.c64 = c64;
+    }
+
+    pub fn channels(&mut self, device: u8) -> Option<&mut dyn Channels> {
+        self.devices.iter_mut().find_map(|attached| attached.channels(device))
+    }
+
+    // Everything pulling together
+    pub fn lines(&self) -> IecLines {
+        self.devices.iter().fold(self.c64, |lines, device| lines.or(device.pulls()))
+    }
+
+    // What the C64 reads back on CIA2 port A: CLK on PA6 and DATA on PA7,
+    // 1 for a released line. The other pins float high.
+    pub fn c64_port_input(&self) -> u8 {
+        let lines = self.lines();
+        let mut input = 0xFF;
+        if lines.clk {
+            input &= !0x40;
+        }
+        if lines.data {
+            input &= !0x80;
+        }
+        input
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        let lines = self.lines();
+        for device in &mut self.devices {
+            device.tick(cycles, lines);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{C64Memory, Memory};
+
+    // Answers ATN by pulling DATA low, as a listening device does, and
+    // pulls SRQ when told to
+    struct Listener {
+        data: bool,
+        srq: bool,
+    }
+
+    impl IecDevice for Listener {
+        fn tick(&mut self, _cycles: u8, bus: IecLines) {
+            self.data = bus.atn;
+        }
+
+        fn pulls(&self) -> IecLines {
+            IecLines { data: self.data, srq: self.srq, ..Default::default() }
+        }
+    }
+
+    #[test]
+    fn test_iec_lines_through_cia2() {
+        let mut memory = C64Memory::new();
+        memory.iec.attach(Box::new(Listener { data: false, srq: false }));
+        memory.write(0xDD02, 0x3F);
+        memory.write(0xDD00, 0x03);
+        assert_eq!(memory.read(0xDD00) & 0xC0, 0xC0);
+
+        // PA3 pulls ATN low; the device answers on its next cycle
+        memory.write(0xDD00, 0x0B);
+        assert!(memory.iec.lines().atn);
+        memory.tick_iec(1);
+        assert_eq!(memory.read(0xDD00) & 0xC0, 0x40);
+
+        // The C64 pulling CLK reads back on PA6
+        memory.write(0xDD00, 0x13);
+        memory.tick_iec(1);
+        assert_eq!(memory.read(0xDD00) & 0xC0, 0x80);
+    }
+
+    #[test]
+    fn test_srq_to_cia1_flag() {
+        let mut memory = C64Memory::new();
+        memory.iec.attach(Box::new(Listener { data: false, srq: true }));
+        memory.tick_iec(1);
+        assert_eq!(memory.read(0xDC0D), 0x10);
+    }
+}
@@ -0,0 +1,134 @@
+// Interrupt lines shared between the chips and the CPU
+//
+// IRQ and NMI are open-collector lines: any chip can pull them low and the
+// line stays asserted until every source has let go. Each source owns one
+// bit here, so one chip releasing its interrupt can't clear another's.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptSource {
+    Vic = 0x01,
+    Cia1 = 0x02,
+    Cia2 = 0x04,
+    Restore = 0x08,
+    Cartridge = 0x10, // A freezer's button, on NMI
+    Swiftlink = 0x20, // The RS-232 cartridge's ACIA, on NMI
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct InterruptLine {
+    sources: u8, // One bit per InterruptSource currently pulling the line
+}
+
+impl InterruptLine {
+    pub fn new() -> Self {
+        Self { sources: 0 }
+    }
+
+    // Drive the line from one source. Chips call this with their current
+    // output level every tick.
+    pub fn set(&mut self, source: InterruptSource, asserted: bool) {
+        if asserted {
+            self.sources |= source as u8;
+        } else {
+            self.sources &= !(source as u8);
+        }
+    }
+
+    pub fn is_asserted(&self) -> bool {
+        self.sources != 0
+    }
+
+    // One bit per InterruptSource pulling the line
+    pub fn sources(&self) -> u8 {
+        self.sources
+    }
+
+    #[cfg(test)]
+    pub fn is_asserted_by(&self, source: InterruptSource) -> bool {
+        (self.sources & source as u8) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cia::Cia;
+    use crate::memory::VicBus;
+    use crate::vic::VicII;
+
+    #[test]
+    fn test_line_is_wired_or() {
+        let mut line = InterruptLine::new();
+        line.set(InterruptSource::Vic, true);
+        line.set(InterruptSource::Cia1, true);
+        line.set(InterruptSource::Vic, false);
+        assert!(line.is_asserted());
+        assert!(line.is_asserted_by(InterruptSource::Cia1));
+        line.set(InterruptSource::Cia1, false);
+        assert!(!line.is_asserted());
+    }
+
+    #[test]
+    fn test_vic_holds_irq_until_acknowledged() {
+        let mut vic = VicII::new();
+        let mut irq = InterruptLine::new();
+        let ram = [0; 0x10000];
+        let bus = VicBus::new(&ram, None);
+        vic.write_register(0x12, 1); // Raster compare line 1
+        vic.write_register(0x1A, 0x01); // Enable raster IRQ
+
+        vic.tick(63, &mut irq, &bus);
+        assert!(irq.is_asserted_by(InterruptSource::Vic));
+
+        assert_eq!(vic.read_register(0x19), 0xF1);
+
+        // Still held on the following lines, and writing 0 doesn't acknowledge
+        vic.write_register(0x19, 0x00);
+        vic.tick(63, &mut irq, &bus);
+        assert!(irq.is_asserted());
+
+        // Writing 1 to bit 0 of $D019 acknowledges it
+        vic.write_register(0x19, 0x01);
+        vic.tick(1, &mut irq, &bus);
+        assert!(!irq.is_asserted());
+        assert_eq!(vic.read_register(0x19), 0x70);
+    }
+
+    #[test]
+    fn test_cia_holds_irq_until_icr_read() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        cia.write(0xDC04, 0x10); // Timer A latch = 16 cycles
+        cia.write(0xDC05, 0x00);
+        cia.write(0xDC0D, 0x81); // Enable timer A interrupt
+        cia.write(0xDC0E, 0x11); // Force load and start
+
+        cia.tick(20, &mut irq, InterruptSource::Cia1);
+        assert!(irq.is_asserted_by(InterruptSource::Cia1));
+
+        // Peeking (debugger, UI) must not acknowledge
+        assert_eq!(cia.peek(0xDC0D), 0x81);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(irq.is_asserted());
+
+        // A CPU read returns the flags and clears them
+        assert_eq!(cia.read(0xDC0D), 0x81);
+        cia.tick(1, &mut irq, InterruptSource::Cia1);
+        assert!(!irq.is_asserted());
+    }
+
+    #[test]
+    fn test_cia_masked_source_sets_flag_without_irq() {
+        let mut cia = Cia::new();
+        let mut irq = InterruptLine::new();
+        cia.write(0xDC04, 0x10);
+        cia.write(0xDC05, 0x00);
+        cia.write(0xDC0E, 0x11);
+
+        cia.tick(20, &mut irq, InterruptSource::Cia1);
+        assert!(!irq.is_asserted());
+        assert_eq!(cia.read(0xDC0D), 0x01);
+    }
+}
@@ -11,10 +11,10 @@ pub struct RomSet {
 }
 
 impl RomSet {
-    pub fn load_from_directory(rom_dir: &str) -> Result<Self> {
-        let basic_path = Path::new(rom_dir).join("basic.rom");
-        let kernal_path = Path::new(rom_dir).join("kernal.rom");
-        let char_path = Path::new(rom_dir).join("char.rom");
+    pub fn load_from_directory(rom_dir: &Path) -> Result<Self> {
+        let basic_path = rom_dir.join("basic.rom");
+        let kernal_path = rom_dir.join("kernal.rom");
+        let char_path = rom_dir.join("char.rom");
         
         let basic = fs::read(&basic_path)
             .context(format!("Failed to load BASIC ROM from {:?}", basic_path))?;
@@ -42,15 +42,14 @@ impl RomSet {
     }
 }
 
-pub fn create_rom_directory_if_missing() -> Result<()> {
-    let rom_dir = Path::new("roms");
+pub fn create_rom_directory_if_missing(rom_dir: &Path) -> Result<()> {
     if !rom_dir.exists() {
-        fs::create_dir(rom_dir)?;
-        println!("Created 'roms' directory.");
-        println!("\nTo run the emulator, you need C64 ROM files:");
-        println!("  - roms/basic.rom  (8KB - BASIC interpreter)");
-        println!("  - roms/kernal.rom (8KB - Operating system)");
-        println!("  - roms/char.rom   (4KB - Character set)");
+        fs::create_dir_all(rom_dir)?;
+        println!("Created {:?} directory.", rom_dir);
+        println!("\nTo run the emulator, you need C64 ROM files in it:");
+        println!("  - basic.rom  (8KB - BASIC interpreter)");
+        println!("  - kernal.rom (8KB - Operating system)");
+        println!("  - char.rom   (4KB - Character set)");
         println!("\nYou can extract these from:");
         println!("  1. VICE emulator installation");
         println!("  2. Download from: https://www.zimmers.net/anonftp/pub/cbm/firmware/computers/c64/");
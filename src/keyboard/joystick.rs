@@ -0,0 +1,73 @@
+//! Joysticks driven from the host keyboard
+//!
+//! A C64 joystick closes up to five switches (up, down, left, right, fire)
+//! that pull CIA1 port lines low: port 1 on PRB, port 2 on PRA. Terminals
+//! only report key presses, so like the keyboard matrix each press holds its
+//! switch closed for a few frames, and key repeat keeps it closed.
+
+use crossterm::event::KeyCode;
+
+/// Joystick switches, as bits 0-4 of the CIA port they pull low
+pub const UP: u8 = 0x01;
+pub const DOWN: u8 = 0x02;
+pub const LEFT: u8 = 0x04;
+pub const RIGHT: u8 = 0x08;
+pub const FIRE: u8 = 0x10;
+
+/// Host keys that work a joystick. A terminal can't report keys like
+/// right Ctrl on their own, or tell the numeric keypad from the digit row,
+/// so the keys taken over are lost to the C64 keyboard while mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum JoystickKeys {
+    /// Cursor keys to move, Insert to fire
+    Cursor,
+    /// 8/2/4/6 to move, 7/9/1/3 for the diagonals, 5 or 0 to fire
+    Numpad,
+}
+
+impl JoystickKeys {
+    /// Switches closed by `key`, if it belongs to this set
+    pub fn map(self, key: KeyCode) -> Option<u8> {
+        match (self, key) {
+            (JoystickKeys::Cursor, KeyCode::Up) => Some(UP),
+            (JoystickKeys::Cursor, KeyCode::Down) => Some(DOWN),
+            (JoystickKeys::Cursor, KeyCode::Left) => Some(LEFT),
+            (JoystickKeys::Cursor, KeyCode::Right) => Some(RIGHT),
+            (JoystickKeys::Cursor, KeyCode::Insert) => Some(FIRE),
+            (JoystickKeys::Numpad, KeyCode::Char(c)) => match c {
+                '8' => Some(UP),
+                '2' => Some(DOWN),
+                '4' => Some(LEFT),
+                '6' => Some(RIGHT),
+                '7' => Some(UP | LEFT),
+                '9' => Some(UP | RIGHT),
+                '1' => Some(DOWN | LEFT),
+                '3' => Some(DOWN | RIGHT),
+                '5' | '0' => Some(FIRE),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The joystick port (1 or 2) and switches a key works, given the key set
+/// mapped to each port
+pub fn map_key(ports: [Option<JoystickKeys>; 2], key: KeyCode) -> Option<(u8, u8)> {
+    (1..=2).find_map(|port| Some((port, ports[port as usize - 1]?.map(key)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_joystick_keys() {
+        let ports = [Some(JoystickKeys::Numpad), Some(JoystickKeys::Cursor)];
+        assert_eq!(map_key(ports, KeyCode::Up), Some((2, UP)));
+        assert_eq!(map_key(ports, KeyCode::Insert), Some((2, FIRE)));
+        assert_eq!(map_key(ports, KeyCode::Char('9')), Some((1, UP | RIGHT)));
+        assert_eq!(map_key(ports, KeyCode::Char('a')), None);
+        assert_eq!(map_key([None, Some(JoystickKeys::Cursor)], KeyCode::Char('8')), None);
+    }
+}
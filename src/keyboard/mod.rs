@@ -1,8 +1,10 @@
-/// C64 Keyboard Matrix Mapping
-/// 
-/// The C64 keyboard is an 8x8 matrix connected to CIA1
-/// Port A (PRA) selects rows, Port B (PRB) reads columns
-/// Both are active low (0 = selected/pressed)
+//! C64 Keyboard Matrix Mapping
+//! 
+//! The C64 keyboard is an 8x8 matrix connected to CIA1
+//! Port A (PRA) selects rows, Port B (PRB) reads columns
+//! Both are active low (0 = selected/pressed)
+
+pub mod joystick;
 
 use crossterm::event::KeyCode;
 
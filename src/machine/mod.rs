@@ -0,0 +1,285 @@
+// C64 system: the 6510 CPU plus the chips wired to its bus
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::{Cpu, StepResult};
+use crate::debugger::calls::CallStack;
+use crate::debugger::expression::Expression;
+use crate::debugger::interrupts::InterruptHistory;
+use crate::debugger::profiler::Profiler;
+use crate::debugger::symbols::Labels;
+use crate::interrupt::InterruptSource;
+use crate::memory::C64Memory;
+
+// Video standard of the machine. The VIC-II variant (6569 PAL, 6567 NTSC)
+// and the crystal it is derived from set the CPU clock and frame timing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum VideoStandard {
+    #[default]
+    Pal,
+    Ntsc,
+}
+
+impl VideoStandard {
+    pub const fn clock_hz(self) -> u32 {
+        match self {
+            VideoStandard::Pal => 985_248,
+            VideoStandard::Ntsc => 1_022_727,
+        }
+    }
+
+    pub const fn raster_lines(self) -> u16 {
+        match self {
+            VideoStandard::Pal => 312,
+            VideoStandard::Ntsc => 263,
+        }
+    }
+
+    pub const fn cycles_per_line(self) -> u16 {
+        match self {
+            VideoStandard::Pal => 63,
+            VideoStandard::Ntsc => 65,
+        }
+    }
+
+    pub const fn cycles_per_frame(self) -> u32 {
+        self.raster_lines() as u32 * self.cycles_per_line() as u32
+    }
+
+    // Real time one frame takes: ~19.95ms PAL, ~16.72ms NTSC
+    pub fn frame_duration(self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.cycles_per_frame() as f64 / self.clock_hz() as f64)
+    }
+
+    // Mains frequency of the countries using the standard, which the power
+    // supply passes to the CIAs' time of day clocks
+    pub const fn mains_hz(self) -> u32 {
+        match self {
+            VideoStandard::Pal => 50,
+            VideoStandard::Ntsc => 60,
+        }
+    }
+
+    // CIA1 timer A value the KERNAL uses for its 1/60s jiffy IRQ
+    pub const fn jiffy_timer(self) -> u16 {
+        match self {
+            VideoStandard::Pal => 0x4025,
+            VideoStandard::Ntsc => 0x4295,
+        }
+    }
+}
+
+// Serializable as a whole for snapshots (see snapshot::save)
+#[derive(Serialize, Deserialize)]
+pub struct Machine {
+    pub cpu: Cpu,
+    pub memory: C64Memory,
+    pub standard: VideoStandard,
+
+    // Opt-in execution profiler (--profile)
+    #[serde(skip)]
+    pub profiler: Option<Profiler>,
+
+    // The user's labels (--labels), for the monitor
+    #[serde(skip)]
+    pub labels: Labels,
+
+    // Expressions the debug view shows the values of (disp, --display)
+    #[serde(skip)]
+    pub displays: Vec<Expression>,
+
+    // Interrupts asserted and taken, and the calls the CPU is in, for the
+    // monitor
+    #[serde(skip)]
+    pub interrupts: InterruptHistory,
+    #[serde(skip)]
+    pub calls: CallStack,
+
+    // RESTORE is wired to the CPU's NMI input and holds it while pressed
+    #[serde(skip)]
+    restore_key: bool,
+}
+
+impl Machine {
+    pub fn new(standard: VideoStandard) -> Self {
+        let mut memory = C64Memory::new();
+        memory.vic.set_standard(standard);
+        memory.sid.set_standard(standard);
+        memory.cia1.set_standard(standard);
+        memory.cia2.set_standard(standard);
+        Self {
+            cpu: Cpu::new(),
+            memory,
+            standard,
+            profiler: None,
+            labels: Labels::new(),
+            displays: Vec::new(),
+            interrupts: InterruptHistory::new(),
+            calls: CallStack::new(),
+            restore_key: false,
+        }
+    }
+
+    // Take on the state of a snapshot, keeping the profiler and what the
+    // host supplies (ROMs, input, sound output). The calls the snapshot was
+    // in aren't known.
+    pub fn restore(&mut self, snapshot: Machine) {
+        self.cpu.restore(snapshot.cpu);
+        self.memory.restore(snapshot.memory);
+        self.standard = snapshot.standard;
+        self.calls = CallStack::new();
+    }
+
+    // Execute one CPU instruction (or interrupt sequence) and advance the
+    // chips by the same number of cycles. A breakpoint hit consumes no time;
+    // a watchpoint hit is reported once the instruction has run.
+    pub fn step(&mut self) -> Result<StepResult> {
+        let (pc, sp) = (self.cpu.pc, self.cpu.sp);
+        let interrupt = self.cpu.pending_interrupt();
+        if let Some(watches) = &mut self.memory.watches {
+            // Left by accesses from outside, such as the monitor's
+            watches.take_hit();
+        }
+        if let Some(coverage) = &mut self.memory.coverage {
+            coverage.expect_instruction(if interrupt.is_none() { Some(pc) } else { None });
+        }
+        let cycles = match self.cpu.step(&mut self.memory)? {
+            StepResult::Executed(cycles) => cycles,
+            hit @ (StepResult::BreakpointHit(_) | StepResult::WatchpointHit { .. }) => return Ok(hit),
+        };
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, cycles);
+        }
+        if let Some(vector) = interrupt {
+            self.interrupts.taken(&self.memory, vector, pc, self.cpu.pc);
+        }
+        self.calls.record(pc, sp, self.cpu.pc, self.cpu.sp, interrupt);
+
+        // Tick CIA timers, the VIC-II raster beam, the SID and the serial
+        // bus devices. The CIAs and VIC-II drive the shared interrupt lines:
+        // VIC-II and CIA1 on IRQ, CIA2, RESTORE, a freezer cartridge and the
+        // Swiftlink on NMI.
+        let memory = &mut self.memory;
+        memory.cia1.tick(cycles, &mut memory.irq, InterruptSource::Cia1);
+        memory.cia2.tick(cycles, &mut memory.nmi, InterruptSource::Cia2);
+        memory.tick_vic(cycles);
+        memory.sid.tick(cycles);
+        memory.tick_iec(cycles);
+        if let Some(swiftlink) = &mut memory.swiftlink {
+            swiftlink.tick(cycles, &mut memory.nmi);
+        }
+        memory.nmi.set(InterruptSource::Restore, self.restore_key);
+        memory.nmi.set(InterruptSource::Cartridge, memory.cartridge_nmi());
+
+        // The CPU samples its interrupt inputs at the next instruction boundary
+        self.cpu.set_irq_line(memory.irq.is_asserted());
+        self.cpu.set_nmi_line(memory.nmi.is_asserted());
+        self.interrupts.observe(memory);
+
+        // BA -> RDY: the VIC-II halts the CPU while it fetches from memory
+        self.cpu.set_rdy(!self.memory.vic.ba_low());
+
+        if let Some(event) = self.memory.watches.as_mut().and_then(|watches| watches.take_hit()) {
+            return Ok(StepResult::WatchpointHit { pc, event, cycles });
+        }
+        Ok(StepResult::Executed(cycles))
+    }
+
+    // Set the clocks to `since_midnight`: both CIAs' TOD clocks, and the
+    // KERNAL's jiffy clock ($A0-$A2, 60ths of a second) that TI and TI$
+    // read, or the host clock serving RDTIM and SETTIM in its place. The
+    // KERNAL clears the jiffy clock in memory as it boots.
+    pub fn set_time_of_day(&mut self, since_midnight: std::time::Duration) {
+        use crate::memory::Memory;
+        let seconds = since_midnight.as_secs() % 86_400;
+        let tenths = since_midnight.subsec_millis() / 100;
+        let (hours, minutes, seconds) = ((seconds / 3600) as u8, (seconds / 60 % 60) as u8, (seconds % 60) as u8);
+        for cia in [&mut self.memory.cia1, &mut self.memory.cia2] {
+            cia.set_time_of_day(hours, minutes, seconds, tenths as u8);
+        }
+        let jiffies = (since_midnight.as_millis() % 86_400_000 * 60 / 1000) as u32;
+        let [_, high, middle, low] = jiffies.to_be_bytes();
+        self.memory.write(0x00A0, high);
+        self.memory.write(0x00A1, middle);
+        self.memory.write(0x00A2, low);
+        if let Some(clock) = &mut self.cpu.clock {
+            *clock = crate::hle::HostClock::new(jiffies);
+        }
+    }
+
+    pub fn set_restore_key(&mut self, pressed: bool) {
+        self.restore_key = pressed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn frame_length(standard: VideoStandard) -> u32 {
+        let mut machine = Machine::new(standard);
+        let raster = |memory: &C64Memory| ((memory.peek(0xD011) as u16 & 0x80) << 1) | memory.peek(0xD012) as u16;
+        let mut cycles = 0;
+        // Run the beam off line 0 and until it wraps back round to it
+        let mut left_line_0 = false;
+        loop {
+            machine.memory.tick_vic(1);
+            cycles += 1;
+            match raster(&machine.memory) {
+                0 if left_line_0 => return cycles,
+                0 => {}
+                _ => left_line_0 = true,
+            }
+        }
+    }
+
+    #[test]
+    fn test_vic_bank_from_cia2() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let memory = &mut machine.memory;
+        assert_eq!(memory.vic.bank(), 0x0000);
+
+        // PA0-PA1 select the bank inverted, once they are outputs
+        memory.write(0xDD00, 0x00);
+        assert_eq!(memory.vic.bank(), 0x0000);
+        memory.write(0xDD02, 0x03);
+        assert_eq!(memory.vic.bank(), 0xC000);
+        memory.write(0xDD00, 0x02);
+        assert_eq!(memory.vic.bank(), 0x4000);
+        assert_eq!(memory.vic.screen_base(), 0x4400);
+        // Back to inputs the pull-ups select bank 0
+        memory.write(0xDD02, 0x00);
+        assert_eq!(memory.vic.bank(), 0x0000);
+
+        // The character ROM shows through in bank 2 but not bank 1
+        memory.load_char_rom(vec![0xAA; 0x1000]);
+        memory.write(0xDD02, 0x03);
+        memory.write(0xDD00, 0x01);
+        assert_eq!(memory.read_vic(memory.vic.char_base()), 0xAA);
+        memory.write(0xDD00, 0x02);
+        assert_eq!(memory.read_vic(memory.vic.char_base()), 0x00);
+    }
+
+    #[test]
+    fn test_set_time_of_day() {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        // 23:59:59.5, the last half second before the jiffy clock wraps
+        machine.set_time_of_day(std::time::Duration::from_millis(86_399_500));
+        assert_eq!(machine.memory.peek(0xDD0B), 0x91);
+        assert_eq!(machine.memory.peek(0xDC09), 0x59);
+        let jiffies = [0xA0, 0xA1, 0xA2].map(|addr| machine.memory.peek(addr));
+        assert_eq!(jiffies, [0x4F, 0x19, 0xE2]);
+    }
+
+    #[test]
+    fn test_video_standard_timing() {
+        assert_eq!(frame_length(VideoStandard::Pal), 312 * 63);
+        assert_eq!(frame_length(VideoStandard::Ntsc), 263 * 65);
+
+        let machine = Machine::new(VideoStandard::Ntsc);
+        assert_eq!(machine.memory.peek(0xDC04), 0x95);
+        assert_eq!(machine.memory.peek(0xDC05), 0x42);
+    }
+}
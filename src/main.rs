@@ -9,10 +9,24 @@ mod ui;
 mod debugger;
 mod keyboard;
 mod storage;
+mod machine;
+mod interrupt;
+mod player;
+mod gamepad;
+mod iec;
+mod snapshot;
+mod cartridge;
+mod tape;
+mod config;
+mod printer;
+mod rs232;
+mod hle;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use crossterm::event::KeyCode;
+use crossterm::event::{Event, KeyCode, MouseButton, MouseEventKind};
+
+use cpu::StepResult;
 
 #[derive(Parser, Debug)]
 #[command(name = "go64")]
@@ -22,56 +36,486 @@ struct Args {
     #[arg(short, long)]
     debug: bool,
     
+    /// Play a PSID/RSID music file (.sid) instead of starting BASIC
+    #[arg(long, value_name = "FILE")]
+    play: Option<std::path::PathBuf>,
+
+    /// Plug in a cartridge image (.crt) and start from it
+    #[arg(long, value_name = "FILE", conflicts_with = "play")]
+    cart: Option<std::path::PathBuf>,
+
+    /// Put a disk image (.d64 or .g64) in drive 8. LOAD and SAVE then use it instead
+    /// of the host directory, and changes are written back to the file.
+    #[arg(long, value_name = "FILE")]
+    disk: Option<std::path::PathBuf>,
+
+    /// Put a tape image (.t64 or .tap), or a folder of .prg files, in the
+    /// datasette
+    #[arg(long, value_name = "FILE")]
+    tape: Option<std::path::PathBuf>,
+
+    /// Map a host folder to a drive, as DEVICE=DIR (8-15). Its .prg files
+    /// are the drive's programs, with wildcards and LOAD"$" as on a disk.
+    /// Drive 8 otherwise uses ~/.go64/1541. Can be repeated.
+    #[arg(long, value_name = "DEVICE=DIR", value_parser = parse_host_drive)]
+    host_drive: Vec<(u8, std::path::PathBuf)>,
+
+    /// Put a printer on device 4 and write what it prints to FILE, as
+    /// ASCII text
+    #[arg(long, value_name = "FILE")]
+    printer: Option<std::path::PathBuf>,
+
+    /// Write the printer's text as the PETSCII bytes it receives
+    #[arg(long, requires = "printer")]
+    printer_raw: bool,
+
+    /// Draw the printed paper into FILE, a PBM image of the MPS-803's dots
+    /// with graphics and the C64's character set. Puts a printer on device
+    /// 4 like --printer.
+    #[arg(long, value_name = "FILE")]
+    printer_bitmap: Option<std::path::PathBuf>,
+
+    /// Plug in a Swiftlink RS-232 cartridge at $DE00 with a modem that
+    /// dials telnet BBSes: ATDT HOST:PORT from a terminal program
+    #[arg(long, conflicts_with = "cart")]
+    swiftlink: bool,
+
+    /// Start with the drives' disks and folders write protected, so nothing
+    /// on them can be changed. Alt+W toggles drive 8's.
+    #[arg(long)]
+    write_protect: bool,
+
+    /// The folder drive 8 uses when no disk or folder is given for it
+    /// [default: ~/.go64/1541, or storage_dir in the config file]
+    #[arg(long, value_name = "DIR")]
+    storage_dir: Option<std::path::PathBuf>,
+
+    /// The folder with basic.rom, kernal.rom and char.rom [default: roms,
+    /// or rom_dir in the config file]
+    #[arg(long, value_name = "DIR")]
+    rom_dir: Option<std::path::PathBuf>,
+
+    /// Read settings from FILE rather than ~/.go64/config.toml
+    #[arg(long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+
+    /// Resume from a snapshot saved with Alt+S, instead of booting afresh
+    #[arg(long, value_name = "FILE", conflicts_with = "play")]
+    load_snapshot: Option<std::path::PathBuf>,
+
     /// Run without UI (for testing)
     #[arg(long)]
     no_ui: bool,
+
+    /// Without UI, stop after N frames and print the hash of the last one
+    #[arg(long, value_name = "N", requires = "no_ui")]
+    frames: Option<u64>,
+
+    /// Without UI, fail unless the last of --frames frames hashes to HASH
+    /// (hex, as printed)
+    #[arg(long, value_name = "HASH", requires = "frames", value_parser = parse_frame_hash)]
+    expect_frame_hash: Option<u64>,
+
+    /// Without UI, print every CPU read and write in a hex address range
+    /// ("D020", "D000-D3FF"), or only reads or writes with ":r" or ":w".
+    /// May be given more than once.
+    #[arg(long, value_name = "RANGE", requires = "no_ui", value_parser = debugger::watch::Watch::parse)]
+    watch: Vec<debugger::watch::Watch>,
+
+    /// Stop before the instruction at a hex address, e.g. "$C000": in the
+    /// monitor with the UI, or with the registers printed without it. May
+    /// be given more than once.
+    #[arg(long = "break", value_name = "ADDR", value_parser = parse_address)]
+    breakpoints: Vec<u16>,
+
+    /// Stop when the CPU reads or writes a hex address range, as for
+    /// --watch ("D020:w"), or with ":c" writes that change the value there
+    /// ("0314-0315:c"). May be given more than once.
+    #[arg(long, value_name = "RANGE", value_parser = debugger::watch::Watch::parse)]
+    watchpoint: Vec<debugger::watch::Watch>,
+
+    /// Stop when a write to $00 or $01 changes the banking, or a cartridge's
+    /// bank registers are written, showing the banking before and after
+    #[arg(long)]
+    watch_banking: bool,
+
+    /// Serve the GDB remote protocol on a local TCP port, for gdb, lldb and
+    /// IDEs to debug the machine through
+    #[arg(long, value_name = "PORT", conflicts_with = "no_ui")]
+    gdb: Option<u16>,
+
+    /// Load labels for the monitor from a VICE label file ("al C:080d
+    /// .main"), as cross-assemblers write them. May be given more than
+    /// once.
+    #[arg(long, value_name = "FILE")]
+    labels: Vec<std::path::PathBuf>,
+
+    /// Show an expression's value in the debug view (F9) as the machine
+    /// runs, e.g. "@w($FB)", "A" or "@($D012)", as the monitor's disp does.
+    /// May be given more than once.
+    #[arg(long, value_name = "EXPR", conflicts_with = "no_ui")]
+    display: Vec<String>,
+
+    /// Profile execution and print the N hottest addresses on exit
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "20")]
+    profile: Option<usize>,
+
+    /// Record every address the CPU runs code from, reads and writes, and
+    /// write a report of them to FILE on exit
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<std::path::PathBuf>,
+
+    /// Record the same, and write a disassembly of the code that ran to
+    /// FILE on exit, each line marked with whether it ran, was read or was
+    /// written
+    #[arg(long, value_name = "FILE")]
+    coverage_listing: Option<std::path::PathBuf>,
+
+    /// Disable the KERNAL file traps (LOAD, SAVE, OPEN, CHRIN and the rest)
+    /// and run the real KERNAL routines
+    #[arg(long)]
+    no_hle_traps: bool,
+
+    /// Emulate an NTSC machine (1.023 MHz, 263 raster lines)
+    #[arg(long, conflicts_with = "pal")]
+    ntsc: bool,
+
+    /// Emulate a PAL machine (0.985 MHz, 312 raster lines) [default]
+    #[arg(long)]
+    pal: bool,
+
+    /// Start in warp mode (run as fast as the host allows, toggle with F11)
+    #[arg(long)]
+    warp: bool,
+
+    /// How to draw the screen: text (character cells), halfblock (the pixel
+    /// picture scaled to two blocks per cell), or sixel or kitty (pixel graphics,
+    /// for terminals that support them) [default: detected from the terminal]
+    #[arg(long, value_enum)]
+    video: Option<ui::VideoOutput>,
+
+    /// SID chip to emulate: the original 6581 or the later 8580 [default:
+    /// 6581, or the chip a .sid file asks for]
+    #[arg(long, value_enum)]
+    sid_model: Option<sid::SidModel>,
+
+    /// Make volume register sample playback (digis) audible on the 8580,
+    /// like the DC offset mod real 8580 machines use for it
+    #[arg(long)]
+    sid_digiboost: bool,
+
+    /// Host keys that work the joystick in port 1 (cursor: cursor keys and
+    /// Insert to fire; numpad: the digit keys as a keypad, 5 or 0 to fire).
+    /// The keys no longer reach the C64 keyboard. Alt+J swaps the ports.
+    #[arg(long, value_enum, value_name = "KEYS")]
+    joystick1: Option<keyboard::joystick::JoystickKeys>,
+
+    /// Host keys that work the joystick in port 2, which most games use
+    #[arg(long, value_enum, value_name = "KEYS")]
+    joystick2: Option<keyboard::joystick::JoystickKeys>,
+
+    /// Joystick port for each gamepad, in the order they are plugged in:
+    /// by default the first works port 2 and the second port 1. The left
+    /// stick also works the port's paddles.
+    #[arg(long, value_name = "PORTS", value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..=2))]
+    gamepad_ports: Option<Vec<u8>>,
+
+    /// Set the CIA time of day clocks to the host's local time, once at
+    /// start (seed) or again every second (track), which also keeps the
+    /// jiffy clock that TI$ reads on the wall clock
+    #[arg(long, value_enum, value_name = "MODE", num_args = 0..=1, default_missing_value = "seed")]
+    host_time: Option<HostTime>,
+
+    /// Serve the KERNAL's RDTIM and SETTIM from the host's clock, so TI and
+    /// TI$ keep real time in warp mode and when emulation falls behind.
+    /// Counts from 0, or from the local time with --host-time.
+    #[arg(long, conflicts_with = "no_hle_traps")]
+    host_clock: bool,
+
+    /// What RAM holds at power on: alternating 64-byte runs of $00 and
+    /// $FF, as on most C64s, or all zeroes
+    #[arg(long, value_enum, value_name = "PATTERN")]
+    ram_pattern: Option<memory::RamPattern>,
+
+    /// Log diagnostics at LEVEL (error, warn, info, debug or trace), for
+    /// everything or per subsystem, e.g. "warn,cia=trace". Subsystems: cpu,
+    /// memory, vic, sid, cia, iec, hle, cartridge, storage, tape, printer,
+    /// rs232 and snapshot.
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<tracing_subscriber::filter::Targets>,
+
+    /// Write the log to FILE rather than stderr, which the UI draws over
+    /// [default level with a file: info]
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Master volume of the sound output, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 100, value_parser = clap::value_parser!(u8).range(0..=100))]
+    volume: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum HostTime {
+    Seed,
+    Track,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    init_logging(&args)?;
 
-    if args.no_ui {
-        run_headless(args.debug)?;
+    // The config file fills in what the command line leaves out
+    let config = config::Config::load(args.config.as_deref())?;
+    args.storage_dir = args.storage_dir.or(config.storage_dir);
+    args.rom_dir = args.rom_dir.or(config.rom_dir);
+
+    if let Some(path) = &args.play {
+        run_player(&args, path)?;
+    } else if args.no_ui {
+        run_headless(&args)?;
     } else {
-        run_with_ui(args.debug)?;
+        run_with_ui(&args)?;
     }
     
     Ok(())
 }
 
-fn run_headless(debug: bool) -> Result<()> {
+// Diagnostics go through tracing, with a target per subsystem. Until
+// --log-level or --log-file asks for them no subscriber is installed, and
+// each event costs one check of its callsite.
+fn init_logging(args: &Args) -> Result<()> {
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+    use tracing_subscriber::layer::SubscriberExt;
+    if args.log_level.is_none() && args.log_file.is_none() {
+        return Ok(());
+    }
+    let targets = args
+        .log_level
+        .clone()
+        .unwrap_or_else(|| tracing_subscriber::filter::Targets::new().with_default(tracing::Level::INFO));
+    let writer = match &args.log_file {
+        Some(path) => {
+            let file = std::fs::File::create(path).context(format!("Failed to create {:?}", path))?;
+            BoxMakeWriter::new(std::sync::Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_max_level(tracing::level_filters::LevelFilter::TRACE)
+        .with_ansi(args.log_file.is_none())
+        .finish()
+        .with(targets);
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(())
+}
+
+// Plug in a cartridge before the machine is reset, so it starts from it
+fn attach_cartridge(machine: &mut machine::Machine, path: &std::path::Path) -> Result<()> {
+    let cartridge = cartridge::Cartridge::load(path).context(format!("Failed to load {:?}", path))?;
+    println!("✅ Cartridge attached: {}", cartridge.name);
+    machine.memory.attach_cartridge(Some(cartridge));
+    Ok(())
+}
+
+// Print the accesses the --watch ranges caught during the step at `pc`
+fn print_bus_events(pc: u16, watches: &mut debugger::watch::Watches) {
+    for event in watches.take_events() {
+        let access = match event.access {
+            debugger::watch::Access::Read => "R",
+            debugger::watch::Access::Write => "W",
+        };
+        println!("{} ${:04X} = ${:02X}  (PC=${:04X})", access, event.addr, event.value, pc);
+    }
+}
+
+// Put a tape in the datasette
+fn insert_tape(machine: &mut machine::Machine, path: &std::path::Path) -> Result<()> {
+    let tape = tape::Tape::load(path)?;
+    println!("✅ Tape inserted: {}", tape.describe());
+    machine.memory.tape = Some(tape);
+    Ok(())
+}
+
+// Drives on the serial bus: 8 with the disk given or the host directory,
+// and any host folders given for other devices. The LOAD/SAVE traps use
+// their files too.
+fn attach_drive(machine: &mut machine::Machine, args: &Args) -> Result<()> {
+    use iec::device::Channels;
+    use storage::drive::{Drive, Medium};
+    use storage::host::HostDir;
+
+    let folder_8 = args.host_drive.iter().any(|(device, _)| *device == 8);
+    if args.disk.is_some() && folder_8 {
+        anyhow::bail!("Drive 8 can't take both --disk and --host-drive 8=DIR");
+    }
+    let mut drives = Vec::new();
+    if let Some(path) = &args.disk {
+        drives.push((8, storage::open_disk(path)?));
+    } else if !folder_8 {
+        drives.push((8, Medium::Host(HostDir::new(&args.storage_dir()?))));
+    }
+    for (device, path) in &args.host_drive {
+        if drives.iter().any(|(taken, _)| taken == device) {
+            anyhow::bail!("Drive {} is given more than once", device);
+        }
+        drives.push((*device, Medium::Host(HostDir::new(path))));
+    }
+    for (device, medium) in drives {
+        let mut drive = Drive::new(medium);
+        drive.set_write_protected(args.write_protect);
+        machine.memory.iec.attach(Box::new(iec::device::SerialDevice::new(device, drive)));
+    }
+    Ok(())
+}
+
+// A printer on device 4, if asked for. Its glyphs come from the character
+// ROM.
+fn attach_printer(machine: &mut machine::Machine, args: &Args) -> Result<()> {
+    if args.printer.is_none() && args.printer_bitmap.is_none() {
+        return Ok(());
+    }
+    let font = io::RomSet::load_from_directory(args.rom_dir()).ok().map(|roms| roms.char_rom);
+    let printer = printer::Printer::new(args.printer.as_deref(), args.printer_raw, args.printer_bitmap.as_deref(), font)?;
+    machine.memory.iec.attach(Box::new(iec::device::SerialDevice::new(printer::DEVICE, printer)));
+    Ok(())
+}
+
+// A host folder for a drive, as DEVICE=DIR
+fn parse_host_drive(spec: &str) -> Result<(u8, std::path::PathBuf), String> {
+    let (device, dir) = spec.split_once('=').ok_or("expected DEVICE=DIR, e.g. 9=./games")?;
+    let device: u8 = device.trim().parse().map_err(|_| format!("invalid device number {:?}", device))?;
+    if !(8..=15).contains(&device) {
+        return Err(format!("device {} is not a disk drive (8-15)", device));
+    }
+    let dir = std::path::PathBuf::from(dir);
+    if !dir.is_dir() {
+        return Err(format!("{:?} is not a directory", dir));
+    }
+    Ok((device, dir))
+}
+
+// Resume from a snapshot given on the command line, once the ROMs are in
+fn load_snapshot(machine: &mut machine::Machine, path: &std::path::Path) -> Result<()> {
+    snapshot::load(machine, path)?;
+    println!("✅ Snapshot loaded: PC=${:04X}", machine.cpu.pc);
+    Ok(())
+}
+
+// Status bar note for a snapshot hotkey, e.g. "SNAPSHOT SAVED"
+fn snapshot_status(done: &str, result: Result<()>) -> String {
+    match result {
+        Ok(()) => format!("SNAPSHOT {}", done),
+        Err(e) => format!("SNAPSHOT FAILED: {}", e),
+    }
+}
+
+fn parse_address(addr: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(addr.trim_start_matches('$'), 16)
+}
+
+fn parse_frame_hash(hash: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(hash.trim_start_matches("0x"), 16)
+}
+
+impl Args {
+    fn storage_dir(&self) -> Result<std::path::PathBuf> {
+        match &self.storage_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => storage::default_storage_path(),
+        }
+    }
+
+    fn rom_dir(&self) -> &std::path::Path {
+        self.rom_dir.as_deref().unwrap_or(std::path::Path::new("roms"))
+    }
+
+    fn video_standard(&self) -> machine::VideoStandard {
+        if self.ntsc {
+            machine::VideoStandard::Ntsc
+        } else {
+            machine::VideoStandard::Pal
+        }
+    }
+}
+
+fn run_headless(args: &Args) -> Result<()> {
     use memory::Memory;
     println!("go64 - Commodore 64 Emulator (Headless Mode)");
     println!("Initializing...");
     
     // Initialize storage
-    storage::init()?;
+    storage::init(&args.storage_dir()?)?;
 
-    let mut cpu = cpu::Cpu::new();
-    let mut memory = memory::C64Memory::new();
-    let _vic = vic::VicII::new();
+    let mut machine = machine::Machine::new(args.video_standard());
+    machine.cpu.hle_traps = !args.no_hle_traps;
+    machine.cpu.clock = args.host_clock.then(|| hle::HostClock::new(0));
+    for &addr in &args.breakpoints {
+        machine.cpu.add_breakpoint(addr);
+    }
+    for path in &args.labels {
+        machine.labels.load(path)?;
+    }
+    for watch in &args.watchpoint {
+        machine.memory.watches.get_or_insert_with(debugger::watch::Watches::new).add_watchpoint(watch.clone());
+    }
+    if args.watch_banking {
+        machine.memory.watches.get_or_insert_with(debugger::watch::Watches::new).watch_banking(true);
+    }
+    machine.memory.fill_ram(args.ram_pattern.unwrap_or_default());
+    machine.memory.sid.set_model(args.sid_model.unwrap_or_default());
+    machine.memory.sid.set_digiboost(args.sid_digiboost);
+    if args.host_time.is_some() {
+        machine.set_time_of_day(host_time_of_day());
+    }
+    if args.profile.is_some() {
+        machine.profiler = Some(debugger::profiler::Profiler::new());
+    }
+    if args.coverage.is_some() || args.coverage_listing.is_some() {
+        machine.memory.coverage = Some(debugger::coverage::Coverage::new());
+    }
+    for watch in &args.watch {
+        machine.memory.watches.get_or_insert_with(debugger::watch::Watches::new).add(watch.clone());
+    }
+    if let Some(path) = &args.cart {
+        attach_cartridge(&mut machine, path)?;
+    }
+    if let Some(path) = &args.tape {
+        insert_tape(&mut machine, path)?;
+    }
+    attach_drive(&mut machine, args)?;
+    attach_printer(&mut machine, args)?;
+    if args.swiftlink {
+        machine.memory.swiftlink = Some(rs232::Swiftlink::new(args.video_standard().clock_hz()));
+    }
 
     // Load ROMs
-    io::create_rom_directory_if_missing()?;
-    match io::RomSet::load_from_directory("roms") {
+    io::create_rom_directory_if_missing(args.rom_dir())?;
+    match io::RomSet::load_from_directory(args.rom_dir()) {
         Ok(roms) => {
             println!("✅ ROMs loaded successfully!");
-            memory.load_basic_rom(roms.basic);
-            memory.load_kernal_rom(roms.kernal);
-            memory.load_char_rom(roms.char_rom);
+            machine.memory.load_basic_rom(roms.basic);
+            machine.memory.load_kernal_rom(roms.kernal);
+            machine.memory.load_char_rom(roms.char_rom);
             
             // Reset CPU to start execution from KERNAL reset vector
-            cpu.reset(&memory);
-            println!("✅ CPU Reset. PC=${:04X}", cpu.pc);
+            machine.cpu.reset(&mut machine.memory);
+            println!("✅ CPU Reset. PC=${:04X}", machine.cpu.pc);
         }
         Err(e) => {
             println!("⚠️  Could not load ROMs: {}", e);
+            if args.expect_frame_hash.is_some() {
+                anyhow::bail!("no frame to check without ROMs");
+            }
             return Ok(());
         }
     }
+    if let Some(path) = &args.load_snapshot {
+        load_snapshot(&mut machine, path)?;
+    }
 
-    if debug {
-        println!("CPU initialized: {:?}", cpu);
+    if args.debug {
+        println!("CPU initialized: {:?}", machine.cpu);
     }
 
     println!("Starting execution loop (Press Ctrl+C to stop)...");
@@ -81,6 +525,8 @@ fn run_headless(debug: bool) -> Result<()> {
     let start_time = std::time::Instant::now();
     let mut last_log = std::time::Instant::now();
     let mut _frame_count = 0;
+    // Why the loop ended before --frames were run, if it did
+    let mut stopped = None;
     
     // Debug: Track if we're stuck
     let mut last_pc = 0;
@@ -88,54 +534,83 @@ fn run_headless(debug: bool) -> Result<()> {
     
     loop {
         // Execute one instruction
-        match cpu.step(&mut memory) {
-            Ok(cycles) => {
+        let pc = machine.cpu.pc;
+        let step = machine.step();
+        if let Some(watches) = &mut machine.memory.watches {
+            print_bus_events(pc, watches);
+        }
+        match step {
+            Ok(StepResult::Executed(cycles)) => {
                 cycles_total += cycles as u64;
-                let irq1 = memory.cia1.tick(cycles);
-                let irq2 = memory.cia2.tick(cycles);
-                let irq_vic = memory.vic.tick(cycles);
-                
-                // Trigger IRQ if CIA requested it
-                if irq1 || irq2 || irq_vic {
-                    cpu.irq(&mut memory);
+                if machine.cpu.is_jammed() {
+                    println!("CPU JAMMED at PC=${:04X}", machine.cpu.pc);
+                    stopped = Some(format!("CPU jammed at PC=${:04X}", machine.cpu.pc));
+                    break;
+                }
+            }
+            Ok(StepResult::BreakpointHit(pc)) => {
+                println!("Breakpoint at PC=${:04X}", pc);
+                for line in debugger::monitor::where_stopped(&machine) {
+                    println!("{}", line);
+                }
+                stopped = Some(format!("breakpoint at PC=${:04X}", pc));
+                break;
+            }
+            Ok(StepResult::WatchpointHit { pc, event, .. }) => {
+                println!("Watchpoint at PC=${:04X}: {}", pc, event.describe());
+                for line in debugger::monitor::banking_change(&machine, &event) {
+                    println!("{}", line);
+                }
+                for line in debugger::monitor::where_stopped(&machine) {
+                    println!("{}", line);
                 }
+                stopped = Some(format!("watchpoint at PC=${:04X}: {}", pc, event.describe()));
+                break;
             }
             Err(e) => {
                 println!("CPU Error: {}", e);
+                stopped = Some(format!("CPU error: {}", e));
                 break;
             }
         }
         
+        // With --frames, stop once the last frame is complete
+        if machine.memory.vic.take_frame_complete()
+            && args.frames.is_some_and(|frames| machine.memory.vic.frame_count() >= frames)
+        {
+            break;
+        }
+        
         // Check for stuck loop
-        if cpu.pc == last_pc {
+        if machine.cpu.pc == last_pc {
             stuck_count += 1;
             if stuck_count == 1000 {
-                println!("⚠️  STUCK at PC=${:04X} for >1000 instructions", cpu.pc);
+                println!("⚠️  STUCK at PC=${:04X} for >1000 instructions", machine.cpu.pc);
                 println!("   A=${:02X} X=${:02X} Y=${:02X} SP=${:02X} Status=${:02X}", 
-                         cpu.a, cpu.x, cpu.y, cpu.sp, cpu.status.as_byte());
+                         machine.cpu.a, machine.cpu.x, machine.cpu.y, machine.cpu.sp, machine.cpu.status.as_byte());
                 
                 // Disassemble a few bytes around PC
                 print!("   Code: ");
                 for i in 0..6 {
-                    print!("{:02X} ", memory.read(cpu.pc.wrapping_add(i)));
+                    print!("{:02X} ", machine.memory.peek(machine.cpu.pc.wrapping_add(i)));
                 }
-                println!("");
+                println!();
                 
                 // Check memory banking config at 0x0001
-                println!("   Mem config: $0001=${:02X}", memory.read(0x0001));
+                println!("   Mem config: $0001=${:02X}", machine.memory.peek(0x0001));
                 
-                println!("   Mem config: $0001=${:02X}", memory.read(0x0001));
-                println!("   VIC $D012: ${:02X}", memory.read(0xD012));
+                println!("   Mem config: $0001=${:02X}", machine.memory.peek(0x0001));
+                println!("   VIC $D012: ${:02X}", machine.memory.peek(0xD012));
                 
                 // Check CIA interrupt state
                 println!("   CIA1 ICR=${:02X} Mask=${:02X} TimerA=${:04X} Control=${:02X}", 
-                         memory.cia1.icr, memory.cia1.icr_mask, 
-                         ((memory.cia1.read(0xDC05) as u16) << 8) | memory.cia1.read(0xDC04) as u16,
-                         memory.cia1.cra);
+                         machine.memory.cia1.icr, machine.memory.cia1.icr_mask, 
+                         ((machine.memory.cia1.peek(0xDC05) as u16) << 8) | machine.memory.cia1.peek(0xDC04) as u16,
+                         machine.memory.cia1.cra);
             }
         } else {
             stuck_count = 0;
-            last_pc = cpu.pc;
+            last_pc = machine.cpu.pc;
         }
         
         // Log status every second
@@ -143,35 +618,54 @@ fn run_headless(debug: bool) -> Result<()> {
             let elapsed = start_time.elapsed().as_secs_f64();
             let mhz = (cycles_total as f64 / elapsed) / 1_000_000.0;
             print!("t={:.1}s | PC=${:04X} | Speed: {:.3} MHz | Cycles: {} | Code: ", 
-                     elapsed, cpu.pc, mhz, cycles_total);
+                     elapsed, machine.cpu.pc, mhz, cycles_total);
             
             // Print next 3 bytes
             for i in 0..3 {
-                print!("{:02X} ", memory.read(cpu.pc.wrapping_add(i)));
+                print!("{:02X} ", machine.memory.peek(machine.cpu.pc.wrapping_add(i)));
             }
-            println!("");
+            println!();
             
             last_log = std::time::Instant::now();
             
-            // Exit after 5 seconds for testing
-            if elapsed > 5.0 {
+            // Exit after 5 seconds for testing, unless running a set
+            // number of frames
+            if elapsed > 5.0 && args.frames.is_none() {
                 println!("Test run complete.");
                 break;
             }
         }
     }
 
+    print_profile(&machine, args.profile);
+    write_coverage(&machine, args)?;
+
+    if let Some(frames) = args.frames {
+        // A frame the run never got to has no hash to check
+        let frame_count = machine.memory.vic.frame_count();
+        if frame_count < frames {
+            anyhow::bail!("stopped after {} of {} frames: {}", frame_count, frames, stopped.unwrap_or_default());
+        }
+        let hash = machine.memory.vic.frame_hash();
+        println!("Frame {} hash: {:016x}", machine.memory.vic.frame_count(), hash);
+        if let Some(expected) = args.expect_frame_hash
+            && hash != expected
+        {
+            anyhow::bail!("frame hash {:016x} does not match expected {:016x}", hash, expected);
+        }
+    }
+
     Ok(())
 }
 
-fn run_with_ui(_debug: bool) -> Result<()> {
+fn run_with_ui(args: &Args) -> Result<()> {
     // Initialize storage
-    storage::init()?;
+    storage::init(&args.storage_dir()?)?;
 
     // Try to load ROMs
-    io::create_rom_directory_if_missing()?;
+    io::create_rom_directory_if_missing(args.rom_dir())?;
     
-    let roms_loaded = match io::RomSet::load_from_directory("roms") {
+    let roms_loaded = match io::RomSet::load_from_directory(args.rom_dir()) {
         Ok(_roms) => {
             println!("✅ ROMs loaded successfully!");
             true
@@ -183,8 +677,47 @@ fn run_with_ui(_debug: bool) -> Result<()> {
         }
     };
     
-    let mut cpu = cpu::Cpu::new();
-    let mut memory = memory::C64Memory::new();
+    let mut machine = machine::Machine::new(args.video_standard());
+    machine.cpu.hle_traps = !args.no_hle_traps;
+    machine.cpu.clock = args.host_clock.then(|| hle::HostClock::new(0));
+    for &addr in &args.breakpoints {
+        machine.cpu.add_breakpoint(addr);
+    }
+    for path in &args.labels {
+        machine.labels.load(path)?;
+    }
+    for text in &args.display {
+        machine.displays.push(debugger::expression::Expression::parse(text, &machine.labels).with_context(|| format!("--display {:?}", text))?);
+    }
+    for watch in &args.watchpoint {
+        machine.memory.watches.get_or_insert_with(debugger::watch::Watches::new).add_watchpoint(watch.clone());
+    }
+    if args.watch_banking {
+        machine.memory.watches.get_or_insert_with(debugger::watch::Watches::new).watch_banking(true);
+    }
+    machine.memory.fill_ram(args.ram_pattern.unwrap_or_default());
+    machine.memory.sid.set_model(args.sid_model.unwrap_or_default());
+    machine.memory.sid.set_digiboost(args.sid_digiboost);
+    if args.host_time.is_some() {
+        machine.set_time_of_day(host_time_of_day());
+    }
+    if args.profile.is_some() {
+        machine.profiler = Some(debugger::profiler::Profiler::new());
+    }
+    if args.coverage.is_some() || args.coverage_listing.is_some() {
+        machine.memory.coverage = Some(debugger::coverage::Coverage::new());
+    }
+    if let Some(path) = &args.cart {
+        attach_cartridge(&mut machine, path)?;
+    }
+    if let Some(path) = &args.tape {
+        insert_tape(&mut machine, path)?;
+    }
+    attach_drive(&mut machine, args)?;
+    attach_printer(&mut machine, args)?;
+    if args.swiftlink {
+        machine.memory.swiftlink = Some(rs232::Swiftlink::new(args.video_standard().clock_hz()));
+    }
     
     // Our own cursor position for direct screen writes
     let _test_cursor_col: u16 = 0;
@@ -192,13 +725,13 @@ fn run_with_ui(_debug: bool) -> Result<()> {
     
     // Load ROMs if available
     if roms_loaded {
-        let roms = io::RomSet::load_from_directory("roms")?;
-        memory.load_basic_rom(roms.basic);
-        memory.load_kernal_rom(roms.kernal);
-        memory.load_char_rom(roms.char_rom);
+        let roms = io::RomSet::load_from_directory(args.rom_dir())?;
+        machine.memory.load_basic_rom(roms.basic);
+        machine.memory.load_kernal_rom(roms.kernal);
+        machine.memory.load_char_rom(roms.char_rom);
         
         // STANDARD BOOT
-        cpu.reset(&mut memory); // Vectors from $FFFC/$FFFD ($FCE2)
+        machine.cpu.reset(&mut machine.memory); // Vectors from $FFFC/$FFFD ($FCE2)
         println!("✅ System reset. Executing KERNAL boot sequence...");
         
         // Ensure CPU interrupts are enabled in our emulator struct so we don't block them artificially
@@ -206,47 +739,252 @@ fn run_with_ui(_debug: bool) -> Result<()> {
     } else {
         // Demo mode: Write test message to screen
         use memory::Memory;
+        // 25 rows, 40 columns, as the KERNAL would set them up
+        machine.memory.write(0xD011, 0x1B);
+        machine.memory.write(0xD016, 0xC8);
         let test_msg = b"    **** COMMODORE 64 BASIC V2 ****     64K RAM SYSTEM  38911 BASIC BYTES FREE  READY.";
         for (i, &ch) in test_msg.iter().enumerate() {
             if i < 40 * 25 {
-                memory.write(0x0400 + i as u16, ch);
+                // Letters are screen codes 1-26
+                machine.memory.write(0x0400 + i as u16, ch & 0x3F);
             }
         }
         
         // Add a cursor
-        memory.write(0x0400 + 7 * 40, 0xA0);
+        machine.memory.write(0x0400 + 7 * 40, 0xA0);
+        
+        // Park the CPU in a JMP * loop so the VIC-II keeps running and
+        // fetching the screen
+        machine.memory.write(0xC000, 0x4C);
+        machine.memory.write(0xC001, 0x00);
+        machine.memory.write(0xC002, 0xC0);
+        machine.cpu.pc = 0xC000;
+    }
+    if let Some(path) = &args.load_snapshot {
+        load_snapshot(&mut machine, path)?;
     }
     
+    // Play the SID if there is somewhere to play it
+    let mut audio = match sid::audio::AudioOutput::open() {
+        Ok(mut audio) => {
+            machine.memory.sid.set_sample_rate(audio.sample_rate());
+            audio.set_volume(args.volume as f32 / 100.0);
+            Some(audio)
+        }
+        Err(e) => {
+            println!("🔇 No sound: {}", e);
+            None
+        }
+    };
+    
+    // Only debuggers on this computer can reach the stub
+    let mut gdb = args.gdb.map(|port| debugger::gdb::GdbStub::listen(("127.0.0.1", port))).transpose()?;
+    
+    let video = args.video.unwrap_or_else(ui::VideoOutput::detect);
     let mut ui = ui::TerminalUI::new()?;
     let mut running_cpu = true;  // Enable CPU by default for standard boot
     let mut _frame_count = 0;
     let mut show_debug = false;  // Hide debug info by default, toggle with F1
+    let mut warp = args.warp;    // Run unthrottled, toggle with F11
+    // The machine language monitor, kept between visits for its history
+    let mut monitor = debugger::monitor::Monitor::new();
+    let mut show_monitor = false;
+    // The hex editor, beside the running machine
+    let mut hex_editor = debugger::hex_editor::HexEditor::new();
+    let mut show_hex_editor = false;
+    let mut joysticks = [args.joystick1, args.joystick2];
+    let mut last_time_sync = std::time::Instant::now();
+    // How the last snapshot hotkey went, shown for a few seconds
+    let mut snapshot_note: Option<(String, std::time::Instant)> = None;
+    // Gamepads are looked for quietly unless asked for
+    let mut gamepads = match gamepad::Gamepads::open(args.gamepad_ports.clone().unwrap_or(vec![2, 1])) {
+        Ok(gamepads) => Some(gamepads),
+        Err(e) => {
+            if args.gamepad_ports.is_some() {
+                println!("🎮 No gamepads: {}", e);
+            }
+            None
+        }
+    };
+    
+    // In warp mode frames are emulated back to back, so only redraw the
+    // terminal a few times a second rather than once per emulated frame
+    const WARP_RENDER_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+    let mut last_render: Option<std::time::Instant> = None;
+    // Where the C64 screen was last drawn, for mapping mouse clicks
+    let mut screen_area = Default::default();
+    let mut screen_cache = ui::ScreenCache::new();
+    let mut pacer = FramePacer::new();
+    const SNAPSHOT_NOTE_TIME: std::time::Duration = std::time::Duration::from_secs(3);
     
     'mainloop: loop {
         // Render the screen
-        ui.render(|frame| {
-            if show_debug {
-                let (title_area, screen_area, status_area) = ui::create_layout(frame.size());
-                ui::render_title_bar(frame, title_area);
-                use memory::Memory;
-                ui::render_c64_screen(frame, screen_area, &memory.vic, &memory as &dyn Memory);
-                ui::render_status_bar(frame, status_area, &cpu);
-            } else {
-                // Simple layout without debug info
-                let (screen_area, status_area) = ui::create_simple_layout(frame.size());
-                use memory::Memory;
-                ui::render_c64_screen(frame, screen_area, &memory.vic, &memory as &dyn Memory);
-                ui::render_simple_status(frame, status_area);
-            }
-        })?;
+        let render_due = !warp || last_render.is_none_or(|t| t.elapsed() >= WARP_RENDER_INTERVAL);
+        if render_due {
+            last_render = Some(std::time::Instant::now());
+            screen_cache.mark_dirty(&machine.memory.vic.take_dirty_lines());
+            let muted = audio.as_ref().is_some_and(|audio| audio.is_muted());
+            let notes: Vec<String> = ui::sound_status(muted, &machine.memory.sid)
+                .into_iter()
+                .chain(ui::joystick_status(joysticks))
+                .chain(gamepads.as_ref().and_then(|gamepads| ui::gamepad_status(&gamepads.ports())))
+                .chain(ui::drive_status(&mut machine.memory))
+                .chain(snapshot_note.as_ref().filter(|(_, at)| at.elapsed() < SNAPSHOT_NOTE_TIME).map(|(note, _)| note.clone()))
+                .collect();
+            ui.render(|frame| {
+                if show_monitor {
+                    let (area, monitor_area, status_area) = ui::create_panel_layout(frame.size(), ui::monitor::MONITOR_WIDTH);
+                    screen_area = area;
+                    use memory::Memory;
+                    ui::render_screen(frame, screen_area, video, &machine.memory.vic, &machine.memory as &dyn Memory, &mut screen_cache);
+                    ui::monitor::render(frame, monitor_area, &monitor, machine.cpu.pc);
+                    ui::render_simple_status(frame, status_area, &machine.cpu, warp, &notes);
+                } else if show_hex_editor {
+                    let (area, editor_area, status_area) = ui::create_panel_layout(frame.size(), ui::hex_editor::HEX_EDITOR_WIDTH);
+                    screen_area = area;
+                    use memory::Memory;
+                    ui::render_screen(frame, screen_area, video, &machine.memory.vic, &machine.memory as &dyn Memory, &mut screen_cache);
+                    ui::hex_editor::render(frame, editor_area, &mut hex_editor, &machine.memory as &dyn Memory, &machine.labels);
+                    ui::render_simple_status(frame, status_area, &machine.cpu, warp, &notes);
+                } else if show_debug {
+                    let (title_area, area, inspector_area, stack_area, displays_area, status_area) = ui::create_layout(frame.size(), machine.displays.len());
+                    screen_area = area;
+                    ui::render_title_bar(frame, title_area);
+                    use memory::Memory;
+                    ui::render_screen(frame, screen_area, video, &machine.memory.vic, &machine.memory as &dyn Memory, &mut screen_cache);
+                    ui::inspector::render(frame, inspector_area, &machine.memory.vic);
+                    ui::stack::render(frame, stack_area, &machine.memory as &dyn Memory, machine.cpu.sp, &machine.labels);
+                    ui::displays::render(frame, displays_area, &machine.displays, &machine.cpu, &machine.memory as &dyn Memory);
+                    ui::render_status_bar(frame, status_area, &machine.cpu, &machine.memory as &dyn Memory, &notes);
+                } else {
+                    // Simple layout without debug info
+                    let (area, status_area) = ui::create_simple_layout(frame.size());
+                    screen_area = area;
+                    use memory::Memory;
+                    ui::render_screen(frame, screen_area, video, &machine.memory.vic, &machine.memory as &dyn Memory, &mut screen_cache);
+                    ui::render_simple_status(frame, status_area, &machine.cpu, warp, &notes);
+                }
+            })?;
+            ui.draw_image(screen_area, video, machine.memory.vic.framebuffer())?;
+        }
         
         // Handle input
-        while let Some(key) = ui.poll_event()? {
+        while let Some(event) = ui.poll_event()? {
+            let key = match event {
+                Event::Key(key) => key,
+                Event::Mouse(mouse) => {
+                    // The left mouse button holds the light pen to the screen
+                    match mouse.kind {
+                        MouseEventKind::Down(MouseButton::Left) | MouseEventKind::Drag(MouseButton::Left) => {
+                            let position = match video {
+                                ui::VideoOutput::Text => ui::light_pen_position(screen_area, mouse.column, mouse.row),
+                                ui::VideoOutput::HalfBlock => ui::halfblock::pixel_position(screen_area, mouse.column, mouse.row),
+                                _ => ui.image_pixel(mouse.column, mouse.row),
+                            };
+                            machine.memory.vic.set_light_pen(position);
+                        }
+                        MouseEventKind::Up(MouseButton::Left) => machine.memory.vic.set_light_pen(None),
+                        _ => {}
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            // The monitor has the keyboard while it is open, and leaving it
+            // sets the machine going again
+            if show_monitor {
+                let leave = match key.code {
+                    KeyCode::Esc => true,
+                    KeyCode::Char('m') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => true,
+                    KeyCode::Enter => monitor.enter(&mut machine),
+                    KeyCode::Backspace => {
+                        monitor.backspace();
+                        false
+                    }
+                    KeyCode::Up | KeyCode::Down => {
+                        monitor.recall(key.code == KeyCode::Up);
+                        false
+                    }
+                    KeyCode::Char(c) => {
+                        monitor.type_char(c);
+                        false
+                    }
+                    _ => false,
+                };
+                if leave {
+                    show_monitor = false;
+                    running_cpu = true;
+                }
+                continue;
+            }
+            // So has the hex editor, but for the function keys and the
+            // other Alt hotkeys, and the machine keeps running
+            if show_hex_editor {
+                use crossterm::event::KeyModifiers;
+                let alt = key.modifiers.contains(KeyModifiers::ALT);
+                let handled = match key.code {
+                    KeyCode::Char('e') if alt => {
+                        show_hex_editor = false;
+                        true
+                    }
+                    KeyCode::Esc => {
+                        show_hex_editor = hex_editor.cancel();
+                        true
+                    }
+                    KeyCode::Left => {
+                        hex_editor.move_cursor(-1);
+                        true
+                    }
+                    KeyCode::Right => {
+                        hex_editor.move_cursor(1);
+                        true
+                    }
+                    KeyCode::Up => {
+                        hex_editor.move_cursor(-(debugger::hex_editor::ROW_WIDTH as i32));
+                        true
+                    }
+                    KeyCode::Down => {
+                        hex_editor.move_cursor(debugger::hex_editor::ROW_WIDTH as i32);
+                        true
+                    }
+                    KeyCode::PageUp | KeyCode::PageDown => {
+                        hex_editor.page(key.code == KeyCode::PageDown);
+                        true
+                    }
+                    KeyCode::Enter => {
+                        hex_editor.enter(&machine.labels);
+                        true
+                    }
+                    KeyCode::Backspace => {
+                        hex_editor.backspace();
+                        true
+                    }
+                    KeyCode::Char(c) if !alt => {
+                        hex_editor.type_char(c, &mut machine.memory);
+                        true
+                    }
+                    _ => false,
+                };
+                if handled {
+                    continue;
+                }
+            }
             match key.code {
                 KeyCode::Esc => {
                     // Quit the emulator
                     break 'mainloop;
                 },
+                KeyCode::F(n @ (2 | 4 | 6)) => {
+                    // The unused even function keys switch voices 1-3 on
+                    // and off
+                    machine.memory.sid.toggle_voice_mute((n / 2 - 1) as usize);
+                }
+                KeyCode::F(8) => {
+                    // Mute/unmute the sound
+                    if let Some(audio) = &mut audio {
+                        audio.toggle_mute();
+                    }
+                }
                 KeyCode::F(9) => {
                     // Toggle debug view
                     show_debug = !show_debug;
@@ -255,21 +993,72 @@ fn run_with_ui(_debug: bool) -> Result<()> {
                     // Enable/toggle CPU execution
                     running_cpu = !running_cpu;
                 }
+                KeyCode::F(11) => {
+                    // Toggle warp mode
+                    warp = !warp;
+                }
+                KeyCode::F(12) => {
+                    // SHIFT + C= switches between the uppercase/graphics and
+                    // lowercase character sets
+                    machine.memory.cia1.set_key(1, 7, true);
+                    machine.memory.cia1.set_key(7, 5, true);
+                }
                 KeyCode::PageUp => {
                     // RESTORE key simulation (NMI)
-                    cpu.nmi(&mut memory);
+                    machine.set_restore_key(true);
+                }
+                KeyCode::PageDown => {
+                    // A freezer cartridge's freeze button
+                    machine.memory.freeze();
                 }
                 KeyCode::Tab => {
                      // Explicitly handle Tab as Run/Stop for clarity, though map_key handles it too
                      // This ensures it gets registered if map_key is missed or we want debug logic
-                     memory.cia1.set_key(7, 7, true); 
+                     machine.memory.cia1.set_key(7, 7, true); 
+                }
+                KeyCode::Char('j') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                    // Swap the joystick ports, for games that use the
+                    // other one
+                    joysticks.swap(0, 1);
+                }
+                KeyCode::Char('w') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                    // Put the write protect tab on drive 8's disk, or take
+                    // it off
+                    use memory::Memory;
+                    if let Some(drive) = machine.memory.channels(8) {
+                        let on = !drive.write_protected();
+                        drive.set_write_protected(on);
+                    }
+                }
+                KeyCode::Char('m') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                    // Pause in the machine language monitor
+                    running_cpu = false;
+                    show_monitor = true;
+                    monitor.open(&machine);
+                }
+                KeyCode::Char('e') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                    // Look through and edit memory as the machine runs
+                    show_hex_editor = true;
+                }
+                KeyCode::Char('s') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                    // Save the whole machine, to pick up from later
+                    let result = snapshot::quick_path().and_then(|path| snapshot::save(&mut machine, &path));
+                    snapshot_note = Some((snapshot_status("SAVED", result), std::time::Instant::now()));
+                }
+                KeyCode::Char('l') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                    // Go back to the last saved snapshot
+                    let result = snapshot::quick_path().and_then(|path| snapshot::load(&mut machine, &path));
+                    snapshot_note = Some((snapshot_status("LOADED", result), std::time::Instant::now()));
                 }
                 _ => {
-                    // Map terminal key to C64 keyboard matrix
-                    if let Some(positions) = keyboard::map_key(key.code) {
+                    // Keys mapped to a joystick work it rather than the
+                    // keyboard; the rest map to the C64 keyboard matrix
+                    if let Some((port, switches)) = keyboard::joystick::map_key(joysticks, key.code) {
+                        machine.memory.cia1.set_joystick(port, switches);
+                    } else if let Some(positions) = keyboard::map_key(key.code) {
                         // Set in CIA matrix - BASIC will read via our intercepted GETIN
                         for (row, col) in positions {
-                            memory.cia1.set_key(row, col, true);
+                            machine.memory.cia1.set_key(row, col, true);
                         }
 
                         // Handle modifiers (SHIFT)
@@ -277,65 +1066,259 @@ fn run_with_ui(_debug: bool) -> Result<()> {
                         // This enables SHIFT+HOME (Clear Screen) and other combos
                         use crossterm::event::KeyModifiers;
                         if key.modifiers.contains(KeyModifiers::SHIFT) {
-                            memory.cia1.set_key(1, 7, true);
+                            machine.memory.cia1.set_key(1, 7, true);
                         }
                     }
                 }
             }
         }
         
+        if let Some(gamepads) = &mut gamepads {
+            gamepads.poll(&mut machine.memory);
+        }
+        if args.host_time == Some(HostTime::Track) && last_time_sync.elapsed() >= std::time::Duration::from_secs(1) {
+            last_time_sync = std::time::Instant::now();
+            machine.set_time_of_day(host_time_of_day());
+        }
+        
+        // A debugger stops the machine when it connects or breaks in, and
+        // takes it from the monitor when it goes on
+        match gdb.as_mut().and_then(|gdb| gdb.poll(&mut machine)) {
+            Some(debugger::gdb::Request::Stop) => running_cpu = false,
+            Some(debugger::gdb::Request::Go) => {
+                running_cpu = true;
+                show_monitor = false;
+            }
+            None => {}
+        }
+        
         // Execute CPU cycles if ROMs are loaded
         if running_cpu {
             use memory::Memory;
-            // C64 runs at ~985,248 Hz (PAL) ≈ 1MHz
-            // At 60fps: 985248 / 60 ≈ 16,420 cycles per frame
-            // Execute cycles and trigger IRQ once per frame
-            const CYCLES_PER_FRAME: u64 = 16420;
-            
-            let cycles_this_frame = CYCLES_PER_FRAME;
-            
-            let mut executed_cycles = 0;
-            while executed_cycles < cycles_this_frame {
-                // Execute one CPU instruction
-                match cpu.step(&mut memory) {
-                    Ok(cycles) => {
-                        executed_cycles += cycles as u64;
-                        // Tick CIA timers based on actual cycles executed
-                        let irq1 = memory.cia1.tick(cycles);
-                        let irq2 = memory.cia2.tick(cycles);
-                        
-                        // Tick VIC-II (raster beam)
-                        let irq_vic = memory.vic.tick(cycles);
-                        
-                        // Connect CIA IRQs to CPU
-                        if irq1 || irq2 || irq_vic {
-                            cpu.irq(&mut memory);
+            // Run until the VIC-II completes a frame: 312 * 63 = 19,656
+            // cycles at 985,248 Hz (PAL, 50fps) or 263 * 65 = 17,095 at
+            // 1,022,727 Hz (NTSC, 60fps)
+            while !machine.memory.vic.take_frame_complete() {
+                // Execute one CPU instruction; the machine ticks the CIAs and
+                // VIC-II and feeds their interrupt lines back to the CPU
+                match machine.step() {
+                    Ok(StepResult::Executed(_)) => {
+                        // Back to the monitor at the end of a step over,
+                        // step out or run to an address
+                        if monitor.run_ended(&machine) {
+                            running_cpu = false;
+                            show_monitor = true;
+                            monitor.open(&machine);
+                            break;
+                        }
+                    }
+                    Ok(StepResult::BreakpointHit(_)) => {
+                        // Pause in the monitor, unless a debugger is
+                        // waiting; going on executes the instruction
+                        running_cpu = false;
+                        if !gdb.as_mut().is_some_and(|gdb| gdb.stopped(debugger::gdb::StopReason::Breakpoint)) {
+                            show_monitor = true;
+                            monitor.open_at_breakpoint(&machine);
+                        }
+                        break;
+                    }
+                    Ok(StepResult::WatchpointHit { pc, event, .. }) => {
+                        running_cpu = false;
+                        if !gdb.as_mut().is_some_and(|gdb| gdb.stopped(debugger::gdb::StopReason::Watchpoint(event))) {
+                            show_monitor = true;
+                            monitor.open_at_watchpoint(&machine, pc, event);
                         }
-                    },
+                        break;
+                    }
                     Err(e) => {
                         // Hit unimplemented opcode or error
+                        let cpu = &machine.cpu;
                         eprintln!("CPU Error: {} at PC=${:04X}", e, cpu.pc.wrapping_sub(1));
                         // Print some context
                         eprintln!("  A=${:02X} X=${:02X} Y=${:02X} SP=${:02X}", cpu.a, cpu.x, cpu.y, cpu.sp);
                         let prev_pc = cpu.pc.wrapping_sub(1);
-                        eprintln!("  Memory at PC-1: ${:02X}", memory.read(prev_pc));
+                        eprintln!("  Memory at PC-1: ${:02X}", machine.memory.peek(prev_pc));
                         running_cpu = false;
+                        if let Some(gdb) = &mut gdb {
+                            gdb.stopped(debugger::gdb::StopReason::Error);
+                        }
                         break;
                     }
                 }
             }
         }
         
+        if let Some(audio) = &audio {
+            audio.queue(&machine.memory.sid.take_samples());
+        }
+        
         // Update jiffy clock once per frame (not using IRQs)
         // if roms_loaded { ... } logic removed until IRQs are working
         
         // Clear keyboard after each frame (keys only pressed for ~16ms)
         // This simulates key press/release and allows KERNAL to detect keypresses
-        memory.cia1.clear_keyboard();
+        machine.memory.cia1.clear_keyboard();
+        machine.set_restore_key(false);
         
-        // Slow down to ~60 FPS
-        std::thread::sleep(std::time::Duration::from_millis(16));
+        // Slow down to the machine's frame rate unless warping (a paused
+        // machine has nothing to warp through, so don't spin the host CPU).
+        // A paused machine makes no sound to be paced by either.
+        if !warp || !running_cpu {
+            let audio = audio.as_ref().filter(|_| running_cpu);
+            pacer.wait(audio, machine.standard.frame_duration());
+        }
     }
     
+    // Restore the terminal before printing the report
+    drop(ui);
+    print_profile(&machine, args.profile);
+    write_coverage(&machine, args)?;
+    
+    Ok(())
+}
+
+fn run_player(args: &Args, path: &std::path::Path) -> Result<()> {
+    let file = sid::file::SidFile::load(path)?;
+
+    // The file's clock and chip, unless overridden
+    let standard = if args.ntsc || args.pal { args.video_standard() } else { file.standard.unwrap_or_default() };
+    let model = args.sid_model.or(file.model).unwrap_or_default();
+    let mut machine = machine::Machine::new(standard);
+    machine.memory.sid.set_model(model);
+    machine.memory.sid.set_digiboost(args.sid_digiboost);
+
+    // Tunes that play from an interrupt go through the KERNAL's handler, so
+    // boot it first when the ROMs are there
+    match io::RomSet::load_from_directory(args.rom_dir()) {
+        Ok(roms) => {
+            machine.memory.load_basic_rom(roms.basic);
+            machine.memory.load_kernal_rom(roms.kernal);
+            machine.memory.load_char_rom(roms.char_rom);
+            machine.cpu.reset(&mut machine.memory);
+            const BOOT_FRAMES: u32 = 150;
+            for _ in 0..BOOT_FRAMES {
+                while !machine.memory.vic.take_frame_complete() {
+                    machine.step()?;
+                }
+            }
+        }
+        Err(e) if file.rsid || file.play_address == 0 => {
+            anyhow::bail!("this tune needs the KERNAL ROM: {}", e);
+        }
+        Err(_) => {}
+    }
+
+    let mut player = player::Player::new(file, &mut machine)?;
+    let mut audio = match sid::audio::AudioOutput::open() {
+        Ok(mut audio) => {
+            machine.memory.sid.set_sample_rate(audio.sample_rate());
+            audio.set_volume(args.volume as f32 / 100.0);
+            Some(audio)
+        }
+        Err(e) => {
+            println!("🔇 No sound: {}", e);
+            None
+        }
+    };
+
+    let mut ui = ui::TerminalUI::new()?;
+    let mut pacer = FramePacer::new();
+    'mainloop: loop {
+        ui.render(|frame| ui::player::render(frame, &player, &machine, model, audio.as_ref()))?;
+
+        while let Some(event) = ui.poll_event()? {
+            let Event::Key(key) = event else { continue };
+            let song = match key.code {
+                KeyCode::Esc => break 'mainloop,
+                KeyCode::F(n @ (2 | 4 | 6)) => {
+                    machine.memory.sid.toggle_voice_mute((n / 2 - 1) as usize);
+                    continue;
+                }
+                KeyCode::F(8) => {
+                    if let Some(audio) = &mut audio {
+                        audio.toggle_mute();
+                    }
+                    continue;
+                }
+                KeyCode::Left | KeyCode::Char('-') => player.song.saturating_sub(1),
+                KeyCode::Right | KeyCode::Char('+') => player.song + 1,
+                KeyCode::Char(c @ '1'..='9') => c as u16 - '0' as u16,
+                _ => continue,
+            };
+            if (1..=player.file.songs).contains(&song) && song != player.song {
+                player.select(&mut machine, song);
+            }
+        }
+
+        player.run_frame(&mut machine);
+        if let Some(audio) = &audio {
+            audio.queue(&machine.memory.sid.take_samples());
+        }
+        pacer.wait(audio.as_ref(), machine.standard.frame_duration());
+    }
     Ok(())
 }
+
+// Local time on the host, since midnight
+fn host_time_of_day() -> std::time::Duration {
+    use chrono::Timelike;
+    let now = chrono::Local::now().time();
+    // A leap second shows as a nanosecond count past 1e9
+    std::time::Duration::new(now.num_seconds_from_midnight() as u64, now.nanosecond().min(999_999_999))
+}
+
+// Holds emulation to real time, one frame at a time. With sound the audio
+// device's appetite for samples sets the pace, so the SID never drifts ahead
+// of or behind what is playing; without it frames follow the host clock.
+struct FramePacer {
+    next_frame: std::time::Instant,
+}
+
+impl FramePacer {
+    // Further behind than this and the pacer gives up catching up, rather
+    // than running flat out after the host has stalled
+    const MAX_LAG: std::time::Duration = std::time::Duration::from_millis(100);
+
+    fn new() -> Self {
+        Self { next_frame: std::time::Instant::now() }
+    }
+
+    fn wait(&mut self, audio: Option<&sid::audio::AudioOutput>, frame_duration: std::time::Duration) {
+        if let Some(audio) = audio {
+            audio.wait_for_room();
+            self.next_frame = std::time::Instant::now();
+            return;
+        }
+        // Sleep to a deadline that advances a frame at a time, so the time
+        // spent emulating and drawing doesn't add up to a slow machine
+        self.next_frame += frame_duration;
+        let now = std::time::Instant::now();
+        if self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > Self::MAX_LAG {
+            self.next_frame = now;
+        }
+    }
+}
+
+// Write the reports --coverage and --coverage-listing asked for
+fn write_coverage(machine: &machine::Machine, args: &Args) -> Result<()> {
+    let Some(coverage) = &machine.memory.coverage else {
+        return Ok(());
+    };
+    if let Some(path) = &args.coverage {
+        std::fs::write(path, coverage.report(&machine.labels)).with_context(|| format!("writing coverage to {}", path.display()))?;
+    }
+    if let Some(path) = &args.coverage_listing {
+        use memory::Memory;
+        let listing = coverage.listing(&machine.memory as &dyn Memory, &machine.labels);
+        std::fs::write(path, listing).with_context(|| format!("writing coverage to {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn print_profile(machine: &machine::Machine, top_n: Option<usize>) {
+    if let (Some(profiler), Some(top_n)) = (&machine.profiler, top_n) {
+        print!("{}", profiler.report(&machine.memory, top_n));
+    }
+}
@@ -1,13 +1,43 @@
 // Memory interface for C64
 
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::debugger::coverage::Coverage;
+use crate::debugger::watch::{Access, BusEvent, Watches};
+
 pub trait Memory {
-    fn read(&self, addr: u16) -> u8;
+    // A CPU bus read. Takes &mut because some I/O registers change state when
+    // read (e.g. the CIA ICR clears, acknowledging its interrupt).
+    fn read(&mut self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, value: u8);
     
-    // For VIC-II access
-    // fn read_vic(&self, addr: u16) -> u8 {
-    //    self.read(addr)
-    // }
+    // Look at an address without side effects, for the UI and debugger
+    fn peek(&self, addr: u16) -> u8;
+    
+    // True when the KERNAL ROM is mapped in at $E000-$FFFF. The CPU's HLE
+    // traps on KERNAL entry points only fire while this holds.
+    fn kernal_rom_visible(&self) -> bool {
+        false
+    }
+    
+    // The VIC-II's view of memory, used for its screen, character and sprite
+    // fetches. It never sees the BASIC/KERNAL ROMs or I/O, only RAM and the
+    // character ROM.
+    fn read_vic(&self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    // The files behind a KERNAL device number, for the HLE traps to reach
+    // directly: the serial bus devices, and RS-232 through a Swiftlink
+    fn channels(&mut self, _device: u8) -> Option<&mut dyn crate::iec::device::Channels> {
+        None
+    }
+
+    // The tape in the datasette, for the HLE traps' LOAD and SAVE
+    fn tape(&mut self) -> Option<&mut crate::tape::Tape> {
+        None
+    }
 }
 
 // C64 Memory Map:
@@ -22,372 +52,641 @@ pub trait Memory {
 
 const RAM_SIZE: usize = 0x10000; // 64KB
 
+// What RAM holds at power on. DRAM comes up in whatever state its cells
+// settle in, which on most C64s is the same striped pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum RamPattern {
+    // 64 bytes of $00, then 64 of $FF, and so on
+    #[default]
+    Stripes,
+    Zero,
+}
+
+impl RamPattern {
+    fn byte(self, addr: usize) -> u8 {
+        match self {
+            RamPattern::Stripes if addr & 0x40 != 0 => 0xFF,
+            _ => 0x00,
+        }
+    }
+}
+
+// What the CPU sees in one 4K page of the address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Bank {
+    #[default]
+    Ram,
+    Basic,
+    Kernal,
+    CharRom,
+    Io,
+    RomL, // Cartridge ROM at $8000, or the low half of a 16K cartridge
+    RomH, // Cartridge ROM at $A000, or at $E000 in Ultimax mode
+    Unmapped, // Nothing answers: Ultimax mode leaves most of the map open
+}
+
+impl Bank {
+    fn name(self) -> &'static str {
+        match self {
+            Bank::Ram => "RAM",
+            Bank::Basic => "BASIC",
+            Bank::Kernal => "KERNAL",
+            Bank::CharRom => "CHAR",
+            Bank::Io => "I/O",
+            Bank::RomL => "ROML",
+            Bank::RomH => "ROMH",
+            Bank::Unmapped => "-",
+        }
+    }
+}
+
+// The banking as the CPU sees it, for the debugger to stop when it
+// changes: LORAM, HIRAM and CHAREN, the cartridge's lines and bank, and
+// the map they make
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Banking {
+    lines: u8,
+    cartridge: Option<(bool, bool, usize)>,
+    map: [Bank; 16],
+}
+
+impl std::fmt::Display for Banking {
+    // e.g. "%111 8:RAM A:BASIC D:I/O E:KERNAL", what is at the places that
+    // can change, then any cartridge's EXROM, GAME and bank
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "%{:03b}", self.lines)?;
+        for page in [0x8, 0xA, 0xD, 0xE] {
+            write!(f, " {:X}:{}", page, self.map[page].name())?;
+        }
+        if let Some((exrom, game, bank)) = self.cartridge {
+            write!(f, " EXROM {} GAME {} bank {}", exrom as u8, game as u8, bank)?;
+        }
+        Ok(())
+    }
+}
+
+// The PLA's memory map for LORAM, HIRAM and CHAREN ($01 bits 0-2) and the
+// cartridge port's EXROM and GAME lines, true for a high level. Writes to a
+// ROM go to the RAM underneath it, except for the cartridge's in Ultimax
+// mode.
+fn pla_map(loram: bool, hiram: bool, charen: bool, exrom: bool, game: bool) -> [Bank; 16] {
+    let mut map = [Bank::Ram; 16];
+    if exrom && !game {
+        // Ultimax: only the first 4K of RAM, the cartridge and I/O remain,
+        // whatever $01 holds
+        map[0x1..=0x7].fill(Bank::Unmapped);
+        map[0x8..=0x9].fill(Bank::RomL);
+        map[0xA..=0xC].fill(Bank::Unmapped);
+        map[0xD] = Bank::Io;
+        map[0xE..=0xF].fill(Bank::RomH);
+        return map;
+    }
+
+    if loram && hiram && !exrom {
+        map[0x8..=0x9].fill(Bank::RomL);
+    }
+    if loram && hiram && game {
+        map[0xA..=0xB].fill(Bank::Basic);
+    } else if hiram && !game {
+        map[0xA..=0xB].fill(Bank::RomH);
+    }
+    // A 16K cartridge with only LORAM set keeps I/O but not the character ROM
+    if charen && (loram || hiram) {
+        map[0xD] = Bank::Io;
+    } else if !charen && (hiram || (loram && game)) {
+        map[0xD] = Bank::CharRom;
+    }
+    if hiram {
+        map[0xE..=0xF].fill(Bank::Kernal);
+    }
+    map
+}
+
+// What a 256-byte page of the CPU's address space reads and writes, worked
+// out from the PLA map (and which ROMs are loaded) whenever it changes, so
+// that an access takes one table lookup to find its way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Page {
+    Ram,
+    Rom,      // BASIC, KERNAL or character ROM; writes go to the RAM under it
+    Port,     // Zero page: RAM, apart from the CPU port at $00/$01
+    RomL,
+    RomH,
+    Unmapped,
+    Vic,
+    Sid,
+    ColorRam,
+    Cia1,
+    Cia2,
+    Expansion, // I/O 1 and 2 ($DE00-$DFFF), for the cartridge
+    Vectors,   // $FF00-$FFFF while a freezer's NMI waits to be taken
+}
+
+fn ram_pages() -> [Page; 256] {
+    [Page::Ram; 256]
+}
+
+fn blank_rom() -> Box<[u8; RAM_SIZE]> {
+    Box::new([0xFF; RAM_SIZE])
+}
+
+// The VIC-II's view of the bus: always RAM, except for the character ROM
+// at $1000-$1FFF and $9000-$9FFF. It never sees BASIC/KERNAL ROM or I/O,
+// whatever the CPU banking in $01. The VIC only drives 14 address lines;
+// CIA2 port A supplies the top two, which the addresses here include.
+pub struct VicBus<'a> {
+    ram: &'a [u8],
+    char_rom: Option<&'a [u8]>,
+    // Ultimax mode: the cartridge's ROMH takes the character ROM's place,
+    // at $3000-$3FFF of every bank
+    romh: Option<&'a [u8]>,
+}
+
+impl<'a> VicBus<'a> {
+    pub fn new(ram: &'a [u8], char_rom: Option<&'a [u8]>) -> Self {
+        Self { ram, char_rom, romh: None }
+    }
+
+    pub fn ultimax(ram: &'a [u8], romh: Option<&'a [u8]>) -> Self {
+        Self { ram, char_rom: None, romh }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match (addr, self.char_rom, self.romh) {
+            (_, _, Some(rom)) if addr & 0x3000 == 0x3000 => rom[(addr & 0x1FFF) as usize],
+            (0x1000..=0x1FFF | 0x9000..=0x9FFF, Some(rom), _) => rom[(addr & 0x0FFF) as usize],
+            _ => self.ram[addr as usize],
+        }
+    }
+}
+
+fn vic_bus<'a>(ram: &'a [u8], char_rom: Option<&'a [u8]>, cartridge: Option<&'a Cartridge>, ultimax: bool) -> VicBus<'a> {
+    if ultimax {
+        VicBus::ultimax(ram, cartridge.and_then(Cartridge::romh))
+    } else {
+        VicBus::new(ram, char_rom)
+    }
+}
+
+// Serializable for snapshots, with every chip on the bus. The ROMs are the
+// host's, and stay as they are when a snapshot is loaded.
+#[derive(Serialize, Deserialize)]
 pub struct C64Memory {
-    ram: [u8; RAM_SIZE],
-    basic_rom: Option<Vec<u8>>,    // $A000-$BFFF
-    kernal_rom: Option<Vec<u8>>,   // $E000-$FFFF
-    char_rom: Option<Vec<u8>>,     // $D000-$DFFF
+    #[serde(with = "crate::snapshot::boxed_byte_array")]
+    ram: Box<[u8; RAM_SIZE]>,
+    // The ROMs, each at the addresses the CPU sees it at: BASIC at $A000,
+    // the character ROM at $D000 and the KERNAL at $E000
+    #[serde(skip, default = "blank_rom")]
+    rom: Box<[u8; RAM_SIZE]>,
+    #[serde(skip)]
+    basic_loaded: bool,
+    #[serde(skip)]
+    kernal_loaded: bool,
+    #[serde(skip)]
+    char_loaded: bool,
+    
+    // Cartridge on the expansion port, and the levels of its EXROM and
+    // GAME lines (true when nothing pulls them low)
+    cartridge: Option<Cartridge>,
+    exrom: bool,
+    game: bool,
     
     // Memory banking control
     port_0000: u8, // Data direction register
     port_0001: u8, // Data port (controls memory banking)
+    // What each 4K bank maps to, worked out again when $00, $01 or the
+    // cartridge lines change, and the page table that follows from it
+    #[serde(skip)]
+    map: [Bank; 16],
+    #[serde(skip, default = "ram_pages")]
+    pages: [Page; 256],
+    
+    // The last byte on the CPU's data bus, which lingers on the lines a
+    // chip doesn't drive. The VIC's fetches in the other half of each
+    // cycle would replace it on real hardware, but aren't modelled.
+    data_bus: u8,
     
     // VIC-II chip reference
     pub vic: crate::vic::VicII,
     
+    // SID sound chip ($D400-$D7FF)
+    pub sid: crate::sid::Sid,
+    
     // CIA chips
     pub cia1: crate::cia::Cia, // $DC00-$DCFF
     pub cia2: crate::cia::Cia, // $DD00-$DDFF
+    
+    // Serial bus, on CIA2 port A
+    pub iec: crate::iec::IecBus,
+    
+    // The tape in the datasette, which like disks stays out of snapshots
+    #[serde(skip)]
+    pub tape: Option<crate::tape::Tape>,
+    
+    // A Swiftlink RS-232 cartridge in I/O 1 ($DE00), whose line is the
+    // host's
+    #[serde(skip)]
+    pub swiftlink: Option<crate::rs232::Swiftlink>,
+    
+    // Interrupt lines to the CPU, driven by the chips above
+    pub irq: crate::interrupt::InterruptLine,
+    pub nmi: crate::interrupt::InterruptLine,
+    
+    // Opt-in bus watches and watchpoints (--watch, --watchpoint, the
+    // monitor's watch), which see every CPU read and write
+    #[serde(skip)]
+    pub watches: Option<Watches>,
+    // Opt-in code coverage (--coverage), which sees them too
+    #[serde(skip)]
+    pub coverage: Option<Coverage>,
 }
 
 impl C64Memory {
     pub fn new() -> Self {
         let mut mem = Self {
-            ram: [0; RAM_SIZE],
-            basic_rom: None,
-            kernal_rom: None,
-            char_rom: None,
+            ram: Box::new([0; RAM_SIZE]),
+            rom: blank_rom(),
+            basic_loaded: false,
+            kernal_loaded: false,
+            char_loaded: false,
+            cartridge: None,
+            exrom: true,
+            game: true,
             port_0000: 0xFF,
             port_0001: 0x37, // Default: BASIC+KERNAL visible, I/O visible
+            map: [Bank::Ram; 16],
+            pages: ram_pages(),
+            data_bus: 0xFF,
             vic: crate::vic::VicII::new(),
+            sid: crate::sid::Sid::new(),
             cia1: crate::cia::Cia::new(),
             cia2: crate::cia::Cia::new(),
+            iec: crate::iec::IecBus::new(),
+            tape: None,
+            swiftlink: None,
+            irq: crate::interrupt::InterruptLine::new(),
+            nmi: crate::interrupt::InterruptLine::new(),
+            watches: None,
+            coverage: None,
         };
         
-        // Initialize default reset vector to point to $FCE2 (KERNAL cold start)
-        mem.ram[0xFFFC] = 0xE2;
-        mem.ram[0xFFFD] = 0xFC;
+        mem.fill_ram(RamPattern::default());
+        mem.update_map();
         
         mem
     }
     
+    // Power-on RAM contents, before anything is loaded
+    pub fn fill_ram(&mut self, pattern: RamPattern) {
+        for (addr, byte) in self.ram.iter_mut().enumerate() {
+            *byte = pattern.byte(addr);
+        }
+        // Initialize default reset vector to point to $FCE2 (KERNAL cold start)
+        self.ram[0xFFFC] = 0xE2;
+        self.ram[0xFFFD] = 0xFC;
+    }
+    
     pub fn load_basic_rom(&mut self, data: Vec<u8>) {
         if data.len() == 0x2000 {
-            self.basic_rom = Some(data);
+            self.rom[0xA000..0xC000].copy_from_slice(&data);
+            self.basic_loaded = true;
+            self.update_map();
         }
     }
     
     pub fn load_kernal_rom(&mut self, data: Vec<u8>) {
         if data.len() == 0x2000 {
-            self.kernal_rom = Some(data);
+            self.rom[0xE000..].copy_from_slice(&data);
+            self.kernal_loaded = true;
+            self.update_map();
         }
     }
     
     pub fn load_char_rom(&mut self, data: Vec<u8>) {
         if data.len() == 0x1000 {
-            self.char_rom = Some(data);
+            self.rom[0xD000..0xE000].copy_from_slice(&data);
+            self.char_loaded = true;
+            self.update_map();
         }
     }
     
-    fn is_basic_visible(&self) -> bool {
-        // BASIC ROM visible when bits 0 and 1 are both 1
-        (self.port_0001 & 0x03) == 0x03
-    }
-    
-    fn is_kernal_visible(&self) -> bool {
-        // KERNAL ROM visible when bit 1 is 1
-        (self.port_0001 & 0x02) != 0
-    }
-    
-    fn is_io_visible(&self) -> bool {
-        // I/O visible when bit 2 is 1 and bit 1 is 1
-        (self.port_0001 & 0x07) >= 0x05
-    }
-    
-    fn is_char_rom_visible(&self) -> bool {
-        // CHAR ROM visible when bits 0-2 are specific values
-        let bits = self.port_0001 & 0x07;
-        bits == 0x01 || bits == 0x03
-    }
-    
-    // /// Scan keyboard and update keyboard buffer (simulates KERNAL keyboard scanner)
-    // /// This should be called ~60 times per second (during IRQ handling)
-    // pub fn scan_keyboard(&mut self) {
-    //     // Keyboard buffer is at $0277-$0280 (10 bytes)
-    //     // $C6 = number of characters in buffer (NDX)
-    //     let buffer_len = self.ram[0xC6] as usize;
-    //     
-    //     // Don't overflow buffer
-    //     if buffer_len >= 10 {
-    //         return;
-    //     }
-    //     
-    //     // Scan all 8 rows of the keyboard matrix
-    //     for row in 0..8 {
-    //         // Set CIA1 Port A to scan this row (active low)
-    //         let row_mask = !(1 << row);
-    //         self.cia1.pra = row_mask;
-    //         
-    //         // Read CIA1 Port B to get column data
-    //         let cols = self.cia1.read_keyboard_columns();
-    //         
-    //         // Check each column for a keypress
-    //         for col in 0..8 {
-    //             if (cols & (1 << col)) == 0 {
-    //                 // Key is pressed (active low)
-    //                 // Convert row/col to C64 key code
-    //                 let key_code = self.matrix_to_keycode(row, col);
-    //                 
-    //                 if key_code == 0 {
-    //                     continue; // Skip modifier keys and special keys for now
-    //                 }
-    //                 
-    //                 // Check if this is a different key from last time
-    //                 let last_key = self.ram[0xCB];
-    //                 if key_code != last_key {
-    //                     // New key pressed - add to buffer
-    //                     self.ram[0xCB] = key_code;
-    //                     
-    //                     // Add to keyboard buffer
-    //                     let buffer_start = 0x0277;
-    //                     self.ram[buffer_start + buffer_len] = key_code;
-    //                     self.ram[0xC6] = (buffer_len + 1) as u8;
-    //                     
-    //                     // Write to debug file (don't use println - it breaks TUI)
-    //                     use std::fs::OpenOptions;
-    //                     use std::io::Write;
-    //                     if let Ok(mut file) = OpenOptions::new()
-    //                         .create(true)
-    //                         .append(true)
-    //                         .open("/tmp/go64_kbd_debug.txt") {
-    //                         writeln!(file, "Key pressed: row={}, col={}, code=${:02X} ({}), buffer_len={}", 
-    //                                  row, col, key_code, key_code as char, buffer_len + 1).ok();
-    //                     }
-    //                     
-    //                     return; // Only process one key per scan
-    //                 }
-    //             }
-    //         }
-    //     }
-    //     
-    //     // No keys pressed - reset last key
-    //     self.ram[0xCB] = 0;
-    // }
-    
-    // /// Convert keyboard matrix position to C64 PETSCII/scan code
-    // pub fn matrix_to_keycode_direct(&self, row: u8, col: u8) -> Option<u8> {
-    //     let code = self.matrix_to_keycode(row, col);
-    //     if code == 0 { None } else { Some(code) }
-    // }
-
-    // fn matrix_to_keycode(&self, row: u8, col: u8) -> u8 {
-    //     // C64 keyboard matrix to PETSCII mapping
-    //     // This is a simplified version - real C64 has shift/control handling
-    //     match (row, col) {
-    //         // Row 0
-    //         (0, 0) => 20,  // DEL
-    //         (0, 1) => 13,  // RETURN
-    //         (0, 2) => 29,  // Cursor Right
-    //         (0, 3) => 0,   // F7 (special)
-    //         (0, 4) => 0,   // F1 (special)
-    //         (0, 5) => 0,   // F3 (special)
-    //         (0, 6) => 0,   // F5 (special)
-    //         (0, 7) => 17,  // Cursor Down
-    //         
-    //         // Row 1: 3, W, A, 4, Z, S, E, Shift
-    //         (1, 0) => b'3',
-    //         (1, 1) => b'W',
-    //         (1, 2) => b'A',
-    //         (1, 3) => b'4',
-    //         (1, 4) => b'Z',
-    //         (1, 5) => b'S',
-    //         (1, 6) => b'E',
-    //         (1, 7) => 0,  // Left Shift (modifier)
-    //         
-    //         // Row 2: 5, R, D, 6, C, F, T, X
-    //         (2, 0) => b'5',
-    //         (2, 1) => b'R',
-    //         (2, 2) => b'D',
-    //         (2, 3) => b'6',
-    //         (2, 4) => b'C',
-    //         (2, 5) => b'F',
-    //         (2, 6) => b'T',
-    //         (2, 7) => b'X',
-    //         
-    //         // Row 3: 7, Y, G, 8, B, H, U, V
-    //         (3, 0) => b'7',
-    //         (3, 1) => b'Y',
-    //         (3, 2) => b'G',
-    //         (3, 3) => b'8',
-    //         (3, 4) => b'B',
-    //         (3, 5) => b'H',
-    //         (3, 6) => b'U',
-    //         (3, 7) => b'V',
-    //         
-    //         // Row 4: 9, I, J, 0, M, K, O, N
-    //         (4, 0) => b'9',
-    //         (4, 1) => b'I',
-    //         (4, 2) => b'J',
-    //         (4, 3) => b'0',
-    //         (4, 4) => b'M',
-    //         (4, 5) => b'K',
-    //         (4, 6) => b'O',
-    //         (4, 7) => b'N',
-    //         
-    //         // Row 5: +, P, L, -, ., :, @, ,
-    //         (5, 0) => b'+',
-    //         (5, 1) => b'P',
-    //         (5, 2) => b'L',
-    //         (5, 3) => b'-',
-    //         (5, 4) => b'.',
-    //         (5, 5) => b':',
-    //         (5, 6) => b'@',
-    //         (5, 7) => b',',
-    //         
-    //         // Row 6: Pound, *, ;, HOME, Right Shift, =, Up Arrow, /
-    //         (6, 0) => 0,   // Pound sign
-    //         (6, 1) => b'*',
-    //         (6, 2) => b';',
-    //         (6, 3) => 19,  // HOME
-    //         (6, 4) => 0,   // Right Shift (modifier)
-    //         (6, 5) => b'=',
-    //         (6, 6) => 145, // Cursor Up
-    //         (6, 7) => b'/',
-    //         
-    //         // Row 7: 1, Left Arrow, CTRL, 2, SPACE, Commodore, Q, Run/Stop
-    //         (7, 0) => b'1',
-    //         (7, 1) => 157, // Cursor Left
-    //         (7, 2) => 0,   // CTRL (modifier)
-    //         (7, 3) => b'2',
-    //         (7, 4) => b' ',
-    //         (7, 5) => 0,   // Commodore key
-    //         (7, 6) => b'Q',
-    //         (7, 7) => 3,   // RUN/STOP
-    //         
-    //         _ => 0,
-    //     }
-    // }
+    fn char_rom(&self) -> Option<&[u8]> {
+        self.char_loaded.then(|| &self.rom[0xD000..0xE000])
+    }
+    
+    // Advance the VIC-II, giving it its own view of memory for the fetches
+    // it makes while drawing
+    pub fn tick_vic(&mut self, cycles: u8) {
+        let char_rom = self.char_loaded.then(|| &self.rom[0xD000..0xE000]);
+        let bus = vic_bus(&self.ram[..], char_rom, self.cartridge.as_ref(), self.ultimax());
+        self.vic.tick(cycles, &mut self.irq, &bus);
+    }
+    
+    // Advance the devices on the serial bus and let the CIAs see the lines:
+    // CLK and DATA on CIA2 port A, SRQ on CIA1's FLAG
+    pub fn tick_iec(&mut self, cycles: u8) {
+        self.iec.tick(cycles);
+        self.cia2.port_a_input = self.iec.c64_port_input();
+        self.cia1.set_flag(!self.iec.lines().srq);
+    }
+    
+    // Plug a cartridge into the expansion port, or pull it out. The machine
+    // should be reset afterwards, as when plugging in a real one.
+    pub fn attach_cartridge(&mut self, cartridge: Option<Cartridge>) {
+        self.cartridge = cartridge;
+        self.update_map();
+    }
+    
+    // Take on the state in a snapshot: RAM, banking, the chips and the
+    // cartridge. The ROMs, serial bus devices, watches and coverage stay.
+    pub fn restore(&mut self, snapshot: C64Memory) {
+        let C64Memory {
+            ram, cartridge, exrom, game, port_0000, port_0001, data_bus,
+            vic, sid, cia1, cia2, iec, irq, nmi,
+            rom: _, basic_loaded: _, kernal_loaded: _, char_loaded: _, map: _, pages: _, watches: _, coverage: _, tape: _,
+            swiftlink: _,
+        } = snapshot;
+        self.ram = ram;
+        self.cartridge = cartridge;
+        (self.exrom, self.game) = (exrom, game);
+        (self.port_0000, self.port_0001) = (port_0000, port_0001);
+        self.data_bus = data_bus;
+        self.vic.restore(vic);
+        self.sid.restore(sid);
+        self.cia1.restore(cia1);
+        self.cia2.restore(cia2);
+        self.iec.restore(iec);
+        (self.irq, self.nmi) = (irq, nmi);
+        self.update_map();
+    }
+    
+    // Press the cartridge's freeze button, if it has one
+    pub fn freeze(&mut self) {
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.freeze();
+            self.update_map();
+        }
+    }
+    
+    // Whether the cartridge is pulling NMI low
+    pub fn cartridge_nmi(&self) -> bool {
+        self.cartridge.as_ref().is_some_and(|cartridge| cartridge.nmi())
+    }
+    
+    // $01 bits set as inputs are pulled up, so they read as high. With no
+    // cartridge plugged in EXROM and GAME are too.
+    fn update_map(&mut self) {
+        (self.exrom, self.game) = self.cartridge.as_ref().map_or((true, true), |cartridge| cartridge.lines());
+        let lines = self.port_0001 | !self.port_0000;
+        self.map = pla_map(lines & 0x01 != 0, lines & 0x02 != 0, lines & 0x04 != 0, self.exrom, self.game);
+        tracing::trace!(target: "memory", "map {:?} (LORAM/HIRAM/CHAREN {:03b}, EXROM {}, GAME {})", self.map, lines & 0x07, self.exrom, self.game);
+        self.pages = std::array::from_fn(|page| self.page_for(page));
+        // A freezer switches its ROM in as the CPU fetches the vector of
+        // the NMI its button raised
+        if self.cartridge_nmi() {
+            self.pages[0xFF] = Page::Vectors;
+        }
+    }
+    
+    // Page `page` as the map has it. A missing ROM leaves the RAM under it
+    // showing.
+    fn page_for(&self, page: usize) -> Page {
+        match self.map[page >> 4] {
+            _ if page == 0x00 => Page::Port,
+            Bank::Ram => Page::Ram,
+            Bank::Basic if self.basic_loaded => Page::Rom,
+            Bank::Kernal if self.kernal_loaded => Page::Rom,
+            Bank::CharRom if self.char_loaded => Page::Rom,
+            Bank::Basic | Bank::Kernal | Bank::CharRom => Page::Ram,
+            Bank::RomL => Page::RomL,
+            Bank::RomH => Page::RomH,
+            Bank::Unmapped => Page::Unmapped,
+            Bank::Io => match page {
+                0xD0..=0xD3 => Page::Vic,
+                0xD4..=0xD7 => Page::Sid,
+                0xD8..=0xDB => Page::ColorRam,
+                0xDC => Page::Cia1,
+                0xDD => Page::Cia2,
+                _ => Page::Expansion,
+            },
+        }
+    }
+    
+    pub fn banking(&self) -> Banking {
+        Banking {
+            lines: (self.port_0001 | !self.port_0000) & 0x07,
+            cartridge: self.cartridge.as_ref().map(|cartridge| (self.exrom, self.game, cartridge.bank())),
+            map: self.map,
+        }
+    }
+
+    // After a write to $00, $01 or a cartridge's register, which left the
+    // banking `after`: stop there if the banking is watched, and it changed
+    // or a cartridge register was written
+    fn banking_written(&mut self, addr: u16, value: u8, before: Option<Banking>) {
+        let Some(before) = before else {
+            return;
+        };
+        let after = self.banking();
+        if let Some(watches) = &mut self.watches
+            && (before != after || addr >= 0xDE00)
+        {
+            watches.banking_changed(BusEvent { access: Access::Write, addr, value }, before, after);
+        }
+    }
+
+    // The banking now, when the watches want to know how it changes
+    fn banking_before(&self) -> Option<Banking> {
+        self.watches.as_ref().filter(|watches| watches.banking_watched()).map(|_| self.banking())
+    }
+    
+    // EXROM high and GAME low: the cartridge replaces most of the map
+    fn ultimax(&self) -> bool {
+        self.exrom && !self.game
+    }
 }
 
-impl Memory for C64Memory {
-    fn read(&self, addr: u16) -> u8 {
-        match addr {
-            0x0000 => self.port_0000,
-            0x0001 => self.port_0001,
-            
-            // Zero page, stack, and low RAM
-            0x0002..=0x9FFF => {
-                self.ram[addr as usize]
+impl C64Memory {
+    // Full address decode, including ROM/IO banking via $01. Side-effect
+    // free: registers that change when read are handled in read().
+    fn peek_banked(&self, addr: u16) -> u8 {
+        self.peek_page(addr, self.pages[(addr >> 8) as usize])
+    }
+    
+    fn peek_page(&self, addr: u16, page: Page) -> u8 {
+        let cartridge = |read: fn(&Cartridge, u16) -> Option<u8>| {
+            self.cartridge.as_ref().and_then(|cartridge| read(cartridge, addr)).unwrap_or(self.ram[addr as usize])
+        };
+        match page {
+            Page::Ram => self.ram[addr as usize],
+            Page::Rom => self.rom[addr as usize],
+            Page::Port => match addr {
+                0x0000 => self.port_0000,
+                0x0001 => self.port_0001,
+                _ => self.ram[addr as usize],
             },
-            
-            // BASIC ROM area
-            0xA000..=0xBFFF => {
-                if self.is_basic_visible() {
-                    if let Some(ref rom) = self.basic_rom {
-                        rom[(addr - 0xA000) as usize]
-                    } else {
-                        self.ram[addr as usize]
-                    }
-                } else {
-                    self.ram[addr as usize]
-                }
-            }
-            
-            // High RAM
-            0xC000..=0xCFFF => self.ram[addr as usize],
-            
-            // I/O or CHAR ROM area
-            0xD000..=0xDFFF => {
-                if self.is_io_visible() {
-                    match addr {
-                        // VIC-II registers: $D000-$D3FF (repeats every $0040 bytes)
-                        0xD000..=0xD3FF => {
-                            let reg = addr & 0x003F;
-                            self.vic.read_register(reg)
-                        }
-                        // SID registers: $D400-$D7FF
-                        0xD400..=0xD7FF => {
-                            0 // SID not implemented yet
-                        }
-                        // Color RAM: $D800-$DBFF
-                        0xD800..=0xDBFF => {
-                            self.vic.read_color_ram(addr - 0xD800)
-                        }
-                        // CIA1: $DC00-$DCFF
-                        0xDC00..=0xDCFF => {
-                            self.cia1.read(addr)
-                        }
-                        // CIA2: $DD00-$DDFF
-                        0xDD00..=0xDDFF => {
-                            self.cia2.read(addr)
-                        }
-                        _ => self.ram[addr as usize]
-                    }
-                } else if self.is_char_rom_visible() {
-                    if let Some(ref rom) = self.char_rom {
-                        rom[(addr - 0xD000) as usize]
-                    } else {
-                        self.ram[addr as usize]
-                    }
-                } else {
-                    self.ram[addr as usize]
-                }
-            }
-            
-            // KERNAL ROM area
-            0xE000..=0xFFFF => {
-                if self.is_kernal_visible() {
-                    if let Some(ref rom) = self.kernal_rom {
-                        rom[(addr - 0xE000) as usize]
-                    } else {
-                        self.ram[addr as usize]
-                    }
-                } else {
-                    self.ram[addr as usize]
+            Page::RomL => cartridge(Cartridge::read_roml),
+            Page::RomH => cartridge(Cartridge::read_romh),
+            // Open bus, which isn't modelled
+            Page::Unmapped => 0xFF,
+            // VIC-II registers: $D000-$D3FF (repeats every $0040 bytes)
+            Page::Vic => self.vic.read_register(addr & 0x003F),
+            // SID registers: $D400-$D7FF
+            Page::Sid => match addr & 0x1F {
+                0x19 | 0x1A => self.sid.read_pot(addr, self.cia1.port_a_output()),
+                _ => self.sid.read_register(addr),
+            },
+            // Color RAM: $D800-$DBFF, which drives only the low nibble
+            Page::ColorRam => (self.data_bus & 0xF0) | self.vic.read_color_ram(addr - 0xD800),
+            Page::Cia1 => self.cia1.peek(addr),
+            Page::Cia2 => self.cia2.peek(addr),
+            // I/O 1 and 2: $DE00-$DFFF, for the cartridge
+            Page::Expansion => match &self.swiftlink {
+                Some(swiftlink) if addr < 0xDF00 => swiftlink.peek(addr),
+                _ => cartridge(Cartridge::read_io),
+            },
+            Page::Vectors => self.peek_page(addr, self.page_for(0xFF)),
+        }
+    }
+    
+    // Reads with side effects, and the rest of the decode, kept out of
+    // read() so that the common path stays small
+    #[inline(never)]
+    fn read_page(&mut self, addr: u16, page: Page) -> u8 {
+        match page {
+            Page::Cia1 => self.cia1.read(addr),
+            Page::Cia2 => self.cia2.read(addr),
+            Page::Expansion if addr < 0xDF00 && self.swiftlink.is_some() => self.swiftlink.as_mut().unwrap().read(addr),
+            Page::Vectors if addr == 0xFFFA => {
+                if let Some(cartridge) = &mut self.cartridge {
+                    cartridge.acknowledge_nmi();
                 }
+                self.update_map();
+                self.peek_banked(addr)
             }
+            _ => self.peek_page(addr, page),
+        }
+    }
+}
+
+impl Memory for C64Memory {
+    // Plain RAM and ROM reads are by far the most common bus accesses, so
+    // keep that path small enough to inline into the CPU and leave the rest
+    // out of line
+    #[inline]
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = match self.pages[(addr >> 8) as usize] {
+            Page::Ram => self.ram[addr as usize],
+            Page::Rom => self.rom[addr as usize],
+            Page::Port if addr > 0x0001 => self.ram[addr as usize],
+            page => self.read_page(addr, page),
+        };
+        self.data_bus = value;
+        if let Some(watches) = &mut self.watches {
+            watches.record(Access::Read, addr, value, false);
         }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(Access::Read, addr, value);
+        }
+        value
+    }
+    
+    fn peek(&self, addr: u16) -> u8 {
+        self.peek_banked(addr)
+    }
+    
+    fn channels(&mut self, device: u8) -> Option<&mut dyn crate::iec::device::Channels> {
+        match device {
+            crate::rs232::DEVICE => self.swiftlink.as_mut().map(|swiftlink| swiftlink as &mut dyn crate::iec::device::Channels),
+            _ => self.iec.channels(device),
+        }
+    }
+
+    fn tape(&mut self) -> Option<&mut crate::tape::Tape> {
+        self.tape.as_mut()
+    }
+
+    fn read_vic(&self, addr: u16) -> u8 {
+        vic_bus(&self.ram[..], self.char_rom(), self.cartridge.as_ref(), self.ultimax()).read(addr)
+    }
+    
+    fn kernal_rom_visible(&self) -> bool {
+        self.pages[0xE0] == Page::Rom
     }
     
     fn write(&mut self, addr: u16, value: u8) {
-        match addr {
-            0x0000 => self.port_0000 = value,
-            0x0001 => {
-                self.port_0001 = value;
-                // Note: Only bits controlled by port_0000 are writable
-                // For simplicity, we allow all bits for now
+        self.data_bus = value;
+        if self.watches.is_some() {
+            let changed = self.peek(addr) != value;
+            if let Some(watches) = &mut self.watches {
+                watches.record(Access::Write, addr, value, changed);
             }
-            
-            // I/O area writes
-            0xD000..=0xDFFF => {
-                if self.is_io_visible() {
-                    match addr {
-                        // VIC-II registers: $D000-$D3FF
-                        0xD000..=0xD3FF => {
-                            let reg = addr & 0x003F;
-                            self.vic.write_register(reg, value);
-                            return;
-                        }
-                        // SID: $D400-$D7FF
-                        0xD400..=0xD7FF => {
-                            // SID not implemented yet
-                            return;
-                        }
-                        // Color RAM: $D800-$DBFF (always writable even through I/O)
-                        0xD800..=0xDBFF => {
-                            self.vic.write_color_ram(addr - 0xD800, value);
-                            return;
-                        }
-                        // CIA1: $DC00-$DCFF
-                        0xDC00..=0xDCFF => {
-                            self.cia1.write(addr, value);
-                            return;
-                        }
-                        // CIA2: $DD00-$DDFF
-                        0xDD00..=0xDDFF => {
-                            self.cia2.write(addr, value);
-                            return;
-                        }
-                        _ => {}
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(Access::Write, addr, value);
+        }
+        match self.pages[(addr >> 8) as usize] {
+            Page::Ram | Page::Rom => self.ram[addr as usize] = value,
+            Page::Port => match addr {
+                0x0000 | 0x0001 => {
+                    let before = self.banking_before();
+                    if addr == 0x0000 {
+                        self.port_0000 = value;
+                    } else {
+                        self.port_0001 = value;
                     }
+                    self.update_map();
+                    self.banking_written(addr, value, before);
                 }
-                // Fall through to RAM
-                self.ram[addr as usize] = value;
+                _ => self.ram[addr as usize] = value,
+            },
+            // VIC-II registers: $D000-$D3FF
+            Page::Vic => self.vic.write_register(addr & 0x003F, value),
+            // SID: $D400-$D7FF
+            Page::Sid => self.sid.write_register(addr, value),
+            // Color RAM: $D800-$DBFF (always writable even through I/O)
+            Page::ColorRam => self.vic.write_color_ram(addr - 0xD800, value),
+            // CIA1: $DC00-$DCFF
+            Page::Cia1 => self.cia1.write(addr, value),
+            // CIA2: $DD00-$DDFF
+            Page::Cia2 => {
+                self.cia2.write(addr, value);
+                // Port A bits 0-1 (inverted) select the VIC's 16K bank
+                let bank = 3 - (self.cia2.port_a_output() & 0x03) as u16;
+                self.vic.set_bank(bank * 0x4000);
+                // PA3-PA5 drive ATN, CLK and DATA
+                self.iec.set_c64_port(self.cia2.port_a_output());
+                self.cia2.port_a_input = self.iec.c64_port_input();
             }
-            
-            // All other writes go to RAM (ROMs are not writable)
-            _ => {
-                self.ram[addr as usize] = value;
+            // I/O 1 and 2: $DE00-$DFFF, where cartridges keep their bank
+            // and mode registers. Without one, writes reach the RAM.
+            Page::Expansion if addr < 0xDF00 && self.swiftlink.is_some() => self.swiftlink.as_mut().unwrap().write(addr, value),
+            Page::Expansion => match &mut self.cartridge {
+                // I/O 1 holds the registers, I/O 2 RAM on the types with it
+                Some(_) if addr < 0xDF00 => {
+                    let before = self.banking_before();
+                    if let Some(cartridge) = &mut self.cartridge {
+                        cartridge.write_io(addr, value);
+                    }
+                    self.update_map();
+                    self.banking_written(addr, value, before);
+                }
+                Some(cartridge) => {
+                    cartridge.write_io(addr, value);
+                    self.update_map();
+                }
+                None => self.ram[addr as usize] = value,
+            },
+            // Writes to the cartridge ROMs go to the RAM underneath, except
+            // in Ultimax mode, where they and writes to the open areas
+            // reach no RAM. Cartridge RAM at ROML takes them too.
+            page @ (Page::RomL | Page::RomH | Page::Unmapped | Page::Vectors) => {
+                if page == Page::RomL && let Some(cartridge) = &mut self.cartridge {
+                    cartridge.write_roml(addr, value);
+                }
+                if !self.ultimax() {
+                    self.ram[addr as usize] = value;
+                }
             }
         }
     }
@@ -409,7 +708,11 @@ impl BasicMemory {
 }
 
 impl Memory for BasicMemory {
-    fn read(&self, addr: u16) -> u8 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+    
+    fn peek(&self, addr: u16) -> u8 {
         self.ram[addr as usize]
     }
 
@@ -417,3 +720,214 @@ impl Memory for BasicMemory {
         self.ram[addr as usize] = value;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pla_map() {
+        // Pages $1000, $8000, $A000, $C000, $D000 and $E000 for each mode,
+        // numbered from the EXROM, GAME, CHAREN, HIRAM and LORAM bits
+        let expected = [
+            "RRRRRR", "RRRRRR", "RRHRCK", "RLHRCK", "RRRRRR", "RRRRIR", "RRHRIK", "RLHRIK",
+            "RRRRRR", "RRRRCR", "RRRRCK", "RLBRCK", "RRRRRR", "RRRRIR", "RRRRIK", "RLBRIK",
+            "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH",
+            "RRRRRR", "RRRRCR", "RRRRCK", "RRBRCK", "RRRRRR", "RRRRIR", "RRRRIK", "RRBRIK",
+        ];
+        for (mode, expected) in expected.iter().enumerate() {
+            let bit = |n: usize| mode & (1 << n) != 0;
+            let map = pla_map(bit(0), bit(1), bit(2), bit(4), bit(3));
+            let layout: String = [0x1, 0x8, 0xA, 0xC, 0xD, 0xE]
+                .iter()
+                .map(|&page| match map[page] {
+                    Bank::Ram => 'R',
+                    Bank::Basic => 'B',
+                    Bank::Kernal => 'K',
+                    Bank::CharRom => 'C',
+                    Bank::Io => 'I',
+                    Bank::RomL => 'L',
+                    Bank::RomH => 'H',
+                    Bank::Unmapped => '-',
+                })
+                .collect();
+            assert_eq!(&layout, expected, "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn test_power_on_pattern() {
+        let mut memory = C64Memory::new();
+        let bytes = [0x0002, 0x003F, 0x0040, 0x007F, 0x0080, 0xC0C0].map(|addr| memory.read(addr));
+        assert_eq!(bytes, [0x00, 0x00, 0xFF, 0xFF, 0x00, 0xFF]);
+
+        memory.fill_ram(RamPattern::Zero);
+        assert_eq!(memory.read(0x0040), 0x00);
+        // The reset vector is kept for running without ROMs
+        assert_eq!((memory.read(0xFFFC), memory.read(0xFFFD)), (0xE2, 0xFC));
+    }
+
+    #[test]
+    fn test_ram_under_rom() {
+        let mut memory = C64Memory::new();
+        memory.load_basic_rom(vec![0xBA; 0x2000]);
+        memory.load_kernal_rom(vec![0xEE; 0x2000]);
+        memory.write(0x0000, 0x2F);
+
+        // Writes to a ROM land in the RAM under it
+        memory.write(0xA000, 0x12);
+        memory.write(0xE000, 0x34);
+        assert_eq!(memory.read(0xA000), 0xBA);
+        assert_eq!(memory.read(0xE000), 0xEE);
+        memory.write(0x0001, 0x34);
+        assert_eq!(memory.read(0xA000), 0x12);
+        assert_eq!(memory.read(0xE000), 0x34);
+        assert!(!memory.kernal_rom_visible());
+
+        // Bits set as inputs are pulled high, whatever the port holds
+        memory.write(0x0000, 0x00);
+        assert_eq!(memory.read(0xA000), 0xBA);
+        assert!(memory.kernal_rom_visible());
+    }
+
+    #[test]
+    fn test_vic_view() {
+        let mut memory = C64Memory::new();
+        memory.load_basic_rom(vec![0xBA; 0x2000]);
+        memory.load_kernal_rom(vec![0xEE; 0x2000]);
+        memory.load_char_rom(vec![0xCC; 0x1000]);
+        memory.write(0x0001, 0x33); // Character ROM in for the CPU too
+        for addr in [0x1000, 0x3000, 0xA000, 0xD020, 0xE000] {
+            memory.write(addr, 0x55);
+        }
+
+        // RAM everywhere but the character ROM, whatever the CPU sees
+        assert_eq!(memory.read_vic(0x1000), 0xCC);
+        assert_eq!(memory.read_vic(0x9000), 0xCC);
+        assert_eq!(memory.read_vic(0x3000), 0x55);
+        assert_eq!(memory.read_vic(0xA000), 0x55);
+        assert_eq!(memory.read_vic(0xD020), 0x55);
+        assert_eq!(memory.read_vic(0xE000), 0x55);
+
+        // Ultimax: the top 4K of ROMH at $3000-$3FFF of each bank instead
+        let mut romh = vec![0x11; 0x2000];
+        romh[0x1000..].fill(0x22);
+        let image = crate::cartridge::crt_image(0, 1, 0, &[(0, 0xE000, &romh)]);
+        memory.attach_cartridge(Some(Cartridge::parse(&image).unwrap()));
+        assert_eq!(memory.read_vic(0x1000), 0x55);
+        for addr in [0x3000, 0x7FFF, 0xB000, 0xF000] {
+            assert_eq!(memory.read_vic(addr), 0x22);
+        }
+    }
+
+    #[test]
+    fn test_cartridge_modes() {
+        let mut memory = C64Memory::new();
+        memory.load_basic_rom(vec![0xBA; 0x2000]);
+        memory.load_kernal_rom(vec![0xEE; 0x2000]);
+        memory.write(0x8000, 0x80);
+        // The same ROMs on a cartridge driving EXROM and GAME each way
+        let cartridge = |exrom, game| {
+            let image = crate::cartridge::crt_image(0, exrom, game, &[(0, 0x8000, &[0x11; 0x2000]), (0, 0xA000, &[0x22; 0x2000])]);
+            Some(Cartridge::parse(&image).unwrap())
+        };
+
+        // 8K: ROML over RAM at $8000, BASIC stays
+        memory.attach_cartridge(cartridge(0, 1));
+        assert_eq!(memory.read(0x8000), 0x11);
+        assert_eq!(memory.read(0xA000), 0xBA);
+        memory.write(0x8000, 0x81);
+        memory.write(0x0001, 0x36);
+        assert_eq!(memory.read(0x8000), 0x81);
+
+        // 16K: ROMH replaces BASIC, even with LORAM clear
+        memory.attach_cartridge(cartridge(0, 0));
+        assert_eq!(memory.read(0xA000), 0x22);
+        memory.write(0x0001, 0x37);
+        assert_eq!(memory.read(0x8000), 0x11);
+
+        // Ultimax: ROMH takes the KERNAL's place and I/O is always in. The
+        // open areas take no writes.
+        memory.attach_cartridge(cartridge(1, 0));
+        memory.write(0x0001, 0x30);
+        assert_eq!(memory.read(0xE000), 0x22);
+        assert_eq!(memory.read(0xD020) & 0x0F, memory.vic.read_register(0x20) & 0x0F);
+        memory.write(0x4000, 0x40);
+        assert_eq!(memory.read(0x4000), 0xFF);
+        memory.write(0x0800, 0x08);
+        assert_eq!(memory.read(0x0800), 0x08);
+
+        memory.attach_cartridge(None);
+        assert_eq!(memory.read(0x4000), 0x00);
+        assert_eq!(memory.read(0x8000), 0x81);
+    }
+
+    #[test]
+    fn test_watches() {
+        use crate::debugger::watch::{BusEvent, Watch, WatchKind};
+        let mut memory = C64Memory::new();
+        let mut watches = Watches::new();
+        watches.add(Watch { range: 0xD020..=0xD020, kind: WatchKind::ReadWrite });
+        watches.add(Watch { range: 0x0400..=0x07E7, kind: WatchKind::Write });
+        memory.watches = Some(watches);
+
+        memory.write(0xD020, 0x0E);
+        let border = memory.read(0xD020);
+        memory.write(0x0400, 0x01);
+        memory.read(0x0400); // Only writes watched here
+        memory.write(0xD021, 0x06);
+
+        let events = memory.watches.as_mut().unwrap().take_events();
+        assert_eq!(
+            events,
+            vec![
+                BusEvent { access: Access::Write, addr: 0xD020, value: 0x0E },
+                BusEvent { access: Access::Read, addr: 0xD020, value: border },
+                BusEvent { access: Access::Write, addr: 0x0400, value: 0x01 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_banking_watch() {
+        let mut memory = C64Memory::new();
+        // Magic Desk: $DE00 picks the bank
+        let image = crate::cartridge::crt_image(19, 0, 1, &[(0, 0x8000, &[0x11; 0x2000]), (1, 0x8000, &[0x22; 0x2000])]);
+        memory.attach_cartridge(Some(Cartridge::parse(&image).unwrap()));
+        let mut watches = Watches::new();
+        watches.watch_banking(true);
+        memory.watches = Some(watches);
+
+        // Writing the bank register stops even when it picks the same bank
+        memory.write(0xDE00, 0x00);
+        let event = memory.watches.as_mut().unwrap().take_hit().unwrap();
+        let (before, after) = memory.watches.as_ref().unwrap().banking_change(&event).unwrap();
+        assert_eq!(before, after);
+        memory.write(0xDE00, 0x01);
+        let event = memory.watches.as_mut().unwrap().take_hit().unwrap();
+        let (_, after) = memory.watches.as_ref().unwrap().banking_change(&event).unwrap();
+        assert_eq!(after.to_string(), "%111 8:ROML A:BASIC D:I/O E:KERNAL EXROM 0 GAME 1 bank 1");
+        // The port's other bits leave the banking as it is
+        memory.write(0x0001, 0x17);
+        assert_eq!(memory.watches.as_mut().unwrap().take_hit(), None);
+    }
+
+    #[test]
+    fn test_color_ram_nibble() {
+        let mut memory = C64Memory::new();
+        memory.write(0xD800, 0xF7);
+        assert_eq!(memory.peek(0xD800) & 0x0F, 0x07);
+
+        // The upper nibble is whatever was last on the bus
+        memory.write(0x0002, 0xA0);
+        assert_eq!(memory.read(0xD800), 0xA7);
+        let floating = memory.read(0x0003) & 0xF0;
+        assert_eq!(memory.read(0xD800), floating | 0x07);
+
+        // All 1024 nibbles are there, past the 1000 the screen uses
+        memory.write(0xDBFF, 0x0C);
+        memory.write(0xDBE8, 0x03);
+        assert_eq!(memory.peek(0xDBFF) & 0x0F, 0x0C);
+        assert_eq!(memory.peek(0xDBE8) & 0x0F, 0x03);
+    }
+}
@@ -0,0 +1,199 @@
+// SID player: runs a .sid tune on the emulated C64 the way a music player
+// program does, calling its init routine once per song and then its play
+// routine at the tune's rate
+
+use anyhow::Result;
+
+use crate::cpu::StepResult;
+use crate::machine::Machine;
+use crate::memory::Memory;
+use crate::sid::file::SidFile;
+
+// Places to try for the driver, outside the tune: the tape buffer, the top
+// of $C000-$CFFF and the BASIC input buffer
+const DRIVER_CANDIDATES: [u16; 3] = [0x0334, 0xCF00, 0x0200];
+const DRIVER_SIZE: u16 = 25;
+
+// Offsets into the driver of the idle loop and the play call
+const IDLE: u16 = 11;
+const PLAY: u16 = 14;
+
+pub struct Player {
+    pub file: SidFile,
+    pub song: u16, // Counted from 1
+    driver: u16,
+    // A play call is due (vertical blank or timer underflow) and will be
+    // made as soon as the CPU is back in the idle loop
+    play_due: bool,
+    cycles_since_play: u32,
+    frames: u64,
+    // Why the tune stopped, if the CPU hit something it couldn't run
+    pub error: Option<String>,
+}
+
+impl Player {
+    // Load the tune into memory and start its default song
+    pub fn new(file: SidFile, machine: &mut Machine) -> Result<Self> {
+        let driver = driver_address(&file)?;
+
+        // With I/O and the ROMs banked out every write lands in RAM
+        let port = machine.memory.peek(0x0001);
+        machine.memory.write(0x0001, 0x34);
+        for (offset, &byte) in file.data.iter().enumerate() {
+            machine.memory.write(file.load_address.wrapping_add(offset as u16), byte);
+        }
+        machine.memory.write(0x0001, port);
+
+        let song = file.start_song;
+        let mut player = Self { file, song, driver, play_due: false, cycles_since_play: 0, frames: 0, error: None };
+        player.select(machine, song);
+        Ok(player)
+    }
+
+    // Silence the SID and run the init routine for `song`
+    pub fn select(&mut self, machine: &mut Machine, song: u16) {
+        let song = song.clamp(1, self.file.songs);
+        for reg in 0..0x19 {
+            machine.memory.sid.write_register(0xD400 + reg, 0);
+        }
+
+        // SEI; LDA #bank; STA $01; LDA #song; JSR init; then CLI for tunes
+        // that play from their own interrupt, and JMP * to idle. The play
+        // call: SEI; LDA #bank; STA $01; JSR play; JMP idle.
+        let [init_lo, init_hi] = self.file.init_address.to_le_bytes();
+        let [play_lo, play_hi] = self.file.play_address.to_le_bytes();
+        let [idle_lo, idle_hi] = (self.driver + IDLE).to_le_bytes();
+        let cli = if self.file.play_address == 0 { 0x58 } else { 0xEA };
+        let code = [
+            0x78, 0xA9, bank(self.file.init_address), 0x85, 0x01, 0xA9, (song - 1) as u8, 0x20, init_lo, init_hi,
+            cli, 0x4C, idle_lo, idle_hi,
+            0x78, 0xA9, bank(self.file.play_address), 0x85, 0x01, 0x20, play_lo, play_hi, 0x4C, idle_lo, idle_hi,
+        ];
+        for (offset, &byte) in code.iter().enumerate() {
+            machine.memory.write(self.driver + offset as u16, byte);
+        }
+
+        machine.cpu.sp = 0xFF;
+        machine.cpu.pc = self.driver;
+        self.song = song;
+        self.play_due = false;
+        self.cycles_since_play = 0;
+        self.frames = 0;
+        self.error = None;
+    }
+
+    // Emulate one video frame, calling the play routine whenever it is due
+    pub fn run_frame(&mut self, machine: &mut Machine) {
+        if self.error.is_some() {
+            return;
+        }
+        let cia_timed = self.file.cia_timed(self.song);
+        while !machine.memory.vic.take_frame_complete() {
+            if self.play_due && self.file.play_address != 0 && machine.cpu.pc == self.driver + IDLE {
+                self.play_due = false;
+                machine.cpu.pc = self.driver + PLAY;
+            }
+            let cycles = match machine.step() {
+                Ok(StepResult::Executed(cycles) | StepResult::WatchpointHit { cycles, .. }) => cycles,
+                Ok(StepResult::BreakpointHit(_)) => continue,
+                Err(e) => {
+                    self.error = Some(e.to_string());
+                    return;
+                }
+            };
+            if machine.cpu.is_jammed() {
+                self.error = Some(format!("CPU jammed at ${:04X}", machine.cpu.pc));
+                return;
+            }
+
+            // CIA timing follows timer A's latch, which init may have set;
+            // the timer underflows once every latch + 1 cycles
+            if cia_timed {
+                let cia = &machine.memory.cia1;
+                let period = match (cia.ta_hi as u32) << 8 | cia.ta_lo as u32 {
+                    0 => machine.standard.jiffy_timer() as u32,
+                    latch => latch,
+                } + 1;
+                self.cycles_since_play += cycles as u32;
+                if self.cycles_since_play >= period {
+                    self.cycles_since_play -= period;
+                    self.play_due = true;
+                }
+            }
+        }
+        if !cia_timed {
+            self.play_due = true;
+        }
+        self.frames += 1;
+    }
+
+    // Time the current song has been playing
+    pub fn elapsed(&self, machine: &Machine) -> std::time::Duration {
+        machine.standard.frame_duration() * self.frames as u32
+    }
+}
+
+// Value for $01 that leaves RAM under `address` visible, keeping I/O in so
+// the tune can reach the SID
+fn bank(address: u16) -> u8 {
+    match address {
+        0x0000..=0x9FFF => 0x37,
+        0xA000..=0xCFFF => 0x36,
+        _ => 0x35,
+    }
+}
+
+fn driver_address(file: &SidFile) -> Result<u16> {
+    if file.start_page != 0 && file.start_page != 0xFF {
+        return Ok((file.start_page as u16) << 8);
+    }
+    let outside = |address: u16| {
+        address as u32 + DRIVER_SIZE as u32 <= file.load_address as u32 || address as u32 >= file.end_address()
+    };
+    match DRIVER_CANDIDATES.into_iter().find(|&address| outside(address)) {
+        Some(address) => Ok(address),
+        None => anyhow::bail!("no free memory for the player driver"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::VideoStandard;
+
+    #[test]
+    fn test_player_calls_init_and_play() {
+        // Init stores the song number at $C000; play counts calls in $C001
+        // and sets the volume from it
+        let code = [
+            0x8D, 0x00, 0xC0, 0x60, // $1000: STA $C000; RTS
+            0xEE, 0x01, 0xC0, 0xAD, 0x01, 0xC0, 0x8D, 0x18, 0xD4, 0x60, // $1004: INC $C001; LDA $C001; STA $D418; RTS
+        ];
+        let mut bytes = vec![0; 0x76];
+        bytes[0..4].copy_from_slice(b"PSID");
+        bytes[0x05] = 1;
+        bytes[0x07] = 0x76;
+        bytes[0x08..0x0E].copy_from_slice(&[0x10, 0x00, 0x10, 0x00, 0x10, 0x04]);
+        bytes[0x0F] = 2;
+        bytes[0x11] = 2;
+        bytes.extend_from_slice(&code);
+        let file = SidFile::parse(&bytes).unwrap();
+
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let mut player = Player::new(file, &mut machine).unwrap();
+        assert_eq!(player.driver, 0x0334);
+        for _ in 0..10 {
+            player.run_frame(&mut machine);
+        }
+        assert_eq!(player.error, None);
+        // Song 2 is passed to init as 1, and play ran once per frame since
+        assert_eq!(machine.memory.peek(0xC000), 1);
+        let calls = machine.memory.peek(0xC001);
+        assert!((9..=10).contains(&calls));
+        assert_eq!(player.elapsed(&machine), VideoStandard::Pal.frame_duration() * 10);
+
+        player.select(&mut machine, 1);
+        player.run_frame(&mut machine);
+        assert_eq!(machine.memory.peek(0xC000), 0);
+    }
+}
@@ -0,0 +1,75 @@
+// Pages as an MPS-803 puts them on paper: the print head has 7 needles in a
+// column, so each printed line is a row of dot columns, bit 0 of a column
+// the top needle. Text lines are spaced 9 dots apart and graphics lines 7,
+// so that rows of graphics meet without a gap.
+
+pub const TEXT_LINE_HEIGHT: usize = 9;
+pub const GRAPHICS_LINE_HEIGHT: usize = 7;
+
+// 80 characters of 8 dots
+const PAPER_WIDTH: usize = 640;
+
+struct Line {
+    columns: Vec<u8>,
+    height: usize,
+}
+
+#[derive(Default)]
+pub struct Page {
+    lines: Vec<Line>,
+}
+
+impl Page {
+    pub fn push_line(&mut self, columns: Vec<u8>, height: usize) {
+        self.lines.push(Line { columns, height });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    // The page as a binary PBM image (P4): one bit a dot, 1 for black,
+    // each row padded to a whole byte
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let width = self.lines.iter().map(|line| line.columns.len()).max().unwrap_or(0).max(PAPER_WIDTH);
+        let height: usize = self.lines.iter().map(|line| line.height).sum();
+        let stride = width.div_ceil(8);
+        let mut pbm = format!("P4\n{} {}\n", width, height).into_bytes();
+        let mut rows = vec![0u8; stride * height];
+        let mut top = 0;
+        for line in &self.lines {
+            for (x, &column) in line.columns.iter().enumerate() {
+                for dot in (0..line.height.min(8)).filter(|&dot| column & (1 << dot) != 0) {
+                    rows[(top + dot) * stride + x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            top += line.height;
+        }
+        pbm.extend_from_slice(&rows);
+        pbm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_pbm() {
+        let mut page = Page::default();
+        page.push_line(vec![0x01, 0x00, 0x40], GRAPHICS_LINE_HEIGHT);
+        page.push_line(vec![0x7F; 700], TEXT_LINE_HEIGHT);
+        let pbm = page.to_pbm();
+        let header = b"P4\n700 16\n";
+        assert!(pbm.starts_with(header));
+        let rows = &pbm[header.len()..];
+        let stride = 700usize.div_ceil(8);
+        assert_eq!(rows.len(), stride * 16);
+        // The first line: dots at the top of column 0 and the bottom of
+        // column 2
+        assert_eq!((rows[0], rows[6 * stride]), (0x80, 0x20));
+        // The second line fills its top 7 rows and leaves 2 between lines
+        assert_eq!(rows[(7 + 6) * stride], 0xFF);
+        assert_eq!(rows[(7 + 7) * stride], 0x00);
+    }
+}
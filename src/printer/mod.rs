@@ -0,0 +1,365 @@
+// A Commodore MPS-803 printer on the serial bus as device 4. What it
+// prints goes to a text file on the host, converted from PETSCII to ASCII
+// or kept as the bytes received, and optionally to a picture of the paper
+// (see bitmap) that shows graphics and the character set's own glyphs.
+//
+// Secondary address 7 prints in the lower case character set, any other in
+// upper case and graphics. Among the bytes sent, these are control codes:
+//
+//   13, 10        end the line (13 also ends reverse)
+//   14, 15        double width on; back to standard characters
+//   8             graphics: bytes from 128 on are dot columns, bit 0 on top
+//   18, 146       reverse on and off
+//   17, 145       lower case and upper case character set
+//   16 "nn"       move to character column nn
+//   26 n b        graphics column b, n times
+//   27 16 hi lo   move to dot column hi * 256 + lo
+
+pub mod bitmap;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::iec::device::Channels;
+use crate::storage::DosError;
+use bitmap::Page;
+
+pub const DEVICE: u8 = 4;
+
+const LOWER_CASE_CHANNEL: u8 = 7;
+
+// Dots across a character
+const CHAR_WIDTH: usize = 8;
+
+// A control code waiting for the bytes after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pending {
+    None,
+    Position(Vec<u8>),
+    RepeatCount,
+    Repeat(u8),
+    Escape,
+    DotPosition(Vec<u8>),
+}
+
+pub struct Printer {
+    text: Option<BufWriter<File>>,
+    // Write PETSCII as it came rather than as ASCII
+    raw: bool,
+    bitmap: Option<PathBuf>,
+    page: Page,
+    // The character ROM the glyphs are drawn from
+    font: Option<Vec<u8>>,
+    channel: u8,
+    // The line being printed, as dot columns and as text
+    columns: Vec<u8>,
+    text_column: usize,
+    graphics_line: bool,
+    lower: bool,
+    graphics: bool,
+    double_width: bool,
+    reverse: bool,
+    pending: Pending,
+}
+
+impl Printer {
+    // A printer writing text to `text` and drawing the paper into `bitmap`,
+    // either of which may be left out
+    pub fn new(text: Option<&Path>, raw: bool, bitmap: Option<&Path>, font: Option<Vec<u8>>) -> Result<Self> {
+        let text = match text {
+            Some(path) => Some(BufWriter::new(File::create(path).context(format!("Failed to create {:?}", path))?)),
+            None => None,
+        };
+        Ok(Self {
+            text,
+            raw,
+            bitmap: bitmap.map(Path::to_path_buf),
+            page: Page::default(),
+            font,
+            channel: 0,
+            columns: Vec::new(),
+            text_column: 0,
+            graphics_line: false,
+            lower: false,
+            graphics: false,
+            double_width: false,
+            reverse: false,
+            pending: Pending::None,
+        })
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match std::mem::replace(&mut self.pending, Pending::None) {
+            Pending::None => {}
+            Pending::Position(mut digits) => {
+                digits.push(byte);
+                if digits.len() < 2 {
+                    self.pending = Pending::Position(digits);
+                } else if let Ok(column) = String::from_utf8_lossy(&digits).parse::<usize>() {
+                    self.move_to(column);
+                }
+                return;
+            }
+            Pending::RepeatCount => {
+                self.pending = Pending::Repeat(byte);
+                return;
+            }
+            Pending::Repeat(count) => {
+                if byte >= 0x80 {
+                    let count = if count == 0 { 256 } else { count as usize };
+                    self.dots(&vec![byte & 0x7F; count]);
+                }
+                return;
+            }
+            Pending::Escape => {
+                if byte == 16 {
+                    self.pending = Pending::DotPosition(Vec::new());
+                }
+                return;
+            }
+            Pending::DotPosition(mut position) => {
+                position.push(byte);
+                if position.len() < 2 {
+                    self.pending = Pending::DotPosition(position);
+                } else {
+                    let dot = (position[0] as usize) << 8 | position[1] as usize;
+                    if self.columns.len() < dot {
+                        self.columns.resize(dot, 0);
+                    }
+                }
+                return;
+            }
+        }
+        match byte {
+            13 | 10 => {
+                self.end_line();
+                if byte == 13 {
+                    self.reverse = false;
+                }
+            }
+            8 => self.graphics = true,
+            14 => self.double_width = true,
+            15 => (self.graphics, self.double_width) = (false, false),
+            18 => self.reverse = true,
+            146 => self.reverse = false,
+            17 => self.lower = true,
+            145 => self.lower = false,
+            16 => self.pending = Pending::Position(Vec::new()),
+            26 => self.pending = Pending::RepeatCount,
+            27 => self.pending = Pending::Escape,
+            0x80.. if self.graphics => self.dots(&[byte & 0x7F]),
+            0x20..=0x7F | 0xA0..=0xFF => self.print(byte),
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, byte: u8) {
+        let text = if self.raw { Some(byte) } else { ascii(byte, self.lower) };
+        if let (Some(out), Some(text)) = (&mut self.text, text) {
+            let _ = out.write_all(&[text]);
+        }
+        self.text_column += 1;
+
+        let glyph = self.glyph(byte);
+        if self.double_width {
+            let doubled: Vec<u8> = glyph.iter().flat_map(|&column| [column, column]).collect();
+            self.columns.extend_from_slice(&doubled);
+        } else {
+            self.columns.extend_from_slice(&glyph);
+        }
+    }
+
+    // The 7 dot rows of a character as columns, from the character ROM
+    fn glyph(&self, byte: u8) -> [u8; CHAR_WIDTH] {
+        let mut columns = [0; CHAR_WIDTH];
+        let Some(font) = &self.font else {
+            return columns;
+        };
+        let code = screen_code(byte) | if self.reverse { 0x80 } else { 0 };
+        let offset = if self.lower { 0x800 } else { 0 } + code as usize * 8;
+        for (row, &bits) in font.iter().skip(offset).take(7).enumerate() {
+            for (x, column) in columns.iter_mut().enumerate() {
+                if bits & (0x80 >> x) != 0 {
+                    *column |= 1 << row;
+                }
+            }
+        }
+        columns
+    }
+
+    fn dots(&mut self, columns: &[u8]) {
+        self.columns.extend_from_slice(columns);
+        self.graphics_line = true;
+    }
+
+    fn move_to(&mut self, column: usize) {
+        while self.text_column < column {
+            if let Some(out) = &mut self.text {
+                let _ = out.write_all(b" ");
+            }
+            self.text_column += 1;
+        }
+        if self.columns.len() < column * CHAR_WIDTH {
+            self.columns.resize(column * CHAR_WIDTH, 0);
+        }
+    }
+
+    fn end_line(&mut self) {
+        if let Some(out) = &mut self.text {
+            let _ = out.write_all(b"\n");
+        }
+        let height = if self.graphics_line { bitmap::GRAPHICS_LINE_HEIGHT } else { bitmap::TEXT_LINE_HEIGHT };
+        self.page.push_line(std::mem::take(&mut self.columns), height);
+        (self.text_column, self.graphics_line) = (0, false);
+    }
+
+    // Put what has been printed in the files
+    fn flush(&mut self) {
+        if let Some(out) = &mut self.text
+            && let Err(error) = out.flush()
+        {
+            tracing::warn!(target: "printer", "text: {}", error);
+        }
+        if let Some(path) = &self.bitmap
+            && !self.page.is_empty()
+            && let Err(error) = std::fs::write(path, self.page.to_pbm())
+        {
+            tracing::warn!(target: "printer", "{:?}: {}", path, error);
+        }
+    }
+}
+
+impl Channels for Printer {
+    fn open(&mut self, _channel: u8, _name: &[u8]) -> Result<(), DosError> {
+        Ok(())
+    }
+
+    // The line left in the buffer is printed, as the file ends
+    fn close(&mut self, _channel: u8) -> Result<(), DosError> {
+        if !self.columns.is_empty() || self.text_column > 0 {
+            self.end_line();
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn read(&mut self, _channel: u8) -> Option<(u8, bool)> {
+        None
+    }
+
+    fn write(&mut self, channel: u8, byte: u8) {
+        if channel != self.channel {
+            self.channel = channel;
+            self.lower = channel == LOWER_CASE_CHANNEL;
+        }
+        self.receive(byte);
+    }
+
+    fn unlisten(&mut self, _channel: u8) {
+        self.flush();
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// The character ROM position of a PETSCII character, as the screen code
+// that shows it
+fn screen_code(byte: u8) -> u8 {
+    match byte {
+        0x40..=0x5F => byte - 0x40,
+        0x60..=0x7F => byte - 0x20,
+        0xA0..=0xBF => byte - 0x40,
+        0xC0..=0xFE => byte - 0x80,
+        0xFF => 0x5E,
+        _ => byte,
+    }
+}
+
+// A PETSCII character as ASCII, in the character set in use. Letters are
+// capitals in upper case, and in lower case the unshifted ones are small
+// and the shifted ones capitals. Graphics characters print as '?'; £, ↑
+// and ← as the ASCII characters in their place.
+fn ascii(byte: u8, lower: bool) -> Option<u8> {
+    match byte {
+        0x41..=0x5A if lower => Some(byte.to_ascii_lowercase()),
+        0x20..=0x5F => Some(byte),
+        0x61..=0x7A | 0xC1..=0xDA if lower => Some((byte & 0x1F) + b'A' - 1),
+        0xA0 => Some(b' '),
+        0x60..=0x7F | 0xA1..=0xFF => Some(b'?'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iec::device::Channels;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("go64-printer-{}-{}", name, std::process::id()))
+    }
+
+    fn send(printer: &mut Printer, channel: u8, bytes: &[u8]) {
+        for &byte in bytes {
+            printer.write(channel, byte);
+        }
+        printer.unlisten(channel);
+    }
+
+    #[test]
+    fn test_text() {
+        let path = temp("text.txt");
+        let mut printer = Printer::new(Some(&path), false, None, None).unwrap();
+        send(&mut printer, 0, b"HELLO\r");
+        // Lower case on secondary address 7, and with a control code
+        send(&mut printer, 7, &[b'H', b'I', 0xC1, b'\r', 145, b'X', 16, b'0', b'4', b'Y', b'\r']);
+        // Graphics don't show in the text
+        send(&mut printer, 0, &[8, 0xFF, 0x80, 15, 0x5C, 0xA0, 0x73, b'\r']);
+        send(&mut printer, 0, b"NO RETURN");
+        printer.close(0).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "HELLO\nhiA\nX   Y\n\\ ?\nNO RETURN\n");
+        std::fs::remove_file(&path).unwrap();
+
+        let mut printer = Printer::new(Some(&path), true, None, None).unwrap();
+        send(&mut printer, 7, &[b'A', 0xC1, b'\r']);
+        drop(printer);
+        assert_eq!(std::fs::read(&path).unwrap(), [b'A', 0xC1, b'\n']);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_dots() {
+        // A font whose every character is a solid block, and 'A' a single dot
+        let mut font = vec![0xFF; 0x1000];
+        font[8..16].copy_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, 0]);
+        let mut printer = Printer::new(None, false, None, Some(font)).unwrap();
+        send(&mut printer, 0, b"A");
+        assert_eq!(printer.columns, [0x01, 0, 0, 0, 0, 0, 0, 0]);
+        // Double width doubles every column, and reverse inverts the dot
+        send(&mut printer, 0, &[14, 18, b'A', 15, 146]);
+        assert_eq!(printer.columns.len(), 24);
+        send(&mut printer, 0, &[8, 0x81, 26, 3, 0xC0, 27, 16, 0, 40, 15]);
+        assert_eq!(printer.columns[24..28], [0x01, 0x40, 0x40, 0x40]);
+        assert_eq!(printer.columns.len(), 40);
+        send(&mut printer, 0, b"\r");
+        assert!(printer.columns.is_empty() && !printer.page.is_empty());
+    }
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(ascii(b'A', false), Some(b'A'));
+        assert_eq!(ascii(b'A', true), Some(b'a'));
+        assert_eq!(ascii(0xC1, true), Some(b'A'));
+        assert_eq!(ascii(0xC1, false), Some(b'?'));
+        assert_eq!(ascii(0x93, false), None);
+        assert_eq!(screen_code(b'A'), 1);
+        assert_eq!(screen_code(0xC1), 0x41);
+    }
+}
@@ -0,0 +1,205 @@
+// RS-232 through a Swiftlink cartridge: a 6551 ACIA at $DE00 with its
+// interrupt on NMI, which terminal programs (CCGMS, Novaterm, StrikeTerm
+// and the rest) drive directly, at up to 38400 baud. A modem on its serial
+// port dials telnet BBSes over the Internet (see modem).
+//
+// The ACIA's four registers repeat through I/O 1:
+//
+//   $DE00  data: received byte on read, byte to send on write
+//   $DE01  status on read (IRQ, DSR, DCD, transmitter empty, receiver
+//          full, overrun, framing, parity from bit 7 down); a write resets
+//   $DE02  command: parity, echo, transmitter interrupt (bits 2-3 = 01),
+//          receiver interrupt off (bit 1), DTR (bit 0)
+//   $DE03  control: stop bits, word length, baud rate (bits 0-3)
+//
+// Bytes arrive no faster than the baud rate allows, and wait while the last
+// one is unread, so nothing is lost to a slow program.
+//
+// The KERNAL's traps reach the modem too, as RS-232 device 2, so BASIC's
+// OPEN 2,2 and PRINT#2 talk to it without the ACIA.
+
+pub mod modem;
+
+use crate::iec::device::Channels;
+use crate::interrupt::{InterruptLine, InterruptSource};
+use crate::storage::DosError;
+use modem::Modem;
+
+// The KERNAL's RS-232 device number
+pub const DEVICE: u8 = 2;
+
+// Baud rates by control register bits 0-3: the 6551's, doubled by the
+// Swiftlink's 3.6864 MHz crystal. 0 is the external clock, taken as the
+// fastest.
+const BAUD_RATES: [u32; 16] = [38400, 100, 150, 220, 269, 300, 600, 1200, 2400, 3600, 4800, 7200, 9600, 14400, 19200, 38400];
+
+// How often the line is checked for data, in cycles
+const POLL_CYCLES: u32 = 1000;
+
+const STATUS_RECEIVER_FULL: u8 = 0x08;
+const STATUS_TRANSMITTER_EMPTY: u8 = 0x10;
+const STATUS_NO_CARRIER: u8 = 0x20;
+const STATUS_IRQ: u8 = 0x80;
+
+const COMMAND_DTR: u8 = 0x01;
+const COMMAND_RECEIVER_IRQ_OFF: u8 = 0x02;
+const COMMAND_TRANSMITTER: u8 = 0x0C;
+const TRANSMITTER_IRQ: u8 = 0x04;
+
+pub struct Swiftlink {
+    modem: Modem,
+    clock_hz: u32,
+    command: u8,
+    control: u8,
+    received: Option<u8>,
+    // The last byte received, which the data register keeps reading
+    data: u8,
+    irq: bool,
+    // Cycles before the next byte can come in, and before the next poll
+    wait: u32,
+    poll: u32,
+}
+
+impl Swiftlink {
+    pub fn new(clock_hz: u32) -> Self {
+        Self { modem: Modem::new(), clock_hz, command: 0, control: 0, received: None, data: 0, irq: false, wait: 0, poll: 0 }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        match addr & 0x03 {
+            0 => {
+                if let Some(byte) = self.received.take() {
+                    self.data = byte;
+                }
+                self.data
+            }
+            1 => {
+                let status = self.peek(addr);
+                self.irq = false;
+                status
+            }
+            _ => self.peek(addr),
+        }
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr & 0x03 {
+            0 => self.received.unwrap_or(self.data),
+            1 => {
+                let mut status = STATUS_TRANSMITTER_EMPTY;
+                if self.received.is_some() {
+                    status |= STATUS_RECEIVER_FULL;
+                }
+                if !self.modem.carrier() {
+                    status |= STATUS_NO_CARRIER;
+                }
+                if self.irq {
+                    status |= STATUS_IRQ;
+                }
+                status
+            }
+            2 => self.command,
+            _ => self.control,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0x03 {
+            0 => self.modem.send(value),
+            // A programmed reset: the command register's low bits clear
+            1 => self.command &= 0xE0,
+            2 => self.command = value,
+            _ => self.control = value,
+        }
+    }
+
+    // Move the line along, and hold NMI while the ACIA interrupts
+    pub fn tick(&mut self, cycles: u8, nmi: &mut InterruptLine) {
+        let cycles = cycles as u32;
+        if self.poll <= cycles {
+            self.modem.poll();
+            self.poll = POLL_CYCLES;
+        } else {
+            self.poll -= cycles;
+        }
+        self.wait = self.wait.saturating_sub(cycles);
+        if self.wait == 0
+            && self.received.is_none()
+            && self.command & COMMAND_DTR != 0
+            && let Some(byte) = self.modem.receive()
+        {
+            self.received = Some(byte);
+            // Start bit, 8 data bits and a stop bit
+            self.wait = self.clock_hz * 10 / BAUD_RATES[(self.control & 0x0F) as usize];
+            if self.command & COMMAND_RECEIVER_IRQ_OFF == 0 {
+                self.irq = true;
+            }
+        }
+        // The transmitter is always empty, so with its interrupt on it
+        // always asks for more
+        if self.command & COMMAND_TRANSMITTER == TRANSMITTER_IRQ {
+            self.irq = true;
+        }
+        nmi.set(InterruptSource::Swiftlink, self.irq);
+    }
+}
+
+// Device 2 for the KERNAL traps: bytes go straight to and from the modem,
+// with no baud rate to keep to
+impl Channels for Swiftlink {
+    fn open(&mut self, _channel: u8, _name: &[u8]) -> Result<(), DosError> {
+        Ok(())
+    }
+
+    fn close(&mut self, _channel: u8) -> Result<(), DosError> {
+        Ok(())
+    }
+
+    fn read(&mut self, _channel: u8) -> Option<(u8, bool)> {
+        self.received.take().or_else(|| self.modem.receive()).map(|byte| (byte, false))
+    }
+
+    fn write(&mut self, _channel: u8, byte: u8) {
+        self.modem.send(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receive_at_baud_rate() {
+        let mut swiftlink = Swiftlink::new(1_000_000);
+        let mut nmi = InterruptLine::new();
+        // 2400 baud, DTR on with the receiver interrupt
+        swiftlink.write(0xDE03, 0x18);
+        swiftlink.write(0xDE02, 0x09);
+        for &byte in b"AT\r" {
+            swiftlink.write(0xDE00, byte);
+        }
+        // With no carrier, the modem's echo and answer come in
+        assert_eq!(swiftlink.peek(0xDE01) & STATUS_NO_CARRIER, STATUS_NO_CARRIER);
+        swiftlink.tick(1, &mut nmi);
+        assert!(nmi.is_asserted_by(InterruptSource::Swiftlink));
+        assert_eq!(swiftlink.read(0xDE01), 0xB8);
+        assert!(swiftlink.read(0xDE01) & STATUS_IRQ == 0);
+        assert_eq!(swiftlink.read(0xDE00), b'A');
+        assert_eq!(swiftlink.read(0xDE01) & STATUS_RECEIVER_FULL, 0);
+
+        // The next byte takes 10 bits at 2400 baud
+        swiftlink.tick(100, &mut nmi);
+        assert!(!nmi.is_asserted_by(InterruptSource::Swiftlink));
+        for _ in 0..41 {
+            swiftlink.tick(100, &mut nmi);
+        }
+        assert_eq!(swiftlink.read(0xDE00), b'T');
+
+        // Without DTR nothing comes in
+        swiftlink.write(0xDE01, 0);
+        for _ in 0..100 {
+            swiftlink.tick(100, &mut nmi);
+        }
+        assert_eq!(swiftlink.peek(0xDE01) & STATUS_RECEIVER_FULL, 0);
+    }
+}
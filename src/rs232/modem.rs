@@ -0,0 +1,372 @@
+// A Hayes modem that dials over the Internet: "ATDT bbs.example.com:6400"
+// connects to a telnet BBS (port 23 if none is given) the way terminal
+// programs expect to dial a phone number. Until connected the modem takes
+// AT commands and answers OK, CONNECT, NO CARRIER or ERROR; once online
+// everything goes down the line, until "+++" and a second's pause return to
+// commands. Telnet option requests are answered so BBSes talk plain text.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+const DEFAULT_PORT: u16 = 23;
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+// The pause after "+++" that makes it an escape rather than data
+const GUARD_TIME: Duration = Duration::from_secs(1);
+
+// Telnet commands
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+// The options a BBS is welcome to have: it echoes, and sends without
+// waiting for go-aheads
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+
+enum Line {
+    OnHook,
+    Dialing(Receiver<std::io::Result<TcpStream>>),
+    Connected(TcpStream),
+}
+
+// Where the telnet stream is between commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Telnet {
+    Data,
+    Iac,
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+pub struct Modem {
+    line: Line,
+    // Online, or taking commands (with the line perhaps held after +++)
+    online: bool,
+    command: Vec<u8>,
+    echo: bool,
+    // Bytes for the computer: the line's data, and the modem's answers
+    output: VecDeque<u8>,
+    telnet: Telnet,
+    // "+" typed in a row while online, and when the last one was
+    escape: (u8, Instant),
+}
+
+impl Modem {
+    pub fn new() -> Self {
+        Self {
+            line: Line::OnHook,
+            online: false,
+            command: Vec::new(),
+            echo: true,
+            output: VecDeque::new(),
+            telnet: Telnet::Data,
+            escape: (0, Instant::now()),
+        }
+    }
+
+    // Carrier detect: a connection is up
+    pub fn carrier(&self) -> bool {
+        matches!(self.line, Line::Connected(_))
+    }
+
+    // The next byte for the computer
+    pub fn receive(&mut self) -> Option<u8> {
+        self.output.pop_front()
+    }
+
+    // A byte from the computer
+    pub fn send(&mut self, byte: u8) {
+        if self.online {
+            self.escape = if byte == b'+' { (self.escape.0 + 1, Instant::now()) } else { (0, Instant::now()) };
+            let data: &[u8] = if byte == IAC { &[IAC, IAC] } else { &[byte] };
+            self.write(data);
+            return;
+        }
+        if let Line::Dialing(_) = self.line {
+            // A key pressed while dialing gives up
+            self.hang_up();
+            return;
+        }
+        if self.echo {
+            self.output.push_back(byte);
+        }
+        match byte {
+            b'\r' => {
+                let command = std::mem::take(&mut self.command);
+                self.run(&command);
+            }
+            // Backspace, or the C64's DEL
+            8 | 20 | 127 => {
+                self.command.pop();
+            }
+            // Letters in any case, ASCII or PETSCII, read as capitals
+            _ => self.command.push((byte & 0x7F).to_ascii_uppercase()),
+        }
+    }
+
+    // Move what has arrived on the line along, and notice a connection
+    // made or lost. Called often, but not on every cycle.
+    pub fn poll(&mut self) {
+        if self.online && self.escape.0 == 3 && self.escape.1.elapsed() >= GUARD_TIME {
+            self.escape.0 = 0;
+            self.online = false;
+            self.answer("OK");
+        }
+        match &mut self.line {
+            Line::OnHook => {}
+            Line::Dialing(result) => match result.try_recv() {
+                Ok(Ok(stream)) => {
+                    if let Err(error) = stream.set_nonblocking(true) {
+                        tracing::warn!(target: "rs232", "{}", error);
+                    }
+                    tracing::info!(target: "rs232", "connected to {:?}", stream.peer_addr().ok());
+                    self.line = Line::Connected(stream);
+                    self.online = true;
+                    self.telnet = Telnet::Data;
+                    self.answer("CONNECT");
+                }
+                Ok(Err(error)) => {
+                    tracing::info!(target: "rs232", "dialing failed: {}", error);
+                    self.hang_up();
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => self.hang_up(),
+            },
+            // A line held after +++ keeps its data until back online
+            Line::Connected(_) if !self.online => {}
+            Line::Connected(stream) => {
+                let mut buffer = [0; 1024];
+                match stream.read(&mut buffer) {
+                    Ok(0) => self.hang_up(),
+                    Ok(count) => {
+                        for &byte in &buffer[..count] {
+                            self.telnet_byte(byte);
+                        }
+                    }
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                    Err(error) => {
+                        tracing::info!(target: "rs232", "{}", error);
+                        self.hang_up();
+                    }
+                }
+            }
+        }
+    }
+
+    // An AT command line, which may hold several commands: "ATE0DT..."
+    fn run(&mut self, line: &[u8]) {
+        let Some(mut rest) = line.strip_prefix(b"AT") else {
+            return;
+        };
+        while let Some((&command, after)) = rest.split_first() {
+            rest = after;
+            let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+            let (number, after) = rest.split_at(digits);
+            match command {
+                b'D' => {
+                    let address = String::from_utf8_lossy(rest);
+                    let address = address.trim_start_matches(['T', 'P', ' ']).trim();
+                    if address.is_empty() {
+                        self.answer("ERROR");
+                    } else {
+                        self.dial(address);
+                    }
+                    return;
+                }
+                b'H' => {
+                    if self.carrier() {
+                        self.line = Line::OnHook;
+                    }
+                }
+                b'Z' => {
+                    self.line = Line::OnHook;
+                    self.echo = true;
+                }
+                b'E' => self.echo = number != b"0",
+                b'O' if self.carrier() => {
+                    self.online = true;
+                    self.answer("CONNECT");
+                    return;
+                }
+                b'O' => {
+                    self.answer("NO CARRIER");
+                    return;
+                }
+                b' ' => {}
+                // S registers and the like are taken and ignored
+                b'S' => {
+                    let value = after.iter().position(|b| !b"=0123456789".contains(b)).unwrap_or(after.len());
+                    rest = &after[value..];
+                    continue;
+                }
+                _ if command.is_ascii_alphabetic() || command == b'&' => {}
+                _ => {
+                    self.answer("ERROR");
+                    return;
+                }
+            }
+            rest = after;
+        }
+        self.answer("OK");
+    }
+
+    // Connect in the background, so the emulation carries on meanwhile
+    fn dial(&mut self, address: &str) {
+        let address = if address.contains(':') { address.to_string() } else { format!("{}:{}", address, DEFAULT_PORT) };
+        tracing::info!(target: "rs232", "dialing {}", address);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let result = address.to_socket_addrs().and_then(|addrs| {
+                let mut last = std::io::Error::new(ErrorKind::NotFound, "no address");
+                for addr in addrs {
+                    match TcpStream::connect_timeout(&addr, DIAL_TIMEOUT) {
+                        Ok(stream) => return Ok(stream),
+                        Err(error) => last = error,
+                    }
+                }
+                Err(last)
+            });
+            let _ = sender.send(result);
+        });
+        self.line = Line::Dialing(receiver);
+    }
+
+    fn hang_up(&mut self) {
+        self.line = Line::OnHook;
+        self.online = false;
+        self.answer("NO CARRIER");
+    }
+
+    fn answer(&mut self, message: &str) {
+        self.output.extend(b"\r\n");
+        self.output.extend(message.as_bytes());
+        self.output.extend(b"\r\n");
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if let Line::Connected(stream) = &mut self.line
+            && let Err(error) = stream.write_all(data)
+        {
+            tracing::info!(target: "rs232", "{}", error);
+            self.hang_up();
+        }
+    }
+
+    // A byte off the line: data for the computer, or part of a telnet
+    // command, which is answered
+    fn telnet_byte(&mut self, byte: u8) {
+        self.telnet = match (self.telnet, byte) {
+            (Telnet::Data, IAC) => Telnet::Iac,
+            (Telnet::Data, _) => {
+                self.output.push_back(byte);
+                Telnet::Data
+            }
+            (Telnet::Iac, IAC) => {
+                self.output.push_back(IAC);
+                Telnet::Data
+            }
+            (Telnet::Iac, WILL..=DONT) => Telnet::Option(byte),
+            (Telnet::Iac, SB) => Telnet::Sub,
+            (Telnet::Iac, _) => Telnet::Data,
+            (Telnet::Option(verb), option) => {
+                let welcome = matches!(option, ECHO | SUPPRESS_GO_AHEAD);
+                let reply = match verb {
+                    WILL if welcome => DO,
+                    WILL => DONT,
+                    DO if option == SUPPRESS_GO_AHEAD => WILL,
+                    DO => WONT,
+                    _ => 0,
+                };
+                if reply != 0 {
+                    self.write(&[IAC, reply, option]);
+                }
+                Telnet::Data
+            }
+            (Telnet::Sub, IAC) => Telnet::SubIac,
+            (Telnet::Sub, _) => Telnet::Sub,
+            (Telnet::SubIac, SE) => Telnet::Data,
+            (Telnet::SubIac, _) => Telnet::Sub,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn type_line(modem: &mut Modem, line: &[u8]) {
+        for &byte in line {
+            modem.send(byte);
+        }
+    }
+
+    fn output(modem: &mut Modem) -> String {
+        String::from_utf8_lossy(&std::iter::from_fn(|| modem.receive()).collect::<Vec<u8>>()).into_owned()
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut modem = Modem::new();
+        type_line(&mut modem, b"AT\r");
+        assert_eq!(output(&mut modem), "AT\r\r\nOK\r\n");
+        // Echo off, with an S register
+        type_line(&mut modem, b"ATE0S0=1\r");
+        assert_eq!(output(&mut modem), "ATE0S0=1\r\r\nOK\r\n");
+        // ASCII small letters, and PETSCII shifted ones
+        type_line(&mut modem, b"at\r");
+        assert_eq!(output(&mut modem), "\r\nOK\r\n");
+        type_line(&mut modem, &[0xC1, 0xD4, b'\r']);
+        assert_eq!(output(&mut modem), "\r\nOK\r\n");
+        type_line(&mut modem, b"ATO\r");
+        assert_eq!(output(&mut modem), "\r\nNO CARRIER\r\n");
+        type_line(&mut modem, b"ATD\r");
+        assert_eq!(output(&mut modem), "\r\nERROR\r\n");
+        type_line(&mut modem, b"HELLO\r");
+        assert_eq!(output(&mut modem), "");
+    }
+
+    #[test]
+    fn test_dial_and_escape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut modem = Modem::new();
+        type_line(&mut modem, format!("ATE0DT127.0.0.1:{}\r", port).as_bytes());
+        assert_eq!(output(&mut modem), format!("ATE0DT127.0.0.1:{}\r", port));
+        let (mut bbs, _) = listener.accept().unwrap();
+        while !modem.carrier() {
+            modem.poll();
+        }
+        assert_eq!(output(&mut modem), "\r\nCONNECT\r\n");
+
+        // The BBS asks to echo and for the terminal type, and sends text
+        bbs.write_all(&[IAC, WILL, ECHO, IAC, DO, 24, b'H', b'I', IAC, IAC]).unwrap();
+        let mut received = String::new();
+        while received.len() < 3 {
+            modem.poll();
+            received.extend(std::iter::from_fn(|| modem.receive()).map(char::from));
+        }
+        assert_eq!(received, "HI\u{FF}");
+        let mut reply = [0; 6];
+        bbs.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [IAC, DO, ECHO, IAC, WONT, 24]);
+
+        type_line(&mut modem, b"X+++");
+        modem.escape.1 -= GUARD_TIME;
+        modem.poll();
+        assert_eq!(output(&mut modem), "\r\nOK\r\n");
+        type_line(&mut modem, b"ATH\r");
+        assert_eq!(output(&mut modem), "\r\nOK\r\n");
+        assert!(!modem.carrier());
+        let mut sent = Vec::new();
+        bbs.read_to_end(&mut sent).unwrap();
+        assert_eq!(sent, b"X+++");
+    }
+}
@@ -0,0 +1,177 @@
+// Sound output: SID samples played on the default output device through
+// cpal. This needs the "audio" feature (and on Linux the ALSA development
+// files); without it the SID still runs but the machine stays silent.
+
+#[cfg(feature = "audio")]
+pub use output::AudioOutput;
+
+#[cfg(feature = "audio")]
+mod output {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::Duration;
+
+    use anyhow::{anyhow, Result};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{FromSample, SizedSample};
+
+    // Samples held back before playing starts, and after running dry, so
+    // the uneven pace of emulated frames doesn't cut the sound up
+    const LATENCY_SECONDS: f32 = 0.06;
+    // Beyond this, as in warp mode, the oldest samples are dropped
+    const MAX_QUEUED_SECONDS: f32 = 0.2;
+    // Longest to wait for the device to take samples, in case it has stopped
+    const MAX_WAIT: Duration = Duration::from_millis(100);
+    // The C64's audio output is AC coupled, which takes the SID's DC offset
+    // out; a high pass at this frequency does the same
+    const DC_BLOCK_HZ: f32 = 16.0;
+
+    struct Queue {
+        samples: VecDeque<f32>,
+        buffering: bool,
+        // DC blocker state: the last sample in and out
+        last_input: f32,
+        last_output: f32,
+    }
+
+    // The queue and a signal the callback raises each time it takes from it
+    struct Shared {
+        queue: Mutex<Queue>,
+        played: Condvar,
+    }
+
+    pub struct AudioOutput {
+        _stream: cpal::Stream,
+        shared: Arc<Shared>,
+        sample_rate: u32,
+        volume: f32,
+        muted: bool,
+    }
+
+    impl AudioOutput {
+        pub fn open() -> Result<Self> {
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| anyhow!("no audio output device"))?;
+            let config = device.default_output_config()?;
+            let sample_rate = config.sample_rate().0;
+            let shared = Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    samples: VecDeque::new(),
+                    buffering: true,
+                    last_input: 0.0,
+                    last_output: 0.0,
+                }),
+                played: Condvar::new(),
+            });
+            let stream = match config.sample_format() {
+                cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config.into(), shared.clone(), sample_rate)?,
+                cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config.into(), shared.clone(), sample_rate)?,
+                cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config.into(), shared.clone(), sample_rate)?,
+                format => return Err(anyhow!("unsupported sample format {}", format)),
+            };
+            stream.play()?;
+            Ok(Self { _stream: stream, shared, sample_rate, volume: 1.0, muted: false })
+        }
+
+        pub fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        // Master volume, 0.0-1.0
+        pub fn set_volume(&mut self, volume: f32) {
+            self.volume = volume.clamp(0.0, 1.0);
+        }
+
+        pub fn toggle_mute(&mut self) {
+            self.muted = !self.muted;
+        }
+
+        pub fn is_muted(&self) -> bool {
+            self.muted
+        }
+
+        pub fn queue(&self, samples: &[f32]) {
+            let pole = 1.0 - 2.0 * std::f32::consts::PI * DC_BLOCK_HZ / self.sample_rate as f32;
+            let gain = if self.muted { 0.0 } else { self.volume };
+            let mut queue = self.shared.queue.lock().unwrap();
+            for &sample in samples {
+                let output = sample - queue.last_input + pole * queue.last_output;
+                queue.last_input = sample;
+                queue.last_output = output;
+                queue.samples.push_back((output * gain).clamp(-1.0, 1.0));
+            }
+            let max = (self.sample_rate as f32 * MAX_QUEUED_SECONDS) as usize;
+            if queue.samples.len() > max {
+                let excess = queue.samples.len() - max;
+                queue.samples.drain(..excess);
+            }
+        }
+
+        // Block until the device has played the queue down to the latency
+        // target. Calling this once per emulated frame runs the machine off
+        // the sound card's clock, so the two can't drift apart.
+        pub fn wait_for_room(&self) {
+            let latency = (self.sample_rate as f32 * LATENCY_SECONDS) as usize;
+            let queue = self.shared.queue.lock().unwrap();
+            let _ = self.shared.played.wait_timeout_while(queue, MAX_WAIT, |queue| queue.samples.len() > latency);
+        }
+    }
+
+    // The mono SID output goes to every channel
+    fn build_stream<T: SizedSample + FromSample<f32>>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        shared: Arc<Shared>,
+        sample_rate: u32,
+    ) -> Result<cpal::Stream> {
+        let channels = config.channels as usize;
+        let latency = (sample_rate as f32 * LATENCY_SECONDS) as usize;
+        let stream = device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let mut queue = shared.queue.lock().unwrap();
+                if queue.buffering && queue.samples.len() >= latency {
+                    queue.buffering = false;
+                }
+                for frame in data.chunks_mut(channels) {
+                    let sample = if queue.buffering { None } else { queue.samples.pop_front() };
+                    if sample.is_none() {
+                        queue.buffering = true;
+                    }
+                    frame.fill(T::from_sample(sample.unwrap_or(0.0)));
+                }
+                shared.played.notify_all();
+            },
+            |err| tracing::warn!(target: "sid", "Audio stream error: {}", err),
+            None,
+        )?;
+        Ok(stream)
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+pub struct AudioOutput;
+
+#[cfg(not(feature = "audio"))]
+impl AudioOutput {
+    pub fn open() -> anyhow::Result<Self> {
+        anyhow::bail!("built without the audio feature")
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        0
+    }
+
+    pub fn set_volume(&mut self, _volume: f32) {}
+
+    pub fn toggle_mute(&mut self) {}
+
+    pub fn is_muted(&self) -> bool {
+        false
+    }
+
+    pub fn queue(&self, _samples: &[f32]) {}
+
+    pub fn wait_for_room(&self) {}
+}
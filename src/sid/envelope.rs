@@ -0,0 +1,145 @@
+// ADSR envelope generator: an 8-bit level that the gate bit sends up
+// (attack), down to the sustain level (decay) and back to zero (release)
+
+use serde::{Deserialize, Serialize};
+
+// Cycles per level step for each 4-bit rate (2ms to 8s for a full attack)
+const RATE_PERIODS: [u16; 16] = [9, 32, 63, 95, 149, 220, 267, 313, 392, 977, 1954, 3126, 3907, 11720, 19532, 31251];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum State {
+    Attack,
+    DecaySustain,
+    Release,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Envelope {
+    attack_decay: u8,    // $D405: attack (bits 4-7), decay (bits 0-3)
+    sustain_release: u8, // $D406: sustain level (bits 4-7), release (bits 0-3)
+    state: State,
+    gate: bool,
+    rate_counter: u16,
+    exponential_counter: u8,
+    level: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            attack_decay: 0,
+            sustain_release: 0,
+            state: State::Release,
+            gate: false,
+            rate_counter: 0,
+            exponential_counter: 0,
+            level: 0,
+        }
+    }
+
+    pub fn set_attack_decay(&mut self, value: u8) {
+        self.attack_decay = value;
+    }
+
+    pub fn set_sustain_release(&mut self, value: u8) {
+        self.sustain_release = value;
+    }
+
+    // Control register bit 0: a rising gate starts the attack, a falling one
+    // the release, from whatever level the envelope is at
+    pub fn set_gate(&mut self, gate: bool) {
+        if gate && !self.gate {
+            self.state = State::Attack;
+        } else if !gate && self.gate {
+            self.state = State::Release;
+        }
+        self.gate = gate;
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn clock(&mut self) {
+        let rate = match self.state {
+            State::Attack => self.attack_decay >> 4,
+            State::DecaySustain => self.attack_decay & 0x0F,
+            State::Release => self.sustain_release & 0x0F,
+        };
+        self.rate_counter += 1;
+        if self.rate_counter < RATE_PERIODS[rate as usize] {
+            return;
+        }
+        self.rate_counter = 0;
+
+        if self.state == State::Attack {
+            self.level = self.level.saturating_add(1);
+            if self.level == 0xFF {
+                self.state = State::DecaySustain;
+            }
+            return;
+        }
+
+        // Decay and release slow down as the level falls, roughly following
+        // an exponential curve
+        self.exponential_counter += 1;
+        if self.exponential_counter < exponential_period(self.level) {
+            return;
+        }
+        self.exponential_counter = 0;
+
+        let floor = match self.state {
+            State::DecaySustain => (self.sustain_release >> 4) * 0x11,
+            _ => 0,
+        };
+        if self.level > floor {
+            self.level -= 1;
+        }
+    }
+}
+
+// Rate counter periods per level step during decay and release
+fn exponential_period(level: u8) -> u8 {
+    match level {
+        0x5E..=0xFF => 1,
+        0x37..=0x5D => 2,
+        0x1B..=0x36 => 4,
+        0x0F..=0x1A => 8,
+        0x07..=0x0E => 16,
+        0x01..=0x06 => 30,
+        0 => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_adsr() {
+        let mut envelope = Envelope::new();
+        envelope.set_attack_decay(0x00);
+        envelope.set_sustain_release(0xA0);
+        envelope.set_gate(true);
+
+        // Fastest attack: 255 steps of 9 cycles
+        for _ in 0..255 * 9 - 1 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.level(), 0xFE);
+        envelope.clock();
+        assert_eq!(envelope.level(), 0xFF);
+
+        // Decay stops at the sustain level
+        for _ in 0..100_000 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.level(), 0xAA);
+
+        envelope.set_gate(false);
+        for _ in 0..100_000 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.level(), 0);
+    }
+}
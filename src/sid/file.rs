@@ -0,0 +1,167 @@
+// PSID/RSID music files (.sid): a header naming the tune and its entry
+// points, followed by the C64 code and data to load
+
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+
+use super::SidModel;
+use crate::machine::VideoStandard;
+
+pub struct SidFile {
+    // RSID tunes need a real C64 environment (KERNAL, CIA interrupts)
+    // rather than just being called
+    pub rsid: bool,
+    pub name: String,
+    pub author: String,
+    pub released: String,
+    pub load_address: u16,
+    pub init_address: u16,
+    // 0 when the tune's init installs an interrupt handler that plays it
+    pub play_address: u16,
+    pub songs: u16,
+    pub start_song: u16, // Counted from 1
+    // Bit n set: song n + 1 is timed by a CIA timer, else by the vertical
+    // blank. Songs past 32 use bit 31.
+    speed: u32,
+    // Version 2+ flags; None when the file doesn't say
+    pub standard: Option<VideoStandard>,
+    pub model: Option<SidModel>,
+    // Page free for a driver: 0 for anywhere outside the tune, $FF for none
+    pub start_page: u8,
+    pub data: Vec<u8>,
+}
+
+impl SidFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).context(format!("Failed to read {:?}", path))?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        // Header fields are big-endian
+        let word = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        // Latin-1 strings padded with zeros
+        let text = |offset: usize| -> String {
+            bytes[offset..offset + 32].iter().take_while(|&&b| b != 0).map(|&b| b as char).collect()
+        };
+
+        if bytes.len() < 0x76 {
+            anyhow::bail!("not a SID file: too short");
+        }
+        let rsid = match &bytes[0..4] {
+            b"PSID" => false,
+            b"RSID" => true,
+            _ => anyhow::bail!("not a SID file: no PSID/RSID magic"),
+        };
+        let version = word(0x04);
+        let data_offset = word(0x06) as usize;
+        if !(1..=4).contains(&version) || data_offset > bytes.len() || data_offset < 0x76 {
+            anyhow::bail!("unsupported SID file version {} (data at ${:04X})", version, data_offset);
+        }
+
+        // A load address of 0 means the data starts with one, as in a PRG
+        let mut data = &bytes[data_offset..];
+        let mut load_address = word(0x08);
+        if load_address == 0 {
+            if data.len() < 2 {
+                anyhow::bail!("SID file has no load address");
+            }
+            load_address = u16::from_le_bytes([data[0], data[1]]);
+            data = &data[2..];
+        }
+        if data.is_empty() {
+            anyhow::bail!("SID file has no tune data");
+        }
+        if load_address as usize + data.len() > 0x10000 {
+            anyhow::bail!("SID file data doesn't fit in memory");
+        }
+        let init_address = match word(0x0A) {
+            0 => load_address,
+            address => address,
+        };
+
+        let (mut standard, mut model, mut start_page) = (None, None, 0);
+        if version >= 2 && data_offset >= 0x7C {
+            let flags = word(0x76);
+            standard = match (flags >> 2) & 0x03 {
+                1 => Some(VideoStandard::Pal),
+                2 => Some(VideoStandard::Ntsc),
+                _ => None,
+            };
+            model = match (flags >> 4) & 0x03 {
+                1 => Some(SidModel::Mos6581),
+                2 => Some(SidModel::Mos8580),
+                _ => None,
+            };
+            start_page = bytes[0x78];
+        }
+
+        let songs = word(0x0E).max(1);
+        Ok(Self {
+            rsid,
+            name: text(0x16),
+            author: text(0x36),
+            released: text(0x56),
+            load_address,
+            init_address,
+            play_address: word(0x0C),
+            songs,
+            start_song: word(0x10).clamp(1, songs),
+            speed: u32::from_be_bytes([bytes[0x12], bytes[0x13], bytes[0x14], bytes[0x15]]),
+            standard,
+            model,
+            start_page,
+            data: data.to_vec(),
+        })
+    }
+
+    // First address past the tune's data
+    pub fn end_address(&self) -> u32 {
+        self.load_address as u32 + self.data.len() as u32
+    }
+
+    // Whether song `song` (from 1) is timed by CIA1 timer A rather than
+    // once per frame. RSID tunes always set up their own timing.
+    pub fn cia_timed(&self, song: u16) -> bool {
+        self.rsid || self.speed & (1 << (song - 1).min(31)) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_psid() {
+        let mut bytes = vec![0; 0x7C];
+        bytes[0..4].copy_from_slice(b"PSID");
+        bytes[0x05] = 2;
+        bytes[0x07] = 0x7C;
+        bytes[0x0A..0x0E].copy_from_slice(&[0x10, 0x00, 0x10, 0x03]);
+        bytes[0x0F] = 3;
+        bytes[0x11] = 2;
+        bytes[0x15] = 0x04;
+        bytes[0x16..0x1B].copy_from_slice(b"Tune\0");
+        bytes[0x36..0x3A].copy_from_slice(b"Me\xE9\0");
+        bytes[0x77] = 0x24; // PAL, 8580
+        // No load address in the header: it comes first in the data
+        bytes.extend_from_slice(&[0x00, 0x10, 0xA9, 0x00, 0x60]);
+
+        let sid = SidFile::parse(&bytes).unwrap();
+        assert!(!sid.rsid);
+        assert_eq!((sid.name.as_str(), sid.author.as_str()), ("Tune", "Meé"));
+        assert_eq!((sid.load_address, sid.init_address, sid.play_address), (0x1000, 0x1000, 0x1003));
+        assert_eq!((sid.songs, sid.start_song), (3, 2));
+        assert_eq!((sid.standard, sid.model), (Some(VideoStandard::Pal), Some(SidModel::Mos8580)));
+        assert_eq!(sid.data, [0xA9, 0x00, 0x60]);
+        assert_eq!(sid.end_address(), 0x1003);
+        assert!(!sid.cia_timed(1) && !sid.cia_timed(2) && sid.cia_timed(3));
+
+        // A load address and nothing after it
+        assert!(SidFile::parse(&bytes[..0x7E]).is_err());
+
+        bytes[0] = b'X';
+        assert!(SidFile::parse(&bytes).is_err());
+    }
+}
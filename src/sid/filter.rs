@@ -0,0 +1,138 @@
+// The SID's multimode filter: a two-integrator state variable filter with
+// low, band and high pass outputs, an 11-bit cutoff and 4-bit resonance
+
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use super::SidModel;
+
+// Cutoff frequency in Hz at points along the 11-bit register range, as
+// measured on a 6581. The curve is far from linear and has a step at the
+// middle of the range. The 8580's is close to a straight line.
+const CUTOFF_CURVE_6581: [(u16, f32); 27] = [
+    (0, 220.0), (128, 230.0), (256, 250.0), (384, 300.0), (512, 420.0), (640, 780.0), (768, 1600.0),
+    (832, 2300.0), (896, 3200.0), (960, 4300.0), (992, 5000.0), (1008, 5400.0), (1016, 5700.0),
+    (1023, 6000.0), (1024, 4600.0), (1032, 4800.0), (1056, 5300.0), (1088, 6000.0), (1120, 6600.0),
+    (1152, 7200.0), (1280, 9500.0), (1408, 12000.0), (1536, 14500.0), (1664, 16000.0), (1792, 17100.0),
+    (1920, 17700.0), (2047, 18000.0),
+];
+const CUTOFF_MAX_8580: f32 = 12_500.0;
+
+#[derive(Serialize, Deserialize)]
+pub struct Filter {
+    cutoff: u16,    // $D415 bits 0-2, $D416
+    resonance: u8,  // $D417 bits 4-7
+    pub routing: u8, // $D417 bits 0-3: voices 1-3 and the external input
+    pub mode: u8,    // $D418 bits 4-6: low, band and high pass
+    model: SidModel,
+
+    // Per cycle integrator gain from the cutoff, and the damping from the
+    // resonance
+    w0: f32,
+    damping: f32,
+    clock_hz: f32,
+
+    low: f32,
+    band: f32,
+    high: f32,
+}
+
+impl Filter {
+    pub fn new(clock_hz: u32) -> Self {
+        let mut filter = Self {
+            cutoff: 0,
+            resonance: 0,
+            routing: 0,
+            mode: 0,
+            model: SidModel::Mos6581,
+            w0: 0.0,
+            damping: 0.0,
+            clock_hz: clock_hz as f32,
+            low: 0.0,
+            band: 0.0,
+            high: 0.0,
+        };
+        filter.update();
+        filter
+    }
+
+    pub fn set_clock(&mut self, clock_hz: u32) {
+        self.clock_hz = clock_hz as f32;
+        self.update();
+    }
+
+    pub fn set_model(&mut self, model: SidModel) {
+        self.model = model;
+        self.update();
+    }
+
+    pub fn set_cutoff_low(&mut self, value: u8) {
+        self.cutoff = (self.cutoff & 0x7F8) | (value & 0x07) as u16;
+        self.update();
+    }
+
+    pub fn set_cutoff_high(&mut self, value: u8) {
+        self.cutoff = (self.cutoff & 0x007) | (value as u16) << 3;
+        self.update();
+    }
+
+    pub fn set_resonance_routing(&mut self, value: u8) {
+        self.resonance = value >> 4;
+        self.routing = value & 0x0F;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        self.w0 = 2.0 * PI * cutoff_hz(self.model, self.cutoff) / self.clock_hz;
+        // 1/Q, from Q = 0.707 with no resonance to 1.707 at full
+        self.damping = 1.0 / (0.707 + self.resonance as f32 / 15.0);
+    }
+
+    // Run the filter a cycle on the sum of the routed voices and return the
+    // selected outputs
+    pub fn clock(&mut self, input: f32) -> f32 {
+        self.low += self.w0 * self.band;
+        self.high = input - self.low - self.damping * self.band;
+        self.band += self.w0 * self.high;
+
+        let mut output = 0.0;
+        if self.mode & 0x10 != 0 {
+            output += self.low;
+        }
+        if self.mode & 0x20 != 0 {
+            output += self.band;
+        }
+        if self.mode & 0x40 != 0 {
+            output += self.high;
+        }
+        output
+    }
+}
+
+fn cutoff_hz(model: SidModel, cutoff: u16) -> f32 {
+    if model == SidModel::Mos8580 {
+        return CUTOFF_MAX_8580 * cutoff as f32 / 2047.0;
+    }
+    let upper = CUTOFF_CURVE_6581.iter().position(|&(fc, _)| fc >= cutoff).unwrap_or(CUTOFF_CURVE_6581.len() - 1);
+    if upper == 0 {
+        return CUTOFF_CURVE_6581[0].1;
+    }
+    let (x0, y0) = CUTOFF_CURVE_6581[upper - 1];
+    let (x1, y1) = CUTOFF_CURVE_6581[upper];
+    y0 + (y1 - y0) * (cutoff - x0) as f32 / (x1 - x0) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cutoff_curves() {
+        assert_eq!(cutoff_hz(SidModel::Mos6581, 0), 220.0);
+        assert_eq!(cutoff_hz(SidModel::Mos6581, 576), 600.0);
+        assert_eq!(cutoff_hz(SidModel::Mos6581, 2047), 18_000.0);
+        assert_eq!(cutoff_hz(SidModel::Mos8580, 0), 0.0);
+        assert_eq!(cutoff_hz(SidModel::Mos8580, 2047), 12_500.0);
+    }
+}
@@ -1 +1,404 @@
-// SID chip placeholder
+// SID sound chip (6581 or 8580): three voices, the filter, and the master
+// volume, mixed down to mono samples for the audio output
+
+pub mod audio;
+mod envelope;
+pub mod file;
+mod filter;
+mod voice;
+
+use serde::{Deserialize, Serialize};
+
+use crate::machine::VideoStandard;
+use filter::Filter;
+use voice::Voice;
+
+// Largest voice output: a full scale waveform at full envelope
+const VOICE_MAX: f32 = (0x800 * 0xFF) as f32;
+// The 6581's mixer adds a negative DC offset of its own
+const MIXER_DC_6581: f32 = -((0xFFF * 0xFF / 18) as f32) / VOICE_MAX;
+// Offset fed into the 8580's mixer with digiboost on, about one 6581
+// voice's worth
+const DIGIBOOST_DC: f32 = 1.0;
+
+// The two SID revisions. The 8580 replaced the 6581 in later C64s; its
+// filter, DC offsets and combined waveforms differ enough that tunes written
+// for one sound wrong on the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, Serialize, Deserialize)]
+pub enum SidModel {
+    #[default]
+    #[value(name = "6581")]
+    Mos6581,
+    #[value(name = "8580")]
+    Mos8580,
+}
+
+// Serializable for snapshots. The paddles and the sample output belong to
+// the host, and are left out along with the voice mutes.
+#[derive(Serialize, Deserialize)]
+pub struct Sid {
+    voices: [Voice; 3],
+    filter: Filter,
+    volume: u8,       // $D418 bits 0-3
+    voice3_off: bool, // $D418 bit 7: voice 3 left out of the unfiltered mix
+    model: SidModel,
+    // The volume scales the mixer's DC offset as well as the voices, so
+    // rapid $D418 writes play 4-bit samples. The 8580 has next to no offset
+    // for them to be heard by; digiboost adds one, as an EXT IN mod does.
+    digiboost: bool,
+    clock_hz: u32,
+    // Paddle pair in each control port (POTX, POTY), or None for nothing
+    // plugged in
+    #[serde(skip)]
+    paddles: [Option<(u8, u8)>; 2],
+
+    // Output at `sample_rate` samples per second, each the average of the
+    // cycles since the previous one. Nothing is mixed until a rate is set.
+    #[serde(skip)]
+    sample_rate: Option<u32>,
+    #[serde(skip)]
+    sample_phase: u32,
+    #[serde(skip)]
+    sample_sum: f32,
+    #[serde(skip)]
+    sample_cycles: u32,
+    #[serde(skip)]
+    samples: Vec<f32>,
+}
+
+impl Sid {
+    pub fn new() -> Self {
+        let clock_hz = VideoStandard::Pal.clock_hz();
+        Self {
+            voices: [Voice::new(), Voice::new(), Voice::new()],
+            filter: Filter::new(clock_hz),
+            volume: 0,
+            voice3_off: false,
+            model: SidModel::Mos6581,
+            digiboost: false,
+            clock_hz,
+            paddles: [None; 2],
+            sample_rate: None,
+            sample_phase: 0,
+            sample_sum: 0.0,
+            sample_cycles: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    // The SID runs off the CPU clock, which sets its pitch
+    pub fn set_standard(&mut self, standard: VideoStandard) {
+        self.clock_hz = standard.clock_hz();
+        self.filter.set_clock(self.clock_hz);
+    }
+
+    pub fn set_model(&mut self, model: SidModel) {
+        self.model = model;
+        for voice in &mut self.voices {
+            voice.set_model(model);
+        }
+        self.filter.set_model(model);
+    }
+
+    pub fn set_digiboost(&mut self, digiboost: bool) {
+        self.digiboost = digiboost;
+    }
+
+    // Host side mute for voice `n` (0-2), for picking out one part of a
+    // tune. The registers, and OSC3/ENV3, behave as before.
+    pub fn toggle_voice_mute(&mut self, n: usize) {
+        self.voices[n].muted = !self.voices[n].muted;
+    }
+
+    pub fn voice_muted(&self, n: usize) -> bool {
+        self.voices[n].muted
+    }
+
+    // Take on the chip state in a snapshot, keeping the host's paddles,
+    // sound output and voice mutes
+    pub fn restore(&mut self, snapshot: Sid) {
+        let old = std::mem::replace(self, snapshot);
+        self.paddles = old.paddles;
+        self.sample_rate = old.sample_rate;
+        self.sample_phase = old.sample_phase;
+        self.sample_sum = old.sample_sum;
+        self.sample_cycles = old.sample_cycles;
+        self.samples = old.samples;
+        for (voice, old) in self.voices.iter_mut().zip(old.voices) {
+            voice.muted = old.muted;
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = Some(sample_rate);
+    }
+
+    // Samples mixed since the last call. A full scale voice swings 1/3 either
+    // way at full volume; the 6581 adds its DC offsets on top.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // The registers repeat every 32 bytes through $D400-$D7FF
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        let reg = (addr & 0x1F) as usize;
+        tracing::trace!(target: "sid", "${:04X} <- ${:02X}", 0xD400 + reg, value);
+        match reg {
+            0x00..=0x14 => self.voices[reg / 7].write_register(reg % 7, value),
+            0x15 => self.filter.set_cutoff_low(value),
+            0x16 => self.filter.set_cutoff_high(value),
+            0x17 => self.filter.set_resonance_routing(value),
+            0x18 => {
+                self.volume = value & 0x0F;
+                self.filter.mode = value & 0x70;
+                self.voice3_off = value & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    #[cfg(any(feature = "gamepad", test))]
+    pub fn set_paddles(&mut self, port: u8, paddles: Option<(u8, u8)>) {
+        self.paddles[port as usize - 1] = paddles;
+    }
+
+    // POTX/POTY read the paddles in the control port CIA1 PA6 (port 1) or
+    // PA7 (port 2) switches through to the SID; two paddles in parallel
+    // give the lower reading. Nothing connected reads $FF, as the pot's
+    // capacitor never charges.
+    pub fn read_pot(&self, addr: u16, port_a: u8) -> u8 {
+        (0..2)
+            .filter(|&port| port_a & (0x40 << port) != 0)
+            .filter_map(|port| self.paddles[port])
+            .map(|(x, y)| if addr & 0x1F == 0x19 { x } else { y })
+            .min()
+            .unwrap_or(0xFF)
+    }
+
+    // Only $D419-$D41C can be read; the pots through read_pot(). OSC3 and
+    // ENV3 give the top of voice 3's waveform and its envelope level; with
+    // the voice set to noise, OSC3 is the usual source of random numbers.
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr & 0x1F {
+            0x1B => (self.voices[2].waveform(&self.voices[1]) >> 4) as u8,
+            0x1C => self.voices[2].envelope.level(),
+            _ => 0,
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        for voice in &mut self.voices {
+            voice.clock();
+            voice.envelope.clock();
+        }
+        // Voice 1 is synced by voice 3, 2 by 1, 3 by 2
+        for n in 0..3 {
+            let (voice, source) = self.voice_and_source(n);
+            voice.synchronize(source);
+        }
+
+        let Some(sample_rate) = self.sample_rate else { return };
+        self.sample_sum += self.mix();
+        self.sample_cycles += 1;
+        self.sample_phase += sample_rate;
+        if self.sample_phase >= self.clock_hz {
+            self.sample_phase -= self.clock_hz;
+            self.samples.push(self.sample_sum / self.sample_cycles as f32);
+            self.sample_sum = 0.0;
+            self.sample_cycles = 0;
+        }
+    }
+
+    fn voice_and_source(&mut self, n: usize) -> (&mut Voice, &Voice) {
+        let [v1, v2, v3] = &mut self.voices;
+        match n {
+            0 => (v1, v3),
+            1 => (v2, v1),
+            _ => (v3, v2),
+        }
+    }
+
+    // Current output: the voices routed through the filter and the rest
+    // straight through, scaled by the volume
+    fn mix(&mut self) -> f32 {
+        let mut direct = 0.0;
+        let mut filtered = 0.0;
+        for n in 0..3 {
+            let source = &self.voices[(n + 2) % 3];
+            let output = self.voices[n].output(source) as f32 / VOICE_MAX;
+            if self.filter.routing & (1 << n) != 0 {
+                filtered += output;
+            } else if !(n == 2 && self.voice3_off) {
+                direct += output;
+            }
+        }
+        let mut output = direct + self.filter.clock(filtered);
+        output += match self.model {
+            SidModel::Mos6581 => MIXER_DC_6581,
+            SidModel::Mos8580 if self.digiboost => DIGIBOOST_DC,
+            SidModel::Mos8580 => 0.0,
+        };
+        output / 3.0 * self.volume as f32 / 15.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sid_output() {
+        let mut sid = Sid::new();
+        sid.set_sample_rate(44_100);
+        // No DC offsets to get in the way
+        sid.set_model(SidModel::Mos8580);
+
+        // Silent until a voice is gated on
+        sid.tick(255);
+        assert!(sid.take_samples().iter().all(|&s| s == 0.0));
+
+        // Voice 1: 440 Hz pulse, fastest attack, full sustain, full volume
+        let frequency = (440.0 * 16_777_216.0 / VideoStandard::Pal.clock_hz() as f64) as u16;
+        sid.write_register(0xD400, frequency as u8);
+        sid.write_register(0xD401, (frequency >> 8) as u8);
+        sid.write_register(0xD403, 0x08);
+        sid.write_register(0xD405, 0x00);
+        sid.write_register(0xD406, 0xF0);
+        sid.write_register(0xD418, 0x0F);
+        sid.write_register(0xD404, 0x41);
+
+        // A second of sound at the sample rate
+        for _ in 0..VideoStandard::Pal.clock_hz() {
+            sid.tick(1);
+        }
+        let samples = sid.take_samples();
+        assert!((samples.len() as i32 - 44_100).abs() <= 1);
+
+        // A square wave at a third of full scale, crossing zero 880 times
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - 1.0 / 3.0).abs() < 0.01);
+        let crossings = samples.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count();
+        assert!((crossings as i32 - 880).abs() <= 2);
+    }
+
+    #[test]
+    fn test_voice_3_readback() {
+        let mut sid = Sid::new();
+        // Voice 3 at full frequency: sawtooth, then noise
+        sid.write_register(0xD40E, 0xFF);
+        sid.write_register(0xD40F, 0xFF);
+        sid.write_register(0xD412, 0x20);
+        sid.tick(0x80);
+        assert_eq!(sid.read_register(0xD41B), 0x7F);
+        // Registers repeat every 32 bytes
+        assert_eq!(sid.read_register(0xD5FB), 0x7F);
+
+        sid.write_register(0xD412, 0x80);
+        let values: std::collections::HashSet<u8> = (0..256)
+            .map(|_| {
+                sid.tick(16);
+                sid.read_register(0xD41B)
+            })
+            .collect();
+        assert!(values.len() > 100);
+
+        // ENV3 follows the envelope through the attack, even with voice 3
+        // silenced by 3OFF
+        sid.write_register(0xD418, 0x80);
+        sid.write_register(0xD413, 0x00);
+        sid.write_register(0xD414, 0xF0);
+        assert_eq!(sid.read_register(0xD41C), 0);
+        sid.write_register(0xD412, 0x81);
+        for _ in 0..100 {
+            sid.tick(9);
+        }
+        assert_eq!(sid.read_register(0xD41C), 100);
+    }
+
+    #[test]
+    fn test_sid_models() {
+        let level = |model: SidModel, volume: u8| {
+            let mut sid = Sid::new();
+            sid.set_model(model);
+            sid.set_sample_rate(44_100);
+            sid.write_register(0xD418, volume);
+            sid.tick(100);
+            sid.take_samples()[0]
+        };
+
+        // With every voice silent the 6581 still puts out a DC level that
+        // follows the volume; the 8580 stays at zero
+        assert!(level(SidModel::Mos6581, 0x0F) > 0.9);
+        assert!((level(SidModel::Mos6581, 0x08) - level(SidModel::Mos6581, 0x0F) * 8.0 / 15.0).abs() < 1e-6);
+        assert_eq!(level(SidModel::Mos8580, 0x0F), 0.0);
+        assert_eq!(level(SidModel::Mos6581, 0x00), 0.0);
+    }
+
+    #[test]
+    fn test_volume_digis() {
+        let play = |model: SidModel, digiboost: bool| {
+            let mut sid = Sid::new();
+            sid.set_model(model);
+            sid.set_digiboost(digiboost);
+            sid.set_sample_rate(44_100);
+            // A 4-bit sawtooth written to the volume at about 8kHz
+            for n in 0..1000u32 {
+                sid.write_register(0xD418, (n % 16) as u8);
+                sid.tick(123);
+            }
+            let samples = sid.take_samples();
+            let min = samples.iter().fold(f32::MAX, |min, &s| min.min(s));
+            let max = samples.iter().fold(f32::MIN, |max, &s| max.max(s));
+            max - min
+        };
+
+        assert!(play(SidModel::Mos6581, false) > 0.9);
+        assert_eq!(play(SidModel::Mos8580, false), 0.0);
+        assert!(play(SidModel::Mos8580, true) > 0.3);
+    }
+
+    #[test]
+    fn test_paddles() {
+        let mut sid = Sid::new();
+        assert_eq!(sid.read_pot(0xD419, 0x40), 0xFF);
+        sid.set_paddles(1, Some((0x10, 0x20)));
+        sid.set_paddles(2, Some((0x30, 0x08)));
+        assert_eq!((sid.read_pot(0xD419, 0x7F), sid.read_pot(0xD41A, 0x7F)), (0x10, 0x20));
+        assert_eq!((sid.read_pot(0xD419, 0xBF), sid.read_pot(0xD41A, 0xBF)), (0x30, 0x08));
+        assert_eq!((sid.read_pot(0xD419, 0xFF), sid.read_pot(0xD41A, 0xFF)), (0x10, 0x08));
+        assert_eq!(sid.read_pot(0xD419, 0x3F), 0xFF);
+    }
+
+    #[test]
+    fn test_voice_mute() {
+        let peak = |muted: bool| {
+            let mut sid = Sid::new();
+            sid.set_model(SidModel::Mos8580);
+            sid.set_sample_rate(44_100);
+            if muted {
+                sid.toggle_voice_mute(0);
+            }
+            // Voice 1 sawtooth at full sustain and volume
+            sid.write_register(0xD401, 0x10);
+            sid.write_register(0xD406, 0xF0);
+            sid.write_register(0xD418, 0x0F);
+            sid.write_register(0xD404, 0x21);
+            for _ in 0..1000 {
+                sid.tick(100);
+            }
+            assert_eq!(sid.voice_muted(0), muted);
+            // The envelope still runs for ENV3-style readback
+            assert_eq!(sid.voices[0].envelope.level(), 0xFF);
+            sid.take_samples().iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+        };
+
+        assert!(peak(false) > 0.3);
+        assert_eq!(peak(true), 0.0);
+    }
+}
@@ -0,0 +1,220 @@
+// One SID voice: a 24-bit phase accumulating oscillator, the waveforms
+// derived from it, and its envelope
+
+use serde::{Deserialize, Serialize};
+
+use super::envelope::Envelope;
+use super::SidModel;
+
+// Noise shift register after reset
+const NOISE_SEED: u32 = 0x7FFFF8;
+
+#[derive(Serialize, Deserialize)]
+pub struct Voice {
+    frequency: u16,   // $D400/$D401
+    pulse_width: u16, // $D402/$D403, 12 bits
+    control: u8,      // $D404
+    accumulator: u32,
+    shift_register: u32,
+    // The accumulator's top bit went from 0 to 1 this cycle (hard sync)
+    msb_rising: bool,
+    pub envelope: Envelope,
+    model: SidModel,
+    // Silenced on the host's side: the chip carries on, but the voice
+    // outputs as if its envelope were at zero
+    #[serde(skip)]
+    pub muted: bool,
+}
+
+impl Voice {
+    pub fn new() -> Self {
+        Self {
+            frequency: 0,
+            pulse_width: 0,
+            control: 0,
+            accumulator: 0,
+            shift_register: NOISE_SEED,
+            msb_rising: false,
+            envelope: Envelope::new(),
+            model: SidModel::Mos6581,
+            muted: false,
+        }
+    }
+
+    pub fn set_model(&mut self, model: SidModel) {
+        self.model = model;
+    }
+
+    // Register 0-6 of the voice's block of seven
+    pub fn write_register(&mut self, reg: usize, value: u8) {
+        match reg {
+            0 => self.frequency = (self.frequency & 0xFF00) | value as u16,
+            1 => self.frequency = (self.frequency & 0x00FF) | (value as u16) << 8,
+            2 => self.pulse_width = (self.pulse_width & 0x0F00) | value as u16,
+            3 => self.pulse_width = (self.pulse_width & 0x00FF) | ((value & 0x0F) as u16) << 8,
+            4 => {
+                self.control = value;
+                self.envelope.set_gate(value & 0x01 != 0);
+            }
+            5 => self.envelope.set_attack_decay(value),
+            6 => self.envelope.set_sustain_release(value),
+            _ => {}
+        }
+    }
+
+    // Advance the oscillator one cycle. The test bit (control bit 3) holds
+    // the accumulator and noise generator in reset.
+    pub fn clock(&mut self) {
+        if self.control & 0x08 != 0 {
+            self.accumulator = 0;
+            self.shift_register = NOISE_SEED;
+            self.msb_rising = false;
+            return;
+        }
+        let previous = self.accumulator;
+        self.accumulator = (self.accumulator + self.frequency as u32) & 0xFFFFFF;
+        self.msb_rising = previous & 0x800000 == 0 && self.accumulator & 0x800000 != 0;
+
+        // The noise LFSR steps whenever bit 19 goes high
+        if previous & 0x080000 == 0 && self.accumulator & 0x080000 != 0 {
+            let bit = ((self.shift_register >> 22) ^ (self.shift_register >> 17)) & 1;
+            self.shift_register = ((self.shift_register << 1) | bit) & 0x7FFFFF;
+        }
+    }
+
+    // Hard sync (control bit 1): restart whenever the source voice's
+    // oscillator wraps round
+    pub fn synchronize(&mut self, source: &Voice) {
+        if self.control & 0x02 != 0 && source.msb_rising {
+            self.accumulator = 0;
+        }
+    }
+
+    // 12-bit waveform output. `source` is the voice that ring modulates
+    // this one.
+    pub fn waveform(&self, source: &Voice) -> u16 {
+        let mut output = 0x0FFF;
+        let mut selected = 0;
+        let mut combine = |wave: u16| {
+            output &= wave;
+            selected += 1;
+        };
+        if self.control & 0x10 != 0 {
+            combine(self.triangle(source));
+        }
+        if self.control & 0x20 != 0 {
+            combine((self.accumulator >> 12) as u16);
+        }
+        if self.control & 0x40 != 0 {
+            combine(self.pulse());
+        }
+        if self.control & 0x80 != 0 {
+            combine(self.noise());
+        }
+        match selected {
+            0 => 0,
+            1 => output,
+            // Several waveforms drive the same output bits, and a bit low in
+            // one waveform pulls its neighbours down too. The 6581 pulls
+            // harder, so its combined waveforms are quieter and thinner.
+            _ => {
+                let up = (output << 1) | 0x001;
+                let down = (output >> 1) | 0x800;
+                let survives = match self.model {
+                    SidModel::Mos6581 => up & down,
+                    SidModel::Mos8580 => up | down,
+                };
+                output & survives & 0x0FFF
+            }
+        }
+    }
+
+    // Waveform scaled by the envelope. The 8580 centres it on zero; on the
+    // 6581 the waveform DAC's zero sits low and the voice adds a DC offset
+    // of its own, which is still there with the envelope at zero (or the
+    // voice muted).
+    pub fn output(&self, source: &Voice) -> i32 {
+        let (wave_zero, dc) = match self.model {
+            SidModel::Mos6581 => (0x380, 0x800 * 0xFF),
+            SidModel::Mos8580 => (0x800, 0),
+        };
+        let level = if self.muted { 0 } else { self.envelope.level() as i32 };
+        (self.waveform(source) as i32 - wave_zero) * level + dc
+    }
+
+    // Ring modulation (control bit 2) replaces the accumulator's top bit
+    // with its XOR against the source voice's, before folding
+    fn triangle(&self, source: &Voice) -> u16 {
+        let mut msb = self.accumulator & 0x800000;
+        if self.control & 0x04 != 0 {
+            msb ^= source.accumulator & 0x800000;
+        }
+        let folded = if msb != 0 { !self.accumulator } else { self.accumulator };
+        ((folded >> 11) & 0x0FFF) as u16
+    }
+
+    fn pulse(&self) -> u16 {
+        if self.control & 0x08 != 0 || (self.accumulator >> 12) as u16 >= self.pulse_width {
+            0x0FFF
+        } else {
+            0
+        }
+    }
+
+    // Eight bits of the shift register make up the top of the output
+    fn noise(&self) -> u16 {
+        let r = self.shift_register;
+        (((r & 0x400000) >> 11)
+            | ((r & 0x100000) >> 10)
+            | ((r & 0x010000) >> 7)
+            | ((r & 0x002000) >> 5)
+            | ((r & 0x000800) >> 4)
+            | ((r & 0x000080) >> 1)
+            | ((r & 0x000010) << 1)
+            | ((r & 0x000004) << 2)) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voice_waveforms() {
+        let mut voice = Voice::new();
+        let source = Voice::new();
+        // Frequency $1000 wraps the accumulator every 4096 cycles
+        voice.write_register(1, 0x10);
+        voice.write_register(2, 0x00);
+        voice.write_register(3, 0x08);
+
+        for _ in 0..1024 {
+            voice.clock();
+        }
+        voice.write_register(4, 0x20);
+        assert_eq!(voice.waveform(&source), 0x400);
+        voice.write_register(4, 0x10);
+        assert_eq!(voice.waveform(&source), 0x800);
+        voice.write_register(4, 0x40);
+        assert_eq!(voice.waveform(&source), 0);
+
+        for _ in 0..2048 {
+            voice.clock();
+        }
+        // Past the top bit the triangle folds back down and the pulse is high
+        voice.write_register(4, 0x10);
+        assert_eq!(voice.waveform(&source), 0x7FF);
+        voice.write_register(4, 0x40);
+        assert_eq!(voice.waveform(&source), 0xFFF);
+        // Saw and pulse: the 6581 loses the bit with a low neighbour
+        voice.write_register(4, 0x60);
+        assert_eq!(voice.waveform(&source), 0x800);
+        voice.set_model(SidModel::Mos8580);
+        assert_eq!(voice.waveform(&source), 0xC00);
+
+        // The test bit resets the oscillator
+        voice.write_register(4, 0x28);
+        voice.clock();
+        assert_eq!(voice.waveform(&source), 0);
+    }
+}
@@ -0,0 +1,162 @@
+// The media a snapshot was taken with: which disk image or host folder is
+// in each drive, and which tape is in the datasette. Snapshots keep where
+// they are rather than what is on them, so loading one puts the same files
+// back. Disk images are written back as they change, and a checksum of each
+// tells on loading whether it has been changed since.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::iec::device::{Channels, SerialDevice};
+use crate::machine::Machine;
+use crate::memory::Memory;
+use crate::storage::drive::{Drive, Medium};
+use crate::storage::host::HostDir;
+use crate::tape::Tape;
+
+// Device numbers of the disk drives
+const DRIVES: std::ops::RangeInclusive<u8> = 8..=15;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Media {
+    drives: Vec<DriveMedia>,
+    tape: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct DriveMedia {
+    device: u8,
+    disk: Disk,
+    write_protected: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Disk {
+    Folder { path: PathBuf },
+    Image { path: PathBuf, checksum: u64 },
+}
+
+// The media opened from their files, ready to go in
+pub struct Opened {
+    drives: Vec<(u8, Medium, bool)>,
+    tape: Option<Tape>,
+}
+
+impl Media {
+    // What is in the machine's drives and datasette. A disk image made in
+    // memory has no file to go back to, and is left out.
+    pub fn of(machine: &mut Machine) -> Self {
+        let mut drives = Vec::new();
+        for device in DRIVES {
+            let Some(channels) = machine.memory.channels(device) else {
+                continue;
+            };
+            let disk = match channels.medium() {
+                Some(Medium::Host(dir)) => Disk::Folder { path: dir.path().to_path_buf() },
+                Some(medium) => match medium.image_file() {
+                    Some((path, checksum)) => Disk::Image { path: path.to_path_buf(), checksum },
+                    None => {
+                        tracing::warn!(target: "snapshot", "the disk in drive {} has no file, so it is left out", device);
+                        continue;
+                    }
+                },
+                None => continue,
+            };
+            drives.push(DriveMedia { device, disk, write_protected: channels.write_protected() });
+        }
+        let tape = machine.memory.tape.as_ref().map(|tape| tape.path.clone());
+        Self { drives, tape }
+    }
+
+    // Open every file, so a missing one stops the snapshot loading before
+    // anything has changed
+    pub fn open(&self) -> Result<Opened> {
+        let mut drives = Vec::new();
+        for drive in &self.drives {
+            let medium = match &drive.disk {
+                Disk::Folder { path } => {
+                    if !path.is_dir() {
+                        anyhow::bail!("drive {}'s folder {:?} is gone", drive.device, path);
+                    }
+                    Medium::Host(HostDir::new(path))
+                }
+                Disk::Image { path, checksum } => {
+                    let medium = crate::storage::open_disk(path).context(format!("drive {}'s disk", drive.device))?;
+                    if medium.image_file().map(|(_, current)| current) != Some(*checksum) {
+                        tracing::warn!(target: "snapshot", "{:?} has changed since the snapshot", path);
+                    }
+                    medium
+                }
+            };
+            drives.push((drive.device, medium, drive.write_protected));
+        }
+        let tape = self.tape.as_deref().map(Tape::load).transpose()?;
+        Ok(Opened { drives, tape })
+    }
+}
+
+impl Opened {
+    // Swap the disks into the drives already on the bus, plugging in those
+    // that aren't, and the tape into the datasette. Drives the snapshot had
+    // nothing for keep what they have.
+    pub fn insert(self, machine: &mut Machine) {
+        for (device, medium, write_protected) in self.drives {
+            match machine.memory.channels(device) {
+                Some(channels) => {
+                    channels.insert(medium);
+                    channels.set_write_protected(write_protected);
+                }
+                None => {
+                    let mut drive = Drive::new(medium);
+                    drive.set_write_protected(write_protected);
+                    machine.memory.iec.attach(Box::new(SerialDevice::new(device, drive)));
+                }
+            }
+        }
+        machine.memory.tape = self.tape;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::VideoStandard;
+    use crate::storage::d64::D64;
+
+    #[test]
+    fn test_media_round_trip() {
+        let dir = std::env::temp_dir().join(format!("go64-media-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("folder")).unwrap();
+        let image = dir.join("disk.d64");
+        // Any 35 track image will do
+        std::fs::write(&image, vec![0; 174848]).unwrap();
+
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let mut drive = Drive::new(crate::storage::open_disk(&image).unwrap());
+        drive.set_write_protected(true);
+        machine.memory.iec.attach(Box::new(SerialDevice::new(8, drive)));
+        machine.memory.iec.attach(Box::new(SerialDevice::new(9, Drive::new(Medium::Host(HostDir::new(&dir.join("folder")))))));
+        machine.memory.iec.attach(Box::new(SerialDevice::new(10, Drive::new(Medium::Image(D64::format(b"RAM", b"02"))))));
+        let media = Media::of(&mut machine);
+        assert_eq!(media.drives.len(), 2);
+        let json = serde_json::to_string(&media).unwrap();
+        let media: Media = serde_json::from_str(&json).unwrap();
+
+        // A machine with nothing plugged in gets both drives
+        let mut restored = Machine::new(VideoStandard::Pal);
+        media.open().unwrap().insert(&mut restored);
+        assert!(restored.memory.channels(8).unwrap().write_protected());
+        assert!(matches!(restored.memory.channels(9).unwrap().medium(), Some(Medium::Host(_))));
+        assert!(restored.memory.channels(10).is_none());
+        assert_eq!(Media::of(&mut restored), media);
+
+        // A missing image stops the loading
+        std::fs::remove_file(&image).unwrap();
+        assert!(media.open().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+// Machine snapshots: the whole machine saved to a file and loaded back, to
+// suspend and resume a session exactly. The chips derive Serialize and
+// Deserialize for their state; what belongs to the host (ROMs, input, sound
+// output, debugger settings) stays out of the file and is kept on loading.
+// The disks and tape in use are recorded by their files (see media). Files
+// are JSON, with a version in front.
+
+pub mod media;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::machine::Machine;
+use media::Media;
+
+// Bumped whenever the saved state changes shape
+const VERSION: u32 = 2;
+
+// Version 1 snapshots, from before the media were recorded, still load
+const OLDEST_VERSION: u32 = 1;
+
+// Where the snapshot hotkeys save and load, next to the virtual disks
+const QUICK_SNAPSHOT: &str = ".go64/snapshot.json";
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile<M> {
+    version: u32,
+    machine: M,
+    // Missing from version 1: no media
+    #[serde(default)]
+    media: Media,
+}
+
+// Only the version, to check it before reading the rest
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+pub fn quick_path() -> Result<PathBuf> {
+    let home_dir = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
+    Ok(home_dir.join(QUICK_SNAPSHOT))
+}
+
+// Save the machine, and the media in it (which takes asking the drives)
+pub fn save(machine: &mut Machine, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let media = Media::of(machine);
+    fs::write(path, to_json(machine, media)?).context(format!("Failed to write {:?}", path))?;
+    tracing::info!(target: "snapshot", "saved {:?} at PC=${:04X}", path, machine.cpu.pc);
+    Ok(())
+}
+
+pub fn load(machine: &mut Machine, path: &Path) -> Result<()> {
+    let json = fs::read_to_string(path).context(format!("Failed to read {:?}", path))?;
+    restore_json(machine, &json).context(format!("Failed to load snapshot {:?}", path))?;
+    tracing::info!(target: "snapshot", "loaded {:?} at PC=${:04X}", path, machine.cpu.pc);
+    Ok(())
+}
+
+fn to_json(machine: &Machine, media: Media) -> Result<String> {
+    Ok(serde_json::to_string(&SnapshotFile { version: VERSION, machine, media })?)
+}
+
+fn restore_json(machine: &mut Machine, json: &str) -> Result<()> {
+    let header: Header = serde_json::from_str(json)?;
+    if !(OLDEST_VERSION..=VERSION).contains(&header.version) {
+        anyhow::bail!("snapshot version {} is not supported (expected {} to {})", header.version, OLDEST_VERSION, VERSION);
+    }
+    let file: SnapshotFile<Machine> = serde_json::from_str(json)?;
+    let media = file.media.open()?;
+    machine.restore(file.machine);
+    media.insert(machine);
+    Ok(())
+}
+
+// serde only handles arrays of up to 32 elements, so chip memories (color
+// RAM, register files) go through this as a sequence of bytes. Use with
+// #[serde(with = "crate::snapshot::byte_array")].
+pub mod byte_array {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| D::Error::invalid_length(bytes.len(), &format!("{} bytes", N).as_str()))
+    }
+}
+
+// The same for memories kept on the heap (64K of RAM), which never pass
+// through the stack on the way in
+pub mod boxed_byte_array {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<Box<[u8; N]>, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?.into_boxed_slice();
+        bytes
+            .try_into()
+            .map_err(|bytes: Box<[u8]>| D::Error::invalid_length(bytes.len(), &format!("{} bytes", N).as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::VideoStandard;
+    use crate::memory::Memory;
+
+    #[derive(Serialize, Deserialize)]
+    struct Chip {
+        #[serde(with = "super::byte_array")]
+        memory: [u8; 40],
+    }
+
+    #[test]
+    fn test_byte_array_round_trip() {
+        let mut chip = Chip { memory: [0; 40] };
+        chip.memory[39] = 0xAB;
+        let json = serde_json::to_string(&chip).unwrap();
+        let restored: Chip = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.memory, chip.memory);
+
+        // A memory of the wrong size is refused
+        assert!(serde_json::from_str::<Chip>(r#"{"memory":[1,2,3]}"#).is_err());
+    }
+
+    // A machine running a loop that touches RAM, the VIC-II and a CIA timer
+    fn running_machine() -> Machine {
+        let mut machine = Machine::new(VideoStandard::Pal);
+        let program = [
+            0xEE, 0x20, 0xD0, // INC $D020
+            0xE8,             // INX
+            0x8E, 0x00, 0x04, // STX $0400
+            0x4C, 0x00, 0xC0, // JMP $C000
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            machine.memory.write(0xC000 + i as u16, *byte);
+        }
+        machine.memory.write(0xDC0E, 0x11);
+        machine.cpu.pc = 0xC000;
+        machine
+    }
+
+    fn run(machine: &mut Machine, steps: usize) {
+        for _ in 0..steps {
+            machine.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_save_and_resume() {
+        let mut machine = running_machine();
+        run(&mut machine, 5000);
+        let json = to_json(&machine, Media::default()).unwrap();
+
+        // A fresh machine loaded from the snapshot carries on exactly as
+        // the original does
+        let mut restored = Machine::new(VideoStandard::Ntsc);
+        restored.cpu.hle_traps = false;
+        restore_json(&mut restored, &json).unwrap();
+        assert_eq!(restored.standard, VideoStandard::Pal);
+        run(&mut machine, 5000);
+        run(&mut restored, 5000);
+        assert_eq!((restored.cpu.pc, restored.cpu.x, restored.cpu.cycles), (machine.cpu.pc, machine.cpu.x, machine.cpu.cycles));
+        assert_eq!(restored.memory.vic.raster_line(), machine.memory.vic.raster_line());
+        for addr in [0x0400, 0xC000, 0xD020, 0xDC04, 0xDC05, 0xDC0D] {
+            assert_eq!(restored.memory.peek(addr), machine.memory.peek(addr), "${:04X}", addr);
+        }
+
+        // The trap setting is the host's, not the machine's
+        assert!(!restored.cpu.hle_traps);
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let machine = running_machine();
+        let json = to_json(&machine, Media::default()).unwrap().replacen(&format!("\"version\":{}", VERSION), "\"version\":999", 1);
+        let mut restored = Machine::new(VideoStandard::Pal);
+        let error = restore_json(&mut restored, &json).unwrap_err();
+        assert!(error.to_string().contains("version 999"));
+    }
+
+    #[test]
+    fn test_version_1() {
+        // Written before the media were: it loads, with none
+        let machine = running_machine();
+        let json = to_json(&machine, Media::default()).unwrap().replacen(&format!("\"version\":{}", VERSION), "\"version\":1", 1);
+        let json = json.replacen(",\"media\":{\"drives\":[],\"tape\":null}", "", 1);
+        assert!(!json.contains("media"));
+        let mut restored = Machine::new(VideoStandard::Pal);
+        restore_json(&mut restored, &json).unwrap();
+        assert_eq!(restored.cpu.pc, machine.cpu.pc);
+        assert!(restored.memory.tape.is_none());
+    }
+}
@@ -0,0 +1,649 @@
+// D64 disk images: the 683 sectors of a 35 track 1541 disk, one after the
+// other. Files are chains of sectors linked by their first two bytes, the
+// directory is such a chain on track 18 and the BAM (block availability map)
+// in sector 18/0 keeps which sectors are in use.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::{DosError, FileType, name_matches};
+
+pub const SECTOR_SIZE: usize = 256;
+
+/// Track holding the BAM and the directory, which files never use
+const DIR_TRACK: u8 = 18;
+
+/// Tracks the BAM keeps, and so the ones files are allocated on
+pub(super) const TRACKS: u8 = 35;
+
+/// Image sizes: 35 or 40 tracks, each with or without an error byte per sector
+const IMAGE_SIZES: [usize; 4] = [174848, 175531, 196608, 197376];
+
+/// Sectors the 1541 skips between the blocks of a file, and of the directory,
+/// so the next one has not passed the head by the time it is wanted
+const INTERLEAVE: u8 = 10;
+const DIR_INTERLEAVE: u8 = 3;
+
+/// Data bytes in a sector after the link to the next one
+const BLOCK_DATA: usize = SECTOR_SIZE - 2;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const NAME_LEN: usize = 16;
+const PADDING: u8 = 0xA0;
+
+// File type byte of a directory entry: the type in the low bits (see
+// FileType::code), with bit 7 set once the file is closed and bit 6 when
+// it is locked against scratching
+const TYPE_CLOSED: u8 = 0x80;
+const TYPE_LOCKED: u8 = 0x40;
+
+pub(super) fn sectors_per_track(track: u8) -> u8 {
+    match track {
+        1..=17 => 21,
+        18..=24 => 19,
+        25..=30 => 18,
+        _ => 17,
+    }
+}
+
+/// A file in the directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// PETSCII, without the padding
+    pub name: Vec<u8>,
+    pub file_type: u8,
+    pub start: (u8, u8),
+    pub blocks: u16,
+    // Directory sector and byte offset of the entry
+    slot: (u8, u8, usize),
+}
+
+impl Entry {
+    pub fn type_name(&self) -> &'static str {
+        match self.file_type & 0x07 {
+            0 => "DEL",
+            1 => "SEQ",
+            2 => "PRG",
+            3 => "USR",
+            _ => "REL",
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.file_type & TYPE_CLOSED != 0
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.file_type & TYPE_LOCKED != 0
+    }
+}
+
+pub struct D64 {
+    /// Where changes are written back, if the image came from a file
+    path: Option<PathBuf>,
+    data: Vec<u8>,
+    tracks: u8,
+}
+
+impl fmt::Debug for D64 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("D64")
+            .field("path", &self.path)
+            .field("tracks", &self.tracks)
+            .field("blocks_free", &self.blocks_free())
+            .finish()
+    }
+}
+
+impl D64 {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path).context(format!("Failed to read {:?}", path))?;
+        let mut disk = Self::from_bytes(data).context(format!("{:?} is not a D64 image", path))?;
+        disk.path = Some(path.to_path_buf());
+        Ok(disk)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        if !IMAGE_SIZES.contains(&data.len()) {
+            anyhow::bail!("unexpected size of {} bytes", data.len());
+        }
+        let tracks = if data.len() >= IMAGE_SIZES[2] { 40 } else { 35 };
+        Ok(Self { path: None, data, tracks })
+    }
+
+    /// A blank 35 track disk, as formatted with N0:NAME,ID
+    pub fn format(name: &[u8], id: &[u8]) -> Self {
+        let mut disk = Self { path: None, data: vec![0; IMAGE_SIZES[0]], tracks: 35 };
+        let bam = disk.offset(DIR_TRACK, 0).unwrap();
+        disk.data[bam..bam + 4].copy_from_slice(&[DIR_TRACK, 1, b'A', 0]);
+        for track in 1..=TRACKS {
+            for sector in 0..sectors_per_track(track) {
+                disk.free_block(track, sector);
+            }
+        }
+        disk.data[bam + 0x90..bam + 0xAB].fill(PADDING);
+        disk.data[bam + 0x90..bam + 0x90 + name.len().min(NAME_LEN)].copy_from_slice(&name[..name.len().min(NAME_LEN)]);
+        disk.data[bam + 0xA2..bam + 0xA2 + id.len().min(2)].copy_from_slice(&id[..id.len().min(2)]);
+        disk.data[bam + 0xA5..bam + 0xA7].copy_from_slice(b"2A");
+        disk.allocate_block(DIR_TRACK, 0);
+        disk.allocate_block(DIR_TRACK, 1);
+        let dir = disk.offset(DIR_TRACK, 1).unwrap();
+        disk.data[dir + 1] = 0xFF;
+        disk
+    }
+
+    /// Write the image back to the file it came from
+    pub fn flush(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            fs::write(path, &self.data).context(format!("Failed to write {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    fn offset(&self, track: u8, sector: u8) -> Option<usize> {
+        if track == 0 || track > self.tracks || sector >= sectors_per_track(track) {
+            return None;
+        }
+        let before: usize = (1..track).map(|t| sectors_per_track(t) as usize).sum();
+        Some((before + sector as usize) * SECTOR_SIZE)
+    }
+
+    fn sector(&self, track: u8, sector: u8) -> Result<&[u8], DosError> {
+        let offset = self.offset(track, sector).ok_or(DosError::IllegalTrackOrSector)?;
+        Ok(&self.data[offset..offset + SECTOR_SIZE])
+    }
+
+    fn sector_mut(&mut self, track: u8, sector: u8) -> Result<&mut [u8], DosError> {
+        let offset = self.offset(track, sector).ok_or(DosError::IllegalTrackOrSector)?;
+        Ok(&mut self.data[offset..offset + SECTOR_SIZE])
+    }
+
+    // The sectors of the chain starting at `start`, guarded against chains
+    // that run in circles on damaged disks
+    fn chain(&self, start: (u8, u8)) -> Result<Vec<(u8, u8)>, DosError> {
+        let mut blocks = Vec::new();
+        let (mut track, mut sector) = start;
+        while track != 0 {
+            if blocks.len() > self.data.len() / SECTOR_SIZE {
+                return Err(DosError::IllegalTrackOrSector);
+            }
+            blocks.push((track, sector));
+            let data = self.sector(track, sector)?;
+            (track, sector) = (data[0], data[1]);
+        }
+        Ok(blocks)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// FNV-1a hash of the whole image, to tell whether it has changed
+    pub fn checksum(&self) -> u64 {
+        self.data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+    }
+
+    /// Disk name and ID from the BAM, PETSCII without the padding
+    pub fn header(&self) -> (Vec<u8>, Vec<u8>) {
+        let bam = self.sector(DIR_TRACK, 0).unwrap();
+        let name = &bam[0x90..0x90 + NAME_LEN];
+        let end = name.iter().rposition(|&b| b != PADDING).map_or(0, |i| i + 1);
+        (name[..end].to_vec(), bam[0xA2..0xA4].to_vec())
+    }
+
+    /// The DOS type after the ID, "2A" on a 1541 disk
+    pub fn dos_type(&self) -> Vec<u8> {
+        self.sector(DIR_TRACK, 0).unwrap()[0xA5..0xA7].to_vec()
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let Ok(chain) = self.chain((DIR_TRACK, 1)) else {
+            return entries;
+        };
+        for (track, sector) in chain {
+            let data = self.sector(track, sector).unwrap();
+            for offset in (0..SECTOR_SIZE).step_by(DIR_ENTRY_SIZE) {
+                let entry = &data[offset..offset + DIR_ENTRY_SIZE];
+                if entry[2] == 0 {
+                    continue;
+                }
+                let name = &entry[5..5 + NAME_LEN];
+                let end = name.iter().rposition(|&b| b != PADDING).map_or(0, |i| i + 1);
+                entries.push(Entry {
+                    name: name[..end].to_vec(),
+                    file_type: entry[2],
+                    start: (entry[3], entry[4]),
+                    blocks: u16::from_le_bytes([entry[30], entry[31]]),
+                    slot: (track, sector, offset),
+                });
+            }
+        }
+        entries
+    }
+
+    /// The first closed file whose name matches `pattern` (with * and ?)
+    pub fn find(&self, pattern: &[u8]) -> Option<Entry> {
+        self.entries().into_iter().find(|entry| entry.is_closed() && name_matches(pattern, &entry.name))
+    }
+
+    /// Read a whole file, which must be of `file_type` if one is given
+    pub fn read_file(&self, pattern: &[u8], file_type: Option<FileType>) -> Result<Vec<u8>, DosError> {
+        let entry = self.find(pattern).ok_or(DosError::FileNotFound)?;
+        if let Some(file_type) = file_type
+            && entry.file_type & 0x07 != file_type.code()
+        {
+            return Err(DosError::FileTypeMismatch);
+        }
+        let mut data = Vec::new();
+        for (track, sector) in self.chain(entry.start)? {
+            let block = self.sector(track, sector)?;
+            // The last block has no next track, and the index of its last
+            // byte where the next sector would be
+            let end = if block[0] == 0 { (block[1] as usize + 1).max(2) } else { SECTOR_SIZE };
+            data.extend_from_slice(&block[2..end]);
+        }
+        Ok(data)
+    }
+
+    /// Load a PRG file: its load address and the data after it
+    #[cfg(test)]
+    pub fn read_prg(&self, pattern: &[u8]) -> Result<(u16, Vec<u8>), DosError> {
+        // LOAD takes a file of any type, as the 1541 does
+        let data = self.read_file(pattern, None)?;
+        if data.len() < 2 {
+            return Err(DosError::FileNotFound);
+        }
+        Ok((u16::from_le_bytes([data[0], data[1]]), data[2..].to_vec()))
+    }
+
+    /// Save a PRG file, replacing one of the same name if `replace` (@0:)
+    #[cfg(test)]
+    pub fn write_prg(&mut self, name: &[u8], replace: bool, start_addr: u16, data: &[u8]) -> Result<(), DosError> {
+        let mut file = start_addr.to_le_bytes().to_vec();
+        file.extend_from_slice(data);
+        self.write_file(name, FileType::Prg, replace, &file)
+    }
+
+    pub fn write_file(&mut self, name: &[u8], file_type: FileType, replace: bool, data: &[u8]) -> Result<(), DosError> {
+        let name = &name[..name.len().min(NAME_LEN)];
+        let existing = self.entries().into_iter().find(|entry| entry.name == name);
+        if existing.is_some() && !replace {
+            return Err(DosError::FileExists);
+        }
+
+        // Like the 1541, the new file is written before the old one is
+        // scratched, so a replacement needs room for both
+        let blocks = data.len().div_ceil(BLOCK_DATA).max(1);
+        if blocks > self.blocks_free() as usize {
+            return Err(DosError::DiskFull);
+        }
+        let slot = match &existing {
+            Some(entry) => entry.slot,
+            None => self.free_slot()?,
+        };
+
+        let mut chain = Vec::with_capacity(blocks);
+        for _ in 0..blocks {
+            let (track, sector) = self.next_block(chain.last().copied()).ok_or(DosError::DiskFull)?;
+            self.allocate_block(track, sector);
+            chain.push((track, sector));
+        }
+        for (i, chunk) in data.chunks(BLOCK_DATA).chain(data.is_empty().then_some(&[][..])).enumerate() {
+            let link = match chain.get(i + 1) {
+                Some(&next) => next,
+                None => (0, chunk.len() as u8 + 1),
+            };
+            let block = self.sector_mut(chain[i].0, chain[i].1)?;
+            block.fill(0);
+            block[0] = link.0;
+            block[1] = link.1;
+            block[2..2 + chunk.len()].copy_from_slice(chunk);
+        }
+
+        if let Some(entry) = &existing {
+            self.free_chain(entry.start);
+        }
+        let (track, sector, offset) = slot;
+        let dir = self.sector_mut(track, sector)?;
+        let entry = &mut dir[offset + 2..offset + DIR_ENTRY_SIZE];
+        entry.fill(0);
+        entry[0] = TYPE_CLOSED | file_type.code();
+        entry[1] = chain[0].0;
+        entry[2] = chain[0].1;
+        entry[3..3 + NAME_LEN].fill(PADDING);
+        entry[3..3 + name.len()].copy_from_slice(name);
+        entry[28..30].copy_from_slice(&(blocks as u16).to_le_bytes());
+        tracing::debug!(target: "storage", "wrote {} blocks from {}/{}, {} blocks free", blocks, chain[0].0, chain[0].1, self.blocks_free());
+        Ok(())
+    }
+
+    /// Delete the files matching `pattern`, returning how many there were
+    pub fn scratch(&mut self, pattern: &[u8]) -> Result<usize, DosError> {
+        let entries: Vec<Entry> = self.entries().into_iter().filter(|entry| name_matches(pattern, &entry.name)).collect();
+        for entry in &entries {
+            if entry.is_closed() {
+                self.free_chain(entry.start);
+            }
+            let (track, sector, offset) = entry.slot;
+            self.sector_mut(track, sector)?[offset + 2] = 0;
+        }
+        Ok(entries.len())
+    }
+
+    /// A whole sector, as U1 reads it
+    pub fn read_block(&self, track: u8, sector: u8) -> Result<Vec<u8>, DosError> {
+        Ok(self.sector(track, sector)?.to_vec())
+    }
+
+    /// Overwrite a sector, as U2 does, leaving the BAM as it is
+    pub fn write_block(&mut self, track: u8, sector: u8, data: &[u8; SECTOR_SIZE]) -> Result<(), DosError> {
+        self.sector_mut(track, sector)?.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Rename a file, as R0:NEW=OLD does
+    pub fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), DosError> {
+        let new = &new[..new.len().min(NAME_LEN)];
+        if self.entries().iter().any(|entry| entry.name == new) {
+            return Err(DosError::FileExists);
+        }
+        let (track, sector, offset) = self.find(old).ok_or(DosError::FileNotFound)?.slot;
+        let name = &mut self.sector_mut(track, sector)?[offset + 5..offset + 5 + NAME_LEN];
+        name.fill(PADDING);
+        name[..new.len()].copy_from_slice(new);
+        Ok(())
+    }
+
+    /// Format the disk, as N0:NAME,ID does, keeping the image's size.
+    /// Without an ID only the BAM and directory are written afresh, as in
+    /// the 1541's quick format, and the disk keeps its ID.
+    pub fn new_disk(&mut self, name: &[u8], id: Option<&[u8]>) {
+        let blank = match id {
+            Some(id) => Self::format(name, id),
+            None => Self::format(name, &self.header().1),
+        };
+        if id.is_some() {
+            let size = self.data.len();
+            self.data = blank.data;
+            self.data.resize(size, 0);
+        } else {
+            for sector in 0..2 {
+                let offset = self.offset(DIR_TRACK, sector).unwrap();
+                self.data[offset..offset + SECTOR_SIZE].copy_from_slice(blank.sector(DIR_TRACK, sector).unwrap());
+            }
+        }
+    }
+
+    /// Rebuild the BAM from the directory, as V0 does: every sector not in
+    /// the directory or a closed file is freed, and files left open (shown
+    /// with a * in the listing) are deleted
+    pub fn validate(&mut self) -> Result<(), DosError> {
+        let directory = self.chain((DIR_TRACK, 1))?;
+        let entries = self.entries();
+        let mut used = vec![(DIR_TRACK, 0)];
+        used.extend(&directory);
+        for entry in &entries {
+            if entry.is_closed() {
+                used.extend(self.chain(entry.start)?);
+            } else {
+                let (track, sector, offset) = entry.slot;
+                self.sector_mut(track, sector)?[offset + 2] = 0;
+            }
+        }
+        for track in 1..=TRACKS {
+            for sector in 0..sectors_per_track(track) {
+                self.free_block(track, sector);
+            }
+        }
+        for (track, sector) in used {
+            self.allocate_block(track, sector);
+        }
+        Ok(())
+    }
+
+    fn free_chain(&mut self, start: (u8, u8)) {
+        // A damaged chain frees what it can
+        let chain = self.chain(start).unwrap_or_default();
+        for (track, sector) in chain {
+            self.free_block(track, sector);
+        }
+    }
+
+    // An unused directory entry, adding a sector to the directory when the
+    // ones it has are full
+    fn free_slot(&mut self) -> Result<(u8, u8, usize), DosError> {
+        let chain = self.chain((DIR_TRACK, 1))?;
+        for &(track, sector) in &chain {
+            let data = self.sector(track, sector)?;
+            if let Some(offset) = (0..SECTOR_SIZE).step_by(DIR_ENTRY_SIZE).find(|&offset| data[offset + 2] == 0) {
+                return Ok((track, sector, offset));
+            }
+        }
+        let &(last_track, last_sector) = chain.last().unwrap();
+        let count = sectors_per_track(DIR_TRACK);
+        let sector = (0..count)
+            .map(|i| (last_sector + DIR_INTERLEAVE + i) % count)
+            .find(|&sector| self.is_free(DIR_TRACK, sector))
+            .ok_or(DosError::DiskFull)?;
+        self.allocate_block(DIR_TRACK, sector);
+        let data = self.sector_mut(DIR_TRACK, sector)?;
+        data.fill(0);
+        data[1] = 0xFF;
+        let last = self.sector_mut(last_track, last_sector)?;
+        last[0] = DIR_TRACK;
+        last[1] = sector;
+        Ok((DIR_TRACK, sector, 0))
+    }
+
+    // The next free sector for a file, picked the way the 1541 does: the
+    // first block on the tracks nearest the directory, each one after that
+    // on the track of the one before, INTERLEAVE sectors on, then on the
+    // tracks further out, then on the other side of the directory
+    fn next_block(&self, previous: Option<(u8, u8)>) -> Option<(u8, u8)> {
+        let (tracks, start): (Vec<u8>, u8) = match previous {
+            None => ((1..DIR_TRACK).flat_map(|distance| [DIR_TRACK - distance, DIR_TRACK + distance]).collect(), 0),
+            Some((track, sector)) => {
+                let (same_side, other_side): (Vec<u8>, Vec<u8>) = if track < DIR_TRACK {
+                    ((1..=track).rev().collect(), (DIR_TRACK + 1..=TRACKS).collect())
+                } else {
+                    ((track..=TRACKS).collect(), (1..DIR_TRACK).rev().collect())
+                };
+                let tracks = same_side.into_iter().chain(other_side).collect();
+                (tracks, sector + INTERLEAVE)
+            }
+        };
+        tracks.into_iter().filter(|&track| (1..=TRACKS).contains(&track)).enumerate().find_map(|(i, track)| {
+            let count = sectors_per_track(track);
+            let first = if i == 0 { start % count } else { 0 };
+            (0..count)
+                .map(|n| (first + n) % count)
+                .find(|&sector| self.is_free(track, sector))
+                .map(|sector| (track, sector))
+        })
+    }
+
+    /// Blocks free for files, which is every free sector off the directory track
+    pub fn blocks_free(&self) -> u16 {
+        let bam = self.offset(DIR_TRACK, 0).unwrap();
+        (1..=TRACKS)
+            .filter(|&track| track != DIR_TRACK)
+            .map(|track| self.data[bam + 4 * track as usize] as u16)
+            .sum()
+    }
+
+    // The BAM entry of a track: its free count, then a bit per sector, set
+    // when the sector is free
+    fn bam_entry(track: u8, sector: u8) -> (usize, usize, u8) {
+        let entry = 4 * track as usize;
+        (entry, entry + 1 + sector as usize / 8, 1 << (sector % 8))
+    }
+
+    fn is_free(&self, track: u8, sector: u8) -> bool {
+        let bam = self.offset(DIR_TRACK, 0).unwrap();
+        let (_, byte, bit) = Self::bam_entry(track, sector);
+        self.data[bam + byte] & bit != 0
+    }
+
+    fn allocate_block(&mut self, track: u8, sector: u8) {
+        if track <= TRACKS && self.is_free(track, sector) {
+            let bam = self.offset(DIR_TRACK, 0).unwrap();
+            let (count, byte, bit) = Self::bam_entry(track, sector);
+            self.data[bam + byte] &= !bit;
+            self.data[bam + count] -= 1;
+        }
+    }
+
+    fn free_block(&mut self, track: u8, sector: u8) {
+        if track <= TRACKS && !self.is_free(track, sector) {
+            let bam = self.offset(DIR_TRACK, 0).unwrap();
+            let (count, byte, bit) = Self::bam_entry(track, sector);
+            self.data[bam + byte] |= bit;
+            self.data[bam + count] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let disk = D64::format(b"GAMES", b"G1");
+        assert_eq!(disk.header(), (b"GAMES".to_vec(), b"G1".to_vec()));
+        assert_eq!(disk.blocks_free(), 664);
+        assert!(disk.entries().is_empty());
+        assert!(D64::from_bytes(disk.data.clone()).is_ok());
+        assert!(D64::from_bytes(vec![0; 1000]).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut disk = D64::format(b"TEST", b"01");
+        let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        disk.write_prg(b"PROGRAM", false, 0x0801, &data).unwrap();
+        disk.write_prg(b"EMPTY", false, 0xC000, &[]).unwrap();
+
+        // 602 bytes take three blocks, the first next to the directory and
+        // the rest INTERLEAVE sectors on
+        let entry = disk.find(b"PROGRAM").unwrap();
+        assert_eq!((entry.type_name(), entry.start, entry.blocks), ("PRG", (17, 0), 3));
+        assert_eq!(disk.chain(entry.start).unwrap(), vec![(17, 0), (17, 10), (17, 20)]);
+        assert_eq!(disk.blocks_free(), 664 - 4);
+
+        assert_eq!(disk.read_prg(b"PROGRAM"), Ok((0x0801, data)));
+        assert_eq!(disk.read_prg(b"PRO*"), disk.read_prg(b"PROGRAM"));
+        assert_eq!(disk.read_prg(b"EMPT?"), Ok((0xC000, Vec::new())));
+        assert_eq!(disk.read_prg(b"MISSING"), Err(DosError::FileNotFound));
+    }
+
+    #[test]
+    fn test_seq_files() {
+        let mut disk = D64::format(b"TEST", b"01");
+        disk.write_file(b"SCORES", FileType::Seq, false, b"100\r90\r").unwrap();
+        disk.write_file(b"EMPTY", FileType::Seq, false, &[]).unwrap();
+        assert_eq!(disk.find(b"SCORES").unwrap().type_name(), "SEQ");
+        assert_eq!(disk.read_file(b"SCORES", Some(FileType::Seq)), Ok(b"100\r90\r".to_vec()));
+        assert_eq!(disk.read_file(b"EMPTY", Some(FileType::Seq)), Ok(Vec::new()));
+        assert_eq!(disk.read_file(b"SCORES", Some(FileType::Prg)), Err(DosError::FileTypeMismatch));
+        assert_eq!(disk.read_file(b"SCORES", None), Ok(b"100\r90\r".to_vec()));
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut disk = D64::format(b"TEST", b"01");
+        disk.write_prg(b"FILE", false, 0x0801, &[1; 300]).unwrap();
+        assert_eq!(disk.write_prg(b"FILE", false, 0x0801, &[2; 10]), Err(DosError::FileExists));
+
+        // @0: keeps the directory entry and frees the old blocks
+        disk.write_prg(b"FILE", true, 0x0801, &[2; 10]).unwrap();
+        assert_eq!(disk.entries().len(), 1);
+        assert_eq!(disk.read_prg(b"FILE"), Ok((0x0801, vec![2; 10])));
+        assert_eq!(disk.blocks_free(), 664 - 1);
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let mut disk = D64::format(b"TEST", b"01");
+        disk.write_prg(b"BIG", false, 0, &vec![0; 660 * BLOCK_DATA - 2]).unwrap();
+        assert_eq!(disk.blocks_free(), 4);
+
+        // A file that doesn't fit leaves the disk as it was
+        let before = disk.data.clone();
+        assert_eq!(disk.write_prg(b"TOO BIG", false, 0, &[0; 5 * BLOCK_DATA]), Err(DosError::DiskFull));
+        assert!(disk.data == before);
+        disk.write_prg(b"FITS", false, 0, &[0; 4 * BLOCK_DATA - 2]).unwrap();
+        assert_eq!(disk.blocks_free(), 0);
+    }
+
+    #[test]
+    fn test_directory_grows() {
+        // Eight entries fill the first directory sector
+        let mut disk = D64::format(b"TEST", b"01");
+        for i in 0..9u8 {
+            disk.write_prg(&[b'F', b'0' + i], false, 0, &[i]).unwrap();
+        }
+        assert_eq!(disk.chain((DIR_TRACK, 1)).unwrap(), vec![(18, 1), (18, 4)]);
+        assert_eq!(disk.entries().len(), 9);
+        assert_eq!(disk.read_prg(b"F8"), Ok((0, vec![8])));
+    }
+
+    #[test]
+    fn test_scratch() {
+        let mut disk = D64::format(b"TEST", b"01");
+        disk.write_prg(b"GAME1", false, 0, &[0; 1000]).unwrap();
+        disk.write_prg(b"GAME2", false, 0, &[0; 1000]).unwrap();
+        disk.write_prg(b"NOTES", false, 0, &[0; 10]).unwrap();
+        assert_eq!(disk.scratch(b"GAME*"), Ok(2));
+        assert_eq!(disk.scratch(b"GAME*"), Ok(0));
+        assert_eq!(disk.entries().iter().map(|entry| entry.name.clone()).collect::<Vec<_>>(), vec![b"NOTES".to_vec()]);
+        assert_eq!(disk.blocks_free(), 664 - 1);
+
+        // The freed entry is used again
+        disk.write_prg(b"NEW", false, 0, &[0; 10]).unwrap();
+        assert_eq!(disk.entries()[0].name, b"NEW".to_vec());
+    }
+
+    #[test]
+    fn test_rename_and_new_disk() {
+        let mut disk = D64::format(b"TEST", b"01");
+        disk.write_prg(b"OLD", false, 0x0801, &[1]).unwrap();
+        disk.write_prg(b"OTHER", false, 0x0801, &[2]).unwrap();
+        assert_eq!(disk.rename(b"OLD", b"OTHER"), Err(DosError::FileExists));
+        assert_eq!(disk.rename(b"MISSING", b"NEW"), Err(DosError::FileNotFound));
+        disk.rename(b"OLD", b"NEW").unwrap();
+        assert_eq!(disk.read_prg(b"NEW"), Ok((0x0801, vec![1])));
+        assert_eq!(disk.read_prg(b"OLD"), Err(DosError::FileNotFound));
+
+        // A quick format keeps the ID, a full one takes the new one
+        disk.new_disk(b"EMPTY", None);
+        assert_eq!(disk.header(), (b"EMPTY".to_vec(), b"01".to_vec()));
+        assert!(disk.entries().is_empty());
+        assert_eq!(disk.blocks_free(), 664);
+        disk.write_prg(b"FILE", false, 0, &[0; 1000]).unwrap();
+        disk.new_disk(b"FRESH", Some(b"02"));
+        assert_eq!(disk.header(), (b"FRESH".to_vec(), b"02".to_vec()));
+        assert!(disk.data[disk.offset(17, 0).unwrap()..][..SECTOR_SIZE].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut disk = D64::format(b"TEST", b"01");
+        disk.write_prg(b"KEEP", false, 0, &[0; 1000]).unwrap();
+        disk.write_prg(b"OPEN", false, 0, &[0; 1000]).unwrap();
+        // A file left open, and a block allocated to nothing
+        let (track, sector, offset) = disk.find(b"OPEN").unwrap().slot;
+        disk.sector_mut(track, sector).unwrap()[offset + 2] &= !TYPE_CLOSED;
+        disk.allocate_block(1, 0);
+        assert_eq!(disk.blocks_free(), 664 - 9);
+
+        disk.validate().unwrap();
+        assert_eq!(disk.entries().len(), 1);
+        assert_eq!(disk.blocks_free(), 664 - 4);
+        assert!(disk.read_prg(b"KEEP").is_ok());
+    }
+}
@@ -0,0 +1,650 @@
+// A disk drive's files: its channels opened on a host folder or on a
+// mounted D64 or G64 image. Reached over the serial bus and by the LOAD/SAVE
+// traps alike, so both see the same disk. Channel 15 takes DOS commands and
+// reads back the drive's status.
+
+use std::path::Path;
+
+use super::d64::{D64, SECTOR_SIZE};
+use super::g64::G64;
+use super::host::HostDir;
+use super::{DosError, FileType, Status, parse_filename};
+use crate::iec::device::Channels;
+
+/// What is in the drive
+pub enum Medium {
+    /// A folder on the host
+    Host(HostDir),
+    Image(D64),
+    /// A G64 image, with the sectors decoded from its tracks, which files
+    /// are read from and written to
+    Gcr { image: G64, disk: D64 },
+}
+
+impl Medium {
+    /// The image file the disk came from and a checksum of what is on it,
+    /// unless it is a folder or an image made in memory
+    pub fn image_file(&self) -> Option<(&Path, u64)> {
+        match self {
+            Medium::Host(_) => None,
+            Medium::Image(disk) => Some((disk.path()?, disk.checksum())),
+            Medium::Gcr { image, .. } => Some((image.path()?, image.checksum())),
+        }
+    }
+}
+
+enum Channel {
+    Read { data: Vec<u8>, pos: usize },
+    Write { name: Vec<u8>, file_type: FileType, replace: bool, data: Vec<u8> },
+    // A buffer in drive memory opened with "#", for U1, U2 and B-P
+    Buffer { data: Box<[u8; SECTOR_SIZE]>, pos: usize },
+}
+
+// How a file is opened: the letter after its name and type, as in
+// "NAME,S,W"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Read,
+    Write,
+    Append,
+}
+
+const COMMAND_CHANNEL: usize = 15;
+
+pub struct Drive {
+    medium: Medium,
+    channels: [Option<Channel>; 16],
+    // A command being sent on the command channel
+    command: Vec<u8>,
+    status: Status,
+    write_protected: bool,
+}
+
+impl Drive {
+    pub fn new(medium: Medium) -> Self {
+        Self { medium, channels: Default::default(), command: Vec::new(), status: Status::DosVersion, write_protected: false }
+    }
+
+    fn set_status(&mut self, status: Status) {
+        if status.is_error() {
+            tracing::debug!(target: "storage", "{}", status);
+        }
+        self.status = status;
+        // A status half read starts again
+        self.channels[COMMAND_CHANNEL] = None;
+    }
+
+    // The status after opening or closing a file
+    fn report(&mut self, result: Result<(), DosError>) -> Result<(), DosError> {
+        self.set_status(result.err().map_or(Status::Ok, Status::Error));
+        result
+    }
+
+    // Run a command, leaving its outcome for the command channel to read
+    fn run(&mut self, command: &[u8]) {
+        if command.is_empty() {
+            return;
+        }
+        tracing::debug!(target: "storage", "command {:?}", String::from_utf8_lossy(command));
+        let status = self.execute(command).unwrap_or_else(Status::Error);
+        self.set_status(status);
+    }
+
+    // A DOS command. Only its first letter counts, so "S0:NAME" and
+    // "SCRATCH0:NAME" are the same, and the drive number before the colon
+    // is ignored.
+    fn execute(&mut self, command: &[u8]) -> Result<Status, DosError> {
+        let command = command.strip_suffix(b"\r").unwrap_or(command);
+        let args = command.iter().position(|&b| b == b':').map(|colon| &command[colon + 1..]);
+        match (command.first(), args) {
+            (Some(b'U'), _) => match command.get(1) {
+                Some(b'1' | b'A') => self.block_read(numbers(&command[2..])?),
+                Some(b'2' | b'B') => self.block_write(numbers(&command[2..])?),
+                _ => Err(DosError::InvalidCommand),
+            },
+            (Some(b'B'), _) if command.starts_with(b"B-P") => {
+                let &[channel, pos] = numbers(&command[3..])?.as_slice() else {
+                    return Err(DosError::SyntaxError);
+                };
+                match &mut self.channels[channel as usize & 0x0F] {
+                    Some(Channel::Buffer { pos: pointer, .. }) => *pointer = pos as usize,
+                    _ => return Err(DosError::NoChannel),
+                }
+                Ok(Status::Ok)
+            }
+            // The disk is read afresh on every access, so initializing has
+            // nothing left to do
+            (Some(b'I'), _) => Ok(Status::Ok),
+            (Some(b'V'), _) => {
+                self.check_writable()?;
+                if let Medium::Image(disk) | Medium::Gcr { disk, .. } = &mut self.medium {
+                    disk.validate()?;
+                    self.flush()?;
+                }
+                Ok(Status::Ok)
+            }
+            (Some(b'S' | b'R' | b'N'), None) => Err(DosError::NoFileGiven),
+            (Some(b'S'), Some(patterns)) => {
+                self.check_writable()?;
+                let mut count = 0;
+                for pattern in patterns.split(|&b| b == b',') {
+                    let (pattern, _) = parse_filename(pattern);
+                    count += match &mut self.medium {
+                        Medium::Host(dir) => dir.scratch(pattern).map_err(|error| self.host_error(error))?,
+                        Medium::Image(disk) | Medium::Gcr { disk, .. } => disk.scratch(pattern)?,
+                    };
+                }
+                self.flush()?;
+                Ok(Status::FilesScratched(count.min(99) as u8))
+            }
+            (Some(b'R'), Some(names)) => {
+                self.check_writable()?;
+                let equals = names.iter().position(|&b| b == b'=').ok_or(DosError::SyntaxError)?;
+                let (new, _) = parse_filename(&names[..equals]);
+                let (old, _) = parse_filename(&names[equals + 1..]);
+                match &mut self.medium {
+                    Medium::Host(dir) => dir.rename(old, new)?,
+                    Medium::Image(disk) | Medium::Gcr { disk, .. } => disk.rename(old, new)?,
+                }
+                self.flush()?;
+                Ok(Status::Ok)
+            }
+            (Some(b'N'), Some(header)) => {
+                self.check_writable()?;
+                let mut parts = header.splitn(2, |&b| b == b',');
+                let name = parts.next().unwrap_or_default();
+                let id = parts.next();
+                match &mut self.medium {
+                    // A folder has no name or ID to take, only its files to lose
+                    Medium::Host(dir) => {
+                        dir.scratch(b"*").map_err(|error| self.host_error(error))?;
+                    }
+                    Medium::Image(disk) | Medium::Gcr { disk, .. } => disk.new_disk(name, id),
+                }
+                self.channels = Default::default();
+                self.flush()?;
+                Ok(Status::Ok)
+            }
+            _ => Err(DosError::InvalidCommand),
+        }
+    }
+
+    // Anything that would change the disk fails while it is write protected
+    fn check_writable(&self) -> Result<(), DosError> {
+        if self.write_protected {
+            return Err(DosError::WriteProtectOn);
+        }
+        Ok(())
+    }
+
+    // U1: read a sector into a channel's buffer, from its start
+    fn block_read(&mut self, args: Vec<u8>) -> Result<Status, DosError> {
+        let &[channel, _, track, sector] = args.as_slice() else {
+            return Err(DosError::SyntaxError);
+        };
+        let block = match &self.medium {
+            Medium::Host(_) => return Err(DosError::DriveNotReady),
+            Medium::Image(disk) | Medium::Gcr { disk, .. } => match disk.read_block(track, sector) {
+                Ok(block) => block,
+                Err(error) => return Ok(Status::ErrorAt(error, track, sector)),
+            },
+        };
+        let Some(Channel::Buffer { data, pos }) = &mut self.channels[channel as usize & 0x0F] else {
+            return Err(DosError::NoChannel);
+        };
+        data.copy_from_slice(&block);
+        *pos = 0;
+        Ok(Status::Ok)
+    }
+
+    // U2: write a channel's buffer to a sector
+    fn block_write(&mut self, args: Vec<u8>) -> Result<Status, DosError> {
+        let &[channel, _, track, sector] = args.as_slice() else {
+            return Err(DosError::SyntaxError);
+        };
+        self.check_writable()?;
+        let Some(Channel::Buffer { data, .. }) = &self.channels[channel as usize & 0x0F] else {
+            return Err(DosError::NoChannel);
+        };
+        let (Medium::Image(disk) | Medium::Gcr { disk, .. }) = &mut self.medium else {
+            return Err(DosError::DriveNotReady);
+        };
+        if let Err(error) = disk.write_block(track, sector, data) {
+            return Ok(Status::ErrorAt(error, track, sector));
+        }
+        self.flush()?;
+        Ok(Status::Ok)
+    }
+
+    // Write a changed image back to its file
+    fn flush(&mut self) -> Result<(), DosError> {
+        let result = match &mut self.medium {
+            Medium::Host(_) => Ok(()),
+            Medium::Image(disk) => disk.flush(),
+            Medium::Gcr { image, disk } => image.store(disk).and_then(|()| image.flush()),
+        };
+        result.map_err(|error| self.host_error(error))
+    }
+
+    fn host_error(&self, error: anyhow::Error) -> DosError {
+        tracing::warn!(target: "storage", "{}", error);
+        DosError::WriteError
+    }
+
+    // Channel 0 is LOAD's and 1 is SAVE's, for programs. The others read
+    // unless the name is followed by ",W" (or ",A" to append), and write
+    // sequential files unless given a type, as in "NAME,P,W".
+    fn open_file(&mut self, channel: usize, name: &[u8]) -> Result<(), DosError> {
+        self.channels[channel] = None;
+        if name.starts_with(b"#") {
+            // The drive has a buffer to spare whichever one is asked for
+            self.channels[channel] = Some(Channel::Buffer { data: Box::new([0; SECTOR_SIZE]), pos: 0 });
+            return Ok(());
+        }
+        let (name, replace) = parse_filename(name);
+
+        // Only the first letter of each part counts, so ",SEQ,WRITE" will do
+        let mut parts = name.split(|&b| b == b',');
+        let name = parts.next().unwrap_or_default();
+        let mut file_type = None;
+        let mut mode = if channel == 1 { Mode::Write } else { Mode::Read };
+        for part in parts {
+            match part.first() {
+                Some(b'R') => mode = Mode::Read,
+                Some(b'W') => mode = Mode::Write,
+                Some(b'A') => mode = Mode::Append,
+                Some(&letter) => file_type = FileType::from_letter(letter).or(file_type),
+                None => {}
+            }
+        }
+        let write_type = file_type.unwrap_or(if channel <= 1 { FileType::Prg } else { FileType::Seq });
+        if mode != Mode::Read {
+            self.check_writable()?;
+        }
+
+        self.channels[channel] = Some(match mode {
+            Mode::Read => Channel::Read { data: self.read_file(name, file_type)?, pos: 0 },
+            Mode::Write => Channel::Write { name: name.to_vec(), file_type: write_type, replace, data: Vec::new() },
+            Mode::Append => {
+                let data = self.read_file(name, Some(write_type))?;
+                Channel::Write { name: name.to_vec(), file_type: write_type, replace: true, data }
+            }
+        });
+        Ok(())
+    }
+
+    // A whole file, of the type given if any, or the directory for "$"
+    fn read_file(&self, name: &[u8], file_type: Option<FileType>) -> Result<Vec<u8>, DosError> {
+        match (&self.medium, name) {
+            (Medium::Host(dir), b"$") => Ok(program_file(dir.listing().map_err(|_| DosError::FileNotFound)?)),
+            (Medium::Image(disk) | Medium::Gcr { disk, .. }, b"$") => Ok(program_file(super::list_image(disk))),
+            (Medium::Host(dir), name) => dir.read_file(name, file_type),
+            (Medium::Image(disk) | Medium::Gcr { disk, .. }, name) => disk.read_file(name, file_type),
+        }
+    }
+
+    fn write_file(&mut self, name: &[u8], file_type: FileType, replace: bool, data: &[u8]) -> Result<(), DosError> {
+        match &mut self.medium {
+            Medium::Host(dir) => {
+                // The host directory always replaces, and fails only on host errors
+                dir.write_file(name, file_type, data).map_err(|error| {
+                    tracing::warn!(target: "storage", "SAVE {:?}: {}", String::from_utf8_lossy(name), error);
+                    DosError::WriteError
+                })
+            }
+            Medium::Image(disk) | Medium::Gcr { disk, .. } => {
+                disk.write_file(name, file_type, replace, data)?;
+                self.flush()
+            }
+        }
+    }
+}
+
+// The numbers after a block command, separated by spaces, commas or a
+// colon, as in "U1:2 0 18 0" or "B-P 2,1"
+fn numbers(args: &[u8]) -> Result<Vec<u8>, DosError> {
+    args.split(|&b| matches!(b, b' ' | b',' | b':' | 0x1D))
+        .filter(|number| !number.is_empty())
+        .map(|number| std::str::from_utf8(number).ok().and_then(|number| number.parse().ok()).ok_or(DosError::SyntaxError))
+        .collect()
+}
+
+// A program with its load address in front, as a channel reads it
+fn program_file((start_addr, data): (u16, Vec<u8>)) -> Vec<u8> {
+    let mut file = start_addr.to_le_bytes().to_vec();
+    file.extend_from_slice(&data);
+    file
+}
+
+impl Channels for Drive {
+    fn open(&mut self, channel: u8, name: &[u8]) -> Result<(), DosError> {
+        let channel = channel as usize & 0x0F;
+        if channel == COMMAND_CHANNEL {
+            // The name is a command
+            self.run(name);
+            return Ok(());
+        }
+        let result = self.open_file(channel, name);
+        self.report(result)
+    }
+
+    fn close(&mut self, channel: u8) -> Result<(), DosError> {
+        // Closing a channel that isn't open leaves the status alone, so an
+        // error stays to be read after a failed LOAD
+        let result = match self.channels[channel as usize & 0x0F].take() {
+            Some(Channel::Write { name, file_type, replace, data }) => self.write_file(&name, file_type, replace, &data),
+            Some(Channel::Read { .. } | Channel::Buffer { .. }) => Ok(()),
+            None => return Ok(()),
+        };
+        self.report(result)
+    }
+
+    fn read(&mut self, channel: u8) -> Option<(u8, bool)> {
+        let channel = channel as usize & 0x0F;
+        if channel == COMMAND_CHANNEL && self.channels[channel].is_none() {
+            // The status, ending in a carriage return for INPUT#, then OK
+            // until the next command
+            let data = format!("{}\r", self.status).into_bytes();
+            self.status = Status::Ok;
+            self.channels[channel] = Some(Channel::Read { data, pos: 0 });
+        }
+        let next = match &mut self.channels[channel] {
+            Some(Channel::Read { data, pos }) if *pos < data.len() => {
+                *pos += 1;
+                Some((data[*pos - 1], *pos == data.len()))
+            }
+            Some(Channel::Buffer { data, pos }) if *pos < SECTOR_SIZE => {
+                *pos += 1;
+                Some((data[*pos - 1], *pos == SECTOR_SIZE))
+            }
+            _ => None,
+        };
+        if channel == COMMAND_CHANNEL && matches!(next, Some((_, true))) {
+            self.channels[channel] = None;
+        }
+        next
+    }
+
+    fn write(&mut self, channel: u8, byte: u8) {
+        let channel = channel as usize & 0x0F;
+        if channel == COMMAND_CHANNEL {
+            self.command.push(byte);
+        } else {
+            match &mut self.channels[channel] {
+                Some(Channel::Write { data, .. }) => data.push(byte),
+                Some(Channel::Buffer { data, pos }) => {
+                    data[*pos % SECTOR_SIZE] = byte;
+                    *pos = (*pos + 1) % SECTOR_SIZE;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn unlisten(&mut self, channel: u8) {
+        if channel as usize & 0x0F == COMMAND_CHANNEL {
+            let command = std::mem::take(&mut self.command);
+            self.run(&command);
+        }
+    }
+
+    fn status(&self) -> Option<Status> {
+        Some(self.status)
+    }
+
+    fn write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    fn set_write_protected(&mut self, on: bool) {
+        self.write_protected = on;
+    }
+
+    fn medium(&self) -> Option<&Medium> {
+        Some(&self.medium)
+    }
+
+    // Swapping disks drops the open files, as taking one out of a 1541 does
+    fn insert(&mut self, medium: Medium) {
+        self.medium = medium;
+        self.channels = Default::default();
+        self.command.clear();
+    }
+}
+
+/// Load a whole PRG through a drive's LOAD channel, as the KERNAL would
+/// over the bus
+/// Returns (start_address, data)
+pub fn load_file(drive: &mut dyn Channels, filename: &[u8]) -> Result<(u16, Vec<u8>), DosError> {
+    drive.open(0, filename)?;
+    let mut file = Vec::new();
+    while let Some((byte, _)) = drive.read(0) {
+        file.push(byte);
+    }
+    drive.close(0)?;
+    if file.len() < 2 {
+        return Err(DosError::FileNotFound);
+    }
+    let data = file.split_off(2);
+    Ok((u16::from_le_bytes([file[0], file[1]]), data))
+}
+
+/// Save a PRG through a drive's SAVE channel
+pub fn save_file(drive: &mut dyn Channels, filename: &[u8], start_addr: u16, data: &[u8]) -> Result<(), DosError> {
+    drive.open(1, filename)?;
+    for byte in start_addr.to_le_bytes().iter().chain(data) {
+        drive.write(1, *byte);
+    }
+    drive.close(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drive() -> Drive {
+        let mut disk = D64::format(b"TEST", b"01");
+        disk.write_prg(b"HELLO", false, 0x0801, &[1, 2, 3]).unwrap();
+        Drive::new(Medium::Image(disk))
+    }
+
+    fn read_all(drive: &mut Drive, channel: u8) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Some((byte, last)) = drive.read(channel) {
+            data.push(byte);
+            if last {
+                assert_eq!(drive.read(channel), None);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_load_and_save_channels() {
+        let mut drive = drive();
+        drive.open(0, b"0:HEL*").unwrap();
+        assert_eq!(read_all(&mut drive, 0), vec![0x01, 0x08, 1, 2, 3]);
+        drive.close(0).unwrap();
+        assert_eq!(drive.open(0, b"NOTHING"), Err(DosError::FileNotFound));
+        assert_eq!(drive.read(0), None);
+
+        for byte in [0x00, 0xC0, 9, 8, 7] {
+            drive.write(1, byte); // Not open: dropped
+        }
+        drive.open(1, b"NEW").unwrap();
+        for byte in [0x00, 0xC0, 9, 8, 7] {
+            drive.write(1, byte);
+        }
+        drive.close(1).unwrap();
+        drive.open(2, b"NEW").unwrap();
+        assert_eq!(read_all(&mut drive, 2), vec![0x00, 0xC0, 9, 8, 7]);
+
+        // Saving over a file takes @0:
+        drive.open(1, b"HELLO").unwrap();
+        drive.write(1, 0);
+        drive.write(1, 0);
+        assert_eq!(drive.close(1), Err(DosError::FileExists));
+        drive.open(3, b"@0:HELLO,P,W").unwrap();
+        drive.write(3, 0);
+        drive.write(3, 0);
+        drive.close(3).unwrap();
+        drive.open(0, b"HELLO").unwrap();
+        assert_eq!(read_all(&mut drive, 0), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_seq_channels() {
+        let mut drive = drive();
+        drive.open(2, b"SCORES,S,W").unwrap();
+        for &byte in b"100\r" {
+            drive.write(2, byte);
+        }
+        drive.close(2).unwrap();
+        drive.open(3, b"SCORES,SEQ,APPEND").unwrap();
+        for &byte in b"90\r" {
+            drive.write(3, byte);
+        }
+        drive.close(3).unwrap();
+
+        drive.open(2, b"SCORES,S,R").unwrap();
+        assert_eq!(read_all(&mut drive, 2), b"100\r90\r".to_vec());
+        drive.close(2).unwrap();
+        assert_eq!(drive.open(2, b"SCORES,P,R"), Err(DosError::FileTypeMismatch));
+        assert_eq!(drive.open(2, b"MISSING,S,A"), Err(DosError::FileNotFound));
+
+        // Without a type, other channels write sequential files
+        drive.open(4, b"LOG,W").unwrap();
+        drive.close(4).unwrap();
+        let Medium::Image(disk) = &drive.medium else { unreachable!() };
+        assert_eq!(disk.find(b"LOG").unwrap().type_name(), "SEQ");
+        assert_eq!(disk.find(b"SCORES").unwrap().type_name(), "SEQ");
+    }
+
+    // The status, as INPUT#15 reads it up to the last byte, after which
+    // the channel starts over
+    fn status(drive: &mut Drive) -> String {
+        let mut status = Vec::new();
+        while let Some((byte, last)) = drive.read(15) {
+            status.push(byte);
+            if last {
+                break;
+            }
+        }
+        String::from_utf8(status).unwrap()
+    }
+
+    // Send a command as PRINT#15 does, and read the status
+    fn command(drive: &mut Drive, command: &[u8]) -> String {
+        for &byte in command.iter().chain(b"\r") {
+            drive.write(15, byte);
+        }
+        drive.unlisten(15);
+        let result = status(drive);
+        assert_eq!(status(drive), "00, OK,00,00\r");
+        result
+    }
+
+    #[test]
+    fn test_command_channel() {
+        let mut drive = drive();
+        drive.open(2, b"DATA,S,W").unwrap();
+        drive.close(2).unwrap();
+        assert_eq!(command(&mut drive, b"R0:GREETING=HELLO"), "00, OK,00,00\r");
+        assert_eq!(command(&mut drive, b"R0:DATA=GREETING"), "63, FILE EXISTS,00,00\r");
+        assert_eq!(command(&mut drive, b"R0:NEW"), "30, SYNTAX ERROR,00,00\r");
+        assert_eq!(command(&mut drive, b"S0:GREETING,0:DATA,MISSING"), "01, FILES SCRATCHED,02,00\r");
+        assert_eq!(command(&mut drive, b"S0"), "34, SYNTAX ERROR,00,00\r");
+        assert_eq!(command(&mut drive, b"X"), "31, SYNTAX ERROR,00,00\r");
+        assert_eq!(command(&mut drive, b"V0"), "00, OK,00,00\r");
+        assert_eq!(command(&mut drive, b"I"), "00, OK,00,00\r");
+
+        // OPEN 15,8,15,"N0:BLANK,02", then the status
+        drive.open(15, b"N0:BLANK,02").unwrap();
+        assert_eq!(status(&mut drive), "00, OK,00,00\r");
+        let Medium::Image(disk) = &drive.medium else { unreachable!() };
+        assert_eq!(disk.header(), (b"BLANK".to_vec(), b"02".to_vec()));
+        assert_eq!(disk.blocks_free(), 664);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut drive = drive();
+        assert_eq!(status(&mut drive), "73, CBM DOS V2.6 1541,00,00\r");
+        assert_eq!(status(&mut drive), "00, OK,00,00\r");
+
+        // A failed LOAD leaves its error until read, even after CLOSE
+        assert_eq!(drive.open(0, b"MISSING"), Err(DosError::FileNotFound));
+        drive.close(0).unwrap();
+        assert_eq!(drive.status(), Some(Status::Error(DosError::FileNotFound)));
+        assert_eq!(status(&mut drive), "62, FILE NOT FOUND,00,00\r");
+        assert_eq!(drive.status(), Some(Status::Ok));
+
+        // As does a SAVE refused at CLOSE, until the next file opens
+        drive.open(1, b"HELLO").unwrap();
+        assert_eq!(drive.close(1), Err(DosError::FileExists));
+        assert_eq!(drive.status(), Some(Status::Error(DosError::FileExists)));
+        drive.open(0, b"HELLO").unwrap();
+        assert_eq!(drive.status(), Some(Status::Ok));
+    }
+
+    #[test]
+    fn test_block_commands() {
+        let mut drive = drive();
+        drive.open(2, b"#").unwrap();
+        assert_eq!(command(&mut drive, b"U1:2 0 18 0"), "00, OK,00,00\r");
+        let bam: Vec<u8> = (0..SECTOR_SIZE).map(|_| drive.read(2).unwrap().0).collect();
+        assert_eq!(&bam[..2], &[18, 1]);
+        assert_eq!(&bam[0x90..0x94], b"TEST");
+        assert_eq!(drive.read(2), None);
+
+        // Change the disk name in the buffer and write the sector back
+        assert_eq!(command(&mut drive, b"B-P:2,144"), "00, OK,00,00\r");
+        for &byte in b"DISK" {
+            drive.write(2, byte);
+        }
+        assert_eq!(command(&mut drive, b"U2 2,0,18,0"), "00, OK,00,00\r");
+        let Medium::Image(disk) = &drive.medium else { unreachable!() };
+        assert_eq!(disk.header().0, b"DISK".to_vec());
+
+        assert_eq!(command(&mut drive, b"U1:2 0 36 0"), "66, ILLEGAL TRACK OR SECTOR,36,00\r");
+        assert_eq!(command(&mut drive, b"U1:3 0 18 0"), "70, NO CHANNEL,00,00\r");
+        assert_eq!(command(&mut drive, b"U1:2 0 18"), "30, SYNTAX ERROR,00,00\r");
+        assert_eq!(command(&mut drive, b"UX"), "31, SYNTAX ERROR,00,00\r");
+    }
+
+    #[test]
+    fn test_write_protect() {
+        let mut drive = drive();
+        drive.set_write_protected(true);
+        assert_eq!(save_file(&mut drive, b"NEW", 0x0801, &[1]), Err(DosError::WriteProtectOn));
+        assert_eq!(status(&mut drive), "26, WRITE PROTECT ON,00,00\r");
+        assert_eq!(drive.open(2, b"LOG,S,A"), Err(DosError::WriteProtectOn));
+        assert_eq!(command(&mut drive, b"S0:HELLO"), "26, WRITE PROTECT ON,00,00\r");
+        assert_eq!(command(&mut drive, b"N0:BLANK,01"), "26, WRITE PROTECT ON,00,00\r");
+
+        // Reading goes on as before
+        assert_eq!(load_file(&mut drive, b"HELLO"), Ok((0x0801, vec![1, 2, 3])));
+        drive.set_write_protected(false);
+        save_file(&mut drive, b"NEW", 0x0801, &[1]).unwrap();
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut drive = drive();
+        drive.open(2, b"HELLO").unwrap();
+        drive.insert(Medium::Image(D64::format(b"OTHER", b"02")));
+        // The file open on the old disk is gone with it
+        assert_eq!(drive.read(2), None);
+        assert_eq!(load_file(&mut drive, b"HELLO"), Err(DosError::FileNotFound));
+        assert!(matches!(drive.medium(), Some(Medium::Image(disk)) if disk.header().0 == b"OTHER"));
+    }
+
+    #[test]
+    fn test_directory_channel() {
+        let mut drive = drive();
+        drive.open(0, b"$").unwrap();
+        let listing = read_all(&mut drive, 0);
+        assert_eq!(&listing[..2], &[0x01, 0x04]);
+        assert!(String::from_utf8_lossy(&listing).contains("HELLO"));
+    }
+}
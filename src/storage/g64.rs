@@ -0,0 +1,457 @@
+// G64 disk images: the GCR bit stream of each track as the drive head sees
+// it, sync marks, gaps and all, so copy protections that play with the
+// encoding survive. The image is kept a halftrack at a time; the DOS sees
+// the sectors decoded from the tracks, and a sector it changes is encoded
+// back over the data block it came from.
+//
+// Layout: "GCR-1541", a version byte, the number of halftracks and the
+// largest track size, then a table of track offsets and one of speed zones,
+// each a 32-bit word per halftrack. A track is its length in two bytes
+// followed by its GCR bytes.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::d64::{self, D64, SECTOR_SIZE};
+
+const SIGNATURE: &[u8; 8] = b"GCR-1541";
+const HEADER_SIZE: usize = 12;
+
+// Halftrack of the first track: the drive numbers them from 2, so track n
+// is halftrack 2n and the odd ones lie between tracks
+const FIRST_HALFTRACK: u8 = 2;
+
+// Halftracks 2 to 85, the most a 1541's head reaches
+const MAX_HALFTRACKS: usize = 84;
+
+// GCR: each 4 bits written as 5, chosen so no more than two 0 bits and
+// eight 1 bits ever follow each other. 10 or more 1 bits are a sync mark.
+const GCR_CODES: [u8; 16] = [0x0A, 0x0B, 0x12, 0x13, 0x0E, 0x0F, 0x16, 0x17, 0x09, 0x19, 0x1A, 0x1B, 0x0D, 0x1D, 0x1E, 0x15];
+const SYNC_BITS: usize = 10;
+
+// A sector is a header block (the mark, a checksum, the sector, track and
+// disk ID, two off bytes) and, after the next sync, a data block (the mark,
+// the 256 bytes, their checksum, two off bytes)
+const HEADER_MARK: u8 = 0x08;
+const HEADER_BLOCK: usize = 8;
+const DATA_MARK: u8 = 0x07;
+const DATA_BLOCK: usize = SECTOR_SIZE + 4;
+
+// How fast a track's bits come off the disk: one of the four zones (3 for
+// the outer tracks down to 0 for the inner ones), or a zone per byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Speed {
+    Zone(u8),
+    // Four zones to a byte, the first in the top bits
+    PerByte(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub data: Vec<u8>,
+    pub speed: Speed,
+}
+
+#[derive(Debug)]
+pub struct G64 {
+    // Where changes are written back, if the image came from a file
+    path: Option<PathBuf>,
+    version: u8,
+    max_track_size: u16,
+    // Indexed from FIRST_HALFTRACK; None where the image has no track
+    halftracks: Vec<Option<Track>>,
+}
+
+// A sector found on a track: its number, the bit its data block starts
+// at, and its bytes unless their checksum is wrong
+struct Sector {
+    number: u8,
+    start: usize,
+    data: Option<Vec<u8>>,
+}
+
+fn word(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).context("truncated table")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// The bit `index` bits into a track, going round as the disk turns
+fn bit(data: &[u8], index: usize) -> u8 {
+    (data[index / 8 % data.len()] >> (7 - index % 8)) & 1
+}
+
+// `count` bytes decoded from the GCR starting `start` bits into a track,
+// or None if a 5 bit group isn't a GCR code
+fn decode(data: &[u8], start: usize, count: usize) -> Option<Vec<u8>> {
+    let nybble = |start: usize| {
+        let code = (0..5).fold(0, |code, i| code << 1 | bit(data, start + i));
+        GCR_CODES.iter().position(|&c| c == code).map(|n| n as u8)
+    };
+    (0..count).map(|n| Some(nybble(start + n * 10)? << 4 | nybble(start + n * 10 + 5)?)).collect()
+}
+
+// The GCR of `bytes`, written over a track from bit `start` on
+fn encode(data: &mut [u8], start: usize, bytes: &[u8]) {
+    let codes = bytes.iter().flat_map(|&byte| [GCR_CODES[byte as usize >> 4], GCR_CODES[byte as usize & 0x0F]]);
+    let bits = codes.flat_map(|code| (0..5).rev().map(move |i| (code >> i) & 1));
+    for (i, value) in bits.enumerate() {
+        let index = (start + i) / 8 % data.len();
+        let mask = 0x80 >> ((start + i) % 8);
+        data[index] = if value != 0 { data[index] | mask } else { data[index] & !mask };
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, &byte| sum ^ byte)
+}
+
+impl G64 {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path).context(format!("Failed to read {:?}", path))?;
+        let mut disk = Self::from_bytes(&data).context(format!("{:?} is not a G64 image", path))?;
+        disk.path = Some(path.to_path_buf());
+        Ok(disk)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE || &data[..8] != SIGNATURE {
+            anyhow::bail!("no GCR-1541 signature");
+        }
+        let version = data[8];
+        let count = data[9] as usize;
+        if !(1..=MAX_HALFTRACKS).contains(&count) {
+            anyhow::bail!("{} halftracks, not 1 to {}", count, MAX_HALFTRACKS);
+        }
+        let max_track_size = u16::from_le_bytes([data[10], data[11]]);
+        let speeds = HEADER_SIZE + 4 * count;
+
+        let mut halftracks = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = word(data, HEADER_SIZE + 4 * i)? as usize;
+            if offset == 0 {
+                halftracks.push(None);
+                continue;
+            }
+            let length = data.get(offset..offset + 2).context(format!("track {} past the end", i))?;
+            let length = u16::from_le_bytes([length[0], length[1]]) as usize;
+            let track = data.get(offset + 2..offset + 2 + length).context(format!("track {} past the end", i))?;
+
+            // Speeds above 3 are offsets of per-byte speed maps
+            let speed = match word(data, speeds + 4 * i)? {
+                zone @ 0..=3 => Speed::Zone(zone as u8),
+                map => {
+                    let map = map as usize;
+                    let bytes = data.get(map..map + length.div_ceil(4)).context(format!("speed map {} past the end", i))?;
+                    Speed::PerByte(bytes.to_vec())
+                }
+            };
+            halftracks.push(Some(Track { data: track.to_vec(), speed }));
+        }
+        Ok(Self { path: None, version, max_track_size, halftracks })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let count = self.halftracks.len();
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.push(self.version);
+        data.push(count as u8);
+        data.extend_from_slice(&self.max_track_size.to_le_bytes());
+        data.resize(HEADER_SIZE + 8 * count, 0);
+
+        // Tracks, each taking max_track_size bytes like the tools that write
+        // them expect, then the speed maps
+        for (i, track) in self.halftracks.iter().enumerate() {
+            let Some(track) = track else { continue };
+            let offset = data.len() as u32;
+            data[HEADER_SIZE + 4 * i..HEADER_SIZE + 4 * i + 4].copy_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&(track.data.len() as u16).to_le_bytes());
+            data.extend_from_slice(&track.data);
+            let size = (self.max_track_size as usize).max(track.data.len());
+            data.resize(offset as usize + 2 + size, 0);
+        }
+        for (i, track) in self.halftracks.iter().enumerate() {
+            let speed = match track.as_ref().map(|track| &track.speed) {
+                None => 0,
+                Some(Speed::Zone(zone)) => *zone as u32,
+                Some(Speed::PerByte(map)) => {
+                    let offset = data.len() as u32;
+                    data.extend_from_slice(map);
+                    offset
+                }
+            };
+            let entry = HEADER_SIZE + 4 * (count + i);
+            data[entry..entry + 4].copy_from_slice(&speed.to_le_bytes());
+        }
+        data
+    }
+
+    // Write the image back to the file it came from
+    pub fn flush(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            fs::write(path, self.to_bytes()).context(format!("Failed to write {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // FNV-1a hash of the whole image, to tell whether it has changed
+    pub fn checksum(&self) -> u64 {
+        self.to_bytes().iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+    }
+
+    // The track under the head at `halftrack` (2 for track 1), if the
+    // image has one there
+    pub fn track(&self, halftrack: u8) -> Option<&Track> {
+        let index = halftrack.checked_sub(FIRST_HALFTRACK)? as usize;
+        self.halftracks.get(index)?.as_ref()
+    }
+
+    // The sectors of a track whose headers pass by in one turn of the
+    // disk, the first of each number only. The data block of the last one
+    // may come round in the next turn.
+    fn sectors(&self, track: u8) -> Vec<Sector> {
+        let Some(data) = self.track(track * 2).map(|track| &track.data).filter(|data| !data.is_empty()) else {
+            return Vec::new();
+        };
+        let bits = data.len() * 8;
+        let mut sectors: Vec<Sector> = Vec::new();
+        let mut header = None;
+        let mut ones = 0;
+        for i in 0..bits * 2 {
+            if i >= bits && header.is_none() {
+                break;
+            }
+            if bit(data, i) == 1 {
+                ones += 1;
+                continue;
+            }
+            let sync = ones >= SYNC_BITS;
+            ones = 0;
+            if !sync {
+                continue;
+            }
+            // A block starts right after the sync, with the first 0 bit
+            match decode(data, i, 1).as_deref() {
+                Some([HEADER_MARK]) if i < bits => {
+                    header = decode(data, i, HEADER_BLOCK)
+                        .filter(|block| block[1] == checksum(&block[2..6]) && block[3] == track)
+                        .map(|block| block[2]);
+                }
+                Some([DATA_MARK]) => {
+                    let Some(number) = header.take() else { continue };
+                    if sectors.iter().any(|sector| sector.number == number) {
+                        continue;
+                    }
+                    let data = decode(data, i, DATA_BLOCK)
+                        .filter(|block| block[SECTOR_SIZE + 1] == checksum(&block[1..=SECTOR_SIZE]))
+                        .map(|block| block[1..=SECTOR_SIZE].to_vec());
+                    sectors.push(Sector { number, start: i % bits, data });
+                }
+                _ => header = None,
+            }
+        }
+        sectors
+    }
+
+    // The disk as the DOS sees it: the sectors of its 35 tracks decoded
+    // from their GCR. Sectors that can't be read, as copy protections make
+    // some, are left zeroed.
+    pub fn decode(&self) -> D64 {
+        let mut data = Vec::new();
+        let mut unreadable = 0;
+        for track in 1..=d64::TRACKS {
+            let sectors = self.sectors(track);
+            for number in 0..d64::sectors_per_track(track) {
+                match sectors.iter().find(|sector| sector.number == number).and_then(|sector| sector.data.as_ref()) {
+                    Some(block) => data.extend_from_slice(block),
+                    None => {
+                        unreadable += 1;
+                        data.extend_from_slice(&[0; SECTOR_SIZE]);
+                    }
+                }
+            }
+        }
+        if unreadable > 0 {
+            tracing::debug!(target: "storage", "{} sectors of the G64 image can't be read", unreadable);
+        }
+        D64::from_bytes(data).expect("35 tracks of sectors make a D64 image")
+    }
+
+    // Encode the sectors of `disk` that have changed since it was decoded
+    // over their old data blocks. A sector whose header isn't on its track
+    // can't be written without formatting the track.
+    pub fn store(&mut self, disk: &D64) -> Result<()> {
+        for track in 1..=d64::TRACKS {
+            let sectors = self.sectors(track);
+            for number in 0..d64::sectors_per_track(track) {
+                let block = disk.read_block(track, number).expect("a sector of the 35 tracks");
+                let found = sectors.iter().find(|sector| sector.number == number);
+                let decoded = found.and_then(|sector| sector.data.clone()).unwrap_or_else(|| vec![0; SECTOR_SIZE]);
+                if block == decoded {
+                    continue;
+                }
+                let start = found.map(|sector| sector.start).context(format!("sector {} of track {} isn't on the G64 image", number, track))?;
+                let mut bytes = vec![DATA_MARK];
+                bytes.extend_from_slice(&block);
+                bytes.extend_from_slice(&[checksum(&block), 0, 0]);
+                let index = (track * 2 - FIRST_HALFTRACK) as usize;
+                let data = &mut self.halftracks[index].as_mut().expect("a track with sectors").data;
+                encode(data, start, &bytes);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A track as the 1541 formats it: each sector a sync, its header, a
+    // gap, a sync and its data, then a gap
+    fn format_track(track: u8, sectors: &[[u8; SECTOR_SIZE]]) -> Track {
+        let mut data = Vec::new();
+        for (number, block) in sectors.iter().enumerate() {
+            let mut header = vec![HEADER_MARK, 0, number as u8, track, b'1', b'0', 0x0F, 0x0F];
+            header[1] = checksum(&header[2..6]);
+            let mut bytes = vec![DATA_MARK];
+            bytes.extend_from_slice(block);
+            bytes.extend_from_slice(&[checksum(block), 0, 0]);
+            for (block, gap) in [(header, 9), (bytes, 8)] {
+                data.extend_from_slice(&[0xFF; 5]);
+                let start = data.len() * 8;
+                data.resize(data.len() + block.len() * 5 / 4, 0);
+                encode(&mut data, start, &block);
+                data.extend(std::iter::repeat_n(0x55, gap));
+            }
+        }
+        Track { data, speed: Speed::Zone(3) }
+    }
+
+    // The tracks of a disk with the sectors of `disk` on them
+    fn image_of(disk: &D64) -> G64 {
+        let mut halftracks = vec![None; 84];
+        for track in 1..=d64::TRACKS {
+            let sectors: Vec<_> = (0..d64::sectors_per_track(track))
+                .map(|n| disk.read_block(track, n).unwrap().try_into().unwrap())
+                .collect();
+            halftracks[(track * 2 - FIRST_HALFTRACK) as usize] = Some(format_track(track, &sectors));
+        }
+        G64 { path: None, version: 0, max_track_size: 7928, halftracks }
+    }
+
+    // Sector n of track t filled with t + n, and track 2's first sector
+    // with a bad checksum, as some copy protections have
+    fn image() -> G64 {
+        let mut data = Vec::new();
+        for track in 1..=d64::TRACKS {
+            for n in 0..d64::sectors_per_track(track) {
+                data.extend_from_slice(&[track + n; SECTOR_SIZE]);
+            }
+        }
+        let mut image = image_of(&D64::from_bytes(data).unwrap());
+        let track = image.halftracks[2].as_mut().unwrap();
+        let end = 5 + 10 + 9 + 5 + DATA_BLOCK * 5 / 4;
+        track.data[end - 5] ^= 0x01;
+        image
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut disk = image();
+        disk.halftracks[69] = Some(Track { data: vec![0x55; 9], speed: Speed::PerByte(vec![0x1B; 3]) });
+        let data = disk.to_bytes();
+        assert_eq!(&data[..12], b"GCR-1541\x00\x54\xF8\x1E");
+
+        let loaded = G64::from_bytes(&data).unwrap();
+        assert_eq!(loaded.halftracks.len(), 84);
+        assert_eq!(loaded.track(2), disk.track(2));
+        assert_eq!(loaded.track(71).unwrap().speed, Speed::PerByte(vec![0x1B; 3]));
+        assert_eq!(loaded.track(3), None);
+        assert_eq!(loaded.track(0), None);
+        assert_eq!(loaded.track(86), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        let disk = image().decode();
+        assert_eq!(disk.read_block(1, 0).unwrap(), vec![1; SECTOR_SIZE]);
+        assert_eq!(disk.read_block(18, 5).unwrap(), vec![23; SECTOR_SIZE]);
+        assert_eq!(disk.read_block(35, 16).unwrap(), vec![51; SECTOR_SIZE]);
+        // The sector with the bad checksum reads as zeros
+        assert_eq!(disk.read_block(2, 0).unwrap(), vec![0; SECTOR_SIZE]);
+        assert_eq!(disk.read_block(2, 1).unwrap(), vec![3; SECTOR_SIZE]);
+
+        // A track that is all sync has no sectors
+        let mut image = image();
+        image.halftracks[0].as_mut().unwrap().data.fill(0xFF);
+        assert_eq!(image.decode().read_block(1, 0).unwrap(), vec![0; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn test_store() {
+        let mut image = image();
+        let mut disk = image.decode();
+        disk.write_block(18, 1, &[0xA5; SECTOR_SIZE]).unwrap();
+        let before = image.track(2).unwrap().clone();
+        image.store(&disk).unwrap();
+        assert_eq!(image.decode().read_block(18, 1).unwrap(), vec![0xA5; SECTOR_SIZE]);
+        // The unreadable sector is left alone while the DOS leaves it zeroed
+        assert_eq!(image.track(2), Some(&before));
+
+        // A sector with no header to find can't be written
+        image.halftracks[0].as_mut().unwrap().data.fill(0xFF);
+        let mut disk = image.decode();
+        disk.write_block(1, 3, &[1; SECTOR_SIZE]).unwrap();
+        assert!(image.store(&disk).is_err());
+    }
+
+    #[test]
+    fn test_write_back() {
+        let path = std::env::temp_dir().join(format!("go64-g64-{}.g64", std::process::id()));
+        fs::write(&path, image().to_bytes()).unwrap();
+
+        let mut image = G64::open(&path).unwrap();
+        let mut disk = image.decode();
+        disk.write_block(3, 4, &[0x52; SECTOR_SIZE]).unwrap();
+        image.store(&disk).unwrap();
+        image.flush().unwrap();
+        let reopened = G64::open(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reopened.unwrap().decode().read_block(3, 4).unwrap(), vec![0x52; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn test_drive() {
+        use crate::storage::drive::{self, Drive};
+
+        let path = std::env::temp_dir().join(format!("go64-g64-drive-{}.g64", std::process::id()));
+        fs::write(&path, image_of(&D64::format(b"GCR", b"01")).to_bytes()).unwrap();
+
+        let mut disk = Drive::new(crate::storage::open_disk(&path).unwrap());
+        let saved = drive::save_file(&mut disk, b"HELLO", 0x0801, &[1, 2, 3]);
+        let mut disk = Drive::new(crate::storage::open_disk(&path).unwrap());
+        let loaded = drive::load_file(&mut disk, b"HEL*");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved, Ok(()));
+        assert_eq!(loaded, Ok((0x0801, vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(G64::from_bytes(b"GCR-1571\x00\x54\xF8\x1E").is_err());
+        // More halftracks than the head reaches
+        let mut data = image().to_bytes();
+        data[9] = 0xFF;
+        assert!(G64::from_bytes(&data).is_err());
+        let mut data = image().to_bytes();
+        data.truncate(1000);
+        assert!(G64::from_bytes(&data).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+// A folder on the host standing in for a disk: each file is a .prg, .seq or
+// .usr file named after it (or a PC64 .p00, .s00 or .u00 file keeping a name
+// the host can't), and names are translated between PETSCII and host
+// filenames as files are found, loaded and saved.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use super::{DosError, FileType, directory_program, disk_header, entry_line, name_matches, p00, sanitize_filename};
+
+const FILE_TYPES: [FileType; 3] = [FileType::Prg, FileType::Seq, FileType::Usr];
+
+/// A file in the folder, under its C64 name
+struct HostFile {
+    name: Vec<u8>,
+    file_type: FileType,
+    path: PathBuf,
+    // Size of the C64 file, without a PC64 header
+    size: u64,
+    pc64: bool,
+}
+
+/// The C64 name of a host file: capitals as the unshifted PETSCII letters,
+/// small letters as the shifted ones (which show as small letters in the
+/// lower case character set), and anything PETSCII can't show as '?'
+fn petscii_name(stem: &str) -> Vec<u8> {
+    stem.chars()
+        .map(|ch| match ch {
+            'a'..='z' => ch as u8 - b'a' + 0xC1,
+            ' '..='_' => ch as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+// A name with shifted letters read as unshifted ones, so LOAD"HELLO" typed
+// in upper case still finds hello.prg
+fn fold(name: &[u8]) -> Vec<u8> {
+    name.iter().map(|&b| if (0xC1..=0xDA).contains(&b) { b - 0x80 } else { b }).collect()
+}
+
+// Whether a file is the one a name without wildcards saves to
+fn exact(pattern: &[u8], file: &HostFile) -> bool {
+    !pattern.contains(&b'*')
+        && !pattern.contains(&b'?')
+        && file.path.file_name().and_then(|name| name.to_str()) == Some(&sanitize_filename(pattern, file.file_type))
+}
+
+fn matches(pattern: &[u8], file: &HostFile) -> bool {
+    exact(pattern, file) || name_matches(&fold(pattern), &fold(&file.name))
+}
+
+pub struct HostDir {
+    path: PathBuf,
+}
+
+impl HostDir {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Save a PRG file (2-byte load address + data)
+    pub fn write_prg(&self, filename: &[u8], start_addr: u16, data: &[u8]) -> Result<()> {
+        // Create PRG file format: [Low Addr] [High Addr] [Data...]
+        let mut file_content = Vec::with_capacity(2 + data.len());
+        file_content.push((start_addr & 0xFF) as u8);
+        file_content.push(((start_addr >> 8) & 0xFF) as u8);
+        file_content.extend_from_slice(data);
+        self.write_file(filename, FileType::Prg, &file_content)
+    }
+
+    /// Save a whole file, with the extension of its type
+    pub fn write_file(&self, filename: &[u8], file_type: FileType, data: &[u8]) -> Result<()> {
+        // Sanitize filename (host files are always replaced, @0: or not)
+        let safe_name = sanitize_filename(filename, file_type);
+
+        // Construct full path
+        let path = self.path.join(safe_name);
+
+        // Write to disk
+        let mut file = fs::File::create(path)?;
+        file.write_all(data)?;
+
+        Ok(())
+    }
+
+    /// Load a PRG file, the first one matching if the name has wildcards
+    /// Returns (C64 name of the file found, start_address, data)
+    pub fn read_prg(&self, pattern: &[u8]) -> Result<(Vec<u8>, u16, Vec<u8>), DosError> {
+        // LOAD takes a file of any type, as the 1541 does
+        let file = self.find(pattern, None)?;
+        let content = self.read(&file)?;
+
+        if content.len() < 2 {
+            // Too short to be a valid PRG
+            return Err(DosError::FileNotFound);
+        }
+
+        // Parse header
+        let start_addr = (content[0] as u16) | ((content[1] as u16) << 8);
+        let data = content[2..].to_vec();
+
+        Ok((file.name, start_addr, data))
+    }
+
+    /// Read a whole file, which must be of `file_type` if one is given
+    pub fn read_file(&self, pattern: &[u8], file_type: Option<FileType>) -> Result<Vec<u8>, DosError> {
+        let file = self.find(pattern, file_type)?;
+        self.read(&file)
+    }
+
+    // The C64 file's data, without a PC64 header
+    fn read(&self, file: &HostFile) -> Result<Vec<u8>, DosError> {
+        let content = fs::read(&file.path).map_err(|_| DosError::FileNotFound)?;
+        if file.pc64 {
+            Ok(p00::parse(&content).map(|(_, data)| data.to_vec()).unwrap_or_default())
+        } else {
+            Ok(content)
+        }
+    }
+
+    /// Delete the files matching `pattern`, returning how many there were
+    pub fn scratch(&self, pattern: &[u8]) -> Result<usize> {
+        let files: Vec<HostFile> = self.files()?.into_iter().filter(|file| matches(pattern, file)).collect();
+        for file in &files {
+            fs::remove_file(&file.path)?;
+        }
+        Ok(files.len())
+    }
+
+    /// Rename a file, as R0:NEW=OLD does. A PC64 file keeps its host name
+    /// and takes the new one in its header.
+    pub fn rename(&self, old: &[u8], new: &[u8]) -> Result<(), DosError> {
+        let file = self.find(old, None)?;
+        let taken = self.files().map_err(|_| DosError::WriteError)?.iter().any(|other| other.name == new);
+        if taken || self.path.join(sanitize_filename(new, file.file_type)).exists() {
+            return Err(DosError::FileExists);
+        }
+        let renamed = if file.pc64 {
+            fs::read(&file.path).and_then(|content| {
+                let data = p00::parse(&content).map(|(_, data)| data.to_vec()).unwrap_or_default();
+                fs::write(&file.path, p00::encode(new, &data))
+            })
+        } else {
+            fs::rename(&file.path, self.path.join(sanitize_filename(new, file.file_type)))
+        };
+        renamed.map_err(|error| {
+            tracing::warn!(target: "storage", "RENAME {:?}: {}", String::from_utf8_lossy(old), error);
+            DosError::WriteError
+        })
+    }
+
+    // The file of that name, else the first whose C64 name matches, else
+    // the first that matches in either case; one of the type asked for
+    // before one of another
+    fn find(&self, pattern: &[u8], file_type: Option<FileType>) -> Result<HostFile, DosError> {
+        let mut files = self.files().map_err(|_| DosError::FileNotFound)?;
+        let of_type = |file: &HostFile| file_type.is_none_or(|t| file.file_type == t);
+        let index = files
+            .iter()
+            .position(|file| of_type(file) && exact(pattern, file))
+            .or_else(|| files.iter().position(|file| of_type(file) && name_matches(pattern, &file.name)))
+            .or_else(|| files.iter().position(|file| of_type(file) && matches(pattern, file)));
+        match index {
+            Some(index) => Ok(files.swap_remove(index)),
+            None if files.iter().any(|file| matches(pattern, file)) => Err(DosError::FileTypeMismatch),
+            None => Err(DosError::FileNotFound),
+        }
+    }
+
+    /// The files of each type in the folder, sorted by C64 name
+    fn files(&self) -> Result<Vec<HostFile>> {
+        let mut files = Vec::new();
+        if !self.path.exists() {
+            return Ok(files);
+        }
+        for entry in fs::read_dir(&self.path)?.flatten() {
+            if let Ok(file_type) = entry.file_type()
+                && file_type.is_file()
+                && let Some(name) = entry.file_name().to_str()
+            {
+                let lower = name.to_lowercase();
+                if let Some(&file_type) = FILE_TYPES.iter().find(|t| lower.ends_with(&format!(".{}", t.extension()))) {
+                    let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                    // Remove the extension
+                    let c64_name = petscii_name(&name[0..name.len()-4]);
+                    files.push(HostFile { name: c64_name, file_type, path: entry.path(), size: len, pc64: false });
+                } else if let Some(&file_type) = FILE_TYPES.iter().find(|t| p00::has_extension(name, t.name().as_bytes()[0]))
+                    && let Ok(content) = fs::read(entry.path())
+                    && let Some((c64_name, data)) = p00::parse(&content)
+                {
+                    files.push(HostFile { name: c64_name, file_type, path: entry.path(), size: data.len() as u64, pc64: true });
+                }
+            }
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name).then(a.path.cmp(&b.path)));
+        Ok(files)
+    }
+
+    /// Generate a C64 directory listing as a BASIC program, headed by the
+    /// folder's name
+    /// Returns (load_address, data)
+    pub fn listing(&self) -> Result<(u16, Vec<u8>)> {
+        let folder = self.path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let header = disk_header(&petscii_name(folder), b"2A 2A");
+
+        // Calculate blocks (approx 254 bytes per block)
+        let lines = self
+            .files()?
+            .iter()
+            .map(|file| entry_line(file.size.div_ceil(254) as u16, &file.name, file.file_type.name(), true, false))
+            .collect();
+
+        Ok(directory_program(header, lines, 664))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A folder of its own under the system temporary directory
+    fn folder(name: &str) -> HostDir {
+        let path = std::env::temp_dir().join(format!("go64-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        HostDir::new(&path)
+    }
+
+    #[test]
+    fn test_petscii_name() {
+        assert_eq!(petscii_name("Game 2"), [b'G', 0xC1, 0xCD, 0xC5, b' ', b'2']);
+        assert_eq!(petscii_name("CAF\u{e9}"), b"CAF?");
+        // The other way round from saving
+        let name = petscii_name("gAme");
+        assert_eq!(sanitize_filename(&name, FileType::Prg), "gAme.prg");
+        assert_eq!(fold(&name), b"GAME");
+    }
+
+    #[test]
+    fn test_wildcards_and_listing() {
+        let dir = folder("host");
+        dir.write_prg(b"GAME1", 0x0801, &[1]).unwrap();
+        dir.write_prg(b"GAME2", 0x0801, &[2]).unwrap();
+        fs::write(dir.path().join("OTHER.prg"), [0x00, 0xC0, 3]).unwrap();
+        fs::write(dir.path().join("hello.prg"), [0x00, 0xC0, 5]).unwrap();
+        fs::write(dir.path().join("x.p00"), p00::encode(b"LONG/NAME", &[0x00, 0x10, 4])).unwrap();
+
+        assert_eq!(dir.read_prg(b"GAME2"), Ok((b"GAME2".to_vec(), 0x0801, vec![2])));
+        assert_eq!(dir.read_prg(b"GAME*"), Ok((b"GAME1".to_vec(), 0x0801, vec![1])));
+        assert_eq!(dir.read_prg(b"OTH?R"), Ok((b"OTHER".to_vec(), 0xC000, vec![3])));
+        assert_eq!(dir.read_prg(b"LONG/NAME"), Ok((b"LONG/NAME".to_vec(), 0x1000, vec![4])));
+        assert_eq!(dir.read_prg(b"*"), Ok((b"GAME1".to_vec(), 0x0801, vec![1])));
+        assert_eq!(dir.read_prg(b"NONE"), Err(DosError::FileNotFound));
+        // A small letter name loads by the shifted name or the unshifted one
+        assert_eq!(dir.read_prg(&petscii_name("hello")), Ok((petscii_name("hello"), 0xC000, vec![5])));
+        assert_eq!(dir.read_prg(b"HEL*"), Ok((petscii_name("hello"), 0xC000, vec![5])));
+
+        let (_, listing) = dir.listing().unwrap();
+        let text = String::from_utf8_lossy(&listing);
+        assert!(text.contains("   \"OTHER\"            PRG  "));
+        let order: Vec<usize> = ["GAME1", "GAME2", "LONG/NAME", "OTHER"].iter().map(|name| text.find(name).unwrap()).collect();
+        assert!(order.is_sorted());
+        let mut header = vec![0x12, b'"'];
+        header.extend_from_slice(&petscii_name(dir.path().file_name().unwrap().to_str().unwrap()));
+        assert!(listing.windows(header.len()).any(|window| window == header));
+        fs::remove_dir_all(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_seq_files() {
+        let dir = folder("seq");
+        dir.write_file(b"SCORES", FileType::Seq, b"100\r").unwrap();
+        assert!(dir.path().join("SCORES.seq").exists());
+        fs::write(dir.path().join("notes.s00"), p00::encode(b"NOTES/1", b"HI")).unwrap();
+
+        assert_eq!(dir.read_file(b"SCORES", Some(FileType::Seq)), Ok(b"100\r".to_vec()));
+        assert_eq!(dir.read_file(b"SCORES", None), Ok(b"100\r".to_vec()));
+        assert_eq!(dir.read_file(b"SCORES", Some(FileType::Prg)), Err(DosError::FileTypeMismatch));
+        assert_eq!(dir.read_file(b"NOTES/1", Some(FileType::Seq)), Ok(b"HI".to_vec()));
+
+        // A program of the same name doesn't hide the sequential file
+        dir.write_prg(b"SCORES", 0x0801, &[]).unwrap();
+        assert_eq!(dir.read_file(b"SCORES", Some(FileType::Seq)), Ok(b"100\r".to_vec()));
+        assert_eq!(dir.read_file(b"SCO*", Some(FileType::Prg)), Ok(vec![0x01, 0x08]));
+
+        // Renaming and scratching take files of every type
+        assert_eq!(dir.rename(b"SCORES", b"NOTES/1"), Err(DosError::FileExists));
+        dir.rename(b"NOTES/1", b"OLD NOTES").unwrap();
+        assert!(dir.path().join("notes.s00").exists());
+        assert_eq!(dir.read_file(b"OLD NOTES", Some(FileType::Seq)), Ok(b"HI".to_vec()));
+        dir.rename(b"OLD NOTES", b"NOTES").unwrap();
+        assert_eq!(dir.scratch(b"SCORES").unwrap(), 2);
+        assert_eq!(dir.scratch(b"SCORES").unwrap(), 0);
+        assert_eq!(dir.read_file(b"NOTES", None), Ok(b"HI".to_vec()));
+
+        let (_, listing) = dir.listing().unwrap();
+        let text = String::from_utf8_lossy(&listing);
+        assert!(!text.contains("SCORES"));
+        assert!(text.contains("   \"NOTES\"            SEQ  "));
+        fs::remove_dir_all(dir.path()).unwrap();
+    }
+}
@@ -1,164 +1,303 @@
+pub mod d64;
+pub mod drive;
+pub mod g64;
+pub mod host;
+pub mod p00;
+
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-use std::io::Write;
+use std::path::{Path, PathBuf};
 use anyhow::Result;
 
+use d64::D64;
+use drive::Medium;
+use g64::G64;
+
 /// Directory where virtual 1541 disks are stored
 const STORAGE_DIR: &str = ".go64/1541";
 
-/// Initialize storage subsystem
-pub fn init() -> Result<()> {
-    let storage_path = get_storage_path()?;
+/// Initialize storage subsystem, creating drive 8's folder if need be
+pub fn init(storage_path: &Path) -> Result<()> {
     if !storage_path.exists() {
-        fs::create_dir_all(&storage_path)?;
+        fs::create_dir_all(storage_path)?;
     }
     Ok(())
 }
 
-/// Get the full path to the storage directory
-fn get_storage_path() -> Result<PathBuf> {
+/// Get the full path to the default storage directory
+pub fn default_storage_path() -> Result<PathBuf> {
     let home_dir = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
     Ok(home_dir.join(STORAGE_DIR))
 }
 
-/// Save a PRG file (2-byte load address + data)
-pub fn save_prg(filename: &[u8], start_addr: u16, data: &[u8]) -> Result<()> {
-    // Sanitize filename
-    let safe_name = sanitize_filename(filename);
-    
-    // Construct full path
-    let mut path = get_storage_path()?;
-    path.push(safe_name);
-    
-    // Create PRG file format: [Low Addr] [High Addr] [Data...]
-    let mut file_content = Vec::with_capacity(2 + data.len());
-    file_content.push((start_addr & 0xFF) as u8);
-    file_content.push(((start_addr >> 8) & 0xFF) as u8);
-    file_content.extend_from_slice(data);
-    
-    // Write to disk
-    let mut file = fs::File::create(path)?;
-    file.write_all(&file_content)?;
-    
-    Ok(())
+/// A DOS error, as the 1541 reports it on its command channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DosError {
+    WriteError,
+    WriteProtectOn,
+    SyntaxError,
+    InvalidCommand,
+    NoFileGiven,
+    FileNotFound,
+    FileExists,
+    FileTypeMismatch,
+    IllegalTrackOrSector,
+    NoChannel,
+    DiskFull,
+    DriveNotReady,
 }
 
-/// Load a PRG file
-/// Returns (start_address, data)
-pub fn load_prg(filename: &[u8]) -> Result<(u16, Vec<u8>)> {
-    // Sanitize filename
-    let safe_name = sanitize_filename(filename);
-    
-    // Construct full path
-    let mut path = get_storage_path()?;
-    path.push(safe_name);
-    
-    // Read file
-    let content = fs::read(path)?;
-    
-    if content.len() < 2 {
-        return Err(anyhow::anyhow!("File too short to be a valid PRG"));
+impl DosError {
+    pub fn code(self) -> u8 {
+        match self {
+            DosError::WriteError => 25,
+            DosError::WriteProtectOn => 26,
+            DosError::SyntaxError => 30,
+            DosError::InvalidCommand => 31,
+            DosError::NoFileGiven => 34,
+            DosError::FileNotFound => 62,
+            DosError::FileExists => 63,
+            DosError::FileTypeMismatch => 64,
+            DosError::IllegalTrackOrSector => 66,
+            DosError::NoChannel => 70,
+            DosError::DiskFull => 72,
+            DosError::DriveNotReady => 74,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            DosError::WriteError => "WRITE ERROR",
+            DosError::WriteProtectOn => "WRITE PROTECT ON",
+            DosError::SyntaxError | DosError::InvalidCommand | DosError::NoFileGiven => "SYNTAX ERROR",
+            DosError::FileNotFound => "FILE NOT FOUND",
+            DosError::FileExists => "FILE EXISTS",
+            DosError::FileTypeMismatch => "FILE TYPE MISMATCH",
+            DosError::IllegalTrackOrSector => "ILLEGAL TRACK OR SECTOR",
+            DosError::NoChannel => "NO CHANNEL",
+            DosError::DiskFull => "DISK FULL",
+            DosError::DriveNotReady => "DRIVE NOT READY",
+        }
+    }
+}
+
+impl fmt::Display for DosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}, {},00,00", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for DosError {}
+
+/// What the command channel reads: how the last command or file went, or
+/// the DOS version after power on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    FilesScratched(u8),
+    Error(DosError),
+    // An error with the track and sector it happened on
+    ErrorAt(DosError, u8, u8),
+    DosVersion,
+}
+
+impl Status {
+    /// Errors are 20 and up; the 1541 blinks its light for them
+    pub fn is_error(self) -> bool {
+        matches!(self, Status::Error(_) | Status::ErrorAt(..))
     }
-    
-    // Parse header
-    let start_addr = (content[0] as u16) | ((content[1] as u16) << 8);
-    let data = content[2..].to_vec();
-    
-    Ok((start_addr, data))
 }
 
-/// Generate a C64 directory listing as a BASIC program
-/// Returns (load_address, data)
-pub fn list_directory() -> Result<(u16, Vec<u8>)> {
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "00, OK,00,00"),
+            Status::FilesScratched(count) => write!(f, "01, FILES SCRATCHED,{:02},00", count),
+            Status::Error(error) => write!(f, "{}", error),
+            Status::ErrorAt(error, track, sector) => write!(f, "{:02}, {},{:02},{:02}", error.code(), error.message(), track, sector),
+            Status::DosVersion => write!(f, "73, CBM DOS V2.6 1541,00,00"),
+        }
+    }
+}
+
+/// The type of a file the drive can read and write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Seq,
+    Prg,
+    Usr,
+}
+
+impl FileType {
+    /// From the letter given after the name, as in "NAME,S,W"
+    pub fn from_letter(letter: u8) -> Option<Self> {
+        match letter {
+            b'S' => Some(FileType::Seq),
+            b'P' => Some(FileType::Prg),
+            b'U' => Some(FileType::Usr),
+            _ => None,
+        }
+    }
+
+    /// The type's code in a directory entry
+    pub fn code(self) -> u8 {
+        match self {
+            FileType::Seq => 1,
+            FileType::Prg => 2,
+            FileType::Usr => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FileType::Seq => "SEQ",
+            FileType::Prg => "PRG",
+            FileType::Usr => "USR",
+        }
+    }
+
+    /// The extension of host files of this type
+    pub fn extension(self) -> &'static str {
+        match self {
+            FileType::Seq => "seq",
+            FileType::Prg => "prg",
+            FileType::Usr => "usr",
+        }
+    }
+}
+
+/// Match a filename against a DOS pattern: ? stands for any one character
+/// and * for the rest of the name
+pub fn name_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (Some(b'*'), _) => true,
+        (None, None) => true,
+        (Some(b'?'), Some(_)) => name_matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => name_matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Split the drive prefix off a filename: "0:NAME", or "@0:NAME" (also
+/// "@:NAME") to replace an existing file on SAVE
+/// Returns (name, replace)
+pub fn parse_filename(filename: &[u8]) -> (&[u8], bool) {
+    let (rest, replace) = match filename.strip_prefix(b"@") {
+        Some(rest) => (rest, true),
+        None => (filename, false),
+    };
+    let rest = rest.strip_prefix(b"0").unwrap_or(rest);
+    match rest.strip_prefix(b":") {
+        Some(name) => (name, replace),
+        None if replace => (rest, true),
+        None => (filename, false),
+    }
+}
+
+/// Open a disk image for a drive, by its extension: a G64 image of GCR
+/// tracks, whose sectors are decoded for the files on it, or a D64
+pub fn open_disk(path: &Path) -> Result<Medium> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase);
+    if extension.as_deref() == Some("g64") {
+        let image = G64::open(path)?;
+        let disk = image.decode();
+        return Ok(Medium::Gcr { image, disk });
+    }
+    Ok(Medium::Image(D64::open(path)?))
+}
+
+/// Directory listing of a disk image, with its own header and free blocks
+pub fn list_image(disk: &D64) -> (u16, Vec<u8>) {
+    let (name, mut id) = disk.header();
+    id.push(b' ');
+    id.extend_from_slice(&disk.dos_type());
+    let header = disk_header(&name, &id);
+
+    let lines = disk
+        .entries()
+        .iter()
+        .map(|entry| entry_line(entry.blocks, &entry.name, entry.type_name(), entry.is_closed(), entry.is_locked()))
+        .collect();
+    directory_program(header, lines, disk.blocks_free())
+}
+
+// Listing lines are 27 characters, as the 1541 pads them
+const LINE_LEN: usize = 27;
+
+// The first line of a listing, in reverse: the disk name in quotes, padded
+// to 16 characters, then the ID and DOS type, as in "42 2A"
+fn disk_header(name: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut header = vec![0x12, b'"'];
+    header.extend_from_slice(&name[..name.len().min(16)]);
+    header.resize(18, b' ');
+    header.extend_from_slice(b"\" ");
+    header.extend(id.iter().map(|&b| if b == 0xA0 { b' ' } else { b }));
+    header
+}
+
+// A file's line in a listing, after its block count: spaces lining the
+// names up whatever the count, the quoted name, padding to line up the
+// types, then * for a file left open, the type and < for a locked file
+//    "NAME"            PRG
+fn entry_line(blocks: u16, name: &[u8], file_type: &str, closed: bool, locked: bool) -> (u16, Vec<u8>) {
+    let mut line = vec![b' '; match blocks { 0..=9 => 3, 10..=99 => 2, _ => 1 }];
+    line.push(b'"');
+    line.extend_from_slice(name);
+    line.push(b'"');
+    line.resize(line.len() + 16usize.saturating_sub(name.len()), b' ');
+    line.push(if closed { b' ' } else { b'*' });
+    line.extend_from_slice(file_type.as_bytes());
+    line.push(if locked { b'<' } else { b' ' });
+    line.resize(line.len().max(LINE_LEN), b' ');
+    (blocks, line)
+}
+
+// The listing as LOAD"$" delivers it: a BASIC program whose line numbers
+// are the block counts. Like the 1541's, it loads at $0401 and every line
+// links to $0101, which BASIC puts right when it relinks the program.
+fn directory_program(header: Vec<u8>, lines: Vec<(u16, Vec<u8>)>, blocks_free: u16) -> (u16, Vec<u8>) {
+    const START_ADDR: u16 = 0x0401;
+    const LINK: u16 = 0x0101;
     let mut data = Vec::new();
-    let start_addr = 0x0801; // Standard BASIC start address
-    
-    // Helper to write a BASIC line
-    // Returns the address of the NEXT line
-    let mut current_addr = start_addr;
-    
-    let mut write_line = |line_num: u16, text: &str| {
-        // Calculate line size: 2 (next) + 2 (num) + text.len() + 1 (null)
-        let line_size = 2 + 2 + text.len() + 1;
-        let next_addr = current_addr + line_size as u16;
-        
-        // Next Line Pointer
-        data.push((next_addr & 0xFF) as u8);
-        data.push(((next_addr >> 8) & 0xFF) as u8);
-        
+
+    let mut write_line = |line_num: u16, text: &[u8]| {
+        data.extend_from_slice(&LINK.to_le_bytes());
         // Line Number (used as block count in directory listings)
-        data.push((line_num & 0xFF) as u8);
-        data.push(((line_num >> 8) & 0xFF) as u8);
-        
-        // Text
-        data.extend_from_slice(text.as_bytes());
+        data.extend_from_slice(&line_num.to_le_bytes());
+        data.extend_from_slice(text);
         data.push(0); // Null terminator
-        
-        current_addr = next_addr;
     };
 
     // Header: Line 0
-    // Simplified header to avoid special characters that might render poorly in terminal
-    write_line(0, "\"FLOPPY DISK\"     ID 2A");
-    
-    // Read directory
-    let path = get_storage_path()?;
-    if path.exists() {
-        // Collect entries to sort them
-        let mut entries = Vec::new();
-        for entry in fs::read_dir(path)? {
-            if let Ok(entry) = entry {
-                if let Ok(file_type) = entry.file_type() {
-                    if file_type.is_file() {
-                        if let Some(name) = entry.file_name().to_str() {
-                            if name.to_lowercase().ends_with(".prg") {
-                                let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
-                                entries.push((name.to_string(), len));
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        
-        entries.sort_by(|a, b| a.0.cmp(&b.0));
-        
-        for (name, size) in entries {
-            // Calculate blocks (approx 254 bytes per block)
-            let blocks = (size + 253) / 254;
-            
-            // Format name: remove .prg, quote it, pad to align "PRG"
-            let disp_name = &name[0..name.len()-4];
-            let upper_name = disp_name.to_uppercase();
-            
-            // Pad with spaces to make it look nice (max 16 chars for name usually)
-            // "NAME"            PRG
-            let mut line_text = format!("\"{}\"", upper_name);
-            while line_text.len() < 18 {
-                line_text.push(' ');
-            }
-            line_text.push_str("PRG");
-            
-            write_line(blocks as u16, &line_text);
-        }
+    write_line(0, &header);
+    for (blocks, text) in lines {
+        write_line(blocks, &text);
     }
-    
+
     // Footer: Line <Free Blocks>
-    write_line(664, "BLOCKS FREE.");
-    
+    let mut footer = b"BLOCKS FREE.".to_vec();
+    footer.resize(25, b' ');
+    write_line(blocks_free, &footer);
+
     // End of Program (2 null bytes)
     data.push(0);
     data.push(0);
-    
-    Ok((start_addr, data))
+
+    (START_ADDR, data)
 }
 
 /// Sanitize C64 filename to be safe for host OS
-/// Replaces reserved characters with '_' and trims whitespace
-pub fn sanitize_filename(petscii: &[u8]) -> String {
+/// Replaces reserved characters with '_' and trims whitespace, and appends
+/// the extension of the file type
+pub fn sanitize_filename(petscii: &[u8], file_type: FileType) -> String {
     let mut name = String::new();
     
     for &byte in petscii {
+        // Shifted letters, the capitals of the lower case character set
+        let byte = match byte {
+            0xC1..=0xDA => byte - 0x80 + 0x20,
+            _ => byte,
+        };
         let ch = byte as char;
         // Check for reserved chars on Windows/Unix
         // / \ : * ? " < > |
@@ -166,7 +305,7 @@ pub fn sanitize_filename(petscii: &[u8]) -> String {
             '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => name.push('_'),
             _ => {
                 // Only allow printable ASCII
-                if byte >= 32 && byte <= 126 {
+                if (32..=126).contains(&byte) {
                     name.push(ch);
                 } else {
                     name.push('_');
@@ -186,8 +325,9 @@ pub fn sanitize_filename(petscii: &[u8]) -> String {
     };
     
     // Ensure extension
-    if !final_name.to_lowercase().ends_with(".prg") {
-        final_name.push_str(".prg");
+    let extension = format!(".{}", file_type.extension());
+    if !final_name.to_lowercase().ends_with(&extension) {
+        final_name.push_str(&extension);
     }
     
     final_name
@@ -200,22 +340,80 @@ mod tests {
     #[test]
     fn test_sanitize_filename() {
         // Basic case
-        assert_eq!(sanitize_filename(b"TEST"), "TEST.prg");
+        assert_eq!(sanitize_filename(b"TEST", FileType::Prg), "TEST.prg");
         
         // With extension
-        assert_eq!(sanitize_filename(b"GAME.PRG"), "GAME.PRG");
+        assert_eq!(sanitize_filename(b"GAME.PRG", FileType::Prg), "GAME.PRG");
         
         // Reserved chars
-        assert_eq!(sanitize_filename(b"TEST/FILE"), "TEST_FILE.prg");
-        assert_eq!(sanitize_filename(b"TEST:FILE"), "TEST_FILE.prg");
+        assert_eq!(sanitize_filename(b"TEST/FILE", FileType::Prg), "TEST_FILE.prg");
+        assert_eq!(sanitize_filename(b"TEST:FILE", FileType::Prg), "TEST_FILE.prg");
         
         // Whitespace
-        assert_eq!(sanitize_filename(b"  TEST  "), "TEST.prg");
+        assert_eq!(sanitize_filename(b"  TEST  ", FileType::Prg), "TEST.prg");
         
         // Empty
-        assert_eq!(sanitize_filename(b""), "UNNAMED.prg");
+        assert_eq!(sanitize_filename(b"", FileType::Prg), "UNNAMED.prg");
         
         // Non-printable
-        assert_eq!(sanitize_filename(&[0, 1, 65, 66]), "__AB.prg");
+        assert_eq!(sanitize_filename(&[0, 1, 65, 66], FileType::Prg), "__AB.prg");
+
+        // Shifted letters
+        assert_eq!(sanitize_filename(&[0xC7, 65, 0xCD, 0xC5], FileType::Prg), "gAme.prg");
+
+        // Sequential files
+        assert_eq!(sanitize_filename(b"SCORES", FileType::Seq), "SCORES.seq");
+    }
+
+    #[test]
+    fn test_name_matches() {
+        assert!(name_matches(b"GAME", b"GAME"));
+        assert!(!name_matches(b"GAME", b"GAMES"));
+        assert!(name_matches(b"GAME*", b"GAMES"));
+        assert!(name_matches(b"*", b"ANYTHING"));
+        assert!(name_matches(b"G?ME", b"GAME"));
+        assert!(!name_matches(b"G?ME", b"GME"));
+    }
+
+    #[test]
+    fn test_parse_filename() {
+        assert_eq!(parse_filename(b"GAME"), (&b"GAME"[..], false));
+        assert_eq!(parse_filename(b"0:GAME"), (&b"GAME"[..], false));
+        assert_eq!(parse_filename(b"@0:GAME"), (&b"GAME"[..], true));
+        assert_eq!(parse_filename(b"@:GAME"), (&b"GAME"[..], true));
+        assert_eq!(parse_filename(b"A:B"), (&b"A:B"[..], false));
+    }
+
+    #[test]
+    fn test_list_image() {
+        let mut disk = D64::format(b"MY DISK", b"42");
+        disk.write_prg(b"HELLO", false, 0x0801, &[0; 300]).unwrap();
+        disk.write_prg(b"BIG", false, 0x0801, &[0; 3000]).unwrap();
+        let (start, data) = list_image(&disk);
+        assert_eq!(start, 0x0401);
+
+        // Lines of the BASIC program: (link, line number, text)
+        let mut lines = Vec::new();
+        let mut rest = &data[..];
+        while rest[0] != 0 || rest[1] != 0 {
+            let end = 4 + rest[4..].iter().position(|&b| b == 0).unwrap();
+            lines.push((u16::from_le_bytes([rest[0], rest[1]]), u16::from_le_bytes([rest[2], rest[3]]), &rest[4..end]));
+            rest = &rest[end + 1..];
+        }
+        assert_eq!(rest, &[0, 0]);
+        assert!(lines.iter().all(|&(link, _, _)| link == 0x0101));
+        assert_eq!(lines[0], (0x0101, 0, &b"\x12\"MY DISK         \" 42 2A"[..]));
+        // 2 and 12 blocks, their names lined up, then 650 blocks free
+        assert_eq!(lines[1], (0x0101, 2, &b"   \"HELLO\"            PRG  "[..]));
+        assert_eq!(lines[2], (0x0101, 12, &b"  \"BIG\"              PRG   "[..]));
+        assert_eq!(lines[3], (0x0101, 650, &b"BLOCKS FREE.             "[..]));
+    }
+
+    #[test]
+    fn test_entry_line() {
+        // A file left open, and a locked one
+        assert_eq!(entry_line(0, b"OPEN", "SEQ", false, false).1, b"   \"OPEN\"            *SEQ  ");
+        assert_eq!(entry_line(123, b"LOCKED", "PRG", true, true).1, b" \"LOCKED\"           PRG<   ");
+        assert_eq!(entry_line(5, b"SIXTEEN CHARS 16", "USR", true, false).1.len(), LINE_LEN);
     }
 }
@@ -0,0 +1,59 @@
+// PC64 files (.P00, .S00, .U00, .R00 and on to 99 for clashing names): a
+// C64 file with a header that keeps its PETSCII name, which host filenames
+// can't hold. The header is "C64File", a zero, the name in 17 bytes padded
+// with zeroes, and the record size of REL files; the file follows as is.
+
+const SIGNATURE: &[u8; 8] = b"C64File\0";
+const NAME_LEN: usize = 16;
+const HEADER_SIZE: usize = 26;
+
+/// Whether a host filename has a PC64 extension of the given type
+/// (b'P' for program files)
+pub fn has_extension(filename: &str, file_type: u8) -> bool {
+    let bytes = filename.as_bytes();
+    bytes.len() > 4
+        && bytes[bytes.len() - 4] == b'.'
+        && bytes[bytes.len() - 3].eq_ignore_ascii_case(&file_type)
+        && bytes[bytes.len() - 2..].iter().all(u8::is_ascii_digit)
+}
+
+/// The C64 name and the file data, if `content` is a PC64 file
+pub fn parse(content: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    if content.len() < HEADER_SIZE || &content[..8] != SIGNATURE {
+        return None;
+    }
+    let name = &content[8..8 + NAME_LEN];
+    let end = name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+    Some((name[..end].to_vec(), &content[HEADER_SIZE..]))
+}
+
+pub fn encode(name: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut content = SIGNATURE.to_vec();
+    content.extend_from_slice(&name[..name.len().min(NAME_LEN)]);
+    content.resize(HEADER_SIZE, 0);
+    content.extend_from_slice(data);
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension() {
+        assert!(has_extension("game.p00", b'P'));
+        assert!(has_extension("GAME.P12", b'P'));
+        assert!(!has_extension("game.s00", b'P'));
+        assert!(!has_extension("game.prg", b'P'));
+        assert!(!has_extension(".p0", b'P'));
+    }
+
+    #[test]
+    fn test_parse() {
+        let content = encode(b"MY GAME/V2", &[0x01, 0x08, 0xAA]);
+        assert_eq!(content.len(), HEADER_SIZE + 3);
+        assert_eq!(parse(&content), Some((b"MY GAME/V2".to_vec(), &[0x01, 0x08, 0xAA][..])));
+        assert_eq!(parse(&content[1..]), None);
+        assert_eq!(parse(b"C64File\0"), None);
+    }
+}
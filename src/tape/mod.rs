@@ -0,0 +1,160 @@
+// Tapes for the datasette (device 1), from the two image formats in use:
+// T64 archives, which keep the files of a tape as they load into memory,
+// and TAP images, which keep the pulses recorded from the tape itself. A
+// host folder can stand in for a tape too, its programs as .prg files.
+//
+// Until the datasette itself is emulated, the KERNAL's LOAD and SAVE traps
+// take programs from here and put them here. Like a tape, LOAD "" loads the
+// next program and LOAD "NAME" the next whose name starts with NAME, except
+// that the search goes round to the start rather than stopping at the end.
+// SAVE adds programs to the end of the tape, and an image file is written
+// back straight away.
+
+pub mod t64;
+pub mod tap;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+
+use crate::storage::host::HostDir;
+
+pub struct Tape {
+    // The file it came from, which snapshots record
+    pub path: PathBuf,
+    pub image: Image,
+    // The next file along, in an image
+    position: usize,
+}
+
+pub enum Image {
+    T64(t64::T64),
+    Tap(tap::Tap),
+    Folder(HostDir),
+}
+
+// A program on a tape
+#[derive(Debug, Clone, PartialEq)]
+pub struct File {
+    pub name: Vec<u8>,
+    pub start: u16,
+    pub data: Vec<u8>,
+    // Loaded where LOAD is told, unless it asks for the file's own address;
+    // the KERNAL's tape header can pin a program to its address instead
+    pub relocatable: bool,
+}
+
+// A name without the spaces (or shifted spaces) padding it
+fn trim_name(name: &[u8]) -> Vec<u8> {
+    let end = name.iter().rposition(|&b| !matches!(b, b' ' | 0xA0 | 0)).map_or(0, |i| i + 1);
+    name[..end].to_vec()
+}
+
+impl Tape {
+    // Open a tape image, telling the formats apart by their signatures
+    pub fn load(path: &Path) -> Result<Self> {
+        let image = if path.is_dir() {
+            Image::Folder(HostDir::new(path))
+        } else {
+            let data = fs::read(path).context(format!("Failed to read {:?}", path))?;
+            if data.starts_with(tap::SIGNATURE) {
+                Image::Tap(tap::Tap::parse(data)?)
+            } else if data.starts_with(t64::SIGNATURE) {
+                Image::T64(t64::T64::parse(&data)?)
+            } else {
+                anyhow::bail!("{:?} is not a T64 or TAP tape image", path);
+            }
+        };
+        let tape = Tape { path: path.to_path_buf(), image, position: 0 };
+        tracing::info!(target: "tape", "{}", tape.describe());
+        Ok(tape)
+    }
+
+    // What's on the tape, e.g. "T64 \"GAMES\", 3 files"
+    pub fn describe(&self) -> String {
+        let files = |count: usize| format!("{} file{}", count, if count == 1 { "" } else { "s" });
+        match &self.image {
+            Image::T64(t64) => format!("T64 \"{}\", {}", String::from_utf8_lossy(&t64.name), files(t64.entries.len())),
+            Image::Tap(tap) => format!("TAP version {}, {} in {} bytes of pulses", tap.version, files(tap.files.len()), tap.pulses.len()),
+            Image::Folder(dir) => format!("folder {:?}", dir.path()),
+        }
+    }
+
+    fn files(&self) -> &[File] {
+        match &self.image {
+            Image::T64(t64) => &t64.entries,
+            Image::Tap(tap) => &tap.files,
+            Image::Folder(_) => &[],
+        }
+    }
+
+    // The next program whose name starts with `name`, or the next of all
+    // for an empty name
+    pub fn find(&mut self, name: &[u8]) -> Option<File> {
+        if let Image::Folder(dir) = &self.image {
+            let mut pattern = name.to_vec();
+            pattern.push(b'*');
+            let (name, start, data) = dir.read_prg(&pattern).ok()?;
+            return Some(File { name, start, data, relocatable: true });
+        }
+        let files = self.files();
+        let found = (self.position..files.len())
+            .chain(0..self.position)
+            .find(|&i| files[i].name.starts_with(name))?;
+        let file = files[found].clone();
+        tracing::info!(target: "tape", "Found {:?}", String::from_utf8_lossy(&file.name));
+        self.position = found + 1;
+        Some(file)
+    }
+
+    // Add a program at the end of the tape
+    pub fn save(&mut self, file: File) -> Result<()> {
+        let data = match &mut self.image {
+            Image::Folder(dir) => return dir.write_prg(&file.name, file.start, &file.data),
+            Image::T64(t64) => {
+                t64.entries.push(file);
+                t64.to_bytes()
+            }
+            Image::Tap(tap) => {
+                tap.record(file);
+                tap.to_bytes()
+            }
+        };
+        self.position = self.files().len();
+        fs::write(&self.path, data).context(format!("Failed to write {:?}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_and_save() {
+        let dir = std::env::temp_dir().join(format!("go64-tape-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("games.t64");
+        std::fs::write(&path, t64::T64 { name: b"GAMES".to_vec(), entries: Vec::new() }.to_bytes()).unwrap();
+
+        let mut tape = Tape::load(&path).unwrap();
+        let file = |name: &[u8], start| File { name: name.to_vec(), start, data: vec![1, 2, 3], relocatable: true };
+        for (name, start) in [(&b"INTRO"[..], 0x0801), (b"GAME", 0x0801), (b"GAME PART 2", 0xC000)] {
+            tape.save(file(name, start)).unwrap();
+        }
+
+        // The image was written back, and searches go on from the last
+        // file found
+        let mut tape = Tape::load(&path).unwrap();
+        assert_eq!(tape.find(b"GAME"), Some(file(b"GAME", 0x0801)));
+        assert_eq!(tape.find(b"GAME").unwrap().start, 0xC000);
+        assert_eq!(tape.find(b"").unwrap().name, b"INTRO");
+        assert_eq!(tape.find(b"MISSING"), None);
+
+        let mut folder = Tape::load(&dir).unwrap();
+        folder.save(file(b"HELLO", 0x0801)).unwrap();
+        // Found by the start of its name, as it is named in the folder
+        assert_eq!(folder.find(b"HEL"), Some(file(b"HELLO", 0x0801)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+// T64 archives: a 64 byte header with the tape's name and how many entries
+// its directory has room for, the directory of 32 byte entries (the start
+// and end address, where in the archive the file is and its name), then
+// the files. Many converters wrote a wrong end address, so a file's size is
+// also bounded by where the next one starts.
+
+use anyhow::Result;
+
+use super::{trim_name, File};
+
+// "C64 tape image file", "C64S tape file" and the like
+pub const SIGNATURE: &[u8] = b"C64";
+
+const HEADER_SIZE: usize = 0x40;
+const ENTRY_SIZE: usize = 0x20;
+const NAME_LEN: usize = 16;
+
+pub struct T64 {
+    pub name: Vec<u8>,
+    pub entries: Vec<File>,
+}
+
+impl T64 {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE || !data.starts_with(SIGNATURE) {
+            anyhow::bail!("not a T64 archive");
+        }
+        let max_entries = u16::from_le_bytes([data[0x22], data[0x23]]) as usize;
+        let name = trim_name(&data[0x28..0x40]);
+
+        // Entry type 0 is a free slot; the others are files
+        let raw: Vec<&[u8]> = (0..max_entries)
+            .map(|i| HEADER_SIZE + i * ENTRY_SIZE)
+            .take_while(|&offset| offset + ENTRY_SIZE <= data.len())
+            .map(|offset| &data[offset..offset + ENTRY_SIZE])
+            .filter(|entry| entry[0] != 0)
+            .collect();
+        let offset_of = |entry: &[u8]| u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+
+        let mut entries = Vec::new();
+        for entry in &raw {
+            let offset = offset_of(entry);
+            if offset >= data.len() {
+                anyhow::bail!("T64 entry past the end of the archive");
+            }
+            let start = u16::from_le_bytes([entry[2], entry[3]]);
+            let end = u16::from_le_bytes([entry[4], entry[5]]);
+            let next = raw.iter().map(|other| offset_of(other)).filter(|&other| other > offset).min().unwrap_or(data.len());
+            let available = next.min(data.len()) - offset;
+            let size = if end > start { (end - start) as usize } else { available };
+            entries.push(File {
+                name: trim_name(&entry[0x10..0x10 + NAME_LEN]),
+                start,
+                data: data[offset..offset + size.min(available)].to_vec(),
+                relocatable: true,
+            });
+        }
+        Ok(Self { name, entries })
+    }
+
+    // The archive, with a directory just big enough for its files
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[..19].copy_from_slice(b"C64 tape image file");
+        data[0x20] = 0x01;
+        data[0x21] = 0x01;
+        let count = (self.entries.len() as u16).to_le_bytes();
+        data[0x22..0x24].copy_from_slice(&count);
+        data[0x24..0x26].copy_from_slice(&count);
+        data[0x28..0x40].copy_from_slice(&padded(&self.name, 0x18));
+        let mut offset = HEADER_SIZE + self.entries.len() * ENTRY_SIZE;
+        for file in &self.entries {
+            let mut entry = vec![0; ENTRY_SIZE];
+            // A normal tape file, a PRG to the 1541
+            entry[0] = 1;
+            entry[1] = 0x82;
+            entry[2..4].copy_from_slice(&file.start.to_le_bytes());
+            entry[4..6].copy_from_slice(&file.start.wrapping_add(file.data.len() as u16).to_le_bytes());
+            entry[8..12].copy_from_slice(&(offset as u32).to_le_bytes());
+            entry[0x10..0x20].copy_from_slice(&padded(&file.name, NAME_LEN));
+            data.extend_from_slice(&entry);
+            offset += file.data.len();
+        }
+        for file in &self.entries {
+            data.extend_from_slice(&file.data);
+        }
+        data
+    }
+}
+
+// A name cut or padded with spaces to its field
+fn padded(name: &[u8], len: usize) -> Vec<u8> {
+    let mut field = name[..name.len().min(len)].to_vec();
+    field.resize(len, b' ');
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An archive of the given files, each (name, start, data, end address
+    // as written in the directory)
+    fn archive(files: &[(&[u8], u16, &[u8], u16)]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[..19].copy_from_slice(b"C64 tape image file");
+        data[0x22] = files.len() as u8;
+        data[0x24] = files.len() as u8;
+        data[0x28..0x40].fill(b' ');
+        data[0x28..0x2D].copy_from_slice(b"GAMES");
+        let mut offset = HEADER_SIZE + files.len() * ENTRY_SIZE;
+        for (name, start, file, end) in files {
+            let mut entry = vec![0; ENTRY_SIZE];
+            entry[0] = 1;
+            entry[1] = 0x82;
+            entry[2..4].copy_from_slice(&start.to_le_bytes());
+            entry[4..6].copy_from_slice(&end.to_le_bytes());
+            entry[8..12].copy_from_slice(&(offset as u32).to_le_bytes());
+            entry[0x10..0x20].fill(b' ');
+            entry[0x10..0x10 + name.len()].copy_from_slice(name);
+            data.extend_from_slice(&entry);
+            offset += file.len();
+        }
+        for (_, _, file, _) in files {
+            data.extend_from_slice(file);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        // The second end address is one of the wrong ones converters wrote
+        let data = archive(&[(b"FIRST", 0x0801, &[1, 2, 3], 0x0804), (b"SECOND", 0xC000, &[4, 5], 0xC3C6)]);
+        let t64 = T64::parse(&data).unwrap();
+        assert_eq!(t64.name, b"GAMES");
+        assert_eq!(t64.entries.len(), 2);
+        assert_eq!((t64.entries[0].name.as_slice(), t64.entries[0].start), (&b"FIRST"[..], 0x0801));
+        assert_eq!(t64.entries[0].data, vec![1, 2, 3]);
+        assert_eq!(t64.entries[1].data, vec![4, 5]);
+        assert!(T64::parse(b"C64").is_err());
+
+        // Written out with the end addresses right
+        let written = T64::parse(&t64.to_bytes()).unwrap();
+        assert_eq!(written.name, b"GAMES");
+        assert_eq!(written.entries, t64.entries);
+    }
+}
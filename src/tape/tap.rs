@@ -0,0 +1,278 @@
+// TAP images: the pulses read off a tape, one byte each giving the time
+// between falling edges in units of 8 cycles. Version 1 and later mark
+// pulses too long for a byte with a zero followed by their length in
+// cycles, in 3 bytes.
+//
+// Programs the KERNAL saved are read back out of the pulses. It writes
+// three lengths of pulse: a bit is a short and a medium pulse for 0 or a
+// medium and a short for 1, and a byte is a long and a medium pulse, eight
+// bits from the lowest up and an odd parity bit. Each program is a header
+// block (its type, start and end address and name in 192 bytes) and a data
+// block, each written twice, after a leader of short pulses. A block starts
+// with a countdown, $89 to $81 the first time and $09 to $01 the second,
+// and ends with a checksum, all its bytes XORed. Turbo loaders write their
+// own formats, which aren't found.
+
+use anyhow::Result;
+
+use super::{trim_name, File};
+
+pub const SIGNATURE: &[u8] = b"C64-TAPE-RAW";
+
+const HEADER_SIZE: usize = 20;
+
+// The KERNAL's pulses, and the lengths that tell them apart on reading
+const SHORT: u8 = 0x30;
+const MEDIUM: u8 = 0x42;
+const LONG: u8 = 0x56;
+const SHORT_UNDER: u32 = 0x3A;
+const MEDIUM_UNDER: u32 = 0x4D;
+const LONG_UNDER: u32 = 0x64;
+
+// Short pulses before the first copy of a header and of a data block, and
+// before the second copies and after them
+const HEADER_LEADER: usize = 0x6A00;
+const DATA_LEADER: usize = 0x1500;
+const REPEAT_LEADER: usize = 0x4F;
+const TRAILER: usize = 0x4E;
+
+// Header blocks: for a program loaded where LOAD is told, or at its own
+// address
+const RELOCATABLE: u8 = 1;
+const ABSOLUTE: u8 = 3;
+const HEADER_BLOCK_SIZE: usize = 192;
+const NAME_LEN: usize = 16;
+
+pub struct Tap {
+    pub version: u8,
+    pub pulses: Vec<u8>,
+    // The programs found in the pulses
+    pub files: Vec<File>,
+    header: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pulse {
+    Short,
+    Medium,
+    Long,
+    Other,
+}
+
+impl Tap {
+    pub fn parse(mut data: Vec<u8>) -> Result<Self> {
+        if data.len() < HEADER_SIZE || !data.starts_with(SIGNATURE) {
+            anyhow::bail!("not a TAP image");
+        }
+        let version = data[12];
+        if version > 2 {
+            anyhow::bail!("unknown TAP version {}", version);
+        }
+        // The size in the header, unless the image was cut short
+        let size = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+        data.truncate(HEADER_SIZE + size.min(data.len() - HEADER_SIZE));
+        let pulses = data.split_off(HEADER_SIZE);
+        let files = decode(&classify(version, &pulses));
+        Ok(Self { version, pulses, files, header: data })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.header.clone();
+        data[16..20].copy_from_slice(&(self.pulses.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.pulses);
+        data
+    }
+
+    // Record a program at the end, as the KERNAL's SAVE would
+    pub fn record(&mut self, file: File) {
+        let end = file.start.wrapping_add(file.data.len() as u16);
+        let mut header = vec![if file.relocatable { RELOCATABLE } else { ABSOLUTE }];
+        header.extend_from_slice(&file.start.to_le_bytes());
+        header.extend_from_slice(&end.to_le_bytes());
+        header.extend_from_slice(&file.name[..file.name.len().min(NAME_LEN)]);
+        header.resize(HEADER_BLOCK_SIZE, b' ');
+        write_block(&mut self.pulses, &header, HEADER_LEADER);
+        write_block(&mut self.pulses, &file.data, DATA_LEADER);
+        self.files.push(file);
+    }
+}
+
+fn classify(version: u8, pulses: &[u8]) -> Vec<Pulse> {
+    let mut classified = Vec::with_capacity(pulses.len());
+    let mut bytes = pulses.iter();
+    while let Some(&byte) = bytes.next() {
+        let length = match byte {
+            // Too long for a byte: in cycles from version 1 on
+            0 if version > 0 => {
+                let cycles: Vec<u8> = bytes.by_ref().take(3).copied().collect();
+                cycles.iter().rev().fold(0, |length, &byte| length << 8 | byte as u32) / 8
+            }
+            0 => u32::MAX,
+            _ => byte as u32,
+        };
+        classified.push(match length {
+            ..SHORT_UNDER => Pulse::Short,
+            SHORT_UNDER..MEDIUM_UNDER => Pulse::Medium,
+            MEDIUM_UNDER..LONG_UNDER => Pulse::Long,
+            _ => Pulse::Other,
+        });
+    }
+    classified
+}
+
+// A byte, from the pulses after its long and medium marker
+fn read_byte(pulses: &[Pulse]) -> Option<u8> {
+    let mut byte = 0;
+    let mut ones = 0u8;
+    for bit in 0..9 {
+        let one = match pulses.get(bit * 2..bit * 2 + 2)? {
+            [Pulse::Short, Pulse::Medium] => 0,
+            [Pulse::Medium, Pulse::Short] => 1,
+            _ => return None,
+        };
+        ones += one;
+        if bit < 8 {
+            byte |= one << bit;
+        }
+    }
+    (!ones.is_multiple_of(2)).then_some(byte)
+}
+
+fn write_byte(pulses: &mut Vec<u8>, byte: u8) {
+    pulses.extend_from_slice(&[LONG, MEDIUM]);
+    for bit in 0..8 {
+        pulses.extend_from_slice(if byte >> bit & 1 == 1 { &[MEDIUM, SHORT] } else { &[SHORT, MEDIUM] });
+    }
+    pulses.extend_from_slice(if byte.count_ones().is_multiple_of(2) { &[MEDIUM, SHORT] } else { &[SHORT, MEDIUM] });
+}
+
+// The runs of bytes between leaders and end of data markers. A byte that
+// doesn't read right spoils its block.
+fn blocks(pulses: &[Pulse]) -> Vec<Vec<u8>> {
+    let mut blocks = Vec::new();
+    let mut block = Vec::new();
+    let mut i = 0;
+    while i + 1 < pulses.len() {
+        if pulses[i] == Pulse::Long && pulses[i + 1] == Pulse::Medium {
+            match read_byte(&pulses[i + 2..]) {
+                Some(byte) => {
+                    block.push(byte);
+                    i += 20;
+                    continue;
+                }
+                None => block.clear(),
+            }
+        } else if !block.is_empty() {
+            blocks.push(std::mem::take(&mut block));
+        }
+        i += 1;
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+// What a block holds, if its countdown and checksum are right, and whether
+// it is the second copy
+fn contents(block: &[u8]) -> Option<(bool, &[u8])> {
+    if block.len() < 10 {
+        return None;
+    }
+    let repeat = block[0] & 0x80 == 0;
+    let copy = if repeat { 0x00 } else { 0x80 };
+    if !block[..9].iter().copied().eq((1..=9).rev().map(|n| copy | n)) {
+        return None;
+    }
+    let (data, checksum) = block[9..].split_at(block.len() - 10);
+    (data.iter().fold(0, |sum, byte| sum ^ byte) == checksum[0]).then_some((repeat, data))
+}
+
+fn write_block(pulses: &mut Vec<u8>, data: &[u8], leader: usize) {
+    let checksum = data.iter().fold(0, |sum, byte| sum ^ byte);
+    for (copy, leader) in [(0x80, leader), (0x00, REPEAT_LEADER)] {
+        pulses.resize(pulses.len() + leader, SHORT);
+        for byte in (1..=9).rev().map(|n| copy | n).chain(data.iter().copied()).chain([checksum]) {
+            write_byte(pulses, byte);
+        }
+        // End of data
+        pulses.extend_from_slice(&[LONG, SHORT]);
+    }
+    pulses.resize(pulses.len() + TRAILER, SHORT);
+}
+
+// The programs on the tape: each header followed by its data, from the
+// first copy of each block or, where that doesn't read, the second
+fn decode(pulses: &[Pulse]) -> Vec<File> {
+    let mut found: Vec<Vec<u8>> = Vec::new();
+    for block in blocks(pulses) {
+        match contents(&block) {
+            // The second copy of a block already read
+            Some((true, data)) if found.last().is_some_and(|last| last == data) => {}
+            Some((_, data)) => found.push(data.to_vec()),
+            None => {}
+        }
+    }
+
+    let mut files = Vec::new();
+    let mut blocks = found.iter().peekable();
+    while let Some(header) = blocks.next() {
+        if header.len() != HEADER_BLOCK_SIZE || !matches!(header[0], RELOCATABLE | ABSOLUTE) {
+            continue;
+        }
+        let start = u16::from_le_bytes([header[1], header[2]]);
+        let end = u16::from_le_bytes([header[3], header[4]]);
+        if let Some(data) = blocks.next_if(|data| data.len() == end.wrapping_sub(start) as usize) {
+            files.push(File {
+                name: trim_name(&header[5..5 + NAME_LEN]),
+                start,
+                data: data.clone(),
+                relocatable: header[0] == RELOCATABLE,
+            });
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut data = SIGNATURE.to_vec();
+        data.extend_from_slice(&[1, 0, 0, 0, 3, 0, 0, 0, 0x30, 0x42, 0x56, 0xFF]);
+        let tap = Tap::parse(data.clone()).unwrap();
+        assert_eq!((tap.version, tap.pulses), (1, vec![0x30, 0x42, 0x56]));
+
+        data[12] = 3;
+        assert!(Tap::parse(data).is_err());
+        assert!(Tap::parse(SIGNATURE.to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_record_and_decode() {
+        let mut data = SIGNATURE.to_vec();
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        let mut tap = Tap::parse(data).unwrap();
+        let game = File { name: b"GAME".to_vec(), start: 0x0801, data: (0..=255).collect(), relocatable: true };
+        let loader = File { name: b"LOADER".to_vec(), start: 0x02A7, data: vec![0xEA; 40], relocatable: false };
+        tap.record(game.clone());
+        let loader_start = tap.pulses.len();
+        tap.record(loader.clone());
+        assert_eq!(Tap::parse(tap.to_bytes()).unwrap().files, vec![game.clone(), loader.clone()]);
+
+        // A bad bit in the first copy of the game's data, which is read
+        // from the second instead, then in both copies of the loader's
+        // header, which loses it
+        let block = |bytes: usize| bytes * 20 + 2;
+        let game_data = HEADER_LEADER + 2 * block(10 + HEADER_BLOCK_SIZE) + REPEAT_LEADER + TRAILER + DATA_LEADER;
+        let loader_header = loader_start + HEADER_LEADER;
+        let loader_repeat = loader_header + block(10 + HEADER_BLOCK_SIZE) + REPEAT_LEADER;
+        for first_pulse in [game_data, loader_header, loader_repeat] {
+            // The second bit of the 20th byte
+            tap.pulses[first_pulse + 20 * 20 + 4] = LONG;
+        }
+        assert_eq!(Tap::parse(tap.to_bytes()).unwrap().files, vec![game]);
+    }
+}
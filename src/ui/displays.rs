@@ -0,0 +1,54 @@
+// Display panel: the expressions added with the monitor's disp or
+// --display, in the debug view below the stack, worked out afresh each frame
+
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+use crate::cpu::Cpu;
+use crate::debugger::expression::Expression;
+use crate::memory::Memory;
+
+// Rows the panel takes up for `count` expressions, borders included, and
+// none without any
+pub fn height(count: usize) -> u16 {
+    if count == 0 { 0 } else { count as u16 + 2 }
+}
+
+pub fn render(frame: &mut Frame, area: Rect, displays: &[Expression], cpu: &Cpu, memory: &dyn Memory) {
+    if displays.is_empty() {
+        return;
+    }
+    let paragraph = Paragraph::new(lines(displays, cpu, memory).join("\n"))
+        .style(Style::default().fg(Color::Gray))
+        .block(Block::default().borders(Borders::ALL).title("Display"));
+    frame.render_widget(paragraph, area);
+}
+
+fn lines(displays: &[Expression], cpu: &Cpu, memory: &dyn Memory) -> Vec<String> {
+    displays.iter().map(|expression| format!("{} = {}", expression, expression.evaluate(cpu, memory))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::symbols::Labels;
+    use crate::memory::BasicMemory;
+
+    #[test]
+    fn test_lines() {
+        let mut memory = BasicMemory::new();
+        memory.write(0xD012, 0x80);
+        let mut cpu = Cpu::new();
+        cpu.a = 0x41;
+        let labels = Labels::new();
+        let displays: Vec<Expression> = ["A", "@($D012)"].iter().map(|text| Expression::parse(text, &labels).unwrap()).collect();
+
+        assert_eq!(lines(&displays, &cpu, &memory), ["A = $41 65", "@($D012) = $80 128"]);
+        assert_eq!(height(displays.len()), 4);
+        assert_eq!(height(0), 0);
+    }
+}
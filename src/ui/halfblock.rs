@@ -0,0 +1,77 @@
+// Half-block output: the VIC framebuffer drawn with '▀', the foreground color
+// giving the top half of a cell and the background the bottom half. The
+// whole framebuffer, border included, is scaled to the area, so bitmaps and
+// sprites keep their shape however big the terminal is.
+
+use std::ops::Range;
+
+use ratatui::{layout::Rect, Frame};
+
+use super::c64_color_to_ratatui;
+use crate::vic::render::{FRAME_HEIGHT, FRAME_WIDTH};
+use crate::vic::{C64Color, VicII};
+
+pub fn render(frame: &mut Frame, area: Rect, vic: &VicII) {
+    let buffer = frame.buffer_mut();
+    for row in 0..area.height {
+        for column in 0..area.width {
+            let columns = span(column as usize, area.width as usize, FRAME_WIDTH);
+            let upper = block_color(vic, columns.clone(), span(row as usize * 2, area.height as usize * 2, FRAME_HEIGHT));
+            let lower = block_color(vic, columns, span(row as usize * 2 + 1, area.height as usize * 2, FRAME_HEIGHT));
+            buffer
+                .get_mut(area.x + column, area.y + row)
+                .set_char('▀')
+                .set_fg(c64_color_to_ratatui(C64Color::from_u8(upper)))
+                .set_bg(c64_color_to_ratatui(C64Color::from_u8(lower)));
+        }
+    }
+}
+
+// Framebuffer pixel under a terminal cell of the area, for pointing the
+// light pen with the mouse. The cell's center stands in for the whole cell.
+pub fn pixel_position(area: Rect, column: u16, row: u16) -> Option<(usize, usize)> {
+    if !area.intersects(Rect::new(column, row, 1, 1)) {
+        return None;
+    }
+    let x = ((column - area.x) as usize * 2 + 1) * FRAME_WIDTH / (area.width as usize * 2);
+    let y = ((row - area.y) as usize * 2 + 1) * FRAME_HEIGHT / (area.height as usize * 2);
+    Some((x, y))
+}
+
+// The framebuffer pixels the `index`th of `parts` equal parts of `size`
+// covers, at least one
+fn span(index: usize, parts: usize, size: usize) -> Range<usize> {
+    let start = index * size / parts;
+    start..((index + 1) * size / parts).max(start + 1)
+}
+
+// Most common color of the pixels in the block
+fn block_color(vic: &VicII, columns: Range<usize>, rows: Range<usize>) -> u8 {
+    let mut counts = [0; 16];
+    for y in rows {
+        for x in columns.clone() {
+            counts[vic.pixel(x, y) as usize & 0x0F] += 1;
+        }
+    }
+    (0..16u8).max_by_key(|&c| counts[c as usize]).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scaled_to_area() {
+        // 48 columns show 8 pixels each, 34 rows 8 pixel rows each
+        assert_eq!(span(0, 48, FRAME_WIDTH), 0..8);
+        assert_eq!(span(47, 48, FRAME_WIDTH), 376..384);
+        assert_eq!(span(67, 68, FRAME_HEIGHT), 268..272);
+        // More cells than pixels still show one each
+        assert_eq!(span(1, 1000, FRAME_WIDTH), 0..1);
+
+        let area = Rect::new(10, 5, 48, 34);
+        assert_eq!(pixel_position(area, 10, 5), Some((4, 4)));
+        assert_eq!(pixel_position(area, 57, 38), Some((380, 268)));
+        assert_eq!(pixel_position(area, 58, 38), None);
+    }
+}
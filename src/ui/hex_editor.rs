@@ -0,0 +1,75 @@
+// The hex editor's panel: rows of bytes with the characters they are, the
+// cursor and the bytes that just changed picked out, and the byte under the
+// cursor (or the address being typed) at the bottom
+
+use std::time::Instant;
+
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+use crate::debugger::hex_editor::{HexEditor, ROW_WIDTH};
+use crate::debugger::symbols::Labels;
+use crate::memory::Memory;
+
+// Columns the panel takes up, borders included: "C000  " then the bytes,
+// then the characters
+pub const HEX_EDITOR_WIDTH: u16 = 6 + ROW_WIDTH * 3 + 1 + ROW_WIDTH + 2;
+
+pub fn render(frame: &mut Frame, area: Rect, editor: &mut HexEditor, memory: &dyn Memory, labels: &Labels) {
+    // As many rows as fit inside the borders, leaving the bottom line
+    editor.set_rows(area.height.saturating_sub(3));
+    editor.refresh(memory, Instant::now());
+
+    let normal = Style::default().fg(Color::Gray);
+    let changed = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+    let cursor = Style::default().add_modifier(Modifier::REVERSED);
+
+    let mut lines = Vec::new();
+    for row in 0..editor.rows {
+        let start = editor.top.wrapping_add(row * ROW_WIDTH);
+        let mut spans = vec![Span::styled(format!("{:04X}  ", start), normal)];
+        let mut text = String::new();
+        for i in 0..ROW_WIDTH {
+            let addr = start.wrapping_add(i);
+            let value = memory.peek(addr);
+            let (hex, style) = if addr == editor.cursor && editor.goto.is_none() {
+                // The digit typed so far stands in for the high one
+                let hex = match editor.pending {
+                    Some(high) => format!("{:X}_", high),
+                    None => format!("{:02X}", value),
+                };
+                (hex, cursor)
+            } else if editor.recently_changed(addr) {
+                (format!("{:02X}", value), changed)
+            } else {
+                (format!("{:02X}", value), normal)
+            };
+            spans.push(Span::styled(hex, style));
+            spans.push(Span::styled(" ", normal));
+            text.push(if value.is_ascii_graphic() || value == b' ' { value as char } else { '.' });
+        }
+        spans.push(Span::styled(format!(" {}", text), normal));
+        lines.push(Line::from(spans));
+    }
+
+    let bottom = match (&editor.goto, &editor.error) {
+        (Some(goto), _) => format!("Go to: {}_", goto),
+        (None, Some(error)) => error.clone(),
+        (None, None) => {
+            let value = memory.peek(editor.cursor);
+            let name = labels.describe(editor.cursor).unwrap_or_default();
+            format!("${:04X} = ${:02X} {:3}  {}", editor.cursor, value, value, name)
+        }
+    };
+    lines.push(Line::styled(bottom, normal));
+
+    let paragraph = Paragraph::new(lines)
+        .style(normal)
+        .block(Block::default().borders(Borders::ALL).title("Memory (g: Go to, Esc: Leave)"));
+    frame.render_widget(paragraph, area);
+}
@@ -0,0 +1,118 @@
+// VIC-II inspector: a debug panel with the chip's registers decoded into
+// what they currently select
+
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+use crate::debugger::interrupts::VIC_SOURCES;
+use crate::vic::sprites::SPRITE_COUNT;
+use crate::vic::VicII;
+
+// Columns the panel takes up, borders included
+pub const INSPECTOR_WIDTH: u16 = 30;
+// And rows: the registers, a line a sprite and the borders
+pub const INSPECTOR_HEIGHT: u16 = 12 + SPRITE_COUNT as u16 + 2;
+
+pub fn render(frame: &mut Frame, area: Rect, vic: &VicII) {
+    let paragraph = Paragraph::new(lines(vic).join("\n"))
+        .style(Style::default().fg(Color::Gray))
+        .block(Block::default().borders(Borders::ALL).title("VIC-II"));
+    frame.render_widget(paragraph, area);
+}
+
+fn lines(vic: &VicII) -> Vec<String> {
+    let d011 = vic.read_register(0xD011);
+    let d016 = vic.read_register(0xD016);
+    let d018 = vic.read_register(0xD018);
+    let charset = if vic.char_rom_selected() { " ROM" } else { "" };
+
+    let mut lines = vec![
+        format!("Raster  {:3}  IRQ at {:3}", vic.raster_line(), vic.raster_compare()),
+        format!("$D019 ${:02X} {}", vic.read_register(0xD019), irq_sources(vic.read_register(0xD019))),
+        format!("$D01A ${:02X} {}", vic.read_register(0xD01A), irq_sources(vic.read_register(0xD01A))),
+        format!(
+            "$D011 ${:02X} {}",
+            d011,
+            flags(d011, &[(0x40, "ECM"), (0x20, "BMM"), (0x10, "DEN"), (0x08, "RSEL")], d011 & 0x07, 'Y')
+        ),
+        format!("$D016 ${:02X} {}", d016, flags(d016, &[(0x10, "MCM"), (0x08, "CSEL")], d016 & 0x07, 'X')),
+        format!("Mode   {:?}", vic.graphics_mode()),
+        format!("$D018 ${:02X} Bank ${:04X}", d018, vic.bank()),
+        format!("Screen ${:04X} Chars ${:04X}{}", vic.screen_base(), vic.char_base(), charset),
+        format!("Bitmap ${:04X}", vic.bitmap_base()),
+        format!(
+            "Border {:2} Background {:2}",
+            vic.read_register(0xD020) & 0x0F,
+            vic.read_register(0xD021) & 0x0F
+        ),
+        String::new(),
+        "#  On    X   Y Col  Flags".to_string(),
+    ];
+
+    for n in 0..SPRITE_COUNT {
+        let bit = 1 << n;
+        let flag = |reg: u16, name: char| if vic.read_register(reg) & bit != 0 { name } else { '-' };
+        lines.push(format!(
+            "{}  {}  {:3} {:3}  {:2}  {}{}{}{}",
+            n,
+            if vic.sprite_enabled(n) { "on" } else { "--" },
+            vic.sprite_x(n),
+            vic.sprite_y(n),
+            vic.read_register(0xD027 + n as u16) & 0x0F,
+            // Multicolor, X and Y expanded, behind the foreground
+            flag(0xD01C, 'M'),
+            flag(0xD01D, 'X'),
+            flag(0xD017, 'Y'),
+            flag(0xD01B, 'B'),
+        ));
+    }
+    lines
+}
+
+fn irq_sources(value: u8) -> String {
+    let names: Vec<&str> = (0..4).filter(|bit| value & (1 << bit) != 0).map(|bit| VIC_SOURCES[bit]).collect();
+    if names.is_empty() { "-".to_string() } else { names.join(" ") }
+}
+
+// Names of the set bits, then the scroll value
+fn flags(value: u8, bits: &[(u8, &str)], scroll: u8, axis: char) -> String {
+    let mut names: Vec<String> =
+        bits.iter().filter(|(bit, _)| value & bit != 0).map(|(_, name)| name.to_string()).collect();
+    names.push(format!("{}{}", axis, scroll));
+    names.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inspector_decodes_registers() {
+        let mut vic = VicII::new();
+        vic.write_register(0xD011, 0x9B);
+        vic.write_register(0xD012, 0x37);
+        vic.write_register(0xD016, 0x18);
+        vic.write_register(0xD01A, 0x01);
+        vic.write_register(0xD015, 0x05);
+        vic.write_register(0xD010, 0x04);
+        vic.write_register(0xD004, 0x20);
+        vic.write_register(0xD005, 0x64);
+        vic.write_register(0xD029, 0x07);
+        vic.write_register(0xD01D, 0x04);
+
+        let lines = lines(&vic);
+        assert_eq!(lines[0], "Raster    0  IRQ at 311");
+        assert_eq!(lines[1], "$D019 $70 -");
+        assert_eq!(lines[2], "$D01A $F1 RST");
+        assert_eq!(lines[3], "$D011 $1B DEN RSEL Y3");
+        assert_eq!(lines[4], "$D016 $18 MCM CSEL X0");
+        assert_eq!(lines[5], "Mode   MulticolorText");
+        assert_eq!(lines[7], "Screen $0400 Chars $1000 ROM");
+        assert_eq!(lines[12 + 1], "1  --    0   0   0  ----");
+        assert_eq!(lines[12 + 2], "2  on  288 100   7  -X--");
+    }
+}
@@ -0,0 +1,90 @@
+// Kitty graphics protocol output: the VIC framebuffer sent as an RGB image
+// that the terminal scales onto a block of cells (kitty, WezTerm, Ghostty)
+
+use crate::vic::render::{FRAME_HEIGHT, FRAME_WIDTH};
+use crate::vic::C64Color;
+
+// Image and placement ids, so each frame replaces the one before
+const IMAGE_ID: u32 = 1;
+const PLACEMENT_ID: u32 = 1;
+
+// Payload bytes per escape sequence, as the protocol requires
+const CHUNK_SIZE: usize = 4096;
+
+// Encode a FRAME_WIDTH x FRAME_HEIGHT framebuffer of palette indices as an
+// image stretched over `columns` x `rows` cells at the cursor. q=2 keeps the
+// terminal from answering, C=1 from moving the cursor.
+pub fn encode(framebuffer: &[u8], columns: u16, rows: u16) -> String {
+    let mut rgb = Vec::with_capacity(FRAME_WIDTH * FRAME_HEIGHT * 3);
+    for &index in framebuffer {
+        let (r, g, b) = C64Color::from_u8(index & 0x0F).rgb();
+        rgb.extend_from_slice(&[r, g, b]);
+    }
+    let payload = base64(&rgb);
+
+    let mut out = String::with_capacity(payload.len() + payload.len() / CHUNK_SIZE * 16 + 128);
+    for start in (0..payload.len()).step_by(CHUNK_SIZE) {
+        let end = (start + CHUNK_SIZE).min(payload.len());
+        let more = if end < payload.len() { 1 } else { 0 };
+        if start == 0 {
+            out.push_str(&format!(
+                "\x1b_Ga=T,f=24,s={},v={},c={},r={},i={},p={},q=2,C=1,m={};",
+                FRAME_WIDTH, FRAME_HEIGHT, columns, rows, IMAGE_ID, PLACEMENT_ID, more
+            ));
+        } else {
+            out.push_str(&format!("\x1b_Gm={};", more));
+        }
+        // Base64 is plain ASCII, so any byte offset is a char boundary
+        out.push_str(&payload[start..end]);
+        out.push_str("\x1b\\");
+    }
+    out
+}
+
+// Remove the image from the screen
+pub fn delete() -> String {
+    format!("\x1b_Ga=d,d=I,i={},q=2\x1b\\", IMAGE_ID)
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bytes = [group[0], *group.get(1).unwrap_or(&0), *group.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b"C64"), "QzY0");
+        assert_eq!(base64(b"go64"), "Z282NA==");
+        assert_eq!(base64(b"vic"), "dmlj");
+        assert_eq!(base64(b"ii"), "aWk=");
+    }
+
+    #[test]
+    fn test_kitty_image_chunks() {
+        let framebuffer = vec![2u8; FRAME_WIDTH * FRAME_HEIGHT];
+        let image = encode(&framebuffer, 48, 17);
+        assert!(image.starts_with("\x1b_Ga=T,f=24,s=384,v=272,c=48,r=17,i=1,p=1,q=2,C=1,m=1;iAAA"));
+        assert_eq!(image.matches("m=0;").count(), 1);
+        assert!(image.ends_with("iAAA\x1b\\"));
+
+        // 384 * 272 RGB pixels as base64, split into 4096 byte chunks
+        let payload = (FRAME_WIDTH * FRAME_HEIGHT * 3).div_ceil(3) * 4;
+        assert_eq!(image.matches("\x1b_G").count(), payload.div_ceil(CHUNK_SIZE));
+    }
+}
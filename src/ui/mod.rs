@@ -1,36 +1,94 @@
 // Terminal UI using ratatui
 
+pub mod displays;
+pub mod halfblock;
+pub mod hex_editor;
+pub mod inspector;
+pub mod kitty;
+pub mod monitor;
+pub mod player;
+pub mod sixel;
+pub mod stack;
+
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Line, Span},
-    widgets::{Block, Borders, Paragraph},
+    widgets::{Block, Borders, Clear, Paragraph},
     Frame, Terminal,
 };
 use crossterm::{
-    event::{self, Event, KeyEvent},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    cursor::MoveTo,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event},
+    execute, queue,
+    terminal::{self, disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use std::io;
+use std::io::{self, Write};
 use anyhow::Result;
 
 use crate::vic::{C64Color, SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::vic::render::{GraphicsMode, FRAME_HEIGHT, FRAME_WIDTH};
+use crate::vic::sprites::SpriteLayer;
+
+// How the C64 screen is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum VideoOutput {
+    // Character cells: PETSCII glyphs and quadrant blocks
+    Text,
+    // The framebuffer in Unicode half blocks, two pixel rows per cell
+    #[value(name = "halfblock")]
+    HalfBlock,
+    // The framebuffer as a sixel image
+    Sixel,
+    // The framebuffer as an image through the kitty graphics protocol
+    Kitty,
+}
+
+impl VideoOutput {
+    // Best output the terminal is known to support, going by the variables
+    // it sets in the environment
+    pub fn detect() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        let program = var("TERM_PROGRAM");
+        if std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term == "xterm-kitty"
+            || term == "xterm-ghostty"
+            || matches!(program.as_str(), "WezTerm" | "ghostty")
+        {
+            VideoOutput::Kitty
+        } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+            VideoOutput::Sixel
+        } else {
+            VideoOutput::Text
+        }
+    }
+}
+
+// Where the last image was put: its cell area, the terminal's cell size and
+// the image's size on screen in pixels, plus the framebuffer it showed
+struct ImagePlacement {
+    video: VideoOutput,
+    area: Rect,
+    cell_size: (u16, u16),
+    size: (usize, usize),
+    framebuffer: Vec<u8>,
+}
 
 pub struct TerminalUI {
     terminal: Terminal<CrosstermBackend<io::Stdout>>,
+    image: Option<ImagePlacement>,
 }
 
 impl TerminalUI {
     pub fn new() -> Result<Self> {
         enable_raw_mode()?;
         let mut stdout = io::stdout();
-        execute!(stdout, EnterAlternateScreen)?;
+        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
         let backend = CrosstermBackend::new(stdout);
         let terminal = Terminal::new(backend)?;
         
-        Ok(Self { terminal })
+        Ok(Self { terminal, image: None })
     }
     
     pub fn render<F>(&mut self, render_fn: F) -> Result<()>
@@ -41,13 +99,84 @@ impl TerminalUI {
         Ok(())
     }
     
-    pub fn poll_event(&self) -> Result<Option<KeyEvent>> {
+    // Draw the framebuffer as an image centered in `area`, as large as fits.
+    // Call after render(), which leaves the area blank. An unchanged frame
+    // isn't sent again.
+    pub fn draw_image(&mut self, area: Rect, video: VideoOutput, framebuffer: &[u8]) -> Result<()> {
+        if matches!(video, VideoOutput::Text | VideoOutput::HalfBlock) {
+            return Ok(());
+        }
+        // Terminals that don't report their size in pixels get 8x16 cells
+        let cell_size = match terminal::window_size() {
+            Ok(size) if size.width > 0 && size.columns > 0 && size.rows > 0 => {
+                (size.width / size.columns, size.height / size.rows)
+            }
+            _ => (8, 16),
+        };
+        let (cell_width, cell_height) = (cell_size.0 as usize, cell_size.1 as usize);
+        let fit_x = (area.width as usize * cell_width) as f64 / FRAME_WIDTH as f64;
+        let fit_y = (area.height as usize * cell_height) as f64 / FRAME_HEIGHT as f64;
+        // Sixel images are blown up by whole pixels; kitty scales freely
+        let scale = match video {
+            VideoOutput::Sixel => fit_x.min(fit_y).floor().max(1.0),
+            _ => fit_x.min(fit_y),
+        };
+
+        let columns = ((FRAME_WIDTH as f64 * scale) as usize).div_ceil(cell_width).min(area.width as usize) as u16;
+        let rows = ((FRAME_HEIGHT as f64 * scale) as usize).div_ceil(cell_height).min(area.height as usize) as u16;
+        let image_area = Rect::new(
+            area.x + (area.width - columns) / 2,
+            area.y + (area.height - rows) / 2,
+            columns,
+            rows,
+        );
+        if let Some(image) = &self.image
+            && image.area == image_area
+            && image.framebuffer == framebuffer
+        {
+            return Ok(());
+        }
+
+        let (data, size) = match video {
+            // Sixel images are cut off at the cells they are given, when
+            // even whole size doesn't fit
+            VideoOutput::Sixel => {
+                let scale = scale as usize;
+                let (width, height) = (columns as usize * cell_width, rows as usize * cell_height);
+                (sixel::encode(framebuffer, scale, width, height), (FRAME_WIDTH * scale, FRAME_HEIGHT * scale))
+            }
+            // Kitty stretches the image over exactly the cells it is given
+            VideoOutput::Kitty => (
+                kitty::encode(framebuffer, columns, rows),
+                (columns as usize * cell_width, rows as usize * cell_height),
+            ),
+            VideoOutput::Text | VideoOutput::HalfBlock => return Ok(()),
+        };
+        let backend = self.terminal.backend_mut();
+        queue!(backend, MoveTo(image_area.x, image_area.y))?;
+        backend.write_all(data.as_bytes())?;
+        backend.flush()?;
+        self.image = Some(ImagePlacement { video, area: image_area, cell_size, size, framebuffer: framebuffer.to_vec() });
+        Ok(())
+    }
+
+    // Framebuffer pixel under a terminal cell of the last image drawn
+    pub fn image_pixel(&self, column: u16, row: u16) -> Option<(usize, usize)> {
+        let image = self.image.as_ref()?;
+        if !image.area.intersects(Rect::new(column, row, 1, 1)) {
+            return None;
+        }
+        let (width, height) = (image.cell_size.0 as usize, image.cell_size.1 as usize);
+        let x = ((column - image.area.x) as usize * width + width / 2) * FRAME_WIDTH / image.size.0;
+        let y = ((row - image.area.y) as usize * height + height / 2) * FRAME_HEIGHT / image.size.1;
+        (x < FRAME_WIDTH && y < FRAME_HEIGHT).then_some((x, y))
+    }
+    
+    pub fn poll_event(&self) -> Result<Option<Event>> {
         // Use a zero timeout to make this non-blocking
         // The main loop manages frame timing via thread::sleep
         if event::poll(std::time::Duration::from_micros(0))? {
-            if let Event::Key(key) = event::read()? {
-                return Ok(Some(key));
-            }
+            return Ok(Some(event::read()?));
         }
         Ok(None)
     }
@@ -55,12 +184,19 @@ impl TerminalUI {
 
 impl Drop for TerminalUI {
     fn drop(&mut self) {
+        if let Some(image) = &self.image
+            && image.video == VideoOutput::Kitty
+        {
+            let _ = self.terminal.backend_mut().write_all(kitty::delete().as_bytes());
+        }
         let _ = disable_raw_mode();
-        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = execute!(self.terminal.backend_mut(), DisableMouseCapture, LeaveAlternateScreen);
     }
 }
 
-pub fn create_layout(area: Rect) -> (Rect, Rect, Rect) {
+// Title bar, C64 screen, VIC-II inspector beside it with the stack and
+// then `displays` expressions below, status bar
+pub fn create_layout(area: Rect, displays: usize) -> (Rect, Rect, Rect, Rect, Rect, Rect) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -70,6 +206,22 @@ pub fn create_layout(area: Rect) -> (Rect, Rect, Rect) {
         ])
         .split(area);
     
+    let middle = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Min(1),
+            Constraint::Length(inspector::INSPECTOR_WIDTH),
+        ])
+        .split(chunks[1]);
+    let panels = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(inspector::INSPECTOR_HEIGHT),
+            Constraint::Min(3),
+            Constraint::Length(displays::height(displays)),
+        ])
+        .split(middle[1]);
+    
     // Center the C64 screen vertically within the middle chunk
     // Use a larger frame to accommodate the C64 border (approx 3 lines top/bottom)
     let display_height = SCREEN_HEIGHT as u16 + 6; // 25 + 6 = 31
@@ -80,7 +232,7 @@ pub fn create_layout(area: Rect) -> (Rect, Rect, Rect) {
             Constraint::Length(display_height),
             Constraint::Min(0),
         ])
-        .split(chunks[1])[1];
+        .split(middle[0])[1];
 
     // Center the C64 screen horizontally
     // Use a larger frame to accommodate the C64 border (approx 5 chars left/right)
@@ -94,7 +246,21 @@ pub fn create_layout(area: Rect) -> (Rect, Rect, Rect) {
         ])
         .split(vertical_center)[1];
     
-    (chunks[0], screen_area, chunks[2])
+    (chunks[0], screen_area, panels[0], panels[1], panels[2], chunks[2])
+}
+
+// The simple layout beside a panel `width` columns wide, the monitor's or
+// the hex editor's: screen, panel, status
+pub fn create_panel_layout(area: Rect, width: u16) -> (Rect, Rect, Rect) {
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Min(1),
+            Constraint::Length(width),
+        ])
+        .split(area);
+    let (screen_area, status_area) = create_simple_layout(columns[0]);
+    (screen_area, columns[1], status_area)
 }
 
 pub fn create_simple_layout(area: Rect) -> (Rect, Rect) {
@@ -131,11 +297,77 @@ pub fn create_simple_layout(area: Rect) -> (Rect, Rect) {
     (screen_area, chunks[1])
 }
 
-pub fn render_simple_status(frame: &mut Frame, area: Rect) {
-    let status = "F9: Debug | F10: Pause/Resume | PgUp: Restore | ESC: Quit";
-    let paragraph = Paragraph::new(status)
-        .style(Style::default().fg(Color::DarkGray))
-        .alignment(Alignment::Center);
+// What the host is doing to the sound, if anything: "MUTED", or which
+// voices are switched off, e.g. "VOICES 1,3 OFF"
+pub fn sound_status(muted: bool, sid: &crate::sid::Sid) -> Option<String> {
+    if muted {
+        return Some("MUTED".to_string());
+    }
+    let off: Vec<String> = (0..3).filter(|&n| sid.voice_muted(n)).map(|n| (n + 1).to_string()).collect();
+    match off.len() {
+        0 => None,
+        1 => Some(format!("VOICE {} OFF", off[0])),
+        _ => Some(format!("VOICES {} OFF", off.join(","))),
+    }
+}
+
+// Which key sets work the joysticks, e.g. "JOY 1:NUMPAD 2:CURSOR"
+pub fn joystick_status(ports: [Option<crate::keyboard::joystick::JoystickKeys>; 2]) -> Option<String> {
+    use crate::keyboard::joystick::JoystickKeys;
+    let mapped: Vec<String> = (0..2)
+        .filter_map(|n| {
+            let keys = match ports[n]? {
+                JoystickKeys::Cursor => "CURSOR",
+                JoystickKeys::Numpad => "NUMPAD",
+            };
+            Some(format!("{}:{}", n + 1, keys))
+        })
+        .collect();
+    (!mapped.is_empty()).then(|| format!("JOY {}", mapped.join(" ")))
+}
+
+// Ports with a gamepad in them, e.g. "PADS 2,1"
+pub fn gamepad_status(ports: &[u8]) -> Option<String> {
+    let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+    match ports.len() {
+        0 => None,
+        1 => Some(format!("PAD {}", ports[0])),
+        _ => Some(format!("PADS {}", ports.join(","))),
+    }
+}
+
+// Drives that are write protected (WP), or whose last command or file
+// failed, as a 1541 blinks its light until the error is read, e.g.
+// "DRIVE 8 WP: 26, WRITE PROTECT ON,00,00"
+pub fn drive_status(memory: &mut dyn crate::memory::Memory) -> Option<String> {
+    let drives: Vec<String> = (8..=15)
+        .filter_map(|device| {
+            let drive = memory.channels(device)?;
+            let protected = if drive.write_protected() { " WP" } else { "" };
+            match drive.status() {
+                Some(status) if status.is_error() => Some(format!("{}{}: {}", device, protected, status)),
+                _ => (!protected.is_empty()).then(|| format!("{}{}", device, protected)),
+            }
+        })
+        .collect();
+    (!drives.is_empty()).then(|| format!("DRIVE {}", drives.join(" ")))
+}
+
+// Notes on the host side state (sound, joysticks, gamepads, drives) at the
+// front of the status bars
+pub fn render_simple_status(frame: &mut Frame, area: Rect, cpu: &crate::cpu::Cpu, warp: bool, notes: &[String]) {
+    let notes: String = notes.iter().map(|note| format!("{} | ", note)).collect();
+    let paragraph = if cpu.is_jammed() {
+        Paragraph::new(format!("CPU JAMMED at ${:04X} | ESC: Quit", cpu.pc))
+            .style(Style::default().fg(Color::Red))
+    } else if warp {
+        Paragraph::new(format!("WARP | {}F11: Normal Speed | F10: Pause/Resume | ESC: Quit", notes))
+            .style(Style::default().fg(Color::Yellow))
+    } else {
+        Paragraph::new(format!("{}F8: Mute | F9: Debug | F10: Pause/Resume | PgUp: Restore | ESC: Quit", notes))
+            .style(Style::default().fg(Color::DarkGray))
+    };
+    let paragraph = paragraph.alignment(Alignment::Center);
     frame.render_widget(paragraph, area);
 }
 
@@ -147,59 +379,271 @@ pub fn render_title_bar(frame: &mut Frame, area: Rect) {
     frame.render_widget(title, area);
 }
 
+pub fn render_screen(
+    frame: &mut Frame,
+    area: Rect,
+    video: VideoOutput,
+    vic: &crate::vic::VicII,
+    memory: &dyn crate::memory::Memory,
+    cache: &mut ScreenCache,
+) {
+    match video {
+        VideoOutput::Text => render_c64_screen(frame, area, vic, memory, cache),
+        VideoOutput::HalfBlock => halfblock::render(frame, area, vic),
+        // Left blank for the image drawn once the frame is out
+        VideoOutput::Sixel | VideoOutput::Kitty => frame.render_widget(Clear, area),
+    }
+}
+
+// The text mode screen as last worked out, cell by cell (glyph, foreground
+// and background palette index). Only rows showing framebuffer lines that
+// changed since are worked out again.
+pub struct ScreenCache {
+    area: Rect,
+    cells: Vec<(char, u8, u8)>,
+    dirty_lines: Vec<bool>,
+}
+
+impl ScreenCache {
+    pub fn new() -> Self {
+        Self { area: Rect::default(), cells: Vec::new(), dirty_lines: vec![true; FRAME_HEIGHT] }
+    }
+
+    // Add the lines from VicII::take_dirty_lines
+    pub fn mark_dirty(&mut self, lines: &[bool]) {
+        for (dirty, &changed) in self.dirty_lines.iter_mut().zip(lines) {
+            *dirty |= changed;
+        }
+    }
+
+    fn row_dirty(&self, row: u16) -> bool {
+        let (_, top) = framebuffer_origin(self.area, self.area.x, row);
+        (top.max(0)..(top + 8).max(0)).any(|line| self.dirty_lines.get(line as usize) == Some(&true))
+    }
+}
+
 pub fn render_c64_screen(
     frame: &mut Frame,
     area: Rect,
     vic: &crate::vic::VicII,
     memory: &dyn crate::memory::Memory,
+    cache: &mut ScreenCache,
 ) {
-    // 1. Render the Border
-    // The 'area' passed in is now the full frame (50x31) including the border.
-    // We fill this entire block with the Border Color.
-    let border_color = c64_color_to_ratatui(vic.get_border_color());
-    let border_block = Block::default()
-        .style(Style::default().bg(border_color));
-    frame.render_widget(border_block, area);
-
-    // 2. Calculate the Inner Screen Area (40x25) centered in the Border Area
-    // The Layout was calculated to be SCREEN_WIDTH + 10 wide, SCREEN_HEIGHT + 6 high.
-    // So we want to inset by 5 horizontally and 3 vertically.
-    
-    // Safety check to ensure we don't panic if area is too small
+    // A new layout starts over
+    if cache.area != area {
+        cache.area = area;
+        cache.cells = vec![(' ', 0, 0); area.width as usize * area.height as usize];
+        cache.dirty_lines.fill(true);
+    }
+
+    // The 40x25 display window sits in the middle of the area, the rest
+    // is border. The Layout makes the area SCREEN_WIDTH + 10 wide and
+    // SCREEN_HEIGHT + 6 high, but it may come out smaller.
     let inner_x = area.x + (area.width.saturating_sub(SCREEN_WIDTH as u16)) / 2;
     let inner_y = area.y + (area.height.saturating_sub(SCREEN_HEIGHT as u16)) / 2;
     let inner_width = (SCREEN_WIDTH as u16).min(area.width);
     let inner_height = (SCREEN_HEIGHT as u16).min(area.height);
-    
     let screen_rect = Rect::new(inner_x, inner_y, inner_width, inner_height);
 
-    // 3. Render the Background (Main Screen)
-    let bg_color = c64_color_to_ratatui(vic.get_background_color());
-    let screen_block = Block::default()
-        .style(Style::default().bg(bg_color));
-    frame.render_widget(screen_block, screen_rect);
-    
-    // 4. Render screen content line by line
-    let mut lines = Vec::new();
-    for y in 0..SCREEN_HEIGHT.min(screen_rect.height as usize) {
-        let mut line_spans = Vec::new();
-        for x in 0..SCREEN_WIDTH.min(screen_rect.width as usize) {
-            let (char_code, color) = vic.get_screen_char(memory, x, y);
-            let ch = crate::vic::screen_code_to_char(char_code);
-            let fg = c64_color_to_ratatui(C64Color::from_u8(color));
-            line_spans.push(Span::styled(ch.to_string(), Style::default().fg(fg).bg(bg_color)));
+    // Work out the changed rows, with sprites drawn over the text
+    let mut sprites = None;
+    for row in area.top()..area.bottom() {
+        if !cache.row_dirty(row) {
+            continue;
+        }
+        let sprites = sprites.get_or_insert_with(|| vic.render_sprites(memory));
+        for column in area.left()..area.right() {
+            let cell = if screen_rect.intersects(Rect::new(column, row, 1, 1)) {
+                text_cell(vic, memory, sprites, (column - inner_x) as usize, (row - inner_y) as usize)
+            } else {
+                // The border comes from the framebuffer, so sprites in an
+                // opened border and color changes within it show up
+                match framebuffer_origin(area, column, row) {
+                    (left, top) if left >= 0 && top >= 0 => framebuffer_cell(vic, left as usize, top as usize),
+                    _ => (' ', 0, vic.get_border_color() as u8),
+                }
+            };
+            cache.cells[(row - area.y) as usize * area.width as usize + (column - area.x) as usize] = cell;
         }
-        lines.push(Line::from(line_spans));
     }
-    
-    let paragraph = Paragraph::new(lines);
-    frame.render_widget(paragraph, screen_rect);
+    cache.dirty_lines.fill(false);
+
+    let buffer = frame.buffer_mut();
+    for row in area.top()..area.bottom() {
+        for column in area.left()..area.right() {
+            let (ch, fg, bg) = cache.cells[(row - area.y) as usize * area.width as usize + (column - area.x) as usize];
+            buffer
+                .get_mut(column, row)
+                .set_char(ch)
+                .set_fg(c64_color_to_ratatui(C64Color::from_u8(fg)))
+                .set_bg(c64_color_to_ratatui(C64Color::from_u8(bg)));
+        }
+    }
+}
+
+// Glyph, foreground and background of display cell (x, y)
+fn text_cell(
+    vic: &crate::vic::VicII,
+    memory: &dyn crate::memory::Memory,
+    sprites: &SpriteLayer,
+    x: usize,
+    y: usize,
+) -> (char, u8, u8) {
+    let border_color = vic.get_border_color() as u8;
+    let bg_color = vic.get_background_color() as u8;
+
+    // 38 column / 24 row modes pull the border over the outer cells,
+    // hiding text and sprites alike
+    if vic.border_covers_cell(x, y) {
+        return (' ', 0, border_color);
+    }
+    // Fine scrolling moves the text under the fixed display window;
+    // cells scrolled in from outside the 40x25 matrix are blank
+    let (scroll_x, scroll_y) = vic.scroll_cells();
+    let source_x = x as i32 - scroll_x;
+    let source_y = y as i32 - scroll_y;
+    let (char_code, color) = if source_x >= 0 && source_y >= 0 {
+        vic.get_screen_char(source_x as usize, source_y as usize)
+    } else {
+        (0x20, 0)
+    };
+    let blank = matches!(char_code, 0x20 | 0x60);
+    if let Some((glyph, sprite_color)) = sprite_cell(sprites, x, y, blank) {
+        return (glyph, sprite_color, bg_color);
+    }
+    // Plain text cells are drawn as PETSCII glyphs. Everything else
+    // (multicolor cells, bitmaps, extended color text) is sampled
+    // from the VIC's framebuffer.
+    let fg = match vic.graphics_mode() {
+        GraphicsMode::Text => color,
+        // Without bit 3 of color RAM a multicolor mode cell is hires
+        // but limited to colors 0-7
+        GraphicsMode::MulticolorText if color & 0x08 == 0 => color & 0x07,
+        _ => {
+            let (left, top) = crate::vic::VicII::cell_origin(x, y);
+            return framebuffer_cell(vic, left, top);
+        }
+    };
+    // Screen codes 128-255 of the ROM are the first 128 in reverse:
+    // the base glyph with foreground and background swapped. A
+    // charset in RAM may redefine any character, so the glyph is
+    // rasterized from its 8x8 bitmap rather than looked up.
+    if vic.char_rom_selected() {
+        let ch = crate::vic::screen_code_to_char(char_code & 0x7F, vic.lowercase_selected());
+        if char_code & 0x80 != 0 { (ch, bg_color, fg) } else { (ch, fg, bg_color) }
+    } else {
+        let glyph = vic.char_base() + char_code as u16 * 8;
+        (braille_glyph(std::array::from_fn(|row| memory.read_vic(glyph + row as u16))), fg, bg_color)
+    }
+}
+
+// Framebuffer pixel under a terminal cell of the screen area drawn by
+// render_c64_screen, for pointing the light pen with the mouse. The cell's
+// center stands in for the whole cell.
+pub fn light_pen_position(area: Rect, column: u16, row: u16) -> Option<(usize, usize)> {
+    if !area.intersects(Rect::new(column, row, 1, 1)) {
+        return None;
+    }
+    let (x, y) = framebuffer_origin(area, column, row);
+    (x + 4 >= 0 && y + 4 >= 0).then_some(((x + 4) as usize, (y + 4) as usize))
+}
+
+// Framebuffer position of the top-left pixel shown by a terminal cell, with
+// the 40x25 display window centered in `area` and 8x8 pixels per cell. It is
+// negative for cells left of or above the framebuffer.
+fn framebuffer_origin(area: Rect, column: u16, row: u16) -> (i32, i32) {
+    let inner_x = area.x + (area.width.saturating_sub(SCREEN_WIDTH as u16)) / 2;
+    let inner_y = area.y + (area.height.saturating_sub(SCREEN_HEIGHT as u16)) / 2;
+    let (origin_x, origin_y) = crate::vic::VicII::cell_origin(0, 0);
+    (
+        origin_x as i32 + (column as i32 - inner_x as i32) * 8,
+        origin_y as i32 + (row as i32 - inner_y as i32) * 8,
+    )
+}
+
+// Reduce an 8x8 character bitmap to a braille pattern: each of the 2x4 dots
+// covers 4x2 pixels and is raised when at least 2 of them are set
+fn braille_glyph(rows: [u8; 8]) -> char {
+    // Unicode braille dot bits, by dot row and column
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    let mut pattern = 0;
+    for (dot_row, dots) in DOTS.iter().enumerate() {
+        for (dot_column, &bit) in dots.iter().enumerate() {
+            let nibble = |row: u8| (row >> (4 - dot_column * 4)) & 0x0F;
+            let set = nibble(rows[dot_row * 2]).count_ones() + nibble(rows[dot_row * 2 + 1]).count_ones();
+            if set >= 2 {
+                pattern |= bit;
+            }
+        }
+    }
+    char::from_u32(0x2800 + pattern).unwrap_or(' ')
+}
+
+// Quadrant block glyphs indexed by a mask of the lit quarters of a cell:
+// bit 0 top-left, bit 1 top-right, bit 2 bottom-left, bit 3 bottom-right
+const QUADRANT_GLYPHS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+// Reduce the 8x8 sprite pixels over a character cell to a quadrant block in
+// the color of the topmost sprite there. Sprites behind the foreground only
+// show on blank cells, since a text cell can't mix glyph and sprite pixels.
+fn sprite_cell(sprites: &SpriteLayer, col: usize, row: usize, blank: bool) -> Option<(char, u8)> {
+    let mut mask = 0;
+    let mut color = None;
+    for py in 0..8 {
+        for px in 0..8 {
+            if let Some(pixel) = sprites.pixel(col * 8 + px, row * 8 + py)
+                && (blank || !pixel.behind_foreground)
+            {
+                mask |= 1 << ((py / 4) * 2 + px / 4);
+                color.get_or_insert(pixel.color);
+            }
+        }
+    }
+    color.map(|color| (QUADRANT_GLYPHS[mask], color))
+}
+
+// Reduce the 8x8 framebuffer pixels at (left, top) to a quadrant block: the
+// most common color becomes the background, quadrants where at least half
+// the pixels differ from it are lit in the next most common color
+fn framebuffer_cell(vic: &crate::vic::VicII, left: usize, top: usize) -> (char, u8, u8) {
+    let mut color_counts = [0; 16];
+    for py in 0..8 {
+        for px in 0..8 {
+            color_counts[vic.pixel(left + px, top + py) as usize] += 1;
+        }
+    }
+    let bg = (0..16u8).max_by_key(|&c| color_counts[c as usize]).unwrap_or(0);
+    let fg = (0..16u8)
+        .filter(|&c| c != bg)
+        .max_by_key(|&c| color_counts[c as usize])
+        .unwrap_or(bg);
+
+    let mut mask = 0;
+    for quadrant in 0..4 {
+        let (qx, qy) = (left + (quadrant % 2) * 4, top + (quadrant / 2) * 4);
+        let lit = (0..16).filter(|i| vic.pixel(qx + i % 4, qy + i / 4) != bg).count();
+        if lit >= 8 {
+            mask |= 1 << quadrant;
+        }
+    }
+    (QUADRANT_GLYPHS[mask], fg, bg)
 }
 
-pub fn render_status_bar(frame: &mut Frame, area: Rect, cpu: &crate::cpu::Cpu) {
+pub fn render_status_bar(
+    frame: &mut Frame,
+    area: Rect,
+    cpu: &crate::cpu::Cpu,
+    memory: &dyn crate::memory::Memory,
+    notes: &[String],
+) {
+    let (instruction, _) = crate::debugger::disassemble(memory, cpu.pc);
     let status = format!(
-        "PC:${:04X} A:${:02X} X:${:02X} Y:${:02X} SP:${:02X} Cyc:{} | F9:Hide | F10:Pause | PgUp:Rst | ESC:Quit",
-        cpu.pc, cpu.a, cpu.x, cpu.y, cpu.sp, cpu.cycles
+        "PC:${:04X} {:<13} A:${:02X} X:${:02X} Y:${:02X} SP:${:02X} Cyc:{}{}{} | F8:Mute | F9:Hide | F10:Pause | PgUp:Rst | ESC:Quit",
+        cpu.pc, instruction, cpu.a, cpu.x, cpu.y, cpu.sp, cpu.cycles,
+        if cpu.is_jammed() { " JAM" } else { "" },
+        notes.iter().map(|note| format!(" {}", note)).collect::<String>()
     );
     
     let paragraph = Paragraph::new(status)
@@ -210,23 +654,67 @@ pub fn render_status_bar(frame: &mut Frame, area: Rect, cpu: &crate::cpu::Cpu) {
 }
 
 fn c64_color_to_ratatui(color: C64Color) -> Color {
-    // Authentic Commodore 64 color palette RGB values
-    match color {
-        C64Color::Black => Color::Rgb(0, 0, 0),
-        C64Color::White => Color::Rgb(255, 255, 255),
-        C64Color::Red => Color::Rgb(136, 0, 0),
-        C64Color::Cyan => Color::Rgb(170, 255, 238),
-        C64Color::Purple => Color::Rgb(204, 68, 204),
-        C64Color::Green => Color::Rgb(0, 204, 85),
-        C64Color::Blue => Color::Rgb(53, 40, 121),           // Authentic Pepto Blue (Dark Purple-Blue)
-        C64Color::Yellow => Color::Rgb(238, 238, 119),
-        C64Color::Orange => Color::Rgb(221, 136, 85),
-        C64Color::Brown => Color::Rgb(102, 68, 0),
-        C64Color::LightRed => Color::Rgb(255, 119, 119),
-        C64Color::DarkGrey => Color::Rgb(51, 51, 51),
-        C64Color::Grey => Color::Rgb(119, 119, 119),
-        C64Color::LightGreen => Color::Rgb(170, 255, 102),
-        C64Color::LightBlue => Color::Rgb(108, 108, 255),   // Authentic Pepto Light Blue (Periwinkle)
-        C64Color::LightGrey => Color::Rgb(187, 187, 187),
+    let (r, g, b) = color.rgb();
+    Color::Rgb(r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_braille_glyph() {
+        assert_eq!(braille_glyph([0; 8]), '\u{2800}');
+        assert_eq!(braille_glyph([0xFF; 8]), '\u{28FF}');
+        // Left half only: the left dot column
+        assert_eq!(braille_glyph([0xF0; 8]), '\u{2847}');
+        // "T" of the ROM charset: the bar raises both top dots, the 2 pixel
+        // stem sits in the middle and is split across both columns
+        let t = [0x7E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x00];
+        assert_eq!(braille_glyph(t), '\u{283F}');
+        // A single pixel is too little for a dot
+        assert_eq!(braille_glyph([0x80, 0, 0, 0, 0, 0, 0, 0]), '\u{2800}');
+    }
+
+    #[test]
+    fn test_sound_status() {
+        let mut sid = crate::sid::Sid::new();
+        assert_eq!(sound_status(false, &sid), None);
+        sid.toggle_voice_mute(1);
+        assert_eq!(sound_status(false, &sid).as_deref(), Some("VOICE 2 OFF"));
+        sid.toggle_voice_mute(0);
+        assert_eq!(sound_status(false, &sid).as_deref(), Some("VOICES 1,2 OFF"));
+        assert_eq!(sound_status(true, &sid).as_deref(), Some("MUTED"));
+    }
+
+    #[test]
+    fn test_joystick_status() {
+        use crate::keyboard::joystick::JoystickKeys;
+        assert_eq!(joystick_status([None, None]), None);
+        assert_eq!(joystick_status([None, Some(JoystickKeys::Cursor)]).as_deref(), Some("JOY 2:CURSOR"));
+        assert_eq!(
+            joystick_status([Some(JoystickKeys::Numpad), Some(JoystickKeys::Cursor)]).as_deref(),
+            Some("JOY 1:NUMPAD 2:CURSOR")
+        );
+    }
+
+    #[test]
+    fn test_drive_status() {
+        use crate::iec::device::SerialDevice;
+        use crate::storage::d64::D64;
+        use crate::storage::drive::{Drive, Medium};
+        use crate::memory::Memory;
+        let mut memory = crate::memory::C64Memory::new();
+        assert_eq!(drive_status(&mut memory), None);
+        let drive = Drive::new(Medium::Image(D64::format(b"TEST", b"01")));
+        memory.iec.attach(Box::new(SerialDevice::new(9, drive)));
+        // The power on message is no error
+        assert_eq!(drive_status(&mut memory), None);
+        let _ = crate::storage::drive::load_file(memory.channels(9).unwrap(), b"MISSING");
+        assert_eq!(drive_status(&mut memory).as_deref(), Some("DRIVE 9: 62, FILE NOT FOUND,00,00"));
+        memory.channels(9).unwrap().set_write_protected(true);
+        assert_eq!(drive_status(&mut memory).as_deref(), Some("DRIVE 9 WP: 62, FILE NOT FOUND,00,00"));
+        let _ = crate::storage::drive::load_file(memory.channels(9).unwrap(), b"$");
+        assert_eq!(drive_status(&mut memory).as_deref(), Some("DRIVE 9 WP"));
     }
 }
@@ -0,0 +1,26 @@
+// The machine language monitor's panel: its output, with the command line
+// being typed at the bottom
+
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+use crate::debugger::monitor::Monitor;
+
+// Columns the panel takes up, borders included
+pub const MONITOR_WIDTH: u16 = 46;
+
+pub fn render(frame: &mut Frame, area: Rect, monitor: &Monitor, pc: u16) {
+    // As many of the last lines as fit inside the borders, then the prompt
+    let rows = area.height.saturating_sub(3) as usize;
+    let shown = &monitor.output[monitor.output.len().saturating_sub(rows)..];
+    let mut lines = shown.to_vec();
+    lines.push(format!("{}{}_", monitor.prompt(pc), monitor.input));
+    let paragraph = Paragraph::new(lines.join("\n"))
+        .style(Style::default().fg(Color::Gray))
+        .block(Block::default().borders(Borders::ALL).title("Monitor (x: Leave)"));
+    frame.render_widget(paragraph, area);
+}
@@ -0,0 +1,84 @@
+// Screen for the SID player (--play): what is playing and the keys to pick
+// another song
+
+use ratatui::{
+    layout::{Alignment, Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+use crate::machine::{Machine, VideoStandard};
+use crate::player::Player;
+use crate::sid::audio::AudioOutput;
+use crate::sid::SidModel;
+
+pub fn render(frame: &mut Frame, player: &Player, machine: &Machine, model: SidModel, audio: Option<&AudioOutput>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3), // Title bar
+            Constraint::Min(1),    // Tune
+            Constraint::Length(1), // Keys
+        ])
+        .split(frame.size());
+
+    let title = Paragraph::new("go64 - SID Player")
+        .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
+        .alignment(Alignment::Center)
+        .block(Block::default().borders(Borders::ALL));
+    frame.render_widget(title, chunks[0]);
+
+    let file = &player.file;
+    let elapsed = player.elapsed(machine).as_secs();
+    let timing = match (file.cia_timed(player.song), machine.standard) {
+        (true, _) => "CIA timer",
+        (false, VideoStandard::Pal) => "50 Hz",
+        (false, VideoStandard::Ntsc) => "60 Hz",
+    };
+    let mut lines = vec![
+        format!("{:<9} {}", "Name", file.name),
+        format!("{:<9} {}", "Author", file.author),
+        format!("{:<9} {}", "Released", file.released),
+        String::new(),
+        format!("{:<9} {} / {}", "Song", player.song, file.songs),
+        format!("{:<9} {}:{:02}", "Time", elapsed / 60, elapsed % 60),
+        String::new(),
+        format!(
+            "{:<9} {} {}, {}{}",
+            "Machine",
+            if file.rsid { "RSID" } else { "PSID" },
+            match machine.standard {
+                VideoStandard::Pal => "PAL",
+                VideoStandard::Ntsc => "NTSC",
+            },
+            match model {
+                SidModel::Mos6581 => "6581",
+                SidModel::Mos8580 => "8580",
+            },
+            if audio.is_some() { "" } else { " (no sound)" }
+        ),
+        format!(
+            "{:<9} ${:04X}-${:04X}  Init ${:04X}  Play ${:04X}  {}",
+            "Load",
+            file.load_address,
+            file.end_address() - 1,
+            file.init_address,
+            file.play_address,
+            timing
+        ),
+    ];
+    if let Some(error) = &player.error {
+        lines.push(String::new());
+        lines.push(format!("Stopped: {}", error));
+    }
+    let tune = Paragraph::new(lines.join("\n")).block(Block::default().borders(Borders::ALL));
+    frame.render_widget(tune, chunks[1]);
+
+    let muted = audio.is_some_and(|audio| audio.is_muted());
+    let sound = super::sound_status(muted, &machine.memory.sid).map(|s| format!("{} | ", s)).unwrap_or_default();
+    let keys = Paragraph::new(format!("{}Left/Right or 1-9: Song | F2/F4/F6: Voice 1-3 | F8: Mute | ESC: Quit", sound))
+        .style(Style::default().fg(Color::DarkGray))
+        .alignment(Alignment::Center);
+    frame.render_widget(keys, chunks[2]);
+}
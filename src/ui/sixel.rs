@@ -0,0 +1,91 @@
+// Sixel output: the VIC framebuffer as a DEC sixel image, for terminals that
+// can show one (foot, WezTerm, mlterm, xterm -ti vt340, ...)
+
+use std::fmt::Write;
+
+use crate::vic::render::{FRAME_HEIGHT, FRAME_WIDTH};
+use crate::vic::C64Color;
+
+// Encode a FRAME_WIDTH x FRAME_HEIGHT framebuffer of palette indices,
+// blown up by `scale` in both directions and cropped to at most `width` by
+// `height` pixels
+pub fn encode(framebuffer: &[u8], scale: usize, width: usize, height: usize) -> String {
+    let width = (FRAME_WIDTH * scale).min(width);
+    let height = (FRAME_HEIGHT * scale).min(height);
+    let pixel = |x: usize, y: usize| framebuffer[(y / scale) * FRAME_WIDTH + x / scale] as usize & 0x0F;
+
+    // DCS q with square pixels, then the 16 colors as RGB percentages
+    let mut out = format!("\x1bP0;0;0q\"1;1;{};{}", width, height);
+    for index in 0..16u8 {
+        let (r, g, b) = C64Color::from_u8(index).rgb();
+        let percent = |v: u8| v as u32 * 100 / 255;
+        let _ = write!(out, "#{};2;{};{};{}", index, percent(r), percent(g), percent(b));
+    }
+
+    // Each band is 6 pixel rows, drawn once per color it uses; a sixel
+    // character holds the bits of one column of the band
+    let mut sixels = vec![0u8; width];
+    for top in (0..height).step_by(6) {
+        let rows = (height - top).min(6);
+        let mut used = [false; 16];
+        for y in top..top + rows {
+            for x in (0..width).step_by(scale) {
+                used[pixel(x, y)] = true;
+            }
+        }
+        for color in (0..16).filter(|&c| used[c]) {
+            for (x, bits) in sixels.iter_mut().enumerate() {
+                *bits = (0..rows).filter(|&dy| pixel(x, top + dy) == color).fold(0, |acc, dy| acc | 1 << dy);
+            }
+            let _ = write!(out, "#{}", color);
+            push_runs(&mut out, &sixels);
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+// Append sixel characters with runs of 4 or more run-length encoded; an
+// empty run at the end of the line is left out
+fn push_runs(out: &mut String, sixels: &[u8]) {
+    let end = sixels.iter().rposition(|&bits| bits != 0).map_or(0, |last| last + 1);
+    let mut x = 0;
+    while x < end {
+        let bits = sixels[x];
+        let run = sixels[x..end].iter().take_while(|&&b| b == bits).count();
+        let ch = (b'?' + bits) as char;
+        if run >= 4 {
+            let _ = write!(out, "!{}{}", run, ch);
+        } else {
+            out.extend(std::iter::repeat_n(ch, run));
+        }
+        x += run;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sixel_encoding() {
+        let mut framebuffer = vec![6u8; FRAME_WIDTH * FRAME_HEIGHT];
+        framebuffer[0] = 1;
+        framebuffer[FRAME_WIDTH] = 1;
+        let sixel = encode(&framebuffer, 1, FRAME_WIDTH, FRAME_HEIGHT);
+
+        assert!(sixel.starts_with("\x1bP0;0;0q\"1;1;384;272#0;2;0;0;0#1;2;100;100;100"));
+        assert!(sixel.ends_with("-\x1b\\"));
+        // Band 0: white in rows 0-1 of column 0, blue everywhere else
+        assert!(sixel.contains("#1B$#6{!383~$-"));
+        assert_eq!(sixel.matches('-').count(), 272usize.div_ceil(6));
+
+        // Cropped to a smaller destination
+        let sixel = encode(&framebuffer, 2, 100, 50);
+        assert!(sixel.starts_with("\x1bP0;0;0q\"1;1;100;50#"));
+        assert!(sixel.contains("#6oo!98~$-"));
+        assert_eq!(sixel.matches('-').count(), 50usize.div_ceil(6));
+    }
+}
@@ -0,0 +1,87 @@
+// Stack viewer: a debug panel with page 1 from the stack pointer up, the
+// return addresses read back as the calls and interrupts that pushed them
+
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+use crate::debugger::stack::{self, Entry};
+use crate::debugger::symbols::Labels;
+use crate::memory::Memory;
+
+// Bytes on a line, when nothing makes sense of them
+const BYTES_PER_LINE: usize = 6;
+
+pub fn render(frame: &mut Frame, area: Rect, memory: &dyn Memory, sp: u8, labels: &Labels) {
+    let paragraph = Paragraph::new(lines(memory, sp, labels).join("\n"))
+        .style(Style::default().fg(Color::Gray))
+        .block(Block::default().borders(Borders::ALL).title(format!("Stack SP=${:02X}", sp)));
+    frame.render_widget(paragraph, area);
+}
+
+// A line an entry, each starting with where it is in page 1, the top of the
+// stack first
+fn lines(memory: &dyn Memory, sp: u8, labels: &Labels) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut bytes = 0;
+    for entry in stack::decode(memory, sp) {
+        let line = match entry {
+            Entry::Byte { addr, value } => {
+                // Bytes on their own run on along the line
+                if bytes > 0 && bytes < BYTES_PER_LINE
+                    && let Some(line) = lines.last_mut()
+                {
+                    line.push_str(&format!(" {:02X}", value));
+                    bytes += 1;
+                    continue;
+                }
+                bytes = 1;
+                format!("{:02X} {:02X}", addr & 0xFF, value)
+            }
+            Entry::Return { addr, call, target } => {
+                let name = labels.describe(target).unwrap_or_else(|| format!("${:04X}", target));
+                format!("{:02X} {:04X} JSR {}", addr & 0xFF, call, name)
+            }
+            Entry::Interrupt { addr, status, to, brk: false } => {
+                format!("{:02X} IRQ/NMI to {:04X} P=${:02X}", addr & 0xFF, to, status)
+            }
+            Entry::Interrupt { addr, status, to, brk: true } => {
+                format!("{:02X} BRK at {:04X} P=${:02X}", addr & 0xFF, to.wrapping_sub(2), status)
+            }
+        };
+        if !matches!(entry, Entry::Byte { .. }) {
+            bytes = 0;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BasicMemory;
+
+    #[test]
+    fn test_lines() {
+        let mut memory = BasicMemory::new();
+        // Under JSR CHROUT from $E10C: a BRK at $C000, then eight bytes
+        for (i, byte) in [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x30, 0x02, 0xC0, 0x0E, 0xE1].iter().enumerate() {
+            memory.write(0x01F3 + i as u16, *byte);
+        }
+        memory.write(0xE10C, 0x20);
+        memory.write(0xE10D, 0xD2);
+        memory.write(0xE10E, 0xFF);
+
+        let mut labels = Labels::new();
+        assert_eq!(
+            lines(&memory, 0xF2, &labels),
+            ["F3 01 02 03 04 05 06", "F9 07 08", "FB BRK at C000 P=$30", "FE E10C JSR CHROUT"]
+        );
+        labels.add(0xFFD2, "print".to_string());
+        assert_eq!(lines(&memory, 0xFD, &labels), ["FE E10C JSR .print"]);
+    }
+}
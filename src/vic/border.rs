@@ -0,0 +1,74 @@
+// Border flip-flops. The main flip-flop draws the border; the vertical one
+// keeps the main one from opening at the left edge of the display window.
+// Both only change when the beam meets a comparison value, so switching
+// RSEL/CSEL so the beam never meets one leaves the border open.
+
+use super::render::DISPLAY_LEFT;
+use super::VicII;
+
+// Line cycles (counted from 0) in which the beam passes the left edges of the
+// 40 and 38 column windows (X 24 and 31) and the right ones (X 335 and 344).
+// The VIC is ticked once per CPU instruction, so a register write counts for
+// the whole instruction it happens in.
+const LEFT_COMPARE_40: u16 = 16;
+const LEFT_COMPARE_38: u16 = 17;
+const RIGHT_COMPARE_38: u16 = 55;
+const RIGHT_COMPARE_40: u16 = 56;
+
+impl VicII {
+    // First and last+1 raster lines of the display window: 25 rows (RSEL)
+    // span lines $33-$FA, 24 rows $37-$F6
+    fn vertical_compare(&self) -> (u16, u16) {
+        if self.registers[0x11] & 0x08 != 0 { (0x33, 0xFB) } else { (0x37, 0xF7) }
+    }
+
+    fn check_vertical_border(&mut self) {
+        let (top, bottom) = self.vertical_compare();
+        if self.raster_line == bottom {
+            self.vertical_border = true;
+        } else if self.raster_line == top && self.registers[0x11] & 0x10 != 0 {
+            self.vertical_border = false;
+        }
+    }
+
+    // Run the comparisons for the line cycles `from..to` the beam just went
+    // through
+    pub(super) fn update_border(&mut self, from: u16, to: u16) {
+        let passes = |cycle: u16| (from..to).contains(&cycle);
+        let csel = self.registers[0x16] & 0x08 != 0;
+
+        let left = if csel { LEFT_COMPARE_40 } else { LEFT_COMPARE_38 };
+        if passes(left) {
+            self.check_vertical_border();
+            if !self.vertical_border && self.main_border {
+                self.main_border = false;
+                self.border_clear_x = Some(DISPLAY_LEFT + if csel { 0 } else { 7 });
+            }
+        }
+
+        let right = if csel { RIGHT_COMPARE_40 } else { RIGHT_COMPARE_38 };
+        if passes(right) && !self.main_border {
+            self.main_border = true;
+            self.border_set_x = Some(DISPLAY_LEFT + if csel { 320 } else { 311 });
+        }
+    }
+
+    // Cycle 63: the vertical comparison is checked once more before the
+    // next line starts
+    pub(super) fn end_border_line(&mut self) {
+        self.check_vertical_border();
+        self.border_line_start = self.main_border;
+        self.border_clear_x = None;
+        self.border_set_x = None;
+    }
+
+    // Whether the main flip-flop was set at framebuffer column `x` of the
+    // line being drawn
+    pub(super) fn border_at(&self, x: usize) -> bool {
+        match (self.border_clear_x, self.border_set_x) {
+            (_, Some(set)) if x >= set => true,
+            (Some(clear), _) if x >= clear => false,
+            _ => self.border_line_start,
+        }
+    }
+}
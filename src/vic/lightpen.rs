@@ -0,0 +1,70 @@
+// Light pen: the VIC latches the beam position into $D013/$D014 when the pen
+// sees the beam pass under it. The host decides where the pen is held.
+
+use super::render::{FRAME_HEIGHT, FRAME_WIDTH, SPRITE_X_OFFSET};
+use super::VicII;
+
+impl VicII {
+    // Hold the pen against the screen at framebuffer pixel (x, y), or lift
+    // it with None
+    pub fn set_light_pen(&mut self, position: Option<(usize, usize)>) {
+        self.light_pen = position.filter(|&(x, y)| x < FRAME_WIDTH && y < FRAME_HEIGHT);
+    }
+
+    // Called as the beam finishes raster line `line`. The VIC only latches
+    // once per frame; later triggers are ignored until the next frame.
+    pub(super) fn check_light_pen(&mut self, line: u16) {
+        let Some((x, y)) = self.light_pen else { return };
+        if self.light_pen_latched || self.frame_row(line) != Some(y) {
+            return;
+        }
+        self.light_pen_latched = true;
+
+        // $D013 holds the sprite X coordinate halved, $D014 the raster line
+        let sprite_x = (x as i32 - SPRITE_X_OFFSET).max(0);
+        self.registers[0x13] = (sprite_x / 2) as u8;
+        self.registers[0x14] = line as u8;
+        self.registers[0x19] |= 0x08;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::{InterruptLine, InterruptSource};
+    use crate::memory::VicBus;
+
+    #[test]
+    fn test_light_pen_latches_once_per_frame() {
+        let mut vic = VicII::new();
+        let ram = [0; 0x10000];
+        let bus = VicBus::new(&ram, None);
+        let mut irq = InterruptLine::new();
+        vic.write_register(0xD01A, 0x08);
+
+        // Pen at the first pixel of the display window: sprite X 24, line $33
+        let (x, y) = VicII::cell_origin(0, 0);
+        vic.set_light_pen(Some((x, y)));
+        for _ in 0..0x34 * 63 {
+            vic.tick(1, &mut irq, &bus);
+        }
+        assert_eq!((vic.read_register(0xD013), vic.read_register(0xD014)), (12, 0x33));
+        assert_eq!(vic.read_register(0xD019) & 0x08, 0x08);
+        assert!(irq.is_asserted_by(InterruptSource::Vic));
+
+        // Moving the pen later in the same frame doesn't latch again
+        vic.write_register(0xD019, 0x08);
+        vic.set_light_pen(Some((x + 80, y + 40)));
+        for _ in 0..100 * 63 {
+            vic.tick(1, &mut irq, &bus);
+        }
+        assert_eq!(vic.read_register(0xD014), 0x33);
+        assert_eq!(vic.read_register(0xD019) & 0x08, 0);
+
+        // The next frame latches the new position
+        for _ in 0..312 * 63 {
+            vic.tick(1, &mut irq, &bus);
+        }
+        assert_eq!((vic.read_register(0xD013), vic.read_register(0xD014)), (52, 0x33 + 40));
+    }
+}
@@ -1,4 +1,16 @@
-// VIC-II chip emulation (text mode)
+// VIC-II chip emulation: raster timing, badlines, interrupts, the border
+// and the character, bitmap and sprite graphics
+
+mod border;
+pub mod lightpen;
+pub mod render;
+pub mod sprites;
+
+use serde::{Deserialize, Serialize};
+
+use crate::interrupt::{InterruptLine, InterruptSource};
+use crate::machine::VideoStandard;
+use crate::memory::VicBus;
 
 // C64 colors (PETSCII color palette)
 #[derive(Debug, Clone, Copy)]
@@ -42,43 +54,223 @@ impl C64Color {
             _ => C64Color::LightGrey,
         }
     }
+    
+    // Authentic Commodore 64 color palette RGB values (Pepto)
+    pub const fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            C64Color::Black => (0, 0, 0),
+            C64Color::White => (255, 255, 255),
+            C64Color::Red => (136, 0, 0),
+            C64Color::Cyan => (170, 255, 238),
+            C64Color::Purple => (204, 68, 204),
+            C64Color::Green => (0, 204, 85),
+            C64Color::Blue => (53, 40, 121),           // Dark purple-blue
+            C64Color::Yellow => (238, 238, 119),
+            C64Color::Orange => (221, 136, 85),
+            C64Color::Brown => (102, 68, 0),
+            C64Color::LightRed => (255, 119, 119),
+            C64Color::DarkGrey => (51, 51, 51),
+            C64Color::Grey => (119, 119, 119),
+            C64Color::LightGreen => (170, 255, 102),
+            C64Color::LightBlue => (108, 108, 255),    // Periwinkle
+            C64Color::LightGrey => (187, 187, 187),
+        }
+    }
 }
 
 pub const SCREEN_WIDTH: usize = 40;
 pub const SCREEN_HEIGHT: usize = 25;
 
+// Color RAM is a 1K x 4 bit chip, of which the screen uses the first 1000
+const COLOR_RAM_SIZE: usize = 0x400;
+
+// Serializable for snapshots. The framebuffer is output, redrawn within a
+// frame of restoring, and the light pen position is host input.
+#[derive(Serialize, Deserialize)]
 pub struct VicII {
-    // Screen memory ($0400-$07E7 default)
-    screen_base: u16,
+    // 16K bank the VIC fetches from, selected through CIA2 port A. Screen,
+    // character and bitmap addresses from $D018 are offsets into it.
+    bank: u16,
     
-    // Color RAM ($D800-$DBE7)
-    color_ram: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // Color RAM ($D800-$DBFF)
+    #[serde(with = "crate::snapshot::byte_array")]
+    color_ram: [u8; COLOR_RAM_SIZE],
     
     // Border and background colors
     border_color: u8,
     background_color: u8,
     
-    // VIC registers (simplified for text mode)
+    // VIC registers
+    #[serde(with = "crate::snapshot::byte_array")]
     registers: [u8; 64],
     
     // Internal timing
-    cycle_count: u16,
+    cycle_count: u16, // Cycle within the current raster line
     raster_line: u16,
+    raster_lines: u16,    // Lines per frame: 312 PAL, 263 NTSC
+    cycles_per_line: u16, // 63 PAL, 65 NTSC
+    badline: bool,    // Current line fetches a new row of character pointers
+    // DEN ($D011 bit 4) as seen during raster line $30. Badlines can only
+    // occur in frames where the display was enabled on that line.
+    den_latched: bool,
+    
+    // Screen codes and colors fetched on the badline of each character row
+    // this frame (the c-accesses). The display shows these, not whatever
+    // screen RAM holds by the time the frame is rendered.
+    #[serde(with = "crate::snapshot::byte_array")]
+    video_matrix: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    #[serde(with = "crate::snapshot::byte_array")]
+    color_matrix: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    row_counter: usize, // Next character row to fetch (VCBASE / 40)
+    row_fetched: bool,  // This line's fetch is done
+    
+    // Display state: set by a badline, and left for the idle state once
+    // the 8 lines (RC 0-7) of a character row have been drawn
+    display_state: bool,
+    rc: u8,
+    
+    // Palette indices of the visible picture, drawn a line at a time
+    #[serde(skip, default = "blank_framebuffer")]
+    framebuffer: Vec<u8>,
+    #[serde(skip, default = "all_lines_dirty")]
+    dirty_lines: Vec<bool>, // Framebuffer rows changed since last taken
+    frame_complete: bool,
+    frame_count: u64,
+    
+    // Framebuffer pixel the light pen is held at, and whether it has
+    // latched a position yet this frame
+    #[serde(skip)]
+    light_pen: Option<(usize, usize)>,
+    light_pen_latched: bool,
+    
+    // Border flip-flops (see border.rs), plus the main flip-flop's state
+    // as the current line began and the framebuffer columns where it was
+    // cleared and set during the line
+    vertical_border: bool,
+    main_border: bool,
+    border_line_start: bool,
+    border_clear_x: Option<usize>,
+    border_set_x: Option<usize>,
+}
+
+// BA goes low 3 cycles before the 40 character pointer fetches of a badline
+// (cycles 15-54), so the CPU is released again from cycle 55 on.
+const BADLINE_BA_START: u16 = 11;
+const BADLINE_BA_END: u16 = 54;
+
+fn blank_framebuffer() -> Vec<u8> {
+    vec![C64Color::LightBlue as u8; render::FRAME_WIDTH * render::FRAME_HEIGHT]
+}
+
+fn all_lines_dirty() -> Vec<bool> {
+    vec![true; render::FRAME_HEIGHT]
 }
 
 impl VicII {
     pub fn new() -> Self {
         Self {
-            screen_base: 0x0400,
-            color_ram: [C64Color::LightBlue as u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+            bank: 0x0000,
+            color_ram: [C64Color::LightBlue as u8; COLOR_RAM_SIZE],
             border_color: C64Color::LightBlue as u8,
             background_color: C64Color::Blue as u8,
-            registers: [0; 64],
+            // $D018 as the KERNAL sets it: screen at $0400, char ROM at $1000
+            registers: {
+                let mut registers = [0; 64];
+                registers[0x18] = 0x15;
+                registers[0x20] = C64Color::LightBlue as u8;
+                registers[0x21] = C64Color::Blue as u8;
+                registers
+            },
             cycle_count: 0,
             raster_line: 0,
+            raster_lines: VideoStandard::Pal.raster_lines(),
+            cycles_per_line: VideoStandard::Pal.cycles_per_line(),
+            badline: false,
+            den_latched: false,
+            video_matrix: [0x20; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_matrix: [C64Color::LightBlue as u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+            row_counter: 0,
+            row_fetched: false,
+            display_state: false,
+            rc: 0,
+            framebuffer: blank_framebuffer(),
+            dirty_lines: all_lines_dirty(),
+            frame_complete: false,
+            frame_count: 0,
+            light_pen: None,
+            light_pen_latched: false,
+            vertical_border: true,
+            main_border: true,
+            border_line_start: true,
+            border_clear_x: None,
+            border_set_x: None,
         }
     }
     
+    pub fn set_bank(&mut self, bank: u16) {
+        self.bank = bank;
+    }
+    
+    pub fn bank(&self) -> u16 {
+        self.bank
+    }
+    
+    // Raster line the beam is on
+    pub fn raster_line(&self) -> u16 {
+        self.raster_line
+    }
+    
+    // Cycle the beam is at on the line
+    pub fn raster_cycle(&self) -> u16 {
+        self.cycle_count
+    }
+    
+    // Raster line that raises the raster IRQ: $D012 written, with bit 8 in
+    // $D011 bit 7
+    pub fn raster_compare(&self) -> u16 {
+        self.registers[0x12] as u16 | ((self.registers[0x11] & 0x80) as u16) << 1
+    }
+    
+    // Video matrix: $D018 bits 4-7 select a 1K block of the bank
+    pub fn screen_base(&self) -> u16 {
+        self.bank + (self.registers[0x18] >> 4) as u16 * 0x400
+    }
+    
+    // Character generator: $D018 bits 1-3 select a 2K block of the bank
+    pub fn char_base(&self) -> u16 {
+        self.bank + ((self.registers[0x18] >> 1) & 0x07) as u16 * 0x800
+    }
+    
+    // The character ROM shows up at $1000-$1FFF of banks 0 and 2, so these
+    // charset selections read the ROM rather than RAM
+    pub fn char_rom_selected(&self) -> bool {
+        self.char_base() & 0x7000 == 0x1000
+    }
+    
+    // The second half of the character ROM ($D018 bit 1, toggled by the
+    // KERNAL on SHIFT+C=) holds lowercase letters
+    pub fn lowercase_selected(&self) -> bool {
+        self.char_rom_selected() && self.char_base() & 0x0800 != 0
+    }
+    
+    // Bitmap: $D018 bit 3 selects the lower or upper 8K of the bank
+    pub fn bitmap_base(&self) -> u16 {
+        self.bank + (self.registers[0x18] & 0x08) as u16 * 0x400
+    }
+    
+    // Take on the chip state in a snapshot, keeping the host's light pen.
+    // The picture is drawn afresh.
+    pub fn restore(&mut self, snapshot: VicII) {
+        let old = std::mem::replace(self, snapshot);
+        self.light_pen = old.light_pen;
+    }
+    
+    pub fn set_standard(&mut self, standard: VideoStandard) {
+        self.raster_lines = standard.raster_lines();
+        self.cycles_per_line = standard.cycles_per_line();
+        self.framebuffer.fill(C64Color::Black as u8);
+    }
+    
     pub fn read_register(&self, addr: u16) -> u8 {
         let reg = (addr & 0x3F) as usize;
         match reg {
@@ -88,14 +280,46 @@ impl VicII {
                 val | raster_high
             }
             0x12 => (self.raster_line & 0xFF) as u8,
+            // Latched sources, with bit 7 set while one of them drives IRQ.
+            // Unused bits read as 1.
+            0x19 => {
+                let irq = if self.registers[0x19] & self.registers[0x1A] & 0x0F != 0 { 0x80 } else { 0 };
+                self.registers[0x19] | 0x70 | irq
+            }
+            0x1A => self.registers[0x1A] | 0xF0,
             _ => self.registers[reg]
         }
     }
     
     pub fn write_register(&mut self, addr: u16, value: u8) {
         let reg = (addr & 0x3F) as usize;
+        tracing::trace!(target: "vic", "${:04X} <- ${:02X}", 0xD000 + reg, value);
+        
+        // $D019: writing a 1 to a latch bit acknowledges that interrupt
+        if reg == 0x19 {
+            self.registers[0x19] &= !(value & 0x0F);
+            return;
+        }
+        // $D013/$D014: the light pen latch is read only
+        if reg == 0x13 || reg == 0x14 {
+            return;
+        }
+        // A new charset changes every character, even ones the text frontend
+        // looks up by screen code rather than reads from the pixels
+        if reg == 0x18 && self.registers[0x18] != value {
+            self.dirty_lines.fill(true);
+        }
         self.registers[reg] = value;
         
+        // A $D011 write can create or cancel a badline mid-line (YSCROLL
+        // tricks such as FLD and DMA delay rely on this)
+        if reg == 0x11 {
+            if self.raster_line == 0x30 && value & 0x10 != 0 {
+                self.den_latched = true;
+            }
+            self.badline = self.is_badline();
+        }
+        
         match reg {
             0x20 => {
                 self.border_color = value & 0x0F;
@@ -110,63 +334,147 @@ impl VicII {
     }
     
     // Simulate VIC-II timing (raster beam)
-    pub fn tick(&mut self, cycles: u8) -> bool {
-        // C64 PAL: 312 lines, 63 cycles/line
+    // Advance the raster beam. The VIC holds IRQ asserted for as long as an
+    // enabled source is latched in $D019, i.e. until the CPU acknowledges it.
+    pub fn tick(&mut self, cycles: u8, irq: &mut InterruptLine, bus: &VicBus) {
+        // PAL: 312 lines of 63 cycles, NTSC: 263 lines of 65 cycles
         // Raster line at $D012 (bits 0-7) and $D011 (bit 7)
+        let line_cycle = self.cycle_count;
         self.cycle_count += cycles as u16;
+        self.update_border(line_cycle, self.cycle_count.min(self.cycles_per_line));
         
-        if self.cycle_count >= 63 {
-            self.cycle_count -= 63;
+        if self.cycle_count >= self.cycles_per_line {
+            self.cycle_count -= self.cycles_per_line;
+            
+            // Draw the line just finished, then step the row counter
+            self.render_line(self.raster_line, bus);
+            self.check_light_pen(self.raster_line);
+            self.end_border_line();
+            if self.display_state {
+                if self.rc == 7 {
+                    self.display_state = false;
+                } else {
+                    self.rc += 1;
+                }
+            }
             
             // Increment raster line
             self.raster_line += 1;
-            if self.raster_line >= 312 {
+            if self.raster_line >= self.raster_lines {
                 self.raster_line = 0;
+                self.row_counter = 0;
+                self.frame_complete = true;
+                self.frame_count += 1;
+                self.light_pen_latched = false;
+            }
+            if self.raster_line == 0x30 {
+                self.den_latched = self.registers[0x11] & 0x10 != 0;
             }
+            self.badline = self.is_badline();
+            self.row_fetched = false;
             
             // Check for Raster IRQ
             // IRQ condition: raster_line == irq_raster_line
-            let irq_raster_line = (self.registers[0x12] as u16) | 
-                                 (if (self.registers[0x11] & 0x80) != 0 { 0x100 } else { 0 });
-            
-            if self.raster_line == irq_raster_line {
+            if self.raster_line == self.raster_compare() {
                 // Set Raster IRQ flag (Bit 0 of $D019)
                 self.registers[0x19] |= 0x01;
-                
-                // If Raster IRQ Enabled ($D01A Bit 0), signal interrupt
-                if (self.registers[0x1A] & 0x01) != 0 {
-                    return true;
-                }
             }
         }
         
-        false
+        // A badline refetches the 40 character pointers and colors for the
+        // next character row while the CPU is held off the bus
+        if self.badline && !self.row_fetched {
+            self.fetch_row(bus);
+        }
+        
+        // IRQ while any latched source is enabled in $D01A
+        let pending = (self.registers[0x19] & self.registers[0x1A] & 0x0F) != 0;
+        irq.set(InterruptSource::Vic, pending);
     }
 
-    pub fn read_color_ram(&self, offset: u16) -> u8 {
-        if (offset as usize) < self.color_ram.len() {
-            self.color_ram[offset as usize] & 0x0F
-        } else {
-            0
+    // Badline condition: inside the display window, the low 3 bits of the
+    // raster line match YSCROLL ($D011 bits 0-2) and the display was enabled
+    // on line $30
+    fn is_badline(&self) -> bool {
+        let yscroll = (self.registers[0x11] & 0x07) as u16;
+        self.den_latched
+            && (0x30..=0xF7).contains(&self.raster_line)
+            && (self.raster_line & 0x07) == yscroll
+    }
+    
+    fn fetch_row(&mut self, bus: &VicBus) {
+        self.row_fetched = true;
+        self.display_state = true;
+        self.rc = 0;
+        if self.row_counter >= SCREEN_HEIGHT {
+            return;
+        }
+        let start = self.row_counter * SCREEN_WIDTH;
+        let screen = self.screen_base();
+        let mut changed = false;
+        for offset in start..start + SCREEN_WIDTH {
+            let code = bus.read(screen + offset as u16);
+            let color = self.color_ram[offset];
+            changed |= self.video_matrix[offset] != code || self.color_matrix[offset] != color;
+            self.video_matrix[offset] = code;
+            self.color_matrix[offset] = color;
         }
+        if changed {
+            self.mark_cell_row_dirty(self.row_counter);
+        }
+        self.row_counter += 1;
+    }
+
+    // BA output, wired to the CPU's RDY input. Low while the VIC needs the
+    // bus, for a badline's character pointers or a sprite's data.
+    pub fn ba_low(&self) -> bool {
+        (self.badline && (BADLINE_BA_START..BADLINE_BA_END).contains(&self.cycle_count)) || self.sprite_ba_low()
+    }
+
+    // Only the low nibble is stored: the chip has four data lines, and the
+    // bus leaves the upper four floating
+    pub fn read_color_ram(&self, offset: u16) -> u8 {
+        self.color_ram[offset as usize % COLOR_RAM_SIZE]
     }
     
     pub fn write_color_ram(&mut self, offset: u16, value: u8) {
-        if (offset as usize) < self.color_ram.len() {
-            self.color_ram[offset as usize] = value & 0x0F;
-        }
+        self.color_ram[offset as usize % COLOR_RAM_SIZE] = value & 0x0F;
     }
     
-    pub fn get_screen_char(&self, memory: &dyn crate::memory::Memory, x: usize, y: usize) -> (u8, u8) {
+    // Screen code and color of a cell, as fetched on its row's badline
+    pub fn get_screen_char(&self, x: usize, y: usize) -> (u8, u8) {
         if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
             return (0x20, C64Color::LightBlue as u8); // Space character
         }
         
         let offset = y * SCREEN_WIDTH + x;
-        let char_code = memory.read(self.screen_base + offset as u16);
-        let color = self.color_ram[offset];
-        
-        (char_code, color)
+        (self.video_matrix[offset], self.color_matrix[offset])
+    }
+    
+    // Fine scroll in pixels: XSCROLL ($D016 bits 0-2) moves the display
+    // right, YSCROLL ($D011 bits 0-2) down. The KERNAL's YSCROLL of 3 is the
+    // unscrolled position.
+    pub fn fine_scroll(&self) -> (u8, u8) {
+        (self.registers[0x16] & 0x07, self.registers[0x11] & 0x07)
+    }
+    
+    // Fine scroll rounded to whole character cells for the text renderer,
+    // so a smooth scroller moves a cell at a time instead of standing still
+    pub fn scroll_cells(&self) -> (i32, i32) {
+        let (xscroll, yscroll) = self.fine_scroll();
+        let x = (xscroll as i32 + 4) / 8;
+        let y = (yscroll as i32 - 3 + 4).div_euclid(8);
+        (x, y)
+    }
+    
+    // CSEL ($D016 bit 3) clear selects 38 columns, RSEL ($D011 bit 3) clear
+    // 24 rows. The border then covers 7 pixels more on the left and 9 on the
+    // right, or 4 more at top and bottom, which hides (most of) the outer
+    // columns or rows of cells.
+    pub fn border_covers_cell(&self, x: usize, y: usize) -> bool {
+        let narrow = self.registers[0x16] & 0x08 == 0;
+        let short = self.registers[0x11] & 0x08 == 0;
+        (narrow && (x == 0 || x == SCREEN_WIDTH - 1)) || (short && (y == 0 || y == SCREEN_HEIGHT - 1))
     }
     
     pub fn get_border_color(&self) -> C64Color {
@@ -178,106 +486,478 @@ impl VicII {
     }
 }
 
+// Graphics characters of screen codes 64-127 in the uppercase set. Line and
+// block pieces that Unicode only has in the Symbols for Legacy Computing
+// block (U+1FB00) come from there, which not every terminal font covers.
+const GRAPHICS_CHARS: [char; 64] = [
+    // $40-$4F
+    '─', '♠', '\u{1FB72}', '\u{1FB78}', '\u{1FB77}', '\u{1FB76}', '\u{1FB7A}', '\u{1FB71}',
+    '\u{1FB74}', '╮', '╰', '╯', '\u{1FB7C}', '╲', '╱', '\u{1FB7D}',
+    // $50-$5F
+    '\u{1FB7E}', '●', '\u{1FB7B}', '♥', '\u{1FB70}', '╭', '╳', '○',
+    '♣', '\u{1FB75}', '♦', '┼', '\u{1FB8C}', '│', 'π', '◥',
+    // $60-$6F
+    ' ', '▌', '▄', '▔', '▁', '▏', '▒', '▕',
+    '\u{1FB8F}', '◤', '\u{1FB87}', '├', '▗', '└', '┐', '▂',
+    // $70-$7F
+    '┌', '┴', '┬', '┤', '▎', '▍', '\u{1FB88}', '\u{1FB82}',
+    '\u{1FB83}', '▃', '\u{1FB7F}', '▖', '▝', '┘', '▘', '▚',
+];
+
 // Convert C64 Screen Code to ASCII/Unicode char
-pub fn screen_code_to_char(code: u8) -> char {
+// `lowercase` picks the second half of the character ROM, where screen codes
+// 1-26 are lowercase letters and 65-90 the capitals. Codes 128-255 are the
+// reversed forms of 0-127 and give the same char; the caller swaps colors.
+pub fn screen_code_to_char(code: u8, lowercase: bool) -> char {
+    let code = code & 0x7F;
     match code {
         0 => '@',
+        1..=26 if lowercase => (b'a' + (code - 1)) as char,
         1..=26 => (b'A' + (code - 1)) as char,
         27 => '[',
         28 => '£',
         29 => ']',
         30 => '↑',
         31 => '←',
-        32 => ' ',
-        33 => '!',
-        34 => '"',
-        35 => '#',
-        36 => '$',
-        37 => '%',
-        38 => '&',
-        39 => '\'',
-        40 => '(',
-        41 => ')',
-        42 => '*',
-        43 => '+',
-        44 => ',',
-        45 => '-',
-        46 => '.',
-        47 => '/',
-        48..=57 => (b'0' + (code - 48)) as char,
-        58 => ':',
-        59 => ';',
-        60 => '<',
-        61 => '=',
-        62 => '>',
-        63 => '?',
-        65 => '♠',
-        66 => '│', // Vertical bar
-        67 => '─', // Horizontal bar
-        68 => '─',
-        69 => '─',
-        70 => '─',
-        71 => '│',
-        72 => '│',
-        73 => '╯', // Curved corner or similar
-        74 => '╮',
-        75 => '╰',
-        76 => '╭',
-        77 => '╲', // Diagonal
-        78 => '╱',
-        79 => '╳',
-        80 => '●', // Circle
-        81 => '●',
-        82 => '○',
-        83 => '♥',
-        84 => '─',
-        85 => '╭',
-        86 => '╳',
-        87 => '○',
-        88 => '♣',
-        89 => '─',
-        90 => '♦',
-        91 => '+',
-        92 => '│',
-        93 => '│',
-        94 => 'π',
-        95 => '◥', // Triangle?
-        160 => '█', // Shift+Space / Reverse Space (Cursor)
-        // Reversed/Inverse characters (recurse to base char)
-        128..=255 => screen_code_to_char(code & 0x7F), 
-        // Default fallback
-        _ => '▒',
+        // Space, punctuation and digits match ASCII
+        32..=63 => code as char,
+        65..=90 if lowercase => (b'A' + (code - 65)) as char,
+        // The few graphics that differ in the lowercase set
+        94 if lowercase => '\u{1FB95}', // Checkerboard
+        95 if lowercase => '\u{1FB98}', // Diagonal hatching
+        105 if lowercase => '\u{1FB99}',
+        122 if lowercase => '✓',
+        _ => GRAPHICS_CHARS[code as usize - 64],
     }
 }
 
-// // PETSCII to ASCII conversion (simplified)
-// pub fn petscii_to_char(petscii: u8) -> char {
-//     match petscii {
-//         0x00..=0x1F => ' ', // Control characters as space
-//         0x20..=0x3F => (petscii as char), // Standard ASCII
-//         0x40 => '@',
-//         0x41..=0x5A => (petscii as char), // A-Z
-//         0x5B => '[',
-//         0x5C => '£', // Pound sign
-//         0x5D => ']',
-//         0x5E => '↑', // Up arrow
-//         0x5F => '←', // Left arrow
-//         0x60 => '─', // Horizontal line
-//         0x61..=0x7A => ((petscii - 0x20) as char), // a-z (lowercase in C64 is uppercase+32)
-//         0x7B => '+',
-//         0x7C => '|',
-//         0x7D => '}',
-//         0x7E => '~',
-//         0x7F => ' ',
-//         0x80..=0xFF => {
-//             // Inverted/graphics characters - use ASCII approximations
-//             if petscii == 0xA0 {
-//                 '█' // Cursor - solid block
-//             } else if petscii >= 0xA0 && petscii <= 0xBF {
-//                 ((petscii - 0x80) as char)
-//             } else {
-//                 '▒' // Use block for graphics chars
-//             }
-//         }
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::render::GraphicsMode;
+
+    fn run_frame(vic: &mut VicII, bus: &VicBus) {
+        let mut irq = InterruptLine::new();
+        for _ in 0..312 * 63 {
+            vic.tick(1, &mut irq, bus);
+        }
+    }
+
+    #[test]
+    fn test_multicolor_text_pixels() {
+        let mut vic = VicII::new();
+        vic.write_register(0xD021, 6);
+        vic.write_register(0xD022, 2);
+        vic.write_register(0xD023, 5);
+        let colors = |pixels: [(u8, bool); 8]| pixels.map(|(color, _)| color);
+        let data = 0b00_01_10_11;
+
+        // Hires until MCM is switched on
+        assert_eq!(vic.graphics_mode(), GraphicsMode::Text);
+        assert_eq!(colors(vic.decode_graphics(vic.graphics_mode(), data, 1, 0x0F)), [6, 6, 6, 15, 15, 6, 15, 15]);
+
+        vic.write_register(0xD016, 0x10);
+        assert_eq!(vic.graphics_mode(), GraphicsMode::MulticolorText);
+        let pixels = vic.decode_graphics(vic.graphics_mode(), data, 1, 0x0F);
+        assert_eq!(colors(pixels), [6, 6, 2, 2, 5, 5, 7, 7]);
+        // Only the 10 and 11 pairs count as foreground for sprite priority
+        assert_eq!(pixels.map(|(_, fg)| fg), [false, false, false, false, true, true, true, true]);
+
+        // Color RAM bit 3 clear: still hires in MCM
+        assert_eq!(colors(vic.decode_graphics(vic.graphics_mode(), data, 1, 0x07)), [6, 6, 6, 7, 7, 6, 7, 7]);
+    }
+
+    #[test]
+    fn test_framebuffer_text_bitmap_and_border() {
+        let mut vic = VicII::new();
+        let mut ram = [0; 0x10000];
+        ram[0x0400] = 0x01; // Top-left cell shows screen code 1
+        ram[0x1008] = 0xF0; // Row 0 of screen code 1 in the charset at $1000
+        vic.write_color_ram(0, 0x02);
+        vic.write_register(0xD020, 14);
+        vic.write_register(0xD021, 6);
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD016, 0x08);
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+
+        let (x, y) = VicII::cell_origin(0, 0);
+        assert_eq!((vic.pixel(x, y), vic.pixel(x + 4, y)), (2, 6));
+        assert_eq!(vic.pixel(x - 1, y), 14); // Left border
+        assert_eq!(vic.pixel(x, y - 1), 14); // Top border
+
+        // Hires bitmap at $2000: screen code holds foreground/background
+        ram[0x2000] = 0x80;
+        ram[0x0400] = 0x5E;
+        vic.write_register(0xD018, 0x18);
+        vic.write_register(0xD011, 0x3B);
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+        assert_eq!(vic.graphics_mode(), GraphicsMode::Bitmap);
+        assert_eq!((vic.pixel(x, y), vic.pixel(x + 1, y)), (5, 14));
+    }
+
+    #[test]
+    fn test_framebuffer_sprite_behind_foreground() {
+        let mut vic = VicII::new();
+        let mut ram = [0; 0x10000];
+        ram[0x1000] = 0xFF; // Screen code 0 row 0: all foreground
+        ram[0x07F8] = 13;
+        ram[0x0340] = 0xFF; // Sprite rows 0 and 1 solid
+        ram[0x0343] = 0xFF;
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD016, 0x08);
+        vic.write_register(0xD021, 6);
+        vic.write_register(0xD000, 24);
+        vic.write_register(0xD001, 0x32); // First line 0x33, the first display line
+        vic.write_register(0xD027, 7);
+        vic.write_register(0xD015, 0x01);
+        vic.write_color_ram(0, 0x01);
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+
+        let (x, y) = VicII::cell_origin(0, 0);
+        assert_eq!(vic.pixel(x, y), 7);
+
+        vic.write_register(0xD01B, 0x01);
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+        assert_eq!(vic.pixel(x, y), 1);
+        // Row 1 of the character is background, so the sprite shows there
+        assert_eq!(vic.pixel(x, y + 1), 7);
+    }
+
+    #[test]
+    fn test_frame_complete_once_per_frame() {
+        let mut vic = VicII::new();
+        let ram = [0; 0x10000];
+        let bus = VicBus::new(&ram, None);
+        let mut irq = InterruptLine::new();
+        vic.write_register(0xD020, 2);
+
+        let mut completed = 0;
+        for _ in 0..3 * 312 * 63 {
+            vic.tick(1, &mut irq, &bus);
+            if vic.take_frame_complete() {
+                completed += 1;
+            }
+        }
+        assert_eq!((completed, vic.frame_count()), (3, 3));
+        assert!(!vic.take_frame_complete());
+
+        let framebuffer = vic.framebuffer();
+        assert_eq!(framebuffer.len(), render::FRAME_WIDTH * render::FRAME_HEIGHT);
+        assert_eq!(framebuffer[0], 2);
+        assert_eq!(C64Color::from_u8(framebuffer[0]).rgb(), (136, 0, 0));
+    }
+
+    #[test]
+    fn test_frame_hash() {
+        let ram = [0; 0x10000];
+        let bus = VicBus::new(&ram, None);
+        let mut irq = InterruptLine::new();
+        let mut frame = |border: u8| {
+            let mut vic = VicII::new();
+            vic.write_register(0xD020, border);
+            while !vic.take_frame_complete() {
+                vic.tick(1, &mut irq, &bus);
+            }
+            vic.frame_hash()
+        };
+
+        // The same picture always hashes the same; any other picture doesn't
+        assert_eq!(frame(2), frame(2));
+        assert_ne!(frame(2), frame(3));
+        assert_eq!(VicII::new().frame_hash(), 0xE0DA_714E_6D1C_8325);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut ram = [0; 0x10000];
+        ram[0x0400] = 0x01;
+        ram[0x1008] = 0xFF;
+        let bus = VicBus::new(&ram, None);
+        let mut irq = InterruptLine::new();
+        let mut vic = VicII::new();
+        vic.write_register(0xD020, 2);
+        vic.write_register(0xD012, 0x80);
+        vic.write_register(0xD01A, 0x01);
+        vic.write_color_ram(0, 0x07);
+        // Save half way down the screen, with rows already fetched
+        for _ in 0..150 * 63 + 20 {
+            vic.tick(1, &mut irq, &bus);
+        }
+
+        let json = serde_json::to_string(&vic).unwrap();
+        let mut restored: VicII = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.raster_line(), 150);
+        assert_eq!(restored.read_register(0xD019), vic.read_register(0xD019));
+        assert_eq!(restored.read_color_ram(0) & 0x0F, 0x07);
+        assert_eq!(restored.get_screen_char(0, 0), vic.get_screen_char(0, 0));
+
+        // Both run on identically and draw the same next frame
+        for vic in [&mut vic, &mut restored] {
+            let mut irq = InterruptLine::new();
+            while !vic.take_frame_complete() {
+                vic.tick(1, &mut irq, &bus);
+            }
+            while !vic.take_frame_complete() {
+                vic.tick(1, &mut irq, &bus);
+            }
+        }
+        assert_eq!(restored.frame_hash(), vic.frame_hash());
+    }
+
+    #[test]
+    fn test_fine_scroll_rounds_to_cells() {
+        let mut vic = VicII::new();
+        vic.write_register(0xD016, 0xC8);
+        vic.write_register(0xD011, 0x1B);
+        assert_eq!(vic.fine_scroll(), (0, 3));
+        assert_eq!(vic.scroll_cells(), (0, 0));
+
+        vic.write_register(0xD016, 0xCC); // XSCROLL 4
+        vic.write_register(0xD011, 0x1F); // YSCROLL 7
+        assert_eq!(vic.scroll_cells(), (1, 1));
+
+        vic.write_register(0xD011, 0x18); // YSCROLL 0
+        assert_eq!(vic.scroll_cells(), (1, 0));
+    }
+
+    #[test]
+    fn test_d018_and_bank_relocation() {
+        let mut vic = VicII::new();
+        assert_eq!((vic.screen_base(), vic.char_base()), (0x0400, 0x1000));
+        assert!(vic.char_rom_selected());
+        vic.write_register(0xD018, 0x17);
+        assert!(vic.lowercase_selected());
+
+        vic.write_register(0xD018, 0x1E); // Screen $0400, chars $3800, bitmap $2000
+        vic.set_bank(0xC000);
+        assert_eq!(vic.screen_base(), 0xC400);
+        assert_eq!(vic.char_base(), 0xF800);
+        assert_eq!(vic.bitmap_base(), 0xE000);
+        assert!(!vic.char_rom_selected());
+        assert!(!vic.lowercase_selected());
+
+        let mut ram = [0; 0x10000];
+        ram[0xC400 + 41] = 0x01;
+        vic.write_register(0xD011, 0x1B);
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+        assert_eq!(vic.get_screen_char(1, 1).0, 0x01);
+    }
+
+    #[test]
+    fn test_badline_stalls_cpu_and_fetches_row() {
+        let mut vic = VicII::new();
+        let mut irq = InterruptLine::new();
+        let mut ram = [0; 0x10000];
+        ram[0x0400] = 0x01;
+        vic.write_register(0xD011, 0x1B); // DEN, YSCROLL 3
+
+        // Line $33 is the first badline: row 0 is fetched as it starts
+        {
+            let bus = VicBus::new(&ram, None);
+            while vic.raster_line != 0x33 {
+                vic.tick(1, &mut irq, &bus);
+            }
+            assert_eq!(vic.get_screen_char(0, 0).0, 0x01);
+
+            let mut stalled = 0;
+            for _ in 0..63 {
+                stalled += vic.ba_low() as u32;
+                vic.tick(1, &mut irq, &bus);
+            }
+            assert_eq!(stalled, 43);
+        }
+
+        // Later screen RAM writes only show once the row is fetched again
+        ram[0x0400] = 0x02;
+        let bus = VicBus::new(&ram, None);
+        assert_eq!(vic.get_screen_char(0, 0).0, 0x01);
+        run_frame(&mut vic, &bus);
+        assert_eq!(vic.get_screen_char(0, 0).0, 0x02);
+
+        // With DEN clear on line $30 the frame has no badlines at all
+        vic.write_register(0xD011, 0x0B);
+        run_frame(&mut vic, &bus);
+        for _ in 0..312 * 63 {
+            assert!(!vic.ba_low());
+            vic.tick(1, &mut irq, &bus);
+        }
+    }
+
+    #[test]
+    fn test_sprite_dma_stalls_cpu() {
+        // Cycles BA is low over a frame without badlines
+        fn stolen(vic: &mut VicII) -> u32 {
+            let ram = [0; 0x10000];
+            let bus = VicBus::new(&ram, None);
+            let mut irq = InterruptLine::new();
+            let mut stalled = 0;
+            for _ in 0..312 * 63 {
+                stalled += vic.ba_low() as u32;
+                vic.tick(1, &mut irq, &bus);
+            }
+            stalled
+        }
+
+        let mut vic = VicII::new();
+        vic.write_register(0xD011, 0x0B);
+        assert_eq!(stolen(&mut vic), 0);
+
+        // 3 cycles before the pointer fetch and the 2 cycles of fetches, on
+        // each of the sprite's 21 lines
+        vic.write_register(0xD001, 0x60);
+        vic.write_register(0xD015, 0x01);
+        assert_eq!(stolen(&mut vic), 21 * 5);
+
+        // Sprite 1 fetches right after sprite 0, so BA stays low between them
+        vic.write_register(0xD003, 0x60);
+        vic.write_register(0xD015, 0x03);
+        assert_eq!(stolen(&mut vic), 21 * 7);
+
+        // Sprite 3 fetches at the start of the next line, after a lead-in
+        // of its own
+        vic.write_register(0xD007, 0x60);
+        vic.write_register(0xD015, 0x09);
+        assert_eq!(stolen(&mut vic), 21 * 10);
+
+        // Expanded vertically, the sprite's DMA runs for 42 lines
+        vic.write_register(0xD015, 0x01);
+        vic.write_register(0xD017, 0x01);
+        assert_eq!(stolen(&mut vic), 42 * 5);
+    }
+
+    #[test]
+    fn test_38_column_and_24_row_borders() {
+        let mut vic = VicII::new();
+        vic.write_register(0xD016, 0x08);
+        vic.write_register(0xD011, 0x1B);
+        assert!(!vic.border_covers_cell(0, 0));
+        assert!(!vic.border_covers_cell(39, 24));
+
+        vic.write_register(0xD016, 0x00); // 38 columns
+        assert!(vic.border_covers_cell(0, 5));
+        assert!(vic.border_covers_cell(39, 5));
+        assert!(!vic.border_covers_cell(1, 0));
+
+        vic.write_register(0xD011, 0x13); // 24 rows
+        assert!(vic.border_covers_cell(1, 0));
+        assert!(vic.border_covers_cell(1, 24));
+        assert!(!vic.border_covers_cell(1, 23));
+    }
+
+    #[test]
+    fn test_open_lower_border_shows_sprites() {
+        let mut vic = VicII::new();
+        let mut ram = [0; 0x10000];
+        ram[0x07F8] = 13;
+        ram[0x0340] = 0xFF; // Sprite row 0 solid
+        let bus = VicBus::new(&ram, None);
+        let mut irq = InterruptLine::new();
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD016, 0x08);
+        vic.write_register(0xD020, 14);
+        vic.write_register(0xD021, 6);
+        vic.write_register(0xD000, 100);
+        vic.write_register(0xD001, 0xFF); // First line $100, in the lower border
+        vic.write_register(0xD027, 7);
+        vic.write_register(0xD015, 0x01);
+
+        let sprite_pixel = |vic: &VicII| vic.pixel(100 + 8, 0x100 - 15);
+        let mut run_to_line = |vic: &mut VicII, line: u16| {
+            while vic.raster_line != line {
+                vic.tick(1, &mut irq, &bus);
+            }
+        };
+
+        run_to_line(&mut vic, 0);
+        run_to_line(&mut vic, 0x130);
+        assert_eq!(sprite_pixel(&vic), 14);
+
+        // 24 rows on lines $F8-$FA: the beam passes line $FB while the
+        // comparison value is $F7, so the border never closes
+        run_to_line(&mut vic, 0);
+        run_to_line(&mut vic, 0xF8);
+        vic.write_register(0xD011, 0x13);
+        run_to_line(&mut vic, 0xFC);
+        vic.write_register(0xD011, 0x1B);
+        run_to_line(&mut vic, 0x130);
+        assert_eq!(sprite_pixel(&vic), 7);
+        // Idle graphics ($3FFF is 0) show background beside the sprite
+        assert_eq!(vic.pixel(100 + 8 - 1, 0x100 - 15), 6);
+    }
+
+    #[test]
+    fn test_ntsc_frame_wraps_into_lower_rows() {
+        let mut vic = VicII::new();
+        vic.set_standard(VideoStandard::Ntsc);
+        let ram = [0; 0x10000];
+        let bus = VicBus::new(&ram, None);
+        let mut irq = InterruptLine::new();
+        vic.write_register(0xD020, 2);
+
+        let mut cycles = 0;
+        while !vic.take_frame_complete() {
+            vic.tick(1, &mut irq, &bus);
+            cycles += 1;
+        }
+        assert_eq!(cycles, 263 * 65);
+
+        // Lines 250-262, then lines 0-14 of the next frame, fill rows 235-262
+        assert_eq!(vic.frame_row(262), Some(247));
+        assert_eq!(vic.frame_row(0), Some(248));
+        assert_eq!(vic.frame_row(15), Some(0));
+        vic.write_register(0xD020, 5);
+        for _ in 0..15 * 65 {
+            vic.tick(1, &mut irq, &bus);
+        }
+        assert_eq!((vic.pixel(0, 247), vic.pixel(0, 248), vic.pixel(0, 262)), (2, 5, 5));
+        assert_eq!(vic.pixel(0, 263), 0);
+    }
+
+    #[test]
+    fn test_screen_codes_in_both_charsets() {
+        // "HI", space, $42 and a reversed $01
+        let codes = [0x08, 0x09, 0x20, 0x42, 0x81];
+        let text: String = codes.iter().map(|&c| screen_code_to_char(c, false)).collect();
+        assert_eq!(text, "HI \u{1FB72}A");
+        let text: String = codes.iter().map(|&c| screen_code_to_char(c, true)).collect();
+        assert_eq!(text, "hi Ba");
+
+        // Card suits, box drawing and blocks of the graphics set
+        let graphics: String = [0x41, 0x53, 0x58, 0x5A, 0x70, 0x6E, 0x66, 0x7F]
+            .iter()
+            .map(|&c| screen_code_to_char(c, false))
+            .collect();
+        assert_eq!(graphics, "♠♥♣♦┌┐▒▚");
+        assert_eq!(screen_code_to_char(0x7A, true), '✓');
+        assert_eq!(screen_code_to_char(0xA0, false), ' ');
+    }
+
+    #[test]
+    fn test_dirty_lines_follow_changes() {
+        let mut vic = VicII::new();
+        let mut ram = [0; 0x10000];
+        ram[0x1008] = 0xFF; // Screen code 1 is a solid bar on its first row
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD016, 0x08);
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+        assert!(vic.take_dirty_lines().iter().any(|&dirty| dirty));
+
+        // Nothing changed
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+        assert!(!vic.take_dirty_lines().iter().any(|&dirty| dirty));
+
+        // A character on row 2 dirties that row's 8 lines only
+        ram[0x0400 + 2 * 40 + 5] = 0x01;
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+        let dirty = vic.take_dirty_lines();
+        let (_, top) = VicII::cell_origin(0, 2);
+        let lines: Vec<usize> = (0..dirty.len()).filter(|&line| dirty[line]).collect();
+        assert_eq!(lines, (top..top + 8).collect::<Vec<_>>());
+
+        // A screen code with a blank glyph still dirties its row
+        ram[0x0400 + 10 * 40] = 0x02;
+        run_frame(&mut vic, &VicBus::new(&ram, None));
+        let (_, top) = VicII::cell_origin(0, 10);
+        assert!(vic.take_dirty_lines()[top]);
+    }
+}
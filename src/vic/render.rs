@@ -0,0 +1,266 @@
+// Scanline renderer: draws each raster line into the framebuffer as the beam
+// finishes it, so mid-frame register changes (raster splits, color bars)
+// show up where they happen
+
+use super::sprites::SPRITE_COUNT;
+use super::{VicII, SCREEN_WIDTH};
+use crate::memory::VicBus;
+
+// Visible picture: the 320x200 display window plus 32 pixels of border left
+// and right and 36 lines above and below
+pub const FRAME_WIDTH: usize = 384;
+pub const FRAME_HEIGHT: usize = 272;
+
+// Raster line shown on the first framebuffer row
+pub(super) const FIRST_VISIBLE_LINE: u16 = 15;
+// Framebuffer column of sprite X coordinate 0
+pub(super) const SPRITE_X_OFFSET: i32 = 8;
+// Framebuffer column of the first display window pixel (sprite X 24)
+pub(super) const DISPLAY_LEFT: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsMode {
+    Text,
+    MulticolorText,
+    Bitmap,
+    MulticolorBitmap,
+    ExtendedColorText,
+    // ECM together with BMM or MCM: the VIC outputs black
+    Invalid,
+}
+
+impl VicII {
+    // ECM ($D011 bit 6), BMM ($D011 bit 5) and MCM ($D016 bit 4)
+    pub fn graphics_mode(&self) -> GraphicsMode {
+        let ecm = self.registers[0x11] & 0x40 != 0;
+        let bmm = self.registers[0x11] & 0x20 != 0;
+        let mcm = self.registers[0x16] & 0x10 != 0;
+        match (ecm, bmm, mcm) {
+            (false, false, false) => GraphicsMode::Text,
+            (false, false, true) => GraphicsMode::MulticolorText,
+            (false, true, false) => GraphicsMode::Bitmap,
+            (false, true, true) => GraphicsMode::MulticolorBitmap,
+            (true, false, false) => GraphicsMode::ExtendedColorText,
+            _ => GraphicsMode::Invalid,
+        }
+    }
+
+    // The picture as FRAME_WIDTH x FRAME_HEIGHT palette indices (0-15, see
+    // C64Color::rgb), row by row. It is drawn as the beam moves, so it holds
+    // one complete frame right after take_frame_complete() returns true.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    // True once after each frame is finished, when the beam wraps back to
+    // raster line 0. Calling it clears the flag.
+    pub fn take_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    // Frames completed since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // 64-bit FNV-1a hash of the framebuffer, for checking a run ends on a
+    // known picture. Unlike std's hasher it is the same on every build.
+    pub fn frame_hash(&self) -> u64 {
+        self.framebuffer.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &pixel| {
+            (hash ^ pixel as u64).wrapping_mul(0x0000_0100_0000_01B3)
+        })
+    }
+
+    // Palette index of a framebuffer pixel
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        if x < FRAME_WIDTH && y < FRAME_HEIGHT {
+            self.framebuffer[y * FRAME_WIDTH + x]
+        } else {
+            self.registers[0x20] & 0x0F
+        }
+    }
+
+    // Framebuffer position of the top-left pixel of a display window cell
+    pub fn cell_origin(x: usize, y: usize) -> (usize, usize) {
+        (DISPLAY_LEFT + x * 8, (0x33 - FIRST_VISIBLE_LINE) as usize + y * 8)
+    }
+
+    // Framebuffer row showing raster line `line`. An NTSC frame has only
+    // 263 lines, so its first lines (still bottom border on screen, before
+    // vertical blank) wrap round to the rows below line 262. The last 9 rows
+    // are never drawn and stay black.
+    pub(super) fn frame_row(&self, line: u16) -> Option<usize> {
+        let row = ((line + self.raster_lines - FIRST_VISIBLE_LINE) % self.raster_lines) as usize;
+        (row < FRAME_HEIGHT).then_some(row)
+    }
+
+    // Draw raster line `line`, which the beam has just finished
+    pub(super) fn render_line(&mut self, line: u16, bus: &VicBus) {
+        let Some(row) = self.frame_row(line) else { return };
+        let border = self.registers[0x20] & 0x0F;
+
+        // Outside the display window an open border shows background
+        let mut pixels = [(self.registers[0x21] & 0x0F, false); FRAME_WIDTH];
+        let open = !self.border_line_start || self.border_clear_x.is_some();
+        if open {
+            self.draw_graphics(&mut pixels, bus);
+        }
+        self.draw_sprites(&mut pixels, line, bus);
+
+        // The border covers graphics and sprites alike
+        let line_pixels: [u8; FRAME_WIDTH] =
+            std::array::from_fn(|x| if self.border_at(x) { border } else { pixels[x].0 });
+        let start = row * FRAME_WIDTH;
+        if self.framebuffer[start..start + FRAME_WIDTH] != line_pixels {
+            self.framebuffer[start..start + FRAME_WIDTH].copy_from_slice(&line_pixels);
+            self.dirty_lines[row] = true;
+        }
+    }
+
+    // Framebuffer lines that changed since the last call, one flag per row,
+    // so a frontend can skip redrawing what it already shows
+    pub fn take_dirty_lines(&mut self) -> Vec<bool> {
+        std::mem::replace(&mut self.dirty_lines, vec![false; FRAME_HEIGHT])
+    }
+
+    // Flag the framebuffer lines of a row of character cells, for changes a
+    // text frontend shows without them reaching the pixels (a screen code
+    // with no character ROM to draw it from, say)
+    pub(super) fn mark_cell_row_dirty(&mut self, row: usize) {
+        let (_, top) = Self::cell_origin(0, row);
+        for dirty in &mut self.dirty_lines[top..top + 8] {
+            *dirty = true;
+        }
+    }
+
+    // Graphics for the display window part of the line, with a foreground
+    // flag per pixel for sprite priority
+    fn draw_graphics(&self, pixels: &mut [(u8, bool); FRAME_WIDTH], bus: &VicBus) {
+        let mode = self.graphics_mode();
+        let background = self.registers[0x21] & 0x0F;
+        let xscroll = (self.registers[0x16] & 0x07) as usize;
+
+        // XSCROLL delays the graphics; the gap it opens shows background
+        for pixel in &mut pixels[DISPLAY_LEFT..DISPLAY_LEFT + xscroll] {
+            *pixel = (background, false);
+        }
+
+        for column in 0..SCREEN_WIDTH {
+            let decoded = if self.display_state {
+                let cell = self.row_counter.saturating_sub(1) * SCREEN_WIDTH + column;
+                let code = self.video_matrix[cell];
+                let color = self.color_matrix[cell];
+                let rc = self.rc as u16;
+                let data = match mode {
+                    GraphicsMode::Bitmap | GraphicsMode::MulticolorBitmap => {
+                        bus.read(self.bitmap_base() + cell as u16 * 8 + rc)
+                    }
+                    GraphicsMode::ExtendedColorText => bus.read(self.char_base() + (code & 0x3F) as u16 * 8 + rc),
+                    _ => bus.read(self.char_base() + code as u16 * 8 + rc),
+                };
+                self.decode_graphics(mode, data, code, color)
+            } else {
+                // Idle state: the VIC reads the last byte of the bank and
+                // draws it in black on the background
+                let data = bus.read(self.bank + if mode == GraphicsMode::ExtendedColorText { 0x39FF } else { 0x3FFF });
+                let mut idle = self.decode_graphics(mode, data, 0, 0);
+                for pixel in &mut idle {
+                    if pixel.1 {
+                        pixel.0 = 0;
+                    }
+                }
+                idle
+            };
+            let start = DISPLAY_LEFT + xscroll + column * 8;
+            for (bit, &pixel) in decoded.iter().enumerate() {
+                if start + bit < FRAME_WIDTH {
+                    pixels[start + bit] = pixel;
+                }
+            }
+        }
+    }
+
+    // Decode one graphics byte to 8 pixels. `code` is the screen code (the
+    // bitmap colors in bitmap modes) and `color` the color RAM nibble.
+    pub(super) fn decode_graphics(&self, mode: GraphicsMode, data: u8, code: u8, color: u8) -> [(u8, bool); 8] {
+        let background = self.registers[0x21] & 0x0F;
+        let mut pixels = [(background, false); 8];
+
+        // Bit pairs at half horizontal resolution; 10 and 11 are foreground
+        let multicolor = |pixels: &mut [(u8, bool); 8], colors: [u8; 4]| {
+            for pair in 0..4 {
+                let value = ((data >> (6 - pair * 2)) & 0x03) as usize;
+                let pixel = (colors[value] & 0x0F, value >= 2);
+                pixels[pair * 2] = pixel;
+                pixels[pair * 2 + 1] = pixel;
+            }
+        };
+        let hires = |pixels: &mut [(u8, bool); 8], foreground: u8, background: u8| {
+            for (bit, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = if data & (0x80 >> bit) != 0 {
+                    (foreground & 0x0F, true)
+                } else {
+                    (background & 0x0F, false)
+                };
+            }
+        };
+
+        match mode {
+            GraphicsMode::Text => hires(&mut pixels, color, background),
+            // Bit 3 of color RAM picks multicolor per cell; other cells are
+            // hires but limited to colors 0-7
+            GraphicsMode::MulticolorText if color & 0x08 != 0 => multicolor(
+                &mut pixels,
+                [background, self.registers[0x22], self.registers[0x23], color & 0x07],
+            ),
+            GraphicsMode::MulticolorText => hires(&mut pixels, color & 0x07, background),
+            GraphicsMode::Bitmap => hires(&mut pixels, code >> 4, code & 0x0F),
+            GraphicsMode::MulticolorBitmap => multicolor(&mut pixels, [background, code >> 4, code & 0x0F, color]),
+            // Screen code bits 6-7 pick one of four background colors
+            GraphicsMode::ExtendedColorText => {
+                hires(&mut pixels, color, self.registers[0x21 + (code >> 6) as usize])
+            }
+            GraphicsMode::Invalid => {
+                hires(&mut pixels, 0, 0);
+            }
+        }
+        pixels
+    }
+
+    fn draw_sprites(&self, pixels: &mut [(u8, bool); FRAME_WIDTH], line: u16, bus: &VicBus) {
+        // Sprite 7 first so lower numbered sprites end up on top
+        for n in (0..SPRITE_COUNT).rev() {
+            if !self.sprite_enabled(n) {
+                continue;
+            }
+            let bit = 1 << n;
+            let y_scale = if self.registers[0x17] & bit != 0 { 2 } else { 1 };
+            let x_scale = if self.registers[0x1D] & bit != 0 { 2 } else { 1 };
+            let behind_foreground = self.registers[0x1B] & bit != 0;
+
+            // A sprite at Y starts on raster line Y + 1
+            let first_line = self.sprite_y(n) as u16 + 1;
+            let Some(offset) = line.checked_sub(first_line) else { continue };
+            let row = offset as usize / y_scale;
+            if row >= 21 {
+                continue;
+            }
+
+            let left = self.sprite_x(n) as i32 + SPRITE_X_OFFSET;
+            let row_pixels = self.sprite_row(n, row, &|addr| bus.read(addr));
+            for (column, color) in row_pixels.iter().enumerate() {
+                let Some(color) = *color else { continue };
+                for dx in 0..x_scale {
+                    let x = left + (column * x_scale + dx) as i32;
+                    if !(0..FRAME_WIDTH as i32).contains(&x) {
+                        continue;
+                    }
+                    let pixel = &mut pixels[x as usize];
+                    if !(behind_foreground && pixel.1) {
+                        pixel.0 = color;
+                    }
+                }
+            }
+        }
+    }
+}
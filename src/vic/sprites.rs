@@ -0,0 +1,210 @@
+// Hardware sprites: eight movable 24x21 pixel objects drawn over the screen
+
+use super::{VicII, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::memory::Memory;
+
+pub const SPRITE_COUNT: usize = 8;
+const SPRITE_WIDTH: usize = 24;
+const SPRITE_HEIGHT: usize = 21;
+
+// Sprite coordinates of the top-left pixel of the 320x200 display window
+const DISPLAY_X: i32 = 24;
+const DISPLAY_Y: i32 = 50;
+
+pub const DISPLAY_WIDTH: usize = SCREEN_WIDTH * 8;
+pub const DISPLAY_HEIGHT: usize = SCREEN_HEIGHT * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpritePixel {
+    pub color: u8,
+    // $D01B: drawn behind the foreground pixels of characters
+    pub behind_foreground: bool,
+}
+
+// The sprites of one frame, flattened to a 320x200 pixel layer with the
+// sprite-to-sprite priority (sprite 0 on top) already resolved
+pub struct SpriteLayer {
+    pixels: Vec<Option<SpritePixel>>,
+}
+
+impl SpriteLayer {
+    pub fn pixel(&self, x: usize, y: usize) -> Option<SpritePixel> {
+        if x < DISPLAY_WIDTH && y < DISPLAY_HEIGHT {
+            self.pixels[y * DISPLAY_WIDTH + x]
+        } else {
+            None
+        }
+    }
+}
+
+impl VicII {
+    // 9-bit X position: $D000/$D002/... plus bit n of $D010
+    pub fn sprite_x(&self, n: usize) -> u16 {
+        let msb = ((self.registers[0x10] >> n) & 1) as u16;
+        (msb << 8) | self.registers[n * 2] as u16
+    }
+
+    pub fn sprite_y(&self, n: usize) -> u8 {
+        self.registers[n * 2 + 1]
+    }
+
+    pub fn sprite_enabled(&self, n: usize) -> bool {
+        self.registers[0x15] & (1 << n) != 0
+    }
+
+    // A sprite's DMA runs on the raster lines from the one whose low 8 bits
+    // match its Y coordinate, fetching a row on each: 21 lines, or 42 when
+    // expanded vertically
+    fn sprite_dma(&self, n: usize, line: u16) -> bool {
+        let height = if self.registers[0x17] & (1 << n) != 0 { 2 } else { 1 } * SPRITE_HEIGHT as u8;
+        self.sprite_enabled(n) && (line as u8).wrapping_sub(self.sprite_y(n)) < height
+    }
+
+    // BA is low for the 3 cycles before a sprite's pointer fetch and the 2
+    // cycles of its fetches. Those start 6 cycles before the end of the DMA
+    // line for sprite 0, 2 cycles apart, so sprites 3-7 fetch at the start
+    // of the line after it.
+    pub(super) fn sprite_ba_low(&self) -> bool {
+        let previous = self.raster_line.checked_sub(1).unwrap_or(self.raster_lines - 1);
+        (0..SPRITE_COUNT).any(|n| {
+            let fetch = self.cycles_per_line - 6 + 2 * n as u16;
+            let window = fetch - 3..fetch + 2;
+            (self.sprite_dma(n, self.raster_line) && window.contains(&self.cycle_count))
+                || (self.sprite_dma(n, previous) && window.contains(&(self.cycle_count + self.cycles_per_line)))
+        })
+    }
+
+    // Colors of the 24 pixels of one row of a sprite (None = transparent),
+    // reading sprite memory through `read`
+    pub(super) fn sprite_row(&self, n: usize, row: usize, read: &dyn Fn(u16) -> u8) -> [Option<u8>; SPRITE_WIDTH] {
+        let multicolor = self.registers[0x1C] & (1 << n) != 0;
+
+        // Sprite pointers live in the last 8 bytes of screen memory and
+        // select a 64-byte block of sprite data within the VIC bank
+        let pointer = read(self.screen_base() + 0x3F8 + n as u16);
+        let offset = self.bank + pointer as u16 * 64 + row as u16 * 3;
+        let bits = ((read(offset) as u32) << 16) | ((read(offset + 1) as u32) << 8) | read(offset + 2) as u32;
+
+        let mut pixels = [None; SPRITE_WIDTH];
+        for (column, pixel) in pixels.iter_mut().enumerate() {
+            let color = if multicolor {
+                // Pairs of bits select one of three colors, at half the
+                // horizontal resolution
+                match (bits >> (22 - (column & !1))) & 0x03 {
+                    0b01 => self.registers[0x25],
+                    0b10 => self.registers[0x27 + n],
+                    0b11 => self.registers[0x26],
+                    _ => continue,
+                }
+            } else if (bits >> (23 - column)) & 1 != 0 {
+                self.registers[0x27 + n]
+            } else {
+                continue;
+            };
+            *pixel = Some(color & 0x0F);
+        }
+        pixels
+    }
+
+    pub fn render_sprites(&self, memory: &dyn Memory) -> SpriteLayer {
+        let mut layer = SpriteLayer { pixels: vec![None; DISPLAY_WIDTH * DISPLAY_HEIGHT] };
+
+        // Paint from sprite 7 down so lower numbered sprites end up on top
+        for n in (0..SPRITE_COUNT).rev() {
+            if !self.sprite_enabled(n) {
+                continue;
+            }
+            let bit = 1 << n;
+            let x_scale = if self.registers[0x1D] & bit != 0 { 2 } else { 1 };
+            let y_scale = if self.registers[0x17] & bit != 0 { 2 } else { 1 };
+            let behind_foreground = self.registers[0x1B] & bit != 0;
+
+            let left = self.sprite_x(n) as i32 - DISPLAY_X;
+            let top = self.sprite_y(n) as i32 - DISPLAY_Y;
+
+            for row in 0..SPRITE_HEIGHT {
+                let row_pixels = self.sprite_row(n, row, &|addr| memory.read_vic(addr));
+                for (column, color) in row_pixels.iter().enumerate() {
+                    let Some(color) = *color else { continue };
+                    let pixel = Some(SpritePixel { color, behind_foreground });
+
+                    for dy in 0..y_scale {
+                        let y = top + (row * y_scale + dy) as i32;
+                        if !(0..DISPLAY_HEIGHT as i32).contains(&y) {
+                            continue;
+                        }
+                        for dx in 0..x_scale {
+                            let x = left + (column * x_scale + dx) as i32;
+                            if (0..DISPLAY_WIDTH as i32).contains(&x) {
+                                layer.pixels[y as usize * DISPLAY_WIDTH + x as usize] = pixel;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BasicMemory;
+
+    #[test]
+    fn test_sprite_position_expansion_and_priority() {
+        let mut vic = VicII::new();
+        let mut memory = BasicMemory::new();
+
+        // Sprite 0: top-left pixel set, data at $0340 (block 13)
+        memory.write(0x07F8, 13);
+        memory.write(0x0340, 0x80);
+        vic.write_register(0xD000, 24 + 10); // X: display column 10
+        vic.write_register(0xD001, 50 + 5); // Y: display row 5
+        vic.write_register(0xD027, 2);
+
+        // Sprite 1: same spot, X expanded, block 14 ($0380)
+        memory.write(0x07F9, 14);
+        memory.write(0x0380, 0x80);
+        vic.write_register(0xD002, 24 + 10);
+        vic.write_register(0xD003, 50 + 5);
+        vic.write_register(0xD028, 7);
+        vic.write_register(0xD01D, 0x02);
+
+        vic.write_register(0xD015, 0x03);
+        let layer = vic.render_sprites(&memory);
+
+        // Sprite 0 wins where they overlap; sprite 1 shows through beside it
+        assert_eq!(layer.pixel(10, 5).map(|p| p.color), Some(2));
+        assert_eq!(layer.pixel(11, 5).map(|p| p.color), Some(7));
+        assert_eq!(layer.pixel(12, 5), None);
+
+        // $D010 bit 0 moves sprite 0 past X=255, off the right of the window
+        vic.write_register(0xD010, 0x01);
+        assert_eq!(vic.sprite_x(0), 256 + 34);
+        let layer = vic.render_sprites(&memory);
+        assert_eq!(layer.pixel(10, 5).map(|p| p.color), Some(7));
+    }
+
+    #[test]
+    fn test_multicolor_sprite_pixels() {
+        let mut vic = VicII::new();
+        let mut memory = BasicMemory::new();
+        memory.write(0x07F8, 13);
+        memory.write(0x0340, 0b01_10_11_00);
+        vic.write_register(0xD000, 24);
+        vic.write_register(0xD001, 50);
+        vic.write_register(0xD025, 3); // Multicolor 0
+        vic.write_register(0xD026, 4); // Multicolor 1
+        vic.write_register(0xD027, 5);
+        vic.write_register(0xD01C, 0x01);
+        vic.write_register(0xD01B, 0x01);
+        vic.write_register(0xD015, 0x01);
+
+        let layer = vic.render_sprites(&memory);
+        let colors: Vec<Option<u8>> = (0..8).map(|x| layer.pixel(x, 0).map(|p| p.color)).collect();
+        assert_eq!(colors, vec![Some(3), Some(3), Some(5), Some(5), Some(4), Some(4), None, None]);
+        assert!(layer.pixel(0, 0).unwrap().behind_foreground);
+    }
+}